@@ -0,0 +1,101 @@
+//! Compare memory usage and throughput of a result channel per target
+//! against a single shared channel, which informs the default
+//! [`uppies::ChannelMode`].
+//!
+//! Run with `cargo bench --bench channels`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::sync::mpsc;
+use uppies::{Expect, PingOutcome};
+
+/// Allocator which tracks the number of bytes currently allocated.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const PER_TARGET_CAPACITY: usize = 5;
+
+fn result(target: &Arc<str>, sequence: u64) -> PingOutcome {
+    PingOutcome {
+        target: Arc::clone(target),
+        probe: "icmp".into(),
+        resolved_ip: Some(Ipv4Addr::LOCALHOST.into()),
+        reply: None,
+        sequence,
+        rtt: Ok(Duration::ZERO),
+        expect: Expect::Up,
+        timestamp: SystemTime::now(),
+    }
+}
+
+async fn per_target(targets: &[Arc<str>]) -> (usize, Duration) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let channels: Vec<_> = targets
+        .iter()
+        .map(|_| mpsc::channel::<PingOutcome>(PER_TARGET_CAPACITY))
+        .collect();
+    let start = Instant::now();
+    for (target, (tx, _)) in targets.iter().zip(&channels) {
+        for seq in 0..PER_TARGET_CAPACITY as u64 {
+            tx.send(result(target, seq)).await.unwrap();
+        }
+    }
+    let memory = ALLOCATED.load(Ordering::Relaxed) - before;
+    let mut channels = channels;
+    for (_, rx) in &mut channels {
+        while rx.try_recv().is_ok() {}
+    }
+    (memory, start.elapsed())
+}
+
+async fn shared(targets: &[Arc<str>]) -> (usize, Duration) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::channel::<PingOutcome>(targets.len() * PER_TARGET_CAPACITY);
+    let senders: Vec<_> = targets.iter().map(|_| tx.clone()).collect();
+    let start = Instant::now();
+    for (target, tx) in targets.iter().zip(&senders) {
+        for seq in 0..PER_TARGET_CAPACITY as u64 {
+            tx.send(result(target, seq)).await.unwrap();
+        }
+    }
+    let memory = ALLOCATED.load(Ordering::Relaxed) - before;
+    while rx.try_recv().is_ok() {}
+    (memory, start.elapsed())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    println!("targets\tper_target_bytes\tshared_bytes\tper_target_time\tshared_time");
+    for count in [1, 10, 100, 1_000, 10_000] {
+        let targets: Vec<Arc<str>> = (0..count).map(|i| i.to_string().into()).collect();
+        let (per_target_memory, per_target_time) = per_target(&targets).await;
+        let (shared_memory, shared_time) = shared(&targets).await;
+        println!(
+            "{count}\t{per_target_memory}\t{shared_memory}\t{per_target_time:?}\t{shared_time:?}"
+        );
+    }
+}
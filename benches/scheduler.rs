@@ -0,0 +1,118 @@
+//! Compare a task per target against a pool of workers probing targets in
+//! order of their deadlines, at 10k targets pinged every second, which
+//! informs the default [`uppies::scheduler::SchedulerMode`].
+//!
+//! Each probe sleeps for a simulated round-trip time, so that only the cost
+//! of scheduling is measured. Run with `cargo bench --bench scheduler`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use prometheus::Registry;
+use tokio_stream::StreamExt;
+use uppies::{
+    probe::{Probe, ProbeOutcome},
+    scheduler::SchedulerMode,
+    PingSender,
+};
+
+/// Allocator which tracks the number of bytes currently allocated.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const TARGETS: usize = 10_000;
+const INTERVAL_MS: u64 = 1000;
+const RTT: Duration = Duration::from_millis(5);
+const DURATION: Duration = Duration::from_secs(10);
+
+/// Probe which succeeds after the simulated round-trip time.
+struct Sleep;
+
+impl Probe for Sleep {
+    async fn probe(&self) -> ProbeOutcome {
+        tokio::time::sleep(RTT).await;
+        ProbeOutcome {
+            resolved_ip: None,
+            reply: None,
+            rtt: Ok(RTT),
+        }
+    }
+}
+
+/// CPU time of the process, in user and system mode.
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Ping rate, bytes allocated, CPU time and greatest lag of the scheduler
+/// of pinging the targets for the duration.
+async fn run(mode: SchedulerMode) -> (f64, usize, Duration, f64) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let cpu_before = cpu_time();
+    let metrics = Registry::new();
+    let mut sender = PingSender::builder()
+        .with_ping_interval_ms(INTERVAL_MS)
+        .with_registry(&metrics)
+        .build()
+        .unwrap()
+        .with_scheduler_mode(mode);
+    for i in 0..TARGETS {
+        sender = sender.with_probe(format!("target-{i}").parse().unwrap(), Sleep);
+    }
+    let mut results = sender.results();
+
+    let start = Instant::now();
+    let mut pings = 0;
+    let mut memory = 0;
+    let mut lag: f64 = 0.0;
+    while start.elapsed() < DURATION {
+        if results.next().await.is_none() {
+            break;
+        }
+        pings += 1;
+        if pings % TARGETS == 0 {
+            memory = memory.max(ALLOCATED.load(Ordering::Relaxed).saturating_sub(before));
+            let gathered = metrics.gather();
+            let scheduler_lag = gathered
+                .iter()
+                .find(|family| family.name() == "uppies_scheduler_lag_seconds")
+                .map(|family| family.get_metric()[0].get_gauge().value());
+            lag = lag.max(scheduler_lag.unwrap_or_default());
+        }
+    }
+    let rate = pings as f64 / start.elapsed().as_secs_f64();
+    (rate, memory, cpu_time() - cpu_before, lag)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    println!("mode\tpings_per_sec\tbytes\tcpu_time\tmax_lag_secs");
+    for mode in [SchedulerMode::Tasks, SchedulerMode::Pool] {
+        let (rate, memory, cpu, lag) = run(mode).await;
+        println!("{mode:?}\t{rate:.0}\t{memory}\t{cpu:?}\t{lag:.3}");
+    }
+}
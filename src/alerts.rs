@@ -0,0 +1,546 @@
+//! Threshold based alerting, for running uppies standalone without an
+//! Alertmanager.
+//!
+//! Each rule is evaluated against every probe of the targets it selects
+//! whenever the target is pinged, over a sliding window of the recent pings
+//! of the probe, so that each probe alerts by itself. An alert is
+//! pending while its condition holds, firing once it has held for the rule's
+//! `for_secs`, and resolved once it no longer holds. Alerts of targets
+//! within a maintenance window are kept pending rather than firing.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{maintenance::Maintenance, sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// Name of the alert, unique among all rules.
+    pub name: String,
+    /// Targets the rule applies to, or all targets when empty.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Fire when the percentage of failed pings within the window is above
+    /// this.
+    pub loss_above_percent: Option<f64>,
+    /// Fire when the 95th percentile round-trip time of successful pings
+    /// within the window is above this.
+    pub rtt_p95_above_ms: Option<f64>,
+    /// Length of the sliding window, in seconds, which the condition is
+    /// evaluated over.
+    #[serde(default = "AlertRule::default_window_secs")]
+    pub window_secs: u64,
+    /// Time, in seconds, the condition must hold for before the alert fires.
+    #[serde(default)]
+    pub for_secs: u64,
+}
+
+impl AlertRule {
+    fn default_window_secs() -> u64 {
+        300
+    }
+
+    /// Value which the rule's condition compares against its threshold,
+    /// along with the threshold.
+    fn evaluate(&self, window: &VecDeque<(SystemTime, bool, Option<f64>)>) -> Option<(f64, f64)> {
+        if let Some(threshold) = self.loss_above_percent {
+            let failed = window.iter().filter(|(_, ok, _)| !ok).count();
+            return Some((failed as f64 / window.len() as f64 * 100.0, threshold));
+        }
+        let threshold = self.rtt_p95_above_ms?;
+        let mut rtts: Vec<_> = window.iter().filter_map(|(_, _, rtt)| *rtt).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        rtts.sort_by(f64::total_cmp);
+        // Nearest-rank percentile.
+        let rank = (rtts.len() as f64 * 0.95).ceil() as usize;
+        Some((rtts[rank.max(1) - 1], threshold))
+    }
+}
+
+/// State of an alert for a single probe of a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Inactive,
+    Pending,
+    Firing,
+    Resolved,
+}
+
+impl AlertState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inactive => "inactive",
+            Self::Pending => "pending",
+            Self::Firing => "firing",
+            Self::Resolved => "resolved",
+        }
+    }
+}
+
+/// An alert which isn't inactive, as served at [`AlertEngine::PATH`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub alert: String,
+    pub target: String,
+    /// Probe of the target, see [`ProbeTarget::probe`](crate::ProbeTarget::probe).
+    pub probe: String,
+    pub state: AlertState,
+    /// Time at which the alert entered its state, in milliseconds since the
+    /// unix epoch.
+    pub since_ms: u64,
+    /// Most recent value of the condition, such as the loss percentage.
+    pub value: f64,
+    pub threshold: f64,
+}
+
+struct Evaluation {
+    /// Time of each ping within the window, whether it succeeded and its
+    /// round-trip time, which targets expected to be down succeed without.
+    window: VecDeque<(SystemTime, bool, Option<f64>)>,
+    state: AlertState,
+    since: SystemTime,
+    /// Time at which the condition began holding.
+    holding_since: Option<SystemTime>,
+    value: f64,
+    threshold: f64,
+}
+
+/// Evaluates alert rules against ping results, clones share the same state.
+#[derive(Clone)]
+pub struct AlertEngine {
+    inner: Arc<Inner>,
+    /// Maintenance windows within which alerts don't fire.
+    maintenance: Option<Maintenance>,
+}
+
+struct Inner {
+    rules: Vec<AlertRule>,
+    /// Evaluation of each rule, by index, for each probe of each target.
+    evaluations: Mutex<HashMap<(usize, String, String), Evaluation>>,
+
+    /// Whether each alert is in a state, labelled by alert, target, probe
+    /// and state.
+    state: IntGaugeVec,
+    /// Number of times each alert entered a state.
+    transitions: IntCounterVec,
+}
+
+impl AlertEngine {
+    /// Path which all alerts which aren't inactive are served at.
+    pub const PATH: &str = "/alerts";
+
+    const STATES: &[AlertState] = &[
+        AlertState::Pending,
+        AlertState::Firing,
+        AlertState::Resolved,
+    ];
+
+    pub fn new(rules: &[AlertRule], metrics: &Registry) -> Result<Self> {
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.loss_above_percent.is_some() == rule.rtt_p95_above_ms.is_some() {
+                return Err(format!(
+                    "alert '{}' requires exactly one of 'loss_above_percent' or 'rtt_p95_above_ms'",
+                    rule.name
+                )
+                .into());
+            }
+            if rules[..i].iter().any(|r| r.name == rule.name) {
+                return Err(format!("alert '{}' is defined more than once", rule.name).into());
+            }
+        }
+        let state = IntGaugeVec::new(
+            Opts::new(
+                "uppies_alert_state",
+                "Whether an alert is pending, firing or resolved for a target",
+            ),
+            &["alert", "target", "probe", "state"],
+        )?;
+        let transitions = IntCounterVec::new(
+            Opts::new(
+                "uppies_alert_transitions_total",
+                "Counter of alerts entering the pending, firing or resolved state",
+            ),
+            &["alert", "target", "probe", "state"],
+        )?;
+        metrics.register(Box::new(state.clone()))?;
+        metrics.register(Box::new(transitions.clone()))?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                rules: rules.to_vec(),
+                evaluations: Mutex::new(HashMap::new()),
+                state,
+                transitions,
+            }),
+            maintenance: None,
+        })
+    }
+
+    /// Keep the alerts of targets pending rather than firing while they are
+    /// within a maintenance window of `maintenance`.
+    pub fn with_maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// All alerts which aren't inactive, ordered by alert, target and probe.
+    pub fn alerts(&self) -> Vec<Alert> {
+        let evaluations = self.inner.evaluations.lock().expect("alerts lock poisoned");
+        let mut alerts: Vec<_> = evaluations
+            .iter()
+            .filter(|(_, e)| e.state != AlertState::Inactive)
+            .map(|((rule, target, probe), e)| Alert {
+                alert: self.inner.rules[*rule].name.clone(),
+                target: target.clone(),
+                probe: probe.clone(),
+                state: e.state,
+                since_ms: e
+                    .since
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+                value: e.value,
+                threshold: e.threshold,
+            })
+            .collect();
+        alerts
+            .sort_by(|a, b| (&a.alert, &a.target, &a.probe).cmp(&(&b.alert, &b.target, &b.probe)));
+        alerts
+    }
+}
+
+impl Inner {
+    fn observe(
+        &self,
+        target: &str,
+        probe: &str,
+        at: SystemTime,
+        succeeded: bool,
+        rtt_ms: Option<f64>,
+        muted: bool,
+    ) {
+        let mut evaluations = self.evaluations.lock().expect("alerts lock poisoned");
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.targets.is_empty() && !rule.targets.iter().any(|t| t == target) {
+                continue;
+            }
+            let evaluation = evaluations
+                .entry((i, target.to_string(), probe.to_string()))
+                .or_insert_with(|| Evaluation {
+                    window: VecDeque::new(),
+                    state: AlertState::Inactive,
+                    since: at,
+                    holding_since: None,
+                    value: 0.0,
+                    threshold: 0.0,
+                });
+            evaluation.window.push_back((at, succeeded, rtt_ms));
+            let window = Duration::from_secs(rule.window_secs);
+            while let Some((oldest, ..)) = evaluation.window.front() {
+                if at.duration_since(*oldest).unwrap_or_default() <= window {
+                    break;
+                }
+                evaluation.window.pop_front();
+            }
+
+            let holds = match rule.evaluate(&evaluation.window) {
+                Some((value, threshold)) => {
+                    evaluation.value = value;
+                    evaluation.threshold = threshold;
+                    value > threshold
+                }
+                None => false,
+            };
+            let holding_since = match (holds, evaluation.holding_since) {
+                (true, Some(since)) => since,
+                (true, None) => *evaluation.holding_since.insert(at),
+                (false, _) => {
+                    evaluation.holding_since = None;
+                    at
+                }
+            };
+            let held = at.duration_since(holding_since).unwrap_or_default();
+            let next = match (evaluation.state, holds) {
+                (AlertState::Firing, true) => AlertState::Firing,
+                (_, true) if held >= Duration::from_secs(rule.for_secs) && !muted => {
+                    AlertState::Firing
+                }
+                (_, true) => AlertState::Pending,
+                (AlertState::Firing | AlertState::Resolved, false) => AlertState::Resolved,
+                (AlertState::Inactive | AlertState::Pending, false) => AlertState::Inactive,
+            };
+            if next != evaluation.state {
+                self.transition(&rule.name, target, probe, evaluation, next, at);
+            }
+        }
+    }
+
+    fn transition(
+        &self,
+        alert: &str,
+        target: &str,
+        probe: &str,
+        evaluation: &mut Evaluation,
+        next: AlertState,
+        at: SystemTime,
+    ) {
+        let (value, threshold) = (evaluation.value, evaluation.threshold);
+        match next {
+            AlertState::Firing => warn!(alert, target, probe, value, threshold, "alert firing"),
+            AlertState::Resolved => {
+                info!(alert, target, probe, value, threshold, "alert resolved")
+            }
+            _ => {}
+        }
+        evaluation.state = next;
+        evaluation.since = at;
+        for state in AlertEngine::STATES {
+            self.state
+                .with_label_values(&[alert, target, probe, state.as_str()])
+                .set((*state == next) as i64);
+        }
+        if next != AlertState::Inactive {
+            self.transitions
+                .with_label_values(&[alert, target, probe, next.as_str()])
+                .inc();
+        }
+    }
+}
+
+impl Sink for AlertEngine {
+    fn record(&self, outcome: &PingOutcome) {
+        let rtt_ms = outcome.latency().map(|d| d.as_secs_f64() * 1000.0);
+        let muted = self
+            .maintenance
+            .as_ref()
+            .is_some_and(|m| m.contains(&outcome.target, outcome.timestamp));
+        self.inner.observe(
+            &outcome.target,
+            &outcome.probe,
+            outcome.timestamp,
+            outcome.rtt.is_ok(),
+            rtt_ms,
+            muted,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use prometheus::Registry;
+
+    use super::{AlertEngine, AlertRule, AlertState};
+    use crate::{
+        groups::GroupsConfig,
+        maintenance::{Maintenance, MaintenanceWindow},
+        sink::Sink,
+        ErrorKind, Expect, PingOutcome,
+    };
+
+    const TARGET: &str = "127.0.0.1";
+
+    fn rule() -> AlertRule {
+        AlertRule {
+            name: "loss".to_string(),
+            targets: Vec::new(),
+            loss_above_percent: Some(40.0),
+            rtt_p95_above_ms: None,
+            window_secs: 10,
+            for_secs: 2,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn state(engine: &AlertEngine) -> Option<AlertState> {
+        engine.alerts().first().map(|a| a.state)
+    }
+
+    #[test]
+    fn pending_firing_resolved() {
+        let engine = AlertEngine::new(&[rule()], &Registry::new()).unwrap();
+        engine
+            .inner
+            .observe(TARGET, "icmp", at(0), true, Some(1.0), false);
+        assert_eq!(state(&engine), None);
+
+        engine
+            .inner
+            .observe(TARGET, "icmp", at(1), false, None, false);
+        assert_eq!(state(&engine), Some(AlertState::Pending));
+        engine
+            .inner
+            .observe(TARGET, "icmp", at(2), false, None, false);
+        assert_eq!(state(&engine), Some(AlertState::Pending));
+        engine
+            .inner
+            .observe(TARGET, "icmp", at(3), false, None, false);
+        assert_eq!(state(&engine), Some(AlertState::Firing));
+        assert_eq!(
+            engine
+                .inner
+                .state
+                .with_label_values(&["loss", TARGET, "icmp", "firing"])
+                .get(),
+            1
+        );
+
+        // The failures leave the window.
+        for secs in 4..15 {
+            engine
+                .inner
+                .observe(TARGET, "icmp", at(secs), true, Some(1.0), false);
+        }
+        assert_eq!(state(&engine), Some(AlertState::Resolved));
+        assert_eq!(
+            engine
+                .inner
+                .transitions
+                .with_label_values(&["loss", TARGET, "icmp", "firing"])
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn pending_without_firing() {
+        let engine = AlertEngine::new(&[rule()], &Registry::new()).unwrap();
+        engine
+            .inner
+            .observe(TARGET, "icmp", at(0), false, None, false);
+        assert_eq!(state(&engine), Some(AlertState::Pending));
+        for secs in 1..3 {
+            engine
+                .inner
+                .observe(TARGET, "icmp", at(secs), true, Some(1.0), false);
+        }
+        assert_eq!(state(&engine), None, "never fired, so is not resolved");
+    }
+
+    #[test]
+    fn maintenance() {
+        let window = MaintenanceWindow {
+            name: "upgrades".to_string(),
+            targets: vec![TARGET.to_string()],
+            groups: Vec::new(),
+            // The first 10 minutes of every hour.
+            schedule: "0 * * * *".parse().unwrap(),
+            duration_mins: 10,
+        };
+        let maintenance =
+            Maintenance::new(&[window], &GroupsConfig::new(), &Registry::new()).unwrap();
+        let engine = AlertEngine::new(&[rule()], &Registry::new())
+            .unwrap()
+            .with_maintenance(maintenance);
+        let mut outcome = PingOutcome::test(TARGET, Err(ErrorKind::Timeout));
+        for secs in 0..5 {
+            outcome.timestamp = at(secs);
+            engine.record(&outcome);
+        }
+        assert_eq!(state(&engine), Some(AlertState::Pending));
+
+        outcome.timestamp = at(600);
+        engine.record(&outcome);
+        assert_eq!(state(&engine), Some(AlertState::Firing));
+    }
+
+    #[test]
+    fn rtt_p95() {
+        let rule = AlertRule {
+            name: "slow".to_string(),
+            targets: vec![TARGET.to_string()],
+            loss_above_percent: None,
+            rtt_p95_above_ms: Some(50.0),
+            for_secs: 0,
+            ..rule()
+        };
+        let engine = AlertEngine::new(&[rule], &Registry::new()).unwrap();
+        for i in 0..19 {
+            engine
+                .inner
+                .observe(TARGET, "icmp", at(0), true, Some(i as f64), false);
+        }
+        engine
+            .inner
+            .observe(TARGET, "icmp", at(0), true, Some(100.0), false);
+        assert_eq!(state(&engine), None, "a single slow ping is above p95");
+        engine
+            .inner
+            .observe(TARGET, "icmp", at(0), true, Some(100.0), false);
+        assert_eq!(state(&engine), Some(AlertState::Firing));
+
+        engine
+            .inner
+            .observe("10.0.0.1", "icmp", at(0), true, Some(100.0), false);
+        assert_eq!(engine.alerts().len(), 1, "other targets aren't selected");
+    }
+
+    #[test]
+    fn probes() {
+        let engine = AlertEngine::new(&[rule()], &Registry::new()).unwrap();
+        for secs in 0..3 {
+            engine
+                .inner
+                .observe(TARGET, "icmp", at(secs), false, None, false);
+            engine
+                .inner
+                .observe(TARGET, "tcp", at(secs), true, Some(1.0), false);
+        }
+        let alerts = engine.alerts();
+        assert_eq!(alerts.len(), 1, "only the failing probe alerts");
+        assert_eq!(
+            (alerts[0].probe.as_str(), alerts[0].state),
+            ("icmp", AlertState::Firing)
+        );
+    }
+
+    #[test]
+    fn expected_down() {
+        let slow = AlertRule {
+            name: "slow".to_string(),
+            loss_above_percent: None,
+            rtt_p95_above_ms: Some(50.0),
+            for_secs: 0,
+            ..rule()
+        };
+        let loss = AlertRule {
+            for_secs: 0,
+            ..rule()
+        };
+        let engine = AlertEngine::new(&[slow, loss], &Registry::new()).unwrap();
+        // Unreachable as expected, after waiting out the timeout.
+        let mut outcome = PingOutcome {
+            expect: Expect::Down,
+            ..PingOutcome::test(TARGET, Ok(Duration::from_secs(1)))
+        };
+        for secs in 0..5 {
+            outcome.timestamp = at(secs);
+            engine.record(&outcome);
+        }
+        assert_eq!(engine.alerts(), Vec::new());
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let both = AlertRule {
+            rtt_p95_above_ms: Some(1.0),
+            ..rule()
+        };
+        assert!(AlertEngine::new(&[both], &Registry::new()).is_err());
+        assert!(AlertEngine::new(&[rule(), rule()], &Registry::new()).is_err());
+    }
+}
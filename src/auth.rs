@@ -0,0 +1,598 @@
+//! Authentication of the HTTP API with bearer tokens, each limited to the
+//! scopes it needs, so that automation gets least-privilege access to a
+//! shared instance.
+//!
+//! The API is open when no tokens or users are configured. Otherwise each
+//! request requires an `Authorization: Bearer <token>` header with a token
+//! having the scope of the route, where every scope includes `read` and
+//! `admin` includes all scopes.
+//!
+//! Users can instead authenticate with basic authentication, for scrapers
+//! and browsers which cannot send a token. Their passwords are hashed with
+//! SHA-crypt, as by `htpasswd -5` or `mkpasswd -m sha-512`, and can be kept
+//! in an htpasswd file. Passwords are verified on the blocking threads of
+//! the runtime, and hashes of more than [`MAX_ROUNDS`] rounds are rejected,
+//! as every request is verified.
+
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use sha_crypt::{password_hash, PasswordVerifier, ShaCrypt};
+use tracing::{debug, warn};
+
+use crate::Result;
+
+/// Permission to use a group of routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Scope {
+    /// Reading metrics, results and the status of targets and alerts.
+    #[serde(rename = "read")]
+    Read,
+    /// Pausing, resuming and replacing targets.
+    #[serde(rename = "targets:write")]
+    TargetsWrite,
+    /// Every other scope.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::TargetsWrite => "targets:write",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// Whether this scope permits routes requiring `required`.
+    pub fn allows(&self, required: Scope) -> bool {
+        *self == Self::Admin || *self == required || required == Self::Read
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "targets:write" => Ok(Self::TargetsWrite),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!(
+                "unknown scope '{s}', expected 'read', 'targets:write' or 'admin'"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Tokens which are accepted, in addition to those of `token_file`.
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    /// TOML file of further `[[tokens]]`, so that they can be kept apart
+    /// from the rest of the configuration.
+    pub token_file: Option<PathBuf>,
+    /// Serve `/metrics` without a token, for scrapers which cannot send
+    /// one.
+    #[serde(default)]
+    pub public_metrics: bool,
+    /// Users of basic authentication, in addition to those of
+    /// `htpasswd_file`.
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// htpasswd file of `user:hash` lines, whose users have
+    /// `htpasswd_scopes`.
+    pub htpasswd_file: Option<PathBuf>,
+    /// Scopes of the users of `htpasswd_file`.
+    #[serde(default = "AuthConfig::default_htpasswd_scopes")]
+    pub htpasswd_scopes: Vec<Scope>,
+}
+
+impl AuthConfig {
+    fn default_htpasswd_scopes() -> Vec<Scope> {
+        vec![Scope::Read]
+    }
+}
+
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    /// SHA-crypt hash of the password, starting with `$5$` or `$6$`.
+    pub password_hash: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// Hashes are redacted along with tokens, as they can be cracked offline.
+impl std::fmt::Debug for UserConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserConfig")
+            .field("name", &self.name)
+            .field("password_hash", &"<redacted>")
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// Name of the token, which is logged instead of the token itself.
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// Tokens are redacted, so that they aren't leaked into logs.
+impl std::fmt::Debug for TokenConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenConfig")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+/// Structure of a `token_file`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenFile {
+    tokens: Vec<TokenConfig>,
+}
+
+/// Reason a request was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// The request has no token, or an unknown one, or the credentials of
+    /// a user are wrong.
+    Unauthenticated,
+    /// The token doesn't have the scope of the route.
+    Forbidden,
+}
+
+impl IntoResponse for Denied {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Unauthenticated => (
+                StatusCode::UNAUTHORIZED,
+                [("WWW-Authenticate", "Bearer")],
+                "missing or unknown credentials",
+            )
+                .into_response(),
+            Self::Forbidden => {
+                (StatusCode::FORBIDDEN, "token lacks the required scope").into_response()
+            }
+        }
+    }
+}
+
+/// Accepted tokens and users, clones share the same tokens.
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    tokens: Arc<[TokenConfig]>,
+    users: Arc<[UserConfig]>,
+    public_metrics: bool,
+}
+
+impl Tokens {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        let mut tokens = config.tokens.clone();
+        if let Some(path) = &config.token_file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read token file {}: {e}", path.display()))?;
+            let file: TokenFile = toml::from_str(&contents)
+                .map_err(|e| format!("invalid token file {}: {e}", path.display()))?;
+            tokens.extend(file.tokens);
+        }
+        for (i, token) in tokens.iter().enumerate() {
+            if token.token.is_empty() {
+                return Err(format!("token '{}' is empty", token.name).into());
+            }
+            if tokens[..i].iter().any(|t| t.token == token.token) {
+                return Err(format!("token '{}' is a duplicate", token.name).into());
+            }
+        }
+        let mut users = config.users.clone();
+        if let Some(path) = &config.htpasswd_file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read htpasswd file {}: {e}", path.display()))?;
+            for line in contents.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (name, hash) = line
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid htpasswd file {}", path.display()))?;
+                users.push(UserConfig {
+                    name: name.to_string(),
+                    password_hash: hash.to_string(),
+                    scopes: config.htpasswd_scopes.clone(),
+                });
+            }
+        }
+        for (i, user) in users.iter().enumerate() {
+            check_hash(&user.password_hash)
+                .map_err(|e| format!("password of user '{}' {e}", user.name))?;
+            if users[..i].iter().any(|u| u.name == user.name) {
+                return Err(format!("user '{}' is a duplicate", user.name).into());
+            }
+        }
+        Ok(Self {
+            tokens: tokens.into(),
+            users: users.into(),
+            public_metrics: config.public_metrics,
+        })
+    }
+
+    /// Whether any tokens or users are required.
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+
+    /// Whether `/metrics` is served without a token.
+    pub fn public_metrics(&self) -> bool {
+        self.public_metrics
+    }
+
+    /// Check the value of an `Authorization` header against the `required`
+    /// scope, returning the name of the token or user.
+    pub async fn authorize(
+        &self,
+        authorization: Option<&str>,
+        required: Scope,
+    ) -> std::result::Result<&str, Denied> {
+        let authorization = authorization.ok_or(Denied::Unauthenticated)?;
+        if let Some(credentials) = authorization.strip_prefix("Basic ") {
+            return self.authorize_user(credentials, required).await;
+        }
+        let presented = authorization
+            .strip_prefix("Bearer ")
+            .ok_or(Denied::Unauthenticated)?;
+        // Every token is compared, without short-circuiting, so that the
+        // time taken doesn't reveal how close a guess was.
+        let token = self
+            .tokens
+            .iter()
+            .fold(None, |found, token| {
+                let matches = constant_time_eq(token.token.as_bytes(), presented.as_bytes());
+                found.or(matches.then_some(token))
+            })
+            .ok_or(Denied::Unauthenticated)?;
+        if !token.scopes.iter().any(|scope| scope.allows(required)) {
+            return Err(Denied::Forbidden);
+        }
+        Ok(&token.name)
+    }
+
+    /// Check the base64 encoded `user:password` of basic authentication.
+    async fn authorize_user(
+        &self,
+        credentials: &str,
+        required: Scope,
+    ) -> std::result::Result<&str, Denied> {
+        let decoded = STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or(Denied::Unauthenticated)?;
+        let (name, password) = decoded.split_once(':').ok_or(Denied::Unauthenticated)?;
+        let user = self.users.iter().position(|user| user.name == name);
+        let users = Arc::clone(&self.users);
+        let password = password.to_string();
+        // The password of an unknown user is still verified, so that the
+        // time taken doesn't reveal which users exist.
+        let verified = tokio::task::spawn_blocking(move || {
+            let hash = user.map_or(DUMMY_HASH, |i| &users[i].password_hash);
+            verify(&password, hash)
+        })
+        .await
+        .map_err(|_| Denied::Unauthenticated)?;
+        let user = match (user, verified) {
+            (Some(i), true) => &self.users[i],
+            _ => return Err(Denied::Unauthenticated),
+        };
+        if !user.scopes.iter().any(|scope| scope.allows(required)) {
+            return Err(Denied::Forbidden);
+        }
+        Ok(&user.name)
+    }
+
+    /// Whether basic authentication is accepted, which is then challenged
+    /// for along with tokens.
+    fn basic(&self) -> bool {
+        !self.users.is_empty()
+    }
+}
+
+/// Most rounds of the hashes of passwords, beyond which verifying the
+/// password of every request would be too slow.
+pub const MAX_ROUNDS: u32 = 100_000;
+
+/// Hash which the passwords of unknown users are verified against.
+const DUMMY_HASH: &str =
+    "$6$uppiesdummysalt$gJYvu95XUGbgkPVnxFBcCDhT7EvGSD9YcdOBWcu8vjQT.RlK4dW9tb\
+                          QQj6bILQ/0uTWnE8lbzUIBD2FQlNQ13/";
+
+/// Check that `hash` is a SHA-crypt hash, of the form
+/// `$6$rounds=N$salt$hash`, of at most [`MAX_ROUNDS`] rounds.
+fn check_hash(hash: &str) -> std::result::Result<(), &'static str> {
+    let rounds = hash
+        .strip_prefix("$5$")
+        .or_else(|| hash.strip_prefix("$6$"))
+        .ok_or("is not a SHA-crypt hash, such as of 'htpasswd -5'")?
+        .strip_prefix("rounds=")
+        .and_then(|rest| rest.split_once('$'));
+    if let Some((rounds, _)) = rounds {
+        let rounds: u32 = rounds.parse().map_err(|_| "has invalid rounds")?;
+        if rounds > MAX_ROUNDS {
+            return Err("has too many rounds to verify on every request");
+        }
+    }
+    match ShaCrypt::default().verify_password(b"", hash) {
+        Ok(()) | Err(password_hash::Error::PasswordInvalid) => Ok(()),
+        Err(_) => Err("is not a valid SHA-crypt hash"),
+    }
+}
+
+/// Whether `password` has the SHA-crypt `hash`.
+fn verify(password: &str, hash: &str) -> bool {
+    ShaCrypt::default()
+        .verify_password(password.as_bytes(), hash)
+        .is_ok()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Middleware which rejects requests without a token having the scope, for
+/// use with [`axum::middleware::from_fn_with_state`].
+pub async fn require(
+    State((tokens, scope)): State<(Tokens, Scope)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !tokens.enabled() {
+        return next.run(request).await;
+    }
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match tokens.authorize(authorization, scope).await {
+        Ok(name) => {
+            debug!(token = name, scope = scope.as_str(), path = %request.uri().path(), "authorized");
+            next.run(request).await
+        }
+        Err(denied) => {
+            warn!(scope = scope.as_str(), path = %request.uri().path(), ?denied, "request denied");
+            let mut response = denied.into_response();
+            if denied == Denied::Unauthenticated && tokens.basic() {
+                response.headers_mut().append(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic realm=\"uppies\""),
+                );
+            }
+            response
+        }
+    }
+}
+
+/// Builder of a client which sends `token` with every request, if any.
+pub fn client_builder(token: Option<&str>) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(token) = token {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {token}"))
+            .map_err(|_| "token contains invalid characters".to_string())?;
+        value.set_sensitive(true);
+        builder = builder.default_headers([(AUTHORIZATION, value)].into_iter().collect());
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{middleware::from_fn_with_state, routing::get, Router};
+    use tokio::net::TcpListener;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::{
+        check_hash, client_builder, require, verify, AuthConfig, Denied, Scope, TokenConfig,
+        Tokens, UserConfig, DUMMY_HASH,
+    };
+
+    #[test]
+    fn sha_crypt() {
+        // Hashes of `openssl passwd -5` and `-6`.
+        for hash in [
+            "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5",
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFd\
+             cbYEdFCoEOfaS35inz1",
+            "$5$rounds=10000$saltstringsaltst$3xv.VbSHBb41AL9AvLeujZkZRBAwqFMz2.opqey6IcA",
+        ] {
+            check_hash(hash).unwrap();
+            assert!(verify("Hello world!", hash), "{hash}");
+            assert!(!verify("Hello world?", hash), "{hash}");
+        }
+        check_hash(DUMMY_HASH).unwrap();
+        assert!(check_hash("$2y$05$bcrypt").is_err());
+        assert!(check_hash("$6$nosalt").is_err());
+        assert!(check_hash("$6$rounds=1000000$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8").is_err());
+    }
+
+    #[tokio::test]
+    async fn basic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("htpasswd");
+        std::fs::write(
+            &path,
+            "# scrapers\nprometheus:$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5\n",
+        )
+        .unwrap();
+        let tokens = Tokens::new(&AuthConfig {
+            users: vec![UserConfig {
+                name: "ops".to_string(),
+                password_hash: "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5"
+                    .to_string(),
+                scopes: vec![Scope::Admin],
+            }],
+            htpasswd_file: Some(path),
+            htpasswd_scopes: vec![Scope::Read],
+            ..Default::default()
+        })
+        .unwrap();
+        let tokens = &tokens;
+        let check = |credentials: &str, scope| {
+            let header = format!("Basic {}", STANDARD.encode(credentials));
+            async move { tokens.authorize(Some(&header), scope).await }
+        };
+        assert_eq!(
+            check("prometheus:Hello world!", Scope::Read).await,
+            Ok("prometheus")
+        );
+        assert_eq!(
+            check("prometheus:Hello world!", Scope::TargetsWrite).await,
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(
+            check("ops:Hello world!", Scope::TargetsWrite).await,
+            Ok("ops")
+        );
+        assert_eq!(
+            check("ops:wrong", Scope::Read).await,
+            Err(Denied::Unauthenticated)
+        );
+        assert_eq!(
+            check("nobody:Hello world!", Scope::Read).await,
+            Err(Denied::Unauthenticated)
+        );
+        assert_eq!(
+            tokens.authorize(Some("Basic !!!"), Scope::Read).await,
+            Err(Denied::Unauthenticated)
+        );
+
+        let invalid = Tokens::new(&AuthConfig {
+            users: vec![UserConfig {
+                name: "ops".to_string(),
+                password_hash: "plaintext".to_string(),
+                scopes: vec![Scope::Read],
+            }],
+            ..Default::default()
+        });
+        assert!(invalid.is_err());
+    }
+
+    fn tokens() -> Tokens {
+        let token = |name: &str, scopes| TokenConfig {
+            name: name.to_string(),
+            token: format!("{name}-secret"),
+            scopes,
+        };
+        Tokens::new(&AuthConfig {
+            tokens: vec![
+                token("grafana", vec![Scope::Read]),
+                token("ci", vec![Scope::TargetsWrite]),
+                token("root", vec![Scope::Admin]),
+            ],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn scopes() {
+        let tokens = &tokens();
+        let check = |token: &str, scope| {
+            let header = format!("Bearer {token}");
+            async move { tokens.authorize(Some(&header), scope).await }
+        };
+        assert_eq!(check("grafana-secret", Scope::Read).await, Ok("grafana"));
+        assert_eq!(
+            check("grafana-secret", Scope::TargetsWrite).await,
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(check("ci-secret", Scope::Read).await, Ok("ci"));
+        assert_eq!(check("ci-secret", Scope::TargetsWrite).await, Ok("ci"));
+        assert_eq!(
+            check("ci-secret", Scope::Admin).await,
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(check("root-secret", Scope::TargetsWrite).await, Ok("root"));
+        assert_eq!(
+            check("unknown", Scope::Read).await,
+            Err(Denied::Unauthenticated)
+        );
+        assert_eq!(
+            tokens.authorize(Some("grafana-secret"), Scope::Read).await,
+            Err(Denied::Unauthenticated),
+            "requires the bearer scheme"
+        );
+        assert_eq!(
+            tokens.authorize(None, Scope::Read).await,
+            Err(Denied::Unauthenticated)
+        );
+    }
+
+    #[tokio::test]
+    async fn token_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.toml");
+        std::fs::write(
+            &path,
+            "[[tokens]]\nname = \"ci\"\ntoken = \"abc\"\nscopes = [\"targets:write\"]\n",
+        )
+        .unwrap();
+        let tokens = Tokens::new(&AuthConfig {
+            token_file: Some(path),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            tokens
+                .authorize(Some("Bearer abc"), Scope::TargetsWrite)
+                .await,
+            Ok("ci")
+        );
+    }
+
+    #[tokio::test]
+    async fn middleware() {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(from_fn_with_state((tokens(), Scope::TargetsWrite), require));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let status = |token: Option<&'static str>| async move {
+            client_builder(token)
+                .unwrap()
+                .build()
+                .unwrap()
+                .get(format!("http://{addr}/"))
+                .send()
+                .await
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+        assert_eq!(status(None).await, 401);
+        assert_eq!(status(Some("grafana-secret")).await, 403);
+        assert_eq!(status(Some("ci-secret")).await, 200);
+    }
+}
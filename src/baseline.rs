@@ -0,0 +1,292 @@
+//! Baselines of the round-trip time of each probe of a target, learned from
+//! its pings
+//! over a long window, so that a current round-trip time can be judged
+//! against what is normal for that target rather than as a raw number. A
+//! 40ms round-trip is healthy for a distant server, but not for the router.
+//!
+//! The window is a [rolling window](crate::rolling) divided into slices. Once a slice is over, only the median
+//! of its pings is kept, and the baseline is the median of the slices
+//! weighted by their number of pings. Old slices are discarded as time moves
+//! on, so the baseline follows lasting changes such as a new ISP.
+//!
+//! Baselines are served at [`Baselines::PATH`], which `uppies top` shows the
+//! recent round-trip time of each target against.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{rolling::Slices, sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BaselineConfig {
+    /// Length of the window, in seconds, which the baseline is learned over.
+    #[serde(default = "BaselineConfig::default_window_secs")]
+    pub window_secs: u64,
+    /// Number of slices the window is divided into.
+    #[serde(default = "BaselineConfig::default_slices")]
+    pub slices: usize,
+}
+
+impl BaselineConfig {
+    fn default_window_secs() -> u64 {
+        7 * 86400
+    }
+
+    fn default_slices() -> usize {
+        7 * 24
+    }
+}
+
+impl Default for BaselineConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: Self::default_window_secs(),
+            slices: Self::default_slices(),
+        }
+    }
+}
+
+/// Baselines of all targets, as served at [`Baselines::PATH`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BaselineReport {
+    /// Length of the window which baselines are learned over.
+    pub window_secs: u64,
+    pub targets: Vec<Baseline>,
+}
+
+/// Baseline of a single probe of a target.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Baseline {
+    pub target: String,
+    /// Probe of the target, see [`ProbeTarget::probe`](crate::ProbeTarget::probe).
+    #[serde(default)]
+    pub probe: String,
+    /// Median round-trip time in milliseconds over the window.
+    pub median_ms: f64,
+    /// Number of successful pings within the window.
+    pub samples: u64,
+}
+
+/// Pings of a target within a single slice of the window.
+#[derive(Default)]
+struct Slice {
+    count: u64,
+    /// Round-trip times of the current slice, which are discarded for their
+    /// median once the slice is over.
+    samples: Vec<f64>,
+    median: f64,
+}
+
+impl Slice {
+    fn finish(&mut self) {
+        self.median = median(&mut self.samples);
+        self.samples = Vec::new();
+    }
+}
+
+/// Learned baselines of every target.
+///
+/// Clones share the same underlying state.
+#[derive(Clone)]
+pub struct Baselines {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    window: Duration,
+    slices: usize,
+    /// Slices for each probe of each target.
+    targets: Mutex<BTreeMap<(String, String), Slices<Slice>>>,
+}
+
+impl Baselines {
+    /// Path which the baselines of all targets are served at.
+    pub const PATH: &str = "/baseline";
+
+    pub fn new(config: &BaselineConfig) -> Result<Self> {
+        if config.window_secs == 0 {
+            return Err("baseline window_secs must be greater than 0"
+                .to_string()
+                .into());
+        }
+        let window = Duration::from_secs(config.window_secs);
+        Ok(Self {
+            inner: Arc::new(Inner {
+                window,
+                slices: config.slices,
+                targets: Mutex::new(BTreeMap::new()),
+            }),
+        })
+    }
+
+    /// Baselines of every probe of a target with pings within the window,
+    /// ordered by target and probe.
+    pub fn report(&self) -> BaselineReport {
+        BaselineReport {
+            window_secs: self.inner.window.as_secs(),
+            targets: self.inner.baselines(Instant::now()),
+        }
+    }
+}
+
+impl Inner {
+    fn observe(&self, target: &str, probe: &str, at: Instant, ms: f64) {
+        let mut targets = self.targets.lock().expect("baseline lock poisoned");
+        let slices = targets
+            .entry((target.to_string(), probe.to_string()))
+            .or_insert_with(|| Slices::new(self.window, self.slices));
+        slices.expire(at);
+        if slices.current(at).is_none() {
+            if let Some(slice) = slices.iter_mut().next_back() {
+                slice.finish();
+            }
+        }
+        let slice = slices.slice(at, Slice::default);
+        slice.count += 1;
+        slice.samples.push(ms);
+    }
+
+    fn baselines(&self, now: Instant) -> Vec<Baseline> {
+        let mut targets = self.targets.lock().expect("baseline lock poisoned");
+        targets.retain(|_, slices| {
+            slices.expire(now);
+            !slices.is_empty()
+        });
+        targets
+            .iter()
+            .map(|((target, probe), slices)| {
+                let mut medians: Vec<_> = slices
+                    .iter()
+                    .map(|slice| {
+                        let median = if slice.samples.is_empty() {
+                            slice.median
+                        } else {
+                            median(&mut slice.samples.clone())
+                        };
+                        (median, slice.count)
+                    })
+                    .collect();
+                medians.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+                let samples = medians.iter().map(|(_, count)| count).sum();
+                let mut cumulative = 0;
+                let median_ms = medians
+                    .iter()
+                    .find(|(_, count)| {
+                        cumulative += count;
+                        cumulative * 2 >= samples
+                    })
+                    .map_or(0.0, |(median, _)| *median);
+                Baseline {
+                    target: target.clone(),
+                    probe: probe.clone(),
+                    median_ms,
+                    samples,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Median of non-empty `samples`, which are sorted in place.
+fn median(samples: &mut [f64]) -> f64 {
+    samples.sort_by(f64::total_cmp);
+    let mid = samples.len() / 2;
+    if samples.len().is_multiple_of(2) {
+        (samples[mid - 1] + samples[mid]) / 2.0
+    } else {
+        samples[mid]
+    }
+}
+
+impl Sink for Baselines {
+    fn record(&self, outcome: &PingOutcome) {
+        if let Some(d) = outcome.latency() {
+            self.inner.observe(
+                &outcome.target,
+                &outcome.probe,
+                Instant::now(),
+                d.as_secs_f64() * 1000.0,
+            );
+        }
+    }
+}
+
+/// Fetch the baselines of the instance at `base_url`.
+pub async fn fetch(client: &reqwest::Client, base_url: &str) -> Result<BaselineReport> {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), Baselines::PATH);
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{BaselineConfig, Baselines};
+
+    fn baselines() -> Baselines {
+        Baselines::new(&BaselineConfig {
+            window_secs: 60,
+            slices: 6,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn weighted_median_of_slices() {
+        let baselines = baselines();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        for ms in [10.0, 12.0, 11.0] {
+            baselines.inner.observe("a", "icmp", at(0), ms);
+        }
+        baselines.inner.observe("a", "icmp", at(15), 100.0);
+        baselines.inner.observe("a", "tls", at(15), 40.0);
+        baselines.inner.observe("b", "icmp", at(15), 1.0);
+
+        let report = baselines.inner.baselines(at(20));
+        assert_eq!(report.len(), 3);
+        assert_eq!(
+            (report[1].probe.as_str(), report[1].median_ms),
+            ("tls", 40.0)
+        );
+        assert_eq!(report[0].target, "a");
+        assert_eq!(report[0].samples, 4);
+        assert_eq!(
+            report[0].median_ms, 11.0,
+            "spike in a smaller slice doesn't move the baseline"
+        );
+
+        // The first slice has left the window.
+        let report = baselines.inner.baselines(at(65));
+        assert_eq!(report[0].median_ms, 100.0);
+        assert_eq!(report[0].samples, 1);
+        assert!(baselines.inner.baselines(at(120)).is_empty());
+    }
+
+    #[test]
+    fn median() {
+        assert_eq!(super::median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(super::median(&mut [4.0, 1.0, 2.0, 3.0]), 2.5);
+    }
+
+    #[test]
+    fn invalid_window() {
+        let config = BaselineConfig {
+            window_secs: 0,
+            slices: 1,
+        };
+        assert!(Baselines::new(&config).is_err());
+    }
+}
@@ -1,86 +1,1882 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::BufReader,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
 use axum::{
-    extract::State,
-    http::{header::CONTENT_TYPE, Response},
+    body::Body,
+    extract::{ws::WebSocketUpgrade, Path, Query, State},
+    http::{
+        header::{ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY},
+        HeaderMap, Response, StatusCode,
+    },
+    middleware::from_fn_with_state,
     response::IntoResponse,
-    routing::get,
-    Router,
+    routing::{get, post, put},
+    Json, Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use prometheus::{Encoder, Registry, TextEncoder};
-use tokio::net::TcpListener;
-use tracing::{debug, info};
-use uppies::{ping_targets, PingSender, Result};
+use prometheus::Registry;
+use serde::Deserialize;
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+#[cfg(unix)]
+use uppies::daemon::{self, PidFile, Startup};
+#[cfg(target_os = "linux")]
+use uppies::systemd;
+use uppies::{
+    alerts::AlertEngine,
+    auth::{self, Scope, Tokens},
+    baseline::Baselines,
+    check,
+    cluster::Cluster,
+    config::Config,
+    destination::Destinations,
+    differential::DifferentialPing,
+    discovery::{
+        consul::ConsulDiscovery, etcd::EtcdDiscovery, http::HttpDiscovery, run_discovery,
+        DiscoveryMetrics,
+    },
+    encoding::{self, Compression, Framing},
+    events::{EventLog, EventQuery},
+    export::{self, ExportFormat, Table},
+    exporter::{
+        otlp::OtlpExporter,
+        pushgateway::PushgatewayExporter,
+        remote_write::{BasicAuth, RemoteWriteExporter},
+        run_exporter,
+    },
+    features::Features,
+    generate::Generator,
+    groups::TargetGroups,
+    health::HealthIndex,
+    history::{self, HistoryWriter},
+    latency::LatencyHealth,
+    launch::{Ramp, Readiness},
+    maintenance::Maintenance,
+    notify::Notifications,
+    openmetrics::{Exemplars, MetricsFormat},
+    parse_duration,
+    pause::Pauses,
+    ping_targets,
+    platform::Capabilities,
+    probe::{
+        arp::ArpProbes,
+        grpc::GrpcProbes,
+        icmp::{IcmpProbes, SocketSupport, SOCKET_PRIVILEGES},
+        mail::MailProbes,
+        neighbor::NeighborConfig,
+        ntp::NtpProbes,
+        ssh::SshProbes,
+        tls::TlsProbes,
+        BoxProbe, ModuleConfig, NeighborProbe,
+    },
+    process::ProcessMetrics,
+    range::{self, TargetSpec},
+    rolling::RollingHistogram,
+    scheduler::{Jitter, MissedTicks, SchedulerMode},
+    server::{self, ServerAddress},
+    sla::Availability,
+    slo::BurnRates,
+    slope::SlopeDetector,
+    state::StateTracker,
+    stream::{StreamSink, Subscription},
+    target::{ProbeTarget, Scheme},
+    targets::{Format, TargetList, TargetSet, TargetSources, TargetsFile},
+    top, tui, ChannelMode, PingSender, Result, UppiesError, DURATION_BUCKETS_MS,
+};
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Targets that should have pings sent to them.
-    targets: Vec<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Targets that should have pings sent to them, such as '1.1.1.1',
+    /// 'gateway=192.168.1.1' to label a target by an alias, or the range
+    /// '10.0.0.0/28' to ping each of its addresses.
+    targets: Vec<TargetSpec>,
+
+    /// File of targets to ping, one per line as given on the command line,
+    /// which is watched so that editing it adds and removes targets.
+    #[clap(long)]
+    targets_file: Option<PathBuf>,
+
+    /// Path to a TOML configuration file.
+    #[clap(long)]
+    config: Option<PathBuf>,
 
-    /// Socket to bind to serve metrics.
+    /// The whole configuration as JSON of the same structure as the TOML
+    /// file, instead of '--config'.
+    #[clap(
+        long,
+        env = "UPPIES_CONFIG_JSON",
+        hide_env_values = true,
+        conflicts_with = "config"
+    )]
+    config_json: Option<String>,
+
+    /// Socket to bind to serve metrics, or a Unix domain socket such as
+    /// 'unix:/run/uppies.sock'.
     #[clap(long, default_value = "0.0.0.0:9000")]
-    metrics_address: String,
+    metrics_address: ServerAddress,
+
+    /// Prefix of the names of the ping metrics, such as 'edge' for
+    /// 'edge_ping_success_count', to tell instances apart which are
+    /// scraped into the same Prometheus.
+    #[clap(long)]
+    namespace: Option<String>,
+
+    /// Length of time which in-flight requests are given to complete on
+    /// shutdown, before pinging is stopped, such as '30s'.
+    #[clap(long, default_value = "30s", value_parser = parse_duration)]
+    shutdown_grace_period: Duration,
+
+    /// Detach from the terminal and run in the background, as a
+    /// traditional daemon on hosts without a service manager. The command
+    /// returns once it is listening, or fails if it couldn't start. Logs
+    /// are discarded unless stdout is redirected, such as to a file.
+    #[clap(long)]
+    daemonize: bool,
+
+    /// File to write the process id into, such as for an init script to
+    /// signal. It is locked while uppies runs, so that a second instance
+    /// given the same file fails to start, and removed on shutdown.
+    #[clap(long)]
+    pid_file: Option<PathBuf>,
+
+    /// PEM file of the certificate chain to serve metrics and the API over
+    /// HTTPS with, rather than plaintext.
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of '--tls-cert'.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file of the CAs which clients must present a certificate issued
+    /// by, for mutual TLS.
+    #[clap(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
 
     /// Interval, in milliseconds, that should be between
     /// the continous pings to configured targets.
-    #[clap(long, default_value = "250")]
+    #[clap(long, default_value_t = PingSender::DEFAULT_PING_INTERVAL_MS)]
     ping_interval_ms: u64,
 
+    /// Channels which ping results are sent through: 'per-target' isolates
+    /// each target, whereas 'shared' uses less memory for large numbers of
+    /// targets.
+    ///
+    /// Defaults to 'shared' from 1000 targets, otherwise 'per-target'.
+    #[clap(long)]
+    channel_mode: Option<ChannelMode>,
+
+    /// Number of results of each target which can wait for the sinks,
+    /// beyond which results are dropped rather than delaying probes.
+    #[clap(long, default_value_t = PingSender::DEFAULT_CHANNEL_CAPACITY)]
+    channel_capacity: usize,
+
+    /// How probes are scheduled: 'tasks' runs a task per target, whereas
+    /// 'pool' probes targets in order of their deadlines with a bounded
+    /// pool of '--workers' workers.
+    ///
+    /// Defaults to 'pool' from 5000 targets, otherwise 'tasks'.
+    #[clap(long)]
+    scheduler_mode: Option<SchedulerMode>,
+
+    /// Number of workers of the 'pool' scheduler, which is the most probes
+    /// awaited at once.
+    #[clap(long, default_value = "512")]
+    workers: usize,
+
+    /// Start probing each target after a random offset within the ping
+    /// interval, rather than all at once, so that probes are spread across
+    /// the interval.
+    #[clap(long)]
+    spread_start: bool,
+
+    /// Delay each probe by up to this percentage of the ping interval, at
+    /// random, so that targets don't remain probed in step.
+    #[clap(long, default_value = "0")]
+    jitter_percent: f64,
+
+    /// Most probes of all targets each second, beyond which probes are
+    /// delayed, so that a large number of targets or a short interval can't
+    /// flood the network.
+    #[clap(long)]
+    max_pps: Option<f64>,
+
+    /// Interval, in milliseconds, of targets after a failure, so that an
+    /// outage is confirmed quickly. The interval doubles after each success
+    /// until it is back at '--ping-interval-ms'.
+    #[clap(long)]
+    fast_interval_ms: Option<u64>,
+
+    /// Length of time, in milliseconds, before a probe of any target is
+    /// considered failed, which limits probes whose own timeout is longer.
+    /// Targets can override it within 'timeouts' of the configuration.
+    #[clap(long, default_value = "10000")]
+    timeout_ms: u64,
+
+    /// What happens to the ticks which are missed as a probe took longer
+    /// than the interval: 'delay' probes immediately then at the interval
+    /// from then, 'burst' probes for each missed tick to catch up, and
+    /// 'skip' probes at the next tick.
+    #[clap(long, default_value = "delay")]
+    missed_ticks: MissedTicks,
+
+    /// Number of targets to start pinging at a time on startup, in the
+    /// order they are given, rather than all at once. '/ready' responds
+    /// with 503 until every target has been started.
+    #[clap(long)]
+    launch_batch_size: Option<usize>,
+
+    /// Interval, in milliseconds, between starting each batch of
+    /// '--launch-batch-size' targets.
+    #[clap(long, default_value = "1000", requires = "launch_batch_size")]
+    launch_interval_ms: u64,
+
+    /// Unique identifier of this instance when running as part of
+    /// a cluster. Clustering is disabled when this is unset.
+    ///
+    /// Of all reachable instances, the one with the lowest node id is
+    /// elected leader and is the only one to send notifications.
+    #[clap(long, requires = "cluster_peers")]
+    cluster_node_id: Option<String>,
+
+    /// Base URL of another uppies instance within the cluster, such as
+    /// 'http://10.0.0.2:9000'. Can be given multiple times.
+    #[clap(long = "cluster-peer")]
+    cluster_peers: Vec<String>,
+
+    /// Interval, in milliseconds, between polling cluster peers.
+    #[clap(long, default_value = "1000")]
+    cluster_heartbeat_ms: u64,
+
+    /// Token sent when polling cluster peers, which requires the 'read'
+    /// scope when peers have authentication enabled.
+    #[clap(long, env = "UPPIES_CLUSTER_TOKEN", hide_env_values = true)]
+    cluster_token: Option<String>,
+
+    /// OTLP/HTTP endpoint of an OpenTelemetry collector to push metrics
+    /// to, such as 'http://localhost:4318'.
+    ///
+    /// Metrics are still served for scraping when this is set.
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Interval, in milliseconds, between pushes to the OTLP endpoint.
+    #[clap(long, default_value = "10000")]
+    otlp_interval_ms: u64,
+
+    /// Prometheus remote write endpoint to push samples to, such as
+    /// 'http://prometheus:9090/api/v1/write'.
+    ///
+    /// Useful when uppies is running behind NAT and cannot be scraped.
+    #[clap(long)]
+    remote_write_url: Option<String>,
+
+    /// Username for basic authentication with the remote write endpoint.
+    #[clap(long, requires = "remote_write_url")]
+    remote_write_username: Option<String>,
+
+    /// Password for basic authentication with the remote write endpoint.
+    #[clap(long, env = "UPPIES_REMOTE_WRITE_PASSWORD", hide_env_values = true)]
+    remote_write_password: Option<String>,
+
+    /// Interval, in milliseconds, between pushes to the remote write endpoint.
+    #[clap(long, default_value = "15000")]
+    remote_write_interval_ms: u64,
+
+    /// Maximum number of series sent within a single remote write request.
+    #[clap(long, default_value = "500")]
+    remote_write_batch_size: usize,
+
+    /// Prometheus Pushgateway to push metrics to, such as
+    /// 'http://pushgateway:9091'.
+    #[clap(long)]
+    pushgateway_url: Option<String>,
+
+    /// Job name which metrics are pushed under.
+    #[clap(long, default_value = "uppies")]
+    pushgateway_job: String,
+
+    /// Grouping label, as 'name=value', identifying this instance within the
+    /// Pushgateway. Can be given multiple times.
+    ///
+    /// Defaults to an 'instance' label containing the hostname.
+    #[clap(long = "pushgateway-label", value_parser = parse_label)]
+    pushgateway_labels: Vec<(String, String)>,
+
+    /// Interval, in milliseconds, between pushes to the Pushgateway.
+    #[clap(long, default_value = "15000")]
+    pushgateway_interval_ms: u64,
+
+    /// File to record the history of all ping results into.
+    #[clap(long)]
+    history_file: Option<PathBuf>,
+
+    /// File containing the ed25519 key used to sign batches within the
+    /// history file. A new key is generated if the file does not exist.
+    #[clap(long, requires = "history_file")]
+    signing_key: Option<PathBuf>,
+
+    /// Number of results within each batch of the history file.
+    #[clap(long, default_value = "100")]
+    history_batch_size: usize,
+
+    /// Interval, in milliseconds, after which a partial batch is written
+    /// to the history file.
+    #[clap(long, default_value = "60000")]
+    history_flush_interval_ms: u64,
+
+    /// Format of the logs: 'text', or 'json' for a JSON object per line
+    /// with the fields of each event, such as the 'target', 'seq', 'rtt_ms'
+    /// and 'error_kind' of pings, at the top level.
+    #[clap(long, default_value = "text", global = true)]
+    log_format: LogFormat,
+
     #[command(flatten)]
     verbosity: Verbosity<InfoLevel>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Format which logs are written to stdout in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    /// A JSON object per line, for ingestion by log pipelines such as Loki.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown log format '{s}', expected 'text' or 'json'"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Inspect history files recorded with '--history-file'.
+    #[command(subcommand)]
+    Report(ReportCommand),
+
+    /// Print the configuration file upgraded to the current version.
+    MigrateConfig {
+        /// Path to the configuration file.
+        config: PathBuf,
+    },
+
+    /// Print the probes, sinks and other capabilities compiled into this
+    /// build as JSON.
+    Features,
+
+    /// Ping each target a fixed number of times, then exit with a status of
+    /// 0 if all are ok, 1 if any exceed a warning threshold or 2 if any exceed
+    /// a critical threshold.
+    Check(CheckArgs),
+
+    /// Check the configuration and targets which uppies would be started
+    /// with, without sending any probes, then exit with a status of 1 if
+    /// any would stop it from starting.
+    Validate(ValidateArgs),
+
+    /// Print Prometheus alerting rules or a Grafana dashboard for the
+    /// metrics of a configuration and its targets.
+    #[command(subcommand)]
+    Generate(GenerateCommand),
+
+    /// Live view of the targets of a running instance, sorted by their
+    /// recent loss or round-trip time.
+    Top {
+        /// Base URL of the instance, as served by '--metrics-address'.
+        #[clap(default_value = "http://127.0.0.1:9000")]
+        url: String,
+
+        /// Interval, in milliseconds, between redrawing the view.
+        #[clap(long, default_value = "500")]
+        refresh_ms: u64,
+
+        /// Token sent to the instance, which requires the 'read' scope, or
+        /// 'targets:write' to pause and resume targets.
+        #[clap(long, env = "UPPIES_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+
+    /// Terminal UI of the targets of a running instance, with a sparkline of
+    /// the round-trip times, loss and last error of each target.
+    Tui {
+        /// Base URL of the instance, as served by '--metrics-address'.
+        #[clap(default_value = "http://127.0.0.1:9000")]
+        url: String,
+
+        /// Interval, in milliseconds, between redrawing the view.
+        #[clap(long, default_value = "250")]
+        refresh_ms: u64,
+
+        /// Token sent to the instance, which requires the 'read' scope.
+        #[clap(long, env = "UPPIES_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+
+    /// Export the results recorded by a 'sqlite' sink as CSV or Parquet.
+    Export {
+        /// Path to the database of the sink.
+        database: PathBuf,
+
+        /// Format of the export: 'csv' or 'parquet'.
+        #[clap(long, default_value = "csv")]
+        format: ExportFormat,
+
+        /// Table to export: 'results', or 'aggregates' when the sink
+        /// downsamples.
+        #[clap(long, default_value = "results")]
+        table: Table,
+
+        /// Only rows at or after this time, either in milliseconds since the
+        /// unix epoch or a duration ago such as '7d'.
+        #[clap(long)]
+        since: Option<String>,
+
+        /// Only rows before this time, in the same form as '--since'.
+        #[clap(long)]
+        until: Option<String>,
+
+        /// File to write the export to, instead of stdout.
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct CheckArgs {
+    /// Targets to ping.
+    #[clap(required = true)]
+    targets: Vec<ProbeTarget>,
+
+    /// Number of pings sent to each target.
+    #[clap(long, short = 'c', default_value = "5")]
+    count: u64,
+
+    /// Interval, in milliseconds, between pings to each target.
+    #[clap(long, default_value = "1000")]
+    interval_ms: u64,
+
+    /// Time, in milliseconds, before a ping is considered failed.
+    #[clap(long, default_value = "2000")]
+    timeout_ms: u64,
+
+    /// Packet loss percentage above which a target is a warning.
+    #[clap(long)]
+    warning_loss: Option<f64>,
+
+    /// Packet loss percentage above which a target is critical.
+    #[clap(long, default_value = "20")]
+    critical_loss: f64,
+
+    /// Average round-trip time, in milliseconds, above which a target is
+    /// a warning.
+    #[clap(long)]
+    warning_rtt_ms: Option<f64>,
+
+    /// Average round-trip time, in milliseconds, above which a target is
+    /// critical.
+    #[clap(long)]
+    critical_rtt_ms: Option<f64>,
+
+    /// Format of the results: 'text', or 'nagios' for the status line and
+    /// performance data of a Nagios plugin.
+    #[clap(long, default_value = "text")]
+    output: check::Output,
+}
+
+#[derive(Debug, clap::Args)]
+struct ValidateArgs {
+    /// Targets as they would be given on the command line.
+    targets: Vec<TargetSpec>,
+
+    /// Path to the TOML configuration file.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// The whole configuration as JSON, as given to '--config-json'.
+    #[clap(
+        long,
+        env = "UPPIES_CONFIG_JSON",
+        hide_env_values = true,
+        conflicts_with = "config"
+    )]
+    config_json: Option<String>,
+
+    /// File of targets, as given to '--targets-file'.
+    #[clap(long)]
+    targets_file: Option<PathBuf>,
+
+    /// Socket which metrics would be served on, as given to
+    /// '--metrics-address'.
+    #[clap(long, default_value = "0.0.0.0:9000")]
+    metrics_address: ServerAddress,
+
+    /// PEM file of the certificate chain, as given to '--tls-cert'.
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key, as given to '--tls-key'.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file of the client CAs, as given to '--tls-client-ca'.
+    #[clap(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum GenerateCommand {
+    /// Print a Grafana dashboard of the targets as JSON.
+    Dashboards(GenerateArgs),
+    /// Print Prometheus alerting rules of the targets as YAML.
+    Rules(GenerateArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct GenerateArgs {
+    /// Targets as they would be given on the command line.
+    targets: Vec<TargetSpec>,
+
+    /// Path to the TOML configuration file.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// File of targets, as given to '--targets-file'.
+    #[clap(long)]
+    targets_file: Option<PathBuf>,
+
+    /// Prefix of the names of the ping metrics, as given to '--namespace'.
+    #[clap(long)]
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum ReportCommand {
+    /// Verify that a signed history file has not been tampered with.
+    Verify {
+        /// Path to the history file.
+        history_file: PathBuf,
+
+        /// Hex encoded public key which all batches must be signed by.
+        ///
+        /// When unset, batches must all be signed by the same key, which is
+        /// taken from the file itself, so their authenticity isn't checked.
+        #[clap(long)]
+        public_key: Option<String>,
+
+        /// Number of records known to have been written, such as logged by
+        /// the agent when it stopped, to detect batches removed from the end.
+        #[clap(long)]
+        records: Option<usize>,
+    },
+    /// Write every record of a history file to stdout, without verifying
+    /// it.
+    Export {
+        /// Path to the history file.
+        history_file: PathBuf,
+
+        /// Framing of the records: 'json' for a line each, or 'protobuf'
+        /// for length-delimited messages.
+        #[clap(long, default_value = "json")]
+        format: Framing,
+
+        /// Compression of the output: 'none', 'gzip' or 'zstd'.
+        #[clap(long, default_value = "none")]
+        compression: Compression,
+    },
+}
+
+fn main() -> Result<()> {
     let cli = Cli::parse();
+    #[cfg(not(unix))]
+    if cli.daemonize || cli.pid_file.is_some() {
+        return Err("--daemonize and --pid-file are only supported on Unix"
+            .to_string()
+            .into());
+    }
+    // The runtime is only started once daemonized, as its threads wouldn't
+    // survive the fork.
+    #[cfg(unix)]
+    let mut startup = cli.daemonize.then(daemon::daemonize).transpose()?;
+    #[cfg(not(unix))]
+    let mut startup = None;
+    let result = start(cli, &mut startup);
+    // Errors before the daemon started fail the command which started it.
+    match (&result, startup) {
+        (Ok(()), Some(startup)) => startup.started(),
+        (Err(e), Some(startup)) => startup.failed(e),
+        (_, None) => {}
+    }
+    result
+}
+
+fn start(cli: Cli, startup: &mut Option<Startup>) -> Result<()> {
+    #[cfg(unix)]
+    let _pid_file = cli.pid_file.as_deref().map(PidFile::create).transpose()?;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(cli, startup))
+}
+
+/// Startup of a daemon, which can't be on platforms other than Unix.
+#[cfg(not(unix))]
+enum Startup {}
 
-    tracing_subscriber::fmt()
+#[cfg(not(unix))]
+impl Startup {
+    fn started(self) {
+        match self {}
+    }
+
+    fn failed(self, _: &dyn std::fmt::Display) {
+        match self {}
+    }
+}
+
+/// Run uppies, reporting `startup` once it is listening if daemonized.
+async fn run(cli: Cli, startup: &mut Option<Startup>) -> Result<()> {
+    // Subcommands print their output to stdout, such as a dashboard to be
+    // redirected into a file, so their logs are kept apart on stderr.
+    let writer = match cli.command {
+        Some(_) => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    let logs = tracing_subscriber::fmt()
         .with_max_level(cli.verbosity)
-        .init();
+        .with_writer(writer);
+    match cli.log_format {
+        LogFormat::Text => logs.init(),
+        // The module of each event is left out, as it would collide with
+        // the `target` field of pings.
+        LogFormat::Json => logs.json().flatten_event(true).with_target(false).init(),
+    }
+
+    if let Some(command) = cli.command {
+        return match command {
+            Command::Report(ReportCommand::Verify {
+                history_file,
+                public_key,
+                records,
+            }) => {
+                let pinned = public_key
+                    .map(|k| history::parse_public_key(&k))
+                    .transpose()?;
+                let file = BufReader::new(std::fs::File::open(history_file)?);
+                let verified = history::verify(file, pinned)?;
+                if let Some(records) = records.filter(|&r| r != verified.records) {
+                    return Err(format!(
+                        "expected {records} records but found {}, records are missing",
+                        verified.records
+                    )
+                    .into());
+                }
+                println!(
+                    "verified {} batches containing {} records",
+                    verified.batches, verified.records
+                );
+                if let (None, Some(key)) = (pinned, verified.public_key) {
+                    println!(
+                        "batches were signed by {}, which was taken from the file itself, so \
+                         their authenticity was NOT checked: pass --public-key with the agent's \
+                         key to check it",
+                        hex::encode(key.to_bytes())
+                    );
+                }
+                if records.is_none() {
+                    println!("batches removed from the end can't be detected without --records");
+                }
+                Ok(())
+            }
+            Command::Report(ReportCommand::Export {
+                history_file,
+                format,
+                compression,
+            }) => {
+                let file = BufReader::new(std::fs::File::open(history_file)?);
+                let encoder = encoding::Encoder::new(format, compression)?;
+                let records = history::export(file, encoder, std::io::stdout().lock())?;
+                info!(records, "exported history");
+                Ok(())
+            }
+            Command::MigrateConfig { config } => {
+                print!("{}", Config::migrate(&std::fs::read_to_string(config)?)?);
+                Ok(())
+            }
+            Command::Features => {
+                println!("{}", serde_json::to_string_pretty(&Features::compiled())?);
+                Ok(())
+            }
+            Command::Check(args) => {
+                let output = args.output;
+                match check(args).await {
+                    Ok(status) => std::process::exit(status.exit_code()),
+                    // Nagios plugins report their own errors as unknown.
+                    Err(e) if output == check::Output::Nagios => {
+                        println!("PING UNKNOWN - {e}");
+                        std::process::exit(3);
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Command::Validate(args) => {
+                let problems = validate(args).await;
+                print!("{problems}");
+                if !problems.errors.is_empty() {
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+            Command::Generate(GenerateCommand::Dashboards(args)) => {
+                let (config, targets) = generated(&args)?;
+                let generator = Generator::new(
+                    &config,
+                    &targets,
+                    args.namespace.as_deref().unwrap_or_default(),
+                );
+                println!("{}", serde_json::to_string_pretty(&generator.dashboard())?);
+                Ok(())
+            }
+            Command::Generate(GenerateCommand::Rules(args)) => {
+                let (config, targets) = generated(&args)?;
+                let generator = Generator::new(
+                    &config,
+                    &targets,
+                    args.namespace.as_deref().unwrap_or_default(),
+                );
+                print!("{}", serde_yaml_ng::to_string(&generator.rules())?);
+                Ok(())
+            }
+            Command::Top {
+                url,
+                refresh_ms,
+                token,
+            } => top::run(&url, Duration::from_millis(refresh_ms), token.as_deref()).await,
+            Command::Tui {
+                url,
+                refresh_ms,
+                token,
+            } => tui::run(&url, Duration::from_millis(refresh_ms), token.as_deref()).await,
+            Command::Export {
+                database,
+                format,
+                table,
+                since,
+                until,
+                output,
+            } => {
+                let now = SystemTime::now();
+                let parse = |time: Option<String>| {
+                    time.map(|time| EventQuery::parse_since(&time, now))
+                        .transpose()
+                };
+                let (since, until) = (parse(since)?, parse(until)?);
+                let exported = match output {
+                    Some(path) => export::export(
+                        &database,
+                        table,
+                        since,
+                        until,
+                        format,
+                        std::fs::File::create(path)?,
+                    )?,
+                    None => {
+                        export::export(&database, table, since, until, format, std::io::stdout())?
+                    }
+                };
+                info!(rows = exported, "exported results");
+                Ok(())
+            }
+        };
+    }
+
+    let config = load_config(cli.config.as_deref(), cli.config_json.as_deref())?;
+    let ranges = config.ranges.clone().unwrap_or_default();
+    let (fixed, targets_file) = expand(cli.targets, &config, cli.targets_file.as_deref())?;
+    let file_targets = match &targets_file {
+        Some(file) => file.load()?,
+        None => Vec::new(),
+    };
+    let targets: Vec<_> = fixed.iter().chain(&file_targets).cloned().collect();
+    // Other configuration refers to targets by label.
+    let labels: BTreeSet<_> = targets.iter().map(ProbeTarget::label).collect();
 
     let metrics = Registry::default();
+    ProcessMetrics::new(&metrics)?;
+    let destinations = Destinations::new(&metrics)?;
+    let groups = TargetGroups::new(&config.groups, &metrics)?;
+    for (section, target) in unpinged(&config, &labels) {
+        warn!(target, "{section} target is not being pinged");
+    }
 
     info!(
-        targets = cli.targets.join(", "),
-        num_targets = cli.targets.len(),
+        targets = labels.iter().cloned().collect::<Vec<_>>().join(", "),
+        num_targets = targets.len(),
         ping_interval_ms = cli.ping_interval_ms,
         "init"
     );
-    let sender = PingSender::new(cli.targets, cli.ping_interval_ms, &metrics)?;
-    ping_targets(sender).await;
+    let cluster = match cli.cluster_node_id {
+        Some(node_id) => {
+            info!(
+                node_id,
+                peers = cli.cluster_peers.join(", "),
+                "clustering enabled"
+            );
+            let cluster = Cluster::new(
+                node_id,
+                cli.cluster_peers,
+                Duration::from_millis(cli.cluster_heartbeat_ms),
+                cli.cluster_token.as_deref(),
+                &metrics,
+            )?;
+            tokio::spawn(cluster.clone().run());
+            Some(cluster)
+        }
+        None => None,
+    };
 
-    let metric_listener = TcpListener::bind(&cli.metrics_address).await?;
-    tokio::spawn(async move {
-        let app = Router::new()
-            .route("/metrics", get(metrics_handler))
-            .with_state(AppState { metrics });
-        axum::serve(metric_listener, app).await.unwrap();
+    if let Some(endpoint) = cli.otlp_endpoint {
+        tokio::spawn(run_exporter(
+            OtlpExporter::new(&endpoint)?,
+            groups.clone(),
+            metrics.clone(),
+            Duration::from_millis(cli.otlp_interval_ms),
+            destinations.register("exporter", "otlp"),
+        ));
+    }
+    if let Some(url) = cli.remote_write_url {
+        let basic_auth = cli.remote_write_username.map(|username| BasicAuth {
+            username,
+            password: cli.remote_write_password,
+        });
+        tokio::spawn(run_exporter(
+            RemoteWriteExporter::new(url, basic_auth, cli.remote_write_batch_size)?,
+            groups.clone(),
+            metrics.clone(),
+            Duration::from_millis(cli.remote_write_interval_ms),
+            destinations.register("exporter", "remote_write"),
+        ));
+    }
+    if let Some(url) = cli.pushgateway_url {
+        tokio::spawn(run_exporter(
+            PushgatewayExporter::new(&url, &cli.pushgateway_job, cli.pushgateway_labels)?,
+            groups.clone(),
+            metrics.clone(),
+            Duration::from_millis(cli.pushgateway_interval_ms),
+            destinations.register("exporter", "pushgateway"),
+        ));
+    }
+
+    let icmp_socket = config.icmp.as_ref().map(|icmp| icmp.socket);
+    // Targets which can't be pinged are reported together, rather than as
+    // each fails to start.
+    let sockets = SocketSupport::check(icmp_socket.unwrap_or_default());
+    let unprobeable: Vec<_> = targets
+        .iter()
+        .filter_map(|target| Some(format!("{target}: {}", sockets.unprobeable(target)?)))
+        .collect();
+    if !unprobeable.is_empty() {
+        return Err(format!(
+            "cannot open ICMP sockets to ping {} targets, {SOCKET_PRIVILEGES}:\n  {}",
+            unprobeable.len(),
+            unprobeable.join("\n  ")
+        )
+        .into());
+    }
+    let capabilities = Capabilities::new(icmp_socket.unwrap_or_default(), &sockets);
+    info!(
+        os = capabilities.os,
+        icmp_socket = capabilities.icmp_socket.as_str(),
+        icmp_ipv4 = ?capabilities.icmp_ipv4,
+        icmp_ipv6 = ?capabilities.icmp_ipv6,
+        interface_scoping = capabilities.interface_scoping,
+        arp = capabilities.arp,
+        "platform capabilities"
+    );
+
+    let mut sender = PingSender::builder()
+        .with_ping_interval_ms(cli.ping_interval_ms)
+        .with_namespace(cli.namespace.as_deref().unwrap_or_default())
+        .with_registry(&metrics)
+        .build()?;
+    let protocols = Protocols::new(&config, None, &metrics)?;
+    for target in targets {
+        let probe = build_probe(&target, &config.neighbors, &protocols)?;
+        sender = sender.with_probe(target, probe);
+    }
+    let neighbors = config.neighbors.clone();
+    sender = sender.with_probe_factory(move |target| build_probe(target, &neighbors, &protocols));
+    sender = sender
+        .with_timeout(Duration::from_millis(cli.timeout_ms))
+        .with_missed_ticks(cli.missed_ticks);
+    for (target, timeout_ms) in &config.timeouts {
+        sender = sender.with_target_timeout(target, Duration::from_millis(*timeout_ms));
+    }
+    for (target, expect) in &config.expect {
+        sender = sender.with_target_expect(target, *expect);
+    }
+    if let Some(channel_mode) = cli.channel_mode {
+        sender = sender.with_channel_mode(channel_mode);
+    }
+    sender = sender.with_channel_capacity(cli.channel_capacity);
+    if let Some(scheduler_mode) = cli.scheduler_mode {
+        sender = sender.with_scheduler_mode(scheduler_mode);
+    }
+    sender = sender.with_workers(cli.workers).with_jitter(Jitter {
+        spread_start: cli.spread_start,
+        tick_percent: cli.jitter_percent,
     });
+    if let Some(max_pps) = cli.max_pps {
+        sender = sender.with_max_pps(max_pps);
+    }
+    if let Some(fast_interval_ms) = cli.fast_interval_ms {
+        sender = sender.with_fast_interval_ms(fast_interval_ms);
+    }
+    if let Some(batch_size) = cli.launch_batch_size {
+        sender = sender.with_ramp(Ramp {
+            batch_size: batch_size.max(1),
+            interval: Duration::from_millis(cli.launch_interval_ms),
+        });
+    }
+    for sink in &config.sinks {
+        sender = sender.with_sink(sink.build(destinations.register("sink", sink.name()))?);
+    }
+    #[cfg(feature = "chaos")]
+    if let Some(chaos) = &config.chaos {
+        warn!(?chaos, "chaos enabled, injecting failures into pings");
+        sender = sender.with_chaos(chaos.clone());
+    }
+    if let Some(slope) = &config.slope {
+        sender = sender.with_sink(Arc::new(SlopeDetector::new(slope, &metrics)?));
+    }
+    if let Some(rolling) = &config.rolling {
+        sender = sender.with_sink(Arc::new(RollingHistogram::new(
+            rolling,
+            DURATION_BUCKETS_MS.to_vec(),
+            &metrics,
+        )?));
+    }
+    if let Some(health) = &config.health {
+        sender = sender.with_sink(Arc::new(HealthIndex::new(health, &metrics)?));
+    }
+    if let Some(latency) = &config.latency {
+        sender = sender.with_sink(Arc::new(LatencyHealth::new(latency, &metrics)?));
+    }
+    if let Some(differential) = &config.differential {
+        sender = sender.with_sink(Arc::new(DifferentialPing::new(differential, &metrics)?));
+    }
+    let maintenance = Maintenance::new(&config.maintenance, &config.groups, &metrics)?;
+    sender = sender.with_sink(Arc::new(maintenance.clone()));
+    // State is always tracked, as it feeds the daemon health summary.
+    let mut state = StateTracker::new(&config.state.clone().unwrap_or_default(), &metrics)?
+        .with_maintenance(maintenance.clone());
+    if let Some(notify) = &config.notify {
+        let mut notifications = Notifications::new(notify, &metrics, &destinations)?;
+        if let Some(cluster) = &cluster {
+            notifications = notifications.with_cluster(cluster.clone());
+        }
+        state = state.with_notifications(notifications);
+    }
+    let events = EventLog::new(&config.events.clone().unwrap_or_default())?;
+    state = state.with_events(events.clone());
+    sender = sender.with_sink(Arc::new(state));
+    sender = sender.with_sink(Arc::new(groups.clone()));
+    let baselines = Baselines::new(&config.baseline.clone().unwrap_or_default())?;
+    sender = sender.with_sink(Arc::new(baselines.clone()));
+    let availability = Availability::new(&config.sla.clone().unwrap_or_default(), &metrics)?;
+    sender = sender.with_sink(Arc::new(availability.clone()));
+    if let Some(slo) = &config.slo {
+        sender = sender.with_sink(Arc::new(BurnRates::new(slo, &metrics)?));
+    }
+    let alerts = AlertEngine::new(&config.alerts, &metrics)?.with_maintenance(maintenance);
+    sender = sender.with_sink(Arc::new(alerts.clone()));
+    let stream = StreamSink::new();
+    sender = sender.with_sink(Arc::new(stream.clone()));
+    if let Some(path) = cli.history_file {
+        let key = cli
+            .signing_key
+            .map(|k| history::load_or_generate_key(&k))
+            .transpose()?;
+        info!(path = %path.display(), signed = key.is_some(), "recording history");
+        sender = sender.with_sink(Arc::new(HistoryWriter::spawn(
+            path,
+            key,
+            cli.history_batch_size,
+            Duration::from_millis(cli.history_flush_interval_ms),
+        )?));
+    }
+    let pauses = sender.pauses();
+    let target_set = sender.target_set();
+    let readiness = sender.readiness();
+    let liveness = sender.liveness();
+    let exemplars = sender.exemplars();
+    let tasks = ping_targets(sender).await;
+    let sources = TargetSources::new(target_set.clone(), fixed);
+    if let Some(discovery) = &config.discovery {
+        let interval = Duration::from_secs(discovery.interval_secs.max(1));
+        let discovery_metrics = DiscoveryMetrics::new(&metrics)?;
+        for consul in &discovery.consul {
+            tokio::spawn(run_discovery(
+                ConsulDiscovery::new(consul)?,
+                sources.clone(),
+                ranges.clone(),
+                interval,
+                discovery_metrics.clone(),
+            ));
+        }
+        for etcd in &discovery.etcd {
+            tokio::spawn(run_discovery(
+                EtcdDiscovery::new(etcd)?,
+                sources.clone(),
+                ranges.clone(),
+                interval,
+                discovery_metrics.clone(),
+            ));
+        }
+        for http in &discovery.http {
+            tokio::spawn(run_discovery(
+                HttpDiscovery::new(http)?,
+                sources.clone(),
+                ranges.clone(),
+                interval,
+                discovery_metrics.clone(),
+            ));
+        }
+    }
+    if let Some(file) = targets_file {
+        sources.insert(TargetsFile::SOURCE, file_targets);
+        let sources = sources.clone();
+        tokio::spawn(async move {
+            if let Err(e) = file.watch(sources).await {
+                error!(%e, "failed to watch targets file");
+            }
+        });
+    }
+
+    let tokens = Tokens::new(&config.auth.clone().unwrap_or_default())?;
+    if tokens.enabled() {
+        info!(
+            public_metrics = tokens.public_metrics(),
+            "authentication enabled"
+        );
+    }
 
-    tokio::signal::ctrl_c().await?;
+    let acceptor = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(server::acceptor(cert, key, cli.tls_client_ca.as_deref())?),
+        _ => None,
+    };
+    let metric_listener = cli.metrics_address.bind().await?;
+    if let Some(startup) = startup.take() {
+        startup.started();
+    }
+    let (shutdown, stopping) = tokio::sync::watch::channel(false);
+    // Dispatchers are stalled once none have ticked within a few intervals,
+    // beyond the longest a probe can take.
+    #[cfg(target_os = "linux")]
+    tokio::spawn(systemd::supervise(
+        readiness.clone(),
+        liveness,
+        target_set.clone(),
+        Duration::from_millis(cli.ping_interval_ms) * 3 + Duration::from_millis(cli.timeout_ms),
+    ));
+    let server = tokio::spawn(async move {
+        let metrics_route = Router::new().route("/metrics", get(metrics_handler));
+        let read = Router::new()
+            .route(Cluster::STATUS_PATH, get(cluster_handler))
+            .route(StreamSink::PATH, get(stream_handler))
+            .route(StreamSink::WS_PATH, get(ws_handler))
+            .route(AlertEngine::PATH, get(alerts_handler))
+            .route(EventLog::PATH, get(events_handler))
+            .route(Baselines::PATH, get(baseline_handler))
+            .route(Availability::PATH, get(sla_handler))
+            .route(Destinations::PATH, get(sinks_handler))
+            .route(Capabilities::PATH, get(capabilities_handler))
+            .route(Pauses::PATH, get(targets_handler));
+        let (read, metrics_route) = if tokens.public_metrics() {
+            (read, metrics_route)
+        } else {
+            (read.merge(metrics_route), Router::new())
+        };
+        let targets_write = Router::new()
+            .route(Pauses::PATH, put(reconcile_handler))
+            .route(
+                &format!("{}/{{target}}/{{action}}", Pauses::PATH),
+                post(pause_handler),
+            );
+        let closing = stream.clone();
+        let app = Router::new()
+            .route(Readiness::PATH, get(ready_handler))
+            .merge(metrics_route)
+            .merge(read.route_layer(from_fn_with_state(
+                (tokens.clone(), Scope::Read),
+                auth::require,
+            )))
+            .merge(targets_write.route_layer(from_fn_with_state(
+                (tokens, Scope::TargetsWrite),
+                auth::require,
+            )))
+            .with_state(AppState {
+                metrics,
+                groups,
+                cluster,
+                stream,
+                pauses,
+                alerts,
+                target_set,
+                readiness,
+                exemplars,
+                events,
+                baselines,
+                availability,
+                destinations,
+                capabilities,
+            });
+        let mut stopping = stopping;
+        let stopped = async move {
+            let _ = stopping.wait_for(|stopping| *stopping).await;
+            // Streams and WebSockets never end by themselves, so are ended
+            // rather than holding up the drain of other requests.
+            closing.close();
+        };
+        server::serve(metric_listener, acceptor, app, stopped).await
+    });
 
-    info!("shutting down");
+    shutdown_signal().await?;
+    info!(grace_period = ?cli.shutdown_grace_period, "shutting down");
+    #[cfg(target_os = "linux")]
+    if let Err(e) = systemd::notify("STOPPING=1") {
+        warn!(%e, "failed to notify systemd of stopping");
+    }
+    let _ = shutdown.send(true);
+    match tokio::time::timeout(cli.shutdown_grace_period, server).await {
+        Ok(Ok(Ok(()))) => info!("served in-flight requests"),
+        Ok(Ok(Err(e))) => error!(%e, "HTTP server failed"),
+        Ok(Err(e)) => error!(%e, "HTTP server panicked"),
+        Err(_) => warn!("in-flight requests outlived the grace period"),
+    }
+    let stopped = tasks.shutdown();
+    info!(stopped, "stopped pinging");
+    match tokio::time::timeout(cli.shutdown_grace_period, tasks.join()).await {
+        Ok(Ok(())) => info!("recorded in-flight results"),
+        Ok(Err(e)) => error!(%e, "recording results failed"),
+        Err(_) => warn!("in-flight results outlived the grace period"),
+    }
     Ok(())
 }
 
+/// Completes on ctrl-c or `SIGTERM`, as sent by a service manager, an init
+/// script or Kubernetes.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = tokio::signal::unix::signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Completes on ctrl-c, as there is no `SIGTERM` outside of Unix.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Run a one-shot check, printing the summary of each target and returning
+/// the worst status.
+async fn check(args: CheckArgs) -> Result<check::Status> {
+    let mut sender = PingSender::builder()
+        .with_ping_interval_ms(args.interval_ms)
+        .build()?;
+    let protocols = Protocols::new(&Config::default(), Some(args.timeout_ms), &Registry::new())?;
+    for target in args.targets {
+        let of = protocols.of(&target)?;
+        let probe = match of.probe(&target)? {
+            Some(probe) => probe,
+            None => of.icmp.probe(&target)?,
+        };
+        sender = sender.with_probe(target, probe);
+    }
+    let thresholds = check::Thresholds {
+        warning_loss_percent: args.warning_loss,
+        critical_loss_percent: Some(args.critical_loss),
+        warning_rtt_ms: args.warning_rtt_ms,
+        critical_rtt_ms: args.critical_rtt_ms,
+    };
+    let summaries = check::run(sender, args.count).await;
+    let (status, report) = check::report(&summaries, &thresholds, args.output);
+    print!("{report}");
+    Ok(status)
+}
+
+/// Configuration of the file at `path`, or else of the `json`, as given to
+/// '--config' and '--config-json'.
+fn load_config(path: Option<&std::path::Path>, json: Option<&str>) -> Result<Config> {
+    Ok(match (path, json) {
+        (Some(path), _) => Config::load(path)?,
+        (None, Some(json)) => {
+            Config::parse_json(json).map_err(|e| format!("invalid config json: {e}"))?
+        }
+        (None, None) => Config::default(),
+    })
+}
+
+/// Configuration and targets which monitoring is generated for.
+fn generated(args: &GenerateArgs) -> Result<(Config, Vec<ProbeTarget>)> {
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let (mut targets, targets_file) =
+        expand(args.targets.clone(), &config, args.targets_file.as_deref())?;
+    if let Some(file) = targets_file {
+        targets.extend(file.load()?);
+    }
+    Ok((config, targets))
+}
+
+/// Targets given by `specs` and those of `config`, with their ranges
+/// expanded, along with the file of further targets at `targets_file`.
+fn expand(
+    mut specs: Vec<TargetSpec>,
+    config: &Config,
+    targets_file: Option<&std::path::Path>,
+) -> Result<(Vec<ProbeTarget>, Option<TargetsFile>)> {
+    let ranges = config.ranges.clone().unwrap_or_default();
+    specs.extend(config.targets.iter().cloned());
+    let targets = range::expand(&specs, &ranges)?;
+    Ok((
+        targets,
+        targets_file.map(|path| TargetsFile::new(path, ranges)),
+    ))
+}
+
+/// Targets which sections of `config` refer to by label but which aren't
+/// among `labels`, with the section referring to each.
+fn unpinged<'a>(config: &'a Config, labels: &BTreeSet<String>) -> Vec<(&'static str, &'a str)> {
+    let mut referred = Vec::new();
+    referred.extend(config.groups.values().flatten().map(|t| ("group", t)));
+    referred.extend(config.neighbors.keys().map(|t| ("neighbor", t)));
+    referred.extend(config.timeouts.keys().map(|t| ("timeout", t)));
+    referred.extend(config.expect.keys().map(|t| ("expectation", t)));
+    if let Some(health) = &config.health {
+        referred.extend(health.targets.iter().map(|w| ("health index", &w.target)));
+    }
+    if let Some(differential) = &config.differential {
+        for pair in &differential.pairs {
+            referred.extend([
+                ("differential", &pair.target),
+                ("differential", &pair.control),
+            ]);
+        }
+    }
+    for window in &config.maintenance {
+        referred.extend(window.targets.iter().map(|t| ("maintenance", t)));
+    }
+    if let Some(slo) = &config.slo {
+        referred.extend(slo.targets.keys().map(|t| ("slo", t)));
+    }
+    if let Some(latency) = &config.latency {
+        referred.extend(latency.targets.keys().map(|t| ("latency", t)));
+    }
+    referred
+        .into_iter()
+        .filter(|(_, target)| !labels.contains(*target))
+        .map(|(section, target)| (section, target.as_str()))
+        .collect()
+}
+
+/// Problems found by `uppies validate`: errors which would stop uppies
+/// starting, and warnings of likely mistakes.
+#[derive(Debug, Default)]
+struct Problems {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+    /// Number of targets which would be pinged.
+    targets: usize,
+}
+
+impl std::fmt::Display for Problems {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for warning in &self.warnings {
+            writeln!(f, "warning: {warning}")?;
+        }
+        for error in &self.errors {
+            writeln!(f, "error: {error}")?;
+        }
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        match self.errors.len() {
+            0 => writeln!(
+                f,
+                "configuration is valid, with {} target{}",
+                self.targets,
+                plural(self.targets)
+            ),
+            errors => writeln!(
+                f,
+                "configuration is invalid, with {errors} error{}",
+                plural(errors)
+            ),
+        }
+    }
+}
+
+/// Check everything uppies would be started with by `args` which can be
+/// checked without sending probes or writing files: the configuration, the
+/// targets and their probes, the sockets to ping them from and the address
+/// and certificates to serve on.
+async fn validate(args: ValidateArgs) -> Problems {
+    let mut problems = Problems::default();
+    let config = match load_config(args.config.as_deref(), args.config_json.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            problems.errors.push(e.to_string());
+            return problems;
+        }
+    };
+    let (mut targets, targets_file) =
+        match expand(args.targets, &config, args.targets_file.as_deref()) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                problems.errors.push(format!("invalid targets: {e}"));
+                return problems;
+            }
+        };
+    if let Some(file) = targets_file {
+        match file.load() {
+            Ok(file_targets) => targets.extend(file_targets),
+            Err(e) => problems.errors.push(e.to_string()),
+        }
+    }
+    problems.targets = targets.len();
+    if targets.is_empty() && config.discovery.is_none() {
+        problems
+            .warnings
+            .push("no targets are given, nor discovered".to_string());
+    }
+    let labels: BTreeSet<_> = targets.iter().map(ProbeTarget::label).collect();
+    for (section, target) in unpinged(&config, &labels) {
+        problems
+            .warnings
+            .push(format!("{section} target '{target}' is not being pinged"));
+    }
+
+    // Unresolvable hostnames are probed regardless, in case they resolve
+    // later, but are usually a typo.
+    for target in targets.iter().filter(|t| t.is_hostname()) {
+        if let Err(e) = tokio::net::lookup_host((target.host(), 0)).await {
+            problems
+                .warnings
+                .push(format!("cannot resolve the hostname of '{target}': {e}"));
+        }
+    }
+    let sockets = SocketSupport::check(config.icmp.as_ref().map(|i| i.socket).unwrap_or_default());
+    for target in &targets {
+        if let Some(e) = sockets.unprobeable(target) {
+            problems.errors.push(format!(
+                "cannot open an ICMP socket to ping '{target}', {SOCKET_PRIVILEGES}: {e}"
+            ));
+        }
+    }
+
+    // Everything which registers metrics is built against a registry of
+    // its own, which is then discarded.
+    let metrics = Registry::new();
+    let destinations = Destinations::new(&metrics).expect("registry is empty");
+    match Protocols::new(&config, None, &metrics) {
+        Ok(protocols) => {
+            for target in &targets {
+                if let Err(e) = build_probe(target, &config.neighbors, &protocols) {
+                    problems
+                        .errors
+                        .push(format!("cannot probe '{target}': {e}"));
+                }
+            }
+        }
+        Err(e) => problems.errors.push(e.to_string()),
+    }
+    let sections: [(&str, Result<()>); 14] = [
+        (
+            "groups",
+            TargetGroups::new(&config.groups, &metrics).map(drop),
+        ),
+        (
+            "maintenance",
+            Maintenance::new(&config.maintenance, &config.groups, &metrics).map(drop),
+        ),
+        (
+            "state",
+            StateTracker::new(&config.state.clone().unwrap_or_default(), &metrics).map(drop),
+        ),
+        (
+            "baseline",
+            Baselines::new(&config.baseline.clone().unwrap_or_default()).map(drop),
+        ),
+        (
+            "sla",
+            Availability::new(&config.sla.clone().unwrap_or_default(), &metrics).map(drop),
+        ),
+        (
+            "alerts",
+            AlertEngine::new(&config.alerts, &metrics).map(drop),
+        ),
+        (
+            "auth",
+            Tokens::new(&config.auth.clone().unwrap_or_default()).map(drop),
+        ),
+        (
+            "slope",
+            config.slope.as_ref().map_or(Ok(()), |slope| {
+                SlopeDetector::new(slope, &metrics).map(drop)
+            }),
+        ),
+        (
+            "rolling",
+            config.rolling.as_ref().map_or(Ok(()), |rolling| {
+                RollingHistogram::new(rolling, DURATION_BUCKETS_MS.to_vec(), &metrics).map(drop)
+            }),
+        ),
+        (
+            "health",
+            config.health.as_ref().map_or(Ok(()), |health| {
+                HealthIndex::new(health, &metrics).map(drop)
+            }),
+        ),
+        (
+            "latency",
+            config.latency.as_ref().map_or(Ok(()), |latency| {
+                LatencyHealth::new(latency, &metrics).map(drop)
+            }),
+        ),
+        (
+            "differential",
+            config.differential.as_ref().map_or(Ok(()), |differential| {
+                DifferentialPing::new(differential, &metrics).map(drop)
+            }),
+        ),
+        (
+            "slo",
+            config
+                .slo
+                .as_ref()
+                .map_or(Ok(()), |slo| BurnRates::new(slo, &metrics).map(drop)),
+        ),
+        (
+            "notify",
+            config.notify.as_ref().map_or(Ok(()), |notify| {
+                Notifications::new(notify, &metrics, &destinations).map(drop)
+            }),
+        ),
+    ];
+    for (section, result) in sections {
+        if let Err(e) = result {
+            problems.errors.push(format!("invalid [{section}]: {e}"));
+        }
+    }
+
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        if let Err(e) = server::acceptor(cert, key, args.tls_client_ca.as_deref()) {
+            problems
+                .errors
+                .push(format!("invalid TLS certificate: {e}"));
+        }
+    }
+    match &args.metrics_address {
+        // The listener is closed straight away, so nothing is served.
+        ServerAddress::Tcp(address) => {
+            if let Err(e) = tokio::net::TcpListener::bind(address).await {
+                problems.errors.push(format!(
+                    "cannot serve metrics on {address}: {e}, is it already in use?"
+                ));
+            }
+        }
+        // Binding would replace the socket of a running instance, so only
+        // its directory is checked.
+        #[cfg(unix)]
+        ServerAddress::Unix(path) => {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            if !dir.is_none_or(|dir| dir.is_dir()) {
+                problems.errors.push(format!(
+                    "cannot serve metrics on {}, its directory doesn't exist",
+                    args.metrics_address
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Builders of the probes of targets by scheme, and those of each module.
+#[derive(Clone)]
+struct Protocols {
+    icmp: IcmpProbes,
+    tls: TlsProbes,
+    grpc: GrpcProbes,
+    ssh: SshProbes,
+    mail: MailProbes,
+    ntp: NtpProbes,
+    arp: ArpProbes,
+    /// Scheme and builders of each module, whose own modules are empty.
+    modules: Arc<BTreeMap<String, (Scheme, Protocols)>>,
+}
+
+impl Protocols {
+    /// Build the probes of `config`, with `timeout_ms` instead of those
+    /// configured if given.
+    fn new(config: &Config, timeout_ms: Option<u64>, metrics: &Registry) -> Result<Self> {
+        let mut tls = config.tls.clone().unwrap_or_default();
+        let mut grpc = config.grpc.clone().unwrap_or_default();
+        let mut ssh = config.ssh.clone().unwrap_or_default();
+        let mut mail = config.mail.clone().unwrap_or_default();
+        let mut ntp = config.ntp.clone().unwrap_or_default();
+        let mut arp = config.arp.clone().unwrap_or_default();
+        let mut icmp = config.icmp.clone().unwrap_or_default();
+        if let Some(timeout_ms) = timeout_ms {
+            icmp.timeout_ms = Some(timeout_ms);
+            tls.timeout_ms = timeout_ms;
+            grpc.timeout_ms = timeout_ms;
+            ssh.timeout_ms = timeout_ms;
+            mail.timeout_ms = timeout_ms;
+            ntp.timeout_ms = timeout_ms;
+            arp.timeout_ms = timeout_ms;
+        }
+        let tls = TlsProbes::new(&tls, metrics)?;
+        let mut protocols = Self {
+            icmp: IcmpProbes::new(&icmp, metrics)?,
+            mail: MailProbes::new(&mail, &tls)?,
+            tls,
+            grpc: GrpcProbes::new(&grpc, metrics)?,
+            ssh: SshProbes::new(&ssh),
+            ntp: NtpProbes::new(&ntp, metrics)?,
+            arp: ArpProbes::new(&arp),
+            modules: Arc::default(),
+        };
+        let mut modules = BTreeMap::new();
+        for (name, module) in &config.modules {
+            let built = protocols
+                .with_module(module)
+                .map_err(|e| format!("invalid module '{name}': {e}"))?;
+            modules.insert(name.clone(), (module.scheme(), built));
+        }
+        protocols.modules = Arc::new(modules);
+        Ok(protocols)
+    }
+
+    /// Builders with the parameters of `module` for its scheme, sharing
+    /// the metrics of these.
+    fn with_module(&self, module: &ModuleConfig) -> Result<Self> {
+        let mut protocols = self.clone();
+        match module {
+            ModuleConfig::Icmp(icmp) => protocols.icmp = self.icmp.with_config(icmp),
+            ModuleConfig::Tls(tls) => protocols.tls = self.tls.with_config(tls)?,
+            ModuleConfig::Grpc(grpc) => protocols.grpc = self.grpc.with_config(grpc),
+            ModuleConfig::Ssh(ssh) => protocols.ssh = SshProbes::new(ssh),
+            ModuleConfig::Smtp(mail) | ModuleConfig::Imap(mail) => {
+                protocols.mail = MailProbes::new(mail, &self.tls)?
+            }
+            ModuleConfig::Ntp(ntp) => protocols.ntp = self.ntp.with_config(ntp),
+            ModuleConfig::Arp(arp) => protocols.arp = ArpProbes::new(arp),
+        }
+        Ok(protocols)
+    }
+
+    /// Builders of `target`, which are those of its module if it has one.
+    fn of(&self, target: &ProbeTarget) -> Result<&Self> {
+        let Some(name) = target.module() else {
+            return Ok(self);
+        };
+        match self.modules.get(name) {
+            Some((scheme, protocols)) if *scheme == target.scheme() => Ok(protocols),
+            Some((scheme, _)) => Err(UppiesError::InvalidTarget(format!(
+                "module '{name}' of '{target}' probes {} targets, not {}",
+                scheme.as_str(),
+                target.scheme().as_str()
+            ))),
+            None => Err(UppiesError::InvalidTarget(format!(
+                "unknown module '{name}' of '{target}'"
+            ))),
+        }
+    }
+
+    /// Probe of `target` by the builder of its scheme, or `None` if it is
+    /// an ICMP target.
+    fn probe(&self, target: &ProbeTarget) -> Result<Option<BoxProbe>> {
+        Ok(Some(match target.scheme() {
+            Scheme::Icmp => return Ok(None),
+            Scheme::Tls => BoxProbe::new(self.tls.probe(target)?),
+            Scheme::Grpc => BoxProbe::new(self.grpc.probe(target)?),
+            Scheme::Ssh => BoxProbe::new(self.ssh.probe(target)?),
+            Scheme::Smtp | Scheme::Imap => BoxProbe::new(self.mail.probe(target)?),
+            Scheme::Ntp => BoxProbe::new(self.ntp.probe(target)?),
+            Scheme::Arp => BoxProbe::new(self.arp.probe(target)?),
+        }))
+    }
+}
+
+/// Build the probe of `target`, checking the neighbor entry of ICMP targets
+/// if configured under their label.
+fn build_probe(
+    target: &ProbeTarget,
+    neighbors: &BTreeMap<String, NeighborConfig>,
+    protocols: &Protocols,
+) -> Result<BoxProbe> {
+    let protocols = protocols.of(target)?;
+    if let Some(probe) = protocols.probe(target)? {
+        return Ok(probe);
+    }
+    let probe = protocols.icmp.probe(target)?;
+    Ok(match neighbors.get(&target.label()) {
+        Some(neighbor) => {
+            info!(target = %target, pinned = neighbor.pin, "checking neighbor entry");
+            BoxProbe::new(NeighborProbe::new(probe, target.host(), neighbor)?)
+        }
+        None => BoxProbe::new(probe),
+    })
+}
+
+/// Parse a label given as 'name=value'.
+fn parse_label(label: &str) -> Result<(String, String)> {
+    let (name, value) = label
+        .split_once('=')
+        .ok_or_else(|| format!("label '{label}' must be of the form 'name=value'"))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
 #[derive(Clone)]
 struct AppState {
     metrics: Registry,
+    groups: TargetGroups,
+    cluster: Option<Cluster>,
+    stream: StreamSink,
+    pauses: Pauses,
+    alerts: AlertEngine,
+    target_set: TargetSet,
+    readiness: Readiness,
+    exemplars: Exemplars,
+    events: EventLog,
+    baselines: Baselines,
+    availability: Availability,
+    destinations: Destinations,
+    capabilities: Capabilities,
 }
 
-async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
-    let text_encoder = TextEncoder::new();
-    let metric_family = state.metrics.gather();
+/// Metrics in the format negotiated by the `Accept` header of the request,
+/// see [`MetricsFormat`].
+async fn metrics_handler(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let metric_family = state.groups.gather(&state.metrics);
+    let format =
+        MetricsFormat::negotiate(headers.get(ACCEPT).and_then(|value| value.to_str().ok()));
+    let encoded_metrics = format.encode(&metric_family, &state.exemplars);
 
-    let encoded_metrics = text_encoder
-        .encode_to_string(&metric_family)
-        .expect("can encode known metrics");
-
-    debug!(?encoded_metrics);
+    debug!(?format, len = encoded_metrics.len(), "encoded metrics");
 
     Response::builder()
-        .header(CONTENT_TYPE, text_encoder.format_type())
-        .body(encoded_metrics)
+        .header(CONTENT_TYPE, format.content_type())
+        .header(VARY, "accept")
+        .body(Body::from(encoded_metrics))
+        .expect("valid response type")
+}
+
+/// Whether every target has been started, for readiness probes.
+async fn ready_handler(State(state): State<AppState>) -> impl IntoResponse {
+    if state.readiness.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "starting targets")
+    }
+}
+
+async fn cluster_handler(State(state): State<AppState>) -> impl IntoResponse {
+    match state.cluster {
+        Some(cluster) => Json(cluster.status()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Stream of ping results, framed by the `format` query and compressed by
+/// the `Accept-Encoding` of the request.
+async fn stream_handler(
+    State(state): State<AppState>,
+    Query(query): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let framing: Framing = match query.format.as_deref().unwrap_or("json").parse() {
+        Ok(framing) => framing,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let compression = Compression::negotiate(
+        headers
+            .get(ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let chunks = match state.stream.encoded(framing, compression) {
+        Ok(chunks) => chunks,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let mut response = Response::builder()
+        .header(CONTENT_TYPE, framing.content_type())
+        .header(VARY, "accept-encoding");
+    if let Some(encoding) = compression.content_encoding() {
+        response = response.header(CONTENT_ENCODING, encoding);
+    }
+    response
+        .body(Body::from_stream(chunks))
         .expect("valid response type")
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct WsQuery {
+    /// Comma separated targets to subscribe to, rather than all targets.
+    targets: Option<String>,
+}
+
+/// Stream of ping results over a WebSocket, optionally of only the given
+/// `targets` until the client subscribes to others.
+async fn ws_handler(
+    State(state): State<AppState>,
+    Query(query): Query<WsQuery>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let subscription = Subscription {
+        targets: query
+            .targets
+            .map(|targets| targets.split(',').map(str::to_string).collect()),
+    };
+    ws.on_upgrade(move |socket| async move { state.stream.websocket(socket, subscription).await })
+}
+
+async fn alerts_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.alerts.alerts())
+}
+
+async fn baseline_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.baselines.report())
+}
+
+async fn sla_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.availability.report())
+}
+
+async fn sinks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.destinations.report())
+}
+
+async fn capabilities_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.capabilities)
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    target: Option<String>,
+    since: Option<String>,
+}
+
+/// Changes of targets between up and down, optionally of a single `target`
+/// and `since` a timestamp or duration ago.
+async fn events_handler(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> impl IntoResponse {
+    let since_ms = match query
+        .since
+        .map(|since| EventQuery::parse_since(&since, SystemTime::now()))
+        .transpose()
+    {
+        Ok(since_ms) => since_ms,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    Json(state.events.query(&EventQuery {
+        target: query.target,
+        since_ms,
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    format: Option<String>,
+}
+
+/// Status of all targets, or with a `format` query, the list of targets
+/// in that format.
+async fn targets_handler(
+    State(state): State<AppState>,
+    Query(query): Query<FormatQuery>,
+) -> impl IntoResponse {
+    let Some(format) = query.format else {
+        return Json(state.pauses.statuses()).into_response();
+    };
+    let format: Format = match format.parse() {
+        Ok(format) => format,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let list = TargetList {
+        targets: state.target_set.targets(),
+    };
+    match list.render(format) {
+        Ok(body) => ([(CONTENT_TYPE, format.content_type())], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Reconcile the targets with the list in the body, given in the `format`
+/// query or JSON by default.
+async fn reconcile_handler(
+    State(state): State<AppState>,
+    Query(query): Query<FormatQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let format: Format = match query.format.as_deref().map(str::parse).transpose() {
+        Ok(format) => format.unwrap_or_default(),
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let list = match TargetList::parse(&body, format) {
+        Ok(list) => list,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    match state.target_set.reconcile(&list.targets) {
+        Ok(reconciled) => {
+            info!(?reconciled.added, ?reconciled.removed, "targets changed");
+            Json(reconciled).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+async fn pause_handler(
+    State(state): State<AppState>,
+    Path((target, action)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let paused = match action.as_str() {
+        "pause" => true,
+        "resume" => false,
+        _ => return StatusCode::NOT_FOUND,
+    };
+    if !state.pauses.set_paused(&target, paused) {
+        return StatusCode::NOT_FOUND;
+    }
+    info!(target, paused, "target pause changed");
+    StatusCode::NO_CONTENT
 }
@@ -0,0 +1,87 @@
+//! Failure injection for testing the monitoring pipeline.
+//!
+//! Injects synthetic ping failures, delays and dispatcher crashes at
+//! configurable rates, so that alerts, dashboards and dispatcher restarts can
+//! be verified before a real outage. This is only available when built with
+//! the `chaos` feature and should never be enabled in production.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+/// Rates are probabilities, from 0 to 1, applied to each ping.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosConfig {
+    /// Rate at which pings are reported as failed, regardless of the outcome.
+    #[serde(default)]
+    pub failure_rate: f64,
+    /// Rate at which results are delayed by `delay_ms`.
+    #[serde(default)]
+    pub delay_rate: f64,
+    /// Delay, in milliseconds, added to delayed results.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Rate at which the dispatcher crashes, requiring it to be restarted.
+    #[serde(default)]
+    pub crash_rate: f64,
+}
+
+/// Chaos injected into a single ping.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Injection {
+    None,
+    Failure,
+    Delay(Duration),
+    Crash,
+}
+
+impl ChaosConfig {
+    /// Decide the chaos to inject into the next ping.
+    pub fn inject(&self) -> Injection {
+        self.inject_with(rand::random::<f64>)
+    }
+
+    fn inject_with(&self, mut roll: impl FnMut() -> f64) -> Injection {
+        if roll() < self.crash_rate {
+            Injection::Crash
+        } else if roll() < self.failure_rate {
+            Injection::Failure
+        } else if roll() < self.delay_rate {
+            Injection::Delay(Duration::from_millis(self.delay_ms))
+        } else {
+            Injection::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ChaosConfig, Injection};
+
+    #[test]
+    fn rates() {
+        let none = ChaosConfig::default();
+        assert!((0..1000).all(|_| none.inject() == Injection::None));
+
+        let always = ChaosConfig {
+            failure_rate: 1.0,
+            ..Default::default()
+        };
+        assert!((0..1000).all(|_| always.inject() == Injection::Failure));
+
+        let delay = ChaosConfig {
+            failure_rate: 0.5,
+            delay_rate: 1.0,
+            delay_ms: 10,
+            ..Default::default()
+        };
+        let mut rolls = [0.9, 0.9, 0.1].into_iter();
+        assert_eq!(
+            delay.inject_with(|| rolls.next().unwrap()),
+            Injection::Delay(Duration::from_millis(10))
+        );
+    }
+}
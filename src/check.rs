@@ -0,0 +1,398 @@
+//! One-shot checks which ping each target a fixed number of times, for use
+//! in CI scripts and Nagios-style monitoring rather than as a daemon.
+
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use tokio_stream::StreamExt;
+
+use crate::PingSender;
+
+/// Result of a check, ordered from best to worst, with the exit code used
+/// by Nagios plugins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok,
+    Warning,
+    Critical,
+}
+
+impl Status {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Ok => 0,
+            Self::Warning => 1,
+            Self::Critical => 2,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Warning => "WARNING",
+            Self::Critical => "CRITICAL",
+        }
+    }
+}
+
+/// Format in which the results of a check are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Output {
+    /// A line summarising each target.
+    #[default]
+    Text,
+    /// The output of a Nagios plugin, as a single status line with
+    /// performance data compatible with `check_ping`.
+    Nagios,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "nagios" => Ok(Self::Nagios),
+            _ => Err(format!("unknown output '{s}', expected 'text' or 'nagios'")),
+        }
+    }
+}
+
+/// Limits of loss and average round-trip time, above which a target is
+/// considered to be in a warning or critical state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Thresholds {
+    pub warning_loss_percent: Option<f64>,
+    pub critical_loss_percent: Option<f64>,
+    pub warning_rtt_ms: Option<f64>,
+    pub critical_rtt_ms: Option<f64>,
+}
+
+/// Pings of a single target within a check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub target: String,
+    pub sent: u64,
+    pub received: u64,
+    /// Round-trip times, in milliseconds, of the successful pings.
+    pub rtts_ms: Vec<f64>,
+}
+
+impl Summary {
+    fn new(target: &str) -> Self {
+        Self {
+            target: target.to_string(),
+            sent: 0,
+            received: 0,
+            rtts_ms: Vec::new(),
+        }
+    }
+
+    /// Percentage of pings which failed.
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        (self.sent - self.received) as f64 / self.sent as f64 * 100.0
+    }
+
+    pub fn min_rtt_ms(&self) -> Option<f64> {
+        self.rtts_ms.iter().copied().reduce(f64::min)
+    }
+
+    pub fn avg_rtt_ms(&self) -> Option<f64> {
+        if self.rtts_ms.is_empty() {
+            return None;
+        }
+        Some(self.rtts_ms.iter().sum::<f64>() / self.rtts_ms.len() as f64)
+    }
+
+    pub fn max_rtt_ms(&self) -> Option<f64> {
+        self.rtts_ms.iter().copied().reduce(f64::max)
+    }
+
+    /// Status of the target against the thresholds, along with the reasons
+    /// it is not ok.
+    pub fn status(&self, thresholds: &Thresholds) -> (Status, Vec<String>) {
+        let loss = Some(self.loss_percent());
+        // A target without any replies has an unknown RTT, but is already
+        // judged on its loss.
+        let rtt = self.avg_rtt_ms();
+        let limits = [
+            (
+                Status::Critical,
+                "loss%",
+                loss,
+                thresholds.critical_loss_percent,
+            ),
+            (Status::Critical, "rtt ms", rtt, thresholds.critical_rtt_ms),
+            (
+                Status::Warning,
+                "loss%",
+                loss,
+                thresholds.warning_loss_percent,
+            ),
+            (Status::Warning, "rtt ms", rtt, thresholds.warning_rtt_ms),
+        ];
+        let exceeded: Vec<_> = limits
+            .into_iter()
+            .filter_map(|(level, what, value, limit)| {
+                let (value, limit) = (value?, limit?);
+                (value > limit).then(|| (level, format!("{what} {value:.1} > {limit:.1}")))
+            })
+            .collect();
+        let status = exceeded
+            .iter()
+            .map(|(level, _)| *level)
+            .max()
+            .unwrap_or(Status::Ok);
+        let reasons = exceeded
+            .into_iter()
+            .filter(|(level, _)| *level == status)
+            .map(|(_, reason)| reason)
+            .collect();
+        (status, reasons)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}/{} received, {:.1}% loss",
+            self.target,
+            self.received,
+            self.sent,
+            self.loss_percent()
+        )?;
+        if let (Some(min), Some(avg), Some(max)) =
+            (self.min_rtt_ms(), self.avg_rtt_ms(), self.max_rtt_ms())
+        {
+            write!(f, ", rtt min/avg/max = {min:.3}/{avg:.3}/{max:.3} ms")?;
+        }
+        Ok(())
+    }
+}
+
+/// Render the summaries in the `output` format, returning the worst status
+/// of all targets alongside.
+pub fn report(summaries: &[Summary], thresholds: &Thresholds, output: Output) -> (Status, String) {
+    let statuses: Vec<_> = summaries.iter().map(|s| s.status(thresholds)).collect();
+    let worst = statuses
+        .iter()
+        .map(|(status, _)| *status)
+        .max()
+        .unwrap_or(Status::Ok);
+    let report = match output {
+        Output::Text => summaries
+            .iter()
+            .zip(&statuses)
+            .map(|(summary, (status, reasons))| match status {
+                Status::Ok => format!("{summary}\n"),
+                _ => format!("{summary} - {}: {}\n", status.as_str(), reasons.join(", ")),
+            })
+            .collect(),
+        Output::Nagios => nagios(summaries, &statuses, thresholds, worst),
+    };
+    (worst, report)
+}
+
+/// Format a Nagios plugin status line, with the `rta` and `pl` performance
+/// data of each target.
+fn nagios(
+    summaries: &[Summary],
+    statuses: &[(Status, Vec<String>)],
+    thresholds: &Thresholds,
+    worst: Status,
+) -> String {
+    let rta = |summary: &Summary| summary.avg_rtt_ms().map(|ms| format!("{ms:.3}ms"));
+    let details: Vec<_> = summaries
+        .iter()
+        .zip(statuses)
+        .map(|(summary, (_, reasons))| {
+            let mut detail = format!(
+                "{}: rta {}, lost {:.0}%",
+                summary.target,
+                rta(summary).unwrap_or_else(|| "-".to_string()),
+                summary.loss_percent()
+            );
+            if !reasons.is_empty() {
+                detail += &format!(" ({})", reasons.join(", "));
+            }
+            detail
+        })
+        .collect();
+    let limit = |limit: Option<f64>| limit.map(|l| l.to_string()).unwrap_or_default();
+    let perfdata: Vec<_> = summaries
+        .iter()
+        .map(|summary| {
+            // Labels are only qualified by the target when there are several.
+            let label = |name: &str| match summaries.len() {
+                1 => name.to_string(),
+                _ => format!("'{} {name}'", summary.target),
+            };
+            format!(
+                "{}={};{};{};0; {}={:.0}%;{};{};0;100",
+                label("rta"),
+                rta(summary).unwrap_or_else(|| "U".to_string()),
+                limit(thresholds.warning_rtt_ms),
+                limit(thresholds.critical_rtt_ms),
+                label("pl"),
+                summary.loss_percent(),
+                limit(thresholds.warning_loss_percent),
+                limit(thresholds.critical_loss_percent),
+            )
+        })
+        .collect();
+    format!(
+        "PING {} - {}|{}\n",
+        worst.as_str(),
+        details.join("; "),
+        perfdata.join(" ")
+    )
+}
+
+/// Ping every target of the `sender` `count` times, returning a summary of
+/// each in the order they were added.
+pub async fn run(sender: PingSender, count: u64) -> Vec<Summary> {
+    let targets = sender.targets();
+    let mut summaries: HashMap<Arc<str>, Summary> = targets
+        .iter()
+        .map(|t| (Arc::clone(t), Summary::new(t)))
+        .collect();
+    let mut remaining = summaries.len();
+    let mut results = sender.results();
+    while remaining > 0 {
+        let Some(ping) = results.next().await else {
+            break;
+        };
+        let Some(summary) = summaries.get_mut(&ping.target) else {
+            continue;
+        };
+        if summary.sent == count {
+            continue;
+        }
+        summary.sent += 1;
+        if ping.rtt.is_ok() {
+            summary.received += 1;
+        }
+        if let Some(rtt) = ping.latency() {
+            summary.rtts_ms.push(rtt.as_secs_f64() * 1000.0);
+        }
+        if summary.sent == count {
+            remaining -= 1;
+        }
+    }
+    targets.iter().filter_map(|t| summaries.remove(t)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{report, run, Output, Status, Summary, Thresholds};
+    use crate::{probe::MockProbe, ErrorKind, PingSender};
+
+    fn summary(sent: u64, rtts_ms: &[f64]) -> Summary {
+        Summary {
+            target: "127.0.0.1".to_string(),
+            sent,
+            received: rtts_ms.len() as u64,
+            rtts_ms: rtts_ms.to_vec(),
+        }
+    }
+
+    #[test]
+    fn thresholds() {
+        let thresholds = Thresholds {
+            warning_loss_percent: Some(10.0),
+            critical_loss_percent: Some(50.0),
+            warning_rtt_ms: Some(100.0),
+            critical_rtt_ms: None,
+        };
+        assert_eq!(
+            summary(4, &[1.0; 4]).status(&thresholds),
+            (Status::Ok, vec![])
+        );
+        assert_eq!(
+            summary(4, &[150.0; 4]).status(&thresholds).0,
+            Status::Warning
+        );
+        assert_eq!(
+            summary(4, &[150.0; 1]).status(&thresholds),
+            (Status::Critical, vec!["loss% 75.0 > 50.0".to_string()]),
+            "only the reasons of the worst status are given"
+        );
+        assert_eq!(summary(4, &[]).status(&thresholds).0, Status::Critical);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            summary(2, &[1.0, 3.0]).to_string(),
+            "127.0.0.1: 2/2 received, 0.0% loss, rtt min/avg/max = 1.000/2.000/3.000 ms"
+        );
+        assert_eq!(
+            summary(2, &[]).to_string(),
+            "127.0.0.1: 0/2 received, 100.0% loss"
+        );
+    }
+
+    #[test]
+    fn nagios_output() {
+        let thresholds = Thresholds {
+            warning_rtt_ms: Some(100.0),
+            critical_loss_percent: Some(20.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            report(&[summary(2, &[1.0, 3.0])], &thresholds, Output::Nagios),
+            (
+                Status::Ok,
+                "PING OK - 127.0.0.1: rta 2.000ms, lost 0%|rta=2.000ms;100;;0; pl=0%;;20;0;100\n"
+                    .to_string()
+            )
+        );
+
+        let lost = Summary {
+            target: "10.0.0.1".to_string(),
+            ..summary(2, &[])
+        };
+        assert_eq!(
+            report(&[summary(2, &[1.0]), lost], &thresholds, Output::Nagios),
+            (
+                Status::Critical,
+                "PING CRITICAL - 127.0.0.1: rta 1.000ms, lost 50% (loss% 50.0 > 20.0); \
+                 10.0.0.1: rta -, lost 100% (loss% 100.0 > 20.0)|\
+                 '127.0.0.1 rta'=1.000ms;100;;0; '127.0.0.1 pl'=50%;;20;0;100 \
+                 '10.0.0.1 rta'=U;100;;0; '10.0.0.1 pl'=100%;;20;0;100\n"
+                    .to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn ping_each_target() {
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(10)
+            .build()
+            .unwrap()
+            .with_probe(
+                "b".parse().unwrap(),
+                MockProbe::new([Ok(Duration::from_millis(2))]),
+            )
+            .with_probe(
+                "a".parse().unwrap(),
+                MockProbe::new([Ok(Duration::from_millis(4)), Err(ErrorKind::Timeout)]),
+            );
+        let summaries = run(sender, 4).await;
+
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| (s.target.as_str(), s.sent, s.received))
+                .collect::<Vec<_>>(),
+            [("b", 4, 4), ("a", 4, 2)]
+        );
+        assert_eq!(summaries[1].avg_rtt_ms(), Some(4.0));
+    }
+}
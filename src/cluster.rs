@@ -0,0 +1,186 @@
+//! Leader election between multiple uppies instances probing the same targets.
+//!
+//! Every instance keeps probing (and reporting metrics for) all of its targets,
+//! but only the elected leader should send notifications, so that running
+//! a redundant pair doesn't result in duplicate pages.
+//!
+//! Election is intentionally simple: each instance is given a unique node id
+//! and the addresses of its peers. Peers are polled periodically and the live
+//! node with the lowest id is the leader. During a network partition both sides
+//! may consider themselves the leader, which errs on the side of duplicate
+//! notifications rather than none at all.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use prometheus::{IntGauge, Registry};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{auth, Result};
+
+/// Status of a node within the cluster, served to peers so that they can
+/// take part in the election.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NodeStatus {
+    /// Unique identifier of the node.
+    pub node_id: String,
+    /// Whether the node currently believes itself to be the leader.
+    pub leader: bool,
+}
+
+/// Membership of this instance within a cluster of uppies instances.
+#[derive(Clone)]
+pub struct Cluster {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    node_id: String,
+    /// Base URLs of peers, such as `http://10.0.0.2:9000`.
+    peers: Vec<String>,
+    heartbeat_interval: Duration,
+    client: reqwest::Client,
+    leader: AtomicBool,
+    is_leader: IntGauge,
+}
+
+impl Cluster {
+    /// Path which peers serve their [`NodeStatus`] on.
+    pub const STATUS_PATH: &str = "/cluster";
+
+    pub fn new(
+        node_id: String,
+        peers: Vec<String>,
+        heartbeat_interval: Duration,
+        token: Option<&str>,
+        metrics: &Registry,
+    ) -> Result<Self> {
+        let is_leader = IntGauge::new(
+            "cluster_is_leader",
+            "Whether this instance is the elected cluster leader",
+        )?;
+        metrics.register(Box::new(is_leader.clone()))?;
+
+        // Until peers have been contacted, assume that no other node is
+        // available. This avoids missing notifications on startup.
+        is_leader.set(1);
+        let client = auth::client_builder(token)?
+            .timeout(heartbeat_interval)
+            .build()?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                node_id,
+                peers,
+                heartbeat_interval,
+                client,
+                leader: AtomicBool::new(true),
+                is_leader,
+            }),
+        })
+    }
+
+    /// Whether this instance is the current leader and should
+    /// therefore send notifications.
+    pub fn is_leader(&self) -> bool {
+        self.inner.leader.load(Ordering::Relaxed)
+    }
+
+    /// Status of this node, as served to peers.
+    pub fn status(&self) -> NodeStatus {
+        NodeStatus {
+            node_id: self.inner.node_id.clone(),
+            leader: self.is_leader(),
+        }
+    }
+
+    /// Continuously poll peers and update the leadership status
+    /// of this instance.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.inner.heartbeat_interval);
+        loop {
+            interval.tick().await;
+            let mut alive = Vec::with_capacity(self.inner.peers.len());
+            for peer in &self.inner.peers {
+                match self.peer_status(peer).await {
+                    Ok(status) => alive.push(status.node_id),
+                    Err(e) => debug!(peer, ?e, "peer unavailable"),
+                }
+            }
+            self.update(elect(&self.inner.node_id, &alive));
+        }
+    }
+
+    async fn peer_status(&self, peer: &str) -> Result<NodeStatus> {
+        let url = format!("{}{}", peer.trim_end_matches('/'), Self::STATUS_PATH);
+        let status = self
+            .inner
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<NodeStatus>()
+            .await?;
+        if status.node_id == self.inner.node_id {
+            warn!(
+                peer,
+                node_id = status.node_id,
+                "peer has a duplicate node id"
+            );
+        }
+        Ok(status)
+    }
+
+    pub(crate) fn update(&self, leader: bool) {
+        let previous = self.inner.leader.swap(leader, Ordering::Relaxed);
+        if previous != leader {
+            info!(node_id = self.inner.node_id, leader, "leadership changed");
+        }
+        self.inner.is_leader.set(leader as i64);
+    }
+}
+
+/// Decide whether `node_id` is the leader given the ids of all peers
+/// which are currently reachable.
+fn elect(node_id: &str, alive_peers: &[String]) -> bool {
+    alive_peers.iter().all(|peer| node_id <= peer.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
+    use super::{elect, Cluster};
+
+    #[test]
+    fn lowest_id_is_leader() {
+        assert!(elect("a", &["b".to_string(), "c".to_string()]));
+        assert!(!elect("b", &["a".to_string(), "c".to_string()]));
+        assert!(elect("b", &[]), "a lone node should lead");
+    }
+
+    #[tokio::test]
+    async fn leader_without_reachable_peers() {
+        let cluster = Cluster::new(
+            "b".to_string(),
+            vec!["http://127.0.0.1:1".to_string()],
+            Duration::from_millis(50),
+            None,
+            &Registry::new(),
+        )
+        .unwrap();
+        tokio::spawn(cluster.clone().run());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(cluster.is_leader());
+        assert!(cluster.status().leader);
+    }
+}
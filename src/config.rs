@@ -0,0 +1,471 @@
+//! Configuration file for uppies, in TOML format.
+//!
+//! ```toml
+//! version = 1
+//! targets = ["1.1.1.1", "8.8.8.8"]
+//!
+//! [[sinks]]
+//! type = "statsd"
+//! address = "127.0.0.1:8125"
+//! dogstatsd = true
+//!
+//! [slope]
+//! threshold_ms_per_min = 5.0
+//! ```
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    alerts::AlertRule,
+    auth::AuthConfig,
+    baseline::BaselineConfig,
+    differential::DifferentialConfig,
+    discovery::DiscoveryConfig,
+    events::EventsConfig,
+    groups::GroupsConfig,
+    health::HealthConfig,
+    latency::LatencyConfig,
+    maintenance::MaintenanceWindow,
+    notify::NotifyConfig,
+    probe::{
+        arp::ArpConfig, grpc::GrpcConfig, icmp::IcmpConfig, mail::MailConfig,
+        neighbor::NeighborConfig, ntp::NtpConfig, ssh::SshConfig, tls::TlsConfig, ModuleConfig,
+    },
+    range::{RangeConfig, TargetSpec},
+    rolling::RollingConfig,
+    sink::SinkConfig,
+    sla::SlaConfig,
+    slo::SloConfig,
+    slope::SlopeConfig,
+    state::StateConfig,
+    Expect, Result,
+};
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Version of the configuration format.
+    #[serde(default)]
+    pub version: ConfigVersion,
+
+    /// Targets that should have pings sent to them, in addition
+    /// to those given on the command line, see
+    /// [`ProbeTarget`](crate::target::ProbeTarget). Ranges such
+    /// as `10.0.0.0/28` are expanded into a target of each address.
+    #[serde(default)]
+    pub targets: Vec<TargetSpec>,
+
+    /// Limits and exclusions of the expansion of ranges of targets.
+    pub ranges: Option<RangeConfig>,
+
+    /// Service registries which further targets are discovered from.
+    pub discovery: Option<DiscoveryConfig>,
+
+    /// Sinks which all ping results are sent to.
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+
+    /// Alerting on the rate of change of round-trip times.
+    pub slope: Option<SlopeConfig>,
+
+    /// Weighted health index across selected targets.
+    pub health: Option<HealthConfig>,
+
+    /// Classification of targets as ok, degraded or critical by their
+    /// round-trip times.
+    pub latency: Option<LatencyConfig>,
+
+    /// Comparison of targets with control targets in front of them.
+    pub differential: Option<DifferentialConfig>,
+
+    /// Histograms of ping durations over a recent window, alongside the
+    /// cumulative histograms.
+    pub rolling: Option<RollingConfig>,
+
+    /// Hysteresis of target state changes between up and down.
+    pub state: Option<StateConfig>,
+
+    /// Named groups of targets, which label the metrics of their targets
+    /// and are aggregated across them.
+    #[serde(default)]
+    pub groups: GroupsConfig,
+
+    /// Neighbor (ARP) entries of LAN targets, which are checked so that
+    /// layer 2 problems aren't counted as packet loss.
+    #[serde(default)]
+    pub neighbors: BTreeMap<String, NeighborConfig>,
+
+    /// Timeouts of the probes of targets by label, in milliseconds, rather
+    /// than that of every target.
+    #[serde(default)]
+    pub timeouts: BTreeMap<String, u64>,
+
+    /// Whether targets are expected to be up or down by label, such as a
+    /// decommissioned host which should stay offline, rather than up.
+    #[serde(default)]
+    pub expect: BTreeMap<String, Expect>,
+
+    /// Sockets and payloads of the pings of ICMP targets.
+    pub icmp: Option<IcmpConfig>,
+
+    /// Certificates trusted by the probes of `tls://` targets.
+    pub tls: Option<TlsConfig>,
+
+    /// Health checks of `grpc://` targets.
+    pub grpc: Option<GrpcConfig>,
+
+    /// Banner checks of `ssh://` targets.
+    pub ssh: Option<SshConfig>,
+
+    /// Greeting exchanges of `smtp://` and `imap://` targets.
+    pub mail: Option<MailConfig>,
+
+    /// Clock offset checks of `ntp://` targets.
+    pub ntp: Option<NtpConfig>,
+
+    /// Address resolution of `arp://` targets.
+    pub arp: Option<ArpConfig>,
+
+    /// Probers with their own parameters, which targets refer to by name
+    /// as in `1.1.1.1?module=icmp-fast`.
+    #[serde(default)]
+    pub modules: BTreeMap<String, ModuleConfig>,
+
+    /// Notifications of targets changing between up and down, using the
+    /// hysteresis of `state`.
+    pub notify: Option<NotifyConfig>,
+
+    /// Log of targets changing between up and down, served at `/events`.
+    pub events: Option<EventsConfig>,
+
+    /// Baselines of the round-trip time of each target, served at
+    /// `/baseline`.
+    pub baseline: Option<BaselineConfig>,
+
+    /// Windows which the availability of each target is computed over,
+    /// served at `/sla`.
+    pub sla: Option<SlaConfig>,
+
+    /// Latency objectives of targets, whose burn rates are exported over
+    /// several windows.
+    pub slo: Option<SloConfig>,
+
+    /// Threshold rules which are evaluated against the recent pings of
+    /// each target, see [`alerts`](crate::alerts).
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+
+    /// Windows during which targets are under maintenance, so that their
+    /// state changes aren't notified and alerts don't fire.
+    #[serde(default)]
+    pub maintenance: Vec<MaintenanceWindow>,
+
+    /// Tokens which are required to use the HTTP API, which is otherwise
+    /// open.
+    pub auth: Option<AuthConfig>,
+
+    /// Failure injection, for testing alerting and dashboards.
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<crate::chaos::ChaosConfig>,
+}
+
+/// Version of the configuration format, which defaults to the current version.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct ConfigVersion(pub u32);
+
+impl Default for ConfigVersion {
+    fn default() -> Self {
+        Self(Config::VERSION)
+    }
+}
+
+/// Migrations of the configuration format, where the migration at index `i`
+/// upgrades a file from version `i` to `i + 1`.
+const MIGRATIONS: &[fn(&mut toml::Table) -> Result<()>] = &[
+    // Files without a version predate versioning, but are otherwise
+    // identical to version 1.
+    |_| Ok(()),
+];
+
+impl Config {
+    /// Current version of the configuration format. Files of an older
+    /// version are migrated automatically.
+    pub const VERSION: u32 = MIGRATIONS.len() as u32;
+
+    /// Load the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read config file {}: {e}", path.display()))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("invalid config file {}: {e}", path.display()).into())
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let table: toml::Table = toml::from_str(contents)?;
+        if file_version(&table)? == Self::VERSION {
+            // Parse the original contents, so that errors refer to its lines.
+            return Ok(toml::from_str(contents)?);
+        }
+        warn!(
+            version = Self::VERSION,
+            "config file uses an old format, upgrade it with 'uppies migrate-config'"
+        );
+        Ok(toml::from_str(&migrate_table(table)?)?)
+    }
+
+    /// Parse the configuration from JSON of the same structure as the TOML
+    /// file, such as from the `UPPIES_CONFIG_JSON` environment variable.
+    pub fn parse_json(contents: &str) -> Result<Self> {
+        let table: toml::Table = serde_json::from_str(contents)?;
+        if file_version(&table)? == Self::VERSION {
+            // Parse the original contents, so that errors refer to them.
+            return Ok(serde_json::from_str(contents)?);
+        }
+        // Unversioned JSON is common, as it is rarely kept as a file, so it
+        // is upgraded without warning.
+        let upgraded = serde_json::to_value(upgrade_table(table)?)?;
+        Ok(serde_json::from_value(upgraded)?)
+    }
+
+    /// Upgrade the configuration file `contents` to the current version,
+    /// returning the upgraded file.
+    ///
+    /// Files which are already of the current version are returned
+    /// unchanged, otherwise comments and formatting are not retained.
+    pub fn migrate(contents: &str) -> Result<String> {
+        let table: toml::Table = toml::from_str(contents)?;
+        if file_version(&table)? == Self::VERSION {
+            return Ok(contents.to_string());
+        }
+        migrate_table(table)
+    }
+}
+
+/// Version of a configuration file, files without a version are version 0.
+fn file_version(table: &toml::Table) -> Result<u32> {
+    let version = match table.get("version") {
+        None => 0,
+        Some(toml::Value::Integer(v)) => {
+            u32::try_from(*v).map_err(|_| "invalid version".to_string())?
+        }
+        Some(_) => return Err("version must be an integer".to_string().into()),
+    };
+    if version > Config::VERSION {
+        return Err(format!(
+            "version {version} is newer than the supported version {}, upgrade uppies to use it",
+            Config::VERSION
+        )
+        .into());
+    }
+    Ok(version)
+}
+
+/// Apply all migrations from the version of `table`, serializing the
+/// upgraded file.
+fn migrate_table(table: toml::Table) -> Result<String> {
+    Ok(toml::to_string(&upgrade_table(table)?)?)
+}
+
+/// Apply all migrations from the version of `table`.
+fn upgrade_table(mut table: toml::Table) -> Result<toml::Table> {
+    let version = file_version(&table)?;
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut table)?;
+    }
+    table.remove("version");
+    let mut upgraded = toml::Table::new();
+    upgraded.insert("version".to_string(), i64::from(Config::VERSION).into());
+    upgraded.extend(table);
+    Ok(upgraded)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{Config, ConfigVersion};
+    use crate::{
+        probe::{
+            icmp::{Dscp, IcmpConfig, SocketType},
+            ModuleConfig,
+        },
+        range::TargetSpec,
+        sink::{statsd::StatsdConfig, SinkConfig},
+        target::Scheme,
+        Expect,
+    };
+
+    #[test]
+    fn parse_sinks() {
+        let config = Config::parse(
+            r#"
+            targets = ["127.0.0.1"]
+
+            [[sinks]]
+            type = "statsd"
+            address = "127.0.0.1:8125"
+
+            [[sinks]]
+            type = "statsd"
+            address = "127.0.0.1:8126"
+            prefix = ""
+            dogstatsd = true
+            tags = { env = "prod" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.targets, vec!["127.0.0.1".parse().unwrap()]);
+        assert_eq!(
+            config.sinks,
+            vec![
+                SinkConfig::Statsd(StatsdConfig {
+                    address: "127.0.0.1:8125".to_string(),
+                    prefix: "uppies.".to_string(),
+                    dogstatsd: false,
+                    tags: BTreeMap::new(),
+                }),
+                SinkConfig::Statsd(StatsdConfig {
+                    address: "127.0.0.1:8126".to_string(),
+                    prefix: "".to_string(),
+                    dogstatsd: true,
+                    tags: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_modules() {
+        let config = Config::parse(
+            r#"
+            targets = ["1.1.1.1?module=icmp-jumbo", "tls://example.com?module=internal"]
+
+            [modules.icmp-jumbo]
+            prober = "icmp"
+            payload_size = 8972
+            socket = "raw"
+            dscp = "EF"
+
+            [modules.internal]
+            prober = "tls"
+            native_roots = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.modules["icmp-jumbo"],
+            ModuleConfig::Icmp(IcmpConfig {
+                timeout_ms: None,
+                payload_size: 8972,
+                count: 0,
+                socket: SocketType::Raw,
+                dscp: Some(Dscp::EF),
+                ..Default::default()
+            })
+        );
+        assert_eq!(config.modules["internal"].scheme(), Scheme::Tls);
+        assert!(
+            matches!(&config.targets[1], TargetSpec::Target(t) if t.module() == Some("internal"))
+        );
+
+        let err = Config::parse("[modules.fast]\nprober = \"icmp\"\ntimeout = 5").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `timeout`"),
+            "unexpected error: {err}"
+        );
+        assert!(Config::parse("[modules.http]\nprober = \"http\"").is_err());
+    }
+
+    #[test]
+    fn empty_config() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        let old = "targets = [\"127.0.0.1\"]\n";
+        let migrated = Config::migrate(old).unwrap();
+        assert_eq!(migrated, "version = 1\ntargets = [\"127.0.0.1\"]\n");
+        assert_eq!(Config::migrate(&migrated).unwrap(), migrated);
+        assert_eq!(
+            Config::parse(old).unwrap(),
+            Config::parse(&migrated).unwrap()
+        );
+        assert_eq!(Config::parse(old).unwrap().version, ConfigVersion(1));
+    }
+
+    #[test]
+    fn parse_expect() {
+        let config = Config::parse("[expect]\nold-db = \"down\"\ngateway = \"up\"").unwrap();
+        assert_eq!(
+            config.expect,
+            BTreeMap::from([
+                ("gateway".to_string(), Expect::Up),
+                ("old-db".to_string(), Expect::Down),
+            ])
+        );
+        let err = Config::parse("[expect]\nold-db = \"offline\"").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown variant `offline`"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let err = Config::parse("version = 1\ntarget = []").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `target`"),
+            "unexpected error: {err}"
+        );
+        let err = Config::parse("[slope]\nthreshold_ms_per_min = 1.0\nwindow = 60").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `window`"),
+            "unexpected error: {err}"
+        );
+        let err = Config::parse("[[sinks]]\ntype = \"statsd\"\naddress = \"\"\nprefx = \"\"")
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `prefx`"),
+            "unexpected error: {err}"
+        );
+        let err = Config::parse("version = 100").unwrap_err();
+        assert!(
+            err.to_string().contains("newer than the supported version"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn parse_json() {
+        let json = r#"{
+            "targets": ["127.0.0.1"],
+            "sinks": [{"type": "statsd", "address": "127.0.0.1:8125"}],
+            "slope": {"threshold_ms_per_min": 1.5}
+        }"#;
+        let toml = r#"
+            targets = ["127.0.0.1"]
+            [[sinks]]
+            type = "statsd"
+            address = "127.0.0.1:8125"
+            [slope]
+            threshold_ms_per_min = 1.5
+        "#;
+        assert_eq!(
+            Config::parse_json(json).unwrap(),
+            Config::parse(toml).unwrap()
+        );
+
+        let err = Config::parse_json(r#"{"target": []}"#).unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `target`"),
+            "unexpected error: {err}"
+        );
+        assert!(Config::parse_json("[]").is_err());
+    }
+}
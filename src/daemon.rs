@@ -0,0 +1,205 @@
+//! Running as a traditional daemon on hosts without a service manager, by
+//! detaching from the terminal which started uppies and recording its
+//! process id in a PID file for init scripts to signal.
+//!
+//! The working directory is kept, unlike most daemons, so that relative
+//! paths of the configuration, targets file and history still resolve.
+//!
+//! The command which started uppies only returns once the daemon has
+//! started, as reported through a pipe by [`Startup`], so that invalid
+//! configuration or an address in use fails the command rather than only
+//! the detached daemon.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, PipeReader, PipeWriter, Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::Result;
+
+/// Detach from the terminal and session which started the process, leaving
+/// it running in the background once the command returns.
+///
+/// Must be called before any other threads are started, such as those of
+/// the tokio runtime, as only the calling thread survives a fork. Stdin is
+/// replaced with `/dev/null`, as are stdout and stderr when they are a
+/// terminal, so logs are only kept when they are redirected, such as to a
+/// file.
+///
+/// The command only returns once the daemon reports how its startup went
+/// through the returned [`Startup`], exiting unsuccessfully with the error
+/// of a failed startup.
+pub fn daemonize() -> Result<Startup> {
+    let (reader, writer) = io::pipe()?;
+    if fork()? {
+        drop(writer);
+        wait_for_startup(reader);
+    }
+    drop(reader);
+    // SAFETY: setsid has no preconditions.
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // A process which isn't the leader of its session can never acquire a
+    // controlling terminal again.
+    if fork()? {
+        std::process::exit(0);
+    }
+    detach_stdio()?;
+    Ok(Startup { writer })
+}
+
+/// Fork, returning whether this is the parent.
+fn fork() -> io::Result<bool> {
+    // SAFETY: the process is single threaded, see `daemonize`, so the child
+    // is left in a consistent state.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Exit once the daemon reports its startup through `reader`, successfully
+/// if it started.
+fn wait_for_startup(mut reader: PipeReader) -> ! {
+    let mut report = Vec::new();
+    let _ = reader.read_to_end(&mut report);
+    match report.split_first() {
+        Some((&STARTED, _)) => std::process::exit(0),
+        Some((&FAILED, error)) => {
+            eprintln!("Error: {}", String::from_utf8_lossy(error));
+        }
+        // The daemon exited, or was killed, without reporting.
+        _ => eprintln!("Error: uppies exited during startup"),
+    }
+    std::process::exit(1)
+}
+
+/// First byte of the report of a daemon which started, or failed to start
+/// and is followed by the error.
+const STARTED: u8 = 0;
+const FAILED: u8 = 1;
+
+/// Report of the startup of a daemon to the command which started it, which
+/// exits once either is reported.
+#[derive(Debug)]
+pub struct Startup {
+    writer: PipeWriter,
+}
+
+impl Startup {
+    /// Report that the daemon has started, such as once it is listening.
+    pub fn started(mut self) {
+        let _ = self.writer.write_all(&[STARTED]);
+    }
+
+    /// Report that the daemon failed to start with `error`.
+    pub fn failed(mut self, error: &dyn std::fmt::Display) {
+        let _ = self.writer.write_all(&[FAILED]);
+        let _ = write!(self.writer, "{error}");
+    }
+}
+
+fn detach_stdio() -> io::Result<()> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: isatty has no preconditions.
+        if fd != libc::STDIN_FILENO && unsafe { libc::isatty(fd) } == 0 {
+            continue;
+        }
+        // SAFETY: both descriptors are open, and `fd` is replaced atomically.
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// File containing the process id of uppies, which is locked while it runs
+/// so that a second instance given the same file fails to start, and
+/// removed once dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    /// Held open for the lock, which is released as it is closed.
+    _file: File,
+}
+
+impl PidFile {
+    /// Lock the file at `path` and write the id of this process into it,
+    /// failing if another process holds it.
+    pub fn create(path: &Path) -> Result<Self> {
+        // The file isn't truncated until it is locked, so that the id of a
+        // running instance is kept.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        // SAFETY: the descriptor is open for the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e.into());
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(format!(
+                "pid file '{}' is held by a running uppies with pid {}",
+                path.display(),
+                pid.trim()
+            )
+            .into());
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            _file: file,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::PidFile;
+
+    #[test]
+    fn pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.pid");
+        // A stale file of an instance which is no longer running is reused.
+        fs::write(&path, "1234567\n").unwrap();
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        let e = PidFile::create(&path).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains(&format!("with pid {}", std::process::id())),
+            "{e}"
+        );
+
+        drop(pid_file);
+        assert!(!path.exists());
+        PidFile::create(&path).unwrap();
+    }
+}
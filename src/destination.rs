@@ -0,0 +1,357 @@
+//! Health of every destination which uppies delivers to: the sinks of ping
+//! results, the notifiers of state changes and the exporters of metrics.
+//!
+//! Each destination reports its deliveries through a [`Destination`], so
+//! that one which is silently failing, such as a webhook rejecting every
+//! notification, is noticed before it's needed. Health is served at
+//! [`Destinations::PATH`] and exposed as the `sink_last_success_timestamp_seconds`,
+//! `sink_consecutive_errors` and `sink_queue_depth` gauges, labelled by the
+//! kind and name of the destination.
+
+#[cfg(feature = "metrics")]
+use std::collections::HashMap;
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "metrics")]
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{self, LabelPair, MetricFamily, MetricType},
+    Registry,
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "metrics")]
+use crate::Result;
+
+/// Health of a single destination, as served at [`Destinations::PATH`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DestinationHealth {
+    /// Kind of destination, such as `sink` or `notifier`.
+    pub kind: String,
+    pub name: String,
+    /// Whether the most recent delivery succeeded.
+    pub healthy: bool,
+    /// Time of the most recent successful delivery, in milliseconds since
+    /// the epoch.
+    pub last_success_ms: Option<u64>,
+    /// Number of deliveries which have failed since the last success.
+    pub consecutive_errors: u64,
+    /// Reason of the most recent failed delivery.
+    pub last_error: Option<String>,
+    /// Number of items waiting to be delivered.
+    pub queue_depth: usize,
+}
+
+/// Handle which a destination reports its deliveries through.
+///
+/// The default handle isn't registered anywhere, for destinations whose
+/// health isn't reported. Clones report to the same destination.
+#[derive(Clone, Default)]
+pub struct Destination {
+    inner: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    #[cfg(feature = "metrics")]
+    kind: String,
+    #[cfg(feature = "metrics")]
+    name: String,
+    /// Time of the most recent success in milliseconds, or 0 if there
+    /// hasn't been one.
+    last_success_ms: AtomicU64,
+    consecutive_errors: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    queue_depth: AtomicUsize,
+}
+
+impl Destination {
+    /// Record a successful delivery.
+    pub fn succeeded(&self) {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.inner.last_success_ms.store(now_ms, Ordering::Relaxed);
+        self.inner.consecutive_errors.store(0, Ordering::Relaxed);
+    }
+
+    /// Record a failed delivery, such as an error response or an item which
+    /// was dropped.
+    pub fn failed(&self, error: impl Display) {
+        self.inner
+            .consecutive_errors
+            .fetch_add(1, Ordering::Relaxed);
+        *self
+            .inner
+            .last_error
+            .lock()
+            .expect("destination lock poisoned") = Some(error.to_string());
+    }
+
+    /// Whether the most recent delivery succeeded, or nothing has failed.
+    pub fn is_healthy(&self) -> bool {
+        self.inner.consecutive_errors.load(Ordering::Relaxed) == 0
+    }
+
+    /// Record that an item was queued for delivery.
+    pub fn enqueued(&self) {
+        self.inner.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a queued item was taken for delivery, or never queued.
+    pub fn dequeued(&self) {
+        self.inner.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Set the number of items waiting to be delivered, for destinations
+    /// which can count their queue.
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.inner.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn health(&self) -> DestinationHealth {
+        let state = &self.inner;
+        let consecutive_errors = state.consecutive_errors.load(Ordering::Relaxed);
+        DestinationHealth {
+            kind: state.kind.clone(),
+            name: state.name.clone(),
+            healthy: consecutive_errors == 0,
+            last_success_ms: Some(state.last_success_ms.load(Ordering::Relaxed))
+                .filter(|&ms| ms > 0),
+            consecutive_errors,
+            last_error: state
+                .last_error
+                .lock()
+                .expect("destination lock poisoned")
+                .clone(),
+            queue_depth: state.queue_depth.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Every registered destination.
+///
+/// Clones share the same destinations.
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub struct Destinations {
+    inner: Arc<Inner>,
+}
+
+#[cfg(feature = "metrics")]
+struct Inner {
+    descs: Vec<Desc>,
+    destinations: Mutex<Vec<Destination>>,
+}
+
+#[cfg(feature = "metrics")]
+impl Destinations {
+    /// Path which the health of all destinations is served at.
+    pub const PATH: &str = "/sinks";
+
+    const METRICS: [(&str, &str); 3] = [
+        (
+            "sink_last_success_timestamp_seconds",
+            "Time of the most recent successful delivery, or 0 if there hasn't been one",
+        ),
+        (
+            "sink_consecutive_errors",
+            "Number of deliveries which have failed since the last success",
+        ),
+        (
+            "sink_queue_depth",
+            "Number of items waiting to be delivered",
+        ),
+    ];
+
+    pub fn new(metrics: &Registry) -> Result<Self> {
+        let descs = Self::METRICS
+            .iter()
+            .map(|(name, help)| {
+                Desc::new(
+                    name.to_string(),
+                    help.to_string(),
+                    vec!["kind".to_string(), "name".to_string()],
+                    HashMap::new(),
+                )
+            })
+            .collect::<prometheus::Result<_>>()?;
+        let destinations = Self {
+            inner: Arc::new(Inner {
+                descs,
+                destinations: Mutex::new(Vec::new()),
+            }),
+        };
+        metrics.register(Box::new(destinations.clone()))?;
+        Ok(destinations)
+    }
+
+    /// Register a destination of `kind`, returning the handle it reports
+    /// through. Names are made unique within each kind by suffixing them
+    /// with a number, such as `webhook-2`.
+    pub fn register(&self, kind: &str, name: &str) -> Destination {
+        let mut destinations = self
+            .inner
+            .destinations
+            .lock()
+            .expect("destinations lock poisoned");
+        let taken = |name: &str| {
+            destinations
+                .iter()
+                .any(|d| d.inner.kind == kind && d.inner.name == name)
+        };
+        let mut unique = name.to_string();
+        let mut n = 1;
+        while taken(&unique) {
+            n += 1;
+            unique = format!("{name}-{n}");
+        }
+        let destination = Destination {
+            inner: Arc::new(State {
+                kind: kind.to_string(),
+                name: unique,
+                ..Default::default()
+            }),
+        };
+        destinations.push(destination.clone());
+        destination
+    }
+
+    /// Health of every destination, in the order they were registered.
+    pub fn report(&self) -> Vec<DestinationHealth> {
+        self.inner
+            .destinations
+            .lock()
+            .expect("destinations lock poisoned")
+            .iter()
+            .map(Destination::health)
+            .collect()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Collector for Destinations {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let label = |name: &str, value: &str| {
+            let mut label = LabelPair::default();
+            label.set_name(name.to_string());
+            label.set_value(value.to_string());
+            label
+        };
+        let report = self.report();
+        Self::METRICS
+            .iter()
+            .enumerate()
+            .map(|(i, (name, help))| {
+                let metrics = report
+                    .iter()
+                    .map(|health| {
+                        let value = match i {
+                            0 => health.last_success_ms.unwrap_or_default() as f64 / 1000.0,
+                            1 => health.consecutive_errors as f64,
+                            _ => health.queue_depth as f64,
+                        };
+                        let mut gauge = proto::Gauge::default();
+                        gauge.set_value(value);
+                        let mut metric = proto::Metric::default();
+                        metric.set_label(vec![
+                            label("kind", &health.kind),
+                            label("name", &health.name),
+                        ]);
+                        metric.set_gauge(gauge);
+                        metric
+                    })
+                    .collect();
+                let mut family = MetricFamily::default();
+                family.set_name(name.to_string());
+                family.set_help(help.to_string());
+                family.set_field_type(MetricType::GAUGE);
+                family.set_metric(metrics);
+                family
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use prometheus::Registry;
+
+    use super::Destinations;
+
+    #[test]
+    fn health() {
+        let destinations = Destinations::new(&Registry::new()).unwrap();
+        let webhook = destinations.register("notifier", "webhook");
+        let other = destinations.register("notifier", "webhook");
+        destinations.register("sink", "webhook");
+
+        webhook.failed("500 Internal Server Error");
+        webhook.failed("500 Internal Server Error");
+        other.enqueued();
+        other.enqueued();
+        other.dequeued();
+        other.succeeded();
+
+        let report = destinations.report();
+        let names: Vec<_> = report
+            .iter()
+            .map(|h| (h.kind.as_str(), h.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("notifier", "webhook"),
+                ("notifier", "webhook-2"),
+                ("sink", "webhook")
+            ]
+        );
+        assert!(!report[0].healthy);
+        assert_eq!(report[0].consecutive_errors, 2);
+        assert_eq!(report[0].last_success_ms, None);
+        assert_eq!(
+            report[0].last_error.as_deref(),
+            Some("500 Internal Server Error")
+        );
+        assert!(report[1].healthy);
+        assert!(report[1].last_success_ms.is_some());
+        assert_eq!(report[1].queue_depth, 1);
+
+        webhook.succeeded();
+        assert!(webhook.is_healthy());
+        assert_eq!(destinations.report()[0].consecutive_errors, 0);
+    }
+
+    #[test]
+    fn gauges() {
+        let metrics = Registry::new();
+        let destinations = Destinations::new(&metrics).unwrap();
+        destinations
+            .register("exporter", "remote_write")
+            .failed("timeout");
+
+        let families = metrics.gather();
+        let errors = families
+            .iter()
+            .find(|f| f.name() == "sink_consecutive_errors")
+            .unwrap();
+        assert_eq!(errors.get_metric()[0].get_gauge().value(), 1.0);
+        assert_eq!(
+            errors.get_metric()[0].get_label()[1].value(),
+            "remote_write"
+        );
+    }
+}
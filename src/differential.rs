@@ -0,0 +1,320 @@
+//! Differential pings, comparing each target against a control target such
+//! as the gateway in front of it.
+//!
+//! The round-trip time and loss of each probe of a target are compared with
+//! those of the same probe of its control over their recent pings, so that
+//! a problem can be localised
+//! to the path between the control and the target (such as inside a site)
+//! or to the path which they share (such as the uplink of the site).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
+use serde::Deserialize;
+
+use crate::{sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DifferentialConfig {
+    /// Number of recent pings of each target which are compared.
+    #[serde(default = "DifferentialConfig::default_samples")]
+    pub samples: usize,
+    /// Ratio of recent pings which must have failed, from 0 to 1, for the
+    /// target or control to be considered faulty.
+    #[serde(default = "DifferentialConfig::default_loss_threshold")]
+    pub loss_threshold: f64,
+    /// Targets and the controls which they are compared with.
+    pub pairs: Vec<Pair>,
+}
+
+impl DifferentialConfig {
+    fn default_samples() -> usize {
+        20
+    }
+
+    fn default_loss_threshold() -> f64 {
+        0.2
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Pair {
+    pub target: String,
+    /// Target in front of `target`, such as the gateway of its site.
+    pub control: String,
+}
+
+/// Where a fault between a target and its control lies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    None,
+    /// Only the target is faulty, so the fault lies beyond the control.
+    Target,
+    /// Both are faulty, so the fault lies on the path which they share.
+    Shared,
+}
+
+impl Fault {
+    const ALL: [Self; 3] = [Self::None, Self::Target, Self::Shared];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Target => "target",
+            Self::Shared => "shared",
+        }
+    }
+}
+
+/// Recent pings of a probe of a target, as whether they succeeded and their round-trip
+/// time, which targets expected to be down succeed without.
+#[derive(Default)]
+struct Recent(VecDeque<(bool, Option<Duration>)>);
+
+impl Recent {
+    /// Ratio of recent pings which failed, or `None` if none have been
+    /// seen.
+    fn loss(&self) -> Option<f64> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let failures = self.0.iter().filter(|(ok, _)| !ok).count();
+        Some(failures as f64 / self.0.len() as f64)
+    }
+
+    /// Mean round-trip time of recent successful pings, in milliseconds.
+    fn mean_rtt_ms(&self) -> Option<f64> {
+        let rtts: Vec<_> = self.0.iter().filter_map(|(_, rtt)| *rtt).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        let total: f64 = rtts.iter().map(|rtt| rtt.as_secs_f64() * 1000.0).sum();
+        Some(total / rtts.len() as f64)
+    }
+}
+
+pub struct DifferentialPing {
+    samples: usize,
+    loss_threshold: f64,
+    pairs: Vec<Pair>,
+    /// Recent pings of every probe of each target which is part of a pair.
+    recent: Mutex<HashMap<(String, String), Recent>>,
+
+    /// Mean round-trip time of the target minus that of its control.
+    rtt_delta_ms: GaugeVec,
+    /// Loss of the target minus that of its control.
+    loss_delta: GaugeVec,
+    /// Where the fault between the target and its control lies.
+    fault: IntGaugeVec,
+}
+
+impl DifferentialPing {
+    pub fn new(config: &DifferentialConfig, metrics: &Registry) -> Result<Self> {
+        if !(0.0..=1.0).contains(&config.loss_threshold) {
+            return Err("differential loss_threshold must be between 0 and 1"
+                .to_string()
+                .into());
+        }
+        let labels = &["target", "control", "probe"];
+        let rtt_delta_ms = GaugeVec::new(
+            Opts::new(
+                "differential_rtt_delta_ms",
+                "Mean round-trip time of the target minus that of its control, in milliseconds",
+            ),
+            labels,
+        )?;
+        let loss_delta = GaugeVec::new(
+            Opts::new(
+                "differential_loss_delta_ratio",
+                "Ratio of recent pings lost by the target minus that of its control",
+            ),
+            labels,
+        )?;
+        let fault = IntGaugeVec::new(
+            Opts::new(
+                "differential_fault",
+                "Whether the fault between the target and its control lies at the location",
+            ),
+            &["target", "control", "probe", "location"],
+        )?;
+        metrics.register(Box::new(rtt_delta_ms.clone()))?;
+        metrics.register(Box::new(loss_delta.clone()))?;
+        metrics.register(Box::new(fault.clone()))?;
+
+        Ok(Self {
+            samples: config.samples.max(1),
+            loss_threshold: config.loss_threshold,
+            pairs: config.pairs.clone(),
+            recent: Mutex::new(HashMap::new()),
+            rtt_delta_ms,
+            loss_delta,
+            fault,
+        })
+    }
+
+    /// Where the fault between `target` and `control` lies, if both have
+    /// been pinged.
+    fn locate(&self, target: &Recent, control: &Recent) -> Option<Fault> {
+        let faulty = |recent: &Recent| recent.loss().map(|loss| loss > self.loss_threshold);
+        Some(match (faulty(target)?, faulty(control)?) {
+            (true, true) => Fault::Shared,
+            (true, false) => Fault::Target,
+            (false, _) => Fault::None,
+        })
+    }
+}
+
+impl Sink for DifferentialPing {
+    fn record(&self, outcome: &PingOutcome) {
+        let paired =
+            |pair: &Pair| *pair.target == *outcome.target || *pair.control == *outcome.target;
+        if !self.pairs.iter().any(paired) {
+            return;
+        }
+        let mut recent = self.recent.lock().expect("differential lock poisoned");
+        let pings = recent
+            .entry((outcome.target.to_string(), outcome.probe.to_string()))
+            .or_default();
+        if pings.0.len() == self.samples {
+            pings.0.pop_front();
+        }
+        pings.0.push_back((outcome.rtt.is_ok(), outcome.latency()));
+
+        let probe = &*outcome.probe;
+        for pair in self.pairs.iter().filter(|pair| paired(pair)) {
+            let recent = |target: &String| recent.get(&(target.clone(), probe.to_string()));
+            let (Some(target), Some(control)) = (recent(&pair.target), recent(&pair.control))
+            else {
+                continue;
+            };
+            let labels = [pair.target.as_str(), pair.control.as_str(), probe];
+            if let (Some(t), Some(c)) = (target.mean_rtt_ms(), control.mean_rtt_ms()) {
+                self.rtt_delta_ms.with_label_values(&labels).set(t - c);
+            }
+            if let (Some(t), Some(c)) = (target.loss(), control.loss()) {
+                self.loss_delta.with_label_values(&labels).set(t - c);
+            }
+            if let Some(fault) = self.locate(target, control) {
+                for location in Fault::ALL {
+                    self.fault
+                        .with_label_values(&[&pair.target, &pair.control, probe, location.as_str()])
+                        .set(i64::from(location == fault));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
+    use super::{DifferentialConfig, DifferentialPing, Pair};
+    use crate::{sink::Sink, ErrorKind, PingOutcome};
+
+    const SERVER: &str = "10.0.0.5";
+    const GATEWAY: &str = "10.0.0.1";
+
+    fn differential() -> DifferentialPing {
+        DifferentialPing::new(
+            &DifferentialConfig {
+                samples: 4,
+                loss_threshold: 0.2,
+                pairs: vec![Pair {
+                    target: SERVER.to_string(),
+                    control: GATEWAY.to_string(),
+                }],
+            },
+            &Registry::new(),
+        )
+        .unwrap()
+    }
+
+    fn fault(differential: &DifferentialPing, location: &str) -> i64 {
+        differential
+            .fault
+            .with_label_values(&[SERVER, GATEWAY, "icmp", location])
+            .get()
+    }
+
+    #[test]
+    fn deltas() {
+        let differential = differential();
+        differential.record(&PingOutcome::test(SERVER, Ok(Duration::from_millis(12))));
+        assert_eq!(fault(&differential, "none"), 0, "control not pinged yet");
+
+        differential.record(&PingOutcome::test(GATEWAY, Ok(Duration::from_millis(2))));
+        differential.record(&PingOutcome::test(SERVER, Err(ErrorKind::Timeout)));
+        let labels = [SERVER, GATEWAY, "icmp"];
+        assert_eq!(
+            differential.rtt_delta_ms.with_label_values(&labels).get(),
+            10.0
+        );
+        assert_eq!(
+            differential.loss_delta.with_label_values(&labels).get(),
+            0.5
+        );
+        assert_eq!(fault(&differential, "target"), 1);
+        assert_eq!(fault(&differential, "none"), 0);
+
+        // Unpaired targets are ignored.
+        differential.record(&PingOutcome::test("1.1.1.1", Err(ErrorKind::Timeout)));
+    }
+
+    #[test]
+    fn shared_fault() {
+        let differential = differential();
+        for target in [SERVER, GATEWAY] {
+            differential.record(&PingOutcome::test(target, Ok(Duration::from_millis(1))));
+            differential.record(&PingOutcome::test(target, Err(ErrorKind::Timeout)));
+        }
+        assert_eq!(fault(&differential, "shared"), 1);
+        assert_eq!(fault(&differential, "target"), 0);
+
+        for _ in 0..4 {
+            differential.record(&PingOutcome::test(SERVER, Ok(Duration::from_millis(1))));
+            differential.record(&PingOutcome::test(GATEWAY, Ok(Duration::from_millis(1))));
+        }
+        assert_eq!(fault(&differential, "none"), 1);
+    }
+
+    #[test]
+    fn probes() {
+        let differential = differential();
+        let ping = |target, probe: &str, rtt| {
+            let mut outcome = PingOutcome::test(target, rtt);
+            outcome.probe = probe.into();
+            outcome
+        };
+        differential.record(&ping(SERVER, "tls", Err(ErrorKind::Timeout)));
+        differential.record(&ping(GATEWAY, "icmp", Ok(Duration::from_millis(1))));
+        assert_eq!(fault(&differential, "target"), 0, "probes aren't compared");
+
+        differential.record(&ping(GATEWAY, "tls", Ok(Duration::from_millis(1))));
+        let fault = |probe| {
+            differential
+                .fault
+                .with_label_values(&[SERVER, GATEWAY, probe, "target"])
+                .get()
+        };
+        assert_eq!(fault("tls"), 1);
+    }
+
+    #[test]
+    fn invalid_threshold() {
+        let config = DifferentialConfig {
+            samples: 4,
+            loss_threshold: 20.0,
+            pairs: Vec::new(),
+        };
+        assert!(DifferentialPing::new(&config, &Registry::new()).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Discovery of targets from service registries, such as the catalog of
+//! Consul, a prefix of etcd or a URL in the format of the HTTP service
+//! discovery of Prometheus, so that targets which are already registered
+//! aren't maintained in a second list.
+//!
+//! Each source is refreshed periodically and its targets are reconciled
+//! through [`TargetSources`], alongside the targets of the command line,
+//! configuration and other sources. A source which fails to refresh keeps
+//! its previous targets until it succeeds again.
+
+use std::{future::Future, time::Duration};
+
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    range::{self, RangeConfig, TargetSpec},
+    target::Scheme,
+    targets::TargetSources,
+    Result,
+};
+
+pub mod consul;
+pub mod etcd;
+pub mod http;
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Interval between refreshes of each source, in seconds.
+    #[serde(default = "DiscoveryConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// Services of Consul catalogs.
+    #[serde(default)]
+    pub consul: Vec<consul::ConsulConfig>,
+    /// Prefixes of etcd clusters.
+    #[serde(default)]
+    pub etcd: Vec<etcd::EtcdConfig>,
+    /// URLs returning targets, such as of a CMDB.
+    #[serde(default)]
+    pub http: Vec<http::HttpConfig>,
+}
+
+impl DiscoveryConfig {
+    fn default_interval_secs() -> u64 {
+        30
+    }
+}
+
+/// A registry which the current targets are discovered from.
+pub trait Discovery: Send + Sync + 'static {
+    /// Name of the source, which is unique among sources, such as
+    /// `consul:routers`.
+    fn name(&self) -> &str;
+
+    /// Targets which are currently registered.
+    fn discover(&self) -> impl Future<Output = Result<Vec<TargetSpec>>> + Send;
+}
+
+/// Metrics of the refreshes of every source.
+#[derive(Clone)]
+pub struct DiscoveryMetrics {
+    /// Number of targets of each source as of its last refresh.
+    targets: IntGaugeVec,
+    /// Number of refreshes of each source which failed.
+    errors: IntCounterVec,
+}
+
+impl DiscoveryMetrics {
+    pub fn new(metrics: &Registry) -> Result<Self> {
+        let targets = IntGaugeVec::new(
+            Opts::new(
+                "discovered_targets",
+                "Number of targets of the source as of its last refresh",
+            ),
+            &["source"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "discovery_errors_total",
+                "Counter of refreshes of the source which failed",
+            ),
+            &["source"],
+        )?;
+        metrics.register(Box::new(targets.clone()))?;
+        metrics.register(Box::new(errors.clone()))?;
+        Ok(Self { targets, errors })
+    }
+}
+
+/// Target of a discovered address, probed with `scheme` on `port` when the
+/// scheme has ports.
+fn target(scheme: Scheme, host: &str, port: Option<u16>) -> Result<TargetSpec> {
+    let mut target = String::new();
+    if scheme != Scheme::default() {
+        target.push_str(scheme.as_str());
+        target.push_str("://");
+    }
+    match host.contains(':') {
+        true => target.push_str(&format!("[{host}]")),
+        false => target.push_str(host),
+    }
+    if let (true, Some(port)) = (scheme.has_ports(), port) {
+        target.push_str(&format!(":{port}"));
+    }
+    target.parse()
+}
+
+/// Periodically discover the targets of `source`, expanding any ranges
+/// with `ranges`, and update them within `sources`.
+pub async fn run_discovery<D: Discovery>(
+    source: D,
+    sources: TargetSources,
+    ranges: RangeConfig,
+    interval: Duration,
+    metrics: DiscoveryMetrics,
+) {
+    info!(source = source.name(), ?interval, "starting discovery");
+    let labels = &[source.name()];
+    metrics.errors.with_label_values(labels).inc_by(0);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let refreshed = source
+            .discover()
+            .await
+            .and_then(|specs| range::expand(&specs, &ranges))
+            .and_then(|targets| {
+                let len = targets.len();
+                Ok((len, sources.update(source.name(), targets)?))
+            });
+        match refreshed {
+            Ok((len, reconciled)) => {
+                metrics.targets.with_label_values(labels).set(len as i64);
+                if reconciled.added.is_empty() && reconciled.removed.is_empty() {
+                    debug!(source = source.name(), "discovered targets unchanged");
+                } else {
+                    info!(
+                        source = source.name(),
+                        ?reconciled.added,
+                        ?reconciled.removed,
+                        "discovered targets changed"
+                    );
+                }
+            }
+            Err(e) => {
+                metrics.errors.with_label_values(labels).inc();
+                warn!(source = source.name(), %e, "discovery failure");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::target;
+    use crate::target::Scheme;
+
+    #[test]
+    fn targets() {
+        for (scheme, host, port, expected) in [
+            (Scheme::Icmp, "10.0.0.1", Some(8080), "10.0.0.1"),
+            (Scheme::Tls, "10.0.0.1", Some(8443), "tls://10.0.0.1:8443"),
+            (Scheme::Tls, "2001:db8::1", Some(443), "tls://2001:db8::1"),
+            (
+                Scheme::Ssh,
+                "bastion.example.com",
+                None,
+                "ssh://bastion.example.com",
+            ),
+        ] {
+            let target = target(scheme, host, port).unwrap();
+            assert_eq!(target.to_string(), expected);
+        }
+        assert!(target(Scheme::Grpc, "10.0.0.1", None).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Discovery of the instances of a service from the catalog of Consul.
+
+use serde::Deserialize;
+
+use super::Discovery;
+use crate::{range::TargetSpec, target::Scheme, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ConsulConfig {
+    /// Address of the HTTP API of a Consul agent.
+    #[serde(default = "ConsulConfig::default_address")]
+    pub address: String,
+    /// Service whose instances are discovered.
+    pub service: String,
+    /// Tags which the discovered instances must all have.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Datacenter of the catalog, otherwise that of the agent.
+    pub datacenter: Option<String>,
+    /// ACL token which the catalog is read with.
+    pub token: Option<String>,
+    /// Scheme which instances are probed with, on the port they are
+    /// registered with if it has ports.
+    #[serde(default)]
+    pub scheme: Scheme,
+}
+
+impl ConsulConfig {
+    fn default_address() -> String {
+        "http://127.0.0.1:8500".to_string()
+    }
+}
+
+/// Instance of a service within the catalog.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CatalogService {
+    /// Address of the node of the instance.
+    address: String,
+    /// Address of the instance, if it differs from that of its node.
+    #[serde(default)]
+    service_address: String,
+    #[serde(default)]
+    service_port: u16,
+}
+
+/// Source of the instances of a service of a Consul catalog.
+pub struct ConsulDiscovery {
+    name: String,
+    /// URL of the service within the catalog, with the query of its tags.
+    url: reqwest::Url,
+    token: Option<String>,
+    scheme: Scheme,
+    client: reqwest::Client,
+}
+
+impl ConsulDiscovery {
+    pub fn new(config: &ConsulConfig) -> Result<Self> {
+        let mut query: Vec<_> = config.tags.iter().map(|tag| ("tag", tag.clone())).collect();
+        if let Some(datacenter) = &config.datacenter {
+            query.push(("dc", datacenter.clone()));
+        }
+        let url = format!(
+            "{}/v1/catalog/service/{}",
+            config.address.trim_end_matches('/'),
+            config.service
+        );
+        Ok(Self {
+            name: format!("consul:{}", config.service),
+            url: reqwest::Url::parse_with_params(&url, &query)
+                .map_err(|e| format!("invalid consul address '{}': {e}", config.address))?,
+            token: config.token.clone(),
+            scheme: config.scheme,
+            client: reqwest::Client::builder().build()?,
+        })
+    }
+}
+
+impl Discovery for ConsulDiscovery {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn discover(&self) -> Result<Vec<TargetSpec>> {
+        let mut request = self.client.get(self.url.clone());
+        if let Some(token) = &self.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        let instances: Vec<CatalogService> =
+            request.send().await?.error_for_status()?.json().await?;
+        instances
+            .iter()
+            .map(|instance| {
+                let host = match instance.service_address.as_str() {
+                    "" => &instance.address,
+                    address => address,
+                };
+                super::target(self.scheme, host, Some(instance.service_port))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use axum::{
+        extract::{Path, Query},
+        http::HeaderMap,
+        routing::get,
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::{ConsulConfig, ConsulDiscovery};
+    use crate::{discovery::Discovery, target::Scheme};
+
+    async fn catalog(
+        Path(service): Path<String>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+    ) -> Json<Value> {
+        assert_eq!(service, "routers");
+        assert_eq!(query["tag"], "core");
+        assert_eq!(headers["x-consul-token"], "secret");
+        Json(json!([
+            {"Node": "r1", "Address": "10.0.0.1", "ServiceAddress": "", "ServicePort": 22},
+            {"Node": "r2", "Address": "10.0.0.2", "ServiceAddress": "10.1.0.2", "ServicePort": 2222},
+        ]))
+    }
+
+    #[tokio::test]
+    async fn discover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route("/v1/catalog/service/{service}", get(catalog));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let discovery = ConsulDiscovery::new(&ConsulConfig {
+            address,
+            service: "routers".to_string(),
+            tags: vec!["core".to_string()],
+            datacenter: None,
+            token: Some("secret".to_string()),
+            scheme: Scheme::Ssh,
+        })
+        .unwrap();
+        assert_eq!(discovery.name(), "consul:routers");
+        let targets: Vec<_> = discovery
+            .discover()
+            .await
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(targets, ["ssh://10.0.0.1", "ssh://10.1.0.2:2222"]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Discovery of the targets stored under a prefix of etcd, through the JSON
+//! gateway of its v3 API.
+//!
+//! The value of each key under the prefix is a target as it is given on the
+//! command line, such as `gateway=192.168.1.1` or `tls://10.0.0.5:8443`.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::Discovery;
+use crate::{range::TargetSpec, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EtcdConfig {
+    /// Address of the HTTP API of an etcd member.
+    #[serde(default = "EtcdConfig::default_endpoint")]
+    pub endpoint: String,
+    /// Prefix of the keys whose values are targets.
+    pub prefix: String,
+}
+
+impl EtcdConfig {
+    fn default_endpoint() -> String {
+        "http://127.0.0.1:2379".to_string()
+    }
+}
+
+#[derive(Deserialize)]
+struct RangeResponse {
+    /// Keys within the range, absent when there are none.
+    #[serde(default)]
+    kvs: Vec<KeyValue>,
+}
+
+#[derive(Deserialize)]
+struct KeyValue {
+    /// Base64 of the key.
+    key: String,
+    /// Base64 of the value.
+    #[serde(default)]
+    value: String,
+}
+
+/// Source of the targets under a prefix of etcd.
+pub struct EtcdDiscovery {
+    name: String,
+    url: String,
+    prefix: Vec<u8>,
+    client: reqwest::Client,
+}
+
+impl EtcdDiscovery {
+    pub fn new(config: &EtcdConfig) -> Result<Self> {
+        Ok(Self {
+            name: format!("etcd:{}", config.prefix),
+            url: format!("{}/v3/kv/range", config.endpoint.trim_end_matches('/')),
+            prefix: config.prefix.as_bytes().to_vec(),
+            client: reqwest::Client::builder().build()?,
+        })
+    }
+}
+
+/// End of the range of keys starting with `prefix`, which is the prefix
+/// with its last byte incremented, or `\0` for all keys.
+fn range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    vec![0]
+}
+
+impl Discovery for EtcdDiscovery {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn discover(&self) -> Result<Vec<TargetSpec>> {
+        let body = json!({
+            "key": STANDARD.encode(&self.prefix),
+            "range_end": STANDARD.encode(range_end(&self.prefix)),
+        });
+        let response: RangeResponse = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response
+            .kvs
+            .iter()
+            .map(|kv| {
+                let key = String::from_utf8_lossy(&STANDARD.decode(&kv.key)?).into_owned();
+                let value = String::from_utf8(STANDARD.decode(&kv.value)?)
+                    .map_err(|_| format!("value of {key} is not UTF-8"))?;
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|e| format!("value of {key}: {e}").into())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::post, Json, Router};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::{range_end, EtcdConfig, EtcdDiscovery};
+    use crate::discovery::Discovery;
+
+    async fn range(Json(body): Json<Value>) -> Json<Value> {
+        assert_eq!(body["key"], STANDARD.encode("/uppies/"));
+        assert_eq!(body["range_end"], STANDARD.encode("/uppies0"));
+        let encoded = |key: &str, value: &str| {
+            let (key, value) = (STANDARD.encode(key), STANDARD.encode(value));
+            json!({"key": key, "value": value})
+        };
+        Json(json!({"kvs": [
+            encoded("/uppies/gateway", "gateway=192.168.1.1"),
+            encoded("/uppies/web", "tls://10.0.0.5:8443\n"),
+        ]}))
+    }
+
+    #[tokio::test]
+    async fn discover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route("/v3/kv/range", post(range));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let discovery = EtcdDiscovery::new(&EtcdConfig {
+            endpoint,
+            prefix: "/uppies/".to_string(),
+        })
+        .unwrap();
+        let targets: Vec<_> = discovery
+            .discover()
+            .await
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(targets, ["gateway=192.168.1.1", "tls://10.0.0.5:8443"]);
+    }
+
+    #[test]
+    fn range_ends() {
+        assert_eq!(range_end(b"/a"), b"/b");
+        assert_eq!(range_end(b"a\xff"), b"b");
+        assert_eq!(range_end(b""), b"\0");
+    }
+}
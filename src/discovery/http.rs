@@ -0,0 +1,169 @@
+//! Discovery of targets from a URL returning the targets as JSON, in the
+//! format of the HTTP service discovery of Prometheus, such as from an
+//! in-house CMDB.
+//!
+//! The URL returns a list of target groups, whose `labels` are ignored:
+//!
+//! ```json
+//! [{"targets": ["10.0.0.1:9100", "10.0.0.2"], "labels": {"site": "lon"}}]
+//! ```
+//!
+//! Each address, with an optional port, is probed with the configured
+//! scheme. Targets may also be written as they are given on the command
+//! line, such as `tls://10.0.0.5:8443` or `gateway=192.168.1.1`.
+
+use std::collections::BTreeMap;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use super::Discovery;
+use crate::{range::TargetSpec, target::Scheme, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// URL which returns the targets.
+    pub url: String,
+    /// Headers of each request, such as `Authorization`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Scheme which addresses are probed with, on their port if it has
+    /// ports.
+    #[serde(default)]
+    pub scheme: Scheme,
+}
+
+/// Group of targets sharing labels.
+#[derive(Deserialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+}
+
+/// Source of the targets returned by a URL.
+pub struct HttpDiscovery {
+    name: String,
+    url: String,
+    scheme: Scheme,
+    client: reqwest::Client,
+}
+
+impl HttpDiscovery {
+    pub fn new(config: &HttpConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::try_from(name.as_str())?,
+                HeaderValue::try_from(value.as_str())?,
+            );
+        }
+        Ok(Self {
+            name: format!("http:{}", config.url),
+            url: config.url.clone(),
+            scheme: config.scheme,
+            client: reqwest::Client::builder()
+                .default_headers(headers)
+                .build()?,
+        })
+    }
+
+    /// Target of an entry of a target group.
+    fn target(&self, entry: &str) -> Result<TargetSpec> {
+        if entry.contains("://") || entry.contains('=') {
+            return entry.parse();
+        }
+        let (host, port) = match entry.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("invalid target '{entry}': missing closing bracket"))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match entry.rsplit_once(':') {
+                // Unbracketed IPv6 addresses have no port.
+                Some((host, port)) if !host.contains(':') => (host, Some(port)),
+                _ => (entry, None),
+            },
+        };
+        let port = port
+            .map(|port| {
+                port.parse()
+                    .map_err(|_| format!("invalid target '{entry}': invalid port"))
+            })
+            .transpose()?;
+        super::target(self.scheme, host, port)
+    }
+}
+
+impl Discovery for HttpDiscovery {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn discover(&self) -> Result<Vec<TargetSpec>> {
+        let groups: Vec<TargetGroup> = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        groups
+            .iter()
+            .flat_map(|group| &group.targets)
+            .map(|entry| self.target(entry))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{http::HeaderMap, routing::get, Json, Router};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::{HttpConfig, HttpDiscovery};
+    use crate::{discovery::Discovery, target::Scheme};
+
+    async fn targets(headers: HeaderMap) -> Json<Value> {
+        assert_eq!(headers["authorization"], "Bearer secret");
+        Json(json!([
+            {"targets": ["10.0.0.1:9100", "10.0.0.2"], "labels": {"site": "lon"}},
+            {"targets": ["[2001:db8::1]:8443", "gw=192.168.1.1"]},
+        ]))
+    }
+
+    async fn discover(scheme: Scheme) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/sd", listener.local_addr().unwrap());
+        let app = Router::new().route("/sd", get(targets));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let discovery = HttpDiscovery::new(&HttpConfig {
+            url,
+            headers: [("Authorization".to_string(), "Bearer secret".to_string())].into(),
+            scheme,
+        })
+        .unwrap();
+        let targets = discovery.discover().await.unwrap();
+        targets.iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn http_sd() {
+        assert_eq!(
+            discover(Scheme::Icmp).await,
+            ["10.0.0.1", "10.0.0.2", "2001:db8::1", "gw=192.168.1.1"]
+        );
+        assert_eq!(
+            discover(Scheme::Tls).await,
+            [
+                "tls://10.0.0.1:9100",
+                "tls://10.0.0.2",
+                "tls://[2001:db8::1]:8443",
+                "gw=192.168.1.1"
+            ]
+        );
+    }
+}
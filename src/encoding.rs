@@ -0,0 +1,270 @@
+//! Encodings of ping results for transfer, as served by `/stream` and
+//! exported from history files.
+//!
+//! Results are framed either as newline delimited JSON, or as protobuf
+//! messages each prefixed by their length as a varint, which is far less
+//! verbose with many targets at short intervals. Either can be compressed
+//! with gzip or zstd, which is flushed after each batch of results so that
+//! a live stream isn't held back.
+//!
+//! The protobuf messages are defined within `proto/uppies/v1/uppies.proto`,
+//! see [`proto`](crate::proto).
+
+use std::{io::Write, str::FromStr};
+
+use flate2::write::GzEncoder;
+use serde::Serialize;
+
+use crate::Result;
+
+/// A value which can be framed in either format.
+pub trait Frame: Serialize {
+    type Proto: prost::Message;
+
+    /// The protobuf message of this value.
+    fn to_proto(&self) -> Self::Proto;
+}
+
+/// Framing of each encoded value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// A line of JSON for each value.
+    #[default]
+    Json,
+    /// A protobuf message for each value, prefixed by its length as a
+    /// varint.
+    Protobuf,
+}
+
+impl Framing {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/x-ndjson",
+            Self::Protobuf => "application/x-protobuf; delimited=true",
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "protobuf" => Ok(Self::Protobuf),
+            _ => Err(format!(
+                "unknown framing '{s}', expected 'json' or 'protobuf'"
+            )),
+        }
+    }
+}
+
+/// Compression of the encoded values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Preferred compression of an `Accept-Encoding` header, where zstd is
+    /// preferred over gzip.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let accepted = |encoding: &str| {
+            accept_encoding.is_some_and(|header| {
+                header.split(',').any(|value| {
+                    let mut params = value.split(';').map(str::trim);
+                    params.next() == Some(encoding) && !params.any(|param| param == "q=0")
+                })
+            })
+        };
+        if accepted("zstd") {
+            Self::Zstd
+        } else if accepted("gzip") {
+            Self::Gzip
+        } else {
+            Self::None
+        }
+    }
+
+    /// Value of the `Content-Encoding` header, if compressed.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gzip"),
+            Self::Zstd => Some("zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!(
+                "unknown compression '{s}', expected 'none', 'gzip' or 'zstd'"
+            )),
+        }
+    }
+}
+
+/// Encoder of batches of values into a single stream of bytes.
+pub struct Encoder {
+    framing: Framing,
+    compressor: Compressor,
+}
+
+enum Compressor {
+    None,
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(framing: Framing, compression: Compression) -> Result<Self> {
+        let compressor = match compression {
+            Compression::None => Compressor::None,
+            Compression::Gzip => {
+                Compressor::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+            Compression::Zstd => Compressor::Zstd(zstd::Encoder::new(Vec::new(), 0)?),
+        };
+        Ok(Self {
+            framing,
+            compressor,
+        })
+    }
+
+    /// Encode a batch of `values`, returning the bytes which follow those
+    /// of previous batches.
+    pub fn encode<T: Frame>(&mut self, values: &[T]) -> Result<Vec<u8>> {
+        let mut framed = Vec::new();
+        for value in values {
+            match self.framing {
+                Framing::Json => {
+                    serde_json::to_writer(&mut framed, value)?;
+                    framed.push(b'\n');
+                }
+                Framing::Protobuf => {
+                    prost::Message::encode_length_delimited(&value.to_proto(), &mut framed)?
+                }
+            }
+        }
+        match &mut self.compressor {
+            Compressor::None => Ok(framed),
+            Compressor::Gzip(encoder) => {
+                encoder.write_all(&framed)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Compressor::Zstd(encoder) => {
+                encoder.write_all(&framed)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// Finish the stream of bytes, returning those which remain.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        Ok(match self.compressor {
+            Compressor::None => Vec::new(),
+            Compressor::Gzip(encoder) => encoder.finish()?,
+            Compressor::Zstd(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use prost::Message;
+
+    use super::{Compression, Encoder, Framing};
+    use crate::{proto, stream::StreamEvent};
+
+    fn events() -> Vec<StreamEvent> {
+        (0..3)
+            .map(|sequence| StreamEvent {
+                target: "1.1.1.1".to_string(),
+                probe: "icmp".to_string(),
+                sequence,
+                rtt_ms: Some(1.5),
+                error: None,
+                timestamp_ms: 1000 + sequence,
+                reply_ttl: None,
+                reply_source: None,
+            })
+            .collect()
+    }
+
+    fn encode(framing: Framing, compression: Compression) -> Vec<u8> {
+        let mut encoder = Encoder::new(framing, compression).unwrap();
+        let events = events();
+        let mut bytes = encoder.encode(&events[..1]).unwrap();
+        bytes.extend(encoder.encode(&events[1..]).unwrap());
+        bytes.extend(encoder.finish().unwrap());
+        bytes
+    }
+
+    #[test]
+    fn compressions() {
+        let plain = encode(Framing::Json, Compression::None);
+        assert_eq!(plain.iter().filter(|b| **b == b'\n').count(), 3);
+
+        let mut gzip = Vec::new();
+        flate2::read::GzDecoder::new(&encode(Framing::Json, Compression::Gzip)[..])
+            .read_to_end(&mut gzip)
+            .unwrap();
+        assert_eq!(gzip, plain);
+        let zstd = zstd::decode_all(&encode(Framing::Json, Compression::Zstd)[..]).unwrap();
+        assert_eq!(zstd, plain);
+    }
+
+    #[test]
+    fn flush_each_batch() {
+        let mut encoder = Encoder::new(Framing::Json, Compression::Gzip).unwrap();
+        let first = encoder.encode(&events()[..1]).unwrap();
+        let mut decoded = String::new();
+        let _ = flate2::read::GzDecoder::new(&first[..]).read_to_string(&mut decoded);
+        assert!(
+            decoded.ends_with('\n'),
+            "batch is decodable before finishing"
+        );
+    }
+
+    #[test]
+    fn protobuf() {
+        let bytes = encode(Framing::Protobuf, Compression::None);
+        let mut buf = &bytes[..];
+        let mut decoded = Vec::new();
+        while !buf.is_empty() {
+            decoded.push(proto::ProbeResult::decode_length_delimited(&mut buf).unwrap());
+        }
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[2].sequence, 2);
+        assert_eq!(decoded[2].rtt_ms, Some(1.5));
+        assert!(bytes.len() < encode(Framing::Json, Compression::None).len());
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(Compression::negotiate(None), Compression::None);
+        assert_eq!(
+            Compression::negotiate(Some("gzip, deflate, br, zstd")),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::negotiate(Some("zstd;q=0, gzip;q=0.5")),
+            Compression::Gzip
+        );
+        assert_eq!(Compression::negotiate(Some("br")), Compression::None);
+    }
+}
@@ -0,0 +1,153 @@
+//! Errors of uppies, which library users can match on to decide whether to
+//! retry, such as after [`UppiesError::SocketInit`] once privileges are
+//! granted, or to abort, such as after [`UppiesError::InvalidTarget`].
+
+use std::{fmt, io};
+
+use crate::{probe::icmp::SOCKET_PRIVILEGES, ErrorKind, PingError};
+
+/// Error of any operation of uppies.
+#[derive(thiserror::Error)]
+#[non_exhaustive]
+pub enum UppiesError {
+    /// A target could not be parsed, resolved or probed as given.
+    #[error("{0}")]
+    InvalidTarget(String),
+    /// A socket to probe the target from could not be opened, such as for
+    /// a lack of privileges.
+    #[error("failed to create socket for '{target}': {source}{}", privileges(.source))]
+    SocketInit { target: String, source: io::Error },
+    /// A probe did not complete within its timeout.
+    #[error("{0}")]
+    ProbeTimeout(PingError),
+    /// A probe failed for any other reason.
+    #[error("{0}")]
+    Probe(PingError),
+    /// Results could no longer be sent, as their receiver was dropped.
+    #[error("result channel closed")]
+    ChannelClosed,
+    /// Metrics could not be registered, such as as they were already.
+    #[cfg(feature = "metrics")]
+    #[error("failed to register metrics: {0}")]
+    MetricRegistration(#[from] prometheus::Error),
+    /// A task panicked.
+    #[error("task panicked: {0}")]
+    TaskPanicked(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Any other error, such as of invalid configuration, described by its
+    /// message.
+    #[error("{0}")]
+    Message(String),
+    /// Any other error, such as of a dependency.
+    #[error(transparent)]
+    Other(#[from] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Errors are debugged as their message, which is how they are reported
+/// when returned from `main`.
+impl fmt::Debug for UppiesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<PingError> for UppiesError {
+    fn from(e: PingError) -> Self {
+        match e.kind {
+            ErrorKind::Timeout => Self::ProbeTimeout(e),
+            _ => Self::Probe(e),
+        }
+    }
+}
+
+/// Messages are only of errors without a variant of their own, such as of
+/// invalid configuration, so targets, probes and sockets which fail must
+/// use theirs.
+impl From<String> for UppiesError {
+    fn from(message: String) -> Self {
+        Self::Message(message)
+    }
+}
+
+/// Hint of how to grant the privileges to open sockets, if they were
+/// lacking.
+fn privileges(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => format!(" ({SOCKET_PRIVILEGES})"),
+        _ => String::new(),
+    }
+}
+
+/// Errors of dependencies which are kept as [`UppiesError::Other`], as
+/// callers have no use in telling them apart.
+macro_rules! other_errors {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for UppiesError {
+                fn from(e: $error) -> Self {
+                    Self::Other(Box::new(e))
+                }
+            }
+        )*
+    };
+}
+
+other_errors!(
+    base64::DecodeError,
+    ed25519_dalek::ed25519::Error,
+    hex::FromHexError,
+    hyper::header::InvalidHeaderName,
+    hyper::header::InvalidHeaderValue,
+    lettre::error::Error,
+    lettre::transport::smtp::Error,
+    minijinja::Error,
+    notify::Error,
+    parquet::errors::ParquetError,
+    prost::EncodeError,
+    reqwest::Error,
+    rusqlite::Error,
+    serde_json::Error,
+    serde_yaml_ng::Error,
+    snap::Error,
+    tokio_rustls::rustls::Error,
+    tokio_rustls::rustls::client::VerifierBuilderError,
+    tokio_rustls::rustls::pki_types::InvalidDnsNameError,
+    toml::de::Error,
+    toml::ser::Error,
+);
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::UppiesError;
+    use crate::{ErrorKind, PingError};
+
+    #[test]
+    fn kinds() {
+        let ping = |kind| PingError {
+            kind,
+            message: "failed".to_string(),
+        };
+        assert!(matches!(
+            UppiesError::from(ping(ErrorKind::Timeout)),
+            UppiesError::ProbeTimeout(_)
+        ));
+        assert!(matches!(
+            UppiesError::from(ping(ErrorKind::Io)),
+            UppiesError::Probe(_)
+        ));
+
+        let socket = |kind| UppiesError::SocketInit {
+            target: "1.1.1.1".to_string(),
+            source: io::Error::from(kind),
+        };
+        let denied = socket(io::ErrorKind::PermissionDenied).to_string();
+        assert!(denied.starts_with("failed to create socket for '1.1.1.1': "));
+        assert!(denied.contains("CAP_NET_RAW"), "{denied}");
+        assert!(!socket(io::ErrorKind::Other)
+            .to_string()
+            .contains("CAP_NET_RAW"));
+    }
+}
@@ -0,0 +1,262 @@
+//! Log of every change of a target between up and down, so that questions
+//! such as when exactly a link dropped overnight can be answered without
+//! querying Prometheus.
+//!
+//! The most recent events are kept in memory and served at
+//! [`EventLog::PATH`]. They can optionally be appended to a file of one JSON
+//! [`Event`] per line, which is reloaded (and trimmed to the capacity) on
+//! startup so that events survive restarts.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{state::State, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EventsConfig {
+    /// Number of the most recent events which are kept.
+    #[serde(default = "EventsConfig::default_capacity")]
+    pub capacity: usize,
+    /// File which events are appended to, and reloaded from on startup.
+    pub path: Option<PathBuf>,
+}
+
+impl EventsConfig {
+    fn default_capacity() -> usize {
+        10_000
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            capacity: Self::default_capacity(),
+            path: None,
+        }
+    }
+}
+
+/// A change of a target between up and down.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub target: String,
+    /// Probe of the target which changed state, see [`ProbeTarget::probe`](crate::ProbeTarget::probe).
+    #[serde(default)]
+    pub probe: String,
+    /// State which the target changed to.
+    pub state: State,
+    /// Time of the change, in milliseconds since the unix epoch.
+    pub timestamp_ms: u64,
+    /// Time spent in the previous state, in milliseconds, unless it has been
+    /// in that state since startup. For a change to up, this is the
+    /// downtime.
+    pub duration_ms: Option<u64>,
+}
+
+/// Filter of the events returned by [`EventLog::query`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventQuery {
+    /// Only events of this target.
+    pub target: Option<String>,
+    /// Only events at or after this time, in milliseconds since the unix
+    /// epoch.
+    pub since_ms: Option<u64>,
+}
+
+impl EventQuery {
+    /// Parse the `since` of a query, either as milliseconds since the unix
+    /// epoch or as a duration before `now` such as `30m`, `12h` or `7d`.
+    pub fn parse_since(since: &str, now: SystemTime) -> Result<u64> {
+        if let Ok(ms) = since.parse() {
+            return Ok(ms);
+        }
+        let ago = crate::parse_duration(since).map_err(|_| {
+            format!("invalid since '{since}', expected a timestamp in milliseconds or a duration such as '12h'")
+        })?;
+        let since = now.checked_sub(ago).unwrap_or(UNIX_EPOCH);
+        Ok(since
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64)
+    }
+}
+
+/// Log of the most recent events.
+///
+/// Clones share the same events.
+#[derive(Clone)]
+pub struct EventLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    capacity: usize,
+    events: VecDeque<Event>,
+    /// File which events are appended to, if persisted.
+    file: Option<File>,
+}
+
+impl EventLog {
+    /// Path which events are served at.
+    pub const PATH: &str = "/events";
+
+    /// Create a log, reloading the events of its file if persisted.
+    pub fn new(config: &EventsConfig) -> Result<Self> {
+        let capacity = config.capacity.max(1);
+        let mut events = VecDeque::new();
+        let file = match &config.path {
+            Some(path) => {
+                if path.exists() {
+                    let reader = BufReader::new(File::open(path)?);
+                    for line in reader.lines() {
+                        let line = line?;
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        let event = serde_json::from_str(&line)
+                            .map_err(|e| format!("invalid event within {}: {e}", path.display()))?;
+                        if events.len() == capacity {
+                            events.pop_front();
+                        }
+                        events.push_back(event);
+                    }
+                }
+                // The file is rewritten with only the retained events, so
+                // that it doesn't grow without bound.
+                let mut file = File::create(path)?;
+                for event in &events {
+                    writeln!(file, "{}", serde_json::to_string(event)?)?;
+                }
+                info!(path = %path.display(), events = events.len(), "loaded event log");
+                Some(file)
+            }
+            None => None,
+        };
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                events,
+                file,
+            })),
+        })
+    }
+
+    /// Record an event, evicting the oldest once at capacity.
+    pub fn record(&self, event: Event) {
+        let mut inner = self.inner.lock().expect("event log lock poisoned");
+        if let Some(file) = &mut inner.file {
+            let written = serde_json::to_string(&event)
+                .map_err(std::io::Error::from)
+                .and_then(|line| writeln!(file, "{line}"));
+            if let Err(e) = written {
+                error!(?e, "failed to persist event");
+            }
+        }
+        if inner.events.len() == inner.capacity {
+            inner.events.pop_front();
+        }
+        inner.events.push_back(event);
+    }
+
+    /// Events matching the `query`, oldest first.
+    pub fn query(&self, query: &EventQuery) -> Vec<Event> {
+        let inner = self.inner.lock().expect("event log lock poisoned");
+        inner
+            .events
+            .iter()
+            .filter(|event| query.target.as_ref().is_none_or(|t| *t == event.target))
+            .filter(|event| {
+                query
+                    .since_ms
+                    .is_none_or(|since| event.timestamp_ms >= since)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{Event, EventLog, EventQuery, EventsConfig};
+    use crate::state::State;
+
+    fn event(target: &str, state: State, timestamp_ms: u64) -> Event {
+        Event {
+            target: target.to_string(),
+            probe: "icmp".to_string(),
+            state,
+            timestamp_ms,
+            duration_ms: None,
+        }
+    }
+
+    #[test]
+    fn query() {
+        let log = EventLog::new(&EventsConfig {
+            capacity: 3,
+            path: None,
+        })
+        .unwrap();
+        log.record(event("a", State::Down, 1));
+        log.record(event("a", State::Up, 2));
+        log.record(event("b", State::Down, 3));
+        log.record(event("a", State::Down, 4));
+
+        let all = log.query(&EventQuery::default());
+        assert_eq!(all.len(), 3, "oldest event is evicted");
+        assert_eq!(all[0].timestamp_ms, 2);
+        let query = EventQuery {
+            target: Some("a".to_string()),
+            since_ms: Some(3),
+        };
+        assert_eq!(log.query(&query), [event("a", State::Down, 4)]);
+    }
+
+    #[test]
+    fn persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = EventsConfig {
+            capacity: 2,
+            path: Some(dir.path().join("events.jsonl")),
+        };
+        let log = EventLog::new(&config).unwrap();
+        for timestamp_ms in 0..3 {
+            log.record(event("a", State::Down, timestamp_ms));
+        }
+        drop(log);
+
+        let reloaded = EventLog::new(&config).unwrap();
+        let events = reloaded.query(&EventQuery::default());
+        assert_eq!(
+            events,
+            [event("a", State::Down, 1), event("a", State::Down, 2)]
+        );
+        let contents = std::fs::read_to_string(dir.path().join("events.jsonl")).unwrap();
+        assert_eq!(contents.lines().count(), 2, "file is compacted on load");
+    }
+
+    #[test]
+    fn parse_since() {
+        let now = UNIX_EPOCH + Duration::from_secs(100_000);
+        assert_eq!(EventQuery::parse_since("1234", now).unwrap(), 1234);
+        assert_eq!(
+            EventQuery::parse_since("1h", now).unwrap(),
+            (100_000 - 3600) * 1000
+        );
+        assert_eq!(EventQuery::parse_since("7d", now).unwrap(), 0);
+        assert!(EventQuery::parse_since("yesterday", now).is_err());
+        assert!(EventQuery::parse_since("", now).is_err());
+    }
+}
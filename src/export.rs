@@ -0,0 +1,465 @@
+//! Export of the results recorded by the [SQLite sink](crate::sink::sqlite)
+//! as CSV or Parquet, so that they can be analysed with tools such as pandas
+//! or a spreadsheet.
+//!
+//! Rows are read within a range of time and written in batches, so that
+//! large databases aren't held in memory. Times are written as milliseconds
+//! since the unix epoch, which Parquet marks as UTC timestamps.
+
+use std::{io::Write, path::Path, str::FromStr, sync::Arc};
+
+use parquet::{
+    basic::{Compression, ZstdLevel},
+    column::writer::ColumnWriter,
+    data_type::ByteArray,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use rusqlite::{Connection, OpenFlags, Row};
+
+use crate::Result;
+
+/// Format of an export.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(format!(
+                "unknown export format '{s}', expected 'csv' or 'parquet'"
+            )),
+        }
+    }
+}
+
+/// Table of the database which is exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Table {
+    /// Every result, as written without downsampling.
+    #[default]
+    Results,
+    /// Aggregates of the results over each interval, as written with
+    /// downsampling.
+    Aggregates,
+}
+
+impl FromStr for Table {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "results" => Ok(Self::Results),
+            "aggregates" => Ok(Self::Aggregates),
+            _ => Err(format!(
+                "unknown table '{s}', expected 'results' or 'aggregates'"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Integer,
+    Real,
+    /// Milliseconds since the unix epoch.
+    Timestamp,
+}
+
+struct Column {
+    name: &'static str,
+    kind: Kind,
+    nullable: bool,
+}
+
+const fn column(name: &'static str, kind: Kind, nullable: bool) -> Column {
+    Column {
+        name,
+        kind,
+        nullable,
+    }
+}
+
+/// Columns of the `results` table, with the time second.
+const RESULTS: &[Column] = &[
+    column("target", Kind::Text, false),
+    column("timestamp_ms", Kind::Timestamp, false),
+    column("rtt_ms", Kind::Real, true),
+    column("error", Kind::Text, true),
+    column("probe", Kind::Text, false),
+];
+
+/// Columns of the `aggregates` table, with the time second.
+const AGGREGATES: &[Column] = &[
+    column("target", Kind::Text, false),
+    column("start_ms", Kind::Timestamp, false),
+    column("count", Kind::Integer, false),
+    column("failures", Kind::Integer, false),
+    column("rtt_min_ms", Kind::Real, true),
+    column("rtt_avg_ms", Kind::Real, true),
+    column("rtt_max_ms", Kind::Real, true),
+    column("probe", Kind::Text, false),
+];
+
+impl Table {
+    fn columns(&self) -> &'static [Column] {
+        match self {
+            Self::Results => RESULTS,
+            Self::Aggregates => AGGREGATES,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Results => "results",
+            Self::Aggregates => "aggregates",
+        }
+    }
+
+    /// Query of the rows between two times, ordered by time.
+    fn query(&self) -> String {
+        let columns = self.columns();
+        let names: Vec<_> = columns.iter().map(|c| c.name).collect();
+        let time = columns[1].name;
+        format!(
+            "SELECT {} FROM {} WHERE {time} >= ?1 AND {time} < ?2 ORDER BY {time}, target, probe",
+            names.join(", "),
+            self.name()
+        )
+    }
+
+    /// Parquet schema of the table.
+    fn schema(&self) -> String {
+        let fields: Vec<_> = self
+            .columns()
+            .iter()
+            .map(|c| {
+                let repetition = if c.nullable { "OPTIONAL" } else { "REQUIRED" };
+                let kind = match c.kind {
+                    Kind::Text => "BYTE_ARRAY",
+                    Kind::Integer => "INT64",
+                    Kind::Real => "DOUBLE",
+                    Kind::Timestamp => "INT64",
+                };
+                let logical = match c.kind {
+                    Kind::Text => " (UTF8)",
+                    Kind::Timestamp => " (TIMESTAMP(MILLIS,true))",
+                    Kind::Integer | Kind::Real => "",
+                };
+                format!("{repetition} {kind} {}{logical};", c.name)
+            })
+            .collect();
+        format!("message {} {{ {} }}", self.name(), fields.join(" "))
+    }
+}
+
+/// Values of a column within a batch of rows.
+enum Values {
+    Text(Vec<Option<String>>),
+    Integer(Vec<Option<i64>>),
+    Real(Vec<Option<f64>>),
+}
+
+impl Values {
+    fn new(kind: Kind) -> Self {
+        match kind {
+            Kind::Text => Self::Text(Vec::new()),
+            Kind::Integer | Kind::Timestamp => Self::Integer(Vec::new()),
+            Kind::Real => Self::Real(Vec::new()),
+        }
+    }
+
+    fn push(&mut self, row: &Row, i: usize) -> rusqlite::Result<()> {
+        match self {
+            Self::Text(values) => values.push(row.get(i)?),
+            Self::Integer(values) => values.push(row.get(i)?),
+            Self::Real(values) => values.push(row.get(i)?),
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Text(values) => values.len(),
+            Self::Integer(values) => values.len(),
+            Self::Real(values) => values.len(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Self::Text(values) => values.clear(),
+            Self::Integer(values) => values.clear(),
+            Self::Real(values) => values.clear(),
+        }
+    }
+
+    /// Value of row `i` as a CSV field, which is empty when null.
+    fn csv(&self, i: usize) -> String {
+        match self {
+            Self::Text(values) => values[i].as_deref().map(csv_escape).unwrap_or_default(),
+            Self::Integer(values) => values[i].map(|v| v.to_string()).unwrap_or_default(),
+            Self::Real(values) => values[i].map(|v| v.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Quote a CSV field if it contains a separator, quote or newline.
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Definition levels of nullable `values`, and the values which are present.
+fn levels<T: Clone>(values: &[Option<T>]) -> (Vec<i16>, Vec<T>) {
+    let levels = values.iter().map(|v| i16::from(v.is_some())).collect();
+    (levels, values.iter().flatten().cloned().collect())
+}
+
+enum Output<W: Write + Send> {
+    Csv(W),
+    Parquet(SerializedFileWriter<W>),
+}
+
+impl<W: Write + Send> Output<W> {
+    fn new(format: ExportFormat, table: Table, mut output: W) -> Result<Self> {
+        Ok(match format {
+            ExportFormat::Csv => {
+                let names: Vec<_> = table.columns().iter().map(|c| c.name).collect();
+                writeln!(output, "{}", names.join(","))?;
+                Self::Csv(output)
+            }
+            ExportFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(&table.schema())?);
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .build();
+                Self::Parquet(SerializedFileWriter::new(
+                    output,
+                    schema,
+                    Arc::new(properties),
+                )?)
+            }
+        })
+    }
+
+    /// Write a batch of rows, as a row group of Parquet.
+    fn write(&mut self, table: Table, batch: &[Values]) -> Result<()> {
+        match self {
+            Self::Csv(output) => {
+                for i in 0..batch[0].len() {
+                    let fields: Vec<_> = batch.iter().map(|values| values.csv(i)).collect();
+                    writeln!(output, "{}", fields.join(","))?;
+                }
+            }
+            Self::Parquet(writer) => {
+                let mut row_group = writer.next_row_group()?;
+                for (column, values) in table.columns().iter().zip(batch) {
+                    let mut writer = row_group.next_column()?.ok_or_else(|| {
+                        "parquet schema has fewer columns than the table".to_string()
+                    })?;
+                    match (writer.untyped(), values) {
+                        (ColumnWriter::ByteArrayColumnWriter(w), Values::Text(values)) => {
+                            let (levels, values) = levels(values);
+                            let values: Vec<ByteArray> =
+                                values.iter().map(|v| ByteArray::from(v.as_str())).collect();
+                            w.write_batch(&values, column.nullable.then_some(&levels[..]), None)?;
+                        }
+                        (ColumnWriter::Int64ColumnWriter(w), Values::Integer(values)) => {
+                            let (levels, values) = levels(values);
+                            w.write_batch(&values, column.nullable.then_some(&levels[..]), None)?;
+                        }
+                        (ColumnWriter::DoubleColumnWriter(w), Values::Real(values)) => {
+                            let (levels, values) = levels(values);
+                            w.write_batch(&values, column.nullable.then_some(&levels[..]), None)?;
+                        }
+                        _ => return Err(format!("mismatched column {}", column.name).into()),
+                    }
+                    writer.close()?;
+                }
+                row_group.close()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Csv(mut output) => output.flush()?,
+            Self::Parquet(writer) => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of rows which are read and written at a time, and so the size of
+/// each Parquet row group.
+const BATCH_SIZE: usize = 65_536;
+
+/// Export the rows of `table` within the database at `path` from `since_ms`
+/// until `until_ms`, returning the number of rows exported.
+pub fn export<W: Write + Send>(
+    path: &Path,
+    table: Table,
+    since_ms: Option<u64>,
+    until_ms: Option<u64>,
+    format: ExportFormat,
+    output: W,
+) -> Result<usize> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("cannot open sqlite database {}: {e}", path.display()))?;
+    let since = since_ms.map_or(0, |ms| ms.min(i64::MAX as u64) as i64);
+    let until = until_ms.map_or(i64::MAX, |ms| ms.min(i64::MAX as u64) as i64);
+    let mut statement = connection.prepare(&table.query())?;
+    let mut rows = statement.query([since, until])?;
+
+    let mut output = Output::new(format, table, output)?;
+    let mut batch: Vec<_> = table
+        .columns()
+        .iter()
+        .map(|c| Values::new(c.kind))
+        .collect();
+    let mut exported = 0;
+    loop {
+        let row = rows.next()?;
+        if let Some(row) = row {
+            for (i, values) in batch.iter_mut().enumerate() {
+                values.push(row, i)?;
+            }
+        }
+        let pending = batch[0].len();
+        if pending == BATCH_SIZE || (row.is_none() && pending > 0) {
+            output.write(table, &batch)?;
+            exported += pending;
+            batch.iter_mut().for_each(Values::clear);
+        }
+        if row.is_none() {
+            break;
+        }
+    }
+    output.finish()?;
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, path::Path};
+
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use super::{export, ExportFormat, Table};
+    use crate::sink::sqlite;
+
+    fn database(path: &Path) {
+        let connection = sqlite::open(path).unwrap();
+        connection
+            .execute_batch(
+                "INSERT INTO results VALUES ('1.1.1.1', 1000, 1.5, NULL, 'icmp');
+                 INSERT INTO results VALUES ('1.1.1.1', 2000, NULL, 'said \"no\", twice', 'icmp');
+                 INSERT INTO results VALUES ('8.8.8.8', 3000, 2.0, NULL, 'icmp');
+                 INSERT INTO aggregates VALUES ('1.1.1.1', 0, 2, 1, 1.5, 1.5, 1.5, 'icmp');",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.db");
+        database(&path);
+
+        let mut output = Vec::new();
+        let exported = export(
+            &path,
+            Table::Results,
+            Some(1000),
+            Some(3000),
+            ExportFormat::Csv,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(exported, 2, "until is exclusive");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "target,timestamp_ms,rtt_ms,error,probe\n\
+             1.1.1.1,1000,1.5,,icmp\n\
+             1.1.1.1,2000,,\"said \"\"no\"\", twice\",icmp\n"
+        );
+
+        let mut output = Vec::new();
+        export(
+            &path,
+            Table::Aggregates,
+            None,
+            None,
+            ExportFormat::Csv,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap().lines().nth(1),
+            Some("1.1.1.1,0,2,1,1.5,1.5,1.5,icmp")
+        );
+    }
+
+    #[test]
+    fn parquet() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.db");
+        database(&path);
+        let output = dir.path().join("results.parquet");
+
+        let exported = export(
+            &path,
+            Table::Results,
+            None,
+            None,
+            ExportFormat::Parquet,
+            File::create(&output).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(exported, 3);
+
+        let reader = SerializedFileReader::new(File::open(&output).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 3);
+        let columns: Vec<_> = metadata
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|c| c.name().to_string())
+            .collect();
+        assert_eq!(
+            columns,
+            ["target", "timestamp_ms", "rtt_ms", "error", "probe"]
+        );
+    }
+
+    #[test]
+    fn missing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = export(
+            &dir.path().join("missing.db"),
+            Table::Results,
+            None,
+            None,
+            ExportFormat::Csv,
+            Vec::new(),
+        );
+        assert!(result.is_err());
+    }
+}
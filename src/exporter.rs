@@ -0,0 +1,51 @@
+//! Push-based exporting of metrics, for when scraping the `/metrics`
+//! endpoint isn't possible or desirable.
+
+use std::{future::Future, time::Duration};
+
+use prometheus::{proto::MetricFamily, Registry};
+use tracing::{debug, error, info};
+
+use crate::{destination::Destination, groups::TargetGroups, Result};
+
+pub mod otlp;
+pub mod pushgateway;
+pub mod remote_write;
+
+/// A destination which a snapshot of all gathered metrics can be pushed to.
+pub trait Exporter: Send + Sync + 'static {
+    /// Name of the exporter, used for logging.
+    fn name(&self) -> &str;
+
+    /// Push the given metric families to the underlying destination.
+    fn export(&self, families: Vec<MetricFamily>) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Periodically gather all metrics from the [`Registry`], labelled by the
+/// `groups` of their targets, and push them using the given [`Exporter`].
+///
+/// Failed exports are logged, reported to `destination` and retried on the
+/// next interval.
+pub async fn run_exporter<E: Exporter>(
+    exporter: E,
+    groups: TargetGroups,
+    metrics: Registry,
+    interval: Duration,
+    destination: Destination,
+) {
+    info!(exporter = exporter.name(), ?interval, "starting exporter");
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match exporter.export(groups.gather(&metrics)).await {
+            Ok(()) => {
+                debug!(exporter = exporter.name(), "export success");
+                destination.succeeded();
+            }
+            Err(e) => {
+                error!(exporter = exporter.name(), ?e, "export failure");
+                destination.failed(e);
+            }
+        }
+    }
+}
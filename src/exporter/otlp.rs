@@ -0,0 +1,227 @@
+//! Export metrics to an OpenTelemetry collector using OTLP over HTTP,
+//! with the JSON encoding.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use serde_json::{json, Value};
+
+use super::Exporter;
+use crate::Result;
+
+/// Exporter which pushes metrics to the `/v1/metrics` path of an
+/// OTLP/HTTP endpoint, such as `http://localhost:4318`.
+pub struct OtlpExporter {
+    url: String,
+    client: reqwest::Client,
+    /// Start time of all cumulative metrics, in nanoseconds since the epoch.
+    start_time_ns: u128,
+}
+
+impl OtlpExporter {
+    const METRICS_PATH: &str = "/v1/metrics";
+
+    pub fn new(endpoint: &str) -> Result<Self> {
+        Ok(Self {
+            url: format!("{}{}", endpoint.trim_end_matches('/'), Self::METRICS_PATH),
+            client: reqwest::Client::builder().build()?,
+            start_time_ns: unix_nanos(),
+        })
+    }
+}
+
+impl Exporter for OtlpExporter {
+    fn name(&self) -> &str {
+        "otlp"
+    }
+
+    async fn export(&self, families: Vec<MetricFamily>) -> Result<()> {
+        let body = encode(&families, self.start_time_ns, unix_nanos());
+        self.client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after epoch")
+        .as_nanos()
+}
+
+/// Cumulative aggregation temporality, as all Prometheus metrics are.
+const AGGREGATION_TEMPORALITY_CUMULATIVE: u8 = 2;
+
+/// Encode the metric families as an OTLP `ExportMetricsServiceRequest`.
+///
+/// Note that the JSON encoding of OTLP requires 64-bit integers to be strings.
+fn encode(families: &[MetricFamily], start_time_ns: u128, time_ns: u128) -> Value {
+    let metrics: Vec<Value> = families
+        .iter()
+        .filter_map(|family| {
+            let points: Vec<Value> = family
+                .get_metric()
+                .iter()
+                .map(|m| data_point(family.get_field_type(), m, start_time_ns, time_ns))
+                .collect();
+            let (key, data) = match family.get_field_type() {
+                MetricType::COUNTER => (
+                    "sum",
+                    json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": AGGREGATION_TEMPORALITY_CUMULATIVE,
+                        "isMonotonic": true,
+                    }),
+                ),
+                MetricType::GAUGE => ("gauge", json!({ "dataPoints": points })),
+                MetricType::HISTOGRAM => (
+                    "histogram",
+                    json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": AGGREGATION_TEMPORALITY_CUMULATIVE,
+                    }),
+                ),
+                // No metrics of these types are produced.
+                MetricType::SUMMARY | MetricType::UNTYPED => return None,
+            };
+            let mut metric = json!({
+                "name": family.name(),
+                "description": family.help(),
+            });
+            metric[key] = data;
+            Some(metric)
+        })
+        .collect();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [attribute("service.name", "uppies")],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": "uppies", "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn data_point(kind: MetricType, metric: &Metric, start_time_ns: u128, time_ns: u128) -> Value {
+    let attributes: Vec<Value> = metric
+        .get_label()
+        .iter()
+        .map(|l| attribute(l.name(), l.value()))
+        .collect();
+    let mut point = json!({
+        "attributes": attributes,
+        "startTimeUnixNano": start_time_ns.to_string(),
+        "timeUnixNano": time_ns.to_string(),
+    });
+    match kind {
+        MetricType::COUNTER => point["asDouble"] = json!(metric.get_counter().value()),
+        MetricType::GAUGE => point["asDouble"] = json!(metric.get_gauge().value()),
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            let mut bounds = Vec::new();
+            let mut counts = Vec::new();
+            // Prometheus buckets are cumulative, whereas OTLP expects the
+            // count within each bucket, with a final overflow bucket.
+            let mut previous = 0;
+            for bucket in histogram.get_bucket() {
+                if bucket.upper_bound().is_finite() {
+                    bounds.push(bucket.upper_bound());
+                }
+                counts.push((bucket.cumulative_count() - previous).to_string());
+                previous = bucket.cumulative_count();
+            }
+            counts.push((histogram.get_sample_count() - previous).to_string());
+            point["count"] = json!(histogram.get_sample_count().to_string());
+            point["sum"] = json!(histogram.get_sample_sum());
+            point["bucketCounts"] = json!(counts);
+            point["explicitBounds"] = json!(bounds);
+        }
+        MetricType::SUMMARY | MetricType::UNTYPED => {}
+    }
+    point
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::State, routing::post, Json, Router};
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    use super::{encode, OtlpExporter};
+    use crate::exporter::Exporter;
+
+    fn test_registry() -> Registry {
+        let registry = Registry::new();
+        let counter =
+            IntCounterVec::new(Opts::new("ping_success_count", "help"), &["target"]).unwrap();
+        counter.with_label_values(&["127.0.0.1"]).inc_by(3);
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("ping_duration_ms", "help").buckets(vec![1.0, 10.0]),
+            &["target"],
+        )
+        .unwrap();
+        for v in [0.5, 5.0, 50.0] {
+            histogram.with_label_values(&["127.0.0.1"]).observe(v);
+        }
+        registry.register(Box::new(counter)).unwrap();
+        registry.register(Box::new(histogram)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn encodes_counters_and_histograms() {
+        let body = encode(&test_registry().gather(), 1, 2);
+        let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+
+        let histogram = &metrics[0];
+        assert_eq!(histogram["name"], "ping_duration_ms");
+        let point = &histogram["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "3");
+        assert_eq!(point["bucketCounts"], serde_json::json!(["1", "1", "1"]));
+        assert_eq!(point["explicitBounds"], serde_json::json!([1.0, 10.0]));
+        assert_eq!(point["attributes"][0]["value"]["stringValue"], "127.0.0.1");
+
+        let counter = &metrics[1];
+        assert_eq!(counter["name"], "ping_success_count");
+        assert_eq!(counter["sum"]["isMonotonic"], true);
+        assert_eq!(counter["sum"]["dataPoints"][0]["asDouble"], 3.0);
+    }
+
+    type Received = Arc<Mutex<Vec<Value>>>;
+
+    async fn collect(State(received): State<Received>, Json(body): Json<Value>) {
+        received.lock().unwrap().push(body);
+    }
+
+    #[tokio::test]
+    async fn exports_to_collector() {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/v1/metrics", post(collect))
+            .with_state(Arc::clone(&received));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let exporter = OtlpExporter::new(&format!("http://{addr}/")).unwrap();
+        exporter.export(test_registry().gather()).await.unwrap();
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,154 @@
+//! Export metrics to a Prometheus Pushgateway, for short-lived or
+//! firewalled instances which cannot be scraped.
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use prometheus::{proto::MetricFamily, Encoder, TextEncoder};
+
+use super::Exporter;
+use crate::Result;
+
+/// Exporter which replaces the metrics of its group on a Pushgateway,
+/// such as `http://pushgateway:9091`.
+pub struct PushgatewayExporter {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl PushgatewayExporter {
+    /// Label used to identify the instance when no grouping labels are given.
+    pub const INSTANCE_LABEL: &str = "instance";
+
+    /// Create an exporter pushing to the group identified by the `job` and
+    /// the given grouping labels.
+    ///
+    /// When no grouping labels are given, the `instance` label is set to the
+    /// hostname so that multiple instances don't overwrite each other.
+    pub fn new(endpoint: &str, job: &str, mut grouping: Vec<(String, String)>) -> Result<Self> {
+        if grouping.is_empty() {
+            let hostname = gethostname::gethostname()
+                .into_string()
+                .map_err(|_| "hostname is not valid UTF-8".to_string())?;
+            grouping.push((Self::INSTANCE_LABEL.to_string(), hostname));
+        }
+        let mut url = format!(
+            "{}/metrics/{}",
+            endpoint.trim_end_matches('/'),
+            path_segment("job", job)
+        );
+        for (name, value) in &grouping {
+            url.push('/');
+            url.push_str(&path_segment(name, value));
+        }
+        Ok(Self {
+            url,
+            client: reqwest::Client::builder().build()?,
+        })
+    }
+}
+
+/// Encode a grouping label as a `name/value` path segment. Values which
+/// cannot be safely included within a path are base64 encoded.
+fn path_segment(name: &str, value: &str) -> String {
+    if value.is_empty() {
+        format!("{name}@base64/=")
+    } else if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        format!("{name}/{value}")
+    } else {
+        format!("{name}@base64/{}", URL_SAFE.encode(value))
+    }
+}
+
+impl Exporter for PushgatewayExporter {
+    fn name(&self) -> &str {
+        "pushgateway"
+    }
+
+    async fn export(&self, families: Vec<MetricFamily>) -> Result<()> {
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        encoder.encode(&families, &mut body)?;
+        // PUT replaces all metrics within the group, so that series which are
+        // no longer reported (e.g. removed targets) don't linger.
+        self.client
+            .put(&self.url)
+            .header("Content-Type", encoder.format_type())
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        extract::{Path, State},
+        routing::put,
+        Router,
+    };
+    use prometheus::{IntCounter, Registry};
+    use tokio::net::TcpListener;
+
+    use super::{path_segment, PushgatewayExporter};
+    use crate::exporter::Exporter;
+
+    #[test]
+    fn grouping_label_segments() {
+        assert_eq!(path_segment("instance", "host-1"), "instance/host-1");
+        assert_eq!(path_segment("path", "/var/tmp"), "path@base64/L3Zhci90bXA=");
+        assert_eq!(path_segment("empty", ""), "empty@base64/=");
+    }
+
+    #[test]
+    fn default_instance_label() {
+        let exporter =
+            PushgatewayExporter::new("http://localhost:9091/", "uppies", vec![]).unwrap();
+        assert!(
+            exporter
+                .url
+                .starts_with("http://localhost:9091/metrics/job/uppies/instance"),
+            "unexpected url {}",
+            exporter.url
+        );
+    }
+
+    type Received = Arc<Mutex<Vec<(String, String)>>>;
+
+    async fn push(State(received): State<Received>, Path(group): Path<String>, body: String) {
+        received.lock().unwrap().push((group, body));
+    }
+
+    #[tokio::test]
+    async fn push_metrics() {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/metrics/{*group}", put(push))
+            .with_state(Arc::clone(&received));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let registry = Registry::new();
+        let counter = IntCounter::new("ping_success_count", "help").unwrap();
+        counter.inc();
+        registry.register(Box::new(counter)).unwrap();
+
+        let exporter = PushgatewayExporter::new(
+            &format!("http://{addr}"),
+            "uppies",
+            vec![("site".to_string(), "office".to_string())],
+        )
+        .unwrap();
+        exporter.export(registry.gather()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].0, "job/uppies/site/office");
+        assert!(received[0].1.contains("ping_success_count 1"));
+    }
+}
@@ -0,0 +1,107 @@
+//! Report of the capabilities compiled into this build, such as its probes,
+//! sinks and optional cargo features, printed by `uppies features`.
+//!
+//! Fleet tooling can check the report of an agent before shipping it a
+//! configuration which needs a capability, rather than finding out from a
+//! configuration error.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Capabilities of the build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features {
+    /// Version of uppies.
+    pub version: String,
+    /// Version of the configuration format which is written by
+    /// `migrate-config`, older versions are upgraded.
+    pub config_version: u32,
+    /// Protobuf package of the stream and history messages.
+    pub proto_package: String,
+    /// Optional cargo features which are enabled, such as `chaos`.
+    pub cargo_features: Vec<String>,
+    /// Kinds of probe which targets can be checked with.
+    pub probes: Vec<String>,
+    /// Values of `type` which `[[sinks]]` accept.
+    pub sinks: Vec<String>,
+    /// Kinds of notifier within `[notify]`.
+    pub notifiers: Vec<String>,
+    /// Push-based exporters of metrics.
+    pub exporters: Vec<String>,
+    /// Registries which targets can be discovered from.
+    pub discovery: Vec<String>,
+    /// Framings of the stream and history exports.
+    pub framings: Vec<String>,
+    /// Compressions of the stream and history exports.
+    pub compressions: Vec<String>,
+    /// Formats which results can be exported from a SQLite sink in.
+    pub export_formats: Vec<String>,
+}
+
+impl Features {
+    /// Capabilities of this build.
+    pub fn compiled() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|s| s.to_string()).collect();
+        let cargo_features = [
+            ("chaos", cfg!(feature = "chaos")),
+            ("metrics", cfg!(feature = "metrics")),
+            ("proto", cfg!(feature = "proto")),
+            ("server", cfg!(feature = "server")),
+        ]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect();
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            config_version: Config::VERSION,
+            proto_package: "uppies.v1".to_string(),
+            cargo_features,
+            probes: strings(&[
+                "icmp", "hostname", "neighbor", "tls", "grpc", "ssh", "smtp", "imap", "ntp", "arp",
+            ]),
+            sinks: strings(&["statsd", "influx", "sqlite", "log"]),
+            notifiers: strings(&["webhook", "slack", "discord", "pagerduty", "smtp"]),
+            exporters: strings(&["otlp", "remote_write", "pushgateway"]),
+            discovery: strings(&["consul", "etcd", "http"]),
+            framings: strings(&["json", "protobuf"]),
+            compressions: strings(&["none", "gzip", "zstd"]),
+            export_formats: strings(&["csv", "parquet"]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Features;
+    use crate::{
+        config::Config,
+        encoding::{Compression, Framing},
+        export::ExportFormat,
+    };
+
+    #[test]
+    fn reported_capabilities_exist() {
+        let features = Features::compiled();
+        for sink in &features.sinks {
+            // Sinks may require other fields, but their type must be known.
+            if let Err(e) = Config::parse(&format!("[[sinks]]\ntype = \"{sink}\"")) {
+                assert!(!e.to_string().contains("unknown variant"), "{sink}: {e}");
+            }
+        }
+        for framing in &features.framings {
+            framing.parse::<Framing>().unwrap();
+        }
+        for compression in &features.compressions {
+            compression.parse::<Compression>().unwrap();
+        }
+        for format in &features.export_formats {
+            format.parse::<ExportFormat>().unwrap();
+        }
+        assert_eq!(
+            features.cargo_features.contains(&"chaos".to_string()),
+            cfg!(feature = "chaos")
+        );
+    }
+}
@@ -0,0 +1,636 @@
+//! Prometheus alerting rules and a Grafana dashboard for the metrics of a
+//! configuration, so that monitoring of a new instance can be set up from
+//! its configuration rather than written by hand.
+//!
+//! Both only include what the configuration records, such as the rules of
+//! groups or burn rates, and use the names of the metrics under the
+//! namespace which they are exported with.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{config::Config, parse_duration, target::Scheme, ProbeTarget};
+
+/// File of Prometheus alerting rules, as loaded from `rule_files`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleFile {
+    pub groups: Vec<RuleGroup>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleGroup {
+    pub name: String,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rule {
+    pub alert: String,
+    pub expr: String,
+    /// Length of time which `expr` must hold before the alert fires.
+    #[serde(rename = "for")]
+    pub pending: String,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Rule {
+    fn new(alert: &str, expr: String, pending: &str, severity: &str, summary: &str) -> Self {
+        Self {
+            alert: alert.to_string(),
+            expr,
+            pending: pending.to_string(),
+            labels: BTreeMap::from([("severity".to_string(), severity.to_string())]),
+            annotations: BTreeMap::from([("summary".to_string(), summary.to_string())]),
+        }
+    }
+}
+
+/// Generates the rules and dashboard of a configuration and its targets.
+pub struct Generator<'a> {
+    config: &'a Config,
+    targets: &'a [ProbeTarget],
+    namespace: &'a str,
+}
+
+impl<'a> Generator<'a> {
+    /// Generate for the `targets` pinged with `config`, whose ping metrics
+    /// are exported under `namespace`, as given to
+    /// [`with_namespace`](crate::PingSenderBuilder::with_namespace), or
+    /// none if it is empty.
+    pub fn new(config: &'a Config, targets: &'a [ProbeTarget], namespace: &'a str) -> Self {
+        Self {
+            config,
+            targets,
+            namespace,
+        }
+    }
+
+    /// Name of a metric of [`PingMetrics`](crate::PingMetrics), which are
+    /// the only ones under the namespace.
+    fn ping_metric(&self, name: &str) -> String {
+        match self.namespace {
+            "" => name.to_string(),
+            namespace => format!("{namespace}_{name}"),
+        }
+    }
+
+    /// Ratio of pings of each probe of a target which failed, over `range`.
+    fn loss(&self, selector: &str, range: &str) -> String {
+        let rate = |name: &str| {
+            format!(
+                "sum by (target, probe) (rate({}{selector}[{range}]))",
+                self.ping_metric(name)
+            )
+        };
+        format!(
+            "{failures} / ({successes} + {failures})",
+            failures = rate("ping_failure_count"),
+            successes = rate("ping_success_count"),
+        )
+    }
+
+    /// Whether any target has its certificate expiry exported.
+    fn has_tls(&self) -> bool {
+        self.targets
+            .iter()
+            .any(|t| matches!(t.scheme(), Scheme::Tls | Scheme::Smtp | Scheme::Imap))
+    }
+
+    /// Alerting rules of the targets, their groups and of uppies itself.
+    pub fn rules(&self) -> RuleFile {
+        let mut groups = vec![RuleGroup {
+            name: "uppies-targets".to_string(),
+            rules: vec![
+                Rule::new(
+                    "UppiesTargetDown",
+                    "target_up == 0".to_string(),
+                    "1m",
+                    "critical",
+                    "The {{ $labels.probe }} probe of {{ $labels.target }} is down",
+                ),
+                Rule::new(
+                    "UppiesTargetLoss",
+                    format!("{} > 0.1", self.loss("", "5m")),
+                    "10m",
+                    "warning",
+                    "The {{ $labels.probe }} probe of {{ $labels.target }} is losing {{ $value | humanizePercentage }} of pings",
+                ),
+                Rule::new(
+                    "UppiesTargetFlapping",
+                    "target_flapping == 1".to_string(),
+                    "10m",
+                    "warning",
+                    "The {{ $labels.probe }} probe of {{ $labels.target }} is flapping between up and down",
+                ),
+            ],
+        }];
+        if !self.config.groups.is_empty() {
+            groups.push(RuleGroup {
+                name: "uppies-groups".to_string(),
+                rules: vec![
+                    Rule::new(
+                        "UppiesGroupTargetDown",
+                        "group_any_down == 1".to_string(),
+                        "2m",
+                        "warning",
+                        "A target of group {{ $labels.group }} is down",
+                    ),
+                    Rule::new(
+                        "UppiesGroupLoss",
+                        "group_loss_ratio > 0.1".to_string(),
+                        "10m",
+                        "warning",
+                        "Group {{ $labels.group }} is losing {{ $value | humanizePercentage }} of pings",
+                    ),
+                ],
+            });
+        }
+        if let Some(slo) = &self.config.slo {
+            let rules = burn_rate_rules(&slo.windows);
+            if !rules.is_empty() {
+                groups.push(RuleGroup {
+                    name: "uppies-slo".to_string(),
+                    rules,
+                });
+            }
+        }
+        if self.config.latency.is_some() {
+            groups.push(RuleGroup {
+                name: "uppies-latency".to_string(),
+                rules: vec![
+                    Rule::new(
+                        "UppiesTargetDegraded",
+                        r#"target_health{health="degraded"} == 1"#.to_string(),
+                        "10m",
+                        "warning",
+                        "The {{ $labels.probe }} probe of {{ $labels.target }} is degraded by its round-trip time",
+                    ),
+                    Rule::new(
+                        "UppiesTargetCritical",
+                        r#"target_health{health="critical"} == 1"#.to_string(),
+                        "5m",
+                        "critical",
+                        "The {{ $labels.probe }} probe of {{ $labels.target }} is critical by its round-trip time",
+                    ),
+                ],
+            });
+        }
+        if self.has_tls() {
+            let expiry = |days: u64| format!("tls_cert_expiry_seconds < {}", days * 86400);
+            groups.push(RuleGroup {
+                name: "uppies-tls".to_string(),
+                rules: vec![
+                    Rule::new(
+                        "UppiesCertificateExpiring",
+                        expiry(14),
+                        "1h",
+                        "warning",
+                        "The certificate of {{ $labels.target }} expires in {{ $value | humanizeDuration }}",
+                    ),
+                    Rule::new(
+                        "UppiesCertificateExpiring",
+                        expiry(3),
+                        "1h",
+                        "critical",
+                        "The certificate of {{ $labels.target }} expires in {{ $value | humanizeDuration }}",
+                    ),
+                ],
+            });
+        }
+        groups.push(RuleGroup {
+            name: "uppies".to_string(),
+            rules: vec![
+                Rule::new(
+                    "UppiesResultsDropped",
+                    format!(
+                        "increase({}[10m]) > 0",
+                        self.ping_metric("results_dropped_total")
+                    ),
+                    "0m",
+                    "warning",
+                    "uppies is dropping results as its sinks cannot keep up",
+                ),
+                Rule::new(
+                    "UppiesDispatcherRestarting",
+                    format!(
+                        "increase({}[15m]) > 0",
+                        self.ping_metric("dispatcher_restarts_total")
+                    ),
+                    "0m",
+                    "warning",
+                    "The {{ $labels.probe }} dispatcher of {{ $labels.target }} is restarting after failures",
+                ),
+            ],
+        });
+        RuleFile { groups }
+    }
+
+    /// Grafana dashboard of the targets, their groups and of uppies itself,
+    /// with the Prometheus data source chosen by a variable.
+    pub fn dashboard(&self) -> Value {
+        let selector = r#"{target=~"$target"}"#;
+        let mut panels = Panels::default();
+
+        panels.row("Targets");
+        panels.add(
+            "stat",
+            "Targets down",
+            "none",
+            &[(
+                format!("count(target_up{selector} == 0) or vector(0)"),
+                "down",
+            )],
+        );
+        panels.add(
+            "stat",
+            "Targets by state",
+            "none",
+            &[(
+                "sum by (state) (uppies_targets_by_state)".to_string(),
+                "{{state}}",
+            )],
+        );
+        panels.add(
+            "timeseries",
+            "Loss",
+            "percentunit",
+            &[(
+                self.loss(selector, "$__rate_interval"),
+                "{{target}} ({{probe}})",
+            )],
+        );
+        panels.add(
+            "timeseries",
+            "Round-trip time (p95)",
+            "ms",
+            &[(
+                format!(
+                    "histogram_quantile(0.95, sum by (target, probe, le) (rate({}_bucket{selector}[$__rate_interval])))",
+                    self.ping_metric("ping_duration_ms")
+                ),
+                "{{target}} ({{probe}})",
+            )],
+        );
+        panels.add(
+            "timeseries",
+            "Timeouts",
+            "ops",
+            &[(
+                format!(
+                    "sum by (target, probe) (rate({}{selector}[$__rate_interval]))",
+                    self.ping_metric("ping_timeouts_total")
+                ),
+                "{{target}} ({{probe}})",
+            )],
+        );
+        panels.add(
+            "timeseries",
+            "Availability",
+            "percentunit",
+            &[(
+                format!("target_availability_ratio{selector}"),
+                "{{target}} ({{window}})",
+            )],
+        );
+
+        if !self.config.groups.is_empty() {
+            let selector = r#"{group=~"$group"}"#;
+            panels.row("Groups");
+            panels.add(
+                "stat",
+                "Groups with a target down",
+                "none",
+                &[(format!("group_any_down{selector}"), "{{group}}")],
+            );
+            panels.add(
+                "timeseries",
+                "Group loss",
+                "percentunit",
+                &[(format!("group_loss_ratio{selector}"), "{{group}}")],
+            );
+        }
+        if self.config.slo.is_some() {
+            panels.row("Objectives");
+            panels.add(
+                "timeseries",
+                "Burn rate",
+                "none",
+                &[(
+                    format!("slo_burn_rate{selector}"),
+                    "{{target}} ({{window}})",
+                )],
+            );
+        }
+        if self.config.latency.is_some() {
+            panels.row("Latency");
+            panels.add(
+                "stat",
+                "Targets by health",
+                "none",
+                &[(
+                    format!("sum by (health) (target_health{selector})"),
+                    "{{health}}",
+                )],
+            );
+        }
+        if self.has_tls() {
+            panels.row("Certificates");
+            panels.add(
+                "stat",
+                "Certificate expiry",
+                "s",
+                &[(format!("tls_cert_expiry_seconds{selector}"), "{{target}}")],
+            );
+        }
+
+        panels.row("uppies");
+        panels.add(
+            "stat",
+            "Targets",
+            "none",
+            &[(self.ping_metric("uppies_targets"), "targets")],
+        );
+        panels.add(
+            "timeseries",
+            "Scheduling lag (p99)",
+            "s",
+            &[(
+                format!(
+                    "histogram_quantile(0.99, sum by (le) (rate({}_bucket[$__rate_interval])))",
+                    self.ping_metric("probe_scheduling_lag_seconds")
+                ),
+                "lag",
+            )],
+        );
+        panels.add(
+            "timeseries",
+            "Results queued",
+            "none",
+            &[
+                (self.ping_metric("uppies_results_queued"), "queued"),
+                (
+                    format!(
+                        "rate({}[$__rate_interval])",
+                        self.ping_metric("results_dropped_total")
+                    ),
+                    "dropped",
+                ),
+            ],
+        );
+        panels.add(
+            "timeseries",
+            "Dispatchers",
+            "none",
+            &[(self.ping_metric("uppies_dispatchers"), "{{state}}")],
+        );
+
+        let mut variables = vec![json!({
+            "name": "datasource",
+            "label": "Data source",
+            "type": "datasource",
+            "query": "prometheus",
+        })];
+        let mut targets_query = self.ping_metric("ping_success_count");
+        if !self.config.groups.is_empty() {
+            let groups: Vec<_> = self.config.groups.keys().map(String::as_str).collect();
+            variables.push(json!({
+                "name": "group",
+                "label": "Group",
+                "type": "custom",
+                "query": groups.join(","),
+                "multi": true,
+                "includeAll": true,
+                "allValue": ".*",
+                "current": { "text": "All", "value": "$__all" },
+            }));
+            targets_query.push_str(r#"{group=~"$group"}"#);
+        }
+        variables.push(json!({
+            "name": "target",
+            "label": "Target",
+            "type": "query",
+            "datasource": datasource(),
+            "query": { "query": format!("label_values({targets_query}, target)") },
+            "refresh": 2,
+            "sort": 1,
+            "multi": true,
+            "includeAll": true,
+            "allValue": ".*",
+            "current": { "text": "All", "value": "$__all" },
+        }));
+
+        json!({
+            "title": "uppies",
+            "uid": match self.namespace {
+                "" => "uppies".to_string(),
+                namespace => format!("uppies-{namespace}"),
+            },
+            "tags": ["uppies"],
+            "schemaVersion": 39,
+            "editable": true,
+            "refresh": "30s",
+            "time": { "from": "now-6h", "to": "now" },
+            "templating": { "list": variables },
+            "panels": panels.panels,
+        })
+    }
+}
+
+/// Rules which fire when the error budget burns quickly over both a window
+/// and the next shorter one, so that they stop firing soon after the burn
+/// does, with the thresholds of the multiwindow alerts of the SRE workbook.
+fn burn_rate_rules(windows: &[String]) -> Vec<Rule> {
+    let mut windows: Vec<(Duration, &str)> = windows
+        .iter()
+        .filter_map(|w| Some((parse_duration(w).ok()?, w.as_str())))
+        .collect();
+    windows.sort();
+    windows
+        .windows(2)
+        .map(|pair| {
+            let [(_, short), (long, window)] = [pair[0], pair[1]];
+            let (threshold, severity) = match long {
+                long if long <= Duration::from_secs(3600) => (14.4, "critical"),
+                long if long <= Duration::from_secs(6 * 3600) => (6.0, "warning"),
+                _ => (1.0, "warning"),
+            };
+            Rule::new(
+                "UppiesErrorBudgetBurn",
+                format!(
+                    r#"slo_burn_rate{{window="{window}"}} > {threshold} and on (target) slo_burn_rate{{window="{short}"}} > {threshold}"#
+                ),
+                "2m",
+                severity,
+                &format!("{{{{ $labels.target }}}} is burning its error budget {threshold}x faster than sustainable over {window}"),
+            )
+        })
+        .collect()
+}
+
+/// Panels of a dashboard, laid out two to a row beneath each row header.
+#[derive(Default)]
+struct Panels {
+    panels: Vec<Value>,
+    /// Top of the next row of panels.
+    y: u64,
+    /// Whether the latest row of panels has room for another.
+    half: bool,
+}
+
+impl Panels {
+    const WIDTH: u64 = 12;
+    const HEIGHT: u64 = 8;
+
+    fn next_id(&self) -> usize {
+        self.panels.len() + 1
+    }
+
+    fn row(&mut self, title: &str) {
+        if self.half {
+            self.y += Self::HEIGHT;
+            self.half = false;
+        }
+        self.panels.push(json!({
+            "id": self.next_id(),
+            "type": "row",
+            "title": title,
+            "collapsed": false,
+            "gridPos": { "x": 0, "y": self.y, "w": 24, "h": 1 },
+        }));
+        self.y += 1;
+    }
+
+    fn add(&mut self, kind: &str, title: &str, unit: &str, queries: &[(String, &str)]) {
+        let x = if self.half { Self::WIDTH } else { 0 };
+        let targets: Vec<_> = queries
+            .iter()
+            .zip('A'..)
+            .map(|((expr, legend), id)| {
+                json!({
+                    "refId": id.to_string(),
+                    "datasource": datasource(),
+                    "expr": expr,
+                    "legendFormat": legend,
+                })
+            })
+            .collect();
+        self.panels.push(json!({
+            "id": self.next_id(),
+            "type": kind,
+            "title": title,
+            "datasource": datasource(),
+            "fieldConfig": { "defaults": { "unit": unit }, "overrides": [] },
+            "gridPos": { "x": x, "y": self.y, "w": Self::WIDTH, "h": Self::HEIGHT },
+            "targets": targets,
+        }));
+        if self.half {
+            self.y += Self::HEIGHT;
+        }
+        self.half = !self.half;
+    }
+}
+
+/// The data source of every query, chosen by the `datasource` variable.
+fn datasource() -> Value {
+    json!({ "type": "prometheus", "uid": "${datasource}" })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::Generator;
+    use crate::{config::Config, ProbeTarget};
+
+    fn parse_targets(targets: &[&str]) -> Vec<ProbeTarget> {
+        targets.iter().map(|t| t.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn rules() {
+        let config = Config::default();
+        let targets = parse_targets(&["1.1.1.1"]);
+        let rules = Generator::new(&config, &targets, "").rules();
+        let names: Vec<_> = rules.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["uppies-targets", "uppies"]);
+
+        let config = Config::parse(
+            r#"
+            [groups]
+            dns = ["1.1.1.1"]
+
+            [slo]
+            windows = ["5m", "1h", "6h"]
+
+            [latency.default]
+            degraded_ms = 100
+            critical_ms = 500
+            "#,
+        )
+        .unwrap();
+        let targets = parse_targets(&["1.1.1.1", "tls://example.com"]);
+        let rules = Generator::new(&config, &targets, "edge").rules();
+        let names: Vec<_> = rules.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "uppies-targets",
+                "uppies-groups",
+                "uppies-slo",
+                "uppies-latency",
+                "uppies-tls",
+                "uppies"
+            ]
+        );
+        let slo = &rules.groups[2].rules;
+        assert_eq!(slo.len(), 2);
+        assert_eq!(
+            slo[0].expr,
+            r#"slo_burn_rate{window="1h"} > 14.4 and on (target) slo_burn_rate{window="5m"} > 14.4"#
+        );
+        assert_eq!(slo[1].labels["severity"], "warning");
+        assert!(rules.groups[0].rules[1]
+            .expr
+            .contains("rate(edge_ping_failure_count[5m])"));
+
+        let yaml = serde_yaml_ng::to_string(&rules).unwrap();
+        assert!(yaml.contains("for: 10m"), "{yaml}");
+    }
+
+    #[test]
+    fn dashboard() {
+        let config = Config::parse(
+            r#"
+            [groups]
+            dns = ["1.1.1.1"]
+            web = ["8.8.8.8"]
+            "#,
+        )
+        .unwrap();
+        let targets = parse_targets(&["1.1.1.1", "8.8.8.8"]);
+        let dashboard = Generator::new(&config, &targets, "edge").dashboard();
+
+        let variables = dashboard["templating"]["list"].as_array().unwrap();
+        assert_eq!(variables[1]["query"], "dns,web");
+        assert_eq!(
+            variables[2]["query"]["query"],
+            r#"label_values(edge_ping_success_count{group=~"$group"}, target)"#
+        );
+        let panels = dashboard["panels"].as_array().unwrap();
+        let titles: Vec<_> = panels
+            .iter()
+            .map(|p| p["title"].as_str().unwrap())
+            .collect();
+        assert!(titles.contains(&"Group loss"), "{titles:?}");
+        assert!(!titles.contains(&"Burn rate"), "{titles:?}");
+        // Panels don't overlap, two to a row.
+        let positions: BTreeSet<_> = panels
+            .iter()
+            .map(|p| (p["gridPos"]["y"].as_u64(), p["gridPos"]["x"].as_u64()))
+            .collect();
+        assert_eq!(positions.len(), panels.len());
+    }
+}
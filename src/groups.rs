@@ -0,0 +1,244 @@
+//! Named groups of targets, such as the routers of a site, which label the
+//! metrics of their targets and are aggregated across them.
+//!
+//! Every series with a `target` label is given the `group` label of its
+//! target when gathered, so that queries can select or aggregate whole
+//! groups. Each group also exports whether any of its targets is down, as
+//! `group_any_down`, and the ratio of the recent pings of its targets which
+//! were lost, as `group_loss_ratio`.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use prometheus::{
+    proto::{LabelPair, MetricFamily},
+    GaugeVec, IntGaugeVec, Opts, Registry,
+};
+
+use crate::{sink::Sink, PingOutcome, Result};
+
+/// Targets of each group, by the name of the group.
+pub type GroupsConfig = BTreeMap<String, Vec<String>>;
+
+/// Whether each recent ping of a target succeeded.
+#[derive(Default)]
+struct Recent(VecDeque<bool>);
+
+/// Groups of targets, which are recorded as a [`Sink`].
+#[derive(Clone)]
+pub struct TargetGroups {
+    /// Group of each target which is part of one.
+    groups: Arc<HashMap<String, String>>,
+    /// Recent pings of each probe of every target which is part of a group,
+    /// by label and probe.
+    recent: Arc<Mutex<HashMap<(String, String), Recent>>>,
+
+    /// Whether the last ping of any target of the group failed.
+    any_down: IntGaugeVec,
+    /// Ratio of the recent pings of the targets of the group which failed.
+    loss: GaugeVec,
+}
+
+impl TargetGroups {
+    /// Label added to the series of each target which is part of a group.
+    pub const LABEL: &str = "group";
+    /// Number of recent pings of each target which its group's loss covers.
+    const SAMPLES: usize = 20;
+
+    pub fn new(config: &GroupsConfig, metrics: &Registry) -> Result<Self> {
+        let mut groups = HashMap::new();
+        for (group, targets) in config {
+            if group.is_empty() {
+                return Err("group names must be non-empty".to_string().into());
+            }
+            for target in targets {
+                if let Some(other) = groups.insert(target.clone(), group.clone()) {
+                    if other != *group {
+                        return Err(format!(
+                            "target '{target}' is in both groups '{other}' and '{group}'"
+                        )
+                        .into());
+                    }
+                }
+            }
+        }
+        let any_down = IntGaugeVec::new(
+            Opts::new(
+                "group_any_down",
+                "Whether the last ping of any target of the group failed",
+            ),
+            &[Self::LABEL],
+        )?;
+        let loss = GaugeVec::new(
+            Opts::new(
+                "group_loss_ratio",
+                "Ratio of the recent pings of the targets of the group which were lost",
+            ),
+            &[Self::LABEL],
+        )?;
+        metrics.register(Box::new(any_down.clone()))?;
+        metrics.register(Box::new(loss.clone()))?;
+        Ok(Self {
+            groups: Arc::new(groups),
+            recent: Arc::default(),
+            any_down,
+            loss,
+        })
+    }
+
+    /// Group of the target labelled `target`, if it is part of one.
+    pub fn group(&self, target: &str) -> Option<&str> {
+        self.groups.get(target).map(String::as_str)
+    }
+
+    /// Add the `group` label to every gathered series of a target which is
+    /// part of a group.
+    pub fn label(&self, families: &mut [MetricFamily]) {
+        if self.groups.is_empty() {
+            return;
+        }
+        for metric in families.iter_mut().flat_map(|f| f.mut_metric().iter_mut()) {
+            let labels = &mut metric.label;
+            if labels.iter().any(|l| l.name() == Self::LABEL) {
+                continue;
+            }
+            let Some(group) = labels
+                .iter()
+                .find(|l| l.name() == "target")
+                .and_then(|l| self.group(l.value()))
+            else {
+                continue;
+            };
+            let mut label = LabelPair::default();
+            label.set_name(Self::LABEL.to_string());
+            label.set_value(group.to_string());
+            // Gathered labels are sorted by name, which is kept.
+            let at = labels.partition_point(|l| l.name() < Self::LABEL);
+            labels.insert(at, label);
+        }
+    }
+
+    /// Gather all metrics of `metrics`, labelled by their groups.
+    pub fn gather(&self, metrics: &Registry) -> Vec<MetricFamily> {
+        let mut families = metrics.gather();
+        self.label(&mut families);
+        families
+    }
+}
+
+impl Sink for TargetGroups {
+    fn record(&self, outcome: &PingOutcome) {
+        let Some(group) = self.group(&outcome.target) else {
+            return;
+        };
+        let mut recent = self.recent.lock().expect("groups lock poisoned");
+        let key = (outcome.target.to_string(), outcome.probe.to_string());
+        let pings = &mut recent.entry(key).or_default().0;
+        if pings.len() == Self::SAMPLES {
+            pings.pop_front();
+        }
+        pings.push_back(outcome.rtt.is_ok());
+
+        // Targets which haven't been pinged yet don't count, as they aren't
+        // known to be down.
+        let (mut down, mut lost, mut total) = (false, 0, 0);
+        for ((target, _), pings) in recent.iter() {
+            if self.group(target) != Some(group) {
+                continue;
+            }
+            down |= pings.0.back() == Some(&false);
+            lost += pings.0.iter().filter(|ok| !**ok).count();
+            total += pings.0.len();
+        }
+        self.any_down.with_label_values(&[group]).set(down.into());
+        self.loss
+            .with_label_values(&[group])
+            .set(lost as f64 / total as f64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use prometheus::{IntCounterVec, Opts, Registry};
+
+    use super::{GroupsConfig, TargetGroups};
+    use crate::{sink::Sink, ErrorKind, PingOutcome};
+
+    fn groups(metrics: &Registry) -> TargetGroups {
+        let config: GroupsConfig = [(
+            "core-routers".to_string(),
+            vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()],
+        )]
+        .into();
+        TargetGroups::new(&config, metrics).unwrap()
+    }
+
+    /// Value of the gauge `name` of the group.
+    fn value(metrics: &Registry, name: &str) -> Option<f64> {
+        let family = metrics.gather().into_iter().find(|m| m.name() == name)?;
+        let metric = family.get_metric().first()?;
+        assert_eq!(metric.get_label()[0].value(), "core-routers");
+        Some(metric.get_gauge().value())
+    }
+
+    #[test]
+    fn aggregates() {
+        let metrics = Registry::new();
+        let groups = groups(&metrics);
+        let ok = |target| PingOutcome::test(target, Ok(Duration::from_millis(1)));
+        let failed = |target| PingOutcome::test(target, Err(ErrorKind::Timeout));
+
+        groups.record(&ok("10.0.0.1"));
+        groups.record(&failed("1.1.1.1"));
+        assert_eq!(value(&metrics, "group_any_down"), Some(0.0));
+        assert_eq!(value(&metrics, "group_loss_ratio"), Some(0.0));
+
+        groups.record(&failed("10.0.0.2"));
+        assert_eq!(value(&metrics, "group_any_down"), Some(1.0));
+        assert_eq!(value(&metrics, "group_loss_ratio"), Some(0.5));
+
+        groups.record(&ok("10.0.0.2"));
+        assert_eq!(value(&metrics, "group_any_down"), Some(0.0));
+        assert!((value(&metrics, "group_loss_ratio").unwrap() - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn labels() {
+        let metrics = Registry::new();
+        let groups = groups(&metrics);
+        let counter = IntCounterVec::new(Opts::new("pings", "Pings"), &["target", "kind"]).unwrap();
+        metrics.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["10.0.0.1", "icmp"]).inc();
+        counter.with_label_values(&["1.1.1.1", "icmp"]).inc();
+
+        let families = groups.gather(&metrics);
+        let pings = families.iter().find(|f| f.name() == "pings").unwrap();
+        let labels: Vec<Vec<_>> = pings
+            .get_metric()
+            .iter()
+            .map(|m| m.get_label().iter().map(|l| l.name()).collect())
+            .collect();
+        assert_eq!(
+            labels,
+            [vec!["kind", "target"], vec!["group", "kind", "target"]]
+        );
+    }
+
+    #[test]
+    fn overlapping() {
+        let config: GroupsConfig = [
+            ("a".to_string(), vec!["10.0.0.1".to_string()]),
+            ("b".to_string(), vec!["10.0.0.1".to_string()]),
+        ]
+        .into();
+        let e = TargetGroups::new(&config, &Registry::new())
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(e, "target '10.0.0.1' is in both groups 'a' and 'b'");
+    }
+}
@@ -0,0 +1,237 @@
+//! A single weighted "internet health" index across selected targets.
+//!
+//! Each probe of a selected target is scored by the fraction of its recent
+//! pings which succeeded, optionally only counting those faster than a
+//! maximum RTT, and the target by the average of its probes. The index is
+//! the weighted average of the scores of targets, from 0 to 100, so that
+//! e.g. the local gateway can matter more than a popular website.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use prometheus::{Gauge, GaugeVec, Opts, Registry};
+use serde::Deserialize;
+
+use crate::{sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Number of recent pings each target is scored over.
+    #[serde(default = "HealthConfig::default_samples")]
+    pub samples: usize,
+    /// Targets which contribute to the index.
+    pub targets: Vec<WeightedTarget>,
+}
+
+impl HealthConfig {
+    fn default_samples() -> usize {
+        20
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WeightedTarget {
+    pub target: String,
+    /// Relative weight of the target within the index.
+    #[serde(default = "WeightedTarget::default_weight")]
+    pub weight: f64,
+    /// Successful pings slower than this are scored as failures.
+    pub max_rtt_ms: Option<f64>,
+}
+
+impl WeightedTarget {
+    fn default_weight() -> f64 {
+        1.0
+    }
+}
+
+struct TargetScore {
+    weight: f64,
+    max_rtt: Option<Duration>,
+    /// Whether each recent ping of each probe was considered healthy.
+    recent: HashMap<String, VecDeque<bool>>,
+}
+
+impl TargetScore {
+    /// Score of the recent pings of a probe from 0 to 1, or `None` if none
+    /// have been seen.
+    fn probe_score(recent: &VecDeque<bool>) -> Option<f64> {
+        if recent.is_empty() {
+            return None;
+        }
+        let healthy = recent.iter().filter(|h| **h).count();
+        Some(healthy as f64 / recent.len() as f64)
+    }
+
+    /// Average score of the probes of the target from 0 to 1, or `None` if
+    /// no pings have been seen.
+    fn score(&self) -> Option<f64> {
+        let scores: Vec<f64> = self.recent.values().filter_map(Self::probe_score).collect();
+        (!scores.is_empty()).then(|| scores.iter().sum::<f64>() / scores.len() as f64)
+    }
+}
+
+pub struct HealthIndex {
+    samples: usize,
+    targets: Mutex<HashMap<String, TargetScore>>,
+
+    /// Weighted health index across all selected targets, from 0 to 100.
+    index: Gauge,
+    /// Score of each probe of each selected target, from 0 to 100.
+    target_score: GaugeVec,
+}
+
+impl HealthIndex {
+    pub fn new(config: &HealthConfig, metrics: &Registry) -> Result<Self> {
+        let index = Gauge::new(
+            "internet_health_score",
+            "Weighted health index across selected targets, from 0 to 100",
+        )?;
+        let target_score = GaugeVec::new(
+            Opts::new(
+                "internet_health_target_score",
+                "Health score of a target contributing to the health index, from 0 to 100",
+            ),
+            &["target", "probe"],
+        )?;
+        metrics.register(Box::new(index.clone()))?;
+        metrics.register(Box::new(target_score.clone()))?;
+
+        let targets = config
+            .targets
+            .iter()
+            .map(|t| {
+                if t.weight < 0.0 {
+                    return Err(
+                        format!("health weight of {} must not be negative", t.target).into(),
+                    );
+                }
+                Ok((
+                    t.target.clone(),
+                    TargetScore {
+                        weight: t.weight,
+                        max_rtt: t.max_rtt_ms.map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+                        recent: HashMap::new(),
+                    },
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            samples: config.samples.max(1),
+            targets: Mutex::new(targets),
+            index,
+            target_score,
+        })
+    }
+}
+
+impl Sink for HealthIndex {
+    fn record(&self, outcome: &PingOutcome) {
+        let mut targets = self.targets.lock().expect("health lock poisoned");
+        let Some(score) = targets.get_mut(&*outcome.target) else {
+            return;
+        };
+        let healthy = match (outcome.latency(), score.max_rtt) {
+            (Some(rtt), Some(max)) => rtt <= max,
+            _ => outcome.rtt.is_ok(),
+        };
+        let recent = score
+            .recent
+            .entry(outcome.probe.to_string())
+            .or_insert_with(|| VecDeque::with_capacity(self.samples));
+        if recent.len() == self.samples {
+            recent.pop_front();
+        }
+        recent.push_back(healthy);
+        if let Some(s) = TargetScore::probe_score(recent) {
+            self.target_score
+                .with_label_values(&[&*outcome.target, &*outcome.probe])
+                .set(s * 100.0);
+        }
+
+        // Targets which haven't been pinged yet don't contribute, rather
+        // than dragging down the index on startup.
+        let (weighted, total_weight) = targets
+            .values()
+            .filter_map(|t| t.score().map(|s| (s * t.weight, t.weight)))
+            .fold((0.0, 0.0), |(ws, tw), (s, w)| (ws + s, tw + w));
+        if total_weight > 0.0 {
+            self.index.set(weighted / total_weight * 100.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use prometheus::Registry;
+
+    use super::{HealthConfig, HealthIndex, WeightedTarget};
+    use crate::{sink::Sink, ErrorKind, PingOutcome};
+
+    const GATEWAY: &str = "192.168.1.1";
+    const WEBSITE: &str = "1.1.1.1";
+
+    fn index() -> HealthIndex {
+        HealthIndex::new(
+            &HealthConfig {
+                samples: 4,
+                targets: vec![
+                    WeightedTarget {
+                        target: GATEWAY.to_string(),
+                        weight: 3.0,
+                        max_rtt_ms: None,
+                    },
+                    WeightedTarget {
+                        target: WEBSITE.to_string(),
+                        weight: 1.0,
+                        max_rtt_ms: Some(50.0),
+                    },
+                ],
+            },
+            &Registry::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn weighted_index() {
+        let index = index();
+        let ok = Ok(Duration::from_millis(10));
+
+        index.record(&PingOutcome::test(GATEWAY, ok));
+        assert_eq!(index.index.get(), 100.0, "only seen targets contribute");
+
+        // The website is slower than its maximum RTT, so is unhealthy.
+        index.record(&PingOutcome::test(WEBSITE, Ok(Duration::from_millis(100))));
+        assert_eq!(index.index.get(), 75.0);
+
+        index.record(&PingOutcome::test("10.0.0.1", Err(ErrorKind::Timeout)));
+        assert_eq!(index.index.get(), 75.0, "unselected targets are ignored");
+    }
+
+    #[test]
+    fn score_over_recent_samples() {
+        let index = index();
+        for _ in 0..4 {
+            index.record(&PingOutcome::test(GATEWAY, Err(ErrorKind::Timeout)));
+        }
+        assert_eq!(index.index.get(), 0.0);
+
+        index.record(&PingOutcome::test(GATEWAY, Ok(Duration::from_millis(1))));
+        assert_eq!(
+            index
+                .target_score
+                .with_label_values(&[GATEWAY, "icmp"])
+                .get(),
+            25.0
+        );
+        assert_eq!(index.index.get(), 25.0);
+    }
+}
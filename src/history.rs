@@ -0,0 +1,566 @@
+//! History of ping results, written to a file in batches.
+//!
+//! Batches can optionally be signed with an ed25519 key belonging to the
+//! agent, so that third parties (e.g. an ISP within an SLA dispute) can verify
+//! that the measurements haven't been altered after the fact. Each batch
+//! carries a sequence number and the total number of records written up to
+//! it, which allows the removal of batches to be detected too. Batches
+//! removed from the end can only be detected against a total known to have
+//! been written, such as the one logged by the agent when it stops.
+//!
+//! The public key of the agent is embedded within each batch, which only
+//! shows that the batches were signed by the same key, so verifying that
+//! they were signed by the agent requires its key to be given.
+//!
+//! The history file contains one JSON encoded [`SignedBatch`] per line,
+//! whose records can be exported in any [`encoding`](crate::encoding).
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    encoding::{Encoder, Frame},
+    proto,
+    sink::Sink,
+    PingOutcome, Result,
+};
+
+/// Outcome of a single ping against a target.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Record {
+    pub target: String,
+    /// Probe of the target, see [`ProbeTarget::probe`](crate::ProbeTarget::probe),
+    /// which is empty in records written before it was recorded. It is left
+    /// out when empty so that the signatures of those records still verify.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub probe: String,
+    /// Time that the result was recorded, in milliseconds since the epoch.
+    pub timestamp_ms: u64,
+    /// Round-trip time in microseconds, if the ping was successful, which
+    /// pings of targets expected to be down never have.
+    pub rtt_us: Option<u64>,
+    /// Description of the error, if the ping failed.
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(outcome: &PingOutcome) -> Self {
+        let timestamp_ms = outcome
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis() as u64;
+        Self {
+            target: outcome.target.to_string(),
+            probe: outcome.probe.to_string(),
+            timestamp_ms,
+            rtt_us: outcome.latency().map(|d| d.as_micros() as u64),
+            error: outcome.rtt.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+impl Frame for Record {
+    type Proto = proto::HistoryRecord;
+
+    fn to_proto(&self) -> Self::Proto {
+        self.into()
+    }
+}
+
+/// The signed content of a [`SignedBatch`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Batch {
+    /// Sequence number of the batch, incremented for every batch written.
+    pub sequence: u64,
+    /// Number of records within this and every earlier batch, which is
+    /// missing in batches written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_records: Option<u64>,
+    pub records: Vec<Record>,
+}
+
+/// A [`Batch`] of records, alongside its signature when signing is enabled.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedBatch {
+    #[serde(flatten)]
+    pub batch: Batch,
+    /// Hex encoded ed25519 public key of the agent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Hex encoded ed25519 signature of the JSON encoded [`Batch`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl SignedBatch {
+    fn new(batch: Batch, key: Option<&SigningKey>) -> Result<Self> {
+        let (public_key, signature) = match key {
+            Some(key) => {
+                let signature = key.sign(&serde_json::to_vec(&batch)?);
+                (
+                    Some(hex::encode(key.verifying_key().to_bytes())),
+                    Some(hex::encode(signature.to_bytes())),
+                )
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            batch,
+            public_key,
+            signature,
+        })
+    }
+
+    /// Verify the signature of this batch, returning the key which it
+    /// was signed with.
+    fn verify(&self) -> Result<VerifyingKey> {
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return Err(format!("batch {} is not signed", self.batch.sequence).into());
+        };
+        let public_key = parse_public_key(public_key)?;
+        let signature: [u8; 64] = hex::decode(signature)?
+            .try_into()
+            .map_err(|_| "invalid signature length".to_string())?;
+        public_key
+            .verify(
+                &serde_json::to_vec(&self.batch)?,
+                &Signature::from_bytes(&signature),
+            )
+            .map_err(|_| format!("batch {} has an invalid signature", self.batch.sequence))?;
+        Ok(public_key)
+    }
+}
+
+/// Parse a hex encoded ed25519 public key.
+pub fn parse_public_key(key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(key.trim())?
+        .try_into()
+        .map_err(|_| "invalid public key length".to_string())?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// Load the hex encoded signing key from `path`, generating and writing
+/// a new key if it does not exist.
+pub fn load_or_generate_key(path: &Path) -> Result<SigningKey> {
+    if path.exists() {
+        let seed: [u8; 32] = hex::decode(std::fs::read_to_string(path)?.trim())?
+            .try_into()
+            .map_err(|_| "invalid signing key length".to_string())?;
+        return Ok(SigningKey::from_bytes(&seed));
+    }
+
+    let key = SigningKey::from_bytes(&rand::random());
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", hex::encode(key.to_bytes()))?;
+    info!(
+        path = %path.display(),
+        public_key = hex::encode(key.verifying_key().to_bytes()),
+        "generated signing key"
+    );
+    Ok(key)
+}
+
+/// Summary of a verified history file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verified {
+    pub batches: usize,
+    pub records: usize,
+    /// Key which every batch was signed by, if there were any batches.
+    pub public_key: Option<VerifyingKey>,
+}
+
+/// Verify every batch within a history file.
+///
+/// When `expected_key` is given, batches must have been signed by it.
+/// Otherwise, all batches must have been signed by the same key, which is
+/// only taken from the file itself.
+pub fn verify(history: impl BufRead, expected_key: Option<VerifyingKey>) -> Result<Verified> {
+    let mut expected_key = expected_key;
+    let mut expected_sequence = 0;
+    let mut verified = Verified {
+        batches: 0,
+        records: 0,
+        public_key: None,
+    };
+    for line in history.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let batch: SignedBatch = serde_json::from_str(&line)?;
+        let key = batch.verify()?;
+        match expected_key {
+            Some(expected) if expected != key => {
+                return Err(format!(
+                    "batch {} was signed by an unexpected key",
+                    batch.batch.sequence
+                )
+                .into())
+            }
+            Some(_) => {}
+            None => expected_key = Some(key),
+        }
+        if batch.batch.sequence != expected_sequence {
+            return Err(format!(
+                "expected batch {expected_sequence} but found {}, batches are missing or reordered",
+                batch.batch.sequence
+            )
+            .into());
+        }
+        expected_sequence += 1;
+        verified.batches += 1;
+        verified.records += batch.batch.records.len();
+        match batch.batch.total_records {
+            Some(total) if total != verified.records as u64 => {
+                return Err(format!(
+                    "batch {} follows {total} records but {} were found, records are missing",
+                    batch.batch.sequence, verified.records
+                )
+                .into())
+            }
+            _ => {}
+        }
+    }
+    verified.public_key = expected_key;
+    Ok(verified)
+}
+
+/// Write every record within a history file to `output` with the
+/// `encoder`, without verifying the batches, returning the number of
+/// records.
+pub fn export(
+    history: impl BufRead,
+    mut encoder: Encoder,
+    mut output: impl Write,
+) -> Result<usize> {
+    let mut records = 0;
+    for line in history.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let batch: SignedBatch = serde_json::from_str(&line)?;
+        output.write_all(&encoder.encode(&batch.batch.records)?)?;
+        records += batch.batch.records.len();
+    }
+    output.write_all(&encoder.finish()?)?;
+    output.flush()?;
+    Ok(records)
+}
+
+/// Handle for recording results into the history file.
+#[derive(Clone)]
+pub struct HistoryWriter {
+    tx: mpsc::Sender<Record>,
+    /// Whether the most recent write succeeded, without records being dropped.
+    healthy: Arc<AtomicBool>,
+}
+
+impl HistoryWriter {
+    /// Number of records which can be queued before they are dropped.
+    const CHANNEL_SIZE: usize = 1024;
+
+    /// Start a background task which appends batches of records to the
+    /// file at `path`, signing them with `key` when given.
+    ///
+    /// A batch is written once `batch_size` records have been collected,
+    /// or when `flush_interval` has elapsed.
+    pub fn spawn(
+        path: PathBuf,
+        key: Option<SigningKey>,
+        batch_size: usize,
+        flush_interval: Duration,
+    ) -> Result<Self> {
+        let (mut sequence, mut total_records) = written(&path)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let (tx, mut rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let healthy = Arc::new(AtomicBool::new(true));
+        let writer_healthy = Arc::clone(&healthy);
+
+        tokio::spawn(async move {
+            let mut records = Vec::with_capacity(batch_size);
+            let mut interval = tokio::time::interval(flush_interval);
+            loop {
+                let closed = tokio::select! {
+                    record = rx.recv() => match record {
+                        Some(record) => {
+                            records.push(record);
+                            if records.len() < batch_size {
+                                continue;
+                            }
+                            false
+                        },
+                        None => true,
+                    },
+                    _ = interval.tick() => false,
+                };
+                if !records.is_empty() {
+                    let batch = Batch {
+                        sequence,
+                        total_records: Some(total_records + records.len() as u64),
+                        records: std::mem::take(&mut records),
+                    };
+                    match write_batch(&mut file, &batch, key.as_ref()) {
+                        Ok(()) => {
+                            sequence += 1;
+                            total_records += batch.records.len() as u64;
+                            debug!(sequence, total_records, "wrote history batch");
+                            writer_healthy.store(true, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            error!(path = %path.display(), ?e, "failed to write history");
+                            writer_healthy.store(false, Ordering::Relaxed);
+                        }
+                    }
+                }
+                if closed {
+                    info!(path = %path.display(), total_records, "closed history");
+                    return;
+                }
+            }
+        });
+
+        Ok(Self { tx, healthy })
+    }
+}
+
+impl Sink for HistoryWriter {
+    /// Records are dropped if the writer cannot keep up.
+    fn record(&self, outcome: &PingOutcome) {
+        let target = &*outcome.target;
+        match self.tx.try_send(Record::new(outcome)) {
+            Ok(()) => return,
+            Err(TrySendError::Full(_)) => warn!(target, "history writer is full, dropping record"),
+            Err(TrySendError::Closed(_)) => error!(target, "history writer closed"),
+        }
+        self.healthy.store(false, Ordering::Relaxed);
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+}
+
+fn write_batch(file: &mut std::fs::File, batch: &Batch, key: Option<&SigningKey>) -> Result<()> {
+    let mut line = serde_json::to_vec(&SignedBatch::new(batch.clone(), key)?)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Sequence number following the last batch within an existing history
+/// file, and the number of records within it, so that both are continuous
+/// across restarts.
+fn written(path: &Path) -> Result<(u64, u64)> {
+    if !path.exists() {
+        return Ok((0, 0));
+    }
+    let (mut sequence, mut total_records) = (0, 0);
+    for line in BufReader::new(std::fs::File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let batch = serde_json::from_str::<SignedBatch>(&line)?.batch;
+        sequence = batch.sequence + 1;
+        total_records += batch.records.len() as u64;
+    }
+    Ok((sequence, total_records))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::BufReader, time::Duration};
+
+    use prost::Message;
+
+    use super::{export, load_or_generate_key, verify, Batch, HistoryWriter, Record, SignedBatch};
+    use crate::{
+        encoding::{Compression, Encoder, Framing},
+        proto,
+        sink::Sink,
+        ErrorKind, PingOutcome,
+    };
+
+    fn batch(sequence: u64) -> Batch {
+        Batch {
+            sequence,
+            total_records: Some(sequence + 1),
+            records: vec![Record::new(&PingOutcome::test(
+                "127.0.0.1",
+                Ok(Duration::from_millis(5)),
+            ))],
+        }
+    }
+
+    fn encode(batches: &[SignedBatch]) -> String {
+        batches
+            .iter()
+            .map(|b| serde_json::to_string(b).unwrap() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn verify_signed_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = load_or_generate_key(&dir.path().join("key")).unwrap();
+        let batches = [
+            SignedBatch::new(batch(0), Some(&key)).unwrap(),
+            SignedBatch::new(batch(1), Some(&key)).unwrap(),
+        ];
+        let history = encode(&batches);
+
+        let verified = verify(history.as_bytes(), Some(key.verifying_key())).unwrap();
+        assert_eq!(verified.batches, 2);
+        assert_eq!(verified.records, 2);
+        assert_eq!(verified.public_key, Some(key.verifying_key()));
+
+        let other = load_or_generate_key(&dir.path().join("other")).unwrap();
+        assert!(verify(history.as_bytes(), Some(other.verifying_key())).is_err());
+    }
+
+    #[test]
+    fn verify_records_without_probe() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = load_or_generate_key(&dir.path().join("key")).unwrap();
+        // As written before records had a probe or batches a total.
+        let mut old = batch(0);
+        old.records[0].probe = String::new();
+        old.total_records = None;
+        let history = encode(&[SignedBatch::new(old, Some(&key)).unwrap()]);
+        assert!(!history.contains("probe"));
+        assert!(!history.contains("total_records"));
+
+        let verified = verify(history.as_bytes(), Some(key.verifying_key())).unwrap();
+        assert_eq!(verified.records, 1);
+    }
+
+    #[test]
+    fn detect_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = load_or_generate_key(&dir.path().join("key")).unwrap();
+
+        let mut tampered = SignedBatch::new(batch(0), Some(&key)).unwrap();
+        tampered.batch.records[0].rtt_us = Some(1);
+        assert!(verify(encode(&[tampered]).as_bytes(), None).is_err());
+
+        let missing = [
+            SignedBatch::new(batch(0), Some(&key)).unwrap(),
+            SignedBatch::new(batch(2), Some(&key)).unwrap(),
+        ];
+        assert!(verify(encode(&missing).as_bytes(), None).is_err());
+
+        let unsigned = SignedBatch::new(batch(0), None).unwrap();
+        assert!(verify(encode(&[unsigned]).as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn detect_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = load_or_generate_key(&dir.path().join("key")).unwrap();
+        let batches: Vec<_> = (0..3)
+            .map(|sequence| SignedBatch::new(batch(sequence), Some(&key)).unwrap())
+            .collect();
+
+        let head = verify(encode(&batches[1..]).as_bytes(), None);
+        assert!(head.is_err(), "batches removed from the start");
+
+        let mut mismatched = batch(0);
+        mismatched.total_records = Some(2);
+        let mismatched = SignedBatch::new(mismatched, Some(&key)).unwrap();
+        assert!(
+            verify(encode(&[mismatched]).as_bytes(), None).is_err(),
+            "total doesn't match the records found"
+        );
+
+        // The signed total of the last batch is reported, to be checked
+        // against the total which the agent wrote.
+        let tail = verify(encode(&batches[..2]).as_bytes(), None).unwrap();
+        assert_eq!(tail.records, 2);
+    }
+
+    #[test]
+    fn export_records() {
+        let history = encode(&[
+            SignedBatch::new(batch(0), None).unwrap(),
+            SignedBatch::new(batch(1), None).unwrap(),
+        ]);
+        let encoder = Encoder::new(Framing::Protobuf, Compression::Zstd).unwrap();
+        let mut output = Vec::new();
+        assert_eq!(export(history.as_bytes(), encoder, &mut output).unwrap(), 2);
+
+        let decoded = zstd::decode_all(&output[..]).unwrap();
+        let mut buf = &decoded[..];
+        let record = proto::HistoryRecord::decode_length_delimited(&mut buf).unwrap();
+        assert_eq!(record.target, "127.0.0.1");
+        assert_eq!(record.rtt_us, Some(5000));
+        assert!(proto::HistoryRecord::decode_length_delimited(&mut buf).is_ok());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reload_signing_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        let generated = load_or_generate_key(&path).unwrap();
+        let loaded = load_or_generate_key(&path).unwrap();
+        assert_eq!(generated.to_bytes(), loaded.to_bytes());
+    }
+
+    #[tokio::test]
+    async fn write_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = load_or_generate_key(&dir.path().join("key")).unwrap();
+        let path = dir.path().join("history.jsonl");
+
+        let writer =
+            HistoryWriter::spawn(path.clone(), Some(key), 2, Duration::from_secs(60)).unwrap();
+        for _ in 0..4 {
+            writer.record(&PingOutcome::test(
+                "127.0.0.1",
+                Ok(Duration::from_millis(1)),
+            ));
+        }
+        writer.record(&PingOutcome::test("127.0.0.1", Err(ErrorKind::Timeout)));
+        drop(writer);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let history = BufReader::new(std::fs::File::open(&path).unwrap());
+        let verified = verify(history, None).unwrap();
+        assert_eq!(verified.batches, 3);
+        assert_eq!(verified.records, 5);
+
+        // Sequences and totals continue across restarts.
+        let writer = HistoryWriter::spawn(path.clone(), None, 1, Duration::from_secs(60)).unwrap();
+        writer.record(&PingOutcome::test("127.0.0.1", Err(ErrorKind::Timeout)));
+        drop(writer);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let history = std::fs::read_to_string(&path).unwrap();
+        let last: SignedBatch = serde_json::from_str(history.lines().last().unwrap()).unwrap();
+        assert_eq!(last.batch.sequence, 3);
+        assert_eq!(last.batch.total_records, Some(6));
+    }
+}
@@ -0,0 +1,352 @@
+//! Classification of the results of targets by their round-trip time, as a
+//! link which replies in 800ms is as good as down to its users, even though
+//! every ping succeeds.
+//!
+//! Each result is `ok` below the degraded threshold of its target,
+//! `degraded` below the critical threshold and `critical` above it or when
+//! the ping failed. Targets expected to be down are ok while they stay
+//! unreachable, however long that took to find, and critical once they
+//! reply. The health of the latest result of each probe of a target is
+//! exposed as the `target_health` gauge, labelled by target, probe and
+//! health, which is 1 for the current health and 0 for the others.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use prometheus::{IntGaugeVec, Opts, Registry};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LatencyConfig {
+    /// Thresholds of all targets without their own, if any.
+    pub default: Option<LatencyThresholds>,
+    /// Thresholds of individual targets.
+    #[serde(default)]
+    pub targets: BTreeMap<String, LatencyThresholds>,
+}
+
+/// Round-trip times above which the results of a target are degraded and
+/// critical.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LatencyThresholds {
+    /// Round-trip time above which results are degraded.
+    pub degraded_ms: f64,
+    /// Round-trip time above which results are critical.
+    pub critical_ms: f64,
+}
+
+impl LatencyThresholds {
+    fn validate(&self, name: &str) -> Result<()> {
+        if self.degraded_ms.is_nan() || self.degraded_ms <= 0.0 {
+            return Err(format!("latency of {name} must be degraded above 0ms").into());
+        }
+        if self.critical_ms.is_nan() || self.critical_ms < self.degraded_ms {
+            return Err(format!(
+                "latency of {name} must be critical at or above its degraded threshold"
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Health of a ping which completed with `outcome`, which is ok for
+    /// successes without a round-trip time, of targets expected to be down.
+    pub fn classify(&self, outcome: &PingOutcome) -> TargetHealth {
+        if outcome.rtt.is_err() {
+            return TargetHealth::Critical;
+        }
+        let Some(rtt) = outcome.latency() else {
+            return TargetHealth::Ok;
+        };
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+        if rtt_ms > self.critical_ms {
+            TargetHealth::Critical
+        } else if rtt_ms > self.degraded_ms {
+            TargetHealth::Degraded
+        } else {
+            TargetHealth::Ok
+        }
+    }
+}
+
+/// Health of a target by the round-trip time of its latest ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetHealth {
+    Ok,
+    Degraded,
+    Critical,
+}
+
+impl TargetHealth {
+    const ALL: [Self; 3] = [Self::Ok, Self::Degraded, Self::Critical];
+
+    /// Name of the health, suitable for labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Degraded => "degraded",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// Health of the targets with latency thresholds, which is recorded as a
+/// [`Sink`].
+pub struct LatencyHealth {
+    default: Option<LatencyThresholds>,
+    thresholds: BTreeMap<String, LatencyThresholds>,
+    /// Health of the latest result of each target, by target and probe.
+    targets: Mutex<HashMap<(String, String), TargetHealth>>,
+
+    /// Whether each target is of each health, labelled by target, probe and
+    /// health.
+    health: IntGaugeVec,
+}
+
+impl LatencyHealth {
+    pub fn new(config: &LatencyConfig, metrics: &Registry) -> Result<Self> {
+        if let Some(default) = &config.default {
+            default.validate("the default")?;
+        }
+        for (target, thresholds) in &config.targets {
+            thresholds.validate(target)?;
+        }
+        let health = IntGaugeVec::new(
+            Opts::new(
+                "target_health",
+                "Whether the round-trip time of the latest ping of a target is of each health",
+            ),
+            &["target", "probe", "health"],
+        )?;
+        metrics.register(Box::new(health.clone()))?;
+        Ok(Self {
+            default: config.default,
+            thresholds: config.targets.clone(),
+            targets: Mutex::new(HashMap::new()),
+            health,
+        })
+    }
+
+    /// Current health of the probe `probe` of `target`, if it has thresholds
+    /// and has been pinged.
+    pub fn health(&self, target: &str, probe: &str) -> Option<TargetHealth> {
+        let targets = self.targets.lock().expect("latency lock poisoned");
+        targets
+            .get(&(target.to_string(), probe.to_string()))
+            .copied()
+    }
+}
+
+impl Sink for LatencyHealth {
+    fn record(&self, outcome: &PingOutcome) {
+        let Some(thresholds) = self
+            .thresholds
+            .get(&*outcome.target)
+            .or(self.default.as_ref())
+        else {
+            return;
+        };
+        let health = thresholds.classify(outcome);
+        let previous = self.targets.lock().expect("latency lock poisoned").insert(
+            (outcome.target.to_string(), outcome.probe.to_string()),
+            health,
+        );
+        if previous == Some(health) {
+            return;
+        }
+        for h in TargetHealth::ALL {
+            self.health
+                .with_label_values(&[&outcome.target, &outcome.probe, h.as_str()])
+                .set((h == health) as i64);
+        }
+        // The first result of a target is only logged when it isn't ok.
+        match (previous, health) {
+            (None, TargetHealth::Ok) => {}
+            (Some(previous), TargetHealth::Ok) => info!(
+                target = &*outcome.target,
+                probe = &*outcome.probe,
+                previous = previous.as_str(),
+                "latency ok"
+            ),
+            _ => warn!(
+                target = &*outcome.target,
+                probe = &*outcome.probe,
+                health = health.as_str(),
+                rtt_ms = outcome.latency().map(|d| d.as_secs_f64() * 1000.0),
+                "latency {}",
+                health.as_str()
+            ),
+        }
+    }
+
+    fn remove(&self, target: &str, probe: &str) {
+        let removed = self
+            .targets
+            .lock()
+            .expect("latency lock poisoned")
+            .remove(&(target.to_string(), probe.to_string()));
+        if removed.is_some() {
+            for h in TargetHealth::ALL {
+                let _ = self
+                    .health
+                    .remove_label_values(&[target, probe, h.as_str()]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use prometheus::Registry;
+
+    use super::{LatencyConfig, LatencyHealth, LatencyThresholds, TargetHealth};
+    use crate::{sink::Sink, ErrorKind, Expect, PingOutcome};
+
+    const DEFAULT: LatencyThresholds = LatencyThresholds {
+        degraded_ms: 100.0,
+        critical_ms: 500.0,
+    };
+
+    fn latency() -> LatencyHealth {
+        LatencyHealth::new(
+            &LatencyConfig {
+                default: Some(DEFAULT),
+                targets: BTreeMap::from([(
+                    "lan".to_string(),
+                    LatencyThresholds {
+                        degraded_ms: 5.0,
+                        critical_ms: 20.0,
+                    },
+                )]),
+            },
+            &Registry::new(),
+        )
+        .unwrap()
+    }
+
+    fn ping(target: &str, rtt_ms: u64) -> PingOutcome {
+        PingOutcome::test(target, Ok(Duration::from_millis(rtt_ms)))
+    }
+
+    #[test]
+    fn classify() {
+        let latency = latency();
+        for (target, rtt_ms, health) in [
+            ("1.1.1.1", 20, TargetHealth::Ok),
+            ("1.1.1.1", 100, TargetHealth::Ok),
+            ("1.1.1.1", 101, TargetHealth::Degraded),
+            ("1.1.1.1", 800, TargetHealth::Critical),
+            ("lan", 20, TargetHealth::Degraded),
+            ("lan", 21, TargetHealth::Critical),
+        ] {
+            latency.record(&ping(target, rtt_ms));
+            assert_eq!(
+                latency.health(target, "icmp"),
+                Some(health),
+                "{target} at {rtt_ms}ms"
+            );
+        }
+
+        latency.record(&PingOutcome::test("lan", Err(ErrorKind::Timeout)));
+        assert_eq!(latency.health("lan", "icmp"), Some(TargetHealth::Critical));
+        latency.record(&ping("lan", 1));
+        assert_eq!(latency.health("lan", "icmp"), Some(TargetHealth::Ok));
+    }
+
+    #[test]
+    fn expected_down() {
+        let metrics = Registry::new();
+        let latency = LatencyHealth::new(
+            &LatencyConfig {
+                default: Some(DEFAULT),
+                targets: BTreeMap::new(),
+            },
+            &metrics,
+        )
+        .unwrap();
+        let critical = || {
+            latency
+                .health
+                .with_label_values(&["old-db", "icmp", "critical"])
+                .get()
+        };
+        // Timing out well above the critical threshold, as expected.
+        latency.record(&PingOutcome {
+            expect: Expect::Down,
+            ..ping("old-db", 2000)
+        });
+        assert_eq!(latency.health("old-db", "icmp"), Some(TargetHealth::Ok));
+        assert_eq!(critical(), 0);
+
+        latency.record(&PingOutcome {
+            expect: Expect::Down,
+            ..PingOutcome::test("old-db", Err(ErrorKind::Reachable))
+        });
+        assert_eq!(
+            latency.health("old-db", "icmp"),
+            Some(TargetHealth::Critical)
+        );
+        assert_eq!(critical(), 1);
+    }
+
+    #[test]
+    fn gauge() {
+        let metrics = Registry::new();
+        let latency = LatencyHealth::new(
+            &LatencyConfig {
+                default: None,
+                targets: BTreeMap::from([("lan".to_string(), DEFAULT)]),
+            },
+            &metrics,
+        )
+        .unwrap();
+        latency.record(&ping("lan", 200));
+        // Targets without thresholds aren't classified.
+        latency.record(&ping("1.1.1.1", 200));
+        assert_eq!(latency.health("1.1.1.1", "icmp"), None);
+
+        let value = |health: &str| {
+            latency
+                .health
+                .with_label_values(&["lan", "icmp", health])
+                .get()
+        };
+        assert_eq!(
+            [value("ok"), value("degraded"), value("critical")],
+            [0, 1, 0]
+        );
+        latency.record(&ping("lan", 10));
+        assert_eq!(
+            [value("ok"), value("degraded"), value("critical")],
+            [1, 0, 0]
+        );
+        assert_eq!(metrics.gather()[0].get_metric().len(), 3);
+
+        latency.remove("lan", "icmp");
+        assert_eq!(latency.health("lan", "icmp"), None);
+        assert!(metrics.gather().is_empty());
+    }
+
+    #[test]
+    fn reject_invalid() {
+        for (degraded_ms, critical_ms) in [(0.0, 10.0), (f64::NAN, 10.0), (100.0, 50.0)] {
+            let config = LatencyConfig {
+                default: Some(LatencyThresholds {
+                    degraded_ms,
+                    critical_ms,
+                }),
+                targets: BTreeMap::new(),
+            };
+            assert!(LatencyHealth::new(&config, &Registry::new()).is_err());
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Staggered launch of dispatchers, so that configurations with tens of
+//! thousands of targets don't resolve, open sockets for and ping every
+//! target at once on startup.
+//!
+//! Targets are launched in batches in the order they were added to the
+//! [`PingSender`](crate::PingSender), so the most important targets can be
+//! listed first. The [`Readiness`] of the sender reports whether every
+//! target has been launched, for use as a readiness probe, and its
+//! [`Liveness`] when any dispatcher last ticked.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::targets::TargetSet;
+
+/// Rate which dispatchers are launched at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ramp {
+    /// Number of dispatchers launched in each batch.
+    pub batch_size: usize,
+    /// Delay between launching each batch.
+    pub interval: Duration,
+}
+
+/// Whether every target of a started [`PingSender`](crate::PingSender) has
+/// been launched.
+///
+/// Clones share the same underlying state.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    /// Path which readiness is served at.
+    pub const PATH: &str = "/ready";
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_ready(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// When any dispatcher of a started [`PingSender`](crate::PingSender) last
+/// ticked, which shows that probes are still being scheduled, such as for
+/// the watchdog of [`systemd`](crate::systemd).
+///
+/// Clones share the same underlying state.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    created: Instant,
+    /// Milliseconds after `created` of the latest tick.
+    latest_ms: Arc<AtomicU64>,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            created: Instant::now(),
+            latest_ms: Arc::default(),
+        }
+    }
+}
+
+impl Liveness {
+    pub(crate) fn beat(&self) {
+        let elapsed = self.created.elapsed().as_millis() as u64;
+        self.latest_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Length of time since the latest tick, or since this was created
+    /// before the first.
+    pub fn since_tick(&self) -> Duration {
+        let latest = Duration::from_millis(self.latest_ms.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(latest)
+    }
+}
+
+/// Launch the pending dispatchers of `target_set` in batches of the `ramp`,
+/// until none remain.
+pub(crate) async fn run(target_set: TargetSet, ramp: Ramp, readiness: Readiness) {
+    let total = target_set.pending();
+    info!(
+        total,
+        batch_size = ramp.batch_size,
+        interval = ?ramp.interval,
+        "launching dispatchers in batches"
+    );
+    let mut interval = tokio::time::interval(ramp.interval);
+    loop {
+        interval.tick().await;
+        let pending = target_set.launch(ramp.batch_size);
+        if pending == 0 {
+            break;
+        }
+        info!(
+            launched = total.saturating_sub(pending),
+            pending, "launching dispatchers"
+        );
+    }
+    info!(total, "launched all dispatchers");
+    readiness.set_ready();
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio_stream::StreamExt;
+
+    use super::Ramp;
+    use crate::{probe::MockProbe, target::ProbeTarget, PingSender};
+
+    #[tokio::test]
+    async fn launch_in_batches() {
+        let mut sender = PingSender::builder()
+            .with_ping_interval_ms(1000)
+            .build()
+            .unwrap()
+            .with_ramp(Ramp {
+                batch_size: 2,
+                interval: Duration::from_millis(200),
+            });
+        for target in ["a", "b", "c", "d", "e"] {
+            sender = sender.with_probe(
+                target.parse().unwrap(),
+                MockProbe::new([Ok(Duration::from_millis(1))]),
+            );
+        }
+        let readiness = sender.readiness();
+        let target_set = sender.target_set();
+        let mut results = sender.results();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(target_set.pending(), 3, "first batch launches immediately");
+        assert_eq!(
+            target_set
+                .targets()
+                .iter()
+                .map(ProbeTarget::label)
+                .collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e"]
+        );
+        assert!(!readiness.is_ready());
+        assert!(results.next().await.is_some());
+
+        for _ in 0..100 {
+            if readiness.is_ready() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(readiness.is_ready());
+        assert_eq!(target_set.pending(), 0);
+    }
+}
@@ -1,197 +1,2067 @@
-use std::{net::IpAddr, str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
-use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
-use surge_ping::{Client, Config, PingIdentifier, PingSequence};
-use tokio::sync::mpsc::{error::TryRecvError, Receiver, Sender};
-use tracing::{debug, error, info};
+#[cfg(feature = "metrics")]
+use prometheus::{HistogramOpts, Opts, Registry};
+use surge_ping::SurgeError;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError, Sender, UnboundedReceiver, UnboundedSender},
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt, StreamMap};
+use tracing::{debug, error, info, warn};
 
-pub type Result<T, E = Box<dyn std::error::Error + Send + Sync>> = std::result::Result<T, E>;
+#[cfg(feature = "metrics")]
+use crate::openmetrics::{Exemplar, Exemplars};
+use crate::{
+    launch::{Liveness, Ramp, Readiness},
+    limit::RateLimiter,
+    metrics::{Gauge, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec},
+    pause::Pauses,
+    probe::{icmp::IcmpConfig, BoxProbe, DynProbe, Probe, ProbeOutcome},
+    scheduler::{Jitter, MissedTicks, Pool, SchedulerMode},
+    sink::Sink,
+    target::ProbeTarget,
+    targets::TargetSet,
+};
 
-/// Send pings to various targets.
-pub struct PingSender {
-    /// Dispatchers send pings to the underlying targets.
+#[cfg(feature = "metrics")]
+pub mod alerts;
+#[cfg(feature = "server")]
+pub mod auth;
+pub mod baseline;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod check;
+#[cfg(feature = "server")]
+pub mod cluster;
+#[cfg(feature = "server")]
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod destination;
+#[cfg(feature = "metrics")]
+pub mod differential;
+#[cfg(feature = "metrics")]
+pub mod discovery;
+#[cfg(feature = "server")]
+pub mod encoding;
+pub mod error;
+#[cfg(feature = "metrics")]
+pub mod events;
+pub mod export;
+#[cfg(feature = "metrics")]
+pub mod exporter;
+#[cfg(feature = "server")]
+pub mod features;
+#[cfg(feature = "server")]
+pub mod generate;
+#[cfg(feature = "metrics")]
+pub mod groups;
+#[cfg(feature = "metrics")]
+pub mod health;
+#[cfg(feature = "server")]
+pub mod history;
+#[cfg(feature = "metrics")]
+pub mod latency;
+pub mod launch;
+pub mod limit;
+#[cfg(feature = "metrics")]
+pub mod maintenance;
+mod metrics;
+#[cfg(feature = "metrics")]
+pub mod notify;
+#[cfg(feature = "metrics")]
+pub mod openmetrics;
+pub mod pause;
+pub mod platform;
+pub mod probe;
+#[cfg(feature = "metrics")]
+pub mod process;
+#[cfg(feature = "proto")]
+pub mod proto;
+#[cfg(all(feature = "server", not(feature = "proto")))]
+mod proto;
+pub mod range;
+pub mod rolling;
+pub mod scheduler;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod sink;
+#[cfg(feature = "metrics")]
+pub mod sla;
+#[cfg(feature = "metrics")]
+pub mod slo;
+#[cfg(feature = "metrics")]
+pub mod slope;
+#[cfg(feature = "metrics")]
+pub mod state;
+#[cfg(feature = "server")]
+pub mod stream;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+pub mod target;
+pub mod targets;
+#[cfg(feature = "server")]
+pub mod top;
+#[cfg(feature = "server")]
+pub mod tui;
+
+pub use error::UppiesError;
+
+pub type Result<T, E = UppiesError> = std::result::Result<T, E>;
+
+/// Buckets of the ping duration histograms, in milliseconds.
+pub const DURATION_BUCKETS_MS: &[f64] = &[
+    1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0,
+];
+
+/// Buckets of the probe scheduling lag histogram, in seconds.
+#[cfg(feature = "metrics")]
+const SCHEDULING_LAG_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Parse a duration of a whole number of seconds, minutes, hours or days,
+/// such as `90s`, `30m`, `12h` or `7d`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || format!("invalid duration '{s}', expected a duration such as '12h'");
+    let (split, _) = s.char_indices().last().ok_or_else(invalid)?;
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(invalid().into()),
+    };
+    let secs = value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("duration '{s}' is too long"))?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// Architecture of the channels which ping results are sent through, from
+/// the dispatchers to the task recording them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMode {
+    /// A channel per target, so that a slow or stalled target cannot delay
+    /// the results of others.
+    PerTarget,
+    /// A single bounded channel shared by all targets, with each result
+    /// tagged by its target and sequence number. This uses far less memory
+    /// with a large number of targets.
+    Shared,
+}
+
+impl ChannelMode {
+    /// Number of targets at which [`ChannelMode::Shared`] becomes the default.
     ///
-    /// The corresponding [`Receiver`] returns the result dependent on the outcome
-    /// of the pin.g
-    dispatchers: Vec<(Dispatcher, Receiver<Result<Duration>>)>,
+    /// From `cargo bench --bench channels`, a channel per target costs ~2.6KB
+    /// per target against ~0.3KB for a shared channel, with no measurable
+    /// difference in throughput. Below this many targets the saving is
+    /// negligible, so isolation is preferred.
+    pub const SHARED_THRESHOLD: usize = 1000;
+
+    /// Channel mode used by default for the given number of targets.
+    pub fn for_targets(targets: usize) -> Self {
+        if targets >= Self::SHARED_THRESHOLD {
+            Self::Shared
+        } else {
+            Self::PerTarget
+        }
+    }
+}
+
+impl FromStr for ChannelMode {
+    type Err = String;
 
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "per-target" => Ok(Self::PerTarget),
+            "shared" => Ok(Self::Shared),
+            _ => Err(format!(
+                "unknown channel mode '{s}', expected 'per-target' or 'shared'"
+            )),
+        }
+    }
+}
+
+/// Outcome of a single ping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingOutcome {
+    /// Target which the ping was sent to.
+    pub target: Arc<str>,
+    /// Probe of the target which sent the ping, see [`ProbeTarget::probe`].
+    pub probe: Arc<str>,
+    /// Address of the target which the ping was sent to, if any.
+    pub resolved_ip: Option<IpAddr>,
+    /// Packet which replied to the ping, if known.
+    pub reply: Option<probe::Reply>,
+    /// Sequence number, which increases by one for each ping sent to the
+    /// target.
+    pub sequence: u64,
+    /// Round-trip time of the ping, or the reason it failed. Pings of
+    /// targets which are expected to be down have no round-trip time, and
+    /// instead succeed with the time taken to find them unreachable, see
+    /// [`PingOutcome::latency`].
+    pub rtt: std::result::Result<Duration, PingError>,
+    /// Whether the target is expected to be up or down.
+    pub expect: Expect,
+    /// Time at which the ping completed.
+    pub timestamp: SystemTime,
+}
+
+impl PingOutcome {
+    /// Kind of error which the ping failed with, if any.
+    pub fn error_kind(&self) -> Option<ErrorKind> {
+        self.rtt.as_ref().err().map(|e| e.kind)
+    }
+
+    /// Round-trip time of the ping, if it succeeded with a reply, which is
+    /// never the case for targets [expected to be down](Expect::Down).
+    pub fn latency(&self) -> Option<Duration> {
+        match self.expect {
+            Expect::Up => self.rtt.as_ref().ok().copied(),
+            Expect::Down => None,
+        }
+    }
+}
+
+/// Classification of the reason a ping failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// No reply was received in time.
+    Timeout,
+    /// The ping could not be sent or received, such as when the network
+    /// is unreachable.
+    Io,
+    /// The reply was malformed.
+    Malformed,
+    /// The target could not be resolved to a neighbor on the local network,
+    /// see [`probe::NeighborProbe`].
+    Neighbor,
+    /// The hostname of the target could not be resolved, see
+    /// [`probe::HostnameProbe`].
+    Resolution,
+    /// The TLS handshake failed, such as when the certificate of the server
+    /// is invalid, see [`probe::TlsProbe`].
+    Tls,
+    /// The target replied, but not as expected, such as a gRPC service
+    /// which isn't serving.
+    Unexpected,
+    /// The target replied while it was expected to be down, see
+    /// [`Expect::Down`].
+    Reachable,
+    /// The failure was injected by the `chaos` feature.
+    Injected,
+    /// Any other failure, see the error message.
+    Other,
+}
+
+impl ErrorKind {
+    /// Name of the error kind, suitable for labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::Io => "io",
+            Self::Malformed => "malformed",
+            Self::Neighbor => "neighbor",
+            Self::Resolution => "resolution",
+            Self::Tls => "tls",
+            Self::Unexpected => "unexpected",
+            Self::Reachable => "reachable",
+            Self::Injected => "injected",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Reason a ping failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl std::fmt::Display for PingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for PingError {}
+
+impl From<SurgeError> for PingError {
+    fn from(e: SurgeError) -> Self {
+        let kind = match e {
+            SurgeError::Timeout { .. } => ErrorKind::Timeout,
+            SurgeError::IOError(_) | SurgeError::NetworkError => ErrorKind::Io,
+            SurgeError::MalformedPacket(_) | SurgeError::IncorrectBufferSize => {
+                ErrorKind::Malformed
+            }
+            _ => ErrorKind::Other,
+        };
+        Self {
+            kind,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Whether the probes of a target are expected to succeed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Expect {
+    /// The target is expected to reply.
+    #[default]
+    Up,
+    /// The target is expected to be unreachable, such as behind a firewall
+    /// rule or once decommissioned, so its pings succeed without a
+    /// round-trip time while it stays unreachable, and fail with
+    /// [`ErrorKind::Reachable`] once it replies.
+    Down,
+}
+
+impl Expect {
+    /// Result of a probe which took `elapsed` to complete with `rtt`, as a
+    /// measure of whether the target is as expected. Targets expected to be
+    /// down succeed with `elapsed`, which isn't a round-trip time.
+    ///
+    /// Targets which timed out or could not be reached are as expected when
+    /// down, whereas any reply, including a failed TLS handshake or one
+    /// which is malformed, shows it to be reachable. Other failures, such as
+    /// those resolving the target, are kept as neither.
+    pub fn apply(
+        self,
+        rtt: std::result::Result<Duration, PingError>,
+        elapsed: Duration,
+    ) -> std::result::Result<Duration, PingError> {
+        if self == Self::Up {
+            return rtt;
+        }
+        match rtt {
+            Ok(rtt) => Err(PingError {
+                kind: ErrorKind::Reachable,
+                message: format!("replied in {rtt:?} but expected to be down"),
+            }),
+            Err(e) => match e.kind {
+                ErrorKind::Timeout | ErrorKind::Io => Ok(elapsed),
+                ErrorKind::Malformed | ErrorKind::Tls | ErrorKind::Unexpected => Err(PingError {
+                    kind: ErrorKind::Reachable,
+                    message: format!("replied ({e}) but expected to be down"),
+                }),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+impl PingOutcome {
+    /// Outcome of a ping to `target` which completed now.
+    pub(crate) fn test(target: &str, rtt: std::result::Result<Duration, ErrorKind>) -> Self {
+        Self {
+            target: target.into(),
+            probe: "icmp".into(),
+            resolved_ip: target.parse().ok(),
+            reply: None,
+            sequence: 0,
+            rtt: rtt.map_err(|kind| PingError {
+                kind,
+                message: kind.as_str().to_string(),
+            }),
+            expect: Expect::Up,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+/// Stream of the outcomes of all pings, see [`PingSender::results`].
+pub type PingOutcomes = Pin<Box<dyn Stream<Item = PingOutcome> + Send>>;
+
+/// Prometheus metrics of ping results, which are recorded as a [`Sink`].
+///
+/// Without the `metrics` feature these can't be created, and senders
+/// record no metrics.
+#[derive(Clone)]
+pub struct PingMetrics {
     /// Number of pings which were successful, labelled by the underlying target.
     success_count: IntCounterVec,
     /// Number of pings which were unsuccessful, labelled by the underlying target.
     failure_count: IntCounterVec,
+    /// Number of pings which failed as the target could not be resolved to
+    /// a neighbor, labelled by the underlying target.
+    neighbor_failure_count: IntCounterVec,
+    /// Number of pings which failed as the hostname of the target could not
+    /// be resolved, labelled by the underlying target.
+    resolution_failures: IntCounterVec,
+    /// Number of pings which timed out, which are also counted as failed,
+    /// labelled by the underlying target.
+    timeouts: IntCounterVec,
+    /// Number of probes which took longer than the interval, labelled by
+    /// the underlying target.
+    overruns: IntCounterVec,
+    /// Time to live of the latest reply, labelled by the underlying target.
+    reply_ttl: IntGaugeVec,
+    /// Number of replies which weren't an echo reply from the target,
+    /// labelled by the underlying target and why, see [`probe::Reply`].
+    unexpected_replies: IntCounterVec,
+
+    /// Histogram of ping durations in milliseconds, labelled by the underlying target.
+    ping_duration_ms: HistogramVec,
+    /// Latest ping of each bucket of `ping_duration_ms`.
+    #[cfg(feature = "metrics")]
+    exemplars: Exemplars,
+    /// Name of `ping_duration_ms` including its namespace, which its
+    /// exemplars are kept by.
+    #[cfg(feature = "metrics")]
+    ping_duration_name: String,
+    /// Upper bounds of the buckets of `ping_duration_ms`.
+    #[cfg(feature = "metrics")]
+    buckets: Arc<[f64]>,
+
+    /// Number of times a dispatcher was restarted after failing, labelled by
+    /// the underlying target.
+    restart_count: IntCounterVec,
+
+    /// Number of targets which are pinged.
+    targets: IntGauge,
+    /// Number of targets which are paused, see [`Pauses`].
+    #[cfg(feature = "metrics")]
+    paused: IntGauge,
+    /// Number of dispatchers, labelled by whether they are `running` or
+    /// `restarting`.
+    dispatchers: IntGaugeVec,
+    /// Number of sinks, labelled by whether they are `healthy` or `unhealthy`.
+    sinks: IntGaugeVec,
+    /// Delay between the deadline of the latest probe of the worker pool and
+    /// its start, see [`SchedulerMode::Pool`].
+    scheduler_lag: Gauge,
+    /// Number of probes which waited for the rate limit, see [`RateLimiter`].
+    throttled: IntCounter,
+    /// Histogram of the delay between when each probe was due and when it
+    /// started, in seconds.
+    scheduling_lag: Histogram,
+    /// Number of results which are waiting in the result channels.
+    queued: IntGauge,
+    /// Highest number of results which have waited in the result channels.
+    queued_max: IntGauge,
+    /// Number of results which could not be sent into the result channel,
+    /// labelled by the underlying target.
+    dropped: IntCounterVec,
+}
+
+impl PingMetrics {
+    #[cfg(feature = "metrics")]
+    const LABELS: &[&str] = &["target", "probe"];
+
+    #[cfg(feature = "metrics")]
+    pub fn new(metrics: &Registry) -> Result<Self> {
+        Self::with_options(metrics, "", DURATION_BUCKETS_MS)
+    }
+
+    /// Create the metrics with names prefixed by `namespace` unless it is
+    /// empty, and ping durations observed into `buckets` in milliseconds.
+    #[cfg(feature = "metrics")]
+    pub fn with_options(metrics: &Registry, namespace: &str, buckets: &[f64]) -> Result<Self> {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
+        let success_count = IntCounterVec::new(
+            opts("ping_success_count", "Counter of successful pings"),
+            Self::LABELS,
+        )?;
+        let failure_count = IntCounterVec::new(
+            opts("ping_failure_count", "Counter of failed pings"),
+            Self::LABELS,
+        )?;
+        let neighbor_failure_count = IntCounterVec::new(
+            opts(
+                "ping_neighbor_failure_count",
+                "Counter of pings which failed to resolve the target as a neighbor",
+            ),
+            Self::LABELS,
+        )?;
+        let resolution_failures = IntCounterVec::new(
+            opts(
+                "resolution_failures_total",
+                "Counter of pings which failed as the target hostname did not resolve",
+            ),
+            Self::LABELS,
+        )?;
+        let timeouts = IntCounterVec::new(
+            opts("ping_timeouts_total", "Counter of pings which timed out"),
+            Self::LABELS,
+        )?;
+        let overruns = IntCounterVec::new(
+            opts(
+                "probe_overruns_total",
+                "Counter of probes which took longer than the interval",
+            ),
+            Self::LABELS,
+        )?;
+        let reply_ttl = IntGaugeVec::new(
+            opts(
+                "ping_reply_ttl",
+                "Time to live of the latest reply of each target as it was received",
+            ),
+            Self::LABELS,
+        )?;
+        let unexpected_replies = IntCounterVec::new(
+            opts(
+                "unexpected_reply_total",
+                "Counter of replies which came from another source than the target, or weren't an echo reply",
+            ),
+            &["target", "probe", "reason"],
+        )?;
+        let ping_duration_ms = HistogramVec::new(
+            HistogramOpts::new(
+                "ping_duration_ms",
+                "Histogram of ping round-trip times in milliseconds",
+            )
+            .namespace(namespace)
+            .buckets(buckets.to_vec()),
+            Self::LABELS,
+        )?;
+        let restart_count = IntCounterVec::new(
+            opts(
+                "dispatcher_restarts_total",
+                "Counter of dispatcher restarts after failure",
+            ),
+            Self::LABELS,
+        )?;
+        let targets =
+            IntGauge::with_opts(opts("uppies_targets", "Number of targets which are pinged"))?;
+        let paused = IntGauge::with_opts(opts(
+            "uppies_targets_paused",
+            "Number of targets which are paused",
+        ))?;
+        let dispatchers = IntGaugeVec::new(
+            opts(
+                "uppies_dispatchers",
+                "Number of dispatchers which are running or restarting",
+            ),
+            &["state"],
+        )?;
+        let sinks = IntGaugeVec::new(
+            opts(
+                "uppies_sinks",
+                "Number of sinks which are healthy or unhealthy",
+            ),
+            &["state"],
+        )?;
+        let scheduler_lag = Gauge::with_opts(opts(
+            "uppies_scheduler_lag_seconds",
+            "Delay between the deadline of the latest probe of the worker pool and its start",
+        ))?;
+        let throttled = IntCounter::with_opts(opts(
+            "probes_throttled_total",
+            "Counter of probes which were delayed by the rate limit",
+        ))?;
+        let scheduling_lag = Histogram::with_opts(
+            HistogramOpts::new(
+                "probe_scheduling_lag_seconds",
+                "Histogram of the delay between when probes were due and when they started",
+            )
+            .namespace(namespace)
+            .buckets(SCHEDULING_LAG_BUCKETS.to_vec()),
+        )?;
+        let queued = IntGauge::with_opts(opts(
+            "uppies_results_queued",
+            "Number of results which are waiting in the result channels",
+        ))?;
+        let queued_max = IntGauge::with_opts(opts(
+            "uppies_results_queued_max",
+            "Highest number of results which have waited in the result channels",
+        ))?;
+        let dropped = IntCounterVec::new(
+            opts(
+                "results_dropped_total",
+                "Counter of results which could not be sent into the result channel",
+            ),
+            Self::LABELS,
+        )?;
+        for state in ["running", "restarting"] {
+            dispatchers.with_label_values(&[state]).set(0);
+        }
+        for state in ["healthy", "unhealthy"] {
+            sinks.with_label_values(&[state]).set(0);
+        }
+        metrics.register(Box::new(success_count.clone()))?;
+        metrics.register(Box::new(failure_count.clone()))?;
+        metrics.register(Box::new(neighbor_failure_count.clone()))?;
+        metrics.register(Box::new(resolution_failures.clone()))?;
+        metrics.register(Box::new(timeouts.clone()))?;
+        metrics.register(Box::new(overruns.clone()))?;
+        metrics.register(Box::new(reply_ttl.clone()))?;
+        metrics.register(Box::new(unexpected_replies.clone()))?;
+        metrics.register(Box::new(ping_duration_ms.clone()))?;
+        metrics.register(Box::new(restart_count.clone()))?;
+        metrics.register(Box::new(targets.clone()))?;
+        metrics.register(Box::new(paused.clone()))?;
+        metrics.register(Box::new(dispatchers.clone()))?;
+        metrics.register(Box::new(sinks.clone()))?;
+        metrics.register(Box::new(scheduler_lag.clone()))?;
+        metrics.register(Box::new(throttled.clone()))?;
+        metrics.register(Box::new(scheduling_lag.clone()))?;
+        metrics.register(Box::new(queued.clone()))?;
+        metrics.register(Box::new(queued_max.clone()))?;
+        metrics.register(Box::new(dropped.clone()))?;
+        Ok(Self {
+            success_count,
+            failure_count,
+            neighbor_failure_count,
+            resolution_failures,
+            timeouts,
+            overruns,
+            reply_ttl,
+            unexpected_replies,
+            ping_duration_ms,
+            exemplars: Exemplars::default(),
+            ping_duration_name: match namespace {
+                "" => "ping_duration_ms".to_string(),
+                namespace => format!("{namespace}_ping_duration_ms"),
+            },
+            buckets: buckets.into(),
+            restart_count,
+            targets,
+            paused,
+            dispatchers,
+            sinks,
+            scheduler_lag,
+            throttled,
+            scheduling_lag,
+            queued,
+            queued_max,
+            dropped,
+        })
+    }
+
+    /// Initialise the metrics of a newly added probe of a target.
+    fn add_target(&self, target: &str, probe: &str) {
+        // Initialise the value on start, this allows the
+        // metric to be immediately reported as 0 if there are no
+        // errors for sometime.
+        self.failure_count
+            .with_label_values(&[target, probe])
+            .inc_by(0);
+        self.targets.inc();
+    }
+
+    /// Remove the series of a probe of a target which is no longer pinged,
+    /// so that it isn't reported indefinitely.
+    fn remove_target(&self, target: &str, probe: &str) {
+        let labels: &[&str] = &[target, probe];
+        // Series which were never initialised are absent, which is fine.
+        let _ = self.success_count.remove_label_values(labels);
+        let _ = self.failure_count.remove_label_values(labels);
+        let _ = self.neighbor_failure_count.remove_label_values(labels);
+        let _ = self.resolution_failures.remove_label_values(labels);
+        let _ = self.timeouts.remove_label_values(labels);
+        let _ = self.overruns.remove_label_values(labels);
+        let _ = self.reply_ttl.remove_label_values(labels);
+        for reason in ["type", "source"] {
+            let _ = self
+                .unexpected_replies
+                .remove_label_values(&[target, probe, reason]);
+        }
+        #[cfg(feature = "metrics")]
+        self.exemplars.remove(target, probe);
+        let _ = self.ping_duration_ms.remove_label_values(labels);
+        let _ = self.restart_count.remove_label_values(labels);
+        let _ = self.dropped.remove_label_values(labels);
+        self.targets.dec();
+    }
+
+    /// Record the number of healthy and unhealthy sinks.
+    fn record_sink_health(&self, sinks: &[Arc<dyn Sink>]) {
+        let healthy = sinks.iter().filter(|s| s.is_healthy()).count();
+        self.sinks
+            .with_label_values(&["healthy"])
+            .set(healthy as i64);
+        self.sinks
+            .with_label_values(&["unhealthy"])
+            .set((sinks.len() - healthy) as i64);
+    }
+}
+
+impl Sink for PingMetrics {
+    fn record(&self, outcome: &PingOutcome) {
+        let labels: &[&str] = &[&outcome.target, &outcome.probe];
+        if let Some(reply) = &outcome.reply {
+            if let Some(ttl) = reply.ttl {
+                self.reply_ttl.with_label_values(labels).set(ttl.into());
+            }
+            let unexpected = outcome.resolved_ip.and_then(|ip| reply.unexpected(ip));
+            if let Some(reason) = unexpected {
+                self.unexpected_replies
+                    .with_label_values(&[&outcome.target, &outcome.probe, reason])
+                    .inc();
+            }
+        }
+        match &outcome.rtt {
+            Ok(_) => {
+                self.success_count.with_label_values(labels).inc();
+                let Some(d) = outcome.latency() else {
+                    return;
+                };
+                let value = d.as_millis() as f64;
+                self.ping_duration_ms
+                    .with_label_values(labels)
+                    .observe(value);
+                #[cfg(feature = "metrics")]
+                self.exemplars.observe(
+                    &self.ping_duration_name,
+                    &outcome.target,
+                    &outcome.probe,
+                    &self.buckets,
+                    Exemplar {
+                        value,
+                        sequence: outcome.sequence,
+                        timestamp: outcome.timestamp,
+                    },
+                );
+            }
+            // Layer 2 problems are counted separately, so they aren't
+            // mistaken for packet loss.
+            Err(e) if e.kind == ErrorKind::Neighbor => {
+                self.neighbor_failure_count.with_label_values(labels).inc()
+            }
+            Err(e) if e.kind == ErrorKind::Resolution => {
+                self.resolution_failures.with_label_values(labels).inc()
+            }
+            Err(e) => {
+                if e.kind == ErrorKind::Timeout {
+                    self.timeouts.with_label_values(labels).inc();
+                }
+                self.failure_count.with_label_values(labels).inc()
+            }
+        }
+    }
+}
+
+/// Send pings to various targets.
+pub struct PingSender {
+    /// Dispatchers send pings to the underlying targets.
+    dispatchers: Vec<Dispatcher>,
+
+    ping_interval_ms: u64,
+
+    /// Architecture of the channels which results are sent through,
+    /// otherwise dependent on the number of targets.
+    channel_mode: Option<ChannelMode>,
+
+    /// Capacity of the result channel of each target.
+    channel_capacity: usize,
+
+    /// How probes are scheduled, otherwise dependent on the number of
+    /// targets.
+    scheduler_mode: Option<SchedulerMode>,
+
+    /// Number of workers of a [`SchedulerMode::Pool`].
+    workers: usize,
+
+    /// Randomisation of the times which targets are probed at.
+    jitter: Jitter,
+
+    /// Most probes of all targets each second, otherwise unlimited.
+    max_pps: Option<f64>,
+
+    /// Interval of targets after a failure, until they are healthy again.
+    fast_interval_ms: Option<u64>,
+
+    /// Length of time before a probe is considered failed.
+    timeout: Duration,
+
+    /// Timeouts of targets which differ from `timeout`, by label.
+    timeouts: BTreeMap<String, Duration>,
+
+    /// Targets which are expected to be down, by label.
+    expectations: BTreeMap<String, Expect>,
+
+    /// What happens to the ticks which are missed as probes overran.
+    missed_ticks: MissedTicks,
+
+    /// Metrics which results are recorded into, when enabled.
+    metrics: Option<PingMetrics>,
+
+    /// Additional sinks which all ping results are recorded into.
+    sinks: Vec<Arc<dyn Sink>>,
+
+    /// Functions which are called with every ping result.
+    hooks: Vec<ResultHook>,
+
+    /// Targets which are paused.
+    pauses: Pauses,
+
+    /// Builds the probes of targets which are added once started.
+    probe_factory: ProbeFactory,
+
+    /// Handle to the targets once started.
+    target_set: TargetSet,
+
+    /// Rate which dispatchers are launched at, otherwise all at once.
+    ramp: Option<Ramp>,
+
+    /// Whether all dispatchers have been launched.
+    readiness: Readiness,
+    /// When any dispatcher last ticked.
+    liveness: Liveness,
+
+    /// Chaos injected into all dispatchers, see [`chaos`].
+    #[cfg(feature = "chaos")]
+    chaos: Option<chaos::ChaosConfig>,
+}
+
+impl PingSender {
+    /// Length of time before a probe is considered failed by default, which
+    /// is a limit on probes which don't time out by themselves sooner.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Capacity of the result channel of each target by default.
+    pub const DEFAULT_CHANNEL_CAPACITY: usize = 5;
+
+    /// Interval between the probes of each target by default.
+    pub const DEFAULT_PING_INTERVAL_MS: u64 = 250;
+
+    /// Start building a sender, see [`PingSenderBuilder`].
+    pub fn builder() -> PingSenderBuilder {
+        PingSenderBuilder::default()
+    }
+
+    /// Schedule a custom [`Probe`] of `target`, alongside any other targets.
+    ///
+    /// Its outcomes are recorded identically to those of ICMP targets,
+    /// labelled by the [`label`](ProbeTarget::label) of `target` and the
+    /// [`name`](Probe::name) of the probe.
+    pub fn with_probe(mut self, target: ProbeTarget, probe: impl Probe) -> Self {
+        let name = probe.name().map(Arc::from);
+        let dispatcher = Dispatcher::register(
+            target,
+            Box::new(probe),
+            name,
+            self.ping_interval_ms,
+            self.metrics.as_ref(),
+            &self.pauses,
+        );
+        self.dispatchers.push(dispatcher);
+        self
+    }
+
+    /// Build the probes of targets which are added through the
+    /// [`TargetSet`] once started, rather than an [`IcmpProbe`](probe::IcmpProbe).
+    ///
+    /// Their outcomes are labelled by the [`probe`](ProbeTarget::probe) of
+    /// their target, which the targets of the set are told apart by,
+    /// whatever the [`name`](Probe::name) of the probe.
+    pub fn with_probe_factory<P: Probe>(
+        mut self,
+        factory: impl Fn(&ProbeTarget) -> Result<P> + Send + Sync + 'static,
+    ) -> Self {
+        self.probe_factory = Arc::new(move |target| factory(target).map(BoxProbe::new));
+        self
+    }
+
+    /// Labels of the targets of all dispatchers, in the order they were
+    /// added.
+    pub(crate) fn targets(&self) -> Vec<Arc<str>> {
+        self.dispatchers
+            .iter()
+            .map(|d| Arc::clone(&d.label))
+            .collect()
+    }
+
+    /// Handle to pause and resume targets, which remains usable after
+    /// pinging has started.
+    pub fn pauses(&self) -> Pauses {
+        self.pauses.clone()
+    }
+
+    /// Exemplars of the round-trip times, for the OpenMetrics format, which
+    /// are empty without metrics.
+    #[cfg(feature = "metrics")]
+    pub fn exemplars(&self) -> Exemplars {
+        self.metrics
+            .as_ref()
+            .map(|m| m.exemplars.clone())
+            .unwrap_or_default()
+    }
+
+    /// Handle to add and remove targets, which is usable once pinging has
+    /// started.
+    pub fn target_set(&self) -> TargetSet {
+        self.target_set.clone()
+    }
+
+    /// Launch dispatchers in batches at the rate of `ramp`, see [`launch`],
+    /// rather than all at once.
+    pub fn with_ramp(mut self, ramp: Ramp) -> Self {
+        self.ramp = Some(ramp);
+        self
+    }
+
+    /// Handle to whether all dispatchers have been launched, which remains
+    /// usable after pinging has started.
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// Handle to when any dispatcher last ticked, see [`Liveness`].
+    pub fn liveness(&self) -> Liveness {
+        self.liveness.clone()
+    }
+
+    /// Inject chaos into all dispatchers, see [`chaos`].
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: chaos::ChaosConfig) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Override the [`ChannelMode`], which otherwise depends on the
+    /// number of targets.
+    pub fn with_channel_mode(mut self, channel_mode: ChannelMode) -> Self {
+        self.channel_mode = Some(channel_mode);
+        self
+    }
+
+    /// Set the number of results of each target which can wait to be
+    /// received, beyond which results are dropped and counted by
+    /// `results_dropped_total` rather than delaying the probes of the
+    /// target. A shared channel has this capacity for each of its targets.
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Override the [`SchedulerMode`], which otherwise depends on the
+    /// number of targets.
+    pub fn with_scheduler_mode(mut self, scheduler_mode: SchedulerMode) -> Self {
+        self.scheduler_mode = Some(scheduler_mode);
+        self
+    }
+
+    /// Number of workers of a [`SchedulerMode::Pool`], which is the most
+    /// probes awaited at once, rather than 512.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Spread probes across the interval with `jitter`, rather than probing
+    /// every target in the same instant.
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Limit the probes of all targets to `max_pps` each second, delaying
+    /// those beyond it.
+    pub fn with_max_pps(mut self, max_pps: f64) -> Self {
+        self.max_pps = Some(max_pps);
+        self
+    }
+
+    /// Probe targets at `fast_interval_ms` after a failure, to confirm an
+    /// outage quickly, doubling the interval after each success until it is
+    /// back at the ping interval.
+    pub fn with_fast_interval_ms(mut self, fast_interval_ms: u64) -> Self {
+        self.fast_interval_ms = Some(fast_interval_ms);
+        self
+    }
+
+    /// Alter the length of time before a probe is considered failed, rather
+    /// than [`PingSender::DEFAULT_TIMEOUT`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Alter the length of time before a probe of the target labelled
+    /// `label` is considered failed, rather than that of every target.
+    pub fn with_target_timeout(mut self, label: &str, timeout: Duration) -> Self {
+        self.timeouts.insert(label.to_string(), timeout);
+        self
+    }
+
+    /// Alter whether the probes of the target labelled `label` are expected
+    /// to succeed, see [`Expect`].
+    pub fn with_target_expect(mut self, label: &str, expect: Expect) -> Self {
+        self.expectations.insert(label.to_string(), expect);
+        self
+    }
+
+    /// Alter what happens to the ticks which are missed as a probe overran
+    /// the interval, rather than [`MissedTicks::Delay`].
+    pub fn with_missed_ticks(mut self, missed_ticks: MissedTicks) -> Self {
+        self.missed_ticks = missed_ticks;
+        self
+    }
+
+    /// Record all ping results into the given [`Sink`], in addition
+    /// to any existing sinks.
+    pub fn with_sink(mut self, sink: Arc<dyn Sink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Call `hook` with every ping result once it is recorded into the
+    /// metrics and sinks, in addition to any existing hooks, such as to
+    /// feed a control loop or update a UI.
+    ///
+    /// Hooks are called in the order they were added from the task
+    /// receiving results, so should not block, and a hook which panics
+    /// stops results being recorded, see [`PingTasksHandle::join`].
+    pub fn on_result(mut self, hook: impl Fn(&PingOutcome) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Start pinging all targets, returning the stream of their results.
+    ///
+    /// Results are not recorded into the metrics, sinks or hooks of this
+    /// sender, use [`ping_targets`] for that instead.
+    pub fn results(self) -> PingOutcomes {
+        let channel_mode = self
+            .channel_mode
+            .unwrap_or_else(|| ChannelMode::for_targets(self.dispatchers.len()));
+        let scheduler_mode = self
+            .scheduler_mode
+            .unwrap_or_else(|| SchedulerMode::for_targets(self.dispatchers.len()));
+        info!(?channel_mode, ?scheduler_mode, "starting dispatchers");
+        let (channel, results): (ResultChannel, PingOutcomes) = match channel_mode {
+            ChannelMode::PerTarget => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                (
+                    ResultChannel::PerTarget(tx),
+                    Box::pin(PerTargetResults::new(rx)),
+                )
+            }
+            ChannelMode::Shared => {
+                let capacity = self.channel_capacity * self.dispatchers.len().max(1);
+                let (tx, rx) = mpsc::channel(capacity);
+                (ResultChannel::Shared(tx), Box::pin(ReceiverStream::new(rx)))
+            }
+        };
+        // Results are counted as queued by dispatchers as they are sent.
+        let results: PingOutcomes = match &self.metrics {
+            Some(metrics) => {
+                let queued = metrics.queued.clone();
+                Box::pin(results.map(move |outcome| {
+                    queued.dec();
+                    outcome
+                }))
+            }
+            None => results,
+        };
+        let pool = match scheduler_mode {
+            SchedulerMode::Tasks => None,
+            SchedulerMode::Pool => Some(Pool::start(self.workers, self.metrics.clone())),
+        };
+        let limiter = self.max_pps.map(|max_pps| {
+            let throttled = self.metrics.as_ref().map(|m| m.throttled.clone());
+            RateLimiter::new(max_pps, throttled)
+        });
+        let mut spawner = Spawner {
+            pool,
+            jitter: self.jitter,
+            limiter,
+            fast_interval_ms: self.fast_interval_ms,
+            timeout: self.timeout,
+            timeouts: self.timeouts,
+            expectations: self.expectations,
+            missed_ticks: self.missed_ticks,
+            liveness: self.liveness,
+            metrics: self.metrics,
+            sinks: self.sinks,
+            pauses: self.pauses,
+            ping_interval_ms: self.ping_interval_ms,
+            probe_factory: self.probe_factory,
+            #[cfg(feature = "chaos")]
+            chaos: self.chaos,
+            channel,
+            channel_capacity: self.channel_capacity,
+            tasks: BTreeMap::new(),
+            pending: self.dispatchers.into(),
+        };
+        match self.ramp {
+            Some(ramp) => {
+                self.target_set.start(spawner);
+                tokio::spawn(launch::run(self.target_set, ramp, self.readiness));
+            }
+            None => {
+                spawner.launch(usize::MAX);
+                self.target_set.start(spawner);
+                self.readiness.set_ready();
+            }
+        }
+        results
+    }
+}
+
+/// Builder of a [`PingSender`], for the options which are fixed once it is
+/// created, such as the names of its metrics.
+///
+/// ```no_run
+/// # #[cfg(feature = "metrics")] {
+/// use std::time::Duration;
+///
+/// use prometheus::Registry;
+/// use uppies::PingSender;
+///
+/// let metrics = Registry::new();
+/// let sender = PingSender::builder()
+///     .with_targets(vec!["1.1.1.1".parse()?])
+///     .with_ping_interval_ms(1000)
+///     .with_target_timeout("1.1.1.1", Duration::from_millis(500))
+///     .with_namespace("edge")
+///     .with_registry(&metrics)
+///     .build()?;
+/// # }
+/// # Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+///
+/// Senders without a registry record no metrics, for consuming their
+/// [`PingSender::results`] directly. Options which can change until the
+/// sender is started, such as its sinks, are set on the [`PingSender`].
+#[derive(Clone)]
+pub struct PingSenderBuilder {
+    targets: Vec<ProbeTarget>,
+    ping_interval_ms: u64,
+    timeout: Duration,
+    timeouts: BTreeMap<String, Duration>,
+    #[cfg(feature = "metrics")]
+    buckets: Vec<f64>,
+    #[cfg(feature = "metrics")]
+    namespace: String,
+    channel_capacity: usize,
+    #[cfg(feature = "metrics")]
+    registry: Option<Registry>,
+}
+
+impl Default for PingSenderBuilder {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            ping_interval_ms: PingSender::DEFAULT_PING_INTERVAL_MS,
+            timeout: PingSender::DEFAULT_TIMEOUT,
+            timeouts: BTreeMap::new(),
+            #[cfg(feature = "metrics")]
+            buckets: DURATION_BUCKETS_MS.to_vec(),
+            #[cfg(feature = "metrics")]
+            namespace: String::new(),
+            channel_capacity: PingSender::DEFAULT_CHANNEL_CAPACITY,
+            #[cfg(feature = "metrics")]
+            registry: None,
+        }
+    }
+}
+
+impl PingSenderBuilder {
+    /// Ping `targets` by ICMP, alongside any other targets.
+    pub fn with_targets(mut self, targets: Vec<ProbeTarget>) -> Self {
+        self.targets.extend(targets);
+        self
+    }
+
+    /// Set the interval between the probes of each target.
+    pub fn with_ping_interval_ms(mut self, ping_interval_ms: u64) -> Self {
+        self.ping_interval_ms = ping_interval_ms;
+        self
+    }
+
+    /// See [`PingSender::with_timeout`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// See [`PingSender::with_target_timeout`].
+    pub fn with_target_timeout(mut self, label: &str, timeout: Duration) -> Self {
+        self.timeouts.insert(label.to_string(), timeout);
+        self
+    }
+
+    /// Set the upper bounds of the buckets of `ping_duration_ms`, in
+    /// milliseconds, which must be increasing.
+    #[cfg(feature = "metrics")]
+    pub fn with_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.buckets = buckets;
+        self
+    }
+
+    /// Prefix the names of the metrics of the sender with `namespace`, as
+    /// in `edge_ping_success_count`.
+    #[cfg(feature = "metrics")]
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    /// See [`PingSender::with_channel_capacity`].
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Record results into metrics registered within `registry`.
+    #[cfg(feature = "metrics")]
+    pub fn with_registry(mut self, registry: &Registry) -> Self {
+        self.registry = Some(registry.clone());
+        self
+    }
+
+    /// Build the sender, failing if the options are invalid, such as
+    /// buckets which aren't increasing.
+    pub fn build(self) -> Result<PingSender> {
+        if self.ping_interval_ms == 0 {
+            return Err("ping interval must be above 0ms".to_string().into());
+        }
+        #[cfg(feature = "metrics")]
+        if self.buckets.is_empty() || self.buckets.windows(2).any(|b| b[0] >= b[1]) {
+            return Err("histogram buckets must be increasing".to_string().into());
+        }
+        let mut sender = PingSender {
+            dispatchers: Vec::new(),
+            ping_interval_ms: self.ping_interval_ms,
+            channel_mode: None,
+            channel_capacity: self.channel_capacity.max(1),
+            scheduler_mode: None,
+            workers: Pool::DEFAULT_WORKERS,
+            jitter: Jitter::default(),
+            max_pps: None,
+            fast_interval_ms: None,
+            timeout: self.timeout,
+            timeouts: self.timeouts,
+            expectations: BTreeMap::new(),
+            missed_ticks: MissedTicks::default(),
+            metrics: None,
+            sinks: Vec::new(),
+            hooks: Vec::new(),
+            pauses: Pauses::default(),
+            probe_factory: Arc::new(|target| probe::icmp(target, &IcmpConfig::default())),
+            target_set: TargetSet::default(),
+            ramp: None,
+            readiness: Readiness::default(),
+            liveness: Liveness::default(),
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        };
+        #[cfg(feature = "metrics")]
+        if let Some(registry) = &self.registry {
+            let metrics = PingMetrics::with_options(registry, &self.namespace, &self.buckets)?;
+            sender.pauses = sender.pauses.with_gauge(metrics.paused.clone());
+            sender.metrics = Some(metrics);
+        }
+        for target in self.targets {
+            let probe = probe::icmp(&target, &IcmpConfig::default())?;
+            sender = sender.with_probe(target, probe);
+        }
+        Ok(sender)
+    }
+}
+
+/// Function called with every ping result, see [`PingSender::on_result`].
+type ResultHook = Arc<dyn Fn(&PingOutcome) + Send + Sync>;
+
+/// Builds the probe of a target which is added once started.
+type ProbeFactory = Arc<dyn Fn(&ProbeTarget) -> Result<BoxProbe> + Send + Sync>;
+
+/// Channels which dispatchers send their results through, see
+/// [`ChannelMode`].
+enum ResultChannel {
+    /// The receiver of each dispatcher's channel is sent to the stream of
+    /// results, so that dispatchers can be added once started.
+    PerTarget(UnboundedSender<ReceiverStream<PingOutcome>>),
+    Shared(Sender<PingOutcome>),
+}
+
+/// Results of dispatchers with a channel each, including those which are
+/// added once the stream has started.
+struct PerTargetResults {
+    streams: StreamMap<u64, ReceiverStream<PingOutcome>>,
+    added: UnboundedReceiver<ReceiverStream<PingOutcome>>,
+    /// Key of the next added stream, as targets can be added again after
+    /// being removed.
+    next_key: u64,
+    /// Whether no more streams can be added.
+    closed: bool,
+}
+
+impl PerTargetResults {
+    fn new(added: UnboundedReceiver<ReceiverStream<PingOutcome>>) -> Self {
+        Self {
+            streams: StreamMap::new(),
+            added,
+            next_key: 0,
+            closed: false,
+        }
+    }
+}
+
+impl Stream for PerTargetResults {
+    type Item = PingOutcome;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        use std::task::Poll;
+
+        while !self.closed {
+            match self.added.poll_recv(cx) {
+                Poll::Ready(Some(stream)) => {
+                    let key = self.next_key;
+                    self.next_key += 1;
+                    self.streams.insert(key, stream);
+                }
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => break,
+            }
+        }
+        match Pin::new(&mut self.streams).poll_next(cx) {
+            // The stream only ends once no more dispatchers can be added.
+            Poll::Ready(None) if !self.closed => Poll::Pending,
+            poll => poll.map(|result| result.map(|(_, outcome)| outcome)),
+        }
+    }
+}
+
+/// Spawns the dispatchers of a started [`PingSender`], including those of
+/// targets added through its [`TargetSet`].
+struct Spawner {
+    /// Pool which dispatchers are added to, rather than spawned as tasks.
+    pool: Option<Pool>,
+    jitter: Jitter,
+    limiter: Option<RateLimiter>,
+    fast_interval_ms: Option<u64>,
+    timeout: Duration,
+    /// Timeouts which differ from `timeout`, by label.
+    timeouts: BTreeMap<String, Duration>,
+    /// Expectations which differ from [`Expect::Up`], by label.
+    expectations: BTreeMap<String, Expect>,
+    missed_ticks: MissedTicks,
+    liveness: Liveness,
+    metrics: Option<PingMetrics>,
+    /// Sinks which are told of the targets which are removed.
+    sinks: Vec<Arc<dyn Sink>>,
+    pauses: Pauses,
+    ping_interval_ms: u64,
+    probe_factory: ProbeFactory,
+    #[cfg(feature = "chaos")]
+    chaos: Option<chaos::ChaosConfig>,
+    channel: ResultChannel,
+    /// Capacity of the channel of each dispatcher with a channel of its own.
+    channel_capacity: usize,
+    /// Target and task of each dispatcher, by label and probe.
+    tasks: BTreeMap<(Arc<str>, Arc<str>), (ProbeTarget, Running)>,
+    /// Dispatchers which are yet to be launched, in launch order.
+    pub(crate) pending: VecDeque<Dispatcher>,
+}
+
+impl Spawner {
+    fn spawn(&mut self, mut dispatcher: Dispatcher) {
+        dispatcher.jitter = self.jitter;
+        dispatcher.limiter = self.limiter.clone();
+        dispatcher.fast_interval_ms = self.fast_interval_ms;
+        dispatcher.missed_ticks = self.missed_ticks;
+        dispatcher.liveness = Some(self.liveness.clone());
+        dispatcher.timeout = match self.timeouts.get(&*dispatcher.label) {
+            Some(timeout) => *timeout,
+            None => self.timeout,
+        };
+        dispatcher.expect = self
+            .expectations
+            .get(&*dispatcher.label)
+            .copied()
+            .unwrap_or_default();
+        #[cfg(feature = "chaos")]
+        {
+            dispatcher.chaos = self.chaos.clone();
+        }
+        let result_tx = match &self.channel {
+            ResultChannel::PerTarget(streams) => {
+                let (tx, rx) = mpsc::channel(self.channel_capacity);
+                // The stream of results may have been dropped, in which
+                // case the dispatcher fails and is restarted until removed.
+                let _ = streams.send(ReceiverStream::new(rx));
+                tx
+            }
+            ResultChannel::Shared(tx) => tx.clone(),
+        };
+        let key = (
+            Arc::clone(&dispatcher.label),
+            Arc::clone(&dispatcher.probe_label),
+        );
+        let target = dispatcher.target.clone();
+        if let Some(pool) = &self.pool {
+            let id = pool.add(dispatcher, result_tx);
+            self.tasks.insert(key, (target, Running::Pooled(id)));
+            return;
+        }
+        let metrics = self.metrics.clone();
+        let restarting = Arc::new(AtomicBool::new(false));
+        let task_restarting = Arc::clone(&restarting);
+        info!(
+            target = &*key.0,
+            probe = &*key.1,
+            "starting dispatcher task"
+        );
+        let task = tokio::spawn(async move {
+            // The dispatcher is restarted if it fails, retaining the same
+            // result channel.
+            loop {
+                let running = DispatcherState::enter(metrics.as_ref(), "running");
+                let result = dispatcher.run(&result_tx).await;
+                drop(running);
+                if let Err(e) = result {
+                    error!(
+                        target = &*dispatcher.label,
+                        probe = &*dispatcher.probe_label,
+                        ?e,
+                        "dispatcher failed, restarting"
+                    );
+                    if let Some(metrics) = &metrics {
+                        metrics
+                            .restart_count
+                            .with_label_values(&[&*dispatcher.label, &*dispatcher.probe_label])
+                            .inc();
+                    }
+                }
+                let _restarting = DispatcherState::enter(metrics.as_ref(), "restarting");
+                task_restarting.store(true, Ordering::Relaxed);
+                tokio::time::sleep(Dispatcher::RESTART_DELAY).await;
+                task_restarting.store(false, Ordering::Relaxed);
+            }
+        });
+        let running = Running::Task(task.abort_handle(), restarting);
+        self.tasks.insert(key, (target, running));
+    }
+
+    /// Spawn up to `count` of the pending dispatchers, returning the number
+    /// which remain pending.
+    fn launch(&mut self, count: usize) -> usize {
+        let count = count.min(self.pending.len());
+        for dispatcher in self.pending.drain(..count).collect::<Vec<_>>() {
+            self.spawn(dispatcher);
+        }
+        self.pending.len()
+    }
+
+    /// Status of the dispatchers of each target, by label, which is the
+    /// worst of those of its probes.
+    fn statuses(&self) -> BTreeMap<String, TaskStatus> {
+        let launched = self
+            .tasks
+            .iter()
+            .map(|((label, _), (_, running))| (label, running.status()));
+        let pending = self
+            .pending
+            .iter()
+            .map(|dispatcher| (&dispatcher.label, TaskStatus::Pending));
+        let mut statuses = BTreeMap::new();
+        for (label, status) in launched.chain(pending) {
+            let worst = statuses.entry(label.to_string()).or_insert(status);
+            *worst = status.max(*worst);
+        }
+        statuses
+    }
+
+    /// Targets of all dispatchers, those launched ordered by label followed
+    /// by those pending launch.
+    fn targets(&self) -> impl Iterator<Item = &ProbeTarget> {
+        let pending = self.pending.iter().map(|dispatcher| &dispatcher.target);
+        self.tasks.values().map(|(target, _)| target).chain(pending)
+    }
+
+    /// Label and probe of every dispatcher, along with its target, in the
+    /// same order as [`targets`](Self::targets).
+    pub(crate) fn probes(&self) -> impl Iterator<Item = (&str, &str, &ProbeTarget)> {
+        let pending = self
+            .pending
+            .iter()
+            .map(|d| (&*d.label, &*d.probe_label, &d.target));
+        self.tasks
+            .iter()
+            .map(|((label, probe), (target, _))| (&**label, &**probe, target))
+            .chain(pending)
+    }
+
+    /// Whether the probe `probe` of a target labelled `label` is pinged.
+    fn contains(&self, label: &str, probe: &str) -> bool {
+        self.tasks.contains_key(&(label.into(), probe.into()))
+            || self
+                .pending
+                .iter()
+                .any(|d| &*d.label == label && &*d.probe_label == probe)
+    }
+
+    /// Build the probe of `target` and start pinging it, unless a target
+    /// with the same label and probe is already pinged.
+    fn add(&mut self, target: &ProbeTarget) -> Result<bool> {
+        if self.contains(&target.label(), target.probe()) {
+            return Ok(false);
+        }
+        let probe = (self.probe_factory)(target)?;
+        self.add_probe(target.clone(), probe);
+        Ok(true)
+    }
+
+    fn add_probe(&mut self, target: ProbeTarget, probe: BoxProbe) {
+        let dispatcher = Dispatcher::register(
+            target,
+            Box::new(probe),
+            None,
+            self.ping_interval_ms,
+            self.metrics.as_ref(),
+            &self.pauses,
+        );
+        self.spawn(dispatcher);
+    }
+
+    /// Stop pinging every probe of the target labelled `target`, returning
+    /// `false` if it isn't pinged.
+    fn remove(&mut self, target: &str) -> bool {
+        let probes: Vec<_> = self
+            .probes()
+            .filter(|(label, _, _)| *label == target)
+            .map(|(_, probe, _)| probe.to_string())
+            .collect();
+        probes
+            .iter()
+            .filter(|probe| self.remove_probe(target, probe))
+            .count()
+            > 0
+    }
+
+    /// Stop pinging the probe `probe` of the target labelled `target`,
+    /// returning `false` if it isn't pinged.
+    fn remove_probe(&mut self, target: &str, probe: &str) -> bool {
+        let key = (Arc::from(target), Arc::from(probe));
+        if let Some((_, running)) = self.tasks.remove(&key) {
+            match (running, &self.pool) {
+                (Running::Task(task, _), _) => task.abort(),
+                (Running::Pooled(id), Some(pool)) => pool.remove(id),
+                (Running::Pooled(_), None) => unreachable!("pooled without a pool"),
+            }
+        } else if let Some(i) = self
+            .pending
+            .iter()
+            .position(|d| &*d.label == target && &*d.probe_label == probe)
+        {
+            self.pending.remove(i);
+        } else {
+            return false;
+        }
+        // The probes of a target share its pause, until the last is removed.
+        if self.targets().all(|t| t.label() != target) {
+            self.pauses.unregister(target);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.remove_target(target, probe);
+        }
+        for sink in &self.sinks {
+            sink.remove(target, probe);
+        }
+        info!(target, probe, "stopped dispatcher task");
+        true
+    }
+}
+
+/// How a launched dispatcher is run.
+enum Running {
+    /// Task of the dispatcher, and whether it is waiting to restart.
+    Task(AbortHandle, Arc<AtomicBool>),
+    /// Identifier of the dispatcher within the [`Pool`].
+    Pooled(u64),
+}
+
+impl Running {
+    fn status(&self) -> TaskStatus {
+        match self {
+            // Dispatcher tasks only finish early by panicking, as they are
+            // removed once aborted.
+            Self::Task(task, _) if task.is_finished() => TaskStatus::Panicked,
+            Self::Task(_, restarting) if restarting.load(Ordering::Relaxed) => {
+                TaskStatus::Restarting
+            }
+            Self::Task(..) | Self::Pooled(_) => TaskStatus::Running,
+        }
+    }
+}
+
+/// Status of the dispatcher of a target, see [`PingTasksHandle::statuses`],
+/// ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TaskStatus {
+    /// Waiting to be launched, see [`launch`].
+    Pending,
+    /// Probing the target.
+    Running,
+    /// Waiting to restart after failing.
+    Restarting,
+    /// Stopped by a panic, so the target is no longer probed.
+    Panicked,
+}
+
+/// Counts a dispatcher within a state of the `uppies_dispatchers` gauge
+/// until dropped, including when its task is aborted.
+struct DispatcherState(Option<IntGauge>);
+
+impl DispatcherState {
+    fn enter(metrics: Option<&PingMetrics>, state: &str) -> Self {
+        let gauge = metrics.map(|m| m.dispatchers.with_label_values(&[state]));
+        if let Some(gauge) = &gauge {
+            gauge.inc();
+        }
+        Self(gauge)
+    }
+}
+
+impl Drop for DispatcherState {
+    fn drop(&mut self) {
+        if let Some(gauge) = &self.0 {
+            gauge.dec();
+        }
+    }
+}
+
+/// Start pinging all targets configured within the [`PingSender`], recording
+/// their results into its metrics and sinks and calling its hooks.
+pub async fn ping_targets(mut sender: PingSender) -> PingTasksHandle {
+    let metrics = sender.metrics.clone();
+    // The sinks are also kept by the sender, to forget removed targets.
+    let sinks = sender.sinks.clone();
+    let hooks = std::mem::take(&mut sender.hooks);
+    let target_set = sender.target_set();
+    let mut results = sender.results();
+    let recorder = tokio::spawn(async move {
+        while let Some(ping) = results.next().await {
+            for sink in &sinks {
+                sink.record(&ping);
+            }
+            if let Some(metrics) = &metrics {
+                metrics.record(&ping);
+                metrics.record_sink_health(&sinks);
+            }
+            for hook in &hooks {
+                hook(&ping);
+            }
+        }
+    });
+    PingTasksHandle {
+        recorder,
+        target_set,
+    }
+}
 
-    /// Histogram of ping durations in milliseconds, labelled by the underlying target.
-    ping_duration_ms: HistogramVec,
+/// Handle to the tasks started by [`ping_targets`], to stop them and wait
+/// for the results which were already sent to be recorded.
+///
+/// Dropping the handle leaves the tasks running.
+pub struct PingTasksHandle {
+    /// Task which records results into metrics and sinks.
+    recorder: JoinHandle<()>,
+    target_set: TargetSet,
 }
 
-impl PingSender {
-    const LABELS: &[&str] = &["target"];
+impl PingTasksHandle {
+    /// Status of the dispatcher of each target, by label.
+    pub fn statuses(&self) -> BTreeMap<String, TaskStatus> {
+        self.target_set.statuses()
+    }
 
-    pub fn new(targets: Vec<String>, ping_interval_ms: u64, metrics: &Registry) -> Result<Self> {
-        let success_count = IntCounterVec::new(
-            Opts::new("ping_success_count", "Counter of successful pings"),
-            Self::LABELS,
-        )?;
-        let failure_count = IntCounterVec::new(
-            Opts::new("ping_failure_count", "Counter of failed pings"),
-            Self::LABELS,
-        )?;
-        let ping_duration_ms = HistogramVec::new(
-            HistogramOpts::new(
-                "ping_duration_ms",
-                "Histogram of ping round-trip times in milliseconds",
-            )
-            .buckets(vec![
-                1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0,
-            ]),
-            Self::LABELS,
-        )?;
-        metrics.register(Box::new(success_count.clone()))?;
-        metrics.register(Box::new(failure_count.clone()))?;
-        metrics.register(Box::new(ping_duration_ms.clone()))?;
-        Ok(Self {
-            dispatchers: targets
-                .iter()
-                .map(|t| Dispatcher::new(t.clone(), ping_interval_ms))
-                .collect::<Result<_>>()?,
-            success_count,
-            failure_count,
-            ping_duration_ms,
-        })
+    /// Stop pinging every target, and no longer accept targets through the
+    /// [`TargetSet`], returning the number of targets which were stopped.
+    /// Results which were already sent are still recorded, after which
+    /// [`PingTasksHandle::join`] completes.
+    pub fn shutdown(&self) -> usize {
+        self.target_set.close()
     }
-}
 
-/// Start pinging all targets configured within the [`PingSender`]
-pub async fn ping_targets(sender: PingSender) {
-    for (dispatcher, mut rx) in sender.dispatchers {
-        let success_count = sender.success_count.clone();
-        let failure_count = sender.failure_count.clone();
-        let ping_duration_ms = sender.ping_duration_ms.clone();
+    /// Stop pinging every target and recording results immediately.
+    pub fn abort(&self) {
+        self.target_set.close();
+        self.recorder.abort();
+    }
 
-        // Check the receive channel 2x faster than the known ping interval
-        // to ensure that all sends are caught in good time.
-        let receive_interval = dispatcher.ping_interval_ms.div_ceil(2);
-        let target = dispatcher.target.clone();
-        info!(target, "starting dispatcher tasks");
-        // Initialise the value on start, this allows the
-        // metric to be immediately reported as 0 if there are no
-        // errors for sometime.
-        failure_count.with_label_values(&[target.clone()]).inc_by(0);
-        tokio::spawn(dispatcher.run(None));
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_millis(receive_interval));
-            loop {
-                interval.tick().await;
-                match rx.try_recv() {
-                    Ok(res) => match res {
-                        Ok(d) => {
-                            success_count.with_label_values(&[target.clone()]).inc();
-                            ping_duration_ms
-                                .with_label_values(&[target.clone()])
-                                .observe(d.as_millis() as f64);
-                        }
-                        Err(_) => failure_count.with_label_values(&[target.clone()]).inc(),
-                    },
-                    Err(TryRecvError::Empty) => continue,
-                    Err(TryRecvError::Disconnected) => panic!("send disconnected"),
-                }
-            }
-        });
+    /// Whether results are no longer recorded.
+    pub fn is_finished(&self) -> bool {
+        self.recorder.is_finished()
+    }
+
+    /// Wait until results are no longer recorded, after a shutdown or
+    /// abort, failing if recording a result panicked, such as in a sink.
+    pub async fn join(self) -> Result<()> {
+        match self.recorder.await {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(UppiesError::TaskPanicked(e.to_string())),
+        }
     }
 }
 
-/// A dispatcher to send pings (ICMP packets) to a specified target.
+/// A dispatcher to schedule probes of a specified target.
 struct Dispatcher {
     /// The underlying target of this [`Dispatcher`], such as
     /// '1.1.1.1'.
-    target: String,
-    /// Internal client used to send ICMP packets.
-    client: Client,
-    /// Result channel for receiving dispatched ping results.
-    result_tx: Sender<Result<Duration>>,
+    target: ProbeTarget,
+    /// Label of the target, which its results and metrics are labelled by.
+    label: Arc<str>,
+    /// Name of the probe, which results and metrics are also labelled by,
+    /// see [`ProbeTarget::probe`].
+    probe_label: Arc<str>,
+    /// Probe of the target.
+    probe: Box<dyn DynProbe>,
+    /// Sequence number of the next ping, retained across restarts.
+    sequence: AtomicU64,
+    /// Whether probes are skipped, see [`Pauses`].
+    paused: Arc<AtomicBool>,
 
     ping_interval_ms: u64,
+
+    /// Interval after a failure, see [`PingSender::with_fast_interval_ms`].
+    fast_interval_ms: Option<u64>,
+    /// Current interval, which is shortened after a failure.
+    interval_ms: AtomicU64,
+
+    /// Length of time before a probe is considered failed.
+    timeout: Duration,
+
+    /// Whether the probes of the target are expected to succeed.
+    expect: Expect,
+
+    /// What happens to the ticks which are missed as a probe overran.
+    missed_ticks: MissedTicks,
+    /// Counter of the probes of the target which overran the interval.
+    overruns: Option<IntCounter>,
+    /// Counter of the results of the target which could not be sent.
+    dropped: Option<IntCounter>,
+    /// Whether the latest result was dropped as the channel was full.
+    dropping: AtomicBool,
+    /// Gauges of the results waiting in the result channels and the highest
+    /// number which have, shared with every other dispatcher.
+    queued: Option<(IntGauge, IntGauge)>,
+    /// Histogram of the delay of probes past when they were due, shared with
+    /// every other dispatcher.
+    scheduling_lag: Option<Histogram>,
+    /// Shared with every dispatcher once spawned.
+    liveness: Option<Liveness>,
+
+    /// Randomisation of the times which the target is probed at.
+    jitter: Jitter,
+
+    /// Rate limit shared with every other dispatcher.
+    limiter: Option<RateLimiter>,
+
+    /// Chaos injected into pings, see [`chaos`].
+    #[cfg(feature = "chaos")]
+    chaos: Option<chaos::ChaosConfig>,
 }
 
 impl Dispatcher {
-    /// Create a new [`Dispatcher`] with an accompanying [`Receiver`] that
-    /// will be used to send ping results into.
-    fn new(target: String, ping_interval_ms: u64) -> Result<(Self, Receiver<Result<Duration>>)> {
-        let client = surge_ping::Client::new(&Config::new())?;
-
-        let (result_tx, result_rx) = tokio::sync::mpsc::channel(5);
-        Ok((
-            Self {
-                target,
-                client,
-                result_tx,
-                ping_interval_ms,
-            },
-            result_rx,
-        ))
+    /// Delay before a failed dispatcher is restarted.
+    const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+    /// Create a new [`Dispatcher`] for a newly added target, initialising
+    /// its metrics and pause flag.
+    fn register(
+        target: ProbeTarget,
+        probe: Box<dyn DynProbe>,
+        name: Option<Arc<str>>,
+        ping_interval_ms: u64,
+        metrics: Option<&PingMetrics>,
+        pauses: &Pauses,
+    ) -> Self {
+        let mut dispatcher = Self::new(target, probe, ping_interval_ms);
+        if let Some(name) = name {
+            dispatcher.probe_label = name;
+        }
+        if let Some(metrics) = metrics {
+            metrics.add_target(&dispatcher.label, &dispatcher.probe_label);
+            let labels = &[&*dispatcher.label, &*dispatcher.probe_label];
+            dispatcher.overruns = Some(metrics.overruns.with_label_values(labels));
+            dispatcher.dropped = Some(metrics.dropped.with_label_values(labels));
+            dispatcher.queued = Some((metrics.queued.clone(), metrics.queued_max.clone()));
+            dispatcher.scheduling_lag = Some(metrics.scheduling_lag.clone());
+        }
+        dispatcher.paused = pauses.register(&dispatcher.label);
+        dispatcher
     }
 
-    /// Run this dispatcher, performing the ping operation to the given target.
-    ///
-    /// A `timeout` can be provided, which alters the length of time before
-    /// a timeout error is issued for the dispatched ping against a target.
+    /// Create a new [`Dispatcher`] for the target.
+    fn new(target: ProbeTarget, probe: Box<dyn DynProbe>, ping_interval_ms: u64) -> Self {
+        Self {
+            label: target.label().into(),
+            probe_label: target.probe().into(),
+            target,
+            probe,
+            sequence: AtomicU64::new(0),
+            paused: Arc::default(),
+            ping_interval_ms,
+            fast_interval_ms: None,
+            interval_ms: AtomicU64::new(ping_interval_ms),
+            timeout: PingSender::DEFAULT_TIMEOUT,
+            expect: Expect::default(),
+            missed_ticks: MissedTicks::default(),
+            overruns: None,
+            dropped: None,
+            dropping: AtomicBool::new(false),
+            queued: None,
+            scheduling_lag: None,
+            liveness: None,
+            jitter: Jitter::default(),
+            limiter: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    /// Run this dispatcher, probing the target and sending outcomes into
+    /// `result_tx`.
     ///
-    /// This is a blocking call and will perform continuous pings against
-    /// the target.
-    async fn run(self, timeout: Option<Duration>) -> Result<()> {
-        let mut pinger = self
-            .client
-            .pinger(
-                IpAddr::from_str(&self.target)?,
-                PingIdentifier(rand::random()),
-            )
-            .await;
+    /// This is a blocking call and will perform continuous probes against
+    /// the target, only returning upon failure.
+    async fn run(&self, result_tx: &Sender<PingOutcome>) -> Result<()> {
+        let mut period = self.interval();
+        let offset = self.jitter.offset(period);
+        if !offset.is_zero() {
+            tokio::time::sleep(offset).await;
+        }
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(self.missed_ticks.behavior());
+        loop {
+            let deadline = interval.tick().await;
+            if let Some(scheduling_lag) = &self.scheduling_lag {
+                scheduling_lag.observe(deadline.elapsed().as_secs_f64());
+            }
+            let delay = self.jitter.delay(period);
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            self.tick(result_tx).await?;
+            if self.interval() != period {
+                period = self.interval();
+                let start = tokio::time::Instant::now() + period;
+                interval = tokio::time::interval_at(start, period);
+                interval.set_missed_tick_behavior(self.missed_ticks.behavior());
+            }
+        }
+    }
 
-        if let Some(timeout) = timeout {
-            pinger.timeout(timeout);
+    /// Interval until the next probe of the target.
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.load(Ordering::Relaxed))
+    }
+
+    /// Shorten the interval to the fast interval after a failure, or double
+    /// it after a success until it is back at the ping interval.
+    fn adapt(&self, success: bool) {
+        let Some(fast_interval_ms) = self.fast_interval_ms else {
+            return;
+        };
+        let current = self.interval_ms.load(Ordering::Relaxed);
+        let next = match success {
+            true => current.saturating_mul(2).min(self.ping_interval_ms),
+            false => fast_interval_ms.min(self.ping_interval_ms),
+        };
+        if next == current {
+            return;
         }
+        self.interval_ms.store(next, Ordering::Relaxed);
+        if current == self.ping_interval_ms {
+            info!(target = &*self.label, interval_ms = next, "probing faster");
+        } else if next == self.ping_interval_ms {
+            info!(
+                target = &*self.label,
+                interval_ms = next,
+                "probing at the ping interval"
+            );
+        }
+    }
 
-        let mut interval = tokio::time::interval(Duration::from_millis(self.ping_interval_ms));
-        loop {
-            interval.tick().await;
-            match pinger.ping(PingSequence(0), &[]).await {
-                Ok((_, duration)) => {
-                    debug!(target = self.target, ?duration, "ping success");
-                    self.result_tx.send(Ok(duration)).await?;
+    /// Probe the target once unless it is paused, sending the outcome into
+    /// `result_tx`, or dropping it if the channel is full.
+    async fn tick(&self, result_tx: &Sender<PingOutcome>) -> Result<()> {
+        // Paused dispatchers still tick, so aren't mistaken for stalled.
+        if let Some(liveness) = &self.liveness {
+            liveness.beat();
+        }
+        if self.paused.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        let start = tokio::time::Instant::now();
+        let ProbeOutcome {
+            resolved_ip,
+            reply,
+            rtt,
+        } = match tokio::time::timeout(self.timeout, self.probe.boxed_probe()).await {
+            Ok(outcome) => outcome,
+            Err(_) => ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Err(PingError {
+                    kind: ErrorKind::Timeout,
+                    message: format!("timed out after {:?}", self.timeout),
+                }),
+            },
+        };
+        // Chaos is injected after, so that its failures aren't mistaken for
+        // a target which is down as expected.
+        let rtt = self.expect.apply(rtt, start.elapsed());
+        #[cfg(feature = "chaos")]
+        let rtt = self.inject_chaos(rtt).await?;
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        if let (true, Some(overruns)) = (start.elapsed() > self.interval(), &self.overruns) {
+            overruns.inc();
+        }
+        self.adapt(rtt.is_ok());
+        // The fields of each result are logged, so that structured logs
+        // can be used as a sink of their own.
+        match &rtt {
+            Ok(duration) => debug!(
+                target = &*self.label,
+                probe = &*self.probe_label,
+                seq = sequence,
+                rtt_ms = duration.as_secs_f64() * 1000.0,
+                "ping success"
+            ),
+            Err(e) => error!(
+                target = &*self.label,
+                probe = &*self.probe_label,
+                seq = sequence,
+                error_kind = e.kind.as_str(),
+                %e,
+                "ping failure"
+            ),
+        }
+        let outcome = PingOutcome {
+            target: Arc::clone(&self.label),
+            probe: Arc::clone(&self.probe_label),
+            resolved_ip,
+            reply,
+            sequence,
+            rtt,
+            expect: self.expect,
+            timestamp: SystemTime::now(),
+        };
+        // Counted before sending, as it may be received straight away.
+        if let Some((queued, _)) = &self.queued {
+            queued.inc();
+        }
+        // Results are dropped rather than waiting for space, so that a
+        // stalled consumer can't delay the probes of the target.
+        let closed = match result_tx.try_send(outcome) {
+            Ok(()) => {
+                if let Some((queued, queued_max)) = &self.queued {
+                    queued_max.set(queued_max.get().max(queued.get()));
                 }
-                Err(e) => {
-                    error!(target = self.target, ?e, "ping failure");
-                    self.result_tx.send(Err(Box::new(e))).await?;
+                if self.dropping.swap(false, Ordering::Relaxed) {
+                    info!(target = &*self.label, "result channel has space again");
                 }
+                return Ok(());
+            }
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Closed(_)) => true,
+        };
+        if let Some((queued, _)) = &self.queued {
+            queued.dec();
+        }
+        if let Some(dropped) = &self.dropped {
+            dropped.inc();
+        }
+        if closed {
+            return Err(UppiesError::ChannelClosed);
+        }
+        if !self.dropping.swap(true, Ordering::Relaxed) {
+            warn!(
+                target = &*self.label,
+                seq = sequence,
+                "result channel is full, dropping results"
+            );
+        }
+        Ok(())
+    }
+
+    /// Apply any configured chaos to the result of a ping, returning an
+    /// error when the dispatcher should crash.
+    #[cfg(feature = "chaos")]
+    async fn inject_chaos(
+        &self,
+        rtt: std::result::Result<Duration, PingError>,
+    ) -> Result<std::result::Result<Duration, PingError>> {
+        let Some(chaos) = &self.chaos else {
+            return Ok(rtt);
+        };
+        match chaos.inject() {
+            chaos::Injection::None => Ok(rtt),
+            chaos::Injection::Failure => Ok(Err(PingError {
+                kind: ErrorKind::Injected,
+                message: "chaos: injected failure".to_string(),
+            })),
+            chaos::Injection::Delay(delay) => {
+                tokio::time::sleep(delay).await;
+                Ok(rtt.map(|d| d + delay))
             }
+            chaos::Injection::Crash => Err(UppiesError::Probe(PingError {
+                kind: ErrorKind::Injected,
+                message: "chaos: injected crash".to_string(),
+            })),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{
+        collections::BTreeSet,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
 
+    #[cfg(feature = "metrics")]
     use prometheus::{
         core::{Atomic, GenericCounterVec},
         Registry,
     };
+    use tokio_stream::StreamExt;
 
-    use crate::{ping_targets, Dispatcher, PingSender};
+    use crate::{
+        ping_targets,
+        probe::{IcmpProbe, MockProbe, Probe, ProbeOutcome},
+        ChannelMode, Dispatcher, ErrorKind, Expect, PingError, PingOutcome, PingSender, TaskStatus,
+    };
+    #[cfg(feature = "metrics")]
+    use crate::{probe::Reply, sink::Sink, PingMetrics};
 
     const LOCALHOST: &str = "127.0.0.1";
     const TEST_DURATION_MS: u64 = 200;
 
     #[tokio::test]
     async fn dispatcher_success() {
-        let (dispatcher, mut rx) =
-            Dispatcher::new(LOCALHOST.to_string(), TEST_DURATION_MS).unwrap();
-        tokio::spawn(dispatcher.run(None));
+        let probe = IcmpProbe::new(LOCALHOST).unwrap();
+        let dispatcher = Dispatcher::new(
+            LOCALHOST.parse().unwrap(),
+            Box::new(probe),
+            TEST_DURATION_MS,
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::channel(PingSender::DEFAULT_CHANNEL_CAPACITY);
+        tokio::spawn(async move { dispatcher.run(&tx).await });
 
         let res = tokio::time::timeout(Duration::from_millis(TEST_DURATION_MS * 3), async move {
             loop {
                 match rx.recv().await {
-                    Some(res) => return res,
+                    Some(ping) => return ping.rtt,
                     None => continue,
                 }
             }
@@ -205,14 +2075,22 @@ mod test {
     #[tokio::test]
     async fn dispatcher_failure() {
         let unbound_addr = "10.0.0.200"; // this could be flakey
-        let (dispatcher, mut rx) =
-            Dispatcher::new(unbound_addr.to_string(), TEST_DURATION_MS).unwrap();
-        tokio::spawn(dispatcher.run(Some(Duration::from_millis(100)))); // short time-out duration
+                                         // short time-out duration
+        let probe = IcmpProbe::new(unbound_addr)
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+        let dispatcher = Dispatcher::new(
+            unbound_addr.parse().unwrap(),
+            Box::new(probe),
+            TEST_DURATION_MS,
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::channel(PingSender::DEFAULT_CHANNEL_CAPACITY);
+        tokio::spawn(async move { dispatcher.run(&tx).await });
 
         let res = tokio::time::timeout(Duration::from_secs(1), async move {
             loop {
                 match rx.recv().await {
-                    Some(res) => return res,
+                    Some(ping) => return ping.rtt,
                     None => continue,
                 }
             }
@@ -223,29 +2101,425 @@ mod test {
         assert!(res.is_err());
     }
 
-    fn get_metric_value<P: Atomic>(metric_value: GenericCounterVec<P>, target: &str) -> P::T {
+    #[test]
+    fn error_kinds() {
+        let timeout = PingOutcome::test(LOCALHOST, Err(ErrorKind::Timeout));
+        assert_eq!(timeout.error_kind(), Some(ErrorKind::Timeout));
+        assert_eq!(
+            PingOutcome::test(LOCALHOST, Ok(Duration::ZERO)).error_kind(),
+            None
+        );
+        assert_eq!(
+            PingError::from(surge_ping::SurgeError::NetworkError).kind,
+            ErrorKind::Io
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn unexpected_replies() {
+        let metrics = PingMetrics::new(&Registry::new()).unwrap();
+        let reply = |source: &str, echo| {
+            let mut outcome = PingOutcome::test(LOCALHOST, Ok(Duration::ZERO));
+            outcome.reply = Some(Reply {
+                source: source.parse().unwrap(),
+                ttl: Some(61),
+                echo,
+            });
+            outcome
+        };
+        metrics.record(&reply(LOCALHOST, true));
+        metrics.record(&reply("10.0.0.1", true));
+        metrics.record(&reply(LOCALHOST, false));
+        let unexpected = |reason| {
+            metrics
+                .unexpected_replies
+                .with_label_values(&[LOCALHOST, "icmp", reason])
+                .get()
+        };
+        assert_eq!(unexpected("source"), 1);
+        assert_eq!(unexpected("type"), 1);
+        assert_eq!(
+            metrics
+                .reply_ttl
+                .with_label_values(&[LOCALHOST, "icmp"])
+                .get(),
+            61
+        );
+    }
+
+    #[test]
+    fn parse_duration() {
+        assert_eq!(
+            crate::parse_duration("90s").unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            crate::parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 86400)
+        );
+        for invalid in ["", "12", "1w", "-1h"] {
+            assert!(crate::parse_duration(invalid).is_err(), "{invalid}");
+        }
+        let err = crate::parse_duration(&format!("{}d", u64::MAX / 1000)).unwrap_err();
+        assert!(err.to_string().contains("too long"), "{err}");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn builder() {
+        let metrics = Registry::new();
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(100)
+            .with_buckets(vec![10.0, 100.0])
+            .with_namespace("edge")
+            .with_target_timeout("a", Duration::from_millis(50))
+            .with_channel_capacity(0)
+            .with_registry(&metrics)
+            .build()
+            .unwrap();
+        assert_eq!(sender.ping_interval_ms, 100);
+        assert_eq!(sender.timeouts["a"], Duration::from_millis(50));
+        assert_eq!(sender.channel_capacity, 1);
+
+        let ping_metrics = sender.metrics.unwrap();
+        ping_metrics.record(&PingOutcome::test("a", Ok(Duration::from_millis(20))));
+        let families = metrics.gather();
+        let duration = families
+            .iter()
+            .find(|f| f.name() == "edge_ping_duration_ms")
+            .unwrap();
+        let histogram = duration.get_metric()[0].get_histogram();
+        assert_eq!(histogram.get_bucket().len(), 2);
+        assert_eq!(histogram.get_bucket()[1].cumulative_count(), 1);
+        assert!(ping_metrics
+            .exemplars
+            .get("edge_ping_duration_ms", "a", "icmp", 100.0)
+            .is_some());
+        assert!(families.iter().all(|f| f.name().starts_with("edge_")));
+
+        let invalid = [
+            PingSender::builder().with_ping_interval_ms(0),
+            PingSender::builder()
+                .with_buckets(vec![100.0, 10.0])
+                .with_registry(&Registry::new()),
+        ];
+        for builder in invalid {
+            assert!(builder.build().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn result_hooks() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let hook = |name: &'static str| {
+            let calls = Arc::clone(&calls);
+            move |outcome: &PingOutcome| {
+                let mut calls = calls.lock().unwrap();
+                calls.push((name, outcome.sequence));
+            }
+        };
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(1000)
+            .build()
+            .unwrap()
+            .with_probe("a".parse().unwrap(), SlowProbe(Duration::ZERO))
+            .on_result(hook("first"))
+            .on_result(hook("second"));
+        let tasks = ping_targets(sender).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tasks.abort();
+
+        // Each hook was called with the result, in the order they were added.
+        assert_eq!(*calls.lock().unwrap(), [("first", 0), ("second", 0)]);
+    }
+
+    /// Probe which panics, as a bug in a custom probe might.
+    struct PanickingProbe;
+
+    impl Probe for PanickingProbe {
+        async fn probe(&self) -> ProbeOutcome {
+            panic!("probe panicked")
+        }
+    }
+
+    #[tokio::test]
+    async fn tasks_handle() {
+        let builder = PingSender::builder().with_ping_interval_ms(10);
+        #[cfg(feature = "metrics")]
+        let builder = builder.with_registry(&Registry::new());
+        let sender = builder
+            .build()
+            .unwrap()
+            .with_probe("a".parse().unwrap(), SlowProbe(Duration::ZERO))
+            .with_probe("b".parse().unwrap(), PanickingProbe);
+        #[cfg(feature = "metrics")]
+        let ping_metrics = sender.metrics.clone().unwrap();
+        let tasks = ping_targets(sender).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let statuses = tasks.statuses();
+        assert_eq!(statuses["a"], TaskStatus::Running);
+        assert_eq!(statuses["b"], TaskStatus::Panicked);
+
+        assert_eq!(tasks.shutdown(), 2);
+        assert!(tasks.statuses().is_empty());
+        tokio::time::timeout(Duration::from_secs(1), tasks.join())
+            .await
+            .unwrap()
+            .unwrap();
+        // Every result which was sent was recorded before finishing.
+        #[cfg(feature = "metrics")]
+        assert_eq!(ping_metrics.queued.get(), 0);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn queued_results() {
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(10)
+            .with_registry(&Registry::new())
+            .build()
+            .unwrap()
+            .with_channel_capacity(3)
+            .with_probe("a".parse().unwrap(), SlowProbe(Duration::ZERO));
+        let ping_metrics = sender.metrics.clone().unwrap();
+        let mut results = sender.results();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Nothing is receiving the results, so the channel filled up and
+        // later results were dropped without delaying the probes.
+        assert_eq!(ping_metrics.queued.get(), 3);
+        assert_eq!(ping_metrics.queued_max.get(), 3);
+        assert!(get_metric_value(ping_metrics.dropped.clone(), "a", "custom") >= 10);
+        assert!(ping_metrics.scheduling_lag.get_sample_count() >= 10);
+
+        let first = results.next().await.unwrap();
+        assert_eq!(first.sequence, 0);
+        assert_eq!(ping_metrics.queued.get(), 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn get_metric_value<P: Atomic>(
+        metric_value: GenericCounterVec<P>,
+        target: &str,
+        probe: &str,
+    ) -> P::T {
         metric_value
-            .get_metric_with_label_values(&[target])
+            .get_metric_with_label_values(&[target, probe])
             .unwrap()
             .get()
     }
 
+    /// Probe which never completes, such as of a blackholed target.
+    #[cfg(feature = "metrics")]
+    struct StalledProbe;
+
+    #[cfg(feature = "metrics")]
+    impl Probe for StalledProbe {
+        async fn probe(&self) -> ProbeOutcome {
+            std::future::pending().await
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn timeouts() {
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(100)
+            .with_registry(&Registry::new())
+            .build()
+            .unwrap()
+            .with_timeout(Duration::from_millis(50))
+            .with_target_timeout("slow", Duration::from_secs(60))
+            .with_probe("stalled".parse().unwrap(), StalledProbe)
+            .with_probe("slow".parse().unwrap(), StalledProbe);
+        let ping_metrics = sender.metrics.clone().unwrap();
+        tokio::spawn(ping_targets(sender));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let timeouts = get_metric_value(ping_metrics.timeouts.clone(), "stalled", "custom");
+        assert!(timeouts >= 2, "{timeouts}");
+        assert_eq!(
+            get_metric_value(ping_metrics.failure_count, "stalled", "custom"),
+            timeouts
+        );
+        assert_eq!(get_metric_value(ping_metrics.timeouts, "slow", "custom"), 0);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn expect_down() {
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(100)
+            .with_registry(&Registry::new())
+            .build()
+            .unwrap()
+            .with_timeout(Duration::from_millis(50))
+            .with_target_expect("blocked", Expect::Down)
+            .with_target_expect("open", Expect::Down)
+            .with_probe("blocked".parse().unwrap(), StalledProbe)
+            .with_probe("open".parse().unwrap(), SlowProbe(Duration::from_millis(1)));
+        let ping_metrics = sender.metrics.clone().unwrap();
+        tokio::spawn(ping_targets(sender));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert!(get_metric_value(ping_metrics.success_count.clone(), "blocked", "custom") >= 2);
+        assert_eq!(
+            get_metric_value(ping_metrics.failure_count.clone(), "blocked", "custom"),
+            0
+        );
+        // Compliant pings have no round-trip time to observe.
+        assert_eq!(
+            ping_metrics
+                .ping_duration_ms
+                .with_label_values(&["blocked", "custom"])
+                .get_sample_count(),
+            0
+        );
+        assert_eq!(
+            get_metric_value(ping_metrics.success_count, "open", "custom"),
+            0
+        );
+        assert!(get_metric_value(ping_metrics.failure_count, "open", "custom") >= 2);
+    }
+
+    #[test]
+    fn expect() {
+        let error = |kind| {
+            Err(PingError {
+                kind,
+                message: String::new(),
+            })
+        };
+        let elapsed = Duration::from_millis(50);
+        let rtt = Ok(Duration::from_millis(5));
+        assert_eq!(Expect::Up.apply(rtt.clone(), elapsed), rtt);
+        assert_eq!(
+            Expect::Down.apply(rtt, elapsed).unwrap_err().kind,
+            ErrorKind::Reachable
+        );
+        assert_eq!(
+            Expect::Down.apply(error(ErrorKind::Timeout), elapsed),
+            Ok(elapsed)
+        );
+        assert_eq!(
+            Expect::Down
+                .apply(error(ErrorKind::Tls), elapsed)
+                .unwrap_err()
+                .kind,
+            ErrorKind::Reachable
+        );
+        // Whether the target is down is unknown when it can't be resolved.
+        assert_eq!(
+            Expect::Down.apply(error(ErrorKind::Resolution), elapsed),
+            error(ErrorKind::Resolution)
+        );
+    }
+
+    /// Probe which takes the given length of time.
+    struct SlowProbe(Duration);
+
+    impl Probe for SlowProbe {
+        async fn probe(&self) -> ProbeOutcome {
+            tokio::time::sleep(self.0).await;
+            ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Ok(self.0),
+            }
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn overruns() {
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(100)
+            .with_registry(&Registry::new())
+            .build()
+            .unwrap()
+            .with_probe(
+                "slow".parse().unwrap(),
+                SlowProbe(Duration::from_millis(150)),
+            )
+            .with_probe("fast".parse().unwrap(), SlowProbe(Duration::ZERO));
+        let ping_metrics = sender.metrics.clone().unwrap();
+        tokio::spawn(ping_targets(sender));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert!(get_metric_value(ping_metrics.overruns.clone(), "slow", "custom") >= 2);
+        assert_eq!(get_metric_value(ping_metrics.overruns, "fast", "custom"), 0);
+    }
+
+    #[test]
+    fn adaptive_interval() {
+        let mut dispatcher = Dispatcher::new(
+            "mock".parse().unwrap(),
+            Box::new(MockProbe::new([Ok(Duration::ZERO)])),
+            1000,
+        );
+        dispatcher.adapt(false);
+        assert_eq!(
+            dispatcher.interval(),
+            Duration::from_secs(1),
+            "not adaptive"
+        );
+
+        dispatcher.fast_interval_ms = Some(100);
+        let intervals: Vec<_> = [
+            false, false, true, true, false, true, true, true, true, true,
+        ]
+        .into_iter()
+        .map(|success| {
+            dispatcher.adapt(success);
+            dispatcher.interval().as_millis()
+        })
+        .collect();
+        assert_eq!(
+            intervals,
+            [100, 100, 200, 400, 100, 200, 400, 800, 1000, 1000]
+        );
+    }
+
+    #[test]
+    fn default_channel_mode() {
+        assert_eq!(ChannelMode::for_targets(1), ChannelMode::PerTarget);
+        assert_eq!(
+            ChannelMode::for_targets(ChannelMode::SHARED_THRESHOLD),
+            ChannelMode::Shared
+        );
+        assert_eq!("shared".parse(), Ok(ChannelMode::Shared));
+        assert!("unknown".parse::<ChannelMode>().is_err());
+    }
+
+    #[cfg(feature = "metrics")]
     #[tokio::test]
     async fn pings() {
+        assert_pings(ChannelMode::PerTarget).await;
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn pings_shared_channel() {
+        assert_pings(ChannelMode::Shared).await;
+    }
+
+    #[cfg(feature = "metrics")]
+    async fn assert_pings(channel_mode: ChannelMode) {
         let metrics = Registry::new();
-        let ping_sender = PingSender::new(
-            [LOCALHOST, LOCALHOST]
-                .into_iter()
-                .map(|s| s.to_string())
-                .collect(),
-            TEST_DURATION_MS,
-            &metrics,
-        )
-        .unwrap();
+        let ping_sender = PingSender::builder()
+            .with_targets(vec![LOCALHOST.parse().unwrap(), LOCALHOST.parse().unwrap()])
+            .with_ping_interval_ms(TEST_DURATION_MS)
+            .with_registry(&metrics)
+            .build()
+            .unwrap()
+            .with_channel_mode(channel_mode);
 
-        let success_count = ping_sender.success_count.clone();
-        let failure_count = ping_sender.failure_count.clone();
-        let ping_duration_histogram = ping_sender.ping_duration_ms.clone();
+        let ping_metrics = ping_sender.metrics.clone().unwrap();
+        let success_count = ping_metrics.success_count;
+        let failure_count = ping_metrics.failure_count;
+        let ping_duration_histogram = ping_metrics.ping_duration_ms;
 
         tokio::spawn(ping_targets(ping_sender));
 
@@ -253,19 +2527,199 @@ mod test {
         tokio::time::sleep(Duration::from_secs(1)).await;
 
         assert!(
-            get_metric_value(success_count, LOCALHOST) > 0,
+            get_metric_value(success_count, LOCALHOST, "icmp") > 0,
             "Success counter should have increased"
         );
         assert_eq!(
-            get_metric_value(failure_count, LOCALHOST),
+            get_metric_value(failure_count, LOCALHOST, "icmp"),
             0,
             "Failure counter should still be 0"
         );
         assert!(
             ping_duration_histogram
-                .with_label_values(&[LOCALHOST])
+                .with_label_values(&[LOCALHOST, "icmp"])
                 .get_sample_count()
                 > 0
         );
+        assert_eq!(ping_metrics.targets.get(), 2);
+        assert_eq!(
+            ping_metrics
+                .dispatchers
+                .with_label_values(&["running"])
+                .get(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn results_stream() {
+        let results = PingSender::builder()
+            .with_targets(vec![
+                LOCALHOST.parse().unwrap(),
+                "10.0.0.200".parse().unwrap(),
+            ])
+            .with_ping_interval_ms(TEST_DURATION_MS)
+            .build()
+            .unwrap()
+            .results();
+        let localhost: Vec<_> = results
+            .filter(|ping| &*ping.target == LOCALHOST)
+            .take(3)
+            .collect()
+            .await;
+
+        assert_eq!(
+            localhost
+                .iter()
+                .map(|ping| ping.sequence)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2],
+            "sequence numbers should increase for each ping"
+        );
+        assert!(localhost.iter().all(|ping| ping.rtt.is_ok()));
+        assert!(localhost
+            .iter()
+            .all(|ping| ping.resolved_ip == LOCALHOST.parse().ok()));
+    }
+
+    /// Probe which always succeeds with the same round-trip time.
+    struct ConstantProbe(Duration);
+
+    impl Probe for ConstantProbe {
+        async fn probe(&self) -> ProbeOutcome {
+            ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Ok(self.0),
+            }
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn custom_probe() {
+        let ping_sender = PingSender::builder()
+            .with_ping_interval_ms(TEST_DURATION_MS)
+            .with_registry(&Registry::new())
+            .build()
+            .unwrap()
+            .with_probe(
+                "in-process".parse().unwrap(),
+                ConstantProbe(Duration::from_millis(7)),
+            );
+        let ping_metrics = ping_sender.metrics.clone().unwrap();
+
+        tokio::spawn(ping_targets(ping_sender));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert!(get_metric_value(ping_metrics.success_count, "in-process", "custom") > 0);
+        let histogram = ping_metrics
+            .ping_duration_ms
+            .with_label_values(&["in-process", "custom"]);
+        assert_eq!(
+            histogram.get_sample_sum(),
+            7.0 * histogram.get_sample_count() as f64
+        );
+    }
+
+    #[tokio::test]
+    async fn probes_of_a_target() {
+        let results = PingSender::builder()
+            .with_ping_interval_ms(TEST_DURATION_MS)
+            .build()
+            .unwrap()
+            .with_probe(
+                "web=127.0.0.1".parse().unwrap(),
+                ConstantProbe(Duration::from_millis(1)),
+            )
+            .with_probe(
+                "web=tls://127.0.0.1".parse().unwrap(),
+                MockProbe::new([Err(ErrorKind::Tls)]),
+            )
+            .results();
+        let pings: Vec<_> = results.take(4).collect().await;
+
+        assert!(pings.iter().all(|ping| &*ping.target == "web"));
+        let probes: BTreeSet<_> = pings.iter().map(|ping| &*ping.probe).collect();
+        assert_eq!(probes, BTreeSet::from(["custom", "tls"]));
+        for ping in &pings {
+            match &*ping.probe {
+                "custom" => assert!(ping.rtt.is_ok()),
+                "tls" => assert_eq!(ping.error_kind(), Some(ErrorKind::Tls)),
+                probe => panic!("unexpected probe {probe}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn scripted_probe() {
+        let script = [
+            Ok(Duration::from_millis(3)),
+            Err(ErrorKind::Timeout),
+            Err(ErrorKind::Io),
+        ];
+        let results = PingSender::builder()
+            .with_ping_interval_ms(TEST_DURATION_MS)
+            .build()
+            .unwrap()
+            .with_probe("mock".parse().unwrap(), MockProbe::new(script))
+            .results();
+        let pings: Vec<_> = results.take(4).collect().await;
+
+        assert_eq!(
+            pings
+                .iter()
+                .map(|ping| ping.rtt.clone().map_err(|e| e.kind))
+                .collect::<Vec<_>>(),
+            [script.as_slice(), &script[..1]].concat()
+        );
+        assert!(pings.iter().all(|ping| &*ping.target == "mock"));
+    }
+
+    #[tokio::test]
+    async fn paused_target() {
+        let probe = Arc::new(MockProbe::new([Ok(Duration::from_millis(1))]));
+        let sender = PingSender::builder()
+            .with_ping_interval_ms(50)
+            .build()
+            .unwrap()
+            .with_probe("mock".parse().unwrap(), Arc::clone(&probe));
+        let pauses = sender.pauses();
+        let mut results = sender.results();
+        tokio::spawn(async move { while results.next().await.is_some() {} });
+
+        assert!(pauses.set_paused("mock", true));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let probes = probe.probes();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(probe.probes(), probes, "paused targets aren't probed");
+
+        assert!(pauses.set_paused("mock", false));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(probe.probes() > probes);
+    }
+
+    #[cfg(all(feature = "chaos", feature = "metrics"))]
+    #[tokio::test]
+    async fn restart_after_crash() {
+        let ping_sender = PingSender::builder()
+            .with_targets(vec![LOCALHOST.parse().unwrap()])
+            .with_ping_interval_ms(TEST_DURATION_MS)
+            .with_registry(&Registry::new())
+            .build()
+            .unwrap()
+            .with_chaos(crate::chaos::ChaosConfig {
+                crash_rate: 1.0,
+                ..Default::default()
+            });
+        let restart_count = ping_sender.metrics.clone().unwrap().restart_count;
+
+        tokio::spawn(ping_targets(ping_sender));
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+
+        assert!(
+            get_metric_value(restart_count, LOCALHOST, "icmp") >= 2,
+            "Crashed dispatcher should be restarted"
+        );
     }
 }
@@ -0,0 +1,113 @@
+//! Rate limit of the probes of all targets, so that a large number of
+//! targets or a short interval can't flood the network or trip the rules of
+//! an IDS.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::metrics::IntCounter;
+
+/// Token bucket shared by every dispatcher, which probes wait on once it is
+/// empty.
+///
+/// Clones share the same underlying bucket.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    /// Tokens added each second.
+    rate: f64,
+    /// Most tokens which are held, which is the largest burst of probes.
+    capacity: f64,
+    /// Counter of probes which waited for a token.
+    throttled: Option<IntCounter>,
+}
+
+struct Bucket {
+    /// Tokens which are available, which is negative once probes are
+    /// waiting on tokens which are yet to be added.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter of `max_pps` probes per second, which bursts by at
+    /// most a tenth of a second of probes.
+    pub(crate) fn new(max_pps: f64, throttled: Option<IntCounter>) -> Self {
+        let rate = max_pps.max(f64::MIN_POSITIVE);
+        let capacity = (rate / 10.0).max(1.0);
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: capacity,
+                updated: Instant::now(),
+            })),
+            rate,
+            capacity,
+            throttled,
+        }
+    }
+
+    /// Take a token, waiting until it is added if none are available.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("bucket isn't poisoned");
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+            bucket.updated = now;
+            bucket.tokens -= 1.0;
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / self.rate),
+                false => return,
+            }
+        };
+        if let Some(throttled) = &self.throttled {
+            throttled.inc();
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use std::time::Duration;
+
+    use prometheus::IntCounter;
+    use tokio::time::Instant;
+
+    use super::RateLimiter;
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let throttled = IntCounter::new("throttled", "throttled").unwrap();
+        let limiter = RateLimiter::new(100.0, Some(throttled.clone()));
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire().await;
+        }
+        assert_eq!(throttled.get(), 0, "bursts of 10 aren't limited");
+
+        let waiting: Vec<_> = (0..10)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire().await })
+            })
+            .collect();
+        for waiting in waiting {
+            waiting.await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(throttled.get(), 10);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        for _ in 0..10 {
+            limiter.acquire().await;
+        }
+        assert_eq!(throttled.get(), 10);
+        limiter.acquire().await;
+        assert_eq!(throttled.get(), 11, "tokens are capped at the burst");
+    }
+}
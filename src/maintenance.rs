@@ -0,0 +1,441 @@
+//! Maintenance windows of targets, such as the weekly upgrade of the
+//! routers of a site, during which pings are still recorded but changes of
+//! state aren't notified and alerts don't fire.
+//!
+//! Each window starts at the times of a cron-like schedule in UTC, and lasts
+//! for its duration. Whether a target is within a window is exported by the
+//! `target_in_maintenance` gauge of each target which any window covers.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{IntGaugeVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+
+use crate::{groups::GroupsConfig, sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceWindow {
+    /// Name of the window, unique among all windows.
+    pub name: String,
+    /// Targets within the window, along with those of `groups`, or all
+    /// targets when both are empty.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Groups whose targets are within the window.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Times at which the window starts, in UTC.
+    pub schedule: Schedule,
+    /// Length of the window, in minutes.
+    pub duration_mins: u64,
+}
+
+/// Times matching a cron expression of five fields, the minute, hour, day
+/// of the month, month and day of the week, such as `0 2 * * sun` for 2am
+/// every Sunday.
+///
+/// Each field is `*`, a value, a range such as `1-5`, or a list of them
+/// such as `1,15`, optionally with a step such as `*/15`. Months and days
+/// of the week can also be given by name, such as `jan` and `mon`. As with
+/// cron, a time matches when either of the days of the month and of the
+/// week matches, if both are restricted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the days of the month and of the week are unrestricted.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// Whether the minute which started `minute` minutes since the unix
+    /// epoch matches.
+    fn matches(&self, minute: u64) -> bool {
+        let days = minute / (24 * 60);
+        let (month, day) = month_and_day(days);
+        let weekday = (days + 4) % 7;
+        let day = match self.any_day || self.any_weekday {
+            true => bit(self.days, day) && bit(self.weekdays, weekday),
+            false => bit(self.days, day) || bit(self.weekdays, weekday),
+        };
+        day && bit(self.minutes, minute % 60)
+            && bit(self.hours, minute / 60 % 24)
+            && bit(self.months, month)
+    }
+}
+
+fn bit(bits: u64, value: u64) -> bool {
+    bits & (1 << value) != 0
+}
+
+/// Month and day of the month of the day which started `days` days since the
+/// unix epoch, see <https://howardhinnant.github.io/date_algorithms.html>.
+fn month_and_day(days: u64) -> (u64, u64) {
+    // Days since 0000-03-01, from which years end on the leap day.
+    let z = days + 719_468;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    (month, day)
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Values of a field between `min` and `max`, whose names start from `min`,
+/// as a bit of each value.
+fn parse_field(
+    field: &str,
+    min: u64,
+    max: u64,
+    names: &[&str],
+) -> std::result::Result<u64, String> {
+    let value = |s: &str| -> std::result::Result<u64, String> {
+        let value = match names.iter().position(|name| name.eq_ignore_ascii_case(s)) {
+            Some(i) => min + i as u64,
+            None => s.parse().map_err(|_| format!("invalid value '{s}'"))?,
+        };
+        match (min..=max).contains(&value) {
+            true => Ok(value),
+            false => Err(format!("{value} is not between {min} and {max}")),
+        }
+    };
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+        let step = match step.map(str::parse) {
+            Some(Ok(0) | Err(_)) => return Err(format!("invalid step in '{item}'")),
+            Some(Ok(step)) => step,
+            None => 1,
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // A single value with a step repeats until the end of the field.
+            None if item.contains('/') => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(format!("invalid range '{range}'"));
+        }
+        for value in (first..=last).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid schedule '{s}': {reason}");
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(invalid(
+                "expected 5 fields of minute, hour, day of month, month and day of week",
+            ));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7, WEEKDAYS).map_err(|e| invalid(&e))?;
+        // Both 0 and 7 are Sunday.
+        if bit(weekday_bits, 7) {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            expression: s.to_string(),
+            minutes: parse_field(minutes, 0, 59, &[]).map_err(|e| invalid(&e))?,
+            hours: parse_field(hours, 0, 23, &[]).map_err(|e| invalid(&e))?,
+            days: parse_field(days, 1, 31, &[]).map_err(|e| invalid(&e))?,
+            months: parse_field(months, 1, 12, MONTHS).map_err(|e| invalid(&e))?,
+            weekdays: weekday_bits,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.to_string()
+    }
+}
+
+/// A window with the targets it covers, or `None` for all targets.
+struct Window {
+    targets: Option<HashSet<String>>,
+    schedule: Schedule,
+    duration_mins: u64,
+}
+
+impl Window {
+    fn covers(&self, target: &str) -> bool {
+        self.targets
+            .as_ref()
+            .is_none_or(|targets| targets.contains(target))
+    }
+
+    /// Whether the window started within its duration before `minute`.
+    fn is_active(&self, minute: u64) -> bool {
+        let first = minute.saturating_sub(self.duration_mins.saturating_sub(1));
+        (first..=minute).any(|minute| self.schedule.matches(minute))
+    }
+}
+
+/// Maintenance windows of targets, which are recorded as a [`Sink`].
+///
+/// Clones share the same windows.
+#[derive(Clone)]
+pub struct Maintenance {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    windows: Vec<Window>,
+    /// Whether each window is active, as of the minute it was last checked
+    /// in, since windows start and end on the minute.
+    active: Mutex<Option<(u64, Vec<bool>)>>,
+    in_maintenance: IntGaugeVec,
+}
+
+impl Maintenance {
+    pub fn new(
+        windows: &[MaintenanceWindow],
+        groups: &GroupsConfig,
+        metrics: &Registry,
+    ) -> Result<Self> {
+        let mut resolved = Vec::with_capacity(windows.len());
+        for (i, window) in windows.iter().enumerate() {
+            if window.name.is_empty() {
+                return Err("maintenance window names must be non-empty"
+                    .to_string()
+                    .into());
+            }
+            if windows[..i].iter().any(|w| w.name == window.name) {
+                return Err(format!(
+                    "maintenance window '{}' is defined more than once",
+                    window.name
+                )
+                .into());
+            }
+            if window.duration_mins == 0 {
+                return Err(format!(
+                    "maintenance window '{}' must last at least a minute",
+                    window.name
+                )
+                .into());
+            }
+            let mut targets: HashSet<_> = window.targets.iter().cloned().collect();
+            for group in &window.groups {
+                let members = groups.get(group).ok_or_else(|| {
+                    format!(
+                        "maintenance window '{}' refers to unknown group '{group}'",
+                        window.name
+                    )
+                })?;
+                targets.extend(members.iter().cloned());
+            }
+            let all = window.targets.is_empty() && window.groups.is_empty();
+            resolved.push(Window {
+                targets: (!all).then_some(targets),
+                schedule: window.schedule.clone(),
+                duration_mins: window.duration_mins,
+            });
+        }
+        let in_maintenance = IntGaugeVec::new(
+            Opts::new(
+                "target_in_maintenance",
+                "Whether the target is currently within a maintenance window (1) or not (0)",
+            ),
+            &["target", "probe"],
+        )?;
+        metrics.register(Box::new(in_maintenance.clone()))?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                windows: resolved,
+                active: Mutex::new(None),
+                in_maintenance,
+            }),
+        })
+    }
+
+    /// Whether `target` is within any maintenance window at `at`.
+    pub fn contains(&self, target: &str, at: SystemTime) -> bool {
+        let minute = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        let mut active = self.inner.active.lock().expect("maintenance lock poisoned");
+        let active = match &mut *active {
+            Some((checked, active)) if *checked == minute => active,
+            active => {
+                let windows = self.inner.windows.iter();
+                let now = windows.map(|window| window.is_active(minute)).collect();
+                &mut active.insert((minute, now)).1
+            }
+        };
+        self.inner
+            .windows
+            .iter()
+            .zip(active.iter())
+            .any(|(window, active)| *active && window.covers(target))
+    }
+}
+
+impl Sink for Maintenance {
+    fn record(&self, outcome: &PingOutcome) {
+        let target = &*outcome.target;
+        if !self
+            .inner
+            .windows
+            .iter()
+            .any(|window| window.covers(target))
+        {
+            return;
+        }
+        let within = self.contains(target, outcome.timestamp);
+        self.inner
+            .in_maintenance
+            .with_label_values(&[target, &outcome.probe])
+            .set(within as i64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use prometheus::Registry;
+
+    use super::{Maintenance, MaintenanceWindow, Schedule};
+    use crate::{groups::GroupsConfig, sink::Sink, PingOutcome};
+
+    /// Time of the `hour` and `minute` of the day `days` days into 2024 in
+    /// UTC, which started on a Monday.
+    fn at(days: u64, hour: u64, minute: u64) -> SystemTime {
+        let start_of_2024 = 1_704_067_200;
+        UNIX_EPOCH + Duration::from_secs(start_of_2024 + ((days * 24 + hour) * 60 + minute) * 60)
+    }
+
+    fn minute(at: SystemTime) -> u64 {
+        at.duration_since(UNIX_EPOCH).unwrap().as_secs() / 60
+    }
+
+    #[test]
+    fn schedules() {
+        let schedule: Schedule = "0 2 * * sun".parse().unwrap();
+        // The 7th of January 2024 was a Sunday.
+        assert!(schedule.matches(minute(at(6, 2, 0))));
+        assert!(!schedule.matches(minute(at(6, 2, 1))));
+        assert!(!schedule.matches(minute(at(5, 2, 0))));
+        assert!(schedule.matches(minute(at(13, 2, 0))));
+
+        let schedule: Schedule = "*/15 9-17 * feb 1-5".parse().unwrap();
+        // The 1st of February 2024 was a Thursday.
+        assert!(schedule.matches(minute(at(31, 9, 45))));
+        assert!(!schedule.matches(minute(at(31, 9, 40))));
+        assert!(!schedule.matches(minute(at(31, 18, 0))));
+        assert!(!schedule.matches(minute(at(0, 9, 0))), "not February");
+        assert!(!schedule.matches(minute(at(33, 9, 0))), "a Saturday");
+
+        // Either day matches when both are restricted, and 7 is Sunday.
+        let schedule: Schedule = "30 4 1 * 7".parse().unwrap();
+        assert!(schedule.matches(minute(at(0, 4, 30))), "the 1st");
+        assert!(schedule.matches(minute(at(6, 4, 30))), "a Sunday");
+        assert!(!schedule.matches(minute(at(1, 4, 30))));
+        // The 29th of February of a leap year.
+        let schedule: Schedule = "0 0 29 2 *".parse().unwrap();
+        assert!(schedule.matches(minute(at(59, 0, 0))));
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        for schedule in [
+            "0 2 * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert!(schedule.parse::<Schedule>().is_err(), "{schedule}");
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let groups = GroupsConfig::from([("site".to_string(), vec!["10.0.0.1".to_string()])]);
+        let window = MaintenanceWindow {
+            name: "upgrades".to_string(),
+            targets: vec!["10.0.0.2".to_string()],
+            groups: vec!["site".to_string()],
+            schedule: "0 2 * * sun".parse().unwrap(),
+            duration_mins: 60,
+        };
+        let metrics = Registry::new();
+        let maintenance =
+            Maintenance::new(std::slice::from_ref(&window), &groups, &metrics).unwrap();
+        assert!(maintenance.contains("10.0.0.1", at(6, 2, 0)));
+        assert!(maintenance.contains("10.0.0.2", at(6, 2, 59)));
+        assert!(!maintenance.contains("10.0.0.2", at(6, 3, 0)));
+        assert!(!maintenance.contains("10.0.0.2", at(6, 1, 59)));
+        assert!(!maintenance.contains("10.0.0.3", at(6, 2, 30)));
+
+        let mut outcome = PingOutcome::test("10.0.0.1", Ok(Duration::ZERO));
+        outcome.timestamp = at(6, 2, 30);
+        maintenance.record(&outcome);
+        let gauge = || {
+            maintenance
+                .inner
+                .in_maintenance
+                .with_label_values(&["10.0.0.1", "icmp"])
+                .get()
+        };
+        assert_eq!(gauge(), 1);
+        outcome.timestamp = at(6, 3, 30);
+        maintenance.record(&outcome);
+        assert_eq!(gauge(), 0);
+        // Targets outside every window have no series.
+        maintenance.record(&PingOutcome::test("10.0.0.3", Ok(Duration::ZERO)));
+        assert_eq!(metrics.gather()[0].get_metric().len(), 1);
+
+        let unknown = MaintenanceWindow {
+            groups: vec!["other".to_string()],
+            ..window.clone()
+        };
+        assert!(Maintenance::new(&[unknown], &groups, &Registry::new()).is_err());
+        let duplicate = [window.clone(), window];
+        assert!(Maintenance::new(&duplicate, &groups, &Registry::new()).is_err());
+    }
+}
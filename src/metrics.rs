@@ -0,0 +1,69 @@
+//! Handles of the Prometheus metrics which the probing engine records into,
+//! such as the counters of each dispatcher.
+//!
+//! Without the `metrics` feature these are stand-ins of the same names
+//! which record nothing, so the engine compiles unchanged while the
+//! handles, which are then never created, are always absent.
+
+#[cfg(feature = "metrics")]
+pub(crate) use prometheus::{
+    Gauge, GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) use self::disabled::*;
+
+#[cfg(not(feature = "metrics"))]
+#[allow(dead_code)]
+mod disabled {
+    /// Declare metrics which record nothing, with the methods of their
+    /// prometheus counterparts which the engine calls.
+    macro_rules! disabled {
+        ($($name:ident { $(fn $method:ident($($arg:ty),*) $(-> $ret:ty)?;)* })*) => {$(
+            #[derive(Clone, Debug, Default)]
+            pub(crate) struct $name;
+
+            impl $name {
+                $(pub(crate) fn $method(&self, $(_: $arg),*) $(-> $ret)? {
+                    Default::default()
+                })*
+            }
+        )*};
+    }
+
+    disabled! {
+        Gauge {
+            fn set(f64);
+        }
+        IntGauge {
+            fn inc();
+            fn dec();
+            fn set(i64);
+            fn get() -> i64;
+        }
+        IntCounter {
+            fn inc();
+            fn inc_by(u64);
+            fn get() -> u64;
+        }
+        Histogram {
+            fn observe(f64);
+        }
+        GaugeVec {
+            fn with_label_values(&[&str]) -> Gauge;
+            fn remove_label_values(&[&str]) -> Option<()>;
+        }
+        IntGaugeVec {
+            fn with_label_values(&[&str]) -> IntGauge;
+            fn remove_label_values(&[&str]) -> Option<()>;
+        }
+        IntCounterVec {
+            fn with_label_values(&[&str]) -> IntCounter;
+            fn remove_label_values(&[&str]) -> Option<()>;
+        }
+        HistogramVec {
+            fn with_label_values(&[&str]) -> Histogram;
+            fn remove_label_values(&[&str]) -> Option<()>;
+        }
+    }
+}
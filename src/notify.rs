@@ -0,0 +1,562 @@
+//! Notifications of targets changing between up and down, as decided by the
+//! hysteresis of the [`StateTracker`](crate::state::StateTracker).
+//!
+//! Each notifier delivers changes in order from its own task, retrying
+//! failed deliveries with an exponential backoff so that a brief outage of
+//! the receiver doesn't lose notifications.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    time::Duration,
+};
+
+use prometheus::{IntCounter, IntCounterVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, info, warn};
+
+#[cfg(feature = "server")]
+use crate::cluster::Cluster;
+use crate::{
+    destination::{Destination, Destinations},
+    state::State,
+    Result,
+};
+
+pub mod discord;
+pub mod pagerduty;
+pub mod slack;
+pub mod smtp;
+pub mod template;
+pub mod webhook;
+
+use discord::{DiscordConfig, DiscordNotifier};
+use pagerduty::{PagerDutyConfig, PagerDutyNotifier};
+use slack::{SlackConfig, SlackNotifier};
+use smtp::{SmtpConfig, SmtpNotifier};
+use webhook::{WebhookConfig, WebhookNotifier};
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// URLs which each state change is posted to as JSON.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Slack incoming webhooks which a message is posted to for each state
+    /// change.
+    #[serde(default)]
+    pub slack: Vec<SlackConfig>,
+    /// Discord webhooks which a message is posted to for each state change.
+    #[serde(default)]
+    pub discord: Vec<DiscordConfig>,
+    /// SMTP servers which an email is sent through for each state change.
+    #[serde(default)]
+    pub smtp: Vec<SmtpConfig>,
+    /// PagerDuty services which an incident is triggered on when a target
+    /// goes down, and resolved when it is up again.
+    #[serde(default)]
+    pub pagerduty: Vec<PagerDutyConfig>,
+    /// URL of the status page, such as a dashboard of all targets, which
+    /// message templates can link to.
+    pub status_page_url: Option<String>,
+    /// Named groups of targets, which each notifier can be limited to with
+    /// its `groups`.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Number of times a failed delivery is retried before it is dropped.
+    #[serde(default = "NotifyConfig::default_max_retries")]
+    pub max_retries: u32,
+    /// Delay, in milliseconds, before the first retry of a failed delivery,
+    /// which doubles on each subsequent retry.
+    #[serde(default = "NotifyConfig::default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// Timeout, in milliseconds, of each delivery attempt.
+    #[serde(default = "NotifyConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl NotifyConfig {
+    fn default_max_retries() -> u32 {
+        5
+    }
+
+    fn default_initial_backoff_ms() -> u64 {
+        1000
+    }
+
+    fn default_timeout_ms() -> u64 {
+        5000
+    }
+
+    /// Targets within any of `groups`, or `None` for all targets when no
+    /// groups are given.
+    fn select(&self, groups: &[String]) -> Result<Option<HashSet<String>>> {
+        if groups.is_empty() {
+            return Ok(None);
+        }
+        let mut targets = HashSet::new();
+        for group in groups {
+            let members = self
+                .groups
+                .get(group)
+                .ok_or_else(|| format!("unknown notification group '{group}'"))?;
+            targets.extend(members.iter().cloned());
+        }
+        Ok(Some(targets))
+    }
+}
+
+/// A change of a target between up and down.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateChange {
+    pub target: String,
+    /// Probe of the target which changed state, see [`ProbeTarget::probe`](crate::ProbeTarget::probe).
+    #[serde(default)]
+    pub probe: String,
+    /// State which the target changed to.
+    pub state: State,
+    /// Time of the ping which changed the state, in milliseconds since the
+    /// unix epoch.
+    pub timestamp_ms: u64,
+    /// Time which the target was down for, in milliseconds, when it changed
+    /// to up.
+    pub down_for_ms: Option<u64>,
+    /// Whether the target started flapping, in which case further changes
+    /// aren't notified until a change with this unset once it stops.
+    #[serde(default)]
+    pub flapping: bool,
+    /// Round-trip times of recent successful pings of the target, if any.
+    pub rtt: Option<RttStats>,
+}
+
+/// Summary of the round-trip times of recent successful pings, in
+/// milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RttStats {
+    pub last_ms: f64,
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+}
+
+impl StateChange {
+    /// Human readable summary of the change, for chat notifiers.
+    pub fn message(&self) -> String {
+        let rtt = self
+            .rtt
+            .as_ref()
+            .map(|rtt| format!(", last RTT {:.1}ms", rtt.last_ms))
+            .unwrap_or_default();
+        if self.flapping {
+            return format!("🟠 {} is flapping between up and down", self.target);
+        }
+        match (self.state, self.down_for_ms) {
+            (State::Down, _) => format!("🔴 {} is down{rtt}", self.target),
+            (State::Up, Some(ms)) => format!(
+                "🟢 {} is up after {} of downtime{rtt}",
+                self.target,
+                format_duration(Duration::from_millis(ms))
+            ),
+            (State::Up, None) => format!("🟢 {} is up{rtt}", self.target),
+        }
+    }
+}
+
+/// Format a duration for people to read, such as `1h 2m 3s`, with second
+/// precision.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{secs}s"),
+        (0, _) => format!("{minutes}m {secs}s"),
+        _ => format!("{hours}h {minutes}m {secs}s"),
+    }
+}
+
+/// A destination which state changes are delivered to.
+pub trait Notifier: Send + Sync + 'static {
+    /// Name of the notifier, used for logging and labels.
+    fn name(&self) -> &str;
+
+    /// Deliver a single state change, failing if it should be retried.
+    fn notify(&self, change: &StateChange) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Handle to deliver state changes to all configured notifiers.
+///
+/// Clones deliver to the same notifiers.
+#[derive(Clone)]
+pub struct Notifications {
+    tx: broadcast::Sender<StateChange>,
+    destinations: Destinations,
+    /// Cluster which this instance is part of, whose followers leave
+    /// delivery to the leader.
+    #[cfg(feature = "server")]
+    cluster: Option<Cluster>,
+}
+
+impl Notifications {
+    /// Number of state changes which each notifier can fall behind by before
+    /// changes are dropped.
+    const CAPACITY: usize = 256;
+
+    /// Upper bound of the delay between retries.
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Start delivering to all notifiers of `config`, registering each of
+    /// them with `destinations`.
+    pub fn new(
+        config: &NotifyConfig,
+        metrics: &Registry,
+        destinations: &Destinations,
+    ) -> Result<Self> {
+        let sent = IntCounterVec::new(
+            Opts::new(
+                "notifications_sent_total",
+                "Counter of state change notifications which were delivered",
+            ),
+            &["notifier"],
+        )?;
+        let failed = IntCounterVec::new(
+            Opts::new(
+                "notifications_failed_total",
+                "Counter of state change notifications which were dropped after all retries",
+            ),
+            &["notifier"],
+        )?;
+        metrics.register(Box::new(sent.clone()))?;
+        metrics.register(Box::new(failed.clone()))?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()?;
+        let notifications = Self {
+            tx: broadcast::channel(Self::CAPACITY).0,
+            destinations: destinations.clone(),
+            #[cfg(feature = "server")]
+            cluster: None,
+        };
+        let counters = (&sent, &failed);
+        let status_page_url = config.status_page_url.as_deref();
+        for webhook in &config.webhooks {
+            let notifier = WebhookNotifier::new(webhook, client.clone(), status_page_url)?;
+            notifications.spawn(notifier, &webhook.groups, config, counters)?;
+        }
+        for slack in &config.slack {
+            let notifier = SlackNotifier::new(slack, client.clone(), status_page_url)?;
+            notifications.spawn(notifier, &slack.groups, config, counters)?;
+        }
+        for discord in &config.discord {
+            let notifier = DiscordNotifier::new(discord, client.clone(), status_page_url)?;
+            notifications.spawn(notifier, &discord.groups, config, counters)?;
+        }
+        for pagerduty in &config.pagerduty {
+            let notifier = PagerDutyNotifier::new(pagerduty, client.clone(), status_page_url)?;
+            notifications.spawn(notifier, &pagerduty.groups, config, counters)?;
+        }
+        for smtp in &config.smtp {
+            let timeout = Duration::from_millis(config.timeout_ms);
+            let notifier = SmtpNotifier::new(smtp, timeout, status_page_url)?;
+            notifications.spawn(notifier, &smtp.groups, config, counters)?;
+        }
+        Ok(notifications)
+    }
+
+    /// Only deliver state changes while this instance is the leader of
+    /// `cluster`, so that redundant instances don't notify twice.
+    #[cfg(feature = "server")]
+    pub fn with_cluster(mut self, cluster: Cluster) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    fn spawn<N: Notifier>(
+        &self,
+        notifier: N,
+        groups: &[String],
+        config: &NotifyConfig,
+        (sent, failed): (&IntCounterVec, &IntCounterVec),
+    ) -> Result<()> {
+        info!(notifier = notifier.name(), ?groups, "starting notifier");
+        let delivery = Delivery {
+            targets: config.select(groups)?,
+            sent: sent.with_label_values(&[notifier.name()]),
+            failed: failed.with_label_values(&[notifier.name()]),
+            destination: self.destinations.register("notifier", notifier.name()),
+            notifier,
+            max_retries: config.max_retries,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+        };
+        tokio::spawn(delivery.run(self.tx.subscribe()));
+        Ok(())
+    }
+
+    /// Deliver `change` to all notifiers in the background.
+    pub(crate) fn notify(&self, change: StateChange) {
+        #[cfg(feature = "server")]
+        if self.cluster.as_ref().is_some_and(|c| !c.is_leader()) {
+            debug!(
+                target = change.target,
+                "not the cluster leader, skipping notification"
+            );
+            return;
+        }
+        // Without any notifiers, there is nothing to deliver to.
+        let _ = self.tx.send(change);
+    }
+}
+
+/// Delivery of state changes to a single notifier.
+struct Delivery<N> {
+    notifier: N,
+    /// Targets which changes are delivered for, or `None` for all targets.
+    targets: Option<HashSet<String>>,
+    max_retries: u32,
+    initial_backoff: Duration,
+    sent: IntCounter,
+    failed: IntCounter,
+    destination: Destination,
+}
+
+impl<N: Notifier> Delivery<N> {
+    async fn run(self, mut changes: broadcast::Receiver<StateChange>) {
+        let name = self.notifier.name();
+        loop {
+            let change = match changes.recv().await {
+                Ok(change) => change,
+                Err(RecvError::Lagged(count)) => {
+                    warn!(notifier = name, skipped = count, "notifier fell behind");
+                    self.failed.inc_by(count);
+                    self.destination
+                        .failed(format!("fell behind, dropping {count} changes"));
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            self.destination.set_queue_depth(changes.len());
+            if let Some(targets) = &self.targets {
+                if !targets.contains(&change.target) {
+                    continue;
+                }
+            }
+            let mut backoff = self.initial_backoff;
+            for attempt in 0..=self.max_retries {
+                match self.notifier.notify(&change).await {
+                    Ok(()) => {
+                        debug!(notifier = name, target = change.target, "notification sent");
+                        self.destination.succeeded();
+                        self.sent.inc();
+                        break;
+                    }
+                    Err(e) if attempt < self.max_retries => {
+                        self.destination.failed(&e);
+                        warn!(
+                            notifier = name,
+                            ?e,
+                            ?backoff,
+                            "notification failed, retrying"
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(Notifications::MAX_BACKOFF);
+                    }
+                    Err(e) => {
+                        error!(
+                            notifier = name,
+                            target = change.target,
+                            ?e,
+                            "notification failed, dropping"
+                        );
+                        self.destination.failed(&e);
+                        self.failed.inc();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use prometheus::{IntCounterVec, Opts, Registry};
+
+    use super::{Notifications, Notifier, NotifyConfig, RttStats, StateChange};
+    #[cfg(feature = "server")]
+    use crate::cluster::Cluster;
+    use crate::{
+        destination::Destinations,
+        sink::Sink,
+        state::{State, StateConfig, StateTracker},
+        ErrorKind, PingOutcome, Result,
+    };
+
+    /// Records every change which it is notified of.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<StateChange>>>);
+
+    impl Notifier for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        async fn notify(&self, change: &StateChange) -> Result<()> {
+            self.0.lock().unwrap().push(change.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn messages() {
+        let change = StateChange {
+            target: "1.1.1.1".to_string(),
+            probe: "icmp".to_string(),
+            state: State::Up,
+            timestamp_ms: 0,
+            down_for_ms: Some(3_723_000),
+            flapping: false,
+            rtt: Some(RttStats {
+                last_ms: 12.34,
+                min_ms: 10.0,
+                avg_ms: 11.0,
+                max_ms: 12.34,
+            }),
+        };
+        assert_eq!(
+            change.message(),
+            "🟢 1.1.1.1 is up after 1h 2m 3s of downtime, last RTT 12.3ms"
+        );
+        let change = StateChange {
+            state: State::Down,
+            down_for_ms: None,
+            flapping: false,
+            rtt: None,
+            ..change
+        };
+        assert_eq!(change.message(), "🔴 1.1.1.1 is down");
+        let change = StateChange {
+            flapping: true,
+            ..change
+        };
+        assert_eq!(
+            change.message(),
+            "🟠 1.1.1.1 is flapping between up and down"
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn follower_sends_nothing() {
+        let config = NotifyConfig::default();
+        let counter = || IntCounterVec::new(Opts::new("c", "c"), &["notifier"]).unwrap();
+        let (sent, failed) = (counter(), counter());
+        let destinations = Destinations::new(&Registry::new()).unwrap();
+        let cluster = Cluster::new(
+            "b".to_string(),
+            vec!["http://127.0.0.1:1".to_string()],
+            Duration::from_secs(60),
+            None,
+            &Registry::new(),
+        )
+        .unwrap();
+        let notifications = Notifications::new(&config, &Registry::new(), &destinations)
+            .unwrap()
+            .with_cluster(cluster.clone());
+        let recorder = Recorder::default();
+        notifications
+            .spawn(recorder.clone(), &[], &config, (&sent, &failed))
+            .unwrap();
+        let change = |state| StateChange {
+            target: "1.1.1.1".to_string(),
+            probe: "icmp".to_string(),
+            state,
+            timestamp_ms: 0,
+            down_for_ms: None,
+            flapping: false,
+            rtt: None,
+        };
+
+        cluster.update(false);
+        notifications.notify(change(State::Down));
+        cluster.update(true);
+        notifications.notify(change(State::Up));
+        for _ in 0..100 {
+            if sent.with_label_values(&["recorder"]).get() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let changes = recorder.0.lock().unwrap();
+        assert_eq!(changes.len(), 1, "only the leader should notify");
+        assert_eq!(changes[0].state, State::Up);
+    }
+
+    #[tokio::test]
+    async fn notify_selected_groups() {
+        let config = NotifyConfig {
+            groups: BTreeMap::from([("lan".to_string(), vec!["192.168.1.1".to_string()])]),
+            ..Default::default()
+        };
+        let counter = || IntCounterVec::new(Opts::new("c", "c"), &["notifier"]).unwrap();
+        let (sent, failed) = (counter(), counter());
+        let destinations = Destinations::new(&Registry::new()).unwrap();
+        let notifications = Notifications::new(&config, &Registry::new(), &destinations).unwrap();
+        let (all, lan) = (Recorder::default(), Recorder::default());
+        for (recorder, groups) in [(&all, vec![]), (&lan, vec!["lan".to_string()])] {
+            notifications
+                .spawn(recorder.clone(), &groups, &config, (&sent, &failed))
+                .unwrap();
+        }
+        assert!(notifications
+            .spawn(
+                Recorder::default(),
+                &["wan".to_string()],
+                &config,
+                (&sent, &failed)
+            )
+            .is_err());
+
+        let tracker = StateTracker::new(&StateConfig::default(), &Registry::new())
+            .unwrap()
+            .with_notifications(notifications);
+        let start = SystemTime::UNIX_EPOCH;
+        for target in ["192.168.1.1", "10.0.0.1"] {
+            let ping = |secs, rtt| PingOutcome {
+                timestamp: start + Duration::from_secs(secs),
+                ..PingOutcome::test(target, rtt)
+            };
+            tracker.record(&ping(0, Ok(Duration::from_millis(2))));
+            for secs in 1..=3 {
+                tracker.record(&ping(secs, Err(ErrorKind::Timeout)));
+            }
+            for secs in 4..=6 {
+                tracker.record(&ping(secs, Ok(Duration::from_millis(3))));
+            }
+        }
+
+        for _ in 0..100 {
+            if sent.with_label_values(&["recorder"]).get() == 6 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let all = all.0.lock().unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[0].state, State::Down);
+        assert_eq!(all[0].rtt.as_ref().map(|rtt| rtt.last_ms), Some(2.0));
+        assert_eq!(all[1].state, State::Up);
+        assert_eq!(all[1].down_for_ms, Some(3000));
+        let rtt = all[1].rtt.as_ref().unwrap();
+        assert_eq!((rtt.min_ms, rtt.max_ms, rtt.last_ms), (2.0, 3.0, 3.0));
+        let lan = lan.0.lock().unwrap();
+        assert!(lan.iter().all(|change| change.target == "192.168.1.1"));
+        assert_eq!(lan.len(), 2);
+    }
+}
@@ -0,0 +1,85 @@
+//! Notifier which posts a message for each state change to a Discord
+//! webhook.
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    template::{self, Template},
+    Notifier, StateChange,
+};
+use crate::Result;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    /// URL of the webhook, such as `https://discord.com/api/webhooks/...`.
+    pub url: String,
+    /// Name of the notifier, used in the `notifier` label of the
+    /// notification metrics.
+    #[serde(default = "DiscordConfig::default_name")]
+    pub name: String,
+    /// Name which messages are posted as, instead of that of the webhook.
+    pub username: Option<String>,
+    /// Template of the message, see [`template`](super::template), instead
+    /// of the default message.
+    pub template: Option<String>,
+    /// Groups of targets which messages are posted for, or all targets when
+    /// empty.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl DiscordConfig {
+    fn default_name() -> String {
+        "discord".to_string()
+    }
+}
+
+pub struct DiscordNotifier {
+    name: String,
+    url: String,
+    template: Option<Template>,
+    username: Option<String>,
+    client: reqwest::Client,
+}
+
+impl DiscordNotifier {
+    pub fn new(
+        config: &DiscordConfig,
+        client: reqwest::Client,
+        status_page_url: Option<&str>,
+    ) -> Result<Self> {
+        Ok(Self {
+            name: config.name.clone(),
+            url: config.url.clone(),
+            username: config.username.clone(),
+            template: config
+                .template
+                .as_deref()
+                .map(|source| Template::new(source, status_page_url))
+                .transpose()?,
+            client,
+        })
+    }
+}
+
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, change: &StateChange) -> Result<()> {
+        let mut body = json!({ "content": template::message(self.template.as_ref(), change) });
+        if let Some(username) = &self.username {
+            body["username"] = username.as_str().into();
+        }
+        self.client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
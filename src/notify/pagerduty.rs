@@ -0,0 +1,216 @@
+//! Notifier which triggers a PagerDuty incident when a target goes down and
+//! resolves it when the target is up again, through the Events API v2.
+//!
+//! Events of a target share a dedup key, so that a target which goes down
+//! again before its incident is resolved doesn't open a duplicate incident.
+//! A target which starts flapping triggers an incident whatever its state,
+//! which is resolved once it stops flapping while up.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{
+    template::{self, Template},
+    Notifier, StateChange,
+};
+use crate::{state::State, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PagerDutyConfig {
+    /// Integration key of the PagerDuty service, which is sent as the
+    /// `routing_key` of each event.
+    pub routing_key: String,
+    /// URL of the Events API, such as that of the EU service region.
+    #[serde(default = "PagerDutyConfig::default_url")]
+    pub url: String,
+    /// Severity of triggered incidents, one of `critical`, `error`,
+    /// `warning` or `info`.
+    #[serde(default = "PagerDutyConfig::default_severity")]
+    pub severity: String,
+    /// Prefix of the dedup key of each target, which is followed by the
+    /// target, so that multiple instances can share a service.
+    #[serde(default = "PagerDutyConfig::default_dedup_key_prefix")]
+    pub dedup_key_prefix: String,
+    /// Template of the summary of triggered incidents, see
+    /// [`template`](super::template), instead of the default message.
+    pub template: Option<String>,
+    /// Name of the notifier, used in the `notifier` label of the
+    /// notification metrics.
+    #[serde(default = "PagerDutyConfig::default_name")]
+    pub name: String,
+    /// Groups of targets which incidents are triggered for, or all targets
+    /// when empty.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl PagerDutyConfig {
+    fn default_url() -> String {
+        "https://events.pagerduty.com/v2/enqueue".to_string()
+    }
+
+    fn default_severity() -> String {
+        "critical".to_string()
+    }
+
+    fn default_dedup_key_prefix() -> String {
+        "uppies/".to_string()
+    }
+
+    fn default_name() -> String {
+        "pagerduty".to_string()
+    }
+}
+
+pub struct PagerDutyNotifier {
+    name: String,
+    url: String,
+    routing_key: String,
+    severity: String,
+    dedup_key_prefix: String,
+    template: Option<Template>,
+    status_page_url: Option<String>,
+    client: reqwest::Client,
+}
+
+impl PagerDutyNotifier {
+    pub fn new(
+        config: &PagerDutyConfig,
+        client: reqwest::Client,
+        status_page_url: Option<&str>,
+    ) -> Result<Self> {
+        if !["critical", "error", "warning", "info"].contains(&config.severity.as_str()) {
+            return Err(format!(
+                "invalid pagerduty severity '{}', expected 'critical', 'error', 'warning' or 'info'",
+                config.severity
+            )
+            .into());
+        }
+        Ok(Self {
+            name: config.name.clone(),
+            url: config.url.clone(),
+            routing_key: config.routing_key.clone(),
+            severity: config.severity.clone(),
+            dedup_key_prefix: config.dedup_key_prefix.clone(),
+            template: config
+                .template
+                .as_deref()
+                .map(|source| Template::new(source, status_page_url))
+                .transpose()?,
+            status_page_url: status_page_url.map(str::to_string),
+            client,
+        })
+    }
+
+    fn event(&self, change: &StateChange) -> Value {
+        let dedup_key = format!("{}{}", self.dedup_key_prefix, change.target);
+        match (change.state, change.flapping) {
+            (State::Up, false) => json!({
+                "routing_key": self.routing_key,
+                "event_action": "resolve",
+                "dedup_key": dedup_key,
+            }),
+            _ => {
+                let links = self
+                    .status_page_url
+                    .iter()
+                    .map(|url| json!({ "href": url, "text": "Status page" }))
+                    .collect::<Vec<_>>();
+                json!({
+                    "routing_key": self.routing_key,
+                    "event_action": "trigger",
+                    "dedup_key": dedup_key,
+                    "payload": {
+                        "summary": template::message(self.template.as_ref(), change),
+                        "source": change.target,
+                        "severity": self.severity,
+                        "component": "uppies",
+                        "custom_details": change,
+                    },
+                    "links": links,
+                })
+            }
+        }
+    }
+}
+
+impl Notifier for PagerDutyNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, change: &StateChange) -> Result<()> {
+        self.client
+            .post(&self.url)
+            .json(&self.event(change))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PagerDutyConfig, PagerDutyNotifier};
+    use crate::{notify::StateChange, state::State};
+
+    fn config() -> PagerDutyConfig {
+        PagerDutyConfig {
+            routing_key: "key".to_string(),
+            url: PagerDutyConfig::default_url(),
+            severity: PagerDutyConfig::default_severity(),
+            dedup_key_prefix: PagerDutyConfig::default_dedup_key_prefix(),
+            template: None,
+            name: PagerDutyConfig::default_name(),
+            groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn events() {
+        let notifier = PagerDutyNotifier::new(
+            &config(),
+            reqwest::Client::new(),
+            Some("https://status.example.com"),
+        )
+        .unwrap();
+        let down = StateChange {
+            target: "1.1.1.1".to_string(),
+            probe: "icmp".to_string(),
+            state: State::Down,
+            timestamp_ms: 0,
+            down_for_ms: None,
+            flapping: false,
+            rtt: None,
+        };
+        let trigger = notifier.event(&down);
+        assert_eq!(trigger["event_action"], "trigger");
+        assert_eq!(trigger["dedup_key"], "uppies/1.1.1.1");
+        assert_eq!(trigger["payload"]["summary"], down.message());
+        assert_eq!(trigger["payload"]["severity"], "critical");
+        assert_eq!(trigger["links"][0]["href"], "https://status.example.com");
+
+        let up = StateChange {
+            state: State::Up,
+            down_for_ms: Some(1000),
+            ..down
+        };
+        let resolve = notifier.event(&up);
+        assert_eq!(resolve["event_action"], "resolve");
+        assert_eq!(resolve["dedup_key"], trigger["dedup_key"]);
+        assert!(resolve.get("payload").is_none());
+        let flapping = StateChange {
+            flapping: true,
+            ..up
+        };
+        assert_eq!(notifier.event(&flapping)["event_action"], "trigger");
+
+        let invalid = PagerDutyConfig {
+            severity: "urgent".to_string(),
+            ..config()
+        };
+        assert!(PagerDutyNotifier::new(&invalid, reqwest::Client::new(), None).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Notifier which posts a message for each state change to a Slack
+//! incoming webhook.
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    template::{self, Template},
+    Notifier, StateChange,
+};
+use crate::Result;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SlackConfig {
+    /// URL of the incoming webhook, such as
+    /// `https://hooks.slack.com/services/...`.
+    pub url: String,
+    /// Name of the notifier, used in the `notifier` label of the
+    /// notification metrics.
+    #[serde(default = "SlackConfig::default_name")]
+    pub name: String,
+    /// Template of the message, see [`template`](super::template), instead
+    /// of the default message.
+    pub template: Option<String>,
+    /// Groups of targets which messages are posted for, or all targets when
+    /// empty.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl SlackConfig {
+    fn default_name() -> String {
+        "slack".to_string()
+    }
+}
+
+pub struct SlackNotifier {
+    name: String,
+    url: String,
+    template: Option<Template>,
+    client: reqwest::Client,
+}
+
+impl SlackNotifier {
+    pub fn new(
+        config: &SlackConfig,
+        client: reqwest::Client,
+        status_page_url: Option<&str>,
+    ) -> Result<Self> {
+        Ok(Self {
+            name: config.name.clone(),
+            url: config.url.clone(),
+            template: config
+                .template
+                .as_deref()
+                .map(|source| Template::new(source, status_page_url))
+                .transpose()?,
+            client,
+        })
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, change: &StateChange) -> Result<()> {
+        self.client
+            .post(&self.url)
+            .json(&json!({ "text": template::message(self.template.as_ref(), change) }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
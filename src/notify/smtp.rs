@@ -0,0 +1,222 @@
+//! Notifier which sends an email for each state change through an SMTP
+//! server, for deployments without any chat integration.
+
+use std::time::Duration;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+
+use super::{
+    template::{self, Template},
+    Notifier, StateChange,
+};
+use crate::Result;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpConfig {
+    /// Hostname of the SMTP server, which its certificate is verified
+    /// against.
+    pub host: String,
+    /// Port of the SMTP server, which defaults to that of the `tls` mode.
+    pub port: Option<u16>,
+    /// Encryption of the connection to the server.
+    #[serde(default)]
+    pub tls: SmtpTls,
+    /// Username to authenticate with, along with `password`.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Address which emails are sent from, such as
+    /// `uppies <uppies@example.com>`.
+    pub from: String,
+    /// Addresses which emails are sent to.
+    pub to: Vec<String>,
+    /// Template of the subject, see [`template`](super::template), instead
+    /// of the default message.
+    pub subject_template: Option<String>,
+    /// Template of the plain text body, instead of the default message.
+    pub template: Option<String>,
+    /// Name of the notifier, used in the `notifier` label of the
+    /// notification metrics.
+    #[serde(default = "SmtpConfig::default_name")]
+    pub name: String,
+    /// Groups of targets which emails are sent for, or all targets when
+    /// empty.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl SmtpConfig {
+    fn default_name() -> String {
+        "smtp".to_string()
+    }
+}
+
+/// Encryption of the connection to an SMTP server.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Connect with TLS from the start, on port 465 by default.
+    Tls,
+    /// Upgrade the connection with STARTTLS, failing if the server doesn't
+    /// support it, on port 587 by default.
+    #[default]
+    Starttls,
+    /// Send emails unencrypted, on port 25 by default. Only suitable for a
+    /// relay on the same host or network.
+    None,
+}
+
+pub struct SmtpNotifier {
+    name: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    subject_template: Option<Template>,
+    template: Option<Template>,
+}
+
+impl SmtpNotifier {
+    pub fn new(
+        config: &SmtpConfig,
+        timeout: Duration,
+        status_page_url: Option<&str>,
+    ) -> Result<Self> {
+        let mut transport = match config.tls {
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+            SmtpTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+        }
+        .timeout(Some(timeout));
+        if let Some(port) = config.port {
+            transport = transport.port(port);
+        }
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                transport =
+                    transport.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+            (None, None) => {}
+            _ => {
+                return Err("smtp notifier requires both 'username' and 'password'"
+                    .to_string()
+                    .into())
+            }
+        }
+        if config.to.is_empty() {
+            return Err("smtp notifier requires at least one address in 'to'"
+                .to_string()
+                .into());
+        }
+        let mailbox = |address: &str| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|e| format!("invalid email address '{address}': {e}"))
+        };
+        let template = |source: &Option<String>| {
+            source
+                .as_deref()
+                .map(|source| Template::new(source, status_page_url))
+                .transpose()
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            transport: transport.build(),
+            from: mailbox(&config.from)?,
+            to: config
+                .to
+                .iter()
+                .map(|address| mailbox(address))
+                .collect::<std::result::Result<_, _>>()?,
+            subject_template: template(&config.subject_template)?,
+            template: template(&config.template)?,
+        })
+    }
+
+    fn email(&self, change: &StateChange) -> Result<Message> {
+        let subject = template::message(self.subject_template.as_ref(), change);
+        let mut email = Message::builder()
+            .from(self.from.clone())
+            // Templates may span multiple lines, which subjects cannot.
+            .subject(subject.lines().next().unwrap_or_default())
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            email = email.to(to.clone());
+        }
+        Ok(email.body(template::message(self.template.as_ref(), change))?)
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, change: &StateChange) -> Result<()> {
+        self.transport.send(self.email(change)?).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{SmtpConfig, SmtpNotifier, SmtpTls};
+    use crate::{notify::StateChange, state::State};
+
+    fn config() -> SmtpConfig {
+        SmtpConfig {
+            host: "localhost".to_string(),
+            port: Some(2525),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "uppies <uppies@example.com>".to_string(),
+            to: vec!["ops@example.com".to_string()],
+            subject_template: Some("[uppies] {{ target }} is {{ state }}".to_string()),
+            template: None,
+            name: SmtpConfig::default_name(),
+            groups: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn format_email() {
+        let notifier = SmtpNotifier::new(&config(), Duration::from_secs(1), None).unwrap();
+        let change = StateChange {
+            target: "1.1.1.1".to_string(),
+            probe: "icmp".to_string(),
+            state: State::Down,
+            timestamp_ms: 0,
+            down_for_ms: None,
+            flapping: false,
+            rtt: None,
+        };
+        let email = String::from_utf8(notifier.email(&change).unwrap().formatted()).unwrap();
+        assert!(email.contains("Subject: [uppies] 1.1.1.1 is down\r\n"));
+        assert!(email.contains("To: ops@example.com\r\n"));
+        assert!(email.contains("1.1.1.1 is down"));
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        let timeout = Duration::from_secs(1);
+        let no_password = SmtpConfig {
+            username: Some("uppies".to_string()),
+            ..config()
+        };
+        assert!(SmtpNotifier::new(&no_password, timeout, None).is_err());
+        let invalid_to = SmtpConfig {
+            to: vec!["not an address".to_string()],
+            ..config()
+        };
+        assert!(SmtpNotifier::new(&invalid_to, timeout, None).is_err());
+    }
+}
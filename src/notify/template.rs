@@ -0,0 +1,108 @@
+//! Custom notification messages, written in the Jinja syntax of
+//! [minijinja](https://docs.rs/minijinja).
+//!
+//! Templates can use every field of the [`StateChange`], such as `target`,
+//! `state`, `down_for_ms` and `rtt.avg_ms`, along with:
+//!
+//! - `message`, the default message of the notifier.
+//! - `down_for`, the downtime for people to read, such as `2m 5s`.
+//! - `status_page_url`, the `status_page_url` of the configuration, if set.
+//!
+//! For example, `{{ target }} is {{ state }}{% if down_for %} after
+//! {{ down_for }}{% endif %}, see {{ status_page_url }}`.
+
+use std::time::Duration;
+
+use minijinja::{context, value::Serde, Environment};
+use tracing::error;
+
+use super::{format_duration, StateChange};
+use crate::Result;
+
+pub struct Template {
+    env: Environment<'static>,
+}
+
+impl Template {
+    const NAME: &str = "message";
+
+    /// Compile the template `source`, failing if it isn't valid.
+    pub fn new(source: &str, status_page_url: Option<&str>) -> Result<Self> {
+        let mut env = Environment::new();
+        env.add_template_owned(Self::NAME, source.to_string())
+            .map_err(|e| format!("invalid notification template: {e}"))?;
+        env.add_global("status_page_url", status_page_url);
+        Ok(Self { env })
+    }
+
+    pub fn render(&self, change: &StateChange) -> Result<String> {
+        let down_for = change
+            .down_for_ms
+            .map(|ms| format_duration(Duration::from_millis(ms)));
+        let template = self.env.get_template(Self::NAME)?;
+        Ok(template.render(context! {
+            message => change.message(),
+            down_for,
+            ..Serde(change)
+        })?)
+    }
+}
+
+/// Message of the `change`, from the `template` if any, otherwise the
+/// default message.
+///
+/// A template which fails to render, such as by using an unknown filter,
+/// falls back to the default message rather than losing the notification.
+pub(super) fn message(template: Option<&Template>, change: &StateChange) -> String {
+    let Some(template) = template else {
+        return change.message();
+    };
+    template.render(change).unwrap_or_else(|e| {
+        error!(?e, "failed to render notification template");
+        change.message()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::Template;
+    use crate::{
+        notify::{RttStats, StateChange},
+        state::State,
+    };
+
+    #[test]
+    fn render() {
+        let change = StateChange {
+            target: "1.1.1.1".to_string(),
+            probe: "icmp".to_string(),
+            state: State::Up,
+            timestamp_ms: 0,
+            down_for_ms: Some(125_000),
+            flapping: false,
+            rtt: Some(RttStats {
+                last_ms: 2.0,
+                min_ms: 1.0,
+                avg_ms: 1.5,
+                max_ms: 2.0,
+            }),
+        };
+        let template = Template::new(
+            "{{ target }} is {{ state }}{% if down_for %} after {{ down_for }}{% endif %} \
+             (avg {{ rtt.avg_ms }}ms), see {{ status_page_url }}",
+            Some("https://status.example.com"),
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&change).unwrap(),
+            "1.1.1.1 is up after 2m 5s (avg 1.5ms), see https://status.example.com"
+        );
+
+        let template = Template::new("[{{ message }}]", None).unwrap();
+        assert_eq!(
+            template.render(&change).unwrap(),
+            format!("[{}]", change.message())
+        );
+        assert!(Template::new("{{ target", None).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Notifier which posts each state change as JSON to an HTTP endpoint.
+
+use std::collections::BTreeMap;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde::Deserialize;
+use tracing::error;
+
+use super::{template::Template, Notifier, StateChange};
+use crate::Result;
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// URL which state changes are posted to.
+    pub url: String,
+    /// Name of the webhook, used in the `notifier` label of the
+    /// notification metrics.
+    #[serde(default = "WebhookConfig::default_name")]
+    pub name: String,
+    /// Additional headers of each request, such as for authentication.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Template of the body of each request, see
+    /// [`template`](super::template), instead of the [`StateChange`] as
+    /// JSON.
+    pub template: Option<String>,
+    /// Groups of targets which changes are posted for, or all targets when
+    /// empty.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl WebhookConfig {
+    fn default_name() -> String {
+        "webhook".to_string()
+    }
+}
+
+/// Posts the [`StateChange`] as the body of each request.
+pub struct WebhookNotifier {
+    name: String,
+    url: String,
+    headers: HeaderMap,
+    template: Option<Template>,
+    client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+    pub fn new(
+        config: &WebhookConfig,
+        client: reqwest::Client,
+        status_page_url: Option<&str>,
+    ) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("invalid webhook header '{name}': {e}"))?,
+                HeaderValue::from_str(value)
+                    .map_err(|e| format!("invalid value of webhook header '{name}': {e}"))?,
+            );
+        }
+        Ok(Self {
+            name: config.name.clone(),
+            url: config.url.clone(),
+            headers,
+            template: config
+                .template
+                .as_deref()
+                .map(|source| Template::new(source, status_page_url))
+                .transpose()?,
+            client,
+        })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, change: &StateChange) -> Result<()> {
+        // A template which fails to render falls back to the default payload,
+        // rather than losing the notification.
+        let body = self.template.as_ref().and_then(|template| {
+            template
+                .render(change)
+                .inspect_err(|e| error!(?e, "failed to render webhook template"))
+                .ok()
+        });
+        let request = match body {
+            Some(body) => self
+                .client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body),
+            None => self.client.post(&self.url).json(change),
+        };
+        // Configured headers replace the defaults, such as the content type.
+        request
+            .headers(self.headers.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use prometheus::Registry;
+    use tokio::net::TcpListener;
+
+    use super::WebhookConfig;
+    use crate::{
+        destination::Destinations,
+        notify::{Notifications, NotifyConfig, StateChange},
+        state::State as TargetState,
+    };
+
+    #[tokio::test]
+    async fn retry_failed_deliveries() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(received): State<Arc<Mutex<Vec<StateChange>>>>,
+                     Json(change): Json<StateChange>| async move {
+                        let mut received = received.lock().unwrap();
+                        received.push(change);
+                        // Reject the first attempt, so that it is retried.
+                        if received.len() == 1 {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        } else {
+                            StatusCode::OK
+                        }
+                    },
+                ),
+            )
+            .with_state(Arc::clone(&received));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = NotifyConfig {
+            webhooks: vec![WebhookConfig {
+                url: format!("http://{addr}/hook"),
+                name: WebhookConfig::default_name(),
+                headers: BTreeMap::from([("x-token".to_string(), "secret".to_string())]),
+                template: None,
+                groups: Vec::new(),
+            }],
+            max_retries: 1,
+            initial_backoff_ms: 10,
+            timeout_ms: 1000,
+            ..Default::default()
+        };
+        let metrics = Registry::new();
+        let destinations = Destinations::new(&metrics).unwrap();
+        let notifications = Notifications::new(&config, &metrics, &destinations).unwrap();
+        let change = StateChange {
+            target: "127.0.0.1".to_string(),
+            probe: "icmp".to_string(),
+            state: TargetState::Down,
+            timestamp_ms: 1000,
+            down_for_ms: None,
+            flapping: false,
+            rtt: None,
+        };
+        notifications.notify(change.clone());
+
+        let sent = || {
+            metrics
+                .gather()
+                .into_iter()
+                .find(|m| m.name() == "notifications_sent_total")
+                .map(|m| m.get_metric()[0].get_counter().value())
+        };
+        for _ in 0..100 {
+            if sent() == Some(1.0) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(sent(), Some(1.0));
+        assert_eq!(*received.lock().unwrap(), [change.clone(), change]);
+
+        // The failed first attempt was followed by a success.
+        let health = &destinations.report()[0];
+        assert_eq!(
+            (health.kind.as_str(), health.name.as_str()),
+            ("notifier", "webhook")
+        );
+        assert!(health.healthy);
+        assert!(health.last_success_ms.is_some());
+        assert!(health.last_error.is_some());
+    }
+}
@@ -0,0 +1,471 @@
+//! Encoding of metrics in the OpenMetrics text format, which unlike the
+//! classic text format carries the exemplars of histograms, so that a spike
+//! of round-trip times in a dashboard links back to the exact probe.
+//!
+//! The `prometheus` crate has no notion of exemplars, so the latest
+//! observation of each bucket is kept within [`Exemplars`] alongside the
+//! histogram, and joined with the gathered buckets as they are encoded.
+//!
+//! `/metrics` negotiates between this format, the classic text format and
+//! the delimited protobuf format with [`MetricsFormat`], by the `Accept`
+//! header of each scrape.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{
+    proto::{LabelPair, Metric, MetricFamily, MetricType},
+    Encoder, ProtobufEncoder, TextEncoder,
+};
+
+/// Content type of the OpenMetrics text format.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Format which metrics are served in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MetricsFormat {
+    /// The classic text format of Prometheus, which has no exemplars.
+    #[default]
+    Text,
+    /// The OpenMetrics text format, with exemplars.
+    OpenMetrics,
+    /// Length delimited `MetricFamily` messages of the protobuf format of
+    /// Prometheus, which are without exemplars as the `prometheus` crate
+    /// lacks their fields.
+    Protobuf,
+}
+
+impl MetricsFormat {
+    /// Preferred format of an `Accept` header, which is the accepted format
+    /// with the greatest quality, or the earliest of those which are equal.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let Some(accept) = accept else {
+            return Self::default();
+        };
+        let mut preferred = None;
+        for value in accept.split(',') {
+            let mut params = value.split(';').map(str::trim);
+            let format = match params.next().unwrap_or_default() {
+                "text/plain" => Self::Text,
+                "application/openmetrics-text" => Self::OpenMetrics,
+                "application/vnd.google.protobuf" => Self::Protobuf,
+                "*/*" => Self::Text,
+                _ => continue,
+            };
+            let (mut quality, mut delimited) = (1.0, true);
+            for param in params {
+                match param.split_once('=') {
+                    Some(("q", q)) => quality = q.parse().unwrap_or(0.0),
+                    Some(("proto", proto)) => {
+                        delimited &= proto == "io.prometheus.client.MetricFamily"
+                    }
+                    Some(("encoding", encoding)) => delimited &= encoding == "delimited",
+                    _ => {}
+                }
+            }
+            if quality <= 0.0 || (format == Self::Protobuf && !delimited) {
+                continue;
+            }
+            if preferred.is_none_or(|(_, q)| quality > q) {
+                preferred = Some((format, quality));
+            }
+        }
+        preferred.map(|(format, _)| format).unwrap_or_default()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Text => prometheus::TEXT_FORMAT,
+            Self::OpenMetrics => CONTENT_TYPE,
+            Self::Protobuf => prometheus::PROTOBUF_FORMAT,
+        }
+    }
+
+    /// Encode `families` in this format, with `exemplars` if it has them.
+    pub fn encode(&self, families: &[MetricFamily], exemplars: &Exemplars) -> Vec<u8> {
+        match self {
+            Self::Text => TextEncoder::new()
+                .encode_to_string(families)
+                .expect("can encode known metrics")
+                .into_bytes(),
+            Self::OpenMetrics => encode(families, exemplars).into_bytes(),
+            Self::Protobuf => {
+                let mut buf = Vec::new();
+                ProtobufEncoder::new()
+                    .encode(families, &mut buf)
+                    .expect("can encode known metrics");
+                buf
+            }
+        }
+    }
+}
+
+/// An observation of a histogram, which identifies the probe it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    /// Value which was observed.
+    pub value: f64,
+    /// Sequence number of the ping which was observed.
+    pub sequence: u64,
+    /// Time at which the ping completed.
+    pub timestamp: SystemTime,
+}
+
+/// Upper bound of each bucket which was observed with its exemplar, by the
+/// name of the histogram and the `target` and `probe` labels of its series.
+type BucketExemplars = HashMap<(String, String, String), Vec<(f64, Exemplar)>>;
+
+/// Latest exemplar of each bucket of histograms, by the name of the
+/// histogram and its `target` and `probe` labels.
+///
+/// Clones share the same underlying exemplars.
+#[derive(Clone, Default)]
+pub struct Exemplars {
+    exemplars: Arc<Mutex<BucketExemplars>>,
+}
+
+impl Exemplars {
+    /// Record `exemplar` as the latest of the bucket of `buckets` which it
+    /// falls within.
+    pub fn observe(
+        &self,
+        histogram: &str,
+        target: &str,
+        probe: &str,
+        buckets: &[f64],
+        exemplar: Exemplar,
+    ) {
+        let upper_bound = buckets
+            .iter()
+            .copied()
+            .find(|b| exemplar.value <= *b)
+            .unwrap_or(f64::INFINITY);
+        let mut exemplars = self.exemplars.lock().expect("exemplars lock poisoned");
+        let latest = exemplars
+            .entry((histogram.to_string(), target.to_string(), probe.to_string()))
+            .or_default();
+        match latest.iter_mut().find(|(b, _)| *b == upper_bound) {
+            Some((_, latest)) => *latest = exemplar,
+            None => latest.push((upper_bound, exemplar)),
+        }
+    }
+
+    /// Latest exemplar of the bucket of `histogram` with the upper bound.
+    pub fn get(
+        &self,
+        histogram: &str,
+        target: &str,
+        probe: &str,
+        upper_bound: f64,
+    ) -> Option<Exemplar> {
+        let exemplars = self.exemplars.lock().expect("exemplars lock poisoned");
+        let series = (histogram.to_string(), target.to_string(), probe.to_string());
+        exemplars
+            .get(&series)?
+            .iter()
+            .find(|(b, _)| *b == upper_bound)
+            .map(|(_, exemplar)| exemplar.clone())
+    }
+
+    /// Forget the exemplars of a probe of a target which is no longer
+    /// pinged.
+    pub fn remove(&self, target: &str, probe: &str) {
+        let mut exemplars = self.exemplars.lock().expect("exemplars lock poisoned");
+        exemplars.retain(|(_, t, p), _| t != target || p != probe);
+    }
+}
+
+/// Encode `families` in the OpenMetrics text format, with the exemplars of
+/// their histogram buckets.
+///
+/// Counters are only typed as such when their name ends with `_total`, as
+/// the format requires, so that the names of other series are unchanged
+/// from the classic text format.
+pub fn encode(families: &[MetricFamily], exemplars: &Exemplars) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = family.name();
+        let (family_name, kind) = match family.get_field_type() {
+            MetricType::COUNTER => match name.strip_suffix("_total") {
+                Some(stripped) => (stripped, "counter"),
+                None => (name, "unknown"),
+            },
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(out, "# TYPE {family_name} {kind}");
+        if !family.help().is_empty() {
+            let _ = writeln!(out, "# HELP {family_name} {}", escape(family.help()));
+        }
+        for metric in family.get_metric() {
+            match family.get_field_type() {
+                MetricType::COUNTER => write_sample(
+                    &mut out,
+                    name,
+                    metric,
+                    "",
+                    None,
+                    metric.get_counter().value(),
+                ),
+                MetricType::GAUGE => {
+                    write_sample(&mut out, name, metric, "", None, metric.get_gauge().value())
+                }
+                MetricType::UNTYPED => {
+                    write_sample(&mut out, name, metric, "", None, metric.untyped.value())
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let label = |name| {
+                        metric
+                            .get_label()
+                            .iter()
+                            .find(|l| l.name() == name)
+                            .map(|l| l.value())
+                    };
+                    let (target, probe) = (label("target"), label("probe").unwrap_or_default());
+                    let mut buckets: Vec<_> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|b| (b.upper_bound(), b.cumulative_count()))
+                        .collect();
+                    if buckets.last().is_none_or(|(b, _)| *b != f64::INFINITY) {
+                        buckets.push((f64::INFINITY, histogram.get_sample_count()));
+                    }
+                    for (upper_bound, count) in buckets {
+                        let le = number(upper_bound);
+                        write_sample(
+                            &mut out,
+                            name,
+                            metric,
+                            "_bucket",
+                            Some(("le", &le)),
+                            count as f64,
+                        );
+                        let exemplar =
+                            target.and_then(|t| exemplars.get(name, t, probe, upper_bound));
+                        if let Some(exemplar) = exemplar {
+                            write_exemplar(&mut out, &exemplar);
+                        }
+                        out.push('\n');
+                    }
+                    write_sample(
+                        &mut out,
+                        name,
+                        metric,
+                        "_sum",
+                        None,
+                        histogram.get_sample_sum(),
+                    );
+                    out.push('\n');
+                    write_sample(
+                        &mut out,
+                        name,
+                        metric,
+                        "_count",
+                        None,
+                        histogram.get_sample_count() as f64,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = number(quantile.quantile());
+                        write_sample(
+                            &mut out,
+                            name,
+                            metric,
+                            "",
+                            Some(("quantile", &q)),
+                            quantile.value(),
+                        );
+                        out.push('\n');
+                    }
+                    write_sample(&mut out, name, metric, "_sum", None, summary.sample_sum());
+                    out.push('\n');
+                    write_sample(
+                        &mut out,
+                        name,
+                        metric,
+                        "_count",
+                        None,
+                        summary.sample_count() as f64,
+                    );
+                }
+            }
+            out.push('\n');
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Write a sample without its newline, so that an exemplar can follow it.
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    metric: &Metric,
+    suffix: &str,
+    extra: Option<(&str, &str)>,
+    value: f64,
+) {
+    out.push_str(name);
+    out.push_str(suffix);
+    write_labels(out, metric.get_label(), extra);
+    let _ = write!(out, " {}", number(value));
+    if metric.timestamp_ms() != 0 {
+        let _ = write!(out, " {}", metric.timestamp_ms() as f64 / 1000.0);
+    }
+}
+
+fn write_labels(out: &mut String, labels: &[LabelPair], extra: Option<(&str, &str)>) {
+    let mut labels = labels
+        .iter()
+        .map(|l| (l.name(), l.value()))
+        .chain(extra)
+        .peekable();
+    if labels.peek().is_none() {
+        return;
+    }
+    out.push('{');
+    for (i, (name, value)) in labels.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{name}=\"{}\"", escape(value));
+    }
+    out.push('}');
+}
+
+fn write_exemplar(out: &mut String, exemplar: &Exemplar) {
+    let timestamp = exemplar
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let _ = write!(
+        out,
+        " # {{sequence=\"{}\"}} {} {timestamp:.3}",
+        exemplar.sequence,
+        number(exemplar.value)
+    );
+}
+
+/// A number as written by the format, which spells out infinities.
+fn number(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('\n', r"\n")
+        .replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use prometheus::{
+        Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+    };
+
+    use super::{encode, Exemplar, Exemplars, MetricsFormat};
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(MetricsFormat::negotiate(None), MetricsFormat::Text);
+        for (accept, expected) in [
+            ("text/plain; version=0.0.4", MetricsFormat::Text),
+            (
+                "application/openmetrics-text;version=1.0.0,application/openmetrics-text;\
+                 version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1",
+                MetricsFormat::OpenMetrics,
+            ),
+            (
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;\
+                 encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.2",
+                MetricsFormat::Protobuf,
+            ),
+            (
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;\
+                 encoding=text,application/openmetrics-text;q=0.5",
+                MetricsFormat::OpenMetrics,
+            ),
+            (
+                "application/openmetrics-text;q=0, text/html",
+                MetricsFormat::Text,
+            ),
+            ("application/json", MetricsFormat::Text),
+        ] {
+            assert_eq!(MetricsFormat::negotiate(Some(accept)), expected, "{accept}");
+        }
+    }
+
+    #[test]
+    fn openmetrics() {
+        let registry = Registry::new();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("ping_duration_ms", "Round-trip times").buckets(vec![1.0, 10.0]),
+            &["target", "probe"],
+        )
+        .unwrap();
+        let counter =
+            IntCounterVec::new(Opts::new("ping_success_count", "help"), &["target"]).unwrap();
+        let total = IntCounter::new("probes_throttled_total", "Throttled \"probes\"").unwrap();
+        let gauge = Gauge::new("targets", "help").unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(total.clone())).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+
+        let exemplars = Exemplars::default();
+        let buckets = [1.0, 10.0];
+        for (sequence, value) in [(0, 5.0), (1, 7.0), (2, 50.0)] {
+            histogram.with_label_values(&["a", "icmp"]).observe(value);
+            let timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + sequence);
+            let exemplar = Exemplar {
+                value,
+                sequence,
+                timestamp,
+            };
+            exemplars.observe("ping_duration_ms", "a", "icmp", &buckets, exemplar);
+        }
+        counter.with_label_values(&["a"]).inc();
+        total.inc();
+        gauge.set(2.5);
+
+        let encoded = encode(&registry.gather(), &exemplars);
+        for line in [
+            "# TYPE ping_duration_ms histogram",
+            "ping_duration_ms_bucket{probe=\"icmp\",target=\"a\",le=\"1\"} 0",
+            "ping_duration_ms_bucket{probe=\"icmp\",target=\"a\",le=\"10\"} 2 # {sequence=\"1\"} 7 1700000000.001",
+            "ping_duration_ms_bucket{probe=\"icmp\",target=\"a\",le=\"+Inf\"} 3 # {sequence=\"2\"} 50 1700000000.002",
+            "ping_duration_ms_sum{probe=\"icmp\",target=\"a\"} 62",
+            "ping_duration_ms_count{probe=\"icmp\",target=\"a\"} 3",
+            "# TYPE ping_success_count unknown",
+            "ping_success_count{target=\"a\"} 1",
+            "# TYPE probes_throttled counter",
+            "# HELP probes_throttled Throttled \\\"probes\\\"",
+            "probes_throttled_total 1",
+            "# TYPE targets gauge",
+            "targets 2.5",
+        ] {
+            assert!(encoded.lines().any(|l| l == line), "{line} in {encoded}");
+        }
+        assert!(encoded.ends_with("# EOF\n"));
+
+        exemplars.remove("a", "icmp");
+        assert_eq!(exemplars.get("ping_duration_ms", "a", "icmp", 10.0), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Pausing of individual targets at runtime, such as while a device is
+//! under maintenance.
+//!
+//! Paused targets are not probed at all, so they report neither successes
+//! nor failures until resumed.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{metrics::IntGauge, Result};
+
+/// Whether a target is paused, as served at [`Pauses::PATH`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetStatus {
+    pub target: String,
+    pub paused: bool,
+}
+
+/// Handle to pause and resume the targets of a
+/// [`PingSender`](crate::PingSender).
+///
+/// Clones share the same underlying state.
+#[derive(Clone, Default)]
+pub struct Pauses {
+    targets: Arc<RwLock<BTreeMap<String, Arc<AtomicBool>>>>,
+    /// Number of targets which are paused, when metrics are enabled.
+    paused: Option<IntGauge>,
+}
+
+impl Pauses {
+    /// Path which the status of all targets is served at, with each target
+    /// paused by a `POST` to `{PATH}/{target}/pause` and resumed by one to
+    /// `{PATH}/{target}/resume`.
+    pub const PATH: &str = "/targets";
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn with_gauge(mut self, paused: IntGauge) -> Self {
+        self.paused = Some(paused);
+        self
+    }
+
+    /// Flag which the dispatcher of `target` checks before each probe.
+    pub(crate) fn register(&self, target: &str) -> Arc<AtomicBool> {
+        let mut targets = self.targets.write().expect("pause lock poisoned");
+        Arc::clone(targets.entry(target.to_string()).or_default())
+    }
+
+    /// Forget `target` once it is no longer pinged.
+    pub(crate) fn unregister(&self, target: &str) {
+        let mut targets = self.targets.write().expect("pause lock poisoned");
+        let was_paused = targets
+            .remove(target)
+            .is_some_and(|flag| flag.load(Ordering::Relaxed));
+        if let (true, Some(gauge)) = (was_paused, &self.paused) {
+            gauge.dec();
+        }
+    }
+
+    /// Pause or resume `target`, returning `false` if it isn't pinged.
+    pub fn set_paused(&self, target: &str, paused: bool) -> bool {
+        let targets = self.targets.read().expect("pause lock poisoned");
+        let Some(flag) = targets.get(target) else {
+            return false;
+        };
+        let was_paused = flag.swap(paused, Ordering::Relaxed);
+        if let Some(gauge) = &self.paused {
+            match (was_paused, paused) {
+                (false, true) => gauge.inc(),
+                (true, false) => gauge.dec(),
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Status of all targets, ordered by target.
+    pub fn statuses(&self) -> Vec<TargetStatus> {
+        let targets = self.targets.read().expect("pause lock poisoned");
+        targets
+            .iter()
+            .map(|(target, paused)| TargetStatus {
+                target: target.clone(),
+                paused: paused.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Fetch the status of all targets of the instance at `base_url`.
+pub async fn fetch(client: &reqwest::Client, base_url: &str) -> Result<Vec<TargetStatus>> {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), Pauses::PATH);
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Pause or resume `target` of the instance at `base_url`.
+pub async fn request(
+    client: &reqwest::Client,
+    base_url: &str,
+    target: &str,
+    paused: bool,
+) -> Result<()> {
+    let invalid = |e| format!("invalid base url '{base_url}': {e}");
+    let mut url = reqwest::Url::parse(base_url)
+        .and_then(|url| url.join(Pauses::PATH))
+        .map_err(invalid)?;
+    url.path_segments_mut()
+        .map_err(|_| format!("invalid base url '{base_url}'"))?
+        .push(target)
+        .push(if paused { "pause" } else { "resume" });
+    client.post(url).send().await?.error_for_status()?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use prometheus::IntGauge;
+
+    use super::{Pauses, TargetStatus};
+
+    #[test]
+    fn pause_and_resume() {
+        let gauge = IntGauge::new("paused", "paused").unwrap();
+        let pauses = Pauses::default().with_gauge(gauge.clone());
+        let flag = pauses.register("127.0.0.1");
+
+        assert!(pauses.set_paused("127.0.0.1", true));
+        assert!(pauses.set_paused("127.0.0.1", true));
+        assert!(flag.load(Ordering::Relaxed));
+        assert_eq!(gauge.get(), 1, "pausing twice is counted once");
+        assert_eq!(
+            pauses.statuses(),
+            [TargetStatus {
+                target: "127.0.0.1".to_string(),
+                paused: true
+            }]
+        );
+
+        assert!(pauses.set_paused("127.0.0.1", false));
+        assert_eq!(gauge.get(), 0);
+        assert!(!pauses.set_paused("10.0.0.1", true), "unknown target");
+    }
+}
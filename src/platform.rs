@@ -0,0 +1,101 @@
+//! Capabilities of the platform uppies runs on, which are logged at startup
+//! and served at `/debug/capabilities`.
+//!
+//! ICMP pings are sent from the type of socket which suits each platform,
+//! see [`SocketType`], while probes which rely on Linux, such as those of
+//! `arp://` targets, are reported as unsupported elsewhere. A deployment
+//! beyond Linux can then be checked before its targets start failing.
+
+use serde::Serialize;
+
+use crate::probe::icmp::{SocketSupport, SocketType};
+
+/// Capabilities of this process on its platform.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// Operating system, such as `linux`, `macos` or `windows`.
+    pub os: &'static str,
+    /// Architecture of the CPU, such as `x86_64`.
+    pub arch: &'static str,
+    /// Type of ICMP socket which is preferred.
+    pub icmp_socket: SocketType,
+    /// ICMP socket which could be opened for IPv4 targets.
+    pub icmp_ipv4: SocketCapability,
+    /// ICMP socket which could be opened for IPv6 targets.
+    pub icmp_ipv6: SocketCapability,
+    /// Whether pings can be sent through an interface, as for targets such
+    /// as `fe80::1%eth0`.
+    pub interface_scoping: bool,
+    /// Whether `arp://` targets can be probed.
+    pub arp: bool,
+    /// Whether neighbor entries can be checked, which are read from
+    /// `/proc/net/arp`.
+    pub neighbors: bool,
+    /// Whether systemd is notified of readiness and its watchdog petted.
+    pub systemd: bool,
+}
+
+/// Type of ICMP socket which could be opened for an address family, or the
+/// error of opening either type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SocketCapability {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket: Option<SocketType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<&Result<SocketType, String>> for SocketCapability {
+    fn from(result: &Result<SocketType, String>) -> Self {
+        Self {
+            socket: result.as_ref().ok().copied(),
+            error: result.as_ref().err().cloned(),
+        }
+    }
+}
+
+impl Capabilities {
+    /// Path which the capabilities are served at.
+    pub const PATH: &str = "/debug/capabilities";
+
+    /// Capabilities of this platform, with the ICMP sockets of `sockets`
+    /// which were checked preferring `icmp_socket`.
+    pub fn new(icmp_socket: SocketType, sockets: &SocketSupport) -> Self {
+        let linux = cfg!(target_os = "linux");
+        Self {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            icmp_socket,
+            icmp_ipv4: (&sockets.ipv4).into(),
+            icmp_ipv6: (&sockets.ipv6).into(),
+            interface_scoping: linux,
+            arp: linux,
+            neighbors: linux,
+            systemd: linux,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Capabilities;
+    use crate::probe::icmp::{SocketSupport, SocketType};
+
+    #[test]
+    fn serialize() {
+        let sockets = SocketSupport {
+            ipv4: Ok(SocketType::Dgram),
+            ipv6: Err("Address family not supported by protocol".to_string()),
+        };
+        let capabilities = Capabilities::new(SocketType::Dgram, &sockets);
+        let json = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(json["os"], std::env::consts::OS);
+        assert_eq!(json["icmp_socket"], "dgram");
+        assert_eq!(json["icmp_ipv4"], serde_json::json!({"socket": "dgram"}));
+        assert_eq!(
+            json["icmp_ipv6"],
+            serde_json::json!({"error": "Address family not supported by protocol"})
+        );
+        assert_eq!(json["arp"], cfg!(target_os = "linux"));
+    }
+}
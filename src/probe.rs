@@ -0,0 +1,227 @@
+//! Probes check the reachability of a single target, such as by sending an
+//! ICMP echo request.
+//!
+//! [`PingSender`](crate::PingSender) schedules all probes at the ping
+//! interval and records their outcomes uniformly, so custom protocols or
+//! in-process checks can be added alongside the built-in [`IcmpProbe`].
+//! Targets given by hostname are resolved by a [`HostnameProbe`] first, see
+//! [`icmp`]. Targets with the `tls`, `grpc` and `ssh` schemes are probed by
+//! a [`TlsProbe`], [`GrpcProbe`] and [`SshProbe`] instead, and those with
+//! the `smtp` and `imap` schemes by a [`MailProbe`], `ntp` by an
+//! [`NtpProbe`] and `arp` by an [`ArpProbe`].
+
+use std::{future::Future, net::IpAddr, pin::Pin, sync::Arc, time::Duration};
+
+#[cfg(feature = "metrics")]
+use serde::Deserialize;
+use tokio::net::{lookup_host, TcpStream};
+
+#[cfg(feature = "metrics")]
+use self::{
+    arp::ArpConfig, grpc::GrpcConfig, mail::MailConfig, ntp::NtpConfig, ssh::SshConfig,
+    tls::TlsConfig,
+};
+#[cfg(feature = "metrics")]
+use crate::target::Scheme;
+use crate::{probe::icmp::IcmpConfig, target::ProbeTarget, ErrorKind, PingError, Result};
+
+pub mod arp;
+pub mod dns;
+#[cfg(feature = "metrics")]
+pub mod grpc;
+pub mod icmp;
+#[cfg(feature = "metrics")]
+pub mod mail;
+pub mod mock;
+pub mod neighbor;
+#[cfg(feature = "metrics")]
+pub mod ntp;
+pub mod ssh;
+#[cfg(feature = "metrics")]
+pub mod tls;
+
+pub use arp::ArpProbe;
+pub use dns::HostnameProbe;
+#[cfg(feature = "metrics")]
+pub use grpc::GrpcProbe;
+pub use icmp::IcmpProbe;
+#[cfg(feature = "metrics")]
+pub use mail::MailProbe;
+pub use mock::MockProbe;
+pub use neighbor::NeighborProbe;
+#[cfg(feature = "metrics")]
+pub use ntp::NtpProbe;
+pub use ssh::SshProbe;
+#[cfg(feature = "metrics")]
+pub use tls::TlsProbe;
+
+/// Outcome of a single probe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeOutcome {
+    /// Address which was probed, if any.
+    pub resolved_ip: Option<IpAddr>,
+    /// Reply to the probe at the network layer, for probes which have one
+    /// such as an ICMP echo reply.
+    pub reply: Option<Reply>,
+    /// Round-trip time of the probe, or the reason it failed.
+    pub rtt: std::result::Result<Duration, PingError>,
+}
+
+/// Details of the packet which replied to a probe, which tell apart replies
+/// from somewhere other than the target, such as an ICMP redirect or a NAT
+/// which rewrites replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    /// Address which the reply came from.
+    pub source: IpAddr,
+    /// Time to live of the reply as it was received, if known, which only
+    /// raw IPv4 sockets receive.
+    pub ttl: Option<u8>,
+    /// Whether the reply was an echo reply, rather than an error such as
+    /// destination unreachable which refers to the echo request.
+    pub echo: bool,
+}
+
+impl Reply {
+    /// Why the reply wasn't the one expected from `target`, if it wasn't.
+    pub fn unexpected(&self, target: IpAddr) -> Option<&'static str> {
+        if !self.echo {
+            Some("type")
+        } else if self.source != target {
+            Some("source")
+        } else {
+            None
+        }
+    }
+}
+
+/// Named bundle of a prober and its parameters, such as `icmp-fast` of ICMP
+/// with a short timeout, which targets are probed with instead of the
+/// configuration of their scheme when they refer to it, as in
+/// `1.1.1.1?module=icmp-fast`.
+///
+/// ```toml
+/// [modules.icmp-fast]
+/// prober = "icmp"
+/// timeout_ms = 500
+/// ```
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "prober", rename_all = "lowercase")]
+pub enum ModuleConfig {
+    Icmp(IcmpConfig),
+    Tls(TlsConfig),
+    Grpc(GrpcConfig),
+    Ssh(SshConfig),
+    Smtp(MailConfig),
+    Imap(MailConfig),
+    Ntp(NtpConfig),
+    Arp(ArpConfig),
+}
+
+#[cfg(feature = "metrics")]
+impl ModuleConfig {
+    /// Scheme of the targets which can refer to the module.
+    pub fn scheme(&self) -> Scheme {
+        match self {
+            Self::Icmp(_) => Scheme::Icmp,
+            Self::Tls(_) => Scheme::Tls,
+            Self::Grpc(_) => Scheme::Grpc,
+            Self::Ssh(_) => Scheme::Ssh,
+            Self::Smtp(_) => Scheme::Smtp,
+            Self::Imap(_) => Scheme::Imap,
+            Self::Ntp(_) => Scheme::Ntp,
+            Self::Arp(_) => Scheme::Arp,
+        }
+    }
+}
+
+/// A check of the reachability of a single target.
+pub trait Probe: Send + Sync + 'static {
+    /// Probe the target once.
+    fn probe(&self) -> impl Future<Output = ProbeOutcome> + Send;
+
+    /// Name which the outcomes and metrics of the probe are labelled by
+    /// when scheduled by [`PingSender::with_probe`](crate::PingSender::with_probe),
+    /// or `None` for the [`probe`](ProbeTarget::probe) of its target, as
+    /// of the built-in probes. Custom probes are named `custom` unless they
+    /// name themselves.
+    fn name(&self) -> Option<&str> {
+        Some("custom")
+    }
+}
+
+/// Probes can be shared, such as to inspect a [`MockProbe`] while it is
+/// scheduled.
+impl<P: Probe> Probe for Arc<P> {
+    fn probe(&self) -> impl Future<Output = ProbeOutcome> + Send {
+        P::probe(self)
+    }
+
+    fn name(&self) -> Option<&str> {
+        P::name(self)
+    }
+}
+
+/// Probe of any type, so that probes of different types can be built by the
+/// same function, such as for [`PingSender::with_probe_factory`](crate::PingSender::with_probe_factory).
+pub struct BoxProbe(Box<dyn DynProbe>);
+
+impl BoxProbe {
+    pub fn new(probe: impl Probe) -> Self {
+        Self(Box::new(probe))
+    }
+}
+
+impl Probe for BoxProbe {
+    fn probe(&self) -> impl Future<Output = ProbeOutcome> + Send {
+        self.0.boxed_probe()
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.0.probe_name()
+    }
+}
+
+/// Object safe form of [`Probe`], so that different probes can be
+/// scheduled together.
+pub(crate) trait DynProbe: Send + Sync {
+    fn boxed_probe(&self) -> Pin<Box<dyn Future<Output = ProbeOutcome> + Send + '_>>;
+
+    fn probe_name(&self) -> Option<&str>;
+}
+
+impl<P: Probe> DynProbe for P {
+    fn boxed_probe(&self) -> Pin<Box<dyn Future<Output = ProbeOutcome> + Send + '_>> {
+        Box::pin(self.probe())
+    }
+
+    fn probe_name(&self) -> Option<&str> {
+        self.name()
+    }
+}
+
+/// Connect to `port` of `host` over TCP, resolving it if it is a hostname,
+/// for the probes of TCP services.
+pub(crate) async fn connect(host: &str, port: u16) -> std::result::Result<TcpStream, PingError> {
+    let addrs: Vec<_> = lookup_host((host, port))
+        .await
+        .map_err(|e| PingError {
+            kind: ErrorKind::Resolution,
+            message: e.to_string(),
+        })?
+        .collect();
+    TcpStream::connect(&*addrs).await.map_err(|e| PingError {
+        kind: ErrorKind::Io,
+        message: e.to_string(),
+    })
+}
+
+/// Probe of `target` by ICMP, which is an [`IcmpProbe`] of an IP address, or
+/// of the addresses of a hostname through a [`HostnameProbe`].
+///
+/// The statistics of bursts aren't recorded, use [`IcmpProbes`](icmp::IcmpProbes)
+/// for that instead.
+pub fn icmp(target: &ProbeTarget, config: &IcmpConfig) -> Result<BoxProbe> {
+    icmp::build(target, config, None)
+}
@@ -0,0 +1,481 @@
+//! Probe of a target on the local segment by address resolution, which
+//! sends an ARP request to IPv4 targets, or a neighbor solicitation to IPv6
+//! targets, for hosts which firewall ICMP but must still answer ARP.
+//!
+//! The round-trip time of the probe is the time taken to resolve the MAC
+//! address of the target, without involving the kernel's neighbor table.
+//! Requests are sent from the interface of the on-link route to IPv4 targets
+//! in `/proc/net/route`, or the scope of IPv6 targets such as `fe80::1%eth0`,
+//! unless an interface is configured.
+//!
+//! Only Linux is supported, and raw sockets require `CAP_NET_RAW`.
+
+// The probe fails on other platforms, without using the packets it builds.
+#![cfg_attr(not(unix), allow(dead_code, unused_imports))]
+
+use std::{
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV6},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use socket2::{SockAddr, Socket};
+#[cfg(unix)]
+use tokio::io::{unix::AsyncFd, Interest};
+
+use super::{Probe, ProbeOutcome};
+use crate::{
+    target::{ProbeTarget, Scheme},
+    ErrorKind, PingError, Result, UppiesError,
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ArpConfig {
+    /// Interface to send requests from, instead of that of the route to
+    /// each target.
+    pub interface: Option<String>,
+    /// Length of time to receive the reply within.
+    #[serde(default = "ArpConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl ArpConfig {
+    fn default_timeout_ms() -> u64 {
+        1000
+    }
+}
+
+impl Default for ArpConfig {
+    fn default() -> Self {
+        Self {
+            interface: None,
+            timeout_ms: Self::default_timeout_ms(),
+        }
+    }
+}
+
+/// Builds the [`ArpProbe`] of each `arp` target.
+#[derive(Clone)]
+pub struct ArpProbes {
+    config: ArpConfig,
+    /// Routing table in the format of `/proc/net/route`.
+    routes: PathBuf,
+}
+
+impl ArpProbes {
+    pub fn new(config: &ArpConfig) -> Self {
+        Self {
+            config: config.clone(),
+            routes: PathBuf::from("/proc/net/route"),
+        }
+    }
+
+    /// Probe of the `arp` target `target`, which must be an IP address.
+    pub fn probe(&self, target: &ProbeTarget) -> Result<ArpProbe> {
+        if target.scheme() != Scheme::Arp {
+            return Err(UppiesError::InvalidTarget(format!(
+                "'{target}' is not an arp target"
+            )));
+        }
+        let ip = target.ip().ok_or_else(|| {
+            UppiesError::InvalidTarget(format!("arp targets must be IP addresses, not '{target}'"))
+        })?;
+        let interface = match (&self.config.interface, ip) {
+            (Some(interface), _) => interface.clone(),
+            (None, IpAddr::V4(ip)) => {
+                let routes = std::fs::read_to_string(&self.routes)
+                    .map_err(|e| format!("failed to read {}: {e}", self.routes.display()))?;
+                on_link_interface(&routes, ip).ok_or_else(|| {
+                    UppiesError::InvalidTarget(format!(
+                        "{ip} is not on the local segment of any interface"
+                    ))
+                })?
+            }
+            (None, IpAddr::V6(_)) => target
+                .scope()
+                .ok_or_else(|| {
+                    UppiesError::InvalidTarget(format!(
+                        "'{target}' requires a scope, such as fe80::1%eth0"
+                    ))
+                })?
+                .to_string(),
+        };
+        Ok(ArpProbe {
+            ip,
+            interface,
+            timeout: Duration::from_millis(self.config.timeout_ms),
+        })
+    }
+}
+
+/// Probe which resolves the MAC address of a target on the local segment.
+pub struct ArpProbe {
+    ip: IpAddr,
+    interface: String,
+    timeout: Duration,
+}
+
+/// EtherType of ARP.
+const ETH_P_ARP: u16 = 0x0806;
+
+impl ArpProbe {
+    /// Send a request and wait for the reply of the target, returning the
+    /// time taken since sending it.
+    #[cfg(unix)]
+    async fn resolve(&self) -> std::result::Result<Duration, PingError> {
+        let io = |e: io::Error| PingError {
+            kind: ErrorKind::Io,
+            message: e.to_string(),
+        };
+        let (index, mac) = link(&self.interface).await.map_err(|e| PingError {
+            kind: ErrorKind::Io,
+            message: format!("failed to read interface {}: {e}", self.interface),
+        })?;
+        let mut buf = [0u8; 1500];
+        match self.ip {
+            IpAddr::V4(ip) => {
+                let socket = packet_socket(index).map_err(io)?;
+                let source = source_v4(ip).map_err(io)?;
+                let sent = Instant::now();
+                send(&socket, &arp_request(mac, source, ip), None)
+                    .await
+                    .map_err(io)?;
+                loop {
+                    let len = recv(&socket, &mut buf).await.map_err(io)?;
+                    if is_arp_reply(&buf[..len], ip) {
+                        return Ok(sent.elapsed());
+                    }
+                }
+            }
+            IpAddr::V6(ip) => {
+                let socket = icmpv6_socket(&self.interface, index).map_err(io)?;
+                let to = SocketAddrV6::new(solicited_node(ip), 0, 0, index);
+                let sent = Instant::now();
+                send(&socket, &neighbor_solicitation(mac, ip), Some(&to.into()))
+                    .await
+                    .map_err(io)?;
+                loop {
+                    let len = recv(&socket, &mut buf).await.map_err(io)?;
+                    if is_advertisement(&buf[..len], ip) {
+                        return Ok(sent.elapsed());
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn resolve(&self) -> std::result::Result<Duration, PingError> {
+        Err(PingError {
+            kind: ErrorKind::Io,
+            message: "arp probes are only supported on Linux".to_string(),
+        })
+    }
+}
+
+impl Probe for ArpProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let rtt = match tokio::time::timeout(self.timeout, self.resolve()).await {
+            Ok(result) => result,
+            Err(_) => Err(PingError {
+                kind: ErrorKind::Timeout,
+                message: match self.ip {
+                    IpAddr::V4(_) => "no reply to ARP request".to_string(),
+                    IpAddr::V6(_) => "no reply to neighbor solicitation".to_string(),
+                },
+            }),
+        };
+        ProbeOutcome {
+            resolved_ip: Some(self.ip),
+            reply: None,
+            rtt,
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Interface of the longest on-link route to `ip` within `routes`, in the
+/// format of `/proc/net/route`.
+fn on_link_interface(routes: &str, ip: Ipv4Addr) -> Option<String> {
+    // Addresses are hexadecimal in the byte order of the host.
+    let address = |hex: &str| {
+        let n = u32::from_str_radix(hex, 16).ok()?;
+        Some(u32::from_be_bytes(n.to_le_bytes()))
+    };
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [interface, destination, gateway, _, _, _, _, mask, ..] = fields.as_slice() else {
+                return None;
+            };
+            let (destination, mask) = (address(destination)?, address(mask)?);
+            let on_link = address(gateway)? == 0 && u32::from(ip) & mask == destination;
+            on_link.then(|| (mask.count_ones(), interface.to_string()))
+        })
+        .max_by_key(|(prefix, _)| *prefix)
+        .map(|(_, interface)| interface)
+}
+
+/// Index and MAC address of `interface`.
+async fn link(interface: &str) -> io::Result<(u32, [u8; 6])> {
+    let dir = Path::new("/sys/class/net").join(interface);
+    let invalid =
+        |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {what}"));
+    let index = tokio::fs::read_to_string(dir.join("ifindex"))
+        .await?
+        .trim()
+        .parse()
+        .map_err(|_| invalid("index"))?;
+    let address = tokio::fs::read_to_string(dir.join("address")).await?;
+    let mut mac = [0u8; 6];
+    let mut octets = address.trim().split(':');
+    for octet in &mut mac {
+        *octet = octets
+            .next()
+            .and_then(|octet| u8::from_str_radix(octet, 16).ok())
+            .ok_or_else(|| invalid("MAC address"))?;
+    }
+    Ok((index, mac))
+}
+
+/// Address which the kernel would send to `ip` from, to request it from.
+fn source_v4(ip: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    // Connecting a UDP socket only chooses the route, without sending.
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((ip, 9))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(source) => Ok(source),
+        IpAddr::V6(_) => Err(io::Error::other("no IPv4 source address")),
+    }
+}
+
+/// Ethernet frame of an ARP request for `target`, broadcast from `mac`.
+fn arp_request(mac: [u8; 6], source: Ipv4Addr, target: Ipv4Addr) -> [u8; 42] {
+    let mut frame = [0u8; 42];
+    frame[..6].fill(0xff);
+    frame[6..12].copy_from_slice(&mac);
+    frame[12..14].copy_from_slice(&ETH_P_ARP.to_be_bytes());
+    // Request of an Ethernet address for an IPv4 address.
+    frame[14..22].copy_from_slice(&[0, 1, 8, 0, 6, 4, 0, 1]);
+    frame[22..28].copy_from_slice(&mac);
+    frame[28..32].copy_from_slice(&source.octets());
+    frame[38..42].copy_from_slice(&target.octets());
+    frame
+}
+
+/// Whether `frame` is an ARP reply from `target`.
+fn is_arp_reply(frame: &[u8], target: Ipv4Addr) -> bool {
+    frame.len() >= 42
+        && frame[12..14] == ETH_P_ARP.to_be_bytes()
+        && frame[20..22] == [0, 2]
+        && frame[28..32] == target.octets()
+}
+
+/// ICMPv6 neighbor solicitation for `target` from `mac`, whose checksum is
+/// filled in by the kernel.
+fn neighbor_solicitation(mac: [u8; 6], target: Ipv6Addr) -> [u8; 32] {
+    let mut packet = [0u8; 32];
+    packet[0] = 135;
+    packet[8..24].copy_from_slice(&target.octets());
+    // Source link-layer address option, of 8 bytes.
+    packet[24..26].copy_from_slice(&[1, 1]);
+    packet[26..32].copy_from_slice(&mac);
+    packet
+}
+
+/// Solicited-node multicast address which `target` listens on.
+fn solicited_node(target: Ipv6Addr) -> Ipv6Addr {
+    let [.., a, b, c] = target.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(a),
+        u16::from_be_bytes([b, c]),
+    )
+}
+
+/// Whether `packet` is an ICMPv6 neighbor advertisement of `target`.
+fn is_advertisement(packet: &[u8], target: Ipv6Addr) -> bool {
+    packet.len() >= 24 && packet[0] == 136 && packet[8..24] == target.octets()
+}
+
+#[cfg(unix)]
+async fn send(socket: &AsyncFd<Socket>, packet: &[u8], to: Option<&SockAddr>) -> io::Result<()> {
+    socket
+        .async_io(Interest::WRITABLE, |socket| match to {
+            Some(to) => socket.send_to(packet, to),
+            None => socket.send(packet),
+        })
+        .await
+        .map(drop)
+}
+
+#[cfg(unix)]
+async fn recv(socket: &AsyncFd<Socket>, buf: &mut [u8]) -> io::Result<usize> {
+    socket
+        .async_io(Interest::READABLE, |mut socket| socket.read(buf))
+        .await
+}
+
+/// Raw packet socket of ARP frames on the interface `index`.
+#[cfg(target_os = "linux")]
+fn packet_socket(index: u32) -> io::Result<AsyncFd<Socket>> {
+    use socket2::{Domain, Protocol, SockAddrStorage, Type};
+
+    let protocol = Protocol::from(i32::from(ETH_P_ARP.to_be()));
+    let socket = Socket::new(Domain::PACKET, Type::RAW, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    let link = libc::sockaddr_ll {
+        sll_family: libc::AF_PACKET as u16,
+        sll_protocol: ETH_P_ARP.to_be(),
+        sll_ifindex: index as i32,
+        sll_hatype: 0,
+        sll_pkttype: 0,
+        sll_halen: 0,
+        sll_addr: [0; 8],
+    };
+    let mut storage = SockAddrStorage::zeroed();
+    // SAFETY: `sockaddr_ll` is an address type of the platform, and the
+    // length is its size.
+    let addr = unsafe {
+        *storage.view_as::<libc::sockaddr_ll>() = link;
+        SockAddr::new(
+            storage,
+            size_of::<libc::sockaddr_ll>() as socket2::socklen_t,
+        )
+    };
+    socket.bind(&addr)?;
+    AsyncFd::new(socket)
+}
+
+/// Raw ICMPv6 socket on `interface`, whose index is `index`.
+#[cfg(target_os = "linux")]
+fn icmpv6_socket(interface: &str, index: u32) -> io::Result<AsyncFd<Socket>> {
+    use socket2::{Domain, Protocol, Type};
+
+    let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
+    socket.set_nonblocking(true)?;
+    socket.bind_device(Some(interface.as_bytes()))?;
+    socket.set_multicast_if_v6(index)?;
+    // Neighbor discovery messages are only accepted with a hop limit of 255,
+    // so that they can't have been forwarded.
+    socket.set_multicast_hops_v6(255)?;
+    socket.set_unicast_hops_v6(255)?;
+    AsyncFd::new(socket)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn packet_socket(_: u32) -> io::Result<AsyncFd<Socket>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "arp probes are only supported on Linux",
+    ))
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn icmpv6_socket(_: &str, _: u32) -> io::Result<AsyncFd<Socket>> {
+    packet_socket(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::{
+        arp_request, is_advertisement, is_arp_reply, neighbor_solicitation, on_link_interface,
+        solicited_node, ArpConfig, ArpProbes,
+    };
+
+    const ROUTES: &str = "\
+Iface	Destination	Gateway 	Flags	RefCnt	Use	Metric	Mask		MTU	Window	IRTT
+eth0	00000000	0101A8C0	0003	0	0	100	00000000	0	0	0
+eth0	0001A8C0	00000000	0001	0	0	100	00FFFFFF	0	0	0
+eth1	0000000A	00000000	0001	0	0	100	000000FF	0	0	0
+eth2	0001000A	00000000	0001	0	0	100	00FFFFFF	0	0	0
+";
+
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+
+    #[test]
+    fn interfaces() {
+        let interface = |ip: &str| on_link_interface(ROUTES, ip.parse().unwrap());
+        assert_eq!(interface("192.168.1.20").as_deref(), Some("eth0"));
+        assert_eq!(interface("10.9.9.9").as_deref(), Some("eth1"));
+        assert_eq!(
+            interface("10.0.1.5").as_deref(),
+            Some("eth2"),
+            "longest prefix"
+        );
+        assert_eq!(interface("1.1.1.1"), None, "only through a gateway");
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut probes = ArpProbes::new(&ArpConfig::default());
+        probes.routes = dir.path().join("route");
+        std::fs::write(&probes.routes, ROUTES).unwrap();
+        let probe = probes
+            .probe(&"arp://192.168.1.20".parse().unwrap())
+            .unwrap();
+        assert_eq!(probe.interface, "eth0");
+        let probe = probes
+            .probe(&"arp://fe80::1%eth3".parse().unwrap())
+            .unwrap();
+        assert_eq!(probe.interface, "eth3");
+        for target in [
+            "arp://1.1.1.1",
+            "arp://fe80::1",
+            "arp://router.lan",
+            "1.1.1.1",
+        ] {
+            assert!(probes.probe(&target.parse().unwrap()).is_err(), "{target}");
+        }
+    }
+
+    #[test]
+    fn arp() {
+        let target = Ipv4Addr::new(192, 168, 1, 1);
+        let request = arp_request(MAC, Ipv4Addr::new(192, 168, 1, 20), target);
+        assert_eq!(request[..6], [0xff; 6]);
+        assert_eq!(request[38..42], target.octets());
+        assert!(!is_arp_reply(&request, target), "requests aren't replies");
+
+        let mut reply = request;
+        reply[21] = 2;
+        reply[28..32].copy_from_slice(&target.octets());
+        assert!(is_arp_reply(&reply, target));
+        assert!(!is_arp_reply(&reply, Ipv4Addr::new(192, 168, 1, 2)));
+        assert!(!is_arp_reply(&reply[..41], target));
+    }
+
+    #[test]
+    fn neighbor_discovery() {
+        let target: Ipv6Addr = "fe80::1234:5678".parse().unwrap();
+        assert_eq!(
+            solicited_node(target),
+            "ff02::1:ff34:5678".parse::<Ipv6Addr>().unwrap()
+        );
+        let solicitation = neighbor_solicitation(MAC, target);
+        assert_eq!(solicitation[0], 135);
+        assert_eq!(solicitation[26..], MAC);
+        assert!(!is_advertisement(&solicitation, target));
+
+        let mut advertisement = solicitation;
+        advertisement[0] = 136;
+        assert!(is_advertisement(&advertisement, target));
+        assert!(!is_advertisement(
+            &advertisement,
+            "fe80::1".parse().unwrap()
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+//! Probe of a target given by hostname, which is resolved before the probe
+//! of its address.
+//!
+//! Addresses are cached for [`HostnameProbe::TTL`], so the hostname isn't
+//! resolved at the ping interval. A failed resolution is cached too, with
+//! attempts backed off exponentially (and with jitter) while it keeps
+//! failing, so that a struggling resolver isn't hammered by every target
+//! during the outage it may be part of. Pings which fail to resolve are
+//! counted by `resolution_failures_total` rather than as packet loss.
+
+use std::{
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use super::{BoxProbe, Probe, ProbeOutcome};
+use crate::{ErrorKind, PingError, Result};
+
+type Resolve =
+    dyn Fn(String) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send>> + Send + Sync;
+type Factory = dyn Fn(IpAddr) -> Result<BoxProbe> + Send + Sync;
+
+/// Probe which resolves a hostname, then probes its address with the probe
+/// built for it.
+pub struct HostnameProbe {
+    hostname: String,
+    resolve: Box<Resolve>,
+    /// Builds the probe of each address which the hostname resolves to.
+    factory: Box<Factory>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Most recently resolved address, and its probe.
+    resolved: Option<(IpAddr, BoxProbe)>,
+    /// Time after which the hostname is resolved again.
+    expires: Option<Instant>,
+    /// Number of consecutive failed resolutions.
+    failures: u32,
+    /// Reason of the most recent failed resolution.
+    error: String,
+}
+
+impl HostnameProbe {
+    /// Length of time which a resolved address is used for.
+    pub const TTL: Duration = Duration::from_secs(60);
+    /// Length of time which a failed resolution is cached for, doubling
+    /// with each consecutive failure up to [`HostnameProbe::MAX_BACKOFF`]
+    /// before jitter.
+    pub const NEGATIVE_TTL: Duration = Duration::from_secs(5);
+    pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+    /// Create a probe of `hostname`, which probes its addresses with the
+    /// probe built by `factory`.
+    pub fn new(
+        hostname: &str,
+        factory: impl Fn(IpAddr) -> Result<BoxProbe> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            hostname: hostname.to_string(),
+            resolve: Box::new(|hostname| {
+                Box::pin(async move {
+                    Ok(tokio::net::lookup_host((hostname.as_str(), 0))
+                        .await?
+                        .map(|addr| addr.ip())
+                        .collect())
+                })
+            }),
+            factory: Box::new(factory),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Length of time to wait before resolving again after `failures`
+    /// consecutive failures, with up to 20% of jitter so that targets
+    /// sharing a resolver don't retry in lockstep.
+    fn backoff(failures: u32) -> Duration {
+        let backoff = Self::NEGATIVE_TTL
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(Self::MAX_BACKOFF);
+        backoff.mul_f64(rand::random_range(0.8..1.2))
+    }
+
+    /// Resolve the hostname if its address has expired, returning the
+    /// failure if it is unresolved.
+    async fn refresh(&self, state: &mut State, now: Instant) -> std::result::Result<(), PingError> {
+        if state.expires.is_some_and(|expires| now < expires) {
+            return match state.resolved {
+                Some(_) => Ok(()),
+                None => Err(self.failure(state, now)),
+            };
+        }
+        let resolved = (self.resolve)(self.hostname.clone())
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|ips| {
+                ips.first()
+                    .copied()
+                    .ok_or_else(|| "no addresses".to_string())
+            });
+        match resolved {
+            Ok(ip) => {
+                if state
+                    .resolved
+                    .as_ref()
+                    .is_none_or(|(current, _)| *current != ip)
+                {
+                    debug!(hostname = self.hostname, %ip, "resolved target");
+                    let probe = (self.factory)(ip).map_err(|e| PingError {
+                        kind: ErrorKind::Other,
+                        message: format!("failed to create probe of {ip}: {e}"),
+                    })?;
+                    state.resolved = Some((ip, probe));
+                }
+                state.failures = 0;
+                state.expires = Some(now + Self::TTL);
+                Ok(())
+            }
+            Err(e) => {
+                state.failures += 1;
+                state.resolved = None;
+                state.error = e;
+                let backoff = Self::backoff(state.failures);
+                warn!(
+                    hostname = self.hostname,
+                    failures = state.failures,
+                    ?backoff,
+                    e = state.error,
+                    "failed to resolve target"
+                );
+                state.expires = Some(now + backoff);
+                Err(self.failure(state, now))
+            }
+        }
+    }
+
+    fn failure(&self, state: &State, now: Instant) -> PingError {
+        let retry = state.expires.map_or(Duration::ZERO, |at| at - now);
+        PingError {
+            kind: ErrorKind::Resolution,
+            message: format!(
+                "failed to resolve {}, retrying in {}s: {}",
+                self.hostname,
+                retry.as_secs(),
+                state.error
+            ),
+        }
+    }
+}
+
+impl Probe for HostnameProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let mut state = self.state.lock().await;
+        if let Err(e) = self.refresh(&mut state, Instant::now()).await {
+            return ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Err(e),
+            };
+        }
+        let (_, probe) = state.resolved.as_ref().expect("hostname was resolved");
+        probe.probe().await
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        net::IpAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::HostnameProbe;
+    use crate::{
+        probe::{BoxProbe, MockProbe, Probe},
+        ErrorKind,
+    };
+
+    /// Probe of a hostname which resolves to localhost once `failures`
+    /// resolutions have failed, counting the resolutions.
+    fn probe(failures: usize) -> (HostnameProbe, Arc<AtomicUsize>) {
+        let mut probe = HostnameProbe::new("example.test", |ip| {
+            Ok(BoxProbe::new(
+                MockProbe::new([Ok(Duration::from_millis(1))]).with_resolved_ip(ip),
+            ))
+        });
+        let resolutions = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&resolutions);
+        probe.resolve = Box::new(move |_| {
+            let n = counter.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                if n < failures {
+                    Err(io::Error::other("no such host"))
+                } else {
+                    Ok(vec![IpAddr::from([127, 0, 0, 1])])
+                }
+            })
+        });
+        (probe, resolutions)
+    }
+
+    #[tokio::test]
+    async fn negative_cache() {
+        let (probe, resolutions) = probe(2);
+        for _ in 0..3 {
+            let outcome = probe.probe().await;
+            assert_eq!(outcome.rtt.unwrap_err().kind, ErrorKind::Resolution);
+        }
+        assert_eq!(resolutions.load(Ordering::Relaxed), 1, "failure is cached");
+
+        // Once the failure expires, the hostname is resolved again, failing
+        // for longer.
+        let expires = |probe: &HostnameProbe| probe.state.try_lock().unwrap().expires.unwrap();
+        let first = expires(&probe);
+        probe.state.try_lock().unwrap().expires = Some(Instant::now());
+        probe.probe().await;
+        assert_eq!(resolutions.load(Ordering::Relaxed), 2);
+        assert!(expires(&probe) - Instant::now() > first - Instant::now());
+
+        probe.state.try_lock().unwrap().expires = Some(Instant::now());
+        let outcome = probe.probe().await;
+        assert_eq!(outcome.resolved_ip, Some(IpAddr::from([127, 0, 0, 1])));
+        assert!(outcome.rtt.is_ok());
+        probe.probe().await;
+        assert_eq!(resolutions.load(Ordering::Relaxed), 3, "address is cached");
+    }
+
+    #[test]
+    fn backoff() {
+        let within = |failures, secs: f64| {
+            let backoff = HostnameProbe::backoff(failures).as_secs_f64();
+            (secs * 0.8..=secs * 1.2).contains(&backoff)
+        };
+        assert!(within(1, 5.0));
+        assert!(within(3, 20.0));
+        assert!(within(20, 300.0), "backoff is capped");
+    }
+}
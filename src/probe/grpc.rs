@@ -0,0 +1,452 @@
+//! Probe of a gRPC server, which calls the standard health checking
+//! protocol (`grpc.health.v1.Health/Check`) over plaintext HTTP/2.
+//!
+//! The round-trip time of the probe is the time taken to connect and
+//! complete the call. The serving status of each target is exported as
+//! `grpc_health_status`, and a service which isn't serving fails the probe
+//! as [`ErrorKind::Unexpected`].
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http_body_util::{BodyExt, Full};
+use hyper::{header::CONTENT_TYPE, HeaderMap, Request};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use prometheus::{IntGaugeVec, Opts, Registry};
+use prost::Message;
+use serde::Deserialize;
+
+use super::{Probe, ProbeOutcome};
+use crate::{
+    target::{ProbeTarget, Scheme},
+    ErrorKind, PingError, Result, UppiesError,
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Length of time to connect and complete the health check within.
+    #[serde(default = "GrpcConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl GrpcConfig {
+    fn default_timeout_ms() -> u64 {
+        5000
+    }
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: Self::default_timeout_ms(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HealthCheckRequest {
+    #[prost(string, tag = "1")]
+    service: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HealthCheckResponse {
+    #[prost(int32, tag = "1")]
+    status: i32,
+}
+
+/// Serving status of a health check, see `grpc.health.v1.HealthCheckResponse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServingStatus {
+    Unknown,
+    Serving,
+    NotServing,
+    /// The server doesn't know of the service which was checked.
+    ServiceUnknown,
+}
+
+impl ServingStatus {
+    const ALL: [Self; 4] = [
+        Self::Unknown,
+        Self::Serving,
+        Self::NotServing,
+        Self::ServiceUnknown,
+    ];
+
+    fn from_i32(status: i32) -> Self {
+        match status {
+            1 => Self::Serving,
+            2 => Self::NotServing,
+            3 => Self::ServiceUnknown,
+            _ => Self::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Serving => "serving",
+            Self::NotServing => "not_serving",
+            Self::ServiceUnknown => "service_unknown",
+        }
+    }
+}
+
+/// Builds the [`GrpcProbe`] of each `grpc` target, sharing their metrics.
+#[derive(Clone)]
+pub struct GrpcProbes {
+    timeout: Duration,
+    /// Whether the most recent health check of each target returned the
+    /// status.
+    status: IntGaugeVec,
+}
+
+impl GrpcProbes {
+    pub fn new(config: &GrpcConfig, metrics: &Registry) -> Result<Self> {
+        let status = IntGaugeVec::new(
+            Opts::new(
+                "grpc_health_status",
+                "Whether the most recent gRPC health check of the target returned the status",
+            ),
+            &["target", "probe", "status"],
+        )?;
+        metrics.register(Box::new(status.clone()))?;
+        Ok(Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            status,
+        })
+    }
+
+    /// Probes of `config`, sharing the metrics of these, such as for a
+    /// module.
+    pub fn with_config(&self, config: &GrpcConfig) -> Self {
+        Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            status: self.status.clone(),
+        }
+    }
+
+    /// Probe of the `grpc` target `target`, checking the service given by
+    /// its path or the overall health of the server without one.
+    pub fn probe(&self, target: &ProbeTarget) -> Result<GrpcProbe> {
+        if target.scheme() != Scheme::Grpc {
+            return Err(UppiesError::InvalidTarget(format!(
+                "'{target}' is not a grpc target"
+            )));
+        }
+        let port = target.port().expect("grpc targets have a port");
+        let authority = match target.host().contains(':') {
+            true => format!("[{}]:{port}", target.host()),
+            false => format!("{}:{port}", target.host()),
+        };
+        Ok(GrpcProbe {
+            host: target.host().to_string(),
+            port,
+            authority,
+            service: target.path().unwrap_or_default().to_string(),
+            timeout: self.timeout,
+            labels: [target.label(), target.probe().to_string()],
+            status: self.status.clone(),
+        })
+    }
+}
+
+/// Probe which calls the gRPC health check of a service.
+pub struct GrpcProbe {
+    host: String,
+    port: u16,
+    /// Host and port of the target, as in the `:authority` of requests.
+    authority: String,
+    /// Service which is checked, or empty for the server overall.
+    service: String,
+    timeout: Duration,
+    /// Labels of the target and probe within `status`.
+    labels: [String; 2],
+    status: IntGaugeVec,
+}
+
+impl GrpcProbe {
+    const PATH: &str = "/grpc.health.v1.Health/Check";
+
+    /// Call the health check, returning the address of the server and the
+    /// status of the service.
+    async fn check(&self) -> std::result::Result<(Option<SocketAddr>, ServingStatus), PingError> {
+        let error = |kind, message: String| PingError { kind, message };
+        let tcp = super::connect(&self.host, self.port).await?;
+        let peer = tcp.peer_addr().ok();
+
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tcp))
+                .await
+                .map_err(|e| error(ErrorKind::Io, e.to_string()))?;
+        let connection = tokio::spawn(connection);
+
+        let request = Request::post(format!("http://{}{}", self.authority, Self::PATH))
+            .header(CONTENT_TYPE, "application/grpc")
+            .header("te", "trailers")
+            .body(Full::new(frame(&HealthCheckRequest {
+                service: self.service.clone(),
+            })))
+            .map_err(|e| error(ErrorKind::Other, e.to_string()))?;
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| error(ErrorKind::Io, e.to_string()))?;
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| error(ErrorKind::Io, e.to_string()))?;
+        drop(sender);
+        connection.abort();
+
+        // Errors are sent as trailers, or as headers of a response
+        // without a body.
+        let trailers = body.trailers().cloned().unwrap_or_default();
+        if let Some(e) = grpc_error(&trailers).or_else(|| grpc_error(&parts.headers)) {
+            return Err(error(ErrorKind::Unexpected, e));
+        }
+        let body = body.to_bytes();
+        let message = unframe(&body)
+            .ok_or_else(|| error(ErrorKind::Malformed, "malformed response".to_string()))?;
+        let response = HealthCheckResponse::decode(message)
+            .map_err(|e| error(ErrorKind::Malformed, e.to_string()))?;
+        Ok((peer, ServingStatus::from_i32(response.status)))
+    }
+
+    /// Set the status of the most recent health check.
+    fn set_status(&self, current: ServingStatus) {
+        for status in ServingStatus::ALL {
+            self.status
+                .with_label_values(&[self.labels[0].as_str(), &self.labels[1], status.as_str()])
+                .set(i64::from(status == current));
+        }
+    }
+}
+
+impl Probe for GrpcProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(self.timeout, self.check()).await {
+            Ok(result) => result,
+            Err(_) => Err(PingError {
+                kind: ErrorKind::Timeout,
+                message: "timed out completing the health check".to_string(),
+            }),
+        };
+        let rtt = start.elapsed();
+        let (resolved_ip, status) = match result {
+            Ok((peer, status)) => (peer.map(|addr| addr.ip()), status),
+            Err(e) => {
+                // The status is unknown rather than stale when the check
+                // fails outright.
+                self.set_status(ServingStatus::Unknown);
+                return ProbeOutcome {
+                    resolved_ip: None,
+                    reply: None,
+                    rtt: Err(e),
+                };
+            }
+        };
+        self.set_status(status);
+        ProbeOutcome {
+            resolved_ip,
+            reply: None,
+            rtt: match status {
+                ServingStatus::Serving => Ok(rtt),
+                _ => Err(PingError {
+                    kind: ErrorKind::Unexpected,
+                    message: format!("service is {}", status.as_str()),
+                }),
+            },
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The series of a target which is no longer probed are removed.
+impl Drop for GrpcProbe {
+    fn drop(&mut self) {
+        for status in ServingStatus::ALL {
+            let _ = self.status.remove_label_values(&[
+                self.labels[0].as_str(),
+                &self.labels[1],
+                status.as_str(),
+            ]);
+        }
+    }
+}
+
+/// Message with the length-prefixed framing of gRPC, uncompressed.
+fn frame(message: &impl Message) -> Bytes {
+    let message = message.encode_to_vec();
+    let mut framed = BytesMut::with_capacity(5 + message.len());
+    framed.put_u8(0);
+    framed.put_u32(message.len() as u32);
+    framed.put_slice(&message);
+    framed.freeze()
+}
+
+/// The single uncompressed message of a length-prefixed response.
+fn unframe(body: &[u8]) -> Option<&[u8]> {
+    let (&[0, a, b, c, d], message) = body.split_first_chunk::<5>()? else {
+        return None;
+    };
+    let len = u32::from_be_bytes([a, b, c, d]) as usize;
+    message.get(..len)
+}
+
+/// Error of a call with a non-zero `grpc-status`, if any.
+fn grpc_error(headers: &HeaderMap) -> Option<String> {
+    let status = headers.get("grpc-status")?.to_str().ok()?;
+    if status == "0" {
+        return None;
+    }
+    let message = headers
+        .get("grpc-message")
+        .and_then(|message| message.to_str().ok())
+        .unwrap_or_default();
+    Some(format!("grpc status {status}: {message}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use bytes::Bytes;
+    use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+    use hyper::{body::Frame, service::service_fn, HeaderMap, Request, Response};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use prometheus::Registry;
+    use prost::Message;
+    use tokio::net::TcpListener;
+
+    use super::{frame, unframe, GrpcConfig, GrpcProbes, HealthCheckRequest, HealthCheckResponse};
+    use crate::{probe::Probe, ErrorKind};
+
+    /// Health check which is serving overall, not serving for `payments` and
+    /// unknown for any other service.
+    async fn check(
+        request: Request<hyper::body::Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+        let body = request.into_body().collect().await.unwrap().to_bytes();
+        let service = HealthCheckRequest::decode(unframe(&body).unwrap())
+            .unwrap()
+            .service;
+        let status = match service.as_str() {
+            "" => 1,
+            "payments" => 2,
+            _ => {
+                // Trailers-only response, as for an unknown service.
+                let response = Response::builder()
+                    .header("grpc-status", "5")
+                    .header("grpc-message", "unknown service")
+                    .body(Full::new(Bytes::new()).boxed())
+                    .unwrap();
+                return Ok(response);
+            }
+        };
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        let frames = [
+            Ok(Frame::data(frame(&HealthCheckResponse { status }))),
+            Ok(Frame::trailers(trailers)),
+        ];
+        let body = StreamBody::new(tokio_stream::iter(frames));
+        Ok(Response::builder()
+            .header("content-type", "application/grpc")
+            .body(BodyExt::boxed(body))
+            .unwrap())
+    }
+
+    async fn server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(tcp), service_fn(check)),
+                );
+            }
+        });
+        addr
+    }
+
+    fn status(metrics: &Registry, target: &str) -> Option<String> {
+        let family = metrics
+            .gather()
+            .into_iter()
+            .find(|m| m.name() == "grpc_health_status")?;
+        let label = |m: &prometheus::proto::Metric, name: &str| {
+            let pair = m.get_label().iter().find(|l| l.name() == name)?;
+            Some(pair.value().to_string())
+        };
+        family
+            .get_metric()
+            .iter()
+            .filter(|m| label(m, "target").as_deref() == Some(target))
+            .find(|m| m.get_gauge().value() == 1.0)
+            .and_then(|m| label(m, "status"))
+    }
+
+    #[tokio::test]
+    async fn health_checks() {
+        let addr = server().await;
+        let metrics = Registry::new();
+        let config = GrpcConfig { timeout_ms: 1000 };
+        let probes = GrpcProbes::new(&config, &metrics).unwrap();
+        let probe = |service: &str| {
+            let target = format!("grpc://127.0.0.1:{}/{service}", addr.port());
+            probes.probe(&target.parse().unwrap()).unwrap()
+        };
+
+        let server = probe("");
+        let outcome = server.probe().await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+        assert_eq!(outcome.resolved_ip, Some(addr.ip()));
+        let label = format!("grpc://127.0.0.1:{}", addr.port());
+        assert_eq!(status(&metrics, &label).as_deref(), Some("serving"));
+
+        let payments = probe("payments");
+        let e = payments.probe().await.rtt.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unexpected);
+        assert_eq!(e.message, "service is not_serving");
+        assert_eq!(
+            status(&metrics, &format!("{label}/payments")).as_deref(),
+            Some("not_serving")
+        );
+
+        let e = probe("missing").probe().await.rtt.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unexpected);
+        assert_eq!(e.message, "grpc status 5: unknown service");
+
+        drop(server);
+        assert_eq!(status(&metrics, &label), None, "removed with the probe");
+        assert!(probes.probe(&"1.1.1.1".parse().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let probes = GrpcProbes::new(&GrpcConfig::default(), &Registry::new()).unwrap();
+        let target = format!("grpc://127.0.0.1:{port}").parse().unwrap();
+        let e = probes
+            .probe(&target)
+            .unwrap()
+            .probe()
+            .await
+            .rtt
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Io);
+    }
+}
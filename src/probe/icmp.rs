@@ -0,0 +1,1143 @@
+//! Probe which sends ICMP echo requests.
+//!
+//! Probes share one socket for each address family, interface and
+//! [`SocketOptions`], rather than opening a socket for each target, and are
+//! told apart by their identifier.
+//!
+//! A probe can send a burst of pings, like `ping -c 3`, whose median
+//! round-trip time is its outcome, which is more robust on lossy links than
+//! a single ping. The best, worst and median of the latest burst of each
+//! target, and its loss, are recorded by [`IcmpProbes`].
+//!
+//! Pings are sent from unprivileged datagram sockets by default, or from
+//! raw sockets with [`SocketType::Raw`] and on Windows, which has no ICMP
+//! datagram sockets, falling back to the other type when the preferred one
+//! can't be opened. [`SocketSupport`] checks which
+//! can be opened at startup, so that the targets which can't be probed are
+//! reported together rather than as each fails.
+//!
+//! Echo requests can be marked with a [`Dscp`], such as `EF` to measure
+//! latency within the QoS class of voice traffic, which is exported by the
+//! `ping_dscp` gauge of each target.
+//!
+//! With `dont_fragment`, echo requests which are larger than the MTU of the
+//! path are lost rather than fragmented. The path MTU of a target is then
+//! discovered with `mtu_discovery`, by a binary search for the largest
+//! payload which is still replied to, and exported by the `path_mtu_bytes`
+//! gauge. The search runs in the background once the target first replies,
+//! and again every `mtu_interval_secs`, so that the pings of the search,
+//! some of which time out, never delay the outcome of a probe.
+//!
+//! Each ping has a sequence of its own, whose replies are still tracked
+//! for a while after it is answered or times out, so that replies which are
+//! duplicated or arrive after the timeout are counted by the
+//! `ping_duplicate_replies_total` and `ping_late_replies_total` counters
+//! of each target, as they are signs of a flaky link which the outcome of
+//! each ping hides.
+
+use std::{
+    collections::HashMap,
+    ffi::c_int,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, LazyLock, OnceLock, Weak,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "metrics")]
+use prometheus::{Opts, Registry};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket};
+use surge_ping::{Config, IcmpPacket, SurgeError, ICMP};
+use tokio::{runtime, task::AbortHandle, time::MissedTickBehavior};
+use tracing::warn;
+
+use self::client::{Client, ReplyCounters};
+use super::{BoxProbe, HostnameProbe, Probe, ProbeOutcome, Reply};
+use crate::{
+    metrics::{GaugeVec, IntCounterVec, IntGaugeVec},
+    target::{ProbeTarget, Scheme},
+    PingError, Result, UppiesError,
+};
+
+mod client;
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct IcmpConfig {
+    /// Length of time before a ping is considered failed, defaulting to 2
+    /// seconds.
+    pub timeout_ms: Option<u64>,
+    /// Bytes of payload of each echo request, such as to exercise jumbo
+    /// frames.
+    #[serde(default)]
+    pub payload_size: usize,
+    /// Pings of each probe, sent one after another, defaulting to 1.
+    #[serde(default)]
+    pub count: usize,
+    /// Type of socket which pings are preferably sent from.
+    #[serde(default)]
+    pub socket: SocketType,
+    /// DSCP which echo requests are marked with, such as `EF` or `46`,
+    /// rather than none.
+    pub dscp: Option<Dscp>,
+    /// Never fragment echo requests, so that those larger than the MTU of
+    /// the path are lost.
+    #[serde(default)]
+    pub dont_fragment: bool,
+    /// Discover the path MTU of each target in the background, which
+    /// implies `dont_fragment`.
+    #[serde(default)]
+    pub mtu_discovery: bool,
+    /// Seconds between discoveries of the path MTU of each target,
+    /// defaulting to 10 minutes.
+    pub mtu_interval_secs: Option<u64>,
+}
+
+impl IcmpConfig {
+    /// Options of the sockets of the probes of this configuration.
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            socket: self.socket,
+            dscp: self.dscp,
+            dont_fragment: self.dont_fragment || self.mtu_discovery,
+        }
+    }
+}
+
+/// Options of the socket of a probe, which it only shares with the probes of
+/// the same options.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SocketOptions {
+    /// Type of socket which is preferred.
+    pub socket: SocketType,
+    /// DSCP which echo requests are marked with.
+    pub dscp: Option<Dscp>,
+    /// Whether echo requests are never fragmented.
+    pub dont_fragment: bool,
+}
+
+/// Differentiated services code point of the IP header of echo requests,
+/// which is their QoS class, given by a number up to 63 or a name such as
+/// `EF`, `AF41` or `CS6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "DscpValue")]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// Expedited forwarding, the class of voice traffic.
+    pub const EF: Self = Self(46);
+
+    pub fn new(code: u8) -> std::result::Result<Self, String> {
+        match code {
+            0..=63 => Ok(Self(code)),
+            _ => Err(format!("DSCP {code} is not within 0 to 63")),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self.0
+    }
+
+    /// Type of service byte of IPv4, or traffic class of IPv6, which the
+    /// code point is the upper 6 bits of.
+    fn tos(self) -> u32 {
+        u32::from(self.0) << 2
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(code) = s.parse() {
+            return Self::new(code);
+        }
+        let invalid = || format!("invalid DSCP '{s}', expected 0 to 63, EF, AFxy or CSx");
+        let name = s.to_ascii_uppercase();
+        let digit = |i: usize| name.get(i..i + 1).and_then(|d| d.parse::<u8>().ok());
+        match (name.get(..2), name.len()) {
+            (Some("EF"), 2) => Ok(Self::EF),
+            (Some("CS"), 3) => match digit(2) {
+                Some(class @ 0..=7) => Ok(Self(class << 3)),
+                _ => Err(invalid()),
+            },
+            (Some("AF"), 4) => match (digit(2), digit(3)) {
+                (Some(class @ 1..=4), Some(drop @ 1..=3)) => Ok(Self(class << 3 | drop << 1)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// DSCP as it is written in the configuration, by code or name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DscpValue {
+    Code(u8),
+    Name(String),
+}
+
+impl TryFrom<DscpValue> for Dscp {
+    type Error = String;
+
+    fn try_from(value: DscpValue) -> std::result::Result<Self, Self::Error> {
+        match value {
+            DscpValue::Code(code) => Self::new(code),
+            DscpValue::Name(name) => name.parse(),
+        }
+    }
+}
+
+/// Type of the sockets which echo requests are sent from, which defaults to
+/// the type which suits the platform.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketType {
+    /// Unprivileged ICMP datagram sockets, which on Linux require a group
+    /// of the process within `net.ipv4.ping_group_range`, and are open to
+    /// any process on macOS.
+    Dgram,
+    /// Raw sockets, which require `CAP_NET_RAW`.
+    Raw,
+}
+
+/// Datagram sockets don't need privileges on Linux and macOS, but only raw
+/// sockets exist on Windows.
+impl Default for SocketType {
+    fn default() -> Self {
+        match cfg!(windows) {
+            false => Self::Dgram,
+            true => Self::Raw,
+        }
+    }
+}
+
+impl SocketType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Dgram => "dgram",
+            Self::Raw => "raw",
+        }
+    }
+
+    /// Type of the socket as given to `socket(2)`, which is converted into
+    /// the `socket2` types of both this crate and `surge_ping`.
+    fn as_raw(self) -> c_int {
+        match self {
+            Self::Dgram => socket2::Type::DGRAM,
+            Self::Raw => socket2::Type::RAW,
+        }
+        .into()
+    }
+
+    /// Type which is fallen back to when this can't be opened.
+    fn fallback(self) -> Self {
+        match self {
+            Self::Dgram => Self::Raw,
+            Self::Raw => Self::Dgram,
+        }
+    }
+}
+
+/// Privileges which are required to open either type of socket, for errors
+/// of opening them.
+pub const SOCKET_PRIVILEGES: &str =
+    "raw sockets require CAP_NET_RAW, and datagram sockets a group \
+    within the net.ipv4.ping_group_range sysctl";
+
+/// Type of the ICMP socket which can be opened for each address family, or
+/// why neither type can be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketSupport {
+    pub ipv4: std::result::Result<SocketType, String>,
+    pub ipv6: std::result::Result<SocketType, String>,
+}
+
+impl SocketSupport {
+    /// Open a socket of each address family, preferring `socket`.
+    pub fn check(socket: SocketType) -> Self {
+        let open = |ipv6| open_socket(ipv6, socket).map_err(|e| e.to_string());
+        Self {
+            ipv4: open(false),
+            ipv6: open(true),
+        }
+    }
+
+    /// Why `target` can't be probed, if it is an ICMP target which can't.
+    ///
+    /// Hostnames can be probed if either address family can be, as they
+    /// aren't resolved until they are probed.
+    pub fn unprobeable(&self, target: &ProbeTarget) -> Option<&str> {
+        if target.scheme() != Scheme::Icmp {
+            return None;
+        }
+        let result = match parse_target(target.host()) {
+            Ok((ip, _)) if ip.is_ipv6() => &self.ipv6,
+            Ok(_) => &self.ipv4,
+            Err(_) => match (&self.ipv4, &self.ipv6) {
+                (Err(e), Err(_)) => return Some(e),
+                _ => return None,
+            },
+        };
+        result.as_ref().err().map(String::as_str)
+    }
+}
+
+/// Open an ICMP socket of the address family, of the type `socket` or
+/// otherwise its fallback, returning the type which could be opened.
+fn open_socket(ipv6: bool, socket: SocketType) -> io::Result<SocketType> {
+    let (domain, protocol) = match ipv6 {
+        false => (Domain::IPV4, Protocol::ICMPV4),
+        true => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    Socket::new(domain, socket.as_raw().into(), Some(protocol))
+        .map(|_| socket)
+        .or_else(|_| {
+            let fallback = socket.fallback();
+            Socket::new(domain, fallback.as_raw().into(), Some(protocol)).map(|_| fallback)
+        })
+}
+
+/// Builds the probes of ICMP targets, recording the statistics of their
+/// bursts.
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub struct IcmpProbes {
+    config: IcmpConfig,
+    metrics: IcmpMetrics,
+}
+
+#[cfg(feature = "metrics")]
+impl IcmpProbes {
+    pub fn new(config: &IcmpConfig, metrics: &Registry) -> Result<Self> {
+        let rtt = GaugeVec::new(
+            Opts::new(
+                "ping_burst_rtt_ms",
+                "Best, worst and median round-trip time of the latest burst of pings in milliseconds",
+            ),
+            &["target", "probe", "stat"],
+        )?;
+        let loss = GaugeVec::new(
+            Opts::new(
+                "ping_burst_loss_ratio",
+                "Ratio of the latest burst of pings which were lost",
+            ),
+            &["target", "probe"],
+        )?;
+        let dscp = IntGaugeVec::new(
+            Opts::new(
+                "ping_dscp",
+                "DSCP which the echo requests of each target are marked with",
+            ),
+            &["target", "probe"],
+        )?;
+        let path_mtu = IntGaugeVec::new(
+            Opts::new(
+                "path_mtu_bytes",
+                "Largest packet which reached each target without fragmenting in bytes",
+            ),
+            &["target", "probe"],
+        )?;
+        let late = IntCounterVec::new(
+            Opts::new(
+                "ping_late_replies_total",
+                "Counter of replies which arrived after their ping timed out",
+            ),
+            &["target", "probe"],
+        )?;
+        let duplicate = IntCounterVec::new(
+            Opts::new(
+                "ping_duplicate_replies_total",
+                "Counter of further replies to pings which were already answered",
+            ),
+            &["target", "probe"],
+        )?;
+        metrics.register(Box::new(rtt.clone()))?;
+        metrics.register(Box::new(loss.clone()))?;
+        metrics.register(Box::new(dscp.clone()))?;
+        metrics.register(Box::new(path_mtu.clone()))?;
+        metrics.register(Box::new(late.clone()))?;
+        metrics.register(Box::new(duplicate.clone()))?;
+        Ok(Self {
+            config: config.clone(),
+            metrics: IcmpMetrics {
+                rtt,
+                loss,
+                dscp,
+                path_mtu,
+                late,
+                duplicate,
+            },
+        })
+    }
+
+    /// Probes of `config`, sharing the metrics of these, such as for a
+    /// module.
+    pub fn with_config(&self, config: &IcmpConfig) -> Self {
+        Self {
+            config: config.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Probe of the `icmp` target `target`, see [`icmp`](super::icmp).
+    pub fn probe(&self, target: &ProbeTarget) -> Result<BoxProbe> {
+        build(target, &self.config, Some(&self.metrics))
+    }
+}
+
+/// Gauges of the latest burst of pings of each target, the marking of its
+/// echo requests and its path MTU, and counters of its late and duplicate
+/// replies.
+#[derive(Clone)]
+pub(super) struct IcmpMetrics {
+    rtt: GaugeVec,
+    loss: GaugeVec,
+    dscp: IntGaugeVec,
+    path_mtu: IntGaugeVec,
+    late: IntCounterVec,
+    duplicate: IntCounterVec,
+}
+
+/// Probe of `target`, recording into `metrics` if given.
+pub(super) fn build(
+    target: &ProbeTarget,
+    config: &IcmpConfig,
+    metrics: Option<&IcmpMetrics>,
+) -> Result<BoxProbe> {
+    if target.scheme() != Scheme::Icmp {
+        return Err(UppiesError::InvalidTarget(format!(
+            "'{target}' is not an icmp target"
+        )));
+    }
+    let config = config.clone();
+    let metrics = metrics.map(|metrics| {
+        (
+            metrics.clone(),
+            [target.label(), target.probe().to_string()],
+        )
+    });
+    let build = move |host: &str| -> Result<IcmpProbe> {
+        let mut probe = IcmpProbe::open(host, config.socket_options())?
+            .with_payload_size(config.payload_size)
+            .with_count(config.count);
+        if let Some(timeout_ms) = config.timeout_ms {
+            probe = probe.with_timeout(Duration::from_millis(timeout_ms));
+        }
+        if config.mtu_discovery {
+            let interval = config.mtu_interval_secs.map(Duration::from_secs);
+            probe.mtu_interval = Some(interval.unwrap_or(DEFAULT_MTU_INTERVAL));
+        }
+        probe.metrics = metrics.clone();
+        Ok(probe)
+    };
+    if !target.is_hostname() {
+        return Ok(BoxProbe::new(build(target.host())?));
+    }
+    Ok(BoxProbe::new(HostnameProbe::new(
+        target.host(),
+        move |ip| build(&ip.to_string()).map(BoxProbe::new),
+    )))
+}
+
+/// Probe which sends ICMP echo requests (pings) to an IP address.
+pub struct IcmpProbe {
+    ip: IpAddr,
+    /// Client used to send ICMP packets, which is shared with the other
+    /// probes of its address family, interface and socket options.
+    client: Arc<Client>,
+    /// DSCP which echo requests are marked with.
+    dscp: Option<Dscp>,
+    /// Identifier of the echo requests of this probe.
+    identifier: u16,
+    /// Timeout before a ping is considered failed, defaulting to 2 seconds.
+    timeout: Option<Duration>,
+    /// Payload of each echo request.
+    payload: Vec<u8>,
+    /// Pings of each probe.
+    count: usize,
+    /// Interval between discoveries of the path MTU, from a socket which
+    /// doesn't fragment, if it is discovered.
+    mtu_interval: Option<Duration>,
+    /// Task which discovers the path MTU, once the target has replied.
+    mtu_task: OnceLock<AbortHandle>,
+    /// Metrics which bursts are recorded into, and the labels of the target
+    /// and probe within them.
+    metrics: Option<(IcmpMetrics, [String; 2])>,
+}
+
+impl IcmpProbe {
+    /// Create a probe of the `target` IP address.
+    ///
+    /// IPv6 targets can be scoped to an interface by name or index, as in
+    /// `fe80::1%eth0`, which is required for link-local addresses. Pings are
+    /// then only sent and received through that interface.
+    pub fn new(target: &str) -> Result<Self> {
+        Self::open(target, SocketOptions::default())
+    }
+
+    /// Create a probe of the `target` IP address, as with [`new`](Self::new),
+    /// which sends pings from a socket of the preferred type of `options` if
+    /// it can be opened, otherwise of the other type.
+    pub fn open(target: &str, options: SocketOptions) -> Result<Self> {
+        let (ip, interface) = parse_target(target)?;
+        let client = shared_client(ip.is_ipv6(), interface, options).map_err(|source| {
+            UppiesError::SocketInit {
+                target: target.to_string(),
+                source,
+            }
+        })?;
+        Ok(Self {
+            ip,
+            client,
+            dscp: options.dscp,
+            identifier: NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed),
+            timeout: None,
+            payload: Vec::new(),
+            count: 1,
+            mtu_interval: None,
+            mtu_task: OnceLock::new(),
+            metrics: None,
+        })
+    }
+
+    /// Alter the length of time before a timeout error is issued.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send `size` bytes of payload with each echo request, rather than
+    /// none.
+    pub fn with_payload_size(mut self, size: usize) -> Self {
+        self.payload = vec![0; size];
+        self
+    }
+
+    /// Send a burst of `count` pings with each probe, whose outcome is
+    /// their median round-trip time, rather than one.
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.count = count.max(1);
+        self
+    }
+
+    /// Record the statistics of a burst, which has lost all its pings if
+    /// `rtts` is empty.
+    fn record_burst(&self, rtts: &[Duration]) {
+        let Some((metrics, [target, probe])) = &self.metrics else {
+            return;
+        };
+        if self.count < 2 {
+            return;
+        }
+        let lost = self.count - rtts.len();
+        metrics
+            .loss
+            .with_label_values(&[target, probe])
+            .set(lost as f64 / self.count as f64);
+        let (Some(best), Some(worst)) = (rtts.first(), rtts.last()) else {
+            for stat in ["best", "worst", "median"] {
+                let _ = metrics.rtt.remove_label_values(&[target, probe, stat]);
+            }
+            return;
+        };
+        for (stat, rtt) in [("best", best), ("worst", worst), ("median", &median(rtts))] {
+            metrics
+                .rtt
+                .with_label_values(&[target, probe, stat])
+                .set(rtt.as_secs_f64() * 1000.0);
+        }
+    }
+
+    /// Pinger of the target, counting its late and duplicate replies.
+    fn pinger(&self) -> Pinger {
+        Pinger {
+            ip: self.ip,
+            client: Arc::clone(&self.client),
+            identifier: self.identifier,
+            timeout: self.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            counters: self.metrics.as_ref().map(|(metrics, labels)| {
+                let labels = &[labels[0].as_str(), &labels[1]];
+                ReplyCounters {
+                    late: metrics.late.with_label_values(labels),
+                    duplicate: metrics.duplicate.with_label_values(labels),
+                }
+            }),
+        }
+    }
+
+    /// Discover the path MTU every `interval` in a task of its own, while
+    /// the payload of the probe is replied to.
+    fn spawn_mtu_discovery(&self, interval: Duration) -> AbortHandle {
+        let pinger = self.pinger();
+        let payload = self.payload.clone();
+        let path_mtu = self.metrics.as_ref().map(|(metrics, [target, probe])| {
+            metrics
+                .path_mtu
+                .with_label_values(&[target.as_str(), probe])
+        });
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if pinger.ping(&payload).await.is_err() {
+                    continue;
+                }
+                let mtu = pinger.discover_mtu(payload.len()).await;
+                if let Some(path_mtu) = &path_mtu {
+                    path_mtu.set(mtu as i64);
+                }
+            }
+        })
+        .abort_handle()
+    }
+}
+
+/// Sender of the echo requests of a probe, which its path MTU discovery
+/// shares.
+struct Pinger {
+    ip: IpAddr,
+    client: Arc<Client>,
+    identifier: u16,
+    timeout: Duration,
+    counters: Option<ReplyCounters>,
+}
+
+impl Pinger {
+    /// Ping the target once with `payload`.
+    async fn ping(
+        &self,
+        payload: &[u8],
+    ) -> std::result::Result<(IcmpPacket, Duration), SurgeError> {
+        self.client
+            .ping(
+                self.ip,
+                self.identifier,
+                payload,
+                self.timeout,
+                self.counters.clone(),
+            )
+            .await
+    }
+
+    /// Largest packet which is replied to, as discovered by a binary search.
+    ///
+    /// A payload of `known` bytes is known to be replied to, and each ping
+    /// of the search halves the remaining range up to [`MAX_MTU`].
+    async fn discover_mtu(&self, known: usize) -> usize {
+        let headers = match self.ip {
+            IpAddr::V4(_) => 20 + 8,
+            IpAddr::V6(_) => 40 + 8,
+        };
+        let (mut low, mut high) = (known, MAX_MTU.saturating_sub(headers));
+        while low < high {
+            let mid = (low + high).div_ceil(2);
+            let payload = vec![0; mid];
+            match self.ping(&payload).await {
+                Ok(_) => low = mid,
+                Err(_) => high = mid - 1,
+            }
+        }
+        low + headers
+    }
+}
+
+/// Timeout of pings which have none configured.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Interval between discoveries of the path MTU which have none configured.
+const DEFAULT_MTU_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Largest path MTU which is discovered, that of jumbo frames.
+const MAX_MTU: usize = 9000;
+
+/// Median of durations which are sorted, of which there is at least one.
+fn median(sorted: &[Duration]) -> Duration {
+    let len = sorted.len();
+    (sorted[(len - 1) / 2] + sorted[len / 2]) / 2
+}
+
+impl Probe for IcmpProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let pinger = self.pinger();
+        let mut rtts = Vec::with_capacity(self.count);
+        let mut error = None;
+        let mut reply = None;
+        for _ in 0..self.count {
+            match pinger.ping(&self.payload).await {
+                Ok((packet, rtt)) => {
+                    rtts.push(rtt);
+                    reply = Some(reply_of(&packet));
+                }
+                Err(e) => error = Some(PingError::from(e)),
+            }
+        }
+        rtts.sort();
+        self.record_burst(&rtts);
+        // The marking is recorded by each probe, as the probe of a hostname
+        // which is replaced removes it once dropped.
+        if let (Some((metrics, [target, probe])), Some(dscp)) = (&self.metrics, self.dscp) {
+            metrics
+                .dscp
+                .with_label_values(&[target, probe])
+                .set(dscp.code().into());
+        }
+        if let (Some(interval), false) = (self.mtu_interval, rtts.is_empty()) {
+            self.mtu_task
+                .get_or_init(|| self.spawn_mtu_discovery(interval));
+        }
+        ProbeOutcome {
+            resolved_ip: Some(self.ip),
+            reply,
+            rtt: match error {
+                Some(e) if rtts.is_empty() => Err(e),
+                _ => Ok(median(&rtts)),
+            },
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Details of the latest reply of a probe.
+fn reply_of(packet: &IcmpPacket) -> Reply {
+    match packet {
+        IcmpPacket::V4(packet) => Reply {
+            source: packet.get_source().into(),
+            ttl: packet.get_ttl(),
+            echo: packet.get_icmp_type().0 == ECHO_REPLY_V4,
+        },
+        IcmpPacket::V6(packet) => Reply {
+            source: packet.get_source().into(),
+            ttl: None,
+            echo: packet.get_icmpv6_type().0 == ECHO_REPLY_V6,
+        },
+    }
+}
+
+/// Types of the echo replies of ICMP and ICMPv6.
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REPLY_V6: u8 = 129;
+
+/// The series of a target which is no longer probed are removed.
+impl Drop for IcmpProbe {
+    fn drop(&mut self) {
+        if let Some(task) = self.mtu_task.get() {
+            task.abort();
+        }
+        if let Some((metrics, [target, probe])) = &self.metrics {
+            let labels = &[target.as_str(), probe];
+            let _ = metrics.loss.remove_label_values(labels);
+            let _ = metrics.dscp.remove_label_values(labels);
+            let _ = metrics.path_mtu.remove_label_values(labels);
+            let _ = metrics.late.remove_label_values(labels);
+            let _ = metrics.duplicate.remove_label_values(labels);
+            for stat in ["best", "worst", "median"] {
+                let _ = metrics.rtt.remove_label_values(&[target, probe, stat]);
+            }
+        }
+    }
+}
+
+/// Identifier of the next probe, so that probes sharing a client don't
+/// receive the replies of each other.
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(0);
+
+/// Address family, interface and socket options of a client, within the
+/// runtime its socket is driven by.
+type ClientKey = (runtime::Id, bool, Option<String>, SocketOptions);
+
+/// Clients which are in use by probes, which are closed once their last
+/// probe is dropped.
+static CLIENTS: LazyLock<std::sync::Mutex<HashMap<ClientKey, Weak<Client>>>> =
+    LazyLock::new(Default::default);
+
+/// Client of the address family and interface, shared with the other probes
+/// of them, which is created if there are none.
+fn shared_client(
+    ipv6: bool,
+    interface: Option<String>,
+    options: SocketOptions,
+) -> io::Result<Arc<Client>> {
+    let key = (runtime::Handle::current().id(), ipv6, interface, options);
+    let socket = options.socket;
+    let mut clients = CLIENTS.lock().expect("clients aren't poisoned");
+    if let Some(client) = clients.get(&key).and_then(Weak::upgrade) {
+        return Ok(client);
+    }
+    let mut config = Config::builder()
+        .kind(match ipv6 {
+            false => ICMP::V4,
+            true => ICMP::V6,
+        })
+        .sock_type_hint(socket.as_raw().into());
+    if let Some(interface) = &key.2 {
+        // The client binds sockets to interfaces with `SO_BINDTODEVICE`,
+        // and silently doesn't on other platforms.
+        if !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "pinging through an interface is only supported on Linux",
+            ));
+        }
+        config = config.interface(interface);
+    }
+    // The client falls back to the other type of socket by itself.
+    let client = Arc::new(Client::new(&config.build())?);
+    if c_int::from(client.get_socket().get_type()) != socket.as_raw() {
+        warn!(
+            preferred = socket.as_str(),
+            fallback = socket.fallback().as_str(),
+            ipv6,
+            "could not open the preferred type of ICMP socket"
+        );
+    }
+    if let Some(dscp) = options.dscp {
+        set_tos(&client, ipv6, dscp)?;
+    }
+    if options.dont_fragment {
+        set_dont_fragment(&client, ipv6)?;
+    }
+    clients.retain(|_, client| client.strong_count() > 0);
+    clients.insert(key, Arc::downgrade(&client));
+    Ok(client)
+}
+
+/// Mark the echo requests sent from the socket of `client` with `dscp`.
+#[cfg(unix)]
+fn set_tos(client: &Client, ipv6: bool, dscp: Dscp) -> io::Result<()> {
+    use std::os::fd::BorrowedFd;
+
+    let socket = client.get_socket();
+    // SAFETY: the socket is open for as long as `client` is borrowed.
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.get_native_sock()) };
+    let socket = socket2::SockRef::from(&fd);
+    match ipv6 {
+        false => socket.set_tos_v4(dscp.tos()),
+        true => socket.set_tclass_v6(dscp.tos()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_tos(_: &Client, _: bool, _: Dscp) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "marking pings with a DSCP is only supported on Unix",
+    ))
+}
+
+/// Never fragment the echo requests sent from the socket of `client`, so
+/// that those larger than the path MTU fail to send or are lost instead.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(client: &Client, ipv6: bool) -> io::Result<()> {
+    let (level, name, value) = match ipv6 {
+        false => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        true => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+    };
+    // SAFETY: the socket is open for as long as `client` is borrowed, and
+    // the value of the option is a `c_int`.
+    let result = unsafe {
+        libc::setsockopt(
+            client.get_socket().get_native_sock(),
+            level,
+            name,
+            (&value as *const c_int).cast(),
+            size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_: &Client, _: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinging without fragmenting is only supported on Linux",
+    ))
+}
+
+/// Parse a target into its address and the name of the interface it is
+/// scoped to, if any.
+fn parse_target(target: &str) -> Result<(IpAddr, Option<String>)> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        UppiesError::InvalidTarget(format!("invalid target '{target}': {e}"))
+    };
+    let Some((address, scope)) = target.split_once('%') else {
+        return Ok((target.parse().map_err(|e| invalid(&e))?, None));
+    };
+    let ip: Ipv6Addr = address.parse().map_err(|e| invalid(&e))?;
+    if scope.is_empty() {
+        return Err(invalid(&"empty scope"));
+    }
+    let interface = match scope.parse::<u32>() {
+        Ok(index) => interface_name(index).ok_or_else(|| invalid(&"unknown interface index"))?,
+        Err(_) => scope.to_string(),
+    };
+    Ok((IpAddr::V6(ip), Some(interface)))
+}
+
+/// Name of the network interface with the given index.
+fn interface_name(index: u32) -> Option<String> {
+    std::fs::read_dir("/sys/class/net")
+        .ok()?
+        .flatten()
+        .find(|entry| {
+            std::fs::read_to_string(entry.path().join("ifindex"))
+                .is_ok_and(|i| i.trim().parse() == Ok(index))
+        })
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, sync::Arc, time::Duration};
+
+    use libc::c_int;
+    #[cfg(feature = "metrics")]
+    use prometheus::Registry;
+
+    use super::{median, parse_target, Dscp, IcmpProbe, SocketOptions, SocketSupport, SocketType};
+    #[cfg(feature = "metrics")]
+    use super::{IcmpConfig, IcmpProbes, MAX_MTU};
+    use crate::probe::Probe;
+
+    #[test]
+    fn medians() {
+        let ms = Duration::from_millis;
+        assert_eq!(median(&[ms(3)]), ms(3));
+        assert_eq!(median(&[ms(1), ms(2), ms(9)]), ms(2));
+        assert_eq!(median(&[ms(1), ms(2), ms(4), ms(9)]), ms(3));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn burst() {
+        let metrics = Registry::new();
+        let config = IcmpConfig {
+            count: 3,
+            ..Default::default()
+        };
+        let probes = IcmpProbes::new(&config, &metrics).unwrap();
+        let probe = probes.probe(&"lo=127.0.0.1".parse().unwrap()).unwrap();
+        let outcome = probe.probe().await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+
+        let rtt = |stat| {
+            probes
+                .metrics
+                .rtt
+                .with_label_values(&["lo", "icmp", stat])
+                .get()
+        };
+        assert!(rtt("best") > 0.0);
+        assert!(rtt("best") <= rtt("median") && rtt("median") <= rtt("worst"));
+        assert_eq!(
+            probes.metrics.loss.with_label_values(&["lo", "icmp"]).get(),
+            0.0
+        );
+
+        drop(probe);
+        assert!(metrics
+            .gather()
+            .iter()
+            .all(|family| family.get_metric().is_empty()));
+    }
+
+    #[tokio::test]
+    async fn shared_socket() {
+        let probes: Vec<_> = ["127.0.0.1", "127.0.0.1", "127.0.0.2"]
+            .into_iter()
+            .map(|target| IcmpProbe::new(target).unwrap())
+            .collect();
+        assert!(Arc::ptr_eq(&probes[0].client, &probes[2].client));
+        assert_ne!(probes[0].identifier, probes[1].identifier);
+        let v6 = IcmpProbe::new("::1").unwrap();
+        assert!(!Arc::ptr_eq(&probes[0].client, &v6.client));
+
+        let outcomes = tokio::join!(probes[0].probe(), probes[1].probe(), probes[2].probe());
+        for outcome in [outcomes.0, outcomes.1, outcomes.2] {
+            assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+        }
+    }
+
+    #[test]
+    fn scoped_targets() {
+        let link_local: IpAddr = "fe80::1".parse().unwrap();
+        assert_eq!(
+            parse_target("fe80::1%eth0").unwrap(),
+            (link_local, Some("eth0".to_string()))
+        );
+        assert_eq!(
+            parse_target("fe80::1%1").unwrap(),
+            (link_local, Some("lo".to_string())),
+            "interfaces can be given by index"
+        );
+        assert_eq!(
+            parse_target("1.1.1.1").unwrap(),
+            ("1.1.1.1".parse().unwrap(), None)
+        );
+        assert!(parse_target("fe80::1%").is_err());
+        assert!(parse_target("1.1.1.1%eth0").is_err(), "only IPv6 is scoped");
+    }
+
+    #[tokio::test]
+    async fn scoped_ipv6() {
+        let probe = IcmpProbe::new("::1%lo").unwrap();
+        let outcome = probe.probe().await;
+        assert_eq!(outcome.resolved_ip, "::1".parse().ok());
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+    }
+
+    #[test]
+    fn unprobeable() {
+        let denied = || Err("Permission denied (os error 13)".to_string());
+        let support = SocketSupport {
+            ipv4: Ok(SocketType::Dgram),
+            ipv6: denied(),
+        };
+        let unprobeable = |target: &str| support.unprobeable(&target.parse().unwrap());
+        assert_eq!(unprobeable("1.1.1.1"), None);
+        assert_eq!(unprobeable("::1"), Some("Permission denied (os error 13)"));
+        assert_eq!(unprobeable("fe80::1%eth0"), unprobeable("::1"));
+        assert_eq!(
+            unprobeable("example.com"),
+            None,
+            "resolves to either family"
+        );
+        assert_eq!(unprobeable("tls://example.com"), None, "not an icmp target");
+
+        let support = SocketSupport {
+            ipv4: denied(),
+            ipv6: denied(),
+        };
+        assert!(support
+            .unprobeable(&"example.com".parse().unwrap())
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn socket_types() {
+        for socket in [SocketType::Dgram, SocketType::Raw] {
+            let options = SocketOptions {
+                socket,
+                ..Default::default()
+            };
+            let probe = IcmpProbe::open("127.0.0.1", options).unwrap();
+            let outcome = probe.probe().await;
+            assert!(outcome.rtt.is_ok(), "{socket:?}: {:?}", outcome.rtt);
+            let reply = outcome.reply.unwrap();
+            assert_eq!(reply.source, probe.ip);
+            assert!(reply.echo);
+            assert_eq!(reply.unexpected(probe.ip), None);
+            // Only raw sockets receive the IP header of replies.
+            let raw = c_int::from(probe.client.get_socket().get_type()) == SocketType::Raw.as_raw();
+            assert_eq!(reply.ttl.is_some(), raw, "{socket:?}");
+        }
+    }
+
+    #[test]
+    fn dscp_names() {
+        let dscp = |s: &str| s.parse::<Dscp>().map(Dscp::code);
+        assert_eq!(dscp("EF"), Ok(46));
+        assert_eq!(dscp("af41"), Ok(34));
+        assert_eq!(dscp("AF11"), Ok(10));
+        assert_eq!(dscp("CS6"), Ok(48));
+        assert_eq!(dscp("CS0"), Ok(0));
+        assert_eq!(dscp("26"), Ok(26));
+        for invalid in ["64", "AF51", "AF14", "CS8", "EFF", "", "X"] {
+            assert!(dscp(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn dscp() {
+        let metrics = Registry::new();
+        let config = IcmpConfig {
+            dscp: Some(Dscp::EF),
+            ..Default::default()
+        };
+        let probes = IcmpProbes::new(&config, &metrics).unwrap();
+        let probe = probes.probe(&"lo=127.0.0.1".parse().unwrap()).unwrap();
+        let outcome = probe.probe().await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+        assert_eq!(
+            probes.metrics.dscp.with_label_values(&["lo", "icmp"]).get(),
+            46
+        );
+
+        let unmarked = IcmpProbe::new("127.0.0.1").unwrap();
+        let marked = IcmpProbe::open("127.0.0.1", config.socket_options()).unwrap();
+        assert!(!Arc::ptr_eq(&unmarked.client, &marked.client));
+
+        drop(probe);
+        assert!(metrics
+            .gather()
+            .iter()
+            .all(|family| family.get_metric().is_empty()));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn mtu_discovery() {
+        let metrics = Registry::new();
+        let config = IcmpConfig {
+            mtu_discovery: true,
+            ..Default::default()
+        };
+        let probes = IcmpProbes::new(&config, &metrics).unwrap();
+        let probe = probes.probe(&"lo=127.0.0.1".parse().unwrap()).unwrap();
+        let outcome = probe.probe().await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+        // The path MTU is discovered in the background once the target has
+        // replied, and the loopback interface carries larger packets than
+        // are searched.
+        let path_mtu = || {
+            probes
+                .metrics
+                .path_mtu
+                .with_label_values(&["lo", "icmp"])
+                .get()
+        };
+        for _ in 0..100 {
+            if path_mtu() != 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(path_mtu(), MAX_MTU as i64);
+
+        drop(probe);
+        assert!(metrics
+            .gather()
+            .iter()
+            .all(|family| family.get_metric().is_empty()));
+    }
+
+    #[tokio::test]
+    async fn payload() {
+        let probe = IcmpProbe::new("127.0.0.1").unwrap().with_payload_size(8972);
+        let outcome = probe.probe().await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+    }
+}
@@ -0,0 +1,409 @@
+//! Client which sends echo requests from a socket of surge-ping, and matches
+//! their replies itself.
+//!
+//! surge-ping drops the replies which no ping is waiting for, so the
+//! replies of each ping are instead tracked for a while after it is answered
+//! or times out, to count those which are duplicated or arrive late.
+
+use std::{
+    collections::HashMap,
+    ffi::c_int,
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use surge_ping::{
+    AsyncSocket, Config, IcmpPacket, Icmpv4Packet, Icmpv6Packet, PingSequence, SurgeError,
+};
+use tokio::{
+    sync::oneshot,
+    task::{self, JoinHandle},
+    time,
+};
+use tracing::debug;
+
+use crate::metrics::IntCounter;
+
+/// Length of time the replies of a ping are still tracked after it is
+/// answered or times out, within which further replies are counted as
+/// duplicated or late.
+pub(super) const REPLY_WINDOW: Duration = Duration::from_secs(30);
+
+/// Counters of the replies of a target which are duplicated or late.
+#[derive(Clone)]
+pub(super) struct ReplyCounters {
+    pub(super) late: IntCounter,
+    pub(super) duplicate: IntCounter,
+}
+
+/// Socket which echo requests are sent from, whose replies are received by
+/// a task of its own.
+pub(super) struct Client {
+    socket: AsyncSocket,
+    replies: Arc<Mutex<Replies>>,
+    recv: JoinHandle<()>,
+}
+
+impl Client {
+    /// Open a socket of `config`, which falls back to the other type of
+    /// socket by itself.
+    pub(super) fn new(config: &Config) -> io::Result<Self> {
+        let socket = AsyncSocket::new(config)?;
+        let replies = Arc::new(Mutex::new(Replies::new(REPLY_WINDOW)));
+        let recv = task::spawn(receive(socket.clone(), replies.clone()));
+        Ok(Self {
+            socket,
+            replies,
+            recv,
+        })
+    }
+
+    pub(super) fn get_socket(&self) -> &AsyncSocket {
+        &self.socket
+    }
+
+    /// Send an echo request with `payload` to `host` and wait up to
+    /// `timeout` for its reply, counting its later replies into `counters`.
+    ///
+    /// Each ping has a sequence of its own, as the identifier of
+    /// unprivileged sockets is replaced by the kernel with that of the
+    /// socket.
+    pub(super) async fn ping(
+        &self,
+        host: IpAddr,
+        identifier: u16,
+        payload: &[u8],
+        timeout: Duration,
+        counters: Option<ReplyCounters>,
+    ) -> Result<(IcmpPacket, Duration), SurgeError> {
+        let sequence = next_sequence();
+        let token = Token {
+            host,
+            identifier: match_identifier(&self.socket, identifier),
+            sequence,
+        };
+        let reply = self.lock().wait(token, counters);
+        // The ping is no longer waited for if it is dropped, such as with
+        // its probe.
+        let mut waiting = Waiting {
+            replies: &self.replies,
+            token: Some(token),
+        };
+        let mut packet = echo_request(host, identifier, sequence, payload);
+        self.socket
+            .send_to(&mut packet, &SocketAddr::new(host, 0))
+            .await?;
+        let sent = Instant::now();
+        match time::timeout(timeout, reply).await {
+            Ok(Ok((packet, received))) => {
+                waiting.token = None;
+                Ok((packet, received.saturating_duration_since(sent)))
+            }
+            Ok(Err(_)) => Err(SurgeError::NetworkError),
+            Err(_) => {
+                waiting.token = None;
+                self.lock().timed_out(token);
+                Err(SurgeError::Timeout {
+                    seq: PingSequence(sequence),
+                })
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Replies> {
+        self.replies.lock().expect("replies aren't poisoned")
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.recv.abort();
+    }
+}
+
+/// Ping which stops being waited for once dropped, unless it completed.
+struct Waiting<'a> {
+    replies: &'a Mutex<Replies>,
+    token: Option<Token>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token {
+            self.replies
+                .lock()
+                .expect("replies aren't poisoned")
+                .cancel(token);
+        }
+    }
+}
+
+/// Identifier which the replies of `socket` are matched by, which is none
+/// where the kernel replaces it with that of the socket, as for
+/// unprivileged sockets on Linux.
+fn match_identifier(socket: &AsyncSocket, identifier: u16) -> Option<u16> {
+    let replaced = cfg!(any(target_os = "linux", target_os = "android"))
+        && c_int::from(socket.get_type()) == c_int::from(socket2::Type::DGRAM);
+    (!replaced).then_some(identifier)
+}
+
+/// Sequence of the next ping, which is unique across all probes so that
+/// those of the same address aren't told apart by their identifier alone.
+fn next_sequence() -> u16 {
+    static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+    NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Echo request to `host`, whose checksum of ICMPv6 and of unprivileged
+/// sockets is filled in by the kernel.
+fn echo_request(host: IpAddr, identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let kind = match host {
+        IpAddr::V4(_) => ECHO_REQUEST_V4,
+        IpAddr::V6(_) => ECHO_REQUEST_V6,
+    };
+    let mut packet = vec![kind, 0, 0, 0];
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(payload);
+    if host.is_ipv4() {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// Types of the echo requests of ICMP and ICMPv6.
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REQUEST_V6: u8 = 128;
+
+/// Internet checksum of `data`, the ones' complement of its ones'
+/// complement sum of 16 bit words.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Receive the packets of `socket`, handing replies to the pings waiting
+/// for them.
+async fn receive(socket: AsyncSocket, replies: Arc<Mutex<Replies>>) {
+    let mut buf = [0; 2048];
+    loop {
+        let Ok((size, addr)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let received = Instant::now();
+        let packet = match decode(&socket, &buf[..size], addr.ip()) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!(error = %e, "could not decode ICMP packet");
+                continue;
+            }
+        };
+        let token = Token {
+            host: addr.ip(),
+            identifier: match_identifier(&socket, packet.get_identifier().0),
+            sequence: packet.get_sequence().0,
+        };
+        let mut replies = replies.lock().expect("replies aren't poisoned");
+        if let Some(waiter) = replies.received(token) {
+            // The ping has stopped waiting if it can't be sent to.
+            let _ = waiter.send((packet, received));
+        }
+    }
+}
+
+/// Decode a packet received from `source`.
+fn decode(socket: &AsyncSocket, message: &[u8], source: IpAddr) -> Result<IcmpPacket, SurgeError> {
+    match source {
+        IpAddr::V4(source) => {
+            let IpAddr::V4(local) = socket.local_addr()?.ip() else {
+                return Err(SurgeError::NetworkError);
+            };
+            Icmpv4Packet::decode(message, socket.get_type(), source, local).map(IcmpPacket::V4)
+        }
+        IpAddr::V6(source) => Icmpv6Packet::decode(message, source).map(IcmpPacket::V6),
+    }
+}
+
+/// Address, identifier and sequence which a reply is matched to its ping by,
+/// without an identifier where the kernel replaces it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Token {
+    host: IpAddr,
+    identifier: Option<u16>,
+    sequence: u16,
+}
+
+type Waiter = oneshot::Sender<(IcmpPacket, Instant)>;
+
+/// Pings which are waiting for their reply, and those which were answered
+/// or timed out recently.
+struct Replies {
+    waiting: HashMap<Token, (Waiter, Option<ReplyCounters>)>,
+    /// Pings which completed within the current and previous window, and
+    /// whether they were answered, which are kept for between one and two
+    /// windows.
+    completed: [HashMap<Token, (bool, Option<ReplyCounters>)>; 2],
+    window: Duration,
+    rotated: Instant,
+}
+
+impl Replies {
+    fn new(window: Duration) -> Self {
+        Self {
+            waiting: HashMap::new(),
+            completed: Default::default(),
+            window,
+            rotated: Instant::now(),
+        }
+    }
+
+    /// Wait for the reply of the ping of `token`.
+    fn wait(
+        &mut self,
+        token: Token,
+        counters: Option<ReplyCounters>,
+    ) -> oneshot::Receiver<(IcmpPacket, Instant)> {
+        let (tx, rx) = oneshot::channel();
+        self.waiting.insert(token, (tx, counters));
+        rx
+    }
+
+    /// Stop waiting for the reply of a ping which timed out, counting a
+    /// reply which arrives later as late.
+    fn timed_out(&mut self, token: Token) {
+        if let Some((_, counters)) = self.waiting.remove(&token) {
+            self.complete(token, false, counters);
+        }
+    }
+
+    /// Stop waiting for the reply of a ping, without counting its replies.
+    fn cancel(&mut self, token: Token) {
+        self.waiting.remove(&token);
+    }
+
+    /// Record the reply of `token`, returning the ping waiting for it, or
+    /// counting it if its ping already completed.
+    fn received(&mut self, token: Token) -> Option<Waiter> {
+        if let Some((waiter, counters)) = self.waiting.remove(&token) {
+            self.complete(token, true, counters);
+            return Some(waiter);
+        }
+        let (answered, counters) = self
+            .completed
+            .iter_mut()
+            .find_map(|completed| completed.get_mut(&token))?;
+        if let Some(counters) = counters {
+            match answered {
+                true => counters.duplicate.inc(),
+                false => counters.late.inc(),
+            }
+        }
+        // Further replies of a ping which was answered late are duplicates.
+        *answered = true;
+        None
+    }
+
+    fn complete(&mut self, token: Token, answered: bool, counters: Option<ReplyCounters>) {
+        if self.rotated.elapsed() >= self.window {
+            self.completed[1] = mem::take(&mut self.completed[0]);
+            self.rotated = Instant::now();
+        }
+        self.completed[1].remove(&token);
+        self.completed[0].insert(token, (answered, counters));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "metrics")]
+    use std::{net::IpAddr, time::Duration};
+
+    #[cfg(feature = "metrics")]
+    use prometheus::IntCounter;
+
+    use super::{checksum, echo_request};
+    #[cfg(feature = "metrics")]
+    use super::{Replies, ReplyCounters, Token};
+
+    #[test]
+    fn checksums() {
+        // Echo request of identifier 1 and sequence 1 without a payload.
+        let packet = echo_request("127.0.0.1".parse().unwrap(), 1, 1, &[]);
+        assert_eq!(packet, [8, 0, 0xf7, 0xfd, 0, 1, 0, 1]);
+        assert_eq!(checksum(&packet), 0);
+        assert_eq!(checksum(&[0xff]), 0x00ff);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn late_and_duplicate_replies() {
+        let counters = ReplyCounters {
+            late: IntCounter::new("late", "late").unwrap(),
+            duplicate: IntCounter::new("duplicate", "duplicate").unwrap(),
+        };
+        let mut replies = Replies::new(Duration::from_secs(30));
+        let host: IpAddr = "127.0.0.1".parse().unwrap();
+        let token = |sequence| Token {
+            host,
+            identifier: None,
+            sequence,
+        };
+
+        let _answered = replies.wait(token(0), Some(counters.clone()));
+        assert!(replies.received(token(0)).is_some());
+        assert!(replies.received(token(0)).is_none());
+        assert_eq!(counters.duplicate.get(), 1);
+
+        let _lost = replies.wait(token(1), Some(counters.clone()));
+        replies.timed_out(token(1));
+        assert!(replies.received(token(1)).is_none());
+        assert_eq!(counters.late.get(), 1);
+        // A copy of a late reply is a duplicate.
+        assert!(replies.received(token(1)).is_none());
+        assert_eq!(counters.duplicate.get(), 2);
+
+        // Replies of pings which were never sent aren't counted.
+        assert!(replies.received(token(2)).is_none());
+        let _cancelled = replies.wait(token(3), Some(counters.clone()));
+        replies.cancel(token(3));
+        assert!(replies.received(token(3)).is_none());
+        assert_eq!((counters.late.get(), counters.duplicate.get()), (1, 2));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn window() {
+        let counters = ReplyCounters {
+            late: IntCounter::new("late", "late").unwrap(),
+            duplicate: IntCounter::new("duplicate", "duplicate").unwrap(),
+        };
+        let mut replies = Replies::new(Duration::ZERO);
+        let token = |sequence| Token {
+            host: "127.0.0.1".parse().unwrap(),
+            identifier: Some(1),
+            sequence,
+        };
+        for sequence in 0..3 {
+            let _reply = replies.wait(token(sequence), Some(counters.clone()));
+            replies.received(token(sequence));
+        }
+        // Each completion rotates the window, so only the latest two are kept.
+        assert!(replies.received(token(0)).is_none());
+        assert_eq!(counters.duplicate.get(), 0);
+        replies.received(token(1));
+        replies.received(token(2));
+        assert_eq!(counters.duplicate.get(), 2);
+    }
+}
@@ -0,0 +1,431 @@
+//! Probes of SMTP and IMAP servers, which complete the greeting exchange
+//! of the protocol, and optionally upgrade the connection with STARTTLS,
+//! without authenticating or sending mail.
+//!
+//! The round-trip time of the probe is the time taken to connect and
+//! receive the greeting, while the rest of the exchange must complete within
+//! the timeout. A reply other than the one expected at each step, such as a
+//! `554` greeting of an SMTP relay which refuses service, fails the probe as
+//! [`ErrorKind::Unexpected`].
+
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+use super::{tls::TlsProbes, Probe, ProbeOutcome};
+use crate::{
+    target::{ProbeTarget, Scheme},
+    ErrorKind, PingError, Result, UppiesError,
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MailConfig {
+    /// Upgrade the connection with STARTTLS after the greeting, verifying
+    /// the certificate of the server as for `tls://` targets.
+    #[serde(default)]
+    pub starttls: bool,
+    /// Domain which SMTP servers are greeted with by `EHLO`.
+    #[serde(default = "MailConfig::default_ehlo_domain")]
+    pub ehlo_domain: String,
+    /// Length of time to complete the exchange within.
+    #[serde(default = "MailConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl MailConfig {
+    fn default_ehlo_domain() -> String {
+        "localhost".to_string()
+    }
+
+    fn default_timeout_ms() -> u64 {
+        5000
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            starttls: false,
+            ehlo_domain: Self::default_ehlo_domain(),
+            timeout_ms: Self::default_timeout_ms(),
+        }
+    }
+}
+
+/// Builds the [`MailProbe`] of each `smtp` and `imap` target.
+#[derive(Clone)]
+pub struct MailProbes {
+    config: MailConfig,
+    /// Connector of STARTTLS, if enabled.
+    connector: Option<TlsConnector>,
+}
+
+impl MailProbes {
+    /// Probes which trust the certificates of `tls` for STARTTLS.
+    pub fn new(config: &MailConfig, tls: &TlsProbes) -> Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            connector: config.starttls.then(|| tls.connector()).transpose()?,
+        })
+    }
+
+    /// Probe of the `smtp` or `imap` target `target`.
+    pub fn probe(&self, target: &ProbeTarget) -> Result<MailProbe> {
+        if !matches!(target.scheme(), Scheme::Smtp | Scheme::Imap) {
+            return Err(UppiesError::InvalidTarget(format!(
+                "'{target}' is not an smtp or imap target"
+            )));
+        }
+        let starttls = match &self.connector {
+            Some(connector) => Some((
+                connector.clone(),
+                ServerName::try_from(target.host().to_string())?,
+            )),
+            None => None,
+        };
+        Ok(MailProbe {
+            scheme: target.scheme(),
+            host: target.host().to_string(),
+            port: target.port().expect("mail targets have a port"),
+            ehlo_domain: self.config.ehlo_domain.clone(),
+            starttls,
+            timeout: Duration::from_millis(self.config.timeout_ms),
+        })
+    }
+}
+
+/// Probe which completes the greeting exchange of an SMTP or IMAP server.
+pub struct MailProbe {
+    /// Either [`Scheme::Smtp`] or [`Scheme::Imap`].
+    scheme: Scheme,
+    host: String,
+    port: u16,
+    ehlo_domain: String,
+    /// Connector and name of the server to upgrade the connection with.
+    starttls: Option<(TlsConnector, ServerName<'static>)>,
+    timeout: Duration,
+}
+
+type Reader = BufReader<TcpStream>;
+
+impl MailProbe {
+    /// Length which each line of a reply is limited to, well above the 512
+    /// bytes of SMTP.
+    const MAX_LINE: u64 = 4096;
+
+    /// Complete the exchange, returning the address of the server and the
+    /// time taken to receive the greeting.
+    async fn exchange(
+        &self,
+        start: Instant,
+    ) -> std::result::Result<(Option<IpAddr>, Duration), PingError> {
+        let tcp = super::connect(&self.host, self.port).await?;
+        let resolved_ip = tcp.peer_addr().ok().map(|addr| addr.ip());
+        let mut reader = BufReader::new(tcp);
+        let greeting = match self.scheme {
+            Scheme::Smtp => self.smtp(&mut reader, start).await?,
+            _ => self.imap(&mut reader, start).await?,
+        };
+        if let Some((connector, server_name)) = &self.starttls {
+            connector
+                .connect(server_name.clone(), reader.into_inner())
+                .await
+                .map_err(|e| PingError {
+                    kind: ErrorKind::Tls,
+                    message: e.to_string(),
+                })?;
+        }
+        Ok((resolved_ip, greeting))
+    }
+
+    /// Receive the greeting and reply to `EHLO`, before either `STARTTLS`
+    /// or `QUIT`.
+    async fn smtp(
+        &self,
+        reader: &mut Reader,
+        start: Instant,
+    ) -> std::result::Result<Duration, PingError> {
+        smtp_reply(reader, "220").await?;
+        let greeting = start.elapsed();
+        write(reader, &format!("EHLO {}\r\n", self.ehlo_domain)).await?;
+        smtp_reply(reader, "250").await?;
+        if self.starttls.is_some() {
+            write(reader, "STARTTLS\r\n").await?;
+            smtp_reply(reader, "220").await?;
+        } else {
+            let _ = write(reader, "QUIT\r\n").await;
+        }
+        Ok(greeting)
+    }
+
+    /// Receive the greeting, before either `STARTTLS` or `LOGOUT`.
+    async fn imap(
+        &self,
+        reader: &mut Reader,
+        start: Instant,
+    ) -> std::result::Result<Duration, PingError> {
+        let mut line = String::new();
+        read_line(reader, &mut line).await?;
+        if !line.starts_with("* OK") && !line.starts_with("* PREAUTH") {
+            return Err(unexpected(&line));
+        }
+        let greeting = start.elapsed();
+        if self.starttls.is_some() {
+            write(reader, "a1 STARTTLS\r\n").await?;
+            // Untagged responses may precede the completion of the command.
+            loop {
+                read_line(reader, &mut line).await?;
+                if line.starts_with("a1 ") {
+                    break;
+                }
+            }
+            if !line.starts_with("a1 OK") {
+                return Err(unexpected(&line));
+            }
+        } else {
+            let _ = write(reader, "a1 LOGOUT\r\n").await;
+        }
+        Ok(greeting)
+    }
+}
+
+impl Probe for MailProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(self.timeout, self.exchange(start)).await {
+            Ok(result) => result,
+            Err(_) => Err(PingError {
+                kind: ErrorKind::Timeout,
+                message: format!("timed out completing the {} exchange", self.scheme.as_str()),
+            }),
+        };
+        match result {
+            Ok((resolved_ip, rtt)) => ProbeOutcome {
+                resolved_ip,
+                reply: None,
+                rtt: Ok(rtt),
+            },
+            Err(e) => ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Err(e),
+            },
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Read an SMTP reply, which has a line for each continuation, failing
+/// unless it has the code `expected`.
+async fn smtp_reply(reader: &mut Reader, expected: &str) -> std::result::Result<(), PingError> {
+    let mut line = String::new();
+    loop {
+        read_line(reader, &mut line).await?;
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+    }
+    if line.get(..3) != Some(expected) {
+        return Err(unexpected(&line));
+    }
+    Ok(())
+}
+
+/// Read a single line into `line`, without its line ending.
+async fn read_line(reader: &mut Reader, line: &mut String) -> std::result::Result<(), PingError> {
+    line.clear();
+    let read = reader
+        .take(MailProbe::MAX_LINE)
+        .read_line(line)
+        .await
+        .map_err(|e| PingError {
+            kind: ErrorKind::Malformed,
+            message: e.to_string(),
+        })?;
+    if read == 0 {
+        return Err(PingError {
+            kind: ErrorKind::Unexpected,
+            message: "connection closed before the reply".to_string(),
+        });
+    }
+    if !line.ends_with('\n') {
+        return Err(PingError {
+            kind: ErrorKind::Malformed,
+            message: "reply line is too long".to_string(),
+        });
+    }
+    line.truncate(line.trim_end().len());
+    Ok(())
+}
+
+async fn write(reader: &mut Reader, command: &str) -> std::result::Result<(), PingError> {
+    reader
+        .get_mut()
+        .write_all(command.as_bytes())
+        .await
+        .map_err(|e| PingError {
+            kind: ErrorKind::Io,
+            message: e.to_string(),
+        })
+}
+
+fn unexpected(line: &str) -> PingError {
+    PingError {
+        kind: ErrorKind::Unexpected,
+        message: format!("unexpected reply '{line}'"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use prometheus::Registry;
+    use rcgen::{CertificateParams, KeyPair};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+    use tokio_rustls::{
+        rustls::{
+            crypto::aws_lc_rs,
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+            RootCertStore, ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    use super::{MailConfig, MailProbes};
+    use crate::{
+        probe::{tls::TlsProbes, Probe, ProbeOutcome},
+        ErrorKind,
+    };
+
+    /// Serve a scripted exchange, sending `greeting` and then the reply of
+    /// each command, and accepting TLS after `STARTTLS`
+    /// with a certificate for `localhost`.
+    async fn server(
+        greeting: &'static str,
+        replies: &'static [(&'static str, &'static str)],
+    ) -> (CertificateDer<'static>, SocketAddr) {
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        let cert = params.self_signed(&key).unwrap().der().clone();
+        let config = ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(tcp);
+                    reader.get_mut().write_all(greeting.as_bytes()).await?;
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).await? > 0 {
+                        // Commands of IMAP follow a tag, such as `a1 STARTTLS`.
+                        let words: Vec<_> = line.split_whitespace().collect();
+                        let reply = replies.iter().find(|(c, _)| words.contains(c));
+                        if let Some((_, reply)) = reply {
+                            reader.get_mut().write_all(reply.as_bytes()).await?;
+                        }
+                        if words.contains(&"STARTTLS") {
+                            acceptor.accept(reader.into_inner()).await?;
+                            break;
+                        }
+                        line.clear();
+                    }
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+        (cert, addr)
+    }
+
+    async fn probe(
+        scheme: &str,
+        (cert, addr): (CertificateDer<'static>, SocketAddr),
+        starttls: bool,
+    ) -> ProbeOutcome {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let tls = TlsProbes::with_roots(roots, Duration::from_secs(1), &Registry::new()).unwrap();
+        let config = MailConfig {
+            starttls,
+            timeout_ms: 1000,
+            ..Default::default()
+        };
+        let target = format!("{scheme}://localhost:{}", addr.port())
+            .parse()
+            .unwrap();
+        let probes = MailProbes::new(&config, &tls).unwrap();
+        probes.probe(&target).unwrap().probe().await
+    }
+
+    const SMTP: &[(&str, &str)] = &[
+        (
+            "EHLO",
+            "250-mail.example.com\r\n250-STARTTLS\r\n250 SIZE 1024\r\n",
+        ),
+        ("STARTTLS", "220 ready to start TLS\r\n"),
+    ];
+
+    #[tokio::test]
+    async fn smtp() {
+        let served = server("220 mail.example.com ESMTP\r\n", SMTP).await;
+        let outcome = probe("smtp", served.clone(), false).await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+        assert_eq!(outcome.resolved_ip, Some(served.1.ip()));
+        let outcome = probe("smtp", served, true).await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+
+        let served = server("554 no service\r\n", SMTP).await;
+        let e = probe("smtp", served, false).await.rtt.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unexpected);
+        assert_eq!(e.message, "unexpected reply '554 no service'");
+    }
+
+    #[tokio::test]
+    async fn imap() {
+        const IMAP: &[(&str, &str)] = &[(
+            "STARTTLS",
+            "* OK still here\r\na1 OK begin TLS negotiation now\r\n",
+        )];
+        let served = server("* OK IMAP4rev1 ready\r\n", IMAP).await;
+        assert!(probe("imap", served.clone(), false).await.rtt.is_ok());
+        let outcome = probe("imap", served, true).await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+
+        let served = server("* BYE too many connections\r\n", IMAP).await;
+        let e = probe("imap", served, false).await.rtt.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unexpected);
+    }
+
+    #[tokio::test]
+    async fn untrusted_starttls() {
+        let (_, addr) = server("220 mail.example.com ESMTP\r\n", SMTP).await;
+        let (other, _) = server("220 other.example.com ESMTP\r\n", SMTP).await;
+        let e = probe("smtp", (other, addr), true).await.rtt.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Tls);
+    }
+}
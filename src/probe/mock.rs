@@ -0,0 +1,112 @@
+//! Probe which returns scripted outcomes, for deterministic tests without
+//! ICMP sockets or root.
+
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use super::{Probe, ProbeOutcome};
+use crate::{ErrorKind, PingError};
+
+/// Probe which returns a script of round-trip times and failures in order,
+/// repeating it once exhausted.
+///
+/// Outcomes are returned immediately, the scripted round-trip time is only
+/// reported rather than waited for. It stands in for the probe of its
+/// target, so is labelled by the [`probe`](crate::ProbeTarget::probe) of
+/// the target rather than a name of its own.
+///
+/// ```
+/// use std::time::Duration;
+/// use uppies::{probe::MockProbe, ErrorKind, PingSender};
+///
+/// let sender = PingSender::builder()
+///     .with_ping_interval_ms(100)
+///     .build()?
+///     .with_probe(
+///         "flappy".parse()?,
+///         MockProbe::new([Ok(Duration::from_millis(5)), Err(ErrorKind::Timeout)]),
+///     );
+/// # Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+pub struct MockProbe {
+    script: Vec<Result<Duration, ErrorKind>>,
+    resolved_ip: Option<IpAddr>,
+    /// Number of probes so far, which is also the position in the script.
+    probes: AtomicUsize,
+}
+
+impl MockProbe {
+    /// Create a probe which returns the `script` of outcomes.
+    ///
+    /// # Panics
+    ///
+    /// If the script is empty.
+    pub fn new(script: impl IntoIterator<Item = Result<Duration, ErrorKind>>) -> Self {
+        let script: Vec<_> = script.into_iter().collect();
+        assert!(!script.is_empty(), "mock probe script must not be empty");
+        Self {
+            script,
+            resolved_ip: None,
+            probes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Report the given address as the one which was probed.
+    pub fn with_resolved_ip(mut self, ip: IpAddr) -> Self {
+        self.resolved_ip = Some(ip);
+        self
+    }
+
+    /// Number of times the target has been probed.
+    pub fn probes(&self) -> usize {
+        self.probes.load(Ordering::Relaxed)
+    }
+}
+
+impl Probe for MockProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let i = self.probes.fetch_add(1, Ordering::Relaxed);
+        let rtt = self.script[i % self.script.len()].map_err(|kind| PingError {
+            kind,
+            message: format!("scripted {} failure", kind.as_str()),
+        });
+        ProbeOutcome {
+            resolved_ip: self.resolved_ip,
+            reply: None,
+            rtt,
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::MockProbe;
+    use crate::{probe::Probe, ErrorKind};
+
+    #[tokio::test]
+    async fn repeats_script() {
+        let probe = MockProbe::new([Ok(Duration::from_millis(1)), Err(ErrorKind::Timeout)]);
+        let mut rtts = Vec::new();
+        for _ in 0..3 {
+            rtts.push(probe.probe().await.rtt.map_err(|e| e.kind));
+        }
+        assert_eq!(
+            rtts,
+            [
+                Ok(Duration::from_millis(1)),
+                Err(ErrorKind::Timeout),
+                Ok(Duration::from_millis(1))
+            ]
+        );
+        assert_eq!(probe.probes(), 3);
+    }
+}
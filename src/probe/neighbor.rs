@@ -0,0 +1,210 @@
+//! Verification of the neighbor (ARP) entry of LAN targets, so that layer 2
+//! problems aren't reported as packet loss.
+//!
+//! When a probe of the target fails, the kernel's neighbor table is checked.
+//! If the target has no resolved MAC address, the failure is
+//! reported as [`ErrorKind::Neighbor`] rather than the error of the
+//! underlying probe. With an expected MAC address, replies are also only
+//! accepted from that device.
+//!
+//! Only IPv4 targets on Linux are supported, as the table is read from
+//! `/proc/net/arp`.
+
+use std::{net::Ipv4Addr, path::PathBuf, process::Command};
+
+use serde::Deserialize;
+
+use super::{Probe, ProbeOutcome};
+use crate::{ErrorKind, PingError, Result, UppiesError};
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct NeighborConfig {
+    /// Expected MAC address of the target, such as `aa:bb:cc:dd:ee:ff`.
+    pub mac: Option<String>,
+    /// Interface which the target is reachable through, required to pin
+    /// its entry.
+    pub interface: Option<String>,
+    /// Pin the neighbor entry to `mac` as permanent on startup, with
+    /// `ip neigh replace`. This requires `CAP_NET_ADMIN`.
+    #[serde(default)]
+    pub pin: bool,
+}
+
+/// Probe which checks the neighbor entry of the target alongside an
+/// `inner` probe, typically an [`IcmpProbe`](super::IcmpProbe).
+pub struct NeighborProbe<P> {
+    inner: P,
+    ip: Ipv4Addr,
+    /// Expected MAC address, in lowercase.
+    mac: Option<String>,
+    /// Neighbor table in the format of `/proc/net/arp`.
+    table: PathBuf,
+}
+
+/// Entry has been resolved, see `ATF_COM` in `if_arp.h`.
+const ATF_COM: u32 = 0x2;
+
+impl<P: Probe> NeighborProbe<P> {
+    pub fn new(inner: P, target: &str, config: &NeighborConfig) -> Result<Self> {
+        let ip: Ipv4Addr = target.parse().map_err(|_| {
+            UppiesError::InvalidTarget(format!(
+                "neighbor checks require an IPv4 target, not '{target}'"
+            ))
+        })?;
+        let mac = config.mac.as_ref().map(|mac| mac.to_lowercase());
+        if config.pin {
+            let (Some(mac), Some(interface)) = (&mac, &config.interface) else {
+                return Err(format!(
+                    "pinning the neighbor of {target} requires 'mac' and 'interface'"
+                )
+                .into());
+            };
+            pin(ip, mac, interface)?;
+        }
+        Ok(Self {
+            inner,
+            ip,
+            mac,
+            table: PathBuf::from("/proc/net/arp"),
+        })
+    }
+
+    /// Problem with the neighbor entry of the target, if any.
+    async fn check(&self) -> Option<String> {
+        let table = match tokio::fs::read_to_string(&self.table).await {
+            Ok(table) => table,
+            Err(e) => return Some(format!("failed to read neighbor table: {e}")),
+        };
+        let entry = table.lines().skip(1).find_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [ip, _, flags, mac, ..] if ip.parse() == Ok(self.ip) => Some((*flags, *mac)),
+                _ => None,
+            }
+        });
+        let Some((flags, mac)) = entry else {
+            return Some(format!("no neighbor entry for {}", self.ip));
+        };
+        let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).unwrap_or(0);
+        if flags & ATF_COM == 0 {
+            return Some(format!("neighbor entry for {} is incomplete", self.ip));
+        }
+        match &self.mac {
+            Some(expected) if !mac.eq_ignore_ascii_case(expected) => Some(format!(
+                "neighbor entry for {} is {mac}, expected {expected}",
+                self.ip
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Replace the neighbor entry of `ip` with a permanent one.
+fn pin(ip: Ipv4Addr, mac: &str, interface: &str) -> Result<()> {
+    let ip = ip.to_string();
+    let output = Command::new("ip")
+        .args(["neigh", "replace", &ip, "lladdr", mac, "dev", interface])
+        .args(["nud", "permanent"])
+        .output()
+        .map_err(|e| format!("failed to run 'ip neigh replace': {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "failed to pin neighbor {ip}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+impl<P: Probe> Probe for NeighborProbe<P> {
+    async fn probe(&self) -> ProbeOutcome {
+        let mut outcome = self.inner.probe().await;
+        // Resolution only needs to be explained for failures, whereas a
+        // reply from the wrong device should never count as a success.
+        if outcome.rtt.is_err() || self.mac.is_some() {
+            if let Some(message) = self.check().await {
+                outcome.rtt = Err(PingError {
+                    kind: ErrorKind::Neighbor,
+                    message,
+                });
+            }
+        }
+        outcome
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{NeighborConfig, NeighborProbe};
+    use crate::{
+        probe::{MockProbe, Probe},
+        ErrorKind,
+    };
+
+    const TABLE: &str = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         aa:bb:cc:dd:ee:ff     *        eth0
+192.168.1.2      0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+
+    async fn kind(target: &str, mac: Option<&str>, ok: bool) -> Option<ErrorKind> {
+        let dir = tempfile::tempdir().unwrap();
+        let table = dir.path().join("arp");
+        std::fs::write(&table, TABLE).unwrap();
+        let inner = MockProbe::new([if ok {
+            Ok(Duration::from_millis(1))
+        } else {
+            Err(ErrorKind::Timeout)
+        }]);
+        let config = NeighborConfig {
+            mac: mac.map(str::to_string),
+            ..Default::default()
+        };
+        let mut probe = NeighborProbe::new(inner, target, &config).unwrap();
+        probe.table = table;
+        probe.probe().await.rtt.err().map(|e| e.kind)
+    }
+
+    #[tokio::test]
+    async fn classify_failures() {
+        assert_eq!(
+            kind("192.168.1.1", None, false).await,
+            Some(ErrorKind::Timeout)
+        );
+        assert_eq!(
+            kind("192.168.1.2", None, false).await,
+            Some(ErrorKind::Neighbor)
+        );
+        assert_eq!(
+            kind("192.168.1.3", None, false).await,
+            Some(ErrorKind::Neighbor)
+        );
+        assert_eq!(kind("192.168.1.2", None, true).await, None);
+    }
+
+    #[tokio::test]
+    async fn expected_mac() {
+        assert_eq!(
+            kind("192.168.1.1", Some("AA:BB:CC:DD:EE:FF"), true).await,
+            None
+        );
+        assert_eq!(
+            kind("192.168.1.1", Some("11:22:33:44:55:66"), true).await,
+            Some(ErrorKind::Neighbor)
+        );
+    }
+
+    #[test]
+    fn requires_ipv4() {
+        let inner = MockProbe::new([Ok(Duration::ZERO)]);
+        assert!(NeighborProbe::new(inner, "::1", &NeighborConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Probe of an NTP server, which sends a single client request (as SNTP
+//! does) and measures the offset of the local clock from that of the server.
+//!
+//! The round-trip time of the probe is the delay of the exchange, excluding
+//! the time taken by the server to reply, and the offset is exported as
+//! `ntp_offset_seconds`. A server which replies with a kiss-o'-death, such as
+//! to rate limit the probe, fails it as [`ErrorKind::Unexpected`].
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{GaugeVec, Opts, Registry};
+use serde::Deserialize;
+use tokio::net::{lookup_host, UdpSocket};
+
+use super::{Probe, ProbeOutcome};
+use crate::{
+    target::{ProbeTarget, Scheme},
+    ErrorKind, PingError, Result, UppiesError,
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct NtpConfig {
+    /// Length of time to receive the reply of the server within.
+    #[serde(default = "NtpConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl NtpConfig {
+    fn default_timeout_ms() -> u64 {
+        5000
+    }
+}
+
+impl Default for NtpConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: Self::default_timeout_ms(),
+        }
+    }
+}
+
+/// Builds the [`NtpProbe`] of each `ntp` target, sharing the metrics
+/// between them.
+#[derive(Clone)]
+pub struct NtpProbes {
+    timeout: Duration,
+    /// Offset of the local clock from that of each target.
+    offset: GaugeVec,
+}
+
+impl NtpProbes {
+    pub fn new(config: &NtpConfig, metrics: &Registry) -> Result<Self> {
+        let offset = GaugeVec::new(
+            Opts::new(
+                "ntp_offset_seconds",
+                "Offset of the clock of the target from the local clock",
+            ),
+            &["target", "probe"],
+        )?;
+        metrics.register(Box::new(offset.clone()))?;
+        Ok(Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            offset,
+        })
+    }
+
+    /// Probes of `config`, sharing the metrics of these, such as for a
+    /// module.
+    pub fn with_config(&self, config: &NtpConfig) -> Self {
+        Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            offset: self.offset.clone(),
+        }
+    }
+
+    /// Probe of the `ntp` target `target`.
+    pub fn probe(&self, target: &ProbeTarget) -> Result<NtpProbe> {
+        if target.scheme() != Scheme::Ntp {
+            return Err(UppiesError::InvalidTarget(format!(
+                "'{target}' is not an ntp target"
+            )));
+        }
+        Ok(NtpProbe {
+            host: target.host().to_string(),
+            port: target.port().expect("ntp targets have a port"),
+            timeout: self.timeout,
+            labels: [target.label(), target.probe().to_string()],
+            offset: self.offset.clone(),
+        })
+    }
+}
+
+/// Probe which measures the offset of the local clock from an NTP server.
+pub struct NtpProbe {
+    host: String,
+    port: u16,
+    timeout: Duration,
+    /// Labels of the target and probe within `offset`.
+    labels: [String; 2],
+    offset: GaugeVec,
+}
+
+/// Seconds between the NTP epoch of 1900 and the Unix epoch.
+const NTP_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+/// Current time as an NTP timestamp, in seconds and fractions of a second
+/// of 2^-32.
+fn ntp_now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let fraction = (u64::from(now.subsec_nanos()) << 32) / 1_000_000_000;
+    ((now.as_secs() + NTP_EPOCH_OFFSET) << 32) | fraction
+}
+
+/// Seconds between the NTP timestamps `from` and `to`, which may be
+/// negative.
+fn seconds_between(from: u64, to: u64) -> f64 {
+    to.wrapping_sub(from) as i64 as f64 / (1u64 << 32) as f64
+}
+
+impl NtpProbe {
+    /// Length of the header of a packet, which is all of a client request.
+    const PACKET_LEN: usize = 48;
+
+    /// Exchange a request with the server, returning its address, the delay
+    /// of the exchange and the offset, in seconds.
+    async fn exchange(&self) -> std::result::Result<(IpAddr, f64, f64), PingError> {
+        let addr = lookup_host((self.host.as_str(), self.port))
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| PingError {
+                kind: ErrorKind::Resolution,
+                message: format!("failed to resolve {}", self.host),
+            })?;
+        let io = |e: std::io::Error| PingError {
+            kind: ErrorKind::Io,
+            message: e.to_string(),
+        };
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await.map_err(io)?;
+        socket.connect(addr).await.map_err(io)?;
+
+        let mut request = [0u8; Self::PACKET_LEN];
+        // No leap second warning, version 4 and the client mode.
+        request[0] = (4 << 3) | 3;
+        let sent = ntp_now();
+        request[40..48].copy_from_slice(&sent.to_be_bytes());
+        socket.send(&request).await.map_err(io)?;
+
+        let mut reply = [0u8; 1024];
+        loop {
+            let len = socket.recv(&mut reply).await.map_err(io)?;
+            let received = ntp_now();
+            if len < Self::PACKET_LEN {
+                return Err(PingError {
+                    kind: ErrorKind::Malformed,
+                    message: format!("reply of {len} bytes is too short"),
+                });
+            }
+            let timestamp =
+                |i: usize| u64::from_be_bytes(reply[i..i + 8].try_into().expect("8 bytes"));
+            // Replies to earlier requests which timed out are ignored.
+            if timestamp(24) != sent {
+                continue;
+            }
+            if reply[0] & 0b111 != 4 {
+                return Err(PingError {
+                    kind: ErrorKind::Unexpected,
+                    message: format!("reply has mode {}, expected server", reply[0] & 0b111),
+                });
+            }
+            if reply[1] == 0 {
+                let code = String::from_utf8_lossy(&reply[12..16]);
+                return Err(PingError {
+                    kind: ErrorKind::Unexpected,
+                    message: format!("kiss-o'-death '{}'", code.trim_end_matches('\0')),
+                });
+            }
+            let (server_received, server_sent) = (timestamp(32), timestamp(40));
+            let offset = (seconds_between(sent, server_received)
+                + seconds_between(received, server_sent))
+                / 2.0;
+            let delay =
+                seconds_between(sent, received) - seconds_between(server_received, server_sent);
+            return Ok((addr.ip(), delay.max(0.0), offset));
+        }
+    }
+}
+
+impl Probe for NtpProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let result = match tokio::time::timeout(self.timeout, self.exchange()).await {
+            Ok(result) => result,
+            Err(_) => Err(PingError {
+                kind: ErrorKind::Timeout,
+                message: "timed out waiting for the NTP reply".to_string(),
+            }),
+        };
+        match result {
+            Ok((ip, delay, offset)) => {
+                self.offset.with_label_values(&self.labels).set(offset);
+                ProbeOutcome {
+                    resolved_ip: Some(ip),
+                    reply: None,
+                    rtt: Ok(Duration::from_secs_f64(delay)),
+                }
+            }
+            Err(e) => ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Err(e),
+            },
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The series of a target which is no longer probed is removed.
+impl Drop for NtpProbe {
+    fn drop(&mut self) {
+        let _ = self.offset.remove_label_values(&self.labels);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use prometheus::Registry;
+    use tokio::net::UdpSocket;
+
+    use super::{ntp_now, NtpConfig, NtpProbes};
+    use crate::{probe::Probe, ErrorKind};
+
+    /// Serve replies from a clock `skew` seconds ahead, with `stratum`.
+    async fn server(skew: u64, stratum: u8) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut request = [0u8; 48];
+            while let Ok((_, peer)) = socket.recv_from(&mut request).await {
+                let mut reply = [0u8; 48];
+                reply[0] = (4 << 3) | 4;
+                reply[1] = stratum;
+                reply[12..16].copy_from_slice(b"RATE");
+                reply[24..32].copy_from_slice(&request[40..48]);
+                let now = ntp_now() + (skew << 32);
+                reply[32..40].copy_from_slice(&now.to_be_bytes());
+                reply[40..48].copy_from_slice(&now.to_be_bytes());
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+        addr
+    }
+
+    fn offset(metrics: &Registry) -> Option<f64> {
+        let family = metrics
+            .gather()
+            .into_iter()
+            .find(|m| m.name() == "ntp_offset_seconds")?;
+        Some(family.get_metric().first()?.get_gauge().value())
+    }
+
+    #[tokio::test]
+    async fn offset_and_delay() {
+        let addr = server(10, 2).await;
+        let metrics = Registry::new();
+        let config = NtpConfig { timeout_ms: 1000 };
+        let probes = NtpProbes::new(&config, &metrics).unwrap();
+        let target = format!("ntp://127.0.0.1:{}", addr.port()).parse().unwrap();
+        let probe = probes.probe(&target).unwrap();
+
+        let outcome = probe.probe().await;
+        assert!(outcome.rtt.unwrap() < std::time::Duration::from_millis(500));
+        assert_eq!(outcome.resolved_ip, Some(addr.ip()));
+        assert!((offset(&metrics).unwrap() - 10.0).abs() < 0.5);
+
+        drop(probe);
+        assert_eq!(offset(&metrics), None, "removed with the probe");
+        assert!(probes.probe(&"1.1.1.1".parse().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn kiss_of_death() {
+        let addr = server(0, 0).await;
+        let probes = NtpProbes::new(&NtpConfig::default(), &Registry::new()).unwrap();
+        let target = format!("ntp://127.0.0.1:{}", addr.port()).parse().unwrap();
+        let e = probes
+            .probe(&target)
+            .unwrap()
+            .probe()
+            .await
+            .rtt
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unexpected);
+        assert_eq!(e.message, "kiss-o'-death 'RATE'");
+    }
+}
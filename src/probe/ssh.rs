@@ -0,0 +1,213 @@
+//! Probe of an SSH server, which connects and reads its version banner
+//! without authenticating, such as to check the availability of a bastion.
+//!
+//! The round-trip time of the probe is the time taken to connect and receive
+//! the banner. A banner which doesn't start with the configured prefix fails
+//! the probe as [`ErrorKind::Unexpected`].
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+use super::{Probe, ProbeOutcome};
+use crate::{
+    target::{ProbeTarget, Scheme},
+    ErrorKind, PingError, Result, UppiesError,
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SshConfig {
+    /// Length of time to connect and receive the banner within.
+    #[serde(default = "SshConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Prefix which the banner must start with, such as `SSH-2.0-OpenSSH`,
+    /// otherwise any SSH banner is accepted.
+    pub banner: Option<String>,
+}
+
+impl SshConfig {
+    fn default_timeout_ms() -> u64 {
+        5000
+    }
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: Self::default_timeout_ms(),
+            banner: None,
+        }
+    }
+}
+
+/// Builds the [`SshProbe`] of each `ssh` target.
+#[derive(Clone)]
+pub struct SshProbes {
+    config: SshConfig,
+}
+
+impl SshProbes {
+    pub fn new(config: &SshConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// Probe of the `ssh` target `target`.
+    pub fn probe(&self, target: &ProbeTarget) -> Result<SshProbe> {
+        if target.scheme() != Scheme::Ssh {
+            return Err(UppiesError::InvalidTarget(format!(
+                "'{target}' is not an ssh target"
+            )));
+        }
+        Ok(SshProbe {
+            host: target.host().to_string(),
+            port: target.port().expect("ssh targets have a port"),
+            timeout: Duration::from_millis(self.config.timeout_ms),
+            banner: self.config.banner.clone(),
+        })
+    }
+}
+
+/// Probe which reads the version banner of an SSH server.
+pub struct SshProbe {
+    host: String,
+    port: u16,
+    timeout: Duration,
+    /// Prefix which the banner must start with.
+    banner: Option<String>,
+}
+
+impl SshProbe {
+    /// Number of bytes which the banner must be received within, as
+    /// servers may send other lines before it.
+    const MAX_LEN: u64 = 8192;
+
+    /// Connect and read the banner, returning it with the address of the
+    /// server.
+    async fn banner(&self) -> std::result::Result<(ProbeOutcome, String), PingError> {
+        let tcp = super::connect(&self.host, self.port).await?;
+        let resolved_ip = tcp.peer_addr().ok().map(|addr| addr.ip());
+        let mut reader = BufReader::new(tcp.take(Self::MAX_LEN));
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).await.map_err(|e| PingError {
+                kind: ErrorKind::Malformed,
+                message: e.to_string(),
+            })?;
+            if read == 0 {
+                return Err(PingError {
+                    kind: ErrorKind::Unexpected,
+                    message: "connection closed before the SSH banner".to_string(),
+                });
+            }
+            if line.starts_with("SSH-") {
+                let outcome = ProbeOutcome {
+                    resolved_ip,
+                    reply: None,
+                    rtt: Ok(Duration::ZERO),
+                };
+                return Ok((outcome, line.trim_end().to_string()));
+            }
+        }
+    }
+}
+
+impl Probe for SshProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(self.timeout, self.banner()).await {
+            Ok(result) => result,
+            Err(_) => Err(PingError {
+                kind: ErrorKind::Timeout,
+                message: "timed out waiting for the SSH banner".to_string(),
+            }),
+        };
+        let rtt = start.elapsed();
+        match result {
+            Ok((outcome, banner)) => match &self.banner {
+                Some(expected) if !banner.starts_with(expected.as_str()) => ProbeOutcome {
+                    rtt: Err(PingError {
+                        kind: ErrorKind::Unexpected,
+                        message: format!("unexpected banner '{banner}'"),
+                    }),
+                    ..outcome
+                },
+                _ => ProbeOutcome {
+                    rtt: Ok(rtt),
+                    ..outcome
+                },
+            },
+            Err(e) => ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Err(e),
+            },
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::{SshConfig, SshProbes};
+    use crate::{probe::Probe, ErrorKind};
+
+    /// Serve `response` to every connection.
+    async fn server(response: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut tcp, _)) = listener.accept().await {
+                let _ = tcp.write_all(response.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    async fn probe(addr: SocketAddr, banner: Option<&str>) -> crate::probe::ProbeOutcome {
+        let config = SshConfig {
+            timeout_ms: 1000,
+            banner: banner.map(str::to_string),
+        };
+        let target = format!("ssh://127.0.0.1:{}", addr.port()).parse().unwrap();
+        SshProbes::new(&config)
+            .probe(&target)
+            .unwrap()
+            .probe()
+            .await
+    }
+
+    #[tokio::test]
+    async fn banners() {
+        let addr = server("welcome to the bastion\r\nSSH-2.0-OpenSSH_9.6\r\n").await;
+        let outcome = probe(addr, None).await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+        assert_eq!(outcome.resolved_ip, Some(addr.ip()));
+        assert!(probe(addr, Some("SSH-2.0-OpenSSH")).await.rtt.is_ok());
+
+        let e = probe(addr, Some("SSH-2.0-dropbear")).await.rtt.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unexpected);
+        assert_eq!(e.message, "unexpected banner 'SSH-2.0-OpenSSH_9.6'");
+    }
+
+    #[tokio::test]
+    async fn not_ssh() {
+        let addr = server("HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        let e = probe(addr, None).await.rtt.unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Unexpected);
+        assert!(SshProbes::new(&SshConfig::default())
+            .probe(&"1.1.1.1".parse().unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,430 @@
+//! Probe of a TLS server, which connects and completes a handshake, so that
+//! certificates are monitored by the same agent as reachability.
+//!
+//! The round-trip time of the probe is the time taken to connect and
+//! complete the handshake. The certificate of the server is verified, and
+//! the time until it expires is exported as `tls_cert_expiry_seconds`, even
+//! when verification fails, such as once it has expired.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{GaugeVec, Opts, Registry};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            Resumption, WebPkiServerVerifier,
+        },
+        crypto::aws_lc_rs,
+        pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+        DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
+};
+use tracing::warn;
+
+use super::{Probe, ProbeOutcome};
+use crate::{
+    target::{ProbeTarget, Scheme},
+    ErrorKind, PingError, Result, UppiesError,
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file of additional certificates which servers are trusted if
+    /// signed by, such as an internal CA.
+    pub ca_file: Option<PathBuf>,
+    /// Trust the certificates of the operating system.
+    #[serde(default = "TlsConfig::default_native_roots")]
+    pub native_roots: bool,
+    /// Length of time to connect and complete the handshake within.
+    #[serde(default = "TlsConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl TlsConfig {
+    fn default_native_roots() -> bool {
+        true
+    }
+
+    fn default_timeout_ms() -> u64 {
+        5000
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_file: None,
+            native_roots: Self::default_native_roots(),
+            timeout_ms: Self::default_timeout_ms(),
+        }
+    }
+}
+
+/// Builds the [`TlsProbe`] of each `tls` target, sharing the trusted
+/// certificates and metrics between them.
+#[derive(Clone)]
+pub struct TlsProbes {
+    roots: Arc<RootCertStore>,
+    timeout: Duration,
+    /// Seconds until the certificate of each target expires.
+    expiry: GaugeVec,
+}
+
+impl TlsProbes {
+    pub fn new(config: &TlsConfig, metrics: &Registry) -> Result<Self> {
+        Self::with_roots(
+            load_roots(config)?,
+            Duration::from_millis(config.timeout_ms),
+            metrics,
+        )
+    }
+
+    /// Probes of `config`, sharing the metrics of these, such as for a
+    /// module.
+    pub fn with_config(&self, config: &TlsConfig) -> Result<Self> {
+        Ok(Self {
+            roots: Arc::new(load_roots(config)?),
+            timeout: Duration::from_millis(config.timeout_ms),
+            expiry: self.expiry.clone(),
+        })
+    }
+
+    /// Probes which trust only the certificates of `roots`.
+    pub fn with_roots(roots: RootCertStore, timeout: Duration, metrics: &Registry) -> Result<Self> {
+        let expiry = GaugeVec::new(
+            Opts::new(
+                "tls_cert_expiry_seconds",
+                "Seconds until the certificate presented by the target expires",
+            ),
+            &["target", "probe"],
+        )?;
+        metrics.register(Box::new(expiry.clone()))?;
+        Ok(Self {
+            roots: Arc::new(roots),
+            timeout,
+            expiry,
+        })
+    }
+
+    /// Connector which verifies servers against the trusted certificates,
+    /// such as to upgrade the connection of another protocol with STARTTLS.
+    pub fn connector(&self) -> Result<TlsConnector> {
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let verifier = WebPkiServerVerifier::builder_with_provider(
+            Arc::clone(&self.roots),
+            Arc::clone(&provider),
+        )
+        .build()?;
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_webpki_verifier(verifier)
+            .with_no_client_auth();
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    /// Probe of the `tls` target `target`.
+    pub fn probe(&self, target: &ProbeTarget) -> Result<TlsProbe> {
+        if target.scheme() != Scheme::Tls {
+            return Err(UppiesError::InvalidTarget(format!(
+                "'{target}' is not a tls target"
+            )));
+        }
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let verifier = Arc::new(ExpiryVerifier {
+            inner: WebPkiServerVerifier::builder_with_provider(
+                Arc::clone(&self.roots),
+                Arc::clone(&provider),
+            )
+            .build()?,
+            not_after: Mutex::default(),
+        });
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::clone(&verifier) as _)
+            .with_no_client_auth();
+        // Every probe completes a full handshake, so that the certificate
+        // is seen each time.
+        config.resumption = Resumption::disabled();
+
+        Ok(TlsProbe {
+            host: target.host().to_string(),
+            port: target.port().expect("tls targets have a port"),
+            server_name: ServerName::try_from(target.host().to_string())?,
+            connector: TlsConnector::from(Arc::new(config)),
+            verifier,
+            timeout: self.timeout,
+            labels: [target.label(), target.probe().to_string()],
+            expiry: self.expiry.clone(),
+        })
+    }
+}
+
+/// Certificates trusted by `config`.
+fn load_roots(config: &TlsConfig) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    if config.native_roots {
+        let native = rustls_native_certs::load_native_certs();
+        for e in &native.errors {
+            warn!(%e, "failed to load native certificate");
+        }
+        roots.add_parsable_certificates(native.certs);
+    }
+    if let Some(path) = &config.ca_file {
+        let certs = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        for cert in certs {
+            roots.add(cert)?;
+        }
+    }
+    Ok(roots)
+}
+
+/// Probe which connects to a TLS server and completes a handshake.
+pub struct TlsProbe {
+    host: String,
+    port: u16,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    verifier: Arc<ExpiryVerifier>,
+    timeout: Duration,
+    /// Labels of the target and probe within `expiry`.
+    labels: [String; 2],
+    expiry: GaugeVec,
+}
+
+impl TlsProbe {
+    async fn handshake(&self) -> std::result::Result<TcpStream, PingError> {
+        let tcp = super::connect(&self.host, self.port).await?;
+        let tls = self
+            .connector
+            .connect(self.server_name.clone(), tcp)
+            .await
+            .map_err(|e| PingError {
+                kind: ErrorKind::Tls,
+                message: e.to_string(),
+            })?;
+        Ok(tls.into_inner().0)
+    }
+}
+
+impl Probe for TlsProbe {
+    async fn probe(&self) -> ProbeOutcome {
+        self.verifier.take_not_after();
+        let start = Instant::now();
+        let result = match tokio::time::timeout(self.timeout, self.handshake()).await {
+            Ok(result) => result.map(|tcp| (start.elapsed(), tcp)),
+            Err(_) => Err(PingError {
+                kind: ErrorKind::Timeout,
+                message: "timed out connecting and completing the handshake".to_string(),
+            }),
+        };
+        if let Some(not_after) = self.verifier.take_not_after() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            self.expiry
+                .with_label_values(&self.labels)
+                .set(not_after as f64 - now);
+        }
+        match result {
+            Ok((rtt, tcp)) => ProbeOutcome {
+                resolved_ip: tcp.peer_addr().ok().map(|addr| addr.ip()),
+                reply: None,
+                rtt: Ok(rtt),
+            },
+            Err(e) => ProbeOutcome {
+                resolved_ip: None,
+                reply: None,
+                rtt: Err(e),
+            },
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The series of a target which is no longer probed is removed.
+impl Drop for TlsProbe {
+    fn drop(&mut self) {
+        let _ = self.expiry.remove_label_values(&self.labels);
+    }
+}
+
+/// Verifier which records the expiry of the certificate of the server
+/// before verifying it.
+#[derive(Debug)]
+struct ExpiryVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    /// Expiry of the most recently seen certificate, in seconds since the
+    /// Unix epoch.
+    not_after: Mutex<Option<i64>>,
+}
+
+impl ExpiryVerifier {
+    fn take_not_after(&self) -> Option<i64> {
+        self.not_after.lock().expect("tls lock poisoned").take()
+    }
+}
+
+impl ServerCertVerifier for ExpiryVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let not_after = x509_parser::parse_x509_certificate(end_entity)
+            .ok()
+            .map(|(_, cert)| cert.validity().not_after.timestamp());
+        *self.not_after.lock().expect("tls lock poisoned") = not_after;
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use prometheus::Registry;
+    use rcgen::{CertificateParams, KeyPair};
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{
+            crypto::aws_lc_rs,
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+            RootCertStore, ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    use super::TlsProbes;
+    use crate::{probe::Probe, ErrorKind};
+
+    /// Unix timestamp which the certificate of the server expires at.
+    const NOT_AFTER: i64 = 4102444800;
+
+    /// Serve TLS with a certificate for `localhost`, returning the
+    /// certificate and the address of the server.
+    async fn server() -> (CertificateDer<'static>, std::net::SocketAddr) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(2100, 1, 1);
+        let cert = params.self_signed(&key).unwrap().der().clone();
+        let config = ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move { acceptor.accept(tcp).await });
+            }
+        });
+        (cert, addr)
+    }
+
+    fn expiry(metrics: &Registry) -> Option<f64> {
+        let family = metrics
+            .gather()
+            .into_iter()
+            .find(|m| m.name() == "tls_cert_expiry_seconds")?;
+        Some(family.get_metric().first()?.get_gauge().value())
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (cert, addr) = server().await;
+        let metrics = Registry::new();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let probes = TlsProbes::with_roots(roots, Duration::from_secs(1), &metrics).unwrap();
+        let target = format!("site=tls://localhost:{}", addr.port())
+            .parse()
+            .unwrap();
+        let probe = probes.probe(&target).unwrap();
+
+        let outcome = probe.probe().await;
+        assert!(outcome.rtt.is_ok(), "{:?}", outcome.rtt);
+        assert_eq!(outcome.resolved_ip, Some(addr.ip()));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let expected = NOT_AFTER as f64 - now;
+        assert!((expiry(&metrics).unwrap() - expected).abs() < 5.0);
+
+        drop(probe);
+        assert_eq!(expiry(&metrics), None, "removed with the probe");
+        assert!(probes.probe(&"1.1.1.1".parse().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        let (_, addr) = server().await;
+        let (other, _) = server().await;
+        let metrics = Registry::new();
+        let mut roots = RootCertStore::empty();
+        roots.add(other).unwrap();
+        let probes = TlsProbes::with_roots(roots, Duration::from_secs(1), &metrics).unwrap();
+        let target = format!("tls://localhost:{}", addr.port()).parse().unwrap();
+
+        let probe = probes.probe(&target).unwrap();
+        let outcome = probe.probe().await;
+        assert_eq!(outcome.rtt.unwrap_err().kind, ErrorKind::Tls);
+        assert!(
+            expiry(&metrics).unwrap() > 0.0,
+            "expiry is recorded despite the failure"
+        );
+    }
+}
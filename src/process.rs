@@ -0,0 +1,172 @@
+//! Metrics of the uppies process itself, so that a monitor which is the
+//! bottleneck, such as by running out of file descriptors with a socket per
+//! probe, can be told apart from the network it monitors.
+//!
+//! Memory and file descriptors are read from `/proc/self` when scraped, so
+//! are only reported on Linux, while the tasks of the runtime are reported
+//! everywhere.
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    IntGauge, Registry,
+};
+
+use crate::Result;
+
+/// Gauges of the resources used by this process, which are updated as they
+/// are collected.
+#[derive(Clone)]
+pub struct ProcessMetrics {
+    resident_memory: IntGauge,
+    virtual_memory: IntGauge,
+    open_fds: IntGauge,
+    max_fds: IntGauge,
+    tasks: IntGauge,
+}
+
+impl ProcessMetrics {
+    pub fn new(metrics: &Registry) -> Result<Self> {
+        let process = Self {
+            resident_memory: IntGauge::new(
+                "process_resident_memory_bytes",
+                "Resident memory size of the process in bytes",
+            )?,
+            virtual_memory: IntGauge::new(
+                "process_virtual_memory_bytes",
+                "Virtual memory size of the process in bytes",
+            )?,
+            open_fds: IntGauge::new(
+                "process_open_fds",
+                "Number of file descriptors which the process has open",
+            )?,
+            max_fds: IntGauge::new(
+                "process_max_fds",
+                "Maximum number of file descriptors which the process can open",
+            )?,
+            tasks: IntGauge::new(
+                "uppies_runtime_tasks",
+                "Number of tasks which are alive in the runtime, including dispatchers",
+            )?,
+        };
+        metrics.register(Box::new(process.clone()))?;
+        Ok(process)
+    }
+
+    /// Gauges which are reported on this platform.
+    fn gauges(&self) -> Vec<&IntGauge> {
+        if cfg!(target_os = "linux") {
+            vec![
+                &self.resident_memory,
+                &self.virtual_memory,
+                &self.open_fds,
+                &self.max_fds,
+                &self.tasks,
+            ]
+        } else {
+            vec![&self.tasks]
+        }
+    }
+
+    fn update(&self) {
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            self.tasks.set(handle.metrics().num_alive_tasks() as i64);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some((size, resident)) = linux::memory() {
+                self.virtual_memory.set(size as i64);
+                self.resident_memory.set(resident as i64);
+            }
+            if let Some(open) = linux::open_fds() {
+                self.open_fds.set(open as i64);
+            }
+            if let Some(max) = linux::max_fds() {
+                self.max_fds.set(i64::try_from(max).unwrap_or(i64::MAX));
+            }
+        }
+    }
+}
+
+impl Collector for ProcessMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauges()
+            .into_iter()
+            .flat_map(|gauge| gauge.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.update();
+        self.gauges()
+            .into_iter()
+            .flat_map(|gauge| gauge.collect())
+            .collect()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+
+    /// Virtual and resident memory size of this process in bytes.
+    pub(super) fn memory() -> Option<(u64, u64)> {
+        // The first two fields of `statm` are the sizes in pages.
+        let statm = fs::read_to_string("/proc/self/statm").ok()?;
+        let mut pages = statm.split_whitespace().map(|p| p.parse::<u64>().ok());
+        let (size, resident) = (pages.next()??, pages.next()??);
+        // SAFETY: sysconf has no preconditions.
+        let page_size = u64::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).ok()?;
+        Some((size * page_size, resident * page_size))
+    }
+
+    /// Number of file descriptors which this process has open.
+    pub(super) fn open_fds() -> Option<usize> {
+        // Reading the directory opens one more descriptor, which isn't
+        // counted as it was opened by the read.
+        Some(
+            fs::read_dir("/proc/self/fd")
+                .ok()?
+                .count()
+                .saturating_sub(1),
+        )
+    }
+
+    /// Soft limit of the number of file descriptors of this process.
+    pub(super) fn max_fds() -> Option<u64> {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `limit` is a valid rlimit to write into.
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return None;
+        }
+        Some(limit.rlim_cur)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prometheus::Registry;
+
+    use super::ProcessMetrics;
+
+    #[tokio::test]
+    async fn gauges() {
+        let metrics = Registry::new();
+        ProcessMetrics::new(&metrics).unwrap();
+
+        let families = metrics.gather();
+        let value = |name: &str| {
+            let family = families.iter().find(|f| f.name() == name).unwrap();
+            family.get_metric()[0].get_gauge().value()
+        };
+        assert!(value("uppies_runtime_tasks") >= 0.0);
+        if cfg!(target_os = "linux") {
+            assert!(value("process_resident_memory_bytes") > 0.0);
+            assert!(value("process_open_fds") > 0.0);
+            assert!(value("process_max_fds") >= value("process_open_fds"));
+        }
+    }
+}
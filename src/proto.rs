@@ -0,0 +1,239 @@
+//! Protobuf messages of the contract within `proto/uppies/v1/uppies.proto`,
+//! which the protobuf [`encoding`](crate::encoding) of results uses.
+//!
+//! These are public with the `proto` feature, for Rust consumers of the
+//! protobuf outputs. Conversions from the types of uppies are provided for
+//! each message.
+
+// Messages which no output uses yet are only reachable with the feature.
+#![cfg_attr(not(feature = "proto"), allow(dead_code))]
+
+use crate::{events, history, notify, pause, state, stream};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProbeResult {
+    #[prost(string, tag = "1")]
+    pub target: String,
+    #[prost(uint64, tag = "2")]
+    pub sequence: u64,
+    #[prost(double, optional, tag = "3")]
+    pub rtt_ms: Option<f64>,
+    #[prost(string, optional, tag = "4")]
+    pub error: Option<String>,
+    #[prost(uint64, tag = "5")]
+    pub timestamp_ms: u64,
+    #[prost(uint32, optional, tag = "6")]
+    pub reply_ttl: Option<u32>,
+    #[prost(string, optional, tag = "7")]
+    pub reply_source: Option<String>,
+    #[prost(string, tag = "8")]
+    pub probe: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HistoryRecord {
+    #[prost(string, tag = "1")]
+    pub target: String,
+    #[prost(uint64, tag = "2")]
+    pub timestamp_ms: u64,
+    #[prost(uint64, optional, tag = "3")]
+    pub rtt_us: Option<u64>,
+    #[prost(string, optional, tag = "4")]
+    pub error: Option<String>,
+    #[prost(string, tag = "5")]
+    pub probe: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TargetStatus {
+    #[prost(string, tag = "1")]
+    pub target: String,
+    #[prost(bool, tag = "2")]
+    pub paused: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum State {
+    Unspecified = 0,
+    Up = 1,
+    Down = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RttStats {
+    #[prost(double, tag = "1")]
+    pub last_ms: f64,
+    #[prost(double, tag = "2")]
+    pub min_ms: f64,
+    #[prost(double, tag = "3")]
+    pub avg_ms: f64,
+    #[prost(double, tag = "4")]
+    pub max_ms: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StateChange {
+    #[prost(string, tag = "1")]
+    pub target: String,
+    #[prost(enumeration = "State", tag = "2")]
+    pub state: i32,
+    #[prost(uint64, tag = "3")]
+    pub timestamp_ms: u64,
+    #[prost(uint64, optional, tag = "4")]
+    pub down_for_ms: Option<u64>,
+    #[prost(bool, tag = "5")]
+    pub flapping: bool,
+    #[prost(message, optional, tag = "6")]
+    pub rtt: Option<RttStats>,
+    #[prost(string, tag = "7")]
+    pub probe: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Event {
+    #[prost(string, tag = "1")]
+    pub target: String,
+    #[prost(enumeration = "State", tag = "2")]
+    pub state: i32,
+    #[prost(uint64, tag = "3")]
+    pub timestamp_ms: u64,
+    #[prost(uint64, optional, tag = "4")]
+    pub duration_ms: Option<u64>,
+    #[prost(string, tag = "5")]
+    pub probe: String,
+}
+
+impl From<&stream::StreamEvent> for ProbeResult {
+    fn from(event: &stream::StreamEvent) -> Self {
+        Self {
+            target: event.target.clone(),
+            sequence: event.sequence,
+            rtt_ms: event.rtt_ms,
+            error: event.error.clone(),
+            timestamp_ms: event.timestamp_ms,
+            reply_ttl: event.reply_ttl.map(u32::from),
+            reply_source: event.reply_source.map(|ip| ip.to_string()),
+            probe: event.probe.clone(),
+        }
+    }
+}
+
+impl From<&history::Record> for HistoryRecord {
+    fn from(record: &history::Record) -> Self {
+        Self {
+            target: record.target.clone(),
+            timestamp_ms: record.timestamp_ms,
+            rtt_us: record.rtt_us,
+            error: record.error.clone(),
+            probe: record.probe.clone(),
+        }
+    }
+}
+
+impl From<&pause::TargetStatus> for TargetStatus {
+    fn from(status: &pause::TargetStatus) -> Self {
+        Self {
+            target: status.target.clone(),
+            paused: status.paused,
+        }
+    }
+}
+
+impl From<state::State> for State {
+    fn from(state: state::State) -> Self {
+        match state {
+            state::State::Up => Self::Up,
+            state::State::Down => Self::Down,
+        }
+    }
+}
+
+impl From<&notify::RttStats> for RttStats {
+    fn from(rtt: &notify::RttStats) -> Self {
+        Self {
+            last_ms: rtt.last_ms,
+            min_ms: rtt.min_ms,
+            avg_ms: rtt.avg_ms,
+            max_ms: rtt.max_ms,
+        }
+    }
+}
+
+impl From<&notify::StateChange> for StateChange {
+    fn from(change: &notify::StateChange) -> Self {
+        Self {
+            target: change.target.clone(),
+            state: State::from(change.state).into(),
+            timestamp_ms: change.timestamp_ms,
+            down_for_ms: change.down_for_ms,
+            flapping: change.flapping,
+            rtt: change.rtt.as_ref().map(Into::into),
+            probe: change.probe.clone(),
+        }
+    }
+}
+
+impl From<&events::Event> for Event {
+    fn from(event: &events::Event) -> Self {
+        Self {
+            target: event.target.clone(),
+            state: State::from(event.state).into(),
+            timestamp_ms: event.timestamp_ms,
+            duration_ms: event.duration_ms,
+            probe: event.probe.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use super::{State, StateChange};
+    use crate::{notify, state};
+
+    /// Messages of the contract, which must each have a Rust type.
+    const MESSAGES: &[&str] = &[
+        "ProbeResult",
+        "HistoryRecord",
+        "TargetStatus",
+        "RttStats",
+        "StateChange",
+        "Event",
+    ];
+
+    #[test]
+    fn contract() {
+        let contract = include_str!("../proto/uppies/v1/uppies.proto");
+        let declared: Vec<_> = contract
+            .lines()
+            .filter_map(|line| line.strip_prefix("message "))
+            .filter_map(|line| line.split_whitespace().next())
+            .collect();
+        assert_eq!(declared, MESSAGES);
+        assert!(contract.contains("package uppies.v1;"));
+    }
+
+    #[test]
+    fn state_change() {
+        let change = notify::StateChange {
+            target: "1.1.1.1".to_string(),
+            probe: "icmp".to_string(),
+            state: state::State::Down,
+            timestamp_ms: 1000,
+            down_for_ms: None,
+            flapping: false,
+            rtt: Some(notify::RttStats {
+                last_ms: 1.0,
+                min_ms: 1.0,
+                avg_ms: 1.0,
+                max_ms: 1.0,
+            }),
+        };
+        let encoded = StateChange::from(&change).encode_to_vec();
+        let decoded = StateChange::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded.state(), State::Down);
+        assert_eq!(decoded.rtt.unwrap().avg_ms, 1.0);
+    }
+}
@@ -0,0 +1,414 @@
+//! Ranges of targets in CIDR notation, such as `10.0.0.0/28`, which are
+//! expanded into a target of each of their addresses, to sweep a subnet
+//! without listing each address.
+//!
+//! A range is written as a target whose address is followed by its prefix
+//! length, such as `tls://10.0.0.0/28:8443` or `[2001:db8::/120]`, and each
+//! of its addresses is probed alike. The network and broadcast addresses of
+//! IPv4 ranges are skipped, as they aren't hosts.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{target::ProbeTarget, Result, UppiesError};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RangeConfig {
+    /// Number of addresses which a range may have, so that a mistyped
+    /// prefix such as `/8` isn't expanded into millions of targets.
+    #[serde(default = "RangeConfig::default_max_targets")]
+    pub max_targets: u64,
+    /// Addresses, or ranges of them, which ranges are expanded without.
+    #[serde(default)]
+    pub exclude: Vec<Cidr>,
+}
+
+impl RangeConfig {
+    fn default_max_targets() -> u64 {
+        1024
+    }
+}
+
+impl Default for RangeConfig {
+    fn default() -> Self {
+        Self {
+            max_targets: Self::default_max_targets(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// Network of addresses sharing a prefix, such as `10.0.0.0/28`. A single
+/// address is the network of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Network of `ip` with the given prefix length, without the bits of
+    /// `ip` which are beyond the prefix.
+    pub fn new(ip: IpAddr, prefix: u8) -> Result<Self, String> {
+        let bits = bits(ip);
+        if prefix > bits {
+            return Err(format!("prefix length must be at most {bits}"));
+        }
+        let network = from_u128(ip, to_u128(ip) & !host_mask(bits, prefix));
+        Ok(Self { network, prefix })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.network.is_ipv4()
+            && to_u128(ip) & !host_mask(bits(ip), self.prefix) == to_u128(self.network)
+    }
+
+    /// Range of the addresses of hosts within the network, as integers.
+    fn host_range(&self) -> std::ops::RangeInclusive<u128> {
+        let first = to_u128(self.network);
+        let last = first | host_mask(bits(self.network), self.prefix);
+        match self.network {
+            IpAddr::V4(_) if self.prefix <= 30 => first + 1..=last - 1,
+            _ => first..=last,
+        }
+    }
+
+    /// Number of addresses of hosts within the network.
+    pub fn num_hosts(&self) -> u128 {
+        let range = self.host_range();
+        (range.end() - range.start()).saturating_add(1)
+    }
+
+    /// Addresses of hosts within the network.
+    pub fn hosts(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.host_range().map(|ip| from_u128(self.network, ip))
+    }
+}
+
+/// Number of bits of addresses of the family of `ip`.
+fn bits(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Mask of the bits beyond `prefix` of addresses of `bits` bits.
+fn host_mask(bits: u8, prefix: u8) -> u128 {
+    match bits - prefix {
+        0 => 0,
+        host => u128::MAX >> (128 - u32::from(host)),
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip).into(),
+        IpAddr::V6(ip) => ip.into(),
+    }
+}
+
+/// Address of the family of `family` with the value `ip`.
+fn from_u128(family: IpAddr, ip: u128) -> IpAddr {
+    match family {
+        IpAddr::V4(_) => Ipv4Addr::from(ip as u32).into(),
+        IpAddr::V6(_) => Ipv6Addr::from(ip).into(),
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid range '{s}': {reason}");
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (
+                ip,
+                Some(
+                    prefix
+                        .parse()
+                        .map_err(|_| invalid("invalid prefix length"))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| invalid("expected an IP address"))?;
+        Self::new(ip, prefix.unwrap_or(bits(ip))).map_err(|e| invalid(&e))
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+/// Range of targets, each of which is probed alike.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetRange {
+    cidr: Cidr,
+    /// Target of the network address, which the target of each address of
+    /// the range is the same as.
+    template: ProbeTarget,
+}
+
+impl TargetRange {
+    pub fn cidr(&self) -> Cidr {
+        self.cidr
+    }
+
+    /// Targets of the addresses of the range, except those of `exclude`.
+    pub fn targets<'a>(&'a self, exclude: &'a [Cidr]) -> impl Iterator<Item = ProbeTarget> + 'a {
+        self.cidr
+            .hosts()
+            .filter(|ip| !exclude.iter().any(|cidr| cidr.contains(*ip)))
+            .map(|ip| self.template.with_ip(ip))
+    }
+}
+
+/// Split the prefix length from the address of a range such as
+/// `tls://10.0.0.0/28:8443`, returning the target without it, its address
+/// and the prefix, or `None` if it isn't a range.
+fn split_range(s: &str) -> Option<(String, IpAddr, &str)> {
+    let end = s.find('?').unwrap_or(s.len());
+    let start = s[..end].find('=').map_or(0, |i| i + 1);
+    let start = s[start..end].find("://").map_or(start, |i| start + i + 3);
+    let start = match s[start..].starts_with('[') {
+        true => start + 1,
+        false => start,
+    };
+    let slash = start + s[start..end].find('/')?;
+    let ip = s[start..slash].parse().ok()?;
+    let digits = s[slash + 1..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(s.len(), |i| slash + 1 + i);
+    if digits == slash + 1 {
+        return None;
+    }
+    let target = format!("{}{}", &s[..slash], &s[digits..]);
+    Some((target, ip, &s[slash + 1..digits]))
+}
+
+impl fmt::Display for TargetRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The prefix follows the network address within the target.
+        let target = self.template.to_string();
+        let (before, after) = target.split_at(
+            target
+                .find(self.template.host())
+                .expect("target has its host")
+                + self.template.host().len(),
+        );
+        write!(f, "{before}/{}{after}", self.cidr.prefix)
+    }
+}
+
+impl FromStr for TargetRange {
+    type Err = UppiesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: &str| UppiesError::InvalidTarget(format!("invalid range '{s}': {reason}"));
+        let (target, ip, prefix) =
+            split_range(s).ok_or_else(|| invalid("expected a prefix length"))?;
+        let prefix = prefix
+            .parse()
+            .map_err(|_| invalid("invalid prefix length"))?;
+        let cidr = Cidr::new(ip, prefix).map_err(|e| invalid(&e))?;
+        let template: ProbeTarget = target.parse()?;
+        if template.alias().is_some() {
+            return Err(invalid("ranges can't have an alias"));
+        }
+        Ok(Self {
+            template: template.with_ip(cidr.network),
+            cidr,
+        })
+    }
+}
+
+/// Target given by the configuration or command line, which is either a
+/// single target or a range of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TargetSpec {
+    Target(ProbeTarget),
+    Range(TargetRange),
+}
+
+impl fmt::Display for TargetSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Target(target) => target.fmt(f),
+            Self::Range(range) => range.fmt(f),
+        }
+    }
+}
+
+impl FromStr for TargetSpec {
+    type Err = UppiesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match split_range(s) {
+            Some(_) => Ok(Self::Range(s.parse()?)),
+            None => Ok(Self::Target(s.parse()?)),
+        }
+    }
+}
+
+impl TryFrom<String> for TargetSpec {
+    type Error = UppiesError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TargetSpec> for String {
+    fn from(spec: TargetSpec) -> Self {
+        spec.to_string()
+    }
+}
+
+impl From<ProbeTarget> for TargetSpec {
+    fn from(target: ProbeTarget) -> Self {
+        Self::Target(target)
+    }
+}
+
+/// Targets of `specs`, expanding each range into the targets of its
+/// addresses.
+pub fn expand(specs: &[TargetSpec], config: &RangeConfig) -> Result<Vec<ProbeTarget>> {
+    let mut targets = Vec::new();
+    for spec in specs {
+        match spec {
+            TargetSpec::Target(target) => targets.push(target.clone()),
+            TargetSpec::Range(range) => {
+                let len = range.cidr.num_hosts();
+                if len > u128::from(config.max_targets) {
+                    return Err(UppiesError::InvalidTarget(format!(
+                        "range '{range}' has {len} addresses, more than max_targets of {}",
+                        config.max_targets
+                    )));
+                }
+                targets.extend(range.targets(&config.exclude));
+            }
+        }
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand, Cidr, RangeConfig, TargetSpec};
+    use crate::target::ProbeTarget;
+
+    fn spec(s: &str) -> TargetSpec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        for (range, normalized) in [
+            ("10.0.0.0/28", "10.0.0.0/28"),
+            ("10.0.0.9/28", "10.0.0.0/28"),
+            ("tls://10.0.0.0/30:8443", "tls://10.0.0.0/30:8443"),
+            (
+                "grpc://10.0.0.0/30:50051/payments",
+                "grpc://10.0.0.0/30:50051/payments",
+            ),
+            ("tls://[2001:db8::/126]:443", "tls://2001:db8::/126"),
+            (
+                "2001:db8::/126?module=icmp-fast",
+                "2001:db8::/126?module=icmp-fast",
+            ),
+        ] {
+            let spec = spec(range);
+            assert!(matches!(spec, TargetSpec::Range(_)), "{range} is a range");
+            assert_eq!(spec.to_string(), normalized);
+        }
+        assert!(matches!(
+            spec("grpc://10.0.0.5:50051/payments"),
+            TargetSpec::Target(_)
+        ));
+
+        for (range, e) in [
+            ("10.0.0.0/33", "prefix length must be at most 32"),
+            ("lan=10.0.0.0/28", "ranges can't have an alias"),
+        ] {
+            let err = range.parse::<TargetSpec>().unwrap_err();
+            assert_eq!(err.to_string(), format!("invalid range '{range}': {e}"));
+        }
+    }
+
+    #[test]
+    fn expansion() {
+        let config = RangeConfig {
+            max_targets: 16,
+            exclude: vec!["10.0.0.1".parse().unwrap(), "10.0.0.8/29".parse().unwrap()],
+        };
+        let targets = expand(&[spec("1.1.1.1"), spec("tls://10.0.0.0/28")], &config).unwrap();
+        let expected: Vec<ProbeTarget> = ["1.1.1.1", "tls://10.0.0.2", "tls://10.0.0.3"]
+            .iter()
+            .chain(&[
+                "tls://10.0.0.4",
+                "tls://10.0.0.5",
+                "tls://10.0.0.6",
+                "tls://10.0.0.7",
+            ])
+            .map(|t| t.parse().unwrap())
+            .collect();
+        assert_eq!(targets, expected);
+
+        let targets = expand(&[spec("2001:db8::/127")], &config).unwrap();
+        assert_eq!(targets.len(), 2, "IPv6 ranges have no broadcast address");
+
+        let err = expand(&[spec("10.0.0.0/24")], &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "range '10.0.0.0/24' has 254 addresses, more than max_targets of 16"
+        );
+    }
+
+    #[test]
+    fn cidrs() {
+        let cidr: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(cidr.contains("192.168.10.1".parse().unwrap()));
+        assert!(!cidr.contains("192.169.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+        assert_eq!("10.0.0.1/31".parse::<Cidr>().unwrap().num_hosts(), 2);
+        assert_eq!(
+            "10.0.0.1".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert_eq!("::/0".parse::<Cidr>().unwrap().num_hosts(), u128::MAX);
+    }
+}
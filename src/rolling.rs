@@ -0,0 +1,396 @@
+//! Rolling windows of recent pings, and rolling histograms of ping durations
+//! which are built on them.
+//!
+//! A rolling window is split into a number of [`Slices`] covering it, which
+//! each accumulate the pings within them, with the oldest slice discarded
+//! as time moves on. The window then only reflects recent pings, without
+//! keeping every ping or the cliff of a periodic reset.
+//! [Availability](crate::sla), [objectives](crate::slo) and
+//! [baselines](crate::baseline) are kept over rolling windows.
+//!
+//! Cumulative histograms accumulated over weeks make recent shifts in latency
+//! nearly invisible within the bucket ratios, so ping durations are also kept
+//! as a rolling histogram. As the bucket counts of a rolling histogram can
+//! decrease, they should be used directly, e.g.
+//! `histogram_quantile(0.95, ping_duration_ms_rolling_bucket)`, rather than
+//! through `rate()`.
+
+#[cfg(feature = "metrics")]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "metrics")]
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{self, LabelPair, MetricFamily, MetricType},
+    Registry,
+};
+use serde::Deserialize;
+
+#[cfg(feature = "metrics")]
+use crate::{sink::Sink, PingOutcome, Result};
+
+/// Slices of a rolling window, ordered from oldest to newest, which each
+/// accumulate the pings within them as a `T`.
+pub(crate) struct Slices<T> {
+    window: Duration,
+    slice_duration: Duration,
+    slices: VecDeque<(Instant, T)>,
+}
+
+impl<T> Slices<T> {
+    /// Slices of a window of `window`, which is divided into `slices`.
+    pub(crate) fn new(window: Duration, slices: usize) -> Self {
+        Self {
+            window,
+            slice_duration: window / slices.max(1) as u32,
+            slices: VecDeque::new(),
+        }
+    }
+
+    /// Remove slices which have fallen out of the window at `now`.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        while self
+            .slices
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) >= self.window)
+        {
+            self.slices.pop_front();
+        }
+    }
+
+    /// Newest slice, if `at` falls within it.
+    pub(crate) fn current(&mut self, at: Instant) -> Option<&mut T> {
+        self.slices
+            .back_mut()
+            .filter(|(start, _)| at.duration_since(*start) < self.slice_duration)
+            .map(|(_, slice)| slice)
+    }
+
+    /// Start a new slice at `at`.
+    pub(crate) fn push(&mut self, at: Instant, slice: T) {
+        self.slices.push_back((at, slice));
+    }
+
+    /// Slice which `at` falls within, once older slices have been expired,
+    /// starting a new slice with `new` if the newest is over.
+    pub(crate) fn slice(&mut self, at: Instant, new: impl FnOnce() -> T) -> &mut T {
+        self.expire(at);
+        if self.current(at).is_none() {
+            self.push(at, new());
+        }
+        &mut self.slices.back_mut().expect("slice was pushed").1
+    }
+
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.slices.iter().map(|(_, slice)| slice)
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut T> {
+        self.slices.iter_mut().map(|(_, slice)| slice)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.slices.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RollingConfig {
+    /// Length of the window, in seconds, covered by the histogram.
+    #[serde(default = "RollingConfig::default_window_secs")]
+    pub window_secs: u64,
+    /// Number of slices the window is divided into. More slices result in
+    /// smoother expiry of old pings.
+    #[serde(default = "RollingConfig::default_slices")]
+    pub slices: usize,
+}
+
+impl RollingConfig {
+    fn default_window_secs() -> u64 {
+        3600
+    }
+
+    fn default_slices() -> usize {
+        6
+    }
+}
+
+/// Pings observed within a single slice of the window.
+#[cfg(feature = "metrics")]
+struct Slice {
+    /// Non-cumulative count of pings within each bucket.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Rolling histogram of ping durations, labelled by target and probe.
+///
+/// Clones share the same underlying state.
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub struct RollingHistogram {
+    inner: Arc<Inner>,
+}
+
+#[cfg(feature = "metrics")]
+struct Inner {
+    desc: Desc,
+    buckets: Vec<f64>,
+    window: Duration,
+    slices: usize,
+    /// Slices for each probe of each target.
+    targets: Mutex<HashMap<(String, String), Slices<Slice>>>,
+}
+
+#[cfg(feature = "metrics")]
+impl RollingHistogram {
+    const NAME: &str = "ping_duration_ms_rolling";
+    const LABELS: [&str; 2] = ["target", "probe"];
+
+    pub fn new(config: &RollingConfig, buckets: Vec<f64>, metrics: &Registry) -> Result<Self> {
+        let slices = config.slices.max(1);
+        let inner = Inner {
+            desc: Desc::new(
+                Self::NAME.to_string(),
+                format!(
+                    "Histogram of ping round-trip times in milliseconds over the last {} seconds",
+                    config.window_secs
+                ),
+                Self::LABELS.iter().map(|l| l.to_string()).collect(),
+                HashMap::new(),
+            )?,
+            buckets,
+            window: Duration::from_secs(config.window_secs),
+            slices,
+            targets: Mutex::new(HashMap::new()),
+        };
+        let histogram = Self {
+            inner: Arc::new(inner),
+        };
+        metrics.register(Box::new(histogram.clone()))?;
+        Ok(histogram)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Inner {
+    fn observe(&self, target: &str, probe: &str, at: Instant, ms: f64) {
+        let mut targets = self.targets.lock().expect("rolling lock poisoned");
+        let current = targets
+            .entry((target.to_string(), probe.to_string()))
+            .or_insert_with(|| Slices::new(self.window, self.slices))
+            .slice(at, || Slice {
+                counts: vec![0; self.buckets.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        if let Some(i) = self.buckets.iter().position(|upper| ms <= *upper) {
+            current.counts[i] += 1;
+        }
+        current.sum += ms;
+        current.count += 1;
+    }
+
+    fn families(&self, now: Instant) -> Vec<MetricFamily> {
+        let mut targets = self.targets.lock().expect("rolling lock poisoned");
+        let mut metrics = Vec::with_capacity(targets.len());
+        for ((target, probe), slices) in targets.iter_mut() {
+            slices.expire(now);
+
+            let mut counts = vec![0; self.buckets.len()];
+            let (mut sum, mut count) = (0.0, 0);
+            for slice in slices.iter() {
+                for (total, c) in counts.iter_mut().zip(&slice.counts) {
+                    *total += c;
+                }
+                sum += slice.sum;
+                count += slice.count;
+            }
+
+            let mut cumulative = 0;
+            let buckets = self
+                .buckets
+                .iter()
+                .zip(counts)
+                .map(|(upper, c)| {
+                    cumulative += c;
+                    let mut bucket = proto::Bucket::default();
+                    bucket.set_upper_bound(*upper);
+                    bucket.set_cumulative_count(cumulative);
+                    bucket
+                })
+                .collect();
+            let mut histogram = proto::Histogram::default();
+            histogram.set_sample_count(count);
+            histogram.set_sample_sum(sum);
+            histogram.set_bucket(buckets);
+
+            let labels = RollingHistogram::LABELS
+                .iter()
+                .zip([target, probe])
+                .map(|(name, value)| {
+                    let mut label = LabelPair::default();
+                    label.set_name(name.to_string());
+                    label.set_value(value.clone());
+                    label
+                })
+                .collect();
+            let mut metric = proto::Metric::default();
+            metric.set_label(labels);
+            metric.set_histogram(histogram);
+            metrics.push(metric);
+        }
+
+        let mut family = MetricFamily::default();
+        family.set_name(RollingHistogram::NAME.to_string());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.set_metric(metrics);
+        vec![family]
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Collector for RollingHistogram {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.inner.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.inner.families(Instant::now())
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Sink for RollingHistogram {
+    fn record(&self, outcome: &PingOutcome) {
+        if let Some(d) = outcome.latency() {
+            self.inner.observe(
+                &outcome.target,
+                &outcome.probe,
+                Instant::now(),
+                d.as_millis() as f64,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    #[cfg(feature = "metrics")]
+    use prometheus::Registry;
+
+    use super::Slices;
+    #[cfg(feature = "metrics")]
+    use super::{RollingConfig, RollingHistogram};
+
+    #[cfg(feature = "metrics")]
+    const TARGET: &str = "127.0.0.1";
+    #[cfg(feature = "metrics")]
+    const PROBE: &str = "icmp";
+
+    #[test]
+    fn slices() {
+        let mut slices = Slices::new(Duration::from_secs(60), 6);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        for secs in [0, 5, 10, 30] {
+            *slices.slice(at(secs), || 0) += 1;
+        }
+        assert_eq!(slices.iter().collect::<Vec<_>>(), [&2, &1, &1]);
+        assert!(slices.current(at(35)).is_some());
+        assert!(slices.current(at(40)).is_none());
+
+        // The first slice has left the window.
+        slices.expire(at(65));
+        assert_eq!(slices.iter().collect::<Vec<_>>(), [&1, &1]);
+        slices.expire(at(120));
+        assert!(slices.is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    fn histogram() -> RollingHistogram {
+        RollingHistogram::new(
+            &RollingConfig {
+                window_secs: 60,
+                slices: 6,
+            },
+            vec![10.0, 100.0],
+            &Registry::new(),
+        )
+        .unwrap()
+    }
+
+    /// Cumulative bucket counts and the sample count at the given time.
+    #[cfg(feature = "metrics")]
+    fn snapshot(histogram: &RollingHistogram, at: Instant) -> (Vec<u64>, u64) {
+        let families = histogram.inner.families(at);
+        let h = families[0].get_metric()[0].get_histogram();
+        (
+            h.get_bucket()
+                .iter()
+                .map(|b| b.cumulative_count())
+                .collect(),
+            h.get_sample_count(),
+        )
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn buckets_cover_window() {
+        let histogram = histogram();
+        let start = Instant::now();
+        histogram.inner.observe(TARGET, PROBE, start, 5.0);
+        histogram
+            .inner
+            .observe(TARGET, PROBE, start + Duration::from_secs(30), 50.0);
+        histogram
+            .inner
+            .observe(TARGET, PROBE, start + Duration::from_secs(31), 500.0);
+
+        assert_eq!(
+            snapshot(&histogram, start + Duration::from_secs(40)),
+            (vec![1, 2], 3)
+        );
+        // The first ping has left the window.
+        assert_eq!(
+            snapshot(&histogram, start + Duration::from_secs(65)),
+            (vec![0, 1], 2)
+        );
+        assert_eq!(
+            snapshot(&histogram, start + Duration::from_secs(120)),
+            (vec![0, 0], 0)
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn registered_alongside_cumulative() {
+        let metrics = Registry::new();
+        let histogram = RollingHistogram::new(
+            &RollingConfig {
+                window_secs: 60,
+                slices: 6,
+            },
+            vec![10.0],
+            &metrics,
+        )
+        .unwrap();
+        histogram.inner.observe(TARGET, PROBE, Instant::now(), 1.0);
+
+        let families = metrics.gather();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].name(), "ping_duration_ms_rolling");
+    }
+}
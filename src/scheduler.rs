@@ -0,0 +1,493 @@
+//! Scheduling of the probes of each target.
+//!
+//! By default each dispatcher is a task of its own, which ticks at the ping
+//! interval. With thousands of targets, a bounded pool of workers instead
+//! services a queue of the deadline of the next probe of each dispatcher,
+//! so that the number of tasks and timers doesn't grow with the number of
+//! targets and probes are bounded in how many run at once.
+//!
+//! Either way, probes can be smeared across the interval with [`Jitter`],
+//! rather than every target being probed in the same instant.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{mpsc, Notify},
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::error;
+
+use crate::{Dispatcher, DispatcherState, PingMetrics, PingOutcome};
+
+/// How the probes of dispatchers are scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerMode {
+    /// A task per target, each with its own interval.
+    Tasks,
+    /// A bounded pool of workers probing targets as their deadlines pass, in
+    /// order of deadline.
+    Pool,
+}
+
+impl SchedulerMode {
+    /// Number of targets at which [`SchedulerMode::Pool`] becomes the
+    /// default.
+    ///
+    /// From `cargo bench --bench scheduler`, both keep up with 10k targets
+    /// at 1s intervals on a single core, with the pool using ~1KB per target
+    /// against ~1.4KB for a task per target, at the cost of more CPU time as
+    /// each probe passes through the queue. Below this many targets neither
+    /// is significant, so the simpler task per target is preferred.
+    pub const POOL_THRESHOLD: usize = 5000;
+
+    /// Scheduler mode used by default for the given number of targets.
+    pub fn for_targets(targets: usize) -> Self {
+        if targets >= Self::POOL_THRESHOLD {
+            Self::Pool
+        } else {
+            Self::Tasks
+        }
+    }
+}
+
+impl FromStr for SchedulerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tasks" => Ok(Self::Tasks),
+            "pool" => Ok(Self::Pool),
+            _ => Err(format!(
+                "unknown scheduler mode '{s}', expected 'tasks' or 'pool'"
+            )),
+        }
+    }
+}
+
+/// What happens to the ticks which are missed as a probe overran the
+/// interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissedTicks {
+    /// Probe immediately for each missed tick, catching up with the ticks
+    /// of the interval.
+    Burst,
+    /// Probe immediately, then at the interval from then.
+    #[default]
+    Delay,
+    /// Probe at the next tick of the interval.
+    Skip,
+}
+
+impl MissedTicks {
+    pub(crate) fn behavior(self) -> MissedTickBehavior {
+        match self {
+            Self::Burst => MissedTickBehavior::Burst,
+            Self::Delay => MissedTickBehavior::Delay,
+            Self::Skip => MissedTickBehavior::Skip,
+        }
+    }
+
+    /// Tick after `tick` of the interval, once a probe of it has completed
+    /// at `now`.
+    pub(crate) fn next(self, tick: Instant, interval: Duration, now: Instant) -> Instant {
+        let next = tick + interval;
+        if next > now {
+            return next;
+        }
+        match self {
+            Self::Burst => next,
+            Self::Delay => now,
+            Self::Skip => {
+                let missed = (now - tick).as_nanos() / interval.as_nanos().max(1);
+                tick + interval * (missed as u32 + 1)
+            }
+        }
+    }
+}
+
+impl FromStr for MissedTicks {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "burst" => Ok(Self::Burst),
+            "delay" => Ok(Self::Delay),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "unknown missed ticks '{s}', expected 'burst', 'delay' or 'skip'"
+            )),
+        }
+    }
+}
+
+/// Randomisation of the times which targets are probed at, so that probes
+/// are spread across the interval rather than sent in a burst.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Jitter {
+    /// Whether each target is first probed after a random offset within the
+    /// interval, rather than once started.
+    pub spread_start: bool,
+    /// Greatest delay of each probe beyond its tick, as a percentage of the
+    /// interval, which is clamped to 100.
+    pub tick_percent: f64,
+}
+
+impl Jitter {
+    /// Delay before the first probe of a target.
+    pub(crate) fn offset(&self, interval: Duration) -> Duration {
+        match self.spread_start {
+            true => interval.mul_f64(rand::random()),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Delay of a probe beyond its tick.
+    pub(crate) fn delay(&self, interval: Duration) -> Duration {
+        match self.tick_percent > 0.0 {
+            true => interval.mul_f64(self.tick_percent.min(100.0) / 100.0 * rand::random::<f64>()),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Probe of a dispatcher whose deadline has passed.
+struct Job {
+    id: u64,
+    deadline: Instant,
+    dispatcher: Arc<Dispatcher>,
+    result_tx: mpsc::Sender<PingOutcome>,
+}
+
+/// Dispatcher within the pool.
+struct Pooled {
+    dispatcher: Arc<Dispatcher>,
+    result_tx: mpsc::Sender<PingOutcome>,
+    /// State of the dispatcher within the `uppies_dispatchers` gauge.
+    state: DispatcherState,
+    /// Whether the dispatcher is waiting to restart after failing.
+    restarting: bool,
+    /// Tick of the latest probe, which its deadline is jittered from.
+    tick: Instant,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Deadline of the next probe of each dispatcher, earliest first.
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    dispatchers: HashMap<u64, Pooled>,
+    /// Identifier of the next added dispatcher, as the deadlines of those
+    /// which are removed remain queued.
+    next_id: u64,
+}
+
+/// Pool of workers which probe dispatchers in order of their deadlines, see
+/// [`SchedulerMode::Pool`].
+///
+/// Clones share the same underlying pool.
+#[derive(Clone)]
+pub(crate) struct Pool {
+    queue: Arc<Mutex<Queue>>,
+    /// Notified when a deadline is queued, which may be earlier than that
+    /// being waited for.
+    queued: Arc<Notify>,
+    metrics: Option<PingMetrics>,
+}
+
+impl Pool {
+    /// Number of workers of a pool, which is the most probes which are
+    /// awaited at once.
+    pub(crate) const DEFAULT_WORKERS: usize = 512;
+
+    /// Start a pool with `workers` workers, which runs until the runtime is
+    /// shut down.
+    pub(crate) fn start(workers: usize, metrics: Option<PingMetrics>) -> Self {
+        let pool = Self {
+            queue: Arc::default(),
+            queued: Arc::default(),
+            metrics,
+        };
+        let workers = workers.max(1);
+        let (due_tx, due_rx) = mpsc::channel(workers);
+        let due_rx = Arc::new(tokio::sync::Mutex::new(due_rx));
+        tokio::spawn(pool.clone().schedule(due_tx));
+        for _ in 0..workers {
+            tokio::spawn(pool.clone().work(Arc::clone(&due_rx)));
+        }
+        pool
+    }
+
+    /// Add a dispatcher which is probed once started, after any offset of
+    /// its [`Jitter`], and at its interval thereafter, returning its identifier within the pool.
+    pub(crate) fn add(&self, dispatcher: Dispatcher, result_tx: mpsc::Sender<PingOutcome>) -> u64 {
+        let state = DispatcherState::enter(self.metrics.as_ref(), "running");
+        let interval = Duration::from_millis(dispatcher.ping_interval_ms);
+        let tick = Instant::now() + dispatcher.jitter.offset(interval);
+        let mut queue = self.queue.lock().expect("queue isn't poisoned");
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.dispatchers.insert(
+            id,
+            Pooled {
+                dispatcher: Arc::new(dispatcher),
+                result_tx,
+                state,
+                restarting: false,
+                tick,
+            },
+        );
+        queue.deadlines.push(Reverse((tick, id)));
+        drop(queue);
+        self.queued.notify_one();
+        id
+    }
+
+    /// Stop probing the dispatcher with the identifier `id`, although a
+    /// probe which is running is left to complete.
+    pub(crate) fn remove(&self, id: u64) {
+        let mut queue = self.queue.lock().expect("queue isn't poisoned");
+        queue.dispatchers.remove(&id);
+    }
+
+    /// Send the dispatchers whose deadlines have passed to the workers, in
+    /// order of deadline.
+    async fn schedule(self, due_tx: mpsc::Sender<Job>) {
+        loop {
+            let next = {
+                let mut queue = self.queue.lock().expect("queue isn't poisoned");
+                match queue.deadlines.peek() {
+                    Some(Reverse((deadline, _))) if *deadline <= Instant::now() => {
+                        let Reverse((deadline, id)) = queue.deadlines.pop().expect("peeked");
+                        // Dispatchers which were removed are skipped.
+                        let Some(pooled) = queue.dispatchers.get(&id) else {
+                            continue;
+                        };
+                        Ok(Job {
+                            id,
+                            deadline,
+                            dispatcher: Arc::clone(&pooled.dispatcher),
+                            result_tx: pooled.result_tx.clone(),
+                        })
+                    }
+                    next => Err(next.map(|Reverse((deadline, _))| *deadline)),
+                }
+            };
+            match next {
+                Ok(job) => {
+                    if let Some(metrics) = &self.metrics {
+                        let lag = Instant::now().saturating_duration_since(job.deadline);
+                        metrics.scheduler_lag.set(lag.as_secs_f64());
+                        metrics.scheduling_lag.observe(lag.as_secs_f64());
+                    }
+                    // Workers are busy until there is space for the job.
+                    if due_tx.send(job).await.is_err() {
+                        return;
+                    }
+                }
+                Err(Some(deadline)) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => {}
+                        _ = self.queued.notified() => {}
+                    }
+                }
+                Err(None) => self.queued.notified().await,
+            }
+        }
+    }
+
+    /// Probe the dispatchers which are due, queueing their next deadline.
+    async fn work(self, due_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Job>>>) {
+        loop {
+            let Some(job) = due_rx.lock().await.recv().await else {
+                return;
+            };
+            let result = job.dispatcher.tick(&job.result_tx).await;
+            let now = Instant::now();
+            let mut queue = self.queue.lock().expect("queue isn't poisoned");
+            let Some(pooled) = queue.dispatchers.get_mut(&job.id) else {
+                continue;
+            };
+            let label = &*job.dispatcher.label;
+            let deadline = match result {
+                Ok(()) => {
+                    if pooled.restarting {
+                        pooled.state = DispatcherState::enter(self.metrics.as_ref(), "running");
+                        pooled.restarting = false;
+                    }
+                    let interval = job.dispatcher.interval();
+                    let missed_ticks = job.dispatcher.missed_ticks;
+                    pooled.tick = missed_ticks.next(pooled.tick, interval, now);
+                    pooled.tick + job.dispatcher.jitter.delay(interval)
+                }
+                Err(e) => {
+                    error!(target = label, ?e, "dispatcher failed, restarting");
+                    if let Some(metrics) = &self.metrics {
+                        metrics.restart_count.with_label_values(&[label]).inc();
+                    }
+                    pooled.state = DispatcherState::enter(self.metrics.as_ref(), "restarting");
+                    pooled.restarting = true;
+                    pooled.tick = now + Dispatcher::RESTART_DELAY;
+                    pooled.tick
+                }
+            };
+            queue.deadlines.push(Reverse((deadline, job.id)));
+            drop(queue);
+            self.queued.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    #[cfg(feature = "metrics")]
+    use prometheus::Registry;
+    use tokio_stream::StreamExt;
+
+    use tokio::time::Instant;
+
+    use super::{Jitter, MissedTicks, SchedulerMode};
+    use crate::{ping_targets, probe::MockProbe, PingSender};
+
+    #[test]
+    fn missed_ticks() {
+        let tick = Instant::now();
+        let interval = Duration::from_millis(100);
+        let ms = Duration::from_millis;
+        for missed_ticks in [MissedTicks::Burst, MissedTicks::Delay, MissedTicks::Skip] {
+            assert_eq!(
+                missed_ticks.next(tick, interval, tick + ms(20)),
+                tick + ms(100)
+            );
+        }
+        let now = tick + ms(250);
+        assert_eq!(MissedTicks::Burst.next(tick, interval, now), tick + ms(100));
+        assert_eq!(MissedTicks::Delay.next(tick, interval, now), now);
+        assert_eq!(MissedTicks::Skip.next(tick, interval, now), tick + ms(300));
+        assert_eq!("skip".parse(), Ok(MissedTicks::Skip));
+        assert!("unknown".parse::<MissedTicks>().is_err());
+    }
+
+    #[test]
+    fn default_scheduler_mode() {
+        assert_eq!(SchedulerMode::for_targets(1), SchedulerMode::Tasks);
+        assert_eq!(
+            SchedulerMode::for_targets(SchedulerMode::POOL_THRESHOLD),
+            SchedulerMode::Pool
+        );
+        assert_eq!("pool".parse(), Ok(SchedulerMode::Pool));
+        assert!("unknown".parse::<SchedulerMode>().is_err());
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        let interval = Duration::from_secs(1);
+        assert_eq!(Jitter::default().offset(interval), Duration::ZERO);
+        assert_eq!(Jitter::default().delay(interval), Duration::ZERO);
+        let jitter = Jitter {
+            spread_start: true,
+            tick_percent: 200.0,
+        };
+        for _ in 0..100 {
+            assert!(jitter.offset(interval) < interval);
+            assert!(jitter.delay(interval) <= interval);
+        }
+    }
+
+    #[tokio::test]
+    async fn spread_start() {
+        for mode in [SchedulerMode::Tasks, SchedulerMode::Pool] {
+            let mut sender = PingSender::builder()
+                .with_ping_interval_ms(1000)
+                .build()
+                .unwrap()
+                .with_scheduler_mode(mode)
+                .with_jitter(Jitter {
+                    spread_start: true,
+                    tick_percent: 0.0,
+                });
+            for i in 0..20 {
+                let probe = MockProbe::new([Ok(Duration::ZERO)]);
+                sender = sender.with_probe(format!("mock-{i}").parse().unwrap(), probe);
+            }
+            let pings: Vec<_> = sender.results().take(20).collect().await;
+            let first = pings.first().unwrap().timestamp;
+            let last = pings.last().unwrap().timestamp;
+            assert!(
+                last.duration_since(first).unwrap() > Duration::from_millis(200),
+                "{mode:?} probes are spread across the interval"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pool() {
+        let probes: Vec<_> = (0..20)
+            .map(|_| Arc::new(MockProbe::new([Ok(Duration::from_millis(1))])))
+            .collect();
+        let builder = PingSender::builder().with_ping_interval_ms(100);
+        #[cfg(feature = "metrics")]
+        let builder = builder.with_registry(&Registry::new());
+        let mut sender = builder
+            .build()
+            .unwrap()
+            .with_scheduler_mode(SchedulerMode::Pool)
+            .with_workers(4);
+        for (i, probe) in probes.iter().enumerate() {
+            sender = sender.with_probe(format!("mock-{i}").parse().unwrap(), Arc::clone(probe));
+        }
+        #[cfg(feature = "metrics")]
+        let metrics = sender.metrics.clone().unwrap();
+        let target_set = sender.target_set();
+        tokio::spawn(ping_targets(sender));
+
+        tokio::time::sleep(Duration::from_millis(450)).await;
+        for probe in &probes {
+            assert!((4..=6).contains(&probe.probes()), "{}", probe.probes());
+        }
+        #[cfg(feature = "metrics")]
+        {
+            assert_eq!(
+                metrics.dispatchers.with_label_values(&["running"]).get(),
+                20
+            );
+            assert!(metrics.scheduler_lag.get() < 0.05);
+        }
+
+        assert!(target_set.remove("mock-0").unwrap());
+        let removed = probes[0].probes();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(probes[0].probes(), removed, "removed targets aren't probed");
+        assert!(probes[1].probes() > removed);
+        #[cfg(feature = "metrics")]
+        assert_eq!(
+            metrics.dispatchers.with_label_values(&["running"]).get(),
+            19
+        );
+    }
+
+    #[tokio::test]
+    async fn pool_sequences() {
+        let results = PingSender::builder()
+            .with_ping_interval_ms(50)
+            .build()
+            .unwrap()
+            .with_scheduler_mode(SchedulerMode::Pool)
+            .with_probe(
+                "mock".parse().unwrap(),
+                MockProbe::new([Ok(Duration::ZERO)]),
+            )
+            .results();
+        let pings: Vec<_> = results.take(3).collect().await;
+        assert_eq!(
+            pings.iter().map(|ping| ping.sequence).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+    }
+}
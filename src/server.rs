@@ -0,0 +1,439 @@
+//! Listeners of the HTTP server which serves metrics and the API.
+//!
+//! The server listens on either a TCP socket or, on Unix, a Unix domain
+//! socket, such as to be fronted by a local reverse proxy without opening a
+//! TCP port.
+//! Either can be served over TLS, for environments which forbid plaintext
+//! exporters on routable interfaces, where clients are optionally required
+//! to present a certificate issued by a trusted CA, so that only known
+//! scrapers can connect at all.
+
+use std::{fmt, future::Future, io, path::Path, str::FromStr, sync::Arc, time::Duration};
+#[cfg(unix)]
+use std::{os::unix::fs::FileTypeExt, path::PathBuf};
+
+use axum::{serve::Listener, Router};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
+use tokio_rustls::{
+    rustls::{
+        crypto::aws_lc_rs,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::debug;
+
+use crate::Result;
+
+/// Length of time which clients have to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address which the server listens on, either a TCP socket such as
+/// `0.0.0.0:9000`, or a Unix domain socket such as `unix:/run/uppies.sock`,
+/// which are only supported on Unix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ServerAddress {
+    /// Bind to the address, replacing a stale Unix socket of a previous
+    /// instance. A socket which is still being listened on is left alone.
+    pub async fn bind(&self) -> io::Result<Bound> {
+        match self {
+            Self::Tcp(address) => Ok(Bound::Tcp(TcpListener::bind(address).await?)),
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                let socket = std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket());
+                if socket {
+                    match std::os::unix::net::UnixStream::connect(path) {
+                        Ok(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::AddrInUse,
+                                format!("{} is in use by another instance", path.display()),
+                            ))
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            std::fs::remove_file(path)?;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(Bound::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+}
+
+impl FromStr for ServerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("unix socket address has no path".to_string()),
+            #[cfg(unix)]
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            #[cfg(not(unix))]
+            Some(_) => Err("unix socket addresses are only supported on Unix".to_string()),
+            None => Ok(Self::Tcp(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Listener which is bound to a [`ServerAddress`].
+pub enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Serve `app` on the listener, over TLS with the acceptor if any, until
+/// `shutdown` completes. The listener is then closed, and in-flight
+/// requests are served before this returns.
+pub async fn serve(
+    listener: Bound,
+    acceptor: Option<TlsAcceptor>,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    match (listener, acceptor) {
+        (Bound::Tcp(listener), None) => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        (Bound::Tcp(listener), Some(acceptor)) => {
+            axum::serve(TlsListener::new(listener, acceptor)?, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        #[cfg(unix)]
+        (Bound::Unix(listener), None) => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        #[cfg(unix)]
+        (Bound::Unix(listener), Some(acceptor)) => {
+            axum::serve(TlsListener::new(listener, acceptor)?, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    }
+}
+
+/// Acceptor of the certificate chain and key of the PEM files, which
+/// requires clients to present a certificate issued by `client_ca` if any.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read {}: {e}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("failed to read {}: {e}", key.display()))?;
+
+    let provider = Arc::new(aws_lc_rs::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let cas = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+                .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+            for ca in cas {
+                roots.add(ca)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Listener of connections of `L` which have completed a TLS handshake, to
+/// serve with [`axum::serve`].
+///
+/// Handshakes are completed concurrently, so that a slow or malicious client
+/// doesn't hold back others. The underlying listener is closed once this is
+/// dropped, such as when the server shuts down.
+pub struct TlsListener<L: Listener> {
+    accepted: mpsc::Receiver<(TlsStream<L::Io>, L::Addr)>,
+    local_addr: L::Addr,
+    /// Task which accepts connections of the underlying listener.
+    accepting: JoinHandle<()>,
+}
+
+impl<L> TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Clone + fmt::Debug + Sync,
+{
+    pub fn new(mut listener: L, acceptor: TlsAcceptor) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = mpsc::channel(64);
+        let accepting = tokio::spawn(async move {
+            loop {
+                // Errors of accepting are retried by the listener itself.
+                let (io, addr) = listener.accept().await;
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(io)).await {
+                        Ok(Ok(tls)) => {
+                            let _ = tx.send((tls, addr)).await;
+                        }
+                        Ok(Err(e)) => debug!(?addr, %e, "TLS handshake failed"),
+                        Err(_) => debug!(?addr, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            accepted,
+            local_addr,
+            accepting,
+        })
+    }
+}
+
+impl<L: Listener> Drop for TlsListener<L> {
+    fn drop(&mut self) {
+        // The accepting task owns the underlying listener, which would
+        // otherwise stay bound until the next connection.
+        self.accepting.abort();
+    }
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Clone + Sync,
+{
+    type Io = TlsStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accepting task only ends once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, path::Path, sync::Arc};
+
+    use axum::{routing, Router};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    #[cfg(unix)]
+    use tokio::net::UnixStream;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        rustls::{
+            crypto::aws_lc_rs,
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+
+    use super::{acceptor, serve as serve_app, Bound, ServerAddress, TlsListener};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix() {
+        assert_eq!(
+            "0.0.0.0:9000".parse(),
+            Ok(ServerAddress::Tcp("0.0.0.0:9000".to_string()))
+        );
+        assert!("unix:".parse::<ServerAddress>().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.sock");
+        let address: ServerAddress = format!("unix:{}", path.display()).parse().unwrap();
+        assert_eq!(address, ServerAddress::Unix(path.clone()));
+        // A socket left behind by a previous instance is replaced.
+        drop(address.bind().await.unwrap());
+        let listener = address.bind().await.unwrap();
+        assert_eq!(
+            address.bind().await.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::AddrInUse),
+            "a socket which is listened on isn't replaced"
+        );
+        let app = Router::new().route("/metrics", routing::get(|| async { "metrics" }));
+        let (shutdown, stopped) = tokio::sync::oneshot::channel::<()>();
+        let served = tokio::spawn(serve_app(listener, None, app, async move {
+            let _ = stopped.await;
+        }));
+
+        let mut unix = UnixStream::connect(&path).await.unwrap();
+        unix.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        unix.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        shutdown.send(()).unwrap();
+        served.await.unwrap().unwrap();
+        assert!(
+            UnixStream::connect(&path).await.is_err(),
+            "closed on shutdown"
+        );
+
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let file = ServerAddress::Unix(dir.path().join("file"));
+        assert!(file.bind().await.is_err(), "other files aren't replaced");
+    }
+
+    /// Send a request through TLS with the client certificate, if any,
+    /// returning the response.
+    async fn get(
+        addr: SocketAddr,
+        ca: CertificateDer<'static>,
+        client: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> std::io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let tcp = TcpStream::connect(addr).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut tls = TlsConnector::from(Arc::new(config))
+            .connect(server_name, tcp)
+            .await?;
+        tls.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        tls.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    /// Serve a route over TLS with the certificate, requiring client
+    /// certificates of the CA if any.
+    async fn serve(cert: &Path, key: &Path, client_ca: Option<&Path>) -> SocketAddr {
+        let acceptor = acceptor(cert, key, client_ca).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(listener, acceptor).unwrap();
+        let addr = axum::serve::Listener::local_addr(&listener).unwrap();
+        let app = Router::new().route("/metrics", routing::get(|| async { "metrics" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, pem: String| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::from_params(&ca_params, &ca_key);
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &issuer)
+            .unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client = CertificateParams::new(vec!["scraper".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &issuer)
+            .unwrap();
+        let cert = write("cert.pem", server.pem());
+        let key = write("key.pem", server_key.serialize_pem());
+        let ca_file = write("ca.pem", ca.pem());
+        let ca = ca.der().clone();
+        let client = || {
+            let key = PrivatePkcs8KeyDer::from(client_key.serialize_der());
+            Some((client.der().clone(), PrivateKeyDer::Pkcs8(key)))
+        };
+
+        let addr = serve(&cert, &key, None).await;
+        let response = get(addr, ca.clone(), None).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("metrics"));
+        let plaintext = reqwest::get(format!("http://{addr}/metrics")).await;
+        assert!(plaintext.is_err(), "plaintext is refused");
+
+        let addr = serve(&cert, &key, Some(&ca_file)).await;
+        let response = get(addr, ca.clone(), client()).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(
+            get(addr, ca, None).await.is_err(),
+            "clients without a certificate are refused"
+        );
+
+        assert!(acceptor(&dir.path().join("missing.pem"), &key, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        let acceptor = acceptor(&cert_path, &key_path, None).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/metrics", routing::get(|| async { "metrics" }));
+        let (shutdown, stopped) = tokio::sync::oneshot::channel::<()>();
+        let listener = Bound::Tcp(listener);
+        let served = tokio::spawn(serve_app(listener, Some(acceptor), app, async move {
+            let _ = stopped.await;
+        }));
+        shutdown.send(()).unwrap();
+        served.await.unwrap().unwrap();
+        // Let the aborted accepting task be dropped.
+        tokio::task::yield_now().await;
+
+        TcpListener::bind(addr)
+            .await
+            .expect("listener is closed on shutdown");
+    }
+}
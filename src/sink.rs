@@ -0,0 +1,75 @@
+//! Sinks receive the result of every ping, allowing results to be exported
+//! to systems other than Prometheus.
+//!
+//! Any number of sinks can be configured simultaneously.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::{destination::Destination, PingOutcome, Result};
+
+pub mod influx;
+pub mod log;
+pub mod sqlite;
+pub mod statsd;
+
+use influx::{InfluxConfig, InfluxSink};
+use log::{LogConfig, LogSink};
+use sqlite::{SqliteConfig, SqliteSink};
+use statsd::{StatsdConfig, StatsdSink};
+
+/// A destination for the results of pings.
+pub trait Sink: Send + Sync {
+    /// Record the outcome of a single ping.
+    ///
+    /// This is called from the task receiving results, so implementations
+    /// should not block.
+    fn record(&self, outcome: &PingOutcome);
+
+    /// Whether the sink is currently recording results successfully, such
+    /// as when its most recent write succeeded.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    /// Forget the probe `probe` of `target` once it is no longer pinged,
+    /// such as by removing its series.
+    ///
+    /// This is called from the task removing the target, so implementations
+    /// should not block.
+    fn remove(&self, _target: &str, _probe: &str) {}
+}
+
+/// Configuration of a [`Sink`], selected by its `type`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    Statsd(StatsdConfig),
+    Influx(InfluxConfig),
+    Sqlite(SqliteConfig),
+    Log(LogConfig),
+}
+
+impl SinkConfig {
+    /// Name of the type of sink, which its health is reported under.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Statsd(_) => "statsd",
+            Self::Influx(_) => "influx",
+            Self::Sqlite(_) => "sqlite",
+            Self::Log(_) => "log",
+        }
+    }
+
+    /// Build the configured [`Sink`], which reports its deliveries to
+    /// `destination`.
+    pub fn build(&self, destination: Destination) -> Result<Arc<dyn Sink>> {
+        match self {
+            Self::Statsd(config) => Ok(Arc::new(StatsdSink::new(config, destination)?)),
+            Self::Influx(config) => Ok(Arc::new(InfluxSink::new(config, destination)?)),
+            Self::Sqlite(config) => Ok(Arc::new(SqliteSink::new(config, destination)?)),
+            Self::Log(config) => Ok(Arc::new(LogSink::new(config, destination)?)),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Sink which writes results in the InfluxDB line protocol, either to a file
+//! (or stdout) or to the HTTP write API of an InfluxDB server.
+
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, warn};
+
+use super::Sink;
+use crate::{destination::Destination, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxConfig {
+    /// File to append lines to, or `-` for stdout.
+    pub path: Option<PathBuf>,
+    /// Write API of an InfluxDB server, including the query parameters for
+    /// the destination, such as
+    /// `http://localhost:8086/api/v2/write?org=home&bucket=uppies`.
+    pub url: Option<String>,
+    /// Token used to authenticate with the write API.
+    pub token: Option<String>,
+    /// Name of the measurement which lines are written for.
+    #[serde(default = "InfluxConfig::default_measurement")]
+    pub measurement: String,
+    /// Number of lines written at once.
+    #[serde(default = "InfluxConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// Interval, in milliseconds, after which a partial batch is written.
+    #[serde(default = "InfluxConfig::default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+impl InfluxConfig {
+    fn default_measurement() -> String {
+        "ping".to_string()
+    }
+
+    fn default_batch_size() -> usize {
+        100
+    }
+
+    fn default_flush_interval_ms() -> u64 {
+        1000
+    }
+}
+
+/// Where batches of lines are written to.
+enum Output {
+    Stdout,
+    File(std::fs::File),
+    Http {
+        client: reqwest::Client,
+        url: String,
+        token: Option<String>,
+    },
+}
+
+impl Output {
+    async fn write(&mut self, lines: &str) -> Result<()> {
+        match self {
+            Self::Stdout => std::io::stdout().lock().write_all(lines.as_bytes())?,
+            Self::File(file) => file.write_all(lines.as_bytes())?,
+            Self::Http { client, url, token } => {
+                let mut request = client.post(url.as_str()).body(lines.to_string());
+                if let Some(token) = token {
+                    request = request.header("Authorization", format!("Token {token}"));
+                }
+                request.send().await?.error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct InfluxSink {
+    measurement: String,
+    tx: mpsc::Sender<String>,
+    /// Health of the sink, healthy when the most recent write succeeded
+    /// without lines being dropped.
+    destination: Destination,
+}
+
+impl InfluxSink {
+    /// Number of lines which can be queued before they are dropped.
+    const CHANNEL_SIZE: usize = 1024;
+
+    pub fn new(config: &InfluxConfig, destination: Destination) -> Result<Self> {
+        let mut output = match (&config.path, &config.url) {
+            (Some(path), None) if path.as_os_str() == "-" => Output::Stdout,
+            (Some(path), None) => Output::File(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            (None, Some(url)) => Output::Http {
+                client: reqwest::Client::builder().build()?,
+                url: url.clone(),
+                token: config.token.clone(),
+            },
+            _ => {
+                return Err("influx sink requires exactly one of 'path' or 'url'"
+                    .to_string()
+                    .into())
+            }
+        };
+
+        let (tx, mut rx) = mpsc::channel::<String>(Self::CHANNEL_SIZE);
+        let batch_size = config.batch_size;
+        let flush_interval = Duration::from_millis(config.flush_interval_ms);
+        let writer = destination.clone();
+        tokio::spawn(async move {
+            let mut lines = String::new();
+            let mut pending = 0;
+            let mut interval = tokio::time::interval(flush_interval);
+            loop {
+                let closed = tokio::select! {
+                    line = rx.recv() => match line {
+                        Some(line) => {
+                            writer.dequeued();
+                            lines.push_str(&line);
+                            pending += 1;
+                            if pending < batch_size {
+                                continue;
+                            }
+                            false
+                        }
+                        None => true,
+                    },
+                    _ = interval.tick() => false,
+                };
+                if pending > 0 {
+                    match output.write(&lines).await {
+                        Ok(()) => writer.succeeded(),
+                        Err(e) => {
+                            error!(?e, lines = pending, "failed to write influx lines");
+                            writer.failed(e);
+                        }
+                    }
+                    lines.clear();
+                    pending = 0;
+                }
+                if closed {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            measurement: config.measurement.clone(),
+            tx,
+            destination,
+        })
+    }
+}
+
+impl Sink for InfluxSink {
+    fn record(&self, outcome: &PingOutcome) {
+        let target = &*outcome.target;
+        self.destination.enqueued();
+        let error = match self.tx.try_send(line(&self.measurement, outcome)) {
+            Ok(()) => return,
+            Err(TrySendError::Full(_)) => {
+                warn!(target, "influx sink is full, dropping line");
+                "sink is full"
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!(target, "influx sink closed");
+                "sink is closed"
+            }
+        };
+        self.destination.dequeued();
+        self.destination.failed(error);
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.destination.is_healthy()
+    }
+}
+
+/// Format an outcome as a single line, with a nanosecond timestamp.
+fn line(measurement: &str, outcome: &PingOutcome) -> String {
+    let mut line = format!(
+        "{},probe={},target={} ",
+        escape(measurement, ", "),
+        escape(&outcome.probe, ",= "),
+        escape(&outcome.target, ",= ")
+    );
+    match &outcome.rtt {
+        Ok(_) => {
+            line.push_str("success=true");
+            if let Some(d) = outcome.latency() {
+                let _ = write!(line, ",rtt_ms={}", d.as_secs_f64() * 1000.0);
+            }
+        }
+        Err(e) => {
+            let error = e.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = write!(line, "success=false,error=\"{error}\"");
+        }
+    }
+    let timestamp = outcome
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .expect("time after epoch");
+    let _ = writeln!(line, " {}", timestamp.as_nanos());
+    line
+}
+
+/// Escape the given special characters with a backslash.
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use axum::{extract::State, routing::post, Router};
+    use tokio::net::TcpListener;
+
+    use super::{line, InfluxConfig, InfluxSink};
+    use crate::{sink::Sink, ErrorKind, Expect, PingError, PingOutcome};
+
+    fn config() -> InfluxConfig {
+        InfluxConfig {
+            path: None,
+            url: None,
+            token: None,
+            measurement: InfluxConfig::default_measurement(),
+            batch_size: 2,
+            flush_interval_ms: 60_000,
+        }
+    }
+
+    #[test]
+    fn format_lines() {
+        let outcome = |target, rtt| PingOutcome {
+            rtt,
+            timestamp: UNIX_EPOCH + Duration::from_secs(1),
+            ..PingOutcome::test(target, Ok(Duration::ZERO))
+        };
+        assert_eq!(
+            line(
+                "ping",
+                &outcome("127.0.0.1", Ok(Duration::from_micros(1500)))
+            ),
+            "ping,probe=icmp,target=127.0.0.1 success=true,rtt_ms=1.5 1000000000\n"
+        );
+        let error = PingError {
+            kind: ErrorKind::Other,
+            message: "said \"no\"".to_string(),
+        };
+        assert_eq!(
+            line("ping", &outcome("my host,a=b", Err(error))),
+            "ping,probe=icmp,target=my\\ host\\,a\\=b success=false,error=\"said \\\"no\\\"\" 1000000000\n"
+        );
+        // Targets expected to be down have no round-trip time.
+        let down = PingOutcome {
+            expect: Expect::Down,
+            ..outcome("10.0.0.1", Ok(Duration::from_secs(1)))
+        };
+        assert_eq!(
+            line("ping", &down),
+            "ping,probe=icmp,target=10.0.0.1 success=true 1000000000\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn write_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("results.lp");
+        let sink = InfluxSink::new(
+            &InfluxConfig {
+                path: Some(path.clone()),
+                ..config()
+            },
+            Default::default(),
+        )
+        .unwrap();
+
+        sink.record(&PingOutcome::test(
+            "127.0.0.1",
+            Ok(Duration::from_millis(1)),
+        ));
+        sink.record(&PingOutcome::test("127.0.0.1", Err(ErrorKind::Timeout)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+    }
+
+    type Received = Arc<Mutex<Vec<(Option<String>, String)>>>;
+
+    async fn write(State(received): State<Received>, headers: axum::http::HeaderMap, body: String) {
+        let token = headers
+            .get("Authorization")
+            .map(|v| v.to_str().unwrap().to_string());
+        received.lock().unwrap().push((token, body));
+    }
+
+    #[tokio::test]
+    async fn write_to_http() {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/api/v2/write", post(write))
+            .with_state(Arc::clone(&received));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let sink = InfluxSink::new(
+            &InfluxConfig {
+                url: Some(format!("http://{addr}/api/v2/write?bucket=uppies")),
+                token: Some("secret".to_string()),
+                ..config()
+            },
+            Default::default(),
+        )
+        .unwrap();
+        sink.record(&PingOutcome::test(
+            "127.0.0.1",
+            Ok(Duration::from_millis(1)),
+        ));
+        sink.record(&PingOutcome::test(
+            "127.0.0.1",
+            Ok(Duration::from_millis(2)),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0.as_deref(), Some("Token secret"));
+        assert_eq!(received[0].1.lines().count(), 2);
+    }
+
+    #[test]
+    fn requires_single_output() {
+        assert!(InfluxSink::new(&config(), Default::default()).is_err());
+    }
+}
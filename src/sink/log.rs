@@ -0,0 +1,129 @@
+//! Sink which logs ping results at info level as structured events, with
+//! the same fields as `--log-format json` gives the logs of each ping.
+//!
+//! Results can be sampled, such as logging one in every 100 successes of
+//! each target but every failure, so that frequent pings don't flood the
+//! logs while failures remain visible.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use tracing::info;
+
+use super::Sink;
+use crate::{destination::Destination, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log one in this many successful pings of each target.
+    #[serde(default = "LogConfig::default_sample")]
+    pub sample_successes: u64,
+    /// Log one in this many failed pings of each target.
+    #[serde(default = "LogConfig::default_sample")]
+    pub sample_failures: u64,
+}
+
+impl LogConfig {
+    fn default_sample() -> u64 {
+        1
+    }
+}
+
+pub struct LogSink {
+    sample_successes: u64,
+    sample_failures: u64,
+    /// Number of successful and failed pings seen of each target.
+    counts: Mutex<HashMap<Arc<str>, [u64; 2]>>,
+    destination: Destination,
+}
+
+impl LogSink {
+    pub fn new(config: &LogConfig, destination: Destination) -> Result<Self> {
+        if config.sample_successes == 0 || config.sample_failures == 0 {
+            return Err("log sink samples must be at least 1".to_string().into());
+        }
+        Ok(Self {
+            sample_successes: config.sample_successes,
+            sample_failures: config.sample_failures,
+            counts: Mutex::new(HashMap::new()),
+            destination,
+        })
+    }
+
+    /// Whether `outcome` is within the sample, counting the successes and
+    /// failures of each target separately so that either is sampled evenly.
+    fn sampled(&self, outcome: &PingOutcome) -> bool {
+        let (i, every) = match outcome.rtt {
+            Ok(_) => (0, self.sample_successes),
+            Err(_) => (1, self.sample_failures),
+        };
+        let mut counts = self.counts.lock().expect("log sink lock poisoned");
+        let count = &mut counts.entry(Arc::clone(&outcome.target)).or_default()[i];
+        *count += 1;
+        (*count - 1).is_multiple_of(every)
+    }
+}
+
+impl Sink for LogSink {
+    fn record(&self, outcome: &PingOutcome) {
+        if !self.sampled(outcome) {
+            return;
+        }
+        let target = &*outcome.target;
+        match &outcome.rtt {
+            Ok(_) => info!(
+                target,
+                seq = outcome.sequence,
+                rtt_ms = outcome.latency().map(|d| d.as_secs_f64() * 1000.0),
+                "ping result"
+            ),
+            Err(e) => info!(
+                target,
+                seq = outcome.sequence,
+                error_kind = e.kind.as_str(),
+                %e,
+                "ping result"
+            ),
+        }
+        self.destination.succeeded();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{LogConfig, LogSink};
+    use crate::{ErrorKind, PingOutcome};
+
+    #[test]
+    fn sample_successes() {
+        let config = LogConfig {
+            sample_successes: 100,
+            sample_failures: 1,
+        };
+        let sink = LogSink::new(&config, Default::default()).unwrap();
+        let success = PingOutcome::test("1.1.1.1", Ok(Duration::from_millis(1)));
+        let failure = PingOutcome::test("1.1.1.1", Err(ErrorKind::Timeout));
+        let mut logged = 0;
+        for _ in 0..300 {
+            logged += usize::from(sink.sampled(&success));
+            assert!(sink.sampled(&failure), "every failure is logged");
+        }
+        assert_eq!(logged, 3);
+        assert!(
+            sink.sampled(&PingOutcome::test("8.8.8.8", Ok(Duration::from_millis(1)))),
+            "targets are sampled separately"
+        );
+
+        let config = LogConfig {
+            sample_successes: 0,
+            sample_failures: 1,
+        };
+        assert!(LogSink::new(&config, Default::default()).is_err());
+    }
+}
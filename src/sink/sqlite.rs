@@ -0,0 +1,639 @@
+//! Sink which writes results to a SQLite database, so that history is kept
+//! across restarts and can be analysed offline with plain SQL.
+//!
+//! Every result is written to the `results` table, or when downsampling,
+//! an aggregate of each probe of a target over each interval is written to
+//! the `aggregates` table instead:
+//!
+//! ```sql
+//! CREATE TABLE results (
+//!     target TEXT NOT NULL,
+//!     timestamp_ms INTEGER NOT NULL,
+//!     rtt_ms REAL,
+//!     error TEXT,
+//!     probe TEXT NOT NULL DEFAULT ''
+//! );
+//! CREATE TABLE aggregates (
+//!     target TEXT NOT NULL,
+//!     start_ms INTEGER NOT NULL,
+//!     count INTEGER NOT NULL,
+//!     failures INTEGER NOT NULL,
+//!     rtt_min_ms REAL,
+//!     rtt_avg_ms REAL,
+//!     rtt_max_ms REAL,
+//!     probe TEXT NOT NULL DEFAULT '',
+//!     PRIMARY KEY (target, probe, start_ms)
+//! );
+//! ```
+//!
+//! Databases written before results were labelled by probe are migrated
+//! when opened, leaving the probe of their existing rows empty.
+//!
+//! Rows older than the retention are deleted periodically, reclaiming their
+//! space within the file.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use tracing::{debug, error, info, warn};
+
+use super::Sink;
+use crate::{destination::Destination, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SqliteConfig {
+    /// Database file, which is created if it does not exist.
+    pub path: PathBuf,
+    /// Interval, in seconds, which results are aggregated over instead of
+    /// writing every result.
+    pub downsample_secs: Option<u64>,
+    /// Age, in seconds, after which rows are deleted, or never when unset.
+    pub retention_secs: Option<u64>,
+    /// Interval, in seconds, between deleting rows beyond the retention.
+    #[serde(default = "SqliteConfig::default_compact_interval_secs")]
+    pub compact_interval_secs: u64,
+    /// Number of rows written within each transaction.
+    #[serde(default = "SqliteConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// Interval, in milliseconds, after which a partial batch is written.
+    #[serde(default = "SqliteConfig::default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+impl SqliteConfig {
+    fn default_compact_interval_secs() -> u64 {
+        3600
+    }
+
+    fn default_batch_size() -> usize {
+        500
+    }
+
+    fn default_flush_interval_ms() -> u64 {
+        1000
+    }
+}
+
+/// A single result, as written to the `results` table.
+struct Row {
+    target: Arc<str>,
+    probe: Arc<str>,
+    timestamp_ms: i64,
+    rtt_ms: Option<f64>,
+    error: Option<String>,
+}
+
+/// Aggregate of the results of a probe of a target over an interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Aggregate {
+    count: u64,
+    failures: u64,
+    /// Number of results with a round-trip time.
+    rtts: u64,
+    rtt_min_ms: Option<f64>,
+    rtt_sum_ms: f64,
+    rtt_max_ms: Option<f64>,
+}
+
+impl Aggregate {
+    fn add(&mut self, row: &Row) {
+        self.count += 1;
+        if row.error.is_some() {
+            self.failures += 1;
+        }
+        let Some(rtt_ms) = row.rtt_ms else {
+            return;
+        };
+        self.rtts += 1;
+        self.rtt_sum_ms += rtt_ms;
+        self.rtt_min_ms = Some(self.rtt_min_ms.map_or(rtt_ms, |min| min.min(rtt_ms)));
+        self.rtt_max_ms = Some(self.rtt_max_ms.map_or(rtt_ms, |max| max.max(rtt_ms)));
+    }
+
+    fn rtt_avg_ms(&self) -> Option<f64> {
+        (self.rtts > 0).then(|| self.rtt_sum_ms / self.rtts as f64)
+    }
+}
+
+pub struct SqliteSink {
+    tx: SyncSender<Row>,
+    /// Health of the sink, healthy when the most recent write succeeded
+    /// without results being dropped.
+    destination: Destination,
+}
+
+impl SqliteSink {
+    /// Number of results which can be queued before they are dropped.
+    const CHANNEL_SIZE: usize = 4096;
+
+    pub fn new(config: &SqliteConfig, destination: Destination) -> Result<Self> {
+        let connection = open(&config.path)?;
+        let (tx, rx) = mpsc::sync_channel(Self::CHANNEL_SIZE);
+        let writer = Writer {
+            connection,
+            downsample_ms: config.downsample_secs.map(|secs| secs.max(1) as i64 * 1000),
+            retention: config.retention_secs.map(Duration::from_secs),
+            compact_interval: Duration::from_secs(config.compact_interval_secs),
+            batch_size: config.batch_size.max(1),
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            destination: destination.clone(),
+        };
+        info!(path = %config.path.display(), downsample_secs = config.downsample_secs, "writing results to sqlite");
+        // SQLite blocks, so it is written from a thread of its own rather
+        // than the async runtime.
+        std::thread::Builder::new()
+            .name("sqlite-sink".to_string())
+            .spawn(move || writer.run(rx))?;
+        Ok(Self { tx, destination })
+    }
+}
+
+impl Sink for SqliteSink {
+    fn record(&self, outcome: &PingOutcome) {
+        let row = Row {
+            target: Arc::clone(&outcome.target),
+            probe: Arc::clone(&outcome.probe),
+            timestamp_ms: outcome
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64,
+            rtt_ms: outcome.latency().map(|d| d.as_secs_f64() * 1000.0),
+            error: outcome.rtt.as_ref().err().map(|e| e.to_string()),
+        };
+        let target = &*outcome.target;
+        self.destination.enqueued();
+        let error = match self.tx.try_send(row) {
+            Ok(()) => return,
+            Err(TrySendError::Full(_)) => {
+                warn!(target, "sqlite sink is full, dropping result");
+                "sink is full"
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!(target, "sqlite sink closed");
+                "sink is closed"
+            }
+        };
+        self.destination.dequeued();
+        self.destination.failed(error);
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.destination.is_healthy()
+    }
+}
+
+/// Open the database at `path`, creating or migrating its tables if needed.
+pub(crate) fn open(path: &Path) -> Result<Connection> {
+    let connection = Connection::open(path)
+        .map_err(|e| format!("cannot open sqlite database {}: {e}", path.display()))?;
+    // Incremental vacuuming only applies to new databases, allowing the space
+    // of deleted rows to be reclaimed without rewriting the whole file.
+    connection.execute_batch(
+        "PRAGMA auto_vacuum = INCREMENTAL;
+         PRAGMA journal_mode = WAL;
+         CREATE TABLE IF NOT EXISTS results (
+             target TEXT NOT NULL,
+             timestamp_ms INTEGER NOT NULL,
+             rtt_ms REAL,
+             error TEXT,
+             probe TEXT NOT NULL DEFAULT ''
+         );
+         CREATE INDEX IF NOT EXISTS results_target_timestamp
+             ON results (target, timestamp_ms);
+         CREATE TABLE IF NOT EXISTS aggregates (
+             target TEXT NOT NULL,
+             start_ms INTEGER NOT NULL,
+             count INTEGER NOT NULL,
+             failures INTEGER NOT NULL,
+             rtt_min_ms REAL,
+             rtt_avg_ms REAL,
+             rtt_max_ms REAL,
+             probe TEXT NOT NULL DEFAULT '',
+             PRIMARY KEY (target, probe, start_ms)
+         );",
+    )?;
+    let has_probe = |table: &str| {
+        connection
+            .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = 'probe'")?
+            .exists([table])
+    };
+    if !has_probe("results")? {
+        info!(path = %path.display(), "adding probe to sqlite results");
+        connection
+            .execute_batch("ALTER TABLE results ADD COLUMN probe TEXT NOT NULL DEFAULT '';")?;
+    }
+    // The probe is part of the primary key of aggregates, so the table is
+    // rebuilt rather than altered.
+    if !has_probe("aggregates")? {
+        info!(path = %path.display(), "adding probe to sqlite aggregates");
+        connection.execute_batch(
+            "BEGIN;
+             ALTER TABLE aggregates RENAME TO aggregates_without_probe;
+             CREATE TABLE aggregates (
+                 target TEXT NOT NULL,
+                 start_ms INTEGER NOT NULL,
+                 count INTEGER NOT NULL,
+                 failures INTEGER NOT NULL,
+                 rtt_min_ms REAL,
+                 rtt_avg_ms REAL,
+                 rtt_max_ms REAL,
+                 probe TEXT NOT NULL DEFAULT '',
+                 PRIMARY KEY (target, probe, start_ms)
+             );
+             INSERT INTO aggregates
+                 (target, start_ms, count, failures, rtt_min_ms, rtt_avg_ms, rtt_max_ms)
+                 SELECT target, start_ms, count, failures, rtt_min_ms, rtt_avg_ms, rtt_max_ms
+                 FROM aggregates_without_probe;
+             DROP TABLE aggregates_without_probe;
+             COMMIT;",
+        )?;
+    }
+    Ok(connection)
+}
+
+struct Writer {
+    connection: Connection,
+    downsample_ms: Option<i64>,
+    retention: Option<Duration>,
+    compact_interval: Duration,
+    batch_size: usize,
+    flush_interval: Duration,
+    destination: Destination,
+}
+
+impl Writer {
+    fn run(mut self, rx: mpsc::Receiver<Row>) {
+        let mut rows = Vec::new();
+        // Aggregates of each probe of a target by the start of their
+        // interval, which are written once the interval is over.
+        let mut aggregates = BTreeMap::<(Arc<str>, Arc<str>, i64), Aggregate>::new();
+        let mut last_flush = Instant::now();
+        let mut last_compact = Instant::now();
+        loop {
+            let timeout = self.flush_interval.saturating_sub(last_flush.elapsed());
+            let closed = match rx.recv_timeout(timeout) {
+                Ok(row) => {
+                    self.destination.dequeued();
+                    match self.downsample_ms {
+                        Some(interval) => {
+                            let start_ms = row.timestamp_ms - row.timestamp_ms % interval;
+                            aggregates
+                                .entry((Arc::clone(&row.target), Arc::clone(&row.probe), start_ms))
+                                .or_default()
+                                .add(&row);
+                        }
+                        None => rows.push(row),
+                    }
+                    if rows.len() < self.batch_size && last_flush.elapsed() < self.flush_interval {
+                        continue;
+                    }
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+
+            let completed = match self.downsample_ms {
+                // Every aggregate is written on shutdown, as partial
+                // aggregates are merged with any of the same interval.
+                _ if closed => std::mem::take(&mut aggregates),
+                Some(interval) => {
+                    let now_ms = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as i64;
+                    let (completed, pending) = std::mem::take(&mut aggregates)
+                        .into_iter()
+                        .partition(|((.., start_ms), _)| start_ms + interval <= now_ms);
+                    aggregates = pending;
+                    completed
+                }
+                None => BTreeMap::new(),
+            };
+            if !rows.is_empty() || !completed.is_empty() {
+                let count = rows.len() + completed.len();
+                match self.write(&rows, &completed) {
+                    Ok(()) => {
+                        debug!(rows = count, "wrote sqlite rows");
+                        self.destination.succeeded();
+                    }
+                    Err(e) => {
+                        error!(?e, rows = count, "failed to write sqlite rows");
+                        self.destination.failed(e);
+                    }
+                }
+                rows.clear();
+            }
+            last_flush = Instant::now();
+
+            if closed {
+                return;
+            }
+            if last_compact.elapsed() >= self.compact_interval {
+                if let Err(e) = self.compact(SystemTime::now()) {
+                    error!(?e, "failed to compact sqlite database");
+                }
+                last_compact = Instant::now();
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        rows: &[Row],
+        aggregates: &BTreeMap<(Arc<str>, Arc<str>, i64), Aggregate>,
+    ) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT INTO results (target, probe, timestamp_ms, rtt_ms, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for row in rows {
+                insert.execute(params![
+                    &*row.target,
+                    &*row.probe,
+                    row.timestamp_ms,
+                    row.rtt_ms,
+                    row.error
+                ])?;
+            }
+            // Aggregates of an interval which was already written, such as
+            // before a restart, are merged into the existing row.
+            let mut upsert = transaction.prepare_cached(
+                "INSERT INTO aggregates
+                     (target, probe, start_ms, count, failures, rtt_min_ms, rtt_avg_ms, rtt_max_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (target, probe, start_ms) DO UPDATE SET
+                     count = count + excluded.count,
+                     failures = failures + excluded.failures,
+                     rtt_min_ms = min(coalesce(rtt_min_ms, excluded.rtt_min_ms),
+                                      coalesce(excluded.rtt_min_ms, rtt_min_ms)),
+                     rtt_max_ms = max(coalesce(rtt_max_ms, excluded.rtt_max_ms),
+                                      coalesce(excluded.rtt_max_ms, rtt_max_ms)),
+                     rtt_avg_ms = CASE
+                         WHEN excluded.rtt_avg_ms IS NULL THEN rtt_avg_ms
+                         WHEN rtt_avg_ms IS NULL THEN excluded.rtt_avg_ms
+                         ELSE (rtt_avg_ms * (count - failures)
+                               + excluded.rtt_avg_ms * (excluded.count - excluded.failures))
+                              / (count - failures + excluded.count - excluded.failures)
+                     END",
+            )?;
+            for ((target, probe, start_ms), aggregate) in aggregates {
+                upsert.execute(params![
+                    &**target,
+                    &**probe,
+                    start_ms,
+                    aggregate.count as i64,
+                    aggregate.failures as i64,
+                    aggregate.rtt_min_ms,
+                    aggregate.rtt_avg_ms(),
+                    aggregate.rtt_max_ms,
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Delete rows beyond the retention at `now`, reclaiming their space.
+    fn compact(&self, now: SystemTime) -> Result<()> {
+        let Some(retention) = self.retention else {
+            return Ok(());
+        };
+        let cutoff_ms = now
+            .checked_sub(retention)
+            .unwrap_or(UNIX_EPOCH)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let results = self
+            .connection
+            .execute("DELETE FROM results WHERE timestamp_ms < ?1", [cutoff_ms])?;
+        let aggregates = self
+            .connection
+            .execute("DELETE FROM aggregates WHERE start_ms < ?1", [cutoff_ms])?;
+        self.connection
+            .execute_batch("PRAGMA incremental_vacuum;")?;
+        info!(results, aggregates, "deleted sqlite rows beyond retention");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::Path,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use rusqlite::Connection;
+
+    use super::{SqliteConfig, SqliteSink};
+    use crate::{sink::Sink, ErrorKind, PingOutcome};
+
+    fn config(path: &Path) -> SqliteConfig {
+        SqliteConfig {
+            path: path.to_path_buf(),
+            downsample_secs: None,
+            retention_secs: None,
+            compact_interval_secs: SqliteConfig::default_compact_interval_secs(),
+            batch_size: SqliteConfig::default_batch_size(),
+            flush_interval_ms: 10,
+        }
+    }
+
+    fn ping(secs: u64, rtt: Result<Duration, ErrorKind>) -> PingOutcome {
+        PingOutcome {
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            ..PingOutcome::test("127.0.0.1", rtt)
+        }
+    }
+
+    /// Poll `query` until it returns `expected`.
+    async fn wait_for<T: PartialEq + std::fmt::Debug>(
+        path: &Path,
+        query: impl Fn(&Connection) -> rusqlite::Result<T>,
+        expected: T,
+    ) {
+        let connection = Connection::open(path).unwrap();
+        let mut actual = None;
+        for _ in 0..100 {
+            actual = Some(query(&connection).unwrap());
+            if actual.as_ref() == Some(&expected) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(actual, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn write_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.db");
+        let sink = SqliteSink::new(&config(&path), Default::default()).unwrap();
+        sink.record(&ping(1, Ok(Duration::from_millis(2))));
+        sink.record(&ping(2, Err(ErrorKind::Timeout)));
+
+        let rows = |connection: &Connection| {
+            connection
+                .prepare(
+                    "SELECT probe, timestamp_ms, rtt_ms, error FROM results ORDER BY timestamp_ms",
+                )?
+                .query_map([], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(String, i64, Option<f64>, Option<String>)>>>()
+        };
+        wait_for(
+            &path,
+            rows,
+            vec![
+                ("icmp".to_string(), 1000, Some(2.0), None),
+                ("icmp".to_string(), 2000, None, Some("timeout".to_string())),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn downsample() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.db");
+        let config = SqliteConfig {
+            downsample_secs: Some(60),
+            ..config(&path)
+        };
+        let sink = SqliteSink::new(&config, Default::default()).unwrap();
+        sink.record(&ping(0, Ok(Duration::from_millis(2))));
+        sink.record(&ping(30, Ok(Duration::from_millis(4))));
+        sink.record(&ping(59, Err(ErrorKind::Timeout)));
+        sink.record(&ping(60, Ok(Duration::from_millis(1))));
+        drop(sink);
+
+        let second = SqliteSink::new(&config, Default::default()).unwrap();
+        second.record(&ping(61, Ok(Duration::from_millis(3))));
+
+        let aggregates = |connection: &Connection| {
+            connection
+                .prepare(
+                    "SELECT start_ms, count, failures, rtt_min_ms, rtt_avg_ms, rtt_max_ms
+                     FROM aggregates ORDER BY start_ms",
+                )?
+                .query_map([], |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<(i64, i64, i64, f64, f64, f64)>>>()
+        };
+        wait_for(
+            &path,
+            aggregates,
+            vec![(0, 3, 1, 2.0, 3.0, 4.0), (60_000, 2, 0, 1.0, 2.0, 3.0)],
+        )
+        .await;
+    }
+
+    #[test]
+    fn migrate_without_probe() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE results (
+                     target TEXT NOT NULL,
+                     timestamp_ms INTEGER NOT NULL,
+                     rtt_ms REAL,
+                     error TEXT
+                 );
+                 CREATE TABLE aggregates (
+                     target TEXT NOT NULL,
+                     start_ms INTEGER NOT NULL,
+                     count INTEGER NOT NULL,
+                     failures INTEGER NOT NULL,
+                     rtt_min_ms REAL,
+                     rtt_avg_ms REAL,
+                     rtt_max_ms REAL,
+                     PRIMARY KEY (target, start_ms)
+                 );
+                 INSERT INTO results VALUES ('a', 0, 1.0, NULL);
+                 INSERT INTO aggregates VALUES ('a', 0, 1, 0, 1.0, 1.0, 1.0);",
+            )
+            .unwrap();
+
+        let connection = super::open(&path).unwrap();
+        let probes = |table: &str| {
+            connection
+                .query_row(
+                    &format!("SELECT group_concat(probe) FROM {table}"),
+                    [],
+                    |row| row.get::<_, String>(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(probes("results"), "");
+        assert_eq!(probes("aggregates"), "");
+        // Aggregates of another probe in the same interval are kept apart.
+        connection
+            .execute(
+                "INSERT INTO aggregates (target, probe, start_ms, count, failures)
+                 VALUES ('a', 'tls', 0, 1, 0)",
+                [],
+            )
+            .unwrap();
+        assert_eq!(probes("aggregates"), ",tls");
+        drop(connection);
+        super::open(&path).unwrap();
+    }
+
+    #[test]
+    fn retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uppies.db");
+        let writer = super::Writer {
+            connection: super::open(&path).unwrap(),
+            downsample_ms: None,
+            retention: Some(Duration::from_secs(60)),
+            compact_interval: Duration::from_secs(3600),
+            batch_size: 1,
+            flush_interval: Duration::from_secs(1),
+            destination: Default::default(),
+        };
+        let now = SystemTime::now();
+        let ms = |at: SystemTime| at.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        for at in [now - Duration::from_secs(120), now] {
+            writer
+                .connection
+                .execute(
+                    "INSERT INTO results (target, timestamp_ms) VALUES ('a', ?1)",
+                    [ms(at)],
+                )
+                .unwrap();
+        }
+        writer.compact(now).unwrap();
+        let remaining: i64 = writer
+            .connection
+            .query_row("SELECT count(*) FROM results", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(remaining, 1);
+    }
+}
@@ -0,0 +1,201 @@
+//! Sink which sends results to a StatsD server over UDP, optionally using
+//! the DogStatsD extension for tags.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::{ToSocketAddrs, UdpSocket},
+};
+
+use serde::Deserialize;
+use tracing::debug;
+
+use super::Sink;
+use crate::{destination::Destination, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// Address of the StatsD server, such as `127.0.0.1:8125`.
+    pub address: String,
+    /// Prefix applied to all metric names.
+    #[serde(default = "StatsdConfig::default_prefix")]
+    pub prefix: String,
+    /// Use DogStatsD tags to label metrics by target and probe, rather than
+    /// including them within the metric name.
+    #[serde(default)]
+    pub dogstatsd: bool,
+    /// Additional tags applied to all metrics, when using DogStatsD.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl StatsdConfig {
+    fn default_prefix() -> String {
+        "uppies.".to_string()
+    }
+}
+
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    dogstatsd: bool,
+    /// Tags applied to all metrics, pre-formatted as `k:v` pairs.
+    tags: String,
+    /// Health of the sink, healthy when the most recent packet was sent
+    /// successfully.
+    destination: Destination,
+}
+
+impl StatsdSink {
+    pub fn new(config: &StatsdConfig, destination: Destination) -> Result<Self> {
+        let address = config
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("statsd address {} did not resolve", config.address))?;
+        let socket = UdpSocket::bind(if address.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        })?;
+        socket.connect(address)?;
+        // Metrics are sent on a best-effort basis, they should never
+        // block the receipt of ping results.
+        socket.set_nonblocking(true)?;
+        let tags = config
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{k}:{v}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        Ok(Self {
+            socket,
+            prefix: config.prefix.clone(),
+            dogstatsd: config.dogstatsd,
+            tags,
+            destination,
+        })
+    }
+
+    /// Format a single metric line for the probe of the target.
+    fn line(
+        &self,
+        packet: &mut String,
+        name: &str,
+        value: &str,
+        kind: &str,
+        outcome: &PingOutcome,
+    ) {
+        let (target, probe) = (&*outcome.target, &*outcome.probe);
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        if self.dogstatsd {
+            let _ = write!(
+                packet,
+                "{}{name}:{value}|{kind}|#target:{target},probe:{probe}",
+                self.prefix
+            );
+            if !self.tags.is_empty() {
+                let _ = write!(packet, ",{}", self.tags);
+            }
+        } else {
+            // Plain StatsD has no tags, so the target and probe become part
+            // of the metric name instead.
+            let target = target.replace(['.', ':'], "_");
+            let probe = probe.replace(['.', ':'], "_");
+            let _ = write!(
+                packet,
+                "{}{name}.{target}.{probe}:{value}|{kind}",
+                self.prefix
+            );
+        }
+    }
+}
+
+impl Sink for StatsdSink {
+    fn record(&self, outcome: &PingOutcome) {
+        let target = &*outcome.target;
+        let mut packet = String::new();
+        match &outcome.rtt {
+            Ok(_) => {
+                self.line(&mut packet, "ping.success", "1", "c", outcome);
+                if let Some(d) = outcome.latency() {
+                    let ms = (d.as_secs_f64() * 1000.0).to_string();
+                    self.line(&mut packet, "ping.duration_ms", &ms, "ms", outcome);
+                }
+            }
+            Err(_) => self.line(&mut packet, "ping.failure", "1", "c", outcome),
+        }
+        match self.socket.send(packet.as_bytes()) {
+            Ok(_) => self.destination.succeeded(),
+            Err(e) => {
+                debug!(target, ?e, "failed to send statsd metrics");
+                self.destination.failed(e);
+            }
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.destination.is_healthy()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, net::UdpSocket, time::Duration};
+
+    use super::{StatsdConfig, StatsdSink};
+    use crate::{sink::Sink, ErrorKind, PingOutcome};
+
+    fn receive(server: &UdpSocket) -> String {
+        let mut buf = [0; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    fn sink(server: &UdpSocket, dogstatsd: bool) -> StatsdSink {
+        StatsdSink::new(
+            &StatsdConfig {
+                address: server.local_addr().unwrap().to_string(),
+                prefix: StatsdConfig::default_prefix(),
+                dogstatsd,
+                tags: BTreeMap::from([("env".to_string(), "test".to_string())]),
+            },
+            Default::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn dogstatsd_tags() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = sink(&server, true);
+
+        sink.record(&PingOutcome::test(
+            "127.0.0.1",
+            Ok(Duration::from_micros(1500)),
+        ));
+        assert_eq!(
+            receive(&server),
+            "uppies.ping.success:1|c|#target:127.0.0.1,probe:icmp,env:test\n\
+             uppies.ping.duration_ms:1.5|ms|#target:127.0.0.1,probe:icmp,env:test"
+        );
+
+        sink.record(&PingOutcome::test("127.0.0.1", Err(ErrorKind::Timeout)));
+        assert_eq!(
+            receive(&server),
+            "uppies.ping.failure:1|c|#target:127.0.0.1,probe:icmp,env:test"
+        );
+    }
+
+    #[test]
+    fn plain_statsd() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = sink(&server, false);
+
+        sink.record(&PingOutcome::test("127.0.0.1", Err(ErrorKind::Timeout)));
+        assert_eq!(receive(&server), "uppies.ping.failure.127_0_0_1.icmp:1|c");
+    }
+}
@@ -0,0 +1,352 @@
+//! Availability of each target over windows such as the last hour, day and
+//! month, as the percentage of pings which succeeded.
+//!
+//! Each window is a [rolling window](crate::rolling) divided into slices,
+//! so that the availability over a
+//! month is kept without every ping. Availability is kept for each probe of
+//! a target, and is exposed as the `target_availability_ratio` gauge,
+//! labelled by target, probe and window, and served at
+//! [`Availability::PATH`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{self, LabelPair, MetricFamily, MetricType},
+    Registry,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{rolling::Slices, sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SlaConfig {
+    /// Windows which availability is computed over, such as `24h`.
+    #[serde(default = "SlaConfig::default_windows")]
+    pub windows: Vec<String>,
+    /// Number of slices each window is divided into. The availability of a
+    /// window may include pings up to one slice older than the window.
+    #[serde(default = "SlaConfig::default_slices")]
+    pub slices: usize,
+}
+
+impl SlaConfig {
+    fn default_windows() -> Vec<String> {
+        vec!["1h".to_string(), "24h".to_string(), "30d".to_string()]
+    }
+
+    fn default_slices() -> usize {
+        60
+    }
+}
+
+impl Default for SlaConfig {
+    fn default() -> Self {
+        Self {
+            windows: Self::default_windows(),
+            slices: Self::default_slices(),
+        }
+    }
+}
+
+/// Availability of a probe of a target over each window, as served at
+/// [`Availability::PATH`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TargetAvailability {
+    pub target: String,
+    /// Probe of the target, see [`ProbeTarget::probe`](crate::ProbeTarget::probe).
+    #[serde(default)]
+    pub probe: String,
+    pub windows: Vec<WindowAvailability>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowAvailability {
+    /// Window as configured, such as `24h`.
+    pub window: String,
+    /// Ratio of pings within the window which succeeded, from 0 to 1.
+    pub ratio: f64,
+    /// Number of pings within the window.
+    pub pings: u64,
+}
+
+/// Pings of a target within a single slice of a window.
+#[derive(Default)]
+struct Slice {
+    pings: u64,
+    successes: u64,
+}
+
+/// Slices of each window.
+type Windows = Vec<Slices<Slice>>;
+
+struct Window {
+    name: String,
+    duration: Duration,
+}
+
+/// Availability of every target over each configured window.
+///
+/// Clones share the same underlying state.
+#[derive(Clone)]
+pub struct Availability {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    desc: Desc,
+    windows: Vec<Window>,
+    /// Number of slices each window is divided into.
+    slices: usize,
+    /// Slices of each window for each probe of each target.
+    targets: Mutex<HashMap<(String, String), Windows>>,
+}
+
+impl Availability {
+    /// Path which the availability of all targets is served at.
+    pub const PATH: &str = "/sla";
+
+    const NAME: &str = "target_availability_ratio";
+
+    pub fn new(config: &SlaConfig, metrics: &Registry) -> Result<Self> {
+        let windows = config
+            .windows
+            .iter()
+            .map(|name| {
+                let duration = crate::parse_duration(name)?;
+                if duration.is_zero() {
+                    return Err(format!("sla window '{name}' must be longer than 0s").into());
+                }
+                Ok(Window {
+                    name: name.clone(),
+                    duration,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let inner = Inner {
+            desc: Desc::new(
+                Self::NAME.to_string(),
+                "Ratio of pings which succeeded over the window".to_string(),
+                vec![
+                    "target".to_string(),
+                    "probe".to_string(),
+                    "window".to_string(),
+                ],
+                HashMap::new(),
+            )?,
+            windows,
+            slices: config.slices,
+            targets: Mutex::new(HashMap::new()),
+        };
+        let availability = Self {
+            inner: Arc::new(inner),
+        };
+        metrics.register(Box::new(availability.clone()))?;
+        Ok(availability)
+    }
+
+    /// Availability of every probe of a target with pings within any
+    /// window, ordered by target and probe.
+    pub fn report(&self) -> Vec<TargetAvailability> {
+        self.inner.report(Instant::now())
+    }
+}
+
+impl Inner {
+    fn observe(&self, target: &str, probe: &str, at: Instant, success: bool) {
+        let mut targets = self.targets.lock().expect("sla lock poisoned");
+        let windows = targets
+            .entry((target.to_string(), probe.to_string()))
+            .or_insert_with(|| {
+                self.windows
+                    .iter()
+                    .map(|window| Slices::new(window.duration, self.slices))
+                    .collect()
+            });
+        for slices in windows {
+            let current = slices.slice(at, Slice::default);
+            current.pings += 1;
+            current.successes += u64::from(success);
+        }
+    }
+
+    fn report(&self, now: Instant) -> Vec<TargetAvailability> {
+        let mut targets = self.targets.lock().expect("sla lock poisoned");
+        let mut report: BTreeMap<&(String, String), Vec<WindowAvailability>> = BTreeMap::new();
+        for (target, windows) in targets.iter_mut() {
+            let mut target_windows = Vec::new();
+            for (window, slices) in self.windows.iter().zip(windows) {
+                slices.expire(now);
+                let pings: u64 = slices.iter().map(|s| s.pings).sum();
+                if pings == 0 {
+                    continue;
+                }
+                let successes: u64 = slices.iter().map(|s| s.successes).sum();
+                target_windows.push(WindowAvailability {
+                    window: window.name.clone(),
+                    ratio: successes as f64 / pings as f64,
+                    pings,
+                });
+            }
+            if !target_windows.is_empty() {
+                report.insert(target, target_windows);
+            }
+        }
+        let report = report
+            .into_iter()
+            .map(|((target, probe), windows)| TargetAvailability {
+                target: target.clone(),
+                probe: probe.clone(),
+                windows,
+            })
+            .collect();
+        targets.retain(|_, windows| windows.iter().any(|slices| !slices.is_empty()));
+        report
+    }
+
+    fn families(&self, now: Instant) -> Vec<MetricFamily> {
+        let label = |name: &str, value: &str| {
+            let mut label = LabelPair::default();
+            label.set_name(name.to_string());
+            label.set_value(value.to_string());
+            label
+        };
+        let mut metrics = Vec::new();
+        for target in self.report(now) {
+            for window in target.windows {
+                let mut gauge = proto::Gauge::default();
+                gauge.set_value(window.ratio);
+                let mut metric = proto::Metric::default();
+                metric.set_label(vec![
+                    label("target", &target.target),
+                    label("probe", &target.probe),
+                    label("window", &window.window),
+                ]);
+                metric.set_gauge(gauge);
+                metrics.push(metric);
+            }
+        }
+
+        let mut family = MetricFamily::default();
+        family.set_name(Availability::NAME.to_string());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(metrics);
+        vec![family]
+    }
+}
+
+impl Collector for Availability {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.inner.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.inner.families(Instant::now())
+    }
+}
+
+impl Sink for Availability {
+    fn record(&self, outcome: &PingOutcome) {
+        self.inner.observe(
+            &outcome.target,
+            &outcome.probe,
+            Instant::now(),
+            outcome.rtt.is_ok(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use prometheus::Registry;
+
+    use super::{Availability, SlaConfig};
+
+    fn availability(metrics: &Registry) -> Availability {
+        Availability::new(
+            &SlaConfig {
+                windows: vec!["1m".to_string(), "1h".to_string()],
+                slices: 6,
+            },
+            metrics,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn windows() {
+        let availability = availability(&Registry::new());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        for (secs, success) in [(0, false), (0, false), (30, true), (40, true)] {
+            availability.inner.observe("a", "icmp", at(secs), success);
+        }
+
+        let report = availability.inner.report(at(45));
+        let ratios: Vec<_> = report[0]
+            .windows
+            .iter()
+            .map(|w| (w.window.as_str(), w.ratio, w.pings))
+            .collect();
+        assert_eq!(ratios, [("1m", 0.5, 4), ("1h", 0.5, 4)]);
+
+        // The failures have left the shorter window.
+        let report = availability.inner.report(at(80));
+        assert_eq!(report[0].windows[0].ratio, 1.0);
+        assert_eq!(report[0].windows[1].ratio, 0.5);
+
+        assert!(availability.inner.report(at(7200)).is_empty());
+    }
+
+    #[test]
+    fn gauges() {
+        let metrics = Registry::new();
+        let availability = availability(&metrics);
+        availability
+            .inner
+            .observe("a", "icmp", Instant::now(), true);
+        availability
+            .inner
+            .observe("a", "tcp", Instant::now(), false);
+
+        let families = metrics.gather();
+        assert_eq!(families[0].name(), "target_availability_ratio");
+        let mut labels: Vec<_> = families[0]
+            .get_metric()
+            .iter()
+            .map(|m| {
+                let label = |i: usize| m.get_label()[i].value().to_string();
+                (label(1), label(2), m.get_gauge().value())
+            })
+            .collect();
+        labels.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        let gauge =
+            |probe: &str, window: &str, ratio| (probe.to_string(), window.to_string(), ratio);
+        assert_eq!(
+            labels,
+            [
+                gauge("icmp", "1h", 1.0),
+                gauge("icmp", "1m", 1.0),
+                gauge("tcp", "1h", 0.0),
+                gauge("tcp", "1m", 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_window() {
+        let config = SlaConfig {
+            windows: vec!["month".to_string()],
+            slices: 6,
+        };
+        assert!(Availability::new(&config, &Registry::new()).is_err());
+    }
+}
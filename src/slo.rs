@@ -0,0 +1,405 @@
+//! Burn rates of latency objectives, such as 95% of the pings of a target
+//! completing within 50ms, over several windows.
+//!
+//! The burn rate over a window is the ratio of its pings which missed the
+//! objective, by failing or by taking longer than its latency, to the ratio
+//! which the objective allows to miss. At a burn rate of 1, the error budget
+//! lasts exactly as long as the objective's period, so the usual multi-window
+//! alerts, such as a burn rate above 14.4 over both `1h` and `5m`, can be
+//! written directly against the `slo_burn_rate` gauge, labelled by target,
+//! probe and window. Each probe of a target is measured against the target's
+//! objective by itself. As with [availability](crate::sla), each window is a
+//! [rolling window](crate::rolling) divided into slices rather than keeping
+//! every ping.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{self, LabelPair, MetricFamily, MetricType},
+    Registry,
+};
+use serde::Deserialize;
+
+use crate::{rolling::Slices, sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SloConfig {
+    /// Windows which burn rates are computed over, such as `1h`.
+    #[serde(default = "SloConfig::default_windows")]
+    pub windows: Vec<String>,
+    /// Number of slices each window is divided into. The burn rate of a
+    /// window may include pings up to one slice older than the window.
+    #[serde(default = "SloConfig::default_slices")]
+    pub slices: usize,
+    /// Objective of all targets without one of their own, if any.
+    pub default: Option<LatencyObjective>,
+    /// Objectives of individual targets.
+    #[serde(default)]
+    pub targets: BTreeMap<String, LatencyObjective>,
+}
+
+impl SloConfig {
+    fn default_windows() -> Vec<String> {
+        vec!["5m".to_string(), "1h".to_string(), "6h".to_string()]
+    }
+
+    fn default_slices() -> usize {
+        60
+    }
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            windows: Self::default_windows(),
+            slices: Self::default_slices(),
+            default: None,
+            targets: BTreeMap::new(),
+        }
+    }
+}
+
+/// Objective of the percentage of pings which succeed within a latency.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LatencyObjective {
+    /// Round-trip time which pings must complete within.
+    pub latency_ms: f64,
+    /// Percentage of pings which must succeed within the latency, such as
+    /// `95`.
+    pub percent: f64,
+}
+
+impl LatencyObjective {
+    fn validate(&self, name: &str) -> Result<()> {
+        if self.latency_ms.is_nan() || self.latency_ms <= 0.0 {
+            return Err(format!("slo of {name} must have a latency above 0ms").into());
+        }
+        if self.percent.is_nan() || self.percent <= 0.0 || self.percent >= 100.0 {
+            return Err(format!("slo of {name} must have a percent between 0 and 100").into());
+        }
+        Ok(())
+    }
+
+    /// Ratio of pings which may miss the objective.
+    fn budget(&self) -> f64 {
+        (100.0 - self.percent) / 100.0
+    }
+
+    /// Whether `outcome` succeeded within the latency, which pings without
+    /// a round-trip time, of targets expected to be down, always are.
+    fn is_met(&self, outcome: &PingOutcome) -> bool {
+        outcome.rtt.is_ok()
+            && outcome
+                .latency()
+                .is_none_or(|rtt| rtt.as_secs_f64() * 1000.0 <= self.latency_ms)
+    }
+}
+
+/// Pings of a target within a single slice of a window.
+#[derive(Default)]
+struct Slice {
+    pings: u64,
+    missed: u64,
+}
+
+struct Window {
+    name: String,
+    duration: Duration,
+}
+
+/// Pings of a probe of a target within each window, along with its
+/// objective.
+struct TargetSlices {
+    objective: LatencyObjective,
+    windows: Vec<Slices<Slice>>,
+}
+
+/// Burn rates of the latency objectives of targets, which are recorded as a
+/// [`Sink`].
+///
+/// Clones share the same underlying state.
+#[derive(Clone)]
+pub struct BurnRates {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    desc: Desc,
+    config: SloConfig,
+    windows: Vec<Window>,
+    targets: Mutex<HashMap<(String, String), TargetSlices>>,
+}
+
+impl BurnRates {
+    const NAME: &str = "slo_burn_rate";
+
+    pub fn new(config: &SloConfig, metrics: &Registry) -> Result<Self> {
+        if let Some(default) = &config.default {
+            default.validate("the default")?;
+        }
+        for (target, objective) in &config.targets {
+            objective.validate(&format!("'{target}'"))?;
+        }
+        let windows = config
+            .windows
+            .iter()
+            .map(|name| {
+                let duration = crate::parse_duration(name)?;
+                if duration.is_zero() {
+                    return Err(format!("slo window '{name}' must be longer than 0s").into());
+                }
+                Ok(Window {
+                    name: name.clone(),
+                    duration,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let inner = Inner {
+            desc: Desc::new(
+                Self::NAME.to_string(),
+                "Ratio of pings missing the latency objective over the window to the ratio it allows"
+                    .to_string(),
+                vec![
+                    "target".to_string(),
+                    "probe".to_string(),
+                    "window".to_string(),
+                ],
+                HashMap::new(),
+            )?,
+            config: config.clone(),
+            windows,
+            targets: Mutex::new(HashMap::new()),
+        };
+        let burn_rates = Self {
+            inner: Arc::new(inner),
+        };
+        metrics.register(Box::new(burn_rates.clone()))?;
+        Ok(burn_rates)
+    }
+}
+
+impl Inner {
+    fn observe(&self, outcome: &PingOutcome, at: Instant) {
+        let target = &*outcome.target;
+        let Some(objective) = self
+            .config
+            .targets
+            .get(target)
+            .or(self.config.default.as_ref())
+        else {
+            return;
+        };
+        let mut targets = self.targets.lock().expect("slo lock poisoned");
+        let slices = targets
+            .entry((target.to_string(), outcome.probe.to_string()))
+            .or_insert_with(|| TargetSlices {
+                objective: *objective,
+                windows: self
+                    .windows
+                    .iter()
+                    .map(|window| Slices::new(window.duration, self.config.slices))
+                    .collect(),
+            });
+        let missed = !objective.is_met(outcome);
+        for slices in &mut slices.windows {
+            let current = slices.slice(at, Slice::default);
+            current.pings += 1;
+            current.missed += u64::from(missed);
+        }
+    }
+
+    /// Burn rate of each probe of a target over each window with pings
+    /// within it, by target, probe and window.
+    fn burn_rates(&self, now: Instant) -> Vec<(String, String, &str, f64)> {
+        let mut targets = self.targets.lock().expect("slo lock poisoned");
+        let mut rates = Vec::new();
+        for ((target, probe), slices) in targets.iter_mut() {
+            let budget = slices.objective.budget();
+            for (window, slices) in self.windows.iter().zip(&mut slices.windows) {
+                slices.expire(now);
+                let pings: u64 = slices.iter().map(|s| s.pings).sum();
+                if pings == 0 {
+                    continue;
+                }
+                let missed: u64 = slices.iter().map(|s| s.missed).sum();
+                let rate = missed as f64 / pings as f64 / budget;
+                rates.push((target.clone(), probe.clone(), window.name.as_str(), rate));
+            }
+        }
+        targets.retain(|_, slices| slices.windows.iter().any(|s| !s.is_empty()));
+        rates.sort_by(|a, b| (&a.0, &a.1, a.2).cmp(&(&b.0, &b.1, b.2)));
+        rates
+    }
+
+    fn families(&self, now: Instant) -> Vec<MetricFamily> {
+        let label = |name: &str, value: &str| {
+            let mut label = LabelPair::default();
+            label.set_name(name.to_string());
+            label.set_value(value.to_string());
+            label
+        };
+        let metrics = self
+            .burn_rates(now)
+            .into_iter()
+            .map(|(target, probe, window, rate)| {
+                let mut gauge = proto::Gauge::default();
+                gauge.set_value(rate);
+                let mut metric = proto::Metric::default();
+                metric.set_label(vec![
+                    label("target", &target),
+                    label("probe", &probe),
+                    label("window", window),
+                ]);
+                metric.set_gauge(gauge);
+                metric
+            })
+            .collect();
+
+        let mut family = MetricFamily::default();
+        family.set_name(BurnRates::NAME.to_string());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(metrics);
+        vec![family]
+    }
+}
+
+impl Collector for BurnRates {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.inner.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.inner.families(Instant::now())
+    }
+}
+
+impl Sink for BurnRates {
+    fn record(&self, outcome: &PingOutcome) {
+        self.inner.observe(outcome, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, Instant},
+    };
+
+    use prometheus::Registry;
+
+    use super::{BurnRates, LatencyObjective, SloConfig};
+    use crate::{ErrorKind, PingOutcome};
+
+    fn burn_rates(metrics: &Registry) -> BurnRates {
+        let config = SloConfig {
+            windows: vec!["1m".to_string(), "1h".to_string()],
+            slices: 6,
+            default: Some(LatencyObjective {
+                latency_ms: 50.0,
+                percent: 90.0,
+            }),
+            targets: BTreeMap::from([(
+                "b".to_string(),
+                LatencyObjective {
+                    latency_ms: 10.0,
+                    percent: 99.0,
+                },
+            )]),
+        };
+        BurnRates::new(&config, metrics).unwrap()
+    }
+
+    #[test]
+    fn windows() {
+        let burn_rates = burn_rates(&Registry::new());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let ping = |target, rtt: Result<u64, ErrorKind>| {
+            PingOutcome::test(target, rtt.map(Duration::from_millis))
+        };
+        // One of the 4 pings of `a` was too slow and one failed.
+        let pings = [
+            (0, Ok(100)),
+            (0, Err(ErrorKind::Timeout)),
+            (30, Ok(10)),
+            (40, Ok(50)),
+        ];
+        for (secs, rtt) in pings {
+            burn_rates.inner.observe(&ping("a", rtt), at(secs));
+        }
+        // Within 50ms, but not the 10ms of `b`'s own objective.
+        burn_rates.inner.observe(&ping("b", Ok(20)), at(40));
+        // Another probe of `b` is measured by itself.
+        let mut tcp = ping("b", Ok(5));
+        tcp.probe = "tcp".into();
+        burn_rates.inner.observe(&tcp, at(40));
+
+        let rates = |secs| {
+            let rates = burn_rates.inner.burn_rates(at(secs));
+            rates
+                .into_iter()
+                .map(|(target, probe, window, rate)| {
+                    (target, probe, window.to_string(), (rate * 100.0).round())
+                })
+                .collect::<Vec<_>>()
+        };
+        let rate = |target: &str, probe: &str, window: &str, rate: f64| {
+            (
+                target.to_string(),
+                probe.to_string(),
+                window.to_string(),
+                rate * 100.0,
+            )
+        };
+        assert_eq!(
+            rates(45),
+            [
+                rate("a", "icmp", "1h", 5.0),
+                rate("a", "icmp", "1m", 5.0),
+                rate("b", "icmp", "1h", 100.0),
+                rate("b", "icmp", "1m", 100.0),
+                rate("b", "tcp", "1h", 0.0),
+                rate("b", "tcp", "1m", 0.0),
+            ]
+        );
+        // The misses have left the shorter window.
+        assert_eq!(rates(80)[1], rate("a", "icmp", "1m", 0.0));
+        assert!(rates(7200).is_empty());
+    }
+
+    #[test]
+    fn gauges() {
+        let metrics = Registry::new();
+        let burn_rates = burn_rates(&metrics);
+        let outcome = PingOutcome::test("a", Err(ErrorKind::Timeout));
+        burn_rates.inner.observe(&outcome, Instant::now());
+
+        let families = metrics.gather();
+        assert_eq!(families[0].name(), "slo_burn_rate");
+        assert_eq!(families[0].get_metric().len(), 2);
+        assert_eq!(families[0].get_metric()[0].get_gauge().value(), 10.0);
+    }
+
+    #[test]
+    fn invalid_objectives() {
+        for (latency_ms, percent) in [(0.0, 95.0), (50.0, 100.0), (50.0, 0.0)] {
+            let config = SloConfig {
+                default: Some(LatencyObjective {
+                    latency_ms,
+                    percent,
+                }),
+                ..Default::default()
+            };
+            assert!(BurnRates::new(&config, &Registry::new()).is_err());
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! Detection of targets whose round-trip time is trending upwards.
+//!
+//! A gradually saturating link often shows a steady rise in latency well
+//! before it breaches any absolute threshold. The slope of the RTT is
+//! estimated over a sliding window using a least-squares fit, and an alert is
+//! raised while it exceeds the configured rate of change.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use prometheus::{GaugeVec, IntCounterVec, IntGaugeVec, Opts, Registry};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{sink::Sink, PingOutcome, Result};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SlopeConfig {
+    /// Length of the sliding window, in seconds, which the slope is
+    /// calculated over.
+    #[serde(default = "SlopeConfig::default_window_secs")]
+    pub window_secs: u64,
+    /// Rate of change of the RTT, in milliseconds per minute, above which
+    /// an alert is raised.
+    pub threshold_ms_per_min: f64,
+    /// Minimum number of successful pings within the window before a slope
+    /// is calculated.
+    #[serde(default = "SlopeConfig::default_min_samples")]
+    pub min_samples: usize,
+}
+
+impl SlopeConfig {
+    fn default_window_secs() -> u64 {
+        300
+    }
+
+    fn default_min_samples() -> usize {
+        10
+    }
+}
+
+/// Per-target window of `(time, rtt_ms)` samples.
+#[derive(Default)]
+struct Window {
+    samples: VecDeque<(Instant, f64)>,
+    alerting: bool,
+}
+
+pub struct SlopeDetector {
+    window: Duration,
+    threshold_ms_per_min: f64,
+    min_samples: usize,
+    targets: Mutex<HashMap<(String, String), Window>>,
+
+    /// Current slope of the RTT in milliseconds per minute, labelled by target.
+    slope: GaugeVec,
+    /// Whether the slope currently exceeds the threshold, labelled by target.
+    alerting: IntGaugeVec,
+    /// Number of times the slope began exceeding the threshold, labelled by target.
+    alerts: IntCounterVec,
+}
+
+impl SlopeDetector {
+    const LABELS: &[&str] = &["target", "probe"];
+
+    pub fn new(config: &SlopeConfig, metrics: &Registry) -> Result<Self> {
+        let slope = GaugeVec::new(
+            Opts::new(
+                "rtt_slope_ms_per_minute",
+                "Rate of change of ping round-trip times in milliseconds per minute",
+            ),
+            Self::LABELS,
+        )?;
+        let alerting = IntGaugeVec::new(
+            Opts::new(
+                "rtt_slope_alert",
+                "Whether the rate of change of round-trip times exceeds the threshold",
+            ),
+            Self::LABELS,
+        )?;
+        let alerts = IntCounterVec::new(
+            Opts::new(
+                "rtt_slope_alerts_total",
+                "Counter of round-trip time rate of change alerts",
+            ),
+            Self::LABELS,
+        )?;
+        metrics.register(Box::new(slope.clone()))?;
+        metrics.register(Box::new(alerting.clone()))?;
+        metrics.register(Box::new(alerts.clone()))?;
+        Ok(Self {
+            window: Duration::from_secs(config.window_secs),
+            threshold_ms_per_min: config.threshold_ms_per_min,
+            min_samples: config.min_samples.max(2),
+            targets: Mutex::new(HashMap::new()),
+            slope,
+            alerting,
+            alerts,
+        })
+    }
+
+    fn observe(&self, target: &str, probe: &str, at: Instant, rtt_ms: f64) {
+        let mut targets = self.targets.lock().expect("slope lock poisoned");
+        let window = targets
+            .entry((target.to_string(), probe.to_string()))
+            .or_default();
+        window.samples.push_back((at, rtt_ms));
+        while let Some((oldest, _)) = window.samples.front() {
+            if at.duration_since(*oldest) <= self.window {
+                break;
+            }
+            window.samples.pop_front();
+        }
+        if window.samples.len() < self.min_samples {
+            return;
+        }
+        let Some(slope) = slope_per_minute(&window.samples) else {
+            return;
+        };
+
+        self.slope.with_label_values(&[target, probe]).set(slope);
+        let alerting = slope > self.threshold_ms_per_min;
+        if alerting && !window.alerting {
+            warn!(
+                target,
+                probe,
+                slope_ms_per_min = slope,
+                threshold_ms_per_min = self.threshold_ms_per_min,
+                "round-trip time rising"
+            );
+            self.alerts.with_label_values(&[target, probe]).inc();
+        } else if !alerting && window.alerting {
+            info!(
+                target,
+                probe,
+                slope_ms_per_min = slope,
+                "round-trip time no longer rising"
+            );
+        }
+        window.alerting = alerting;
+        self.alerting
+            .with_label_values(&[target, probe])
+            .set(alerting as i64);
+    }
+}
+
+impl Sink for SlopeDetector {
+    fn record(&self, outcome: &PingOutcome) {
+        // Failures carry no latency information, loss is tracked elsewhere.
+        if let Some(d) = outcome.latency() {
+            self.observe(
+                &outcome.target,
+                &outcome.probe,
+                Instant::now(),
+                d.as_secs_f64() * 1000.0,
+            );
+        }
+    }
+}
+
+/// Least-squares slope of the samples, converted to milliseconds per minute.
+///
+/// Returns `None` when all samples were taken at the same instant.
+fn slope_per_minute(samples: &VecDeque<(Instant, f64)>) -> Option<f64> {
+    let (start, _) = samples.front()?;
+    let n = samples.len() as f64;
+    let points = || {
+        samples
+            .iter()
+            .map(|(at, rtt)| (at.duration_since(*start).as_secs_f64(), *rtt))
+    };
+    let (sum_x, sum_y) = points().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+    let (covariance, variance) = points().fold((0.0, 0.0), |(c, v), (x, y)| {
+        (c + (x - mean_x) * (y - mean_y), v + (x - mean_x).powi(2))
+    });
+    if variance == 0.0 {
+        return None;
+    }
+    Some(covariance / variance * 60.0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use prometheus::Registry;
+
+    use super::{SlopeConfig, SlopeDetector};
+
+    const TARGET: &str = "127.0.0.1";
+    const PROBE: &str = "icmp";
+
+    fn detector() -> SlopeDetector {
+        SlopeDetector::new(
+            &SlopeConfig {
+                window_secs: 60,
+                threshold_ms_per_min: 5.0,
+                min_samples: 5,
+            },
+            &Registry::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rising_rtt_alerts() {
+        let detector = detector();
+        let start = Instant::now();
+        // Rising at 10ms per minute
+        for i in 0..10 {
+            detector.observe(
+                TARGET,
+                PROBE,
+                start + Duration::from_secs(i * 6),
+                20.0 + i as f64,
+            );
+        }
+
+        let slope = detector.slope.with_label_values(&[TARGET, PROBE]).get();
+        assert!((slope - 10.0).abs() < 1e-9, "unexpected slope {slope}");
+        assert_eq!(
+            detector.alerting.with_label_values(&[TARGET, PROBE]).get(),
+            1
+        );
+        assert_eq!(detector.alerts.with_label_values(&[TARGET, PROBE]).get(), 1);
+    }
+
+    #[test]
+    fn stable_rtt_does_not_alert() {
+        let detector = detector();
+        let start = Instant::now();
+        for i in 0..10 {
+            let jitter = if i % 2 == 0 { 1.0 } else { -1.0 };
+            detector.observe(
+                TARGET,
+                PROBE,
+                start + Duration::from_secs(i * 6),
+                20.0 + jitter,
+            );
+        }
+
+        assert!(
+            detector
+                .slope
+                .with_label_values(&[TARGET, PROBE])
+                .get()
+                .abs()
+                < 5.0
+        );
+        assert_eq!(
+            detector.alerting.with_label_values(&[TARGET, PROBE]).get(),
+            0
+        );
+    }
+
+    #[test]
+    fn old_samples_leave_window() {
+        let detector = detector();
+        let start = Instant::now();
+        // A steep rise which falls outside of the window, followed by a
+        // stable period.
+        for i in 0..5 {
+            detector.observe(
+                TARGET,
+                PROBE,
+                start + Duration::from_secs(i),
+                100.0 * i as f64,
+            );
+        }
+        for i in 0..10 {
+            detector.observe(
+                TARGET,
+                PROBE,
+                start + Duration::from_secs(120 + i * 6),
+                20.0,
+            );
+        }
+
+        assert_eq!(
+            detector.slope.with_label_values(&[TARGET, PROBE]).get(),
+            0.0
+        );
+        assert_eq!(
+            detector.alerting.with_label_values(&[TARGET, PROBE]).get(),
+            0
+        );
+        assert_eq!(detector.alerts.with_label_values(&[TARGET, PROBE]).get(), 1);
+    }
+}
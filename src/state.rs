@@ -0,0 +1,625 @@
+//! Tracking of whether each target is up or down.
+//!
+//! A target only changes state after enough consecutive pings disagree with
+//! its current state, and optionally only once they have disagreed for long
+//! enough. The thresholds are independent in each direction and can be
+//! overridden per target, so that flappy links don't ping-pong between
+//! states.
+//!
+//! Links which flap regardless can be detected by the number of state
+//! changes within a window, with a single notification that the target is
+//! flapping instead of one for every change.
+//!
+//! Each probe of a target has a state of its own, so that a host which
+//! answers pings but whose TLS handshakes fail is down only for `tls`.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    events::{Event, EventLog},
+    maintenance::Maintenance,
+    notify::{Notifications, RttStats, StateChange},
+    sink::Sink,
+    PingOutcome, Result,
+};
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    /// Hysteresis of all targets without an override.
+    #[serde(default)]
+    pub default: HysteresisConfig,
+    /// Overrides of the hysteresis for individual targets, unset values
+    /// fall back to `default`.
+    #[serde(default)]
+    pub targets: BTreeMap<String, HysteresisConfig>,
+    /// Detection of targets which are flapping, disabled when unset.
+    pub flapping: Option<FlappingConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FlappingConfig {
+    /// Number of state changes within the window at which a target is
+    /// flapping. It stops flapping once fewer remain within the window.
+    #[serde(default = "FlappingConfig::default_changes")]
+    pub changes: usize,
+    /// Window, in seconds, which state changes are counted over.
+    #[serde(default = "FlappingConfig::default_window_secs")]
+    pub window_secs: u64,
+}
+
+impl FlappingConfig {
+    fn default_changes() -> usize {
+        5
+    }
+
+    fn default_window_secs() -> u64 {
+        600
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HysteresisConfig {
+    /// Consecutive failures before an up target is considered down.
+    pub failures_to_down: Option<u32>,
+    /// Consecutive successes before a down target is considered up.
+    pub successes_to_up: Option<u32>,
+    /// Minimum time, in seconds, that pings must have been failing for
+    /// before an up target is considered down.
+    pub down_after_secs: Option<u64>,
+    /// Minimum time, in seconds, that pings must have been succeeding for
+    /// before a down target is considered up.
+    pub up_after_secs: Option<u64>,
+}
+
+impl HysteresisConfig {
+    const DEFAULT_FAILURES_TO_DOWN: u32 = 3;
+    const DEFAULT_SUCCESSES_TO_UP: u32 = 3;
+
+    /// Resolve the hysteresis, taking unset values from `default`.
+    fn resolve(&self, default: &HysteresisConfig) -> Hysteresis {
+        Hysteresis {
+            failures_to_down: self
+                .failures_to_down
+                .or(default.failures_to_down)
+                .unwrap_or(Self::DEFAULT_FAILURES_TO_DOWN)
+                .max(1),
+            successes_to_up: self
+                .successes_to_up
+                .or(default.successes_to_up)
+                .unwrap_or(Self::DEFAULT_SUCCESSES_TO_UP)
+                .max(1),
+            down_after: Duration::from_secs(
+                self.down_after_secs
+                    .or(default.down_after_secs)
+                    .unwrap_or(0),
+            ),
+            up_after: Duration::from_secs(
+                self.up_after_secs.or(default.up_after_secs).unwrap_or(0),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Hysteresis {
+    failures_to_down: u32,
+    successes_to_up: u32,
+    down_after: Duration,
+    up_after: Duration,
+}
+
+/// Whether a target is up or down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Up,
+    Down,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match self {
+            State::Up => "up",
+            State::Down => "down",
+        }
+    }
+}
+
+struct TargetState {
+    hysteresis: Hysteresis,
+    state: State,
+    /// Number of consecutive pings which disagree with the current state.
+    streak: u32,
+    /// Time of the first ping within the streak.
+    streak_start: SystemTime,
+    /// Time at which the state last changed, if it has.
+    changed_at: Option<SystemTime>,
+    /// Round-trip times of recent successful pings, in milliseconds.
+    recent_rtts: VecDeque<f64>,
+    /// Times of the state changes within the flapping window.
+    recent_changes: VecDeque<SystemTime>,
+    /// Whether the target is flapping, see [`FlappingConfig`].
+    flapping: bool,
+}
+
+impl TargetState {
+    fn new(hysteresis: Hysteresis) -> Self {
+        Self {
+            hysteresis,
+            // Targets are assumed to be up until shown otherwise, so that
+            // they aren't reported as recovering on startup.
+            state: State::Up,
+            streak: 0,
+            streak_start: SystemTime::UNIX_EPOCH,
+            changed_at: None,
+            recent_rtts: VecDeque::new(),
+            recent_changes: VecDeque::new(),
+            flapping: false,
+        }
+    }
+
+    /// Number of recent successful pings which are summarised in
+    /// notifications.
+    const RECENT_RTTS: usize = 20;
+
+    fn record_rtt(&mut self, rtt: Duration) {
+        if self.recent_rtts.len() == Self::RECENT_RTTS {
+            self.recent_rtts.pop_front();
+        }
+        self.recent_rtts.push_back(rtt.as_secs_f64() * 1000.0);
+    }
+
+    fn rtt_stats(&self) -> Option<RttStats> {
+        let last_ms = *self.recent_rtts.back()?;
+        let rtts = self.recent_rtts.iter().copied();
+        Some(RttStats {
+            last_ms,
+            min_ms: rtts.clone().fold(f64::INFINITY, f64::min),
+            avg_ms: rtts.clone().sum::<f64>() / self.recent_rtts.len() as f64,
+            max_ms: rtts.fold(0.0, f64::max),
+        })
+    }
+
+    /// Summary of the state, where an up target that is failing pings but
+    /// hasn't yet gone down is considered degraded.
+    fn summary(&self) -> &'static str {
+        match self.state {
+            State::Up if self.streak > 0 => "degraded",
+            state => state.as_str(),
+        }
+    }
+
+    /// Observe a ping, returning the new state if it changed.
+    fn observe(&mut self, success: bool, at: SystemTime) -> Option<State> {
+        let (next, count, after) = match (self.state, success) {
+            (State::Up, true) | (State::Down, false) => {
+                self.streak = 0;
+                return None;
+            }
+            (State::Up, false) => (
+                State::Down,
+                self.hysteresis.failures_to_down,
+                self.hysteresis.down_after,
+            ),
+            (State::Down, true) => (
+                State::Up,
+                self.hysteresis.successes_to_up,
+                self.hysteresis.up_after,
+            ),
+        };
+        if self.streak == 0 {
+            self.streak_start = at;
+        }
+        self.streak += 1;
+        let elapsed = at.duration_since(self.streak_start).unwrap_or_default();
+        if self.streak >= count && elapsed >= after {
+            self.state = next;
+            self.streak = 0;
+            self.changed_at = Some(at);
+            return Some(next);
+        }
+        None
+    }
+
+    /// Observe a ping at `at`, which `changed` the state or not, returning
+    /// whether the target is flapping if that changed.
+    fn observe_flapping(
+        &mut self,
+        config: &FlappingConfig,
+        changed: bool,
+        at: SystemTime,
+    ) -> Option<bool> {
+        if changed {
+            self.recent_changes.push_back(at);
+        }
+        let window = Duration::from_secs(config.window_secs);
+        while let Some(&first) = self.recent_changes.front() {
+            if at.duration_since(first).unwrap_or_default() <= window {
+                break;
+            }
+            self.recent_changes.pop_front();
+        }
+        let flapping = self.recent_changes.len() >= config.changes.max(1);
+        if flapping == self.flapping {
+            return None;
+        }
+        self.flapping = flapping;
+        Some(flapping)
+    }
+}
+
+pub struct StateTracker {
+    config: StateConfig,
+    /// State of each probe of each target, by label and probe.
+    targets: Mutex<HashMap<(String, String), TargetState>>,
+
+    /// Whether each target is currently up (1) or down (0).
+    up: IntGaugeVec,
+    /// Number of state changes, labelled by target, probe and the new state.
+    transitions: IntCounterVec,
+    /// Number of targets which are up, down or degraded.
+    by_state: IntGaugeVec,
+    /// Whether each target is currently flapping (1) or not (0).
+    flapping: IntGaugeVec,
+
+    /// Notifiers which state changes are delivered to, if any.
+    notifications: Option<Notifications>,
+    /// Log which every state change is recorded into, if any.
+    events: Option<EventLog>,
+    /// Maintenance windows within which state changes aren't notified.
+    maintenance: Option<Maintenance>,
+}
+
+impl StateTracker {
+    pub fn new(config: &StateConfig, metrics: &Registry) -> Result<Self> {
+        let up = IntGaugeVec::new(
+            Opts::new(
+                "target_up",
+                "Whether the target is currently up (1) or down (0)",
+            ),
+            &["target", "probe"],
+        )?;
+        let transitions = IntCounterVec::new(
+            Opts::new(
+                "target_state_transitions_total",
+                "Counter of changes of a target between up and down",
+            ),
+            &["target", "probe", "state"],
+        )?;
+        let by_state = IntGaugeVec::new(
+            Opts::new(
+                "uppies_targets_by_state",
+                "Number of targets which are up, down or degraded",
+            ),
+            &["state"],
+        )?;
+        for state in ["up", "down", "degraded"] {
+            by_state.with_label_values(&[state]).set(0);
+        }
+        let flapping = IntGaugeVec::new(
+            Opts::new(
+                "target_flapping",
+                "Whether the target is currently flapping (1) or not (0)",
+            ),
+            &["target", "probe"],
+        )?;
+        metrics.register(Box::new(up.clone()))?;
+        metrics.register(Box::new(transitions.clone()))?;
+        metrics.register(Box::new(by_state.clone()))?;
+        metrics.register(Box::new(flapping.clone()))?;
+        Ok(Self {
+            config: config.clone(),
+            targets: Mutex::new(HashMap::new()),
+            up,
+            transitions,
+            by_state,
+            flapping,
+            notifications: None,
+            events: None,
+            maintenance: None,
+        })
+    }
+
+    /// Deliver every state change to the given [`Notifications`].
+    pub fn with_notifications(mut self, notifications: Notifications) -> Self {
+        self.notifications = Some(notifications);
+        self
+    }
+
+    /// Record every state change into the given [`EventLog`], including
+    /// those whose notifications are suppressed while flapping.
+    pub fn with_events(mut self, events: EventLog) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Don't notify the state changes of targets while they are within a
+    /// maintenance window of `maintenance`, which are still recorded.
+    pub fn with_maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+}
+
+impl Sink for StateTracker {
+    fn record(&self, outcome: &PingOutcome) {
+        let (target, probe) = (&*outcome.target, &*outcome.probe);
+        let labels = &[target, probe];
+        let mut targets = self.targets.lock().expect("state lock poisoned");
+        let key = (target.to_string(), probe.to_string());
+        let state = targets.entry(key).or_insert_with(|| {
+            let hysteresis = self
+                .config
+                .targets
+                .get(target)
+                .unwrap_or(&self.config.default)
+                .resolve(&self.config.default);
+            self.up.with_label_values(labels).set(1);
+            self.by_state.with_label_values(&["up"]).inc();
+            if self.config.flapping.is_some() {
+                self.flapping.with_label_values(labels).set(0);
+            }
+            TargetState::new(hysteresis)
+        });
+        if let Some(rtt) = outcome.latency() {
+            state.record_rtt(rtt);
+        }
+        let before = state.summary();
+        let previous_change = state.changed_at;
+        let changed = state.observe(outcome.rtt.is_ok(), outcome.timestamp);
+        let after = state.summary();
+        if before != after {
+            self.by_state.with_label_values(&[before]).dec();
+            self.by_state.with_label_values(&[after]).inc();
+        }
+        let flapping = self.config.flapping.as_ref().and_then(|config| {
+            state.observe_flapping(config, changed.is_some(), outcome.timestamp)
+        });
+        if let Some(flapping) = flapping {
+            if flapping {
+                warn!(target, probe, "target is flapping");
+            } else {
+                info!(
+                    target,
+                    probe,
+                    state = state.state.as_str(),
+                    "target stopped flapping"
+                );
+            }
+            self.flapping.with_label_values(labels).set(flapping as i64);
+        }
+        if let Some(new) = changed {
+            match new {
+                State::Up => info!(target, probe, "target is up"),
+                State::Down => warn!(target, probe, "target is down"),
+            }
+            self.up
+                .with_label_values(labels)
+                .set((new == State::Up) as i64);
+            self.transitions
+                .with_label_values(&[target, probe, new.as_str()])
+                .inc();
+            if let Some(events) = &self.events {
+                events.record(Event {
+                    target: target.to_string(),
+                    probe: probe.to_string(),
+                    state: new,
+                    timestamp_ms: timestamp_ms(outcome.timestamp),
+                    duration_ms: previous_change.map(|since| {
+                        outcome
+                            .timestamp
+                            .duration_since(since)
+                            .unwrap_or_default()
+                            .as_millis() as u64
+                    }),
+                });
+            }
+        }
+
+        let Some(notifications) = &self.notifications else {
+            return;
+        };
+        // Changes while flapping are suppressed, rather a single
+        // notification is sent when the target starts flapping and another
+        // of its state once it stops.
+        let down_for = match (flapping, changed) {
+            (Some(_), _) => None,
+            (None, Some(new)) if !state.flapping => {
+                // Targets start up, so only a change to up follows downtime.
+                previous_change
+                    .filter(|_| new == State::Up)
+                    .map(|down_since| {
+                        outcome
+                            .timestamp
+                            .duration_since(down_since)
+                            .unwrap_or_default()
+                    })
+            }
+            _ => return,
+        };
+        if let Some(maintenance) = &self.maintenance {
+            if maintenance.contains(target, outcome.timestamp) {
+                info!(
+                    target,
+                    probe,
+                    state = state.state.as_str(),
+                    "not notifying state change during maintenance"
+                );
+                return;
+            }
+        }
+        notifications.notify(StateChange {
+            target: target.to_string(),
+            probe: probe.to_string(),
+            state: state.state,
+            timestamp_ms: timestamp_ms(outcome.timestamp),
+            down_for_ms: down_for.map(|d| d.as_millis() as u64),
+            flapping: state.flapping,
+            rtt: state.rtt_stats(),
+        });
+    }
+}
+
+fn timestamp_ms(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, SystemTime},
+    };
+
+    use prometheus::Registry;
+
+    use super::{FlappingConfig, HysteresisConfig, State, StateConfig, StateTracker, TargetState};
+    use crate::{sink::Sink, ErrorKind, PingOutcome};
+
+    const FLAPPY: &str = "192.168.1.1";
+
+    fn config() -> StateConfig {
+        StateConfig {
+            default: HysteresisConfig {
+                failures_to_down: Some(2),
+                ..Default::default()
+            },
+            targets: BTreeMap::from([(
+                FLAPPY.to_string(),
+                HysteresisConfig {
+                    successes_to_up: Some(5),
+                    down_after_secs: Some(10),
+                    ..Default::default()
+                },
+            )]),
+            flapping: None,
+        }
+    }
+
+    #[test]
+    fn resolve_overrides() {
+        let config = config();
+        let flappy = config.targets[FLAPPY].resolve(&config.default);
+        assert_eq!(flappy.failures_to_down, 2, "unset values use the default");
+        assert_eq!(flappy.successes_to_up, 5);
+        assert_eq!(flappy.down_after, Duration::from_secs(10));
+        assert_eq!(config.default.resolve(&config.default).successes_to_up, 3);
+    }
+
+    #[test]
+    fn count_and_time_hysteresis() {
+        let config = config();
+        let mut state = TargetState::new(config.targets[FLAPPY].resolve(&config.default));
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert_eq!(state.observe(false, at(0)), None);
+        assert_eq!(
+            state.observe(false, at(5)),
+            None,
+            "failures must persist for 10s"
+        );
+        assert_eq!(state.observe(false, at(10)), Some(State::Down));
+
+        for secs in 11..15 {
+            assert_eq!(state.observe(true, at(secs)), None);
+        }
+        assert_eq!(state.observe(false, at(15)), None, "streak is broken");
+        for secs in 16..20 {
+            assert_eq!(state.observe(true, at(secs)), None);
+        }
+        assert_eq!(state.observe(true, at(20)), Some(State::Up));
+    }
+
+    #[test]
+    fn metrics() {
+        let tracker = StateTracker::new(&config(), &Registry::new()).unwrap();
+        let target = "127.0.0.1";
+        tracker.record(&PingOutcome::test(target, Ok(Duration::from_millis(1))));
+        assert_eq!(tracker.up.with_label_values(&[target, "icmp"]).get(), 1);
+
+        for _ in 0..2 {
+            tracker.record(&PingOutcome::test(target, Err(ErrorKind::Timeout)));
+        }
+        assert_eq!(tracker.up.with_label_values(&[target, "icmp"]).get(), 0);
+        assert_eq!(
+            tracker
+                .transitions
+                .with_label_values(&[target, "icmp", "down"])
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn probes() {
+        let tracker = StateTracker::new(&config(), &Registry::new()).unwrap();
+        let outcome = |probe: &str, rtt| PingOutcome {
+            probe: probe.into(),
+            ..PingOutcome::test("web", rtt)
+        };
+        for _ in 0..2 {
+            tracker.record(&outcome("icmp", Ok(Duration::from_millis(1))));
+            tracker.record(&outcome("tls", Err(ErrorKind::Tls)));
+        }
+        // The failures of one probe don't take down the others.
+        let up = |probe| tracker.up.with_label_values(&["web", probe]).get();
+        assert_eq!((up("icmp"), up("tls")), (1, 0));
+    }
+
+    #[test]
+    fn counts_by_state() {
+        let tracker = StateTracker::new(&config(), &Registry::new()).unwrap();
+        let count = |state| tracker.by_state.with_label_values(&[state]).get();
+        let ok = || Ok(Duration::from_millis(1));
+
+        tracker.record(&PingOutcome::test("127.0.0.1", ok()));
+        tracker.record(&PingOutcome::test("127.0.0.2", ok()));
+        tracker.record(&PingOutcome::test("127.0.0.2", Err(ErrorKind::Timeout)));
+        assert_eq!((count("up"), count("degraded"), count("down")), (1, 1, 0));
+
+        tracker.record(&PingOutcome::test("127.0.0.2", Err(ErrorKind::Timeout)));
+        assert_eq!((count("up"), count("degraded"), count("down")), (1, 0, 1));
+
+        tracker.record(&PingOutcome::test("127.0.0.2", ok()));
+        assert_eq!(count("down"), 1, "recovering targets are still down");
+    }
+
+    #[test]
+    fn flapping() {
+        let config = FlappingConfig {
+            changes: 3,
+            window_secs: 60,
+        };
+        let default = HysteresisConfig {
+            failures_to_down: Some(1),
+            successes_to_up: Some(1),
+            ..Default::default()
+        };
+        let mut state = TargetState::new(default.resolve(&default));
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut observe = |success, secs| {
+            let changed = state.observe(success, at(secs)).is_some();
+            state.observe_flapping(&config, changed, at(secs))
+        };
+
+        assert_eq!(observe(false, 0), None);
+        assert_eq!(observe(true, 10), None);
+        assert_eq!(observe(false, 20), Some(true), "3 changes within 60s");
+        assert_eq!(observe(true, 30), None, "still flapping");
+        assert_eq!(observe(true, 70), None, "changes from 10s remain");
+        assert_eq!(observe(true, 81), Some(false));
+    }
+}
@@ -0,0 +1,466 @@
+//! Live stream of ping results to other processes, such as `uppies top`,
+//! served as newline delimited JSON by default, or in any other
+//! [`encoding`](crate::encoding).
+//!
+//! Results are also served over a WebSocket at [`StreamSink::WS_PATH`], as
+//! a JSON text message each, for dashboards which can't read a streamed
+//! response. Each connection can be limited to some targets, by sending a
+//! [`Subscription`].
+
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    pin::pin,
+    sync::{Arc, RwLock},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tracing::{debug, error};
+
+use crate::{
+    encoding::{Compression, Encoder, Frame, Framing},
+    proto,
+    sink::Sink,
+    PingOutcome, Result,
+};
+
+/// A single ping result within the stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamEvent {
+    pub target: String,
+    /// Probe of the target, see [`ProbeTarget::probe`](crate::ProbeTarget::probe).
+    #[serde(default)]
+    pub probe: String,
+    pub sequence: u64,
+    /// Round-trip time in milliseconds, if the ping succeeded, which pings
+    /// of targets expected to be down never have.
+    pub rtt_ms: Option<f64>,
+    /// Reason the ping failed, if it did.
+    pub error: Option<String>,
+    /// Time at which the ping completed, in milliseconds since the unix
+    /// epoch.
+    pub timestamp_ms: u64,
+    /// Time to live of the reply, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_ttl: Option<u8>,
+    /// Address which the reply came from, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_source: Option<IpAddr>,
+}
+
+impl From<&PingOutcome> for StreamEvent {
+    fn from(outcome: &PingOutcome) -> Self {
+        Self {
+            target: outcome.target.to_string(),
+            probe: outcome.probe.to_string(),
+            sequence: outcome.sequence,
+            rtt_ms: outcome.latency().map(|d| d.as_secs_f64() * 1000.0),
+            error: outcome.rtt.as_ref().err().map(|e| e.to_string()),
+            timestamp_ms: outcome
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            reply_ttl: outcome.reply.and_then(|reply| reply.ttl),
+            reply_source: outcome.reply.map(|reply| reply.source),
+        }
+    }
+}
+
+impl Frame for StreamEvent {
+    type Proto = proto::ProbeResult;
+
+    fn to_proto(&self) -> Self::Proto {
+        self.into()
+    }
+}
+
+/// Targets which a WebSocket connection receives the results of, replaced
+/// whenever the client sends a new subscription such as
+/// `{"targets": ["1.1.1.1"]}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subscription {
+    /// Targets whose results are sent, or every target if `None`.
+    #[serde(default)]
+    pub targets: Option<HashSet<String>>,
+}
+
+impl Subscription {
+    fn matches(&self, target: &str) -> bool {
+        self.targets
+            .as_ref()
+            .is_none_or(|targets| targets.contains(target))
+    }
+}
+
+/// Sink which broadcasts every result to all subscribers of the stream.
+///
+/// Clones share the same subscribers.
+#[derive(Clone)]
+pub struct StreamSink {
+    /// Sender of every result, until the stream is [closed](Self::close).
+    tx: Arc<RwLock<Option<broadcast::Sender<StreamEvent>>>>,
+}
+
+impl Default for StreamSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamSink {
+    /// Path which the stream is served at.
+    pub const PATH: &str = "/stream";
+
+    /// Path which the stream is served at over a WebSocket.
+    pub const WS_PATH: &str = "/ws";
+
+    /// Number of results buffered for each subscriber.
+    const CAPACITY: usize = 1024;
+
+    /// Maximum time which results are batched for before being compressed,
+    /// so that compression isn't defeated by flushing every result.
+    const COMPRESSION_DELAY: Duration = Duration::from_millis(100);
+
+    pub fn new() -> Self {
+        Self {
+            tx: Arc::new(RwLock::new(Some(broadcast::channel(Self::CAPACITY).0))),
+        }
+    }
+
+    /// End every stream of results, including those subscribed later, once
+    /// they have sent the results already recorded. Streamed responses never
+    /// end by themselves, so are closed on shutdown for the server to drain.
+    pub fn close(&self) {
+        self.tx.write().expect("stream lock poisoned").take();
+    }
+
+    #[cfg(test)]
+    fn receiver_count(&self) -> usize {
+        let tx = self.tx.read().expect("stream lock poisoned");
+        tx.as_ref().map_or(0, |tx| tx.receiver_count())
+    }
+
+    /// Stream of all subsequent results, which ends once the stream is
+    /// [closed](Self::close).
+    ///
+    /// Subscribers which fall behind skip results, rather than delaying the
+    /// other sinks.
+    pub fn events(&self) -> impl Stream<Item = StreamEvent> + Send + 'static {
+        let rx = match &*self.tx.read().expect("stream lock poisoned") {
+            Some(tx) => tx.subscribe(),
+            // Receivers of a channel without senders are already closed.
+            None => broadcast::channel(1).1,
+        };
+        BroadcastStream::new(rx).filter_map(|event| event.ok())
+    }
+
+    /// Stream of all subsequent results, each as a line of JSON.
+    pub fn lines(&self) -> impl Stream<Item = String> + Send + 'static {
+        self.events()
+            .filter_map(|event| match serde_json::to_string(&event) {
+                Ok(line) => Some(line + "\n"),
+                Err(e) => {
+                    error!(?e, "failed to serialise stream event");
+                    None
+                }
+            })
+    }
+
+    /// Stream of all subsequent results as chunks of bytes, with the given
+    /// `framing` and `compression`.
+    ///
+    /// Compressed results are batched for up to 100ms, whereas uncompressed
+    /// results are sent as soon as they are recorded.
+    pub fn encoded(
+        &self,
+        framing: Framing,
+        compression: Compression,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>>> + Send + 'static> {
+        let mut encoder = Encoder::new(framing, compression)?;
+        let batch_size = match compression {
+            Compression::None => 1,
+            _ => Self::CAPACITY,
+        };
+        Ok(self
+            .events()
+            .chunks_timeout(batch_size, Self::COMPRESSION_DELAY)
+            .map(move |events| encoder.encode(&events)))
+    }
+
+    /// Send all subsequent results matching `subscription` over `socket`,
+    /// until either side closes it or the stream is [closed](Self::close).
+    ///
+    /// Invalid subscriptions from the client are answered with an
+    /// `{"error": ...}` message, keeping the current subscription.
+    pub async fn websocket(&self, mut socket: WebSocket, mut subscription: Subscription) {
+        let mut events = pin!(self.events());
+        loop {
+            tokio::select! {
+                event = events.next() => {
+                    let Some(event) = event else {
+                        let _ = socket.send(Message::Close(None)).await;
+                        return;
+                    };
+                    if !subscription.matches(&event.target) {
+                        continue;
+                    }
+                    let json = match serde_json::to_string(&event) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            error!(?e, "failed to serialise stream event");
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(json.into())).await.is_err() {
+                        return;
+                    }
+                }
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                        Ok(update) => {
+                            debug!(?update, "websocket subscription updated");
+                            subscription = update;
+                        }
+                        Err(e) => {
+                            let error = serde_json::json!({ "error": e.to_string() });
+                            let reply = Message::Text(error.to_string().into());
+                            if socket.send(reply).await.is_err() {
+                                return;
+                            }
+                        }
+                    },
+                    // Pings are answered by axum itself.
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => {}
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                },
+            }
+        }
+    }
+}
+
+impl Sink for StreamSink {
+    fn record(&self, outcome: &PingOutcome) {
+        let tx = self.tx.read().expect("stream lock poisoned");
+        let Some(tx) = tx.as_ref().filter(|tx| tx.receiver_count() > 0) else {
+            return;
+        };
+        // Subscribers may have disconnected since being counted.
+        let _ = tx.send(StreamEvent::from(outcome));
+    }
+}
+
+/// Subscribe to the stream of the instance at `base_url`, such as
+/// `http://localhost:9000`.
+///
+/// The receiver yields an error, then closes, if the stream ends.
+pub async fn subscribe(
+    client: &reqwest::Client,
+    base_url: &str,
+) -> Result<mpsc::Receiver<Result<StreamEvent>>> {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), StreamSink::PATH);
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    let (tx, rx) = mpsc::channel(StreamSink::CAPACITY);
+    tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => {
+                    let _ = tx.send(Err("stream closed".to_string().into())).await;
+                    return;
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e.into())).await;
+                    return;
+                }
+            };
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<_> = buffer.drain(..=end).collect();
+                let event = serde_json::from_slice(&line).map_err(Into::into);
+                if tx.send(event).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use axum::{body::Body, routing::get, Router};
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+
+    use super::{subscribe, StreamEvent, StreamSink, Subscription};
+    use crate::{sink::Sink, ErrorKind, PingOutcome};
+
+    #[tokio::test]
+    async fn subscribe_to_stream() {
+        let sink = StreamSink::new();
+        let lines = sink.clone();
+        let app = Router::new().route(
+            StreamSink::PATH,
+            get(move || async move {
+                Body::from_stream(lines.lines().map(Ok::<_, std::convert::Infallible>))
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut events = subscribe(&reqwest::Client::new(), &format!("http://{addr}/"))
+            .await
+            .unwrap();
+        sink.record(&PingOutcome::test(
+            "127.0.0.1",
+            Ok(Duration::from_millis(2)),
+        ));
+        sink.record(&PingOutcome::test("127.0.0.1", Err(ErrorKind::Timeout)));
+
+        let first: StreamEvent = events.recv().await.unwrap().unwrap();
+        assert_eq!(first.target, "127.0.0.1");
+        assert_eq!(first.rtt_ms, Some(2.0));
+        let second = events.recv().await.unwrap().unwrap();
+        assert_eq!(second.rtt_ms, None);
+        assert_eq!(second.error.as_deref(), Some("timeout"));
+    }
+
+    #[tokio::test]
+    async fn close_on_shutdown() {
+        let sink = StreamSink::new();
+        let (lines, stream) = (sink.clone(), sink.clone());
+        let app = Router::new()
+            .route(
+                StreamSink::PATH,
+                get(move || async move {
+                    Body::from_stream(lines.lines().map(Ok::<_, std::convert::Infallible>))
+                }),
+            )
+            .route(
+                StreamSink::WS_PATH,
+                get(move |ws: axum::extract::ws::WebSocketUpgrade| async move {
+                    ws.on_upgrade(move |socket| async move {
+                        stream.websocket(socket, Subscription::default()).await
+                    })
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, stopping) = tokio::sync::oneshot::channel::<()>();
+        let closing = sink.clone();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async move {
+                    let _ = stopping.await;
+                    closing.close();
+                })
+                .await
+        });
+
+        let mut events = subscribe(&reqwest::Client::new(), &format!("http://{addr}/"))
+            .await
+            .unwrap();
+        let websocket = std::thread::spawn(move || {
+            let (mut socket, _) = tungstenite::connect(format!("ws://{addr}/ws")).unwrap();
+            loop {
+                match socket.read() {
+                    Ok(tungstenite::Message::Close(_)) => return true,
+                    Ok(_) => {}
+                    Err(_) => return false,
+                }
+            }
+        });
+        for _ in 0..100 {
+            if sink.receiver_count() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(sink.receiver_count(), 2);
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("open streams don't hold up shutdown")
+            .unwrap()
+            .unwrap();
+        assert!(events.recv().await.unwrap().is_err(), "stream closed");
+        let closed = tokio::task::spawn_blocking(move || websocket.join().unwrap())
+            .await
+            .unwrap();
+        assert!(closed, "websocket closed");
+        assert_eq!(sink.events().next().await, None);
+    }
+
+    #[tokio::test]
+    async fn websocket_filters_targets() {
+        let sink = StreamSink::new();
+        let stream = sink.clone();
+        let app = Router::new().route(
+            StreamSink::WS_PATH,
+            get(move |ws: axum::extract::ws::WebSocketUpgrade| async move {
+                let subscription = Subscription {
+                    targets: Some(["10.0.0.1".to_string()].into()),
+                };
+                ws.on_upgrade(
+                    move |socket| async move { stream.websocket(socket, subscription).await },
+                )
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let client = std::thread::spawn(move || {
+            let (mut socket, _) = tungstenite::connect(format!("ws://{addr}/ws")).unwrap();
+            tx.send(()).unwrap();
+            let mut read = || socket.read().unwrap().into_text().unwrap().to_string();
+            let first: StreamEvent = serde_json::from_str(&read()).unwrap();
+            socket
+                .send(tungstenite::Message::text(r#"{"targets": 1}"#))
+                .unwrap();
+            let error = socket.read().unwrap().into_text().unwrap();
+            socket
+                .send(tungstenite::Message::text(r#"{"targets": null}"#))
+                .unwrap();
+            tx.send(()).unwrap();
+            let second: StreamEvent =
+                serde_json::from_str(&socket.read().unwrap().into_text().unwrap()).unwrap();
+            (first, error.to_string(), second)
+        });
+
+        rx.recv().await.unwrap();
+        // The connection may not have subscribed yet once upgraded.
+        for _ in 0..100 {
+            if sink.receiver_count() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let ping = |target| PingOutcome::test(target, Ok(Duration::from_millis(1)));
+        sink.record(&ping("192.168.1.1"));
+        sink.record(&ping("10.0.0.1"));
+        rx.recv().await.unwrap();
+        // Give the subscription a moment to be applied.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sink.record(&ping("192.168.1.1"));
+
+        let (first, error, second) = tokio::task::spawn_blocking(move || client.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(first.target, "10.0.0.1");
+        assert!(error.contains("error"), "{error}");
+        assert_eq!(second.target, "192.168.1.1");
+    }
+}
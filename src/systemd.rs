@@ -0,0 +1,147 @@
+//! Integration with systemd for services of `Type=notify`, which are
+//! started once uppies reports that it is ready, and restarted when it stops
+//! petting the watchdog of `WatchdogSec=`.
+//!
+//! Readiness is reported once every dispatcher has been launched and the
+//! HTTP server is listening. The watchdog is only petted while dispatchers
+//! are still ticking, so that a scheduler which has stalled is restarted
+//! rather than serving stale metrics. Outside of systemd, where
+//! `NOTIFY_SOCKET` is unset, nothing is sent.
+
+use std::{
+    io,
+    os::{linux::net::SocketAddrExt, unix::net::UnixDatagram},
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    launch::{Liveness, Readiness},
+    targets::TargetSet,
+};
+
+/// Send `state`, such as `READY=1`, to the service manager, returning
+/// whether uppies is run by one.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) => notify_socket(&socket, state).map(|()| true),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Send `state` to the notification socket at `socket`, where a leading `@`
+/// is within the abstract namespace.
+fn notify_socket(socket: &str, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        Some(name) => {
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    };
+    Ok(())
+}
+
+/// Interval of the watchdog of the service, if enabled for this process.
+pub fn watchdog() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Whether dispatchers have ticked within `stale_after`, or there are none
+/// to tick.
+fn alive(liveness: &Liveness, target_set: &TargetSet, stale_after: Duration) -> bool {
+    liveness.since_tick() <= stale_after || target_set.is_empty()
+}
+
+/// Report readiness once every dispatcher has been launched, then pet the
+/// watchdog at half of its interval while dispatchers have ticked within
+/// `stale_after`.
+///
+/// This must only be started once the HTTP server is listening.
+pub async fn supervise(
+    readiness: Readiness,
+    liveness: Liveness,
+    target_set: TargetSet,
+    stale_after: Duration,
+) {
+    while !readiness.is_ready() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    match notify("READY=1") {
+        Ok(true) => info!("notified systemd of readiness"),
+        Ok(false) => return,
+        Err(e) => warn!(%e, "failed to notify systemd of readiness"),
+    }
+    let Some(watchdog) = watchdog() else {
+        return;
+    };
+    info!(?watchdog, "petting the systemd watchdog");
+    let mut interval = tokio::time::interval(watchdog / 2);
+    loop {
+        interval.tick().await;
+        if !alive(&liveness, &target_set, stale_after) {
+            warn!(
+                since_tick = ?liveness.since_tick(),
+                "dispatchers have stalled, not petting the watchdog"
+            );
+            continue;
+        }
+        match notify("WATCHDOG=1") {
+            Ok(_) => debug!("petted the systemd watchdog"),
+            Err(e) => warn!(%e, "failed to pet the systemd watchdog"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::{linux::net::SocketAddrExt, unix::net::UnixDatagram},
+        time::Duration,
+    };
+
+    use super::{alive, notify_socket};
+    use crate::{launch::Liveness, targets::TargetSet};
+
+    #[test]
+    fn notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        let name = format!("uppies-test-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        notify_socket(&format!("@{name}"), "WATCHDOG=1").unwrap();
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn liveness() {
+        let liveness = Liveness::default();
+        let target_set = TargetSet::default();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(liveness.since_tick() >= Duration::from_millis(20));
+        assert!(
+            alive(&liveness, &target_set, Duration::ZERO),
+            "alive without targets"
+        );
+        liveness.beat();
+        assert!(liveness.since_tick() < Duration::from_millis(20));
+    }
+}
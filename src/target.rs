@@ -0,0 +1,493 @@
+//! Targets of probes, parsed from strings such as `1.1.1.1`,
+//! `gateway=192.168.1.1`, `tls://example.com:8443` or
+//! `grpc://10.0.0.5:50051/payments`.
+//!
+//! Targets are normalized as they are parsed, so that the same target is
+//! always labelled the same way: IP addresses are written in their canonical
+//! form, hostnames are lowercased without a trailing dot, and the default
+//! port of the scheme is omitted.
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::UppiesError;
+
+/// Protocol which a target is probed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Scheme {
+    #[default]
+    Icmp,
+    /// TLS handshakes, see [`TlsProbe`](crate::probe::TlsProbe).
+    Tls,
+    /// gRPC health checks, see [`GrpcProbe`](crate::probe::GrpcProbe).
+    Grpc,
+    /// SSH version banners, see [`SshProbe`](crate::probe::SshProbe).
+    Ssh,
+    /// SMTP greetings, see [`MailProbe`](crate::probe::MailProbe).
+    Smtp,
+    /// IMAP greetings, see [`MailProbe`](crate::probe::MailProbe).
+    Imap,
+    /// NTP clock offsets, see [`NtpProbe`](crate::probe::NtpProbe).
+    Ntp,
+    /// Address resolution of targets on the local segment, see
+    /// [`ArpProbe`](crate::probe::ArpProbe).
+    Arp,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Icmp => "icmp",
+            Self::Tls => "tls",
+            Self::Grpc => "grpc",
+            Self::Ssh => "ssh",
+            Self::Smtp => "smtp",
+            Self::Imap => "imap",
+            Self::Ntp => "ntp",
+            Self::Arp => "arp",
+        }
+    }
+
+    /// Whether targets of the scheme have a port.
+    pub fn has_ports(&self) -> bool {
+        !matches!(self, Self::Icmp | Self::Arp)
+    }
+
+    /// Port of targets which aren't given one, or `None` if they must be.
+    pub fn default_port(&self) -> Option<u16> {
+        match self {
+            Self::Icmp | Self::Grpc | Self::Arp => None,
+            Self::Tls => Some(443),
+            Self::Ssh => Some(22),
+            Self::Smtp => Some(25),
+            Self::Imap => Some(143),
+            Self::Ntp => Some(123),
+        }
+    }
+
+    /// Whether targets of the scheme can have a path, such as the service
+    /// of a gRPC health check.
+    pub fn has_path(&self) -> bool {
+        matches!(self, Self::Grpc)
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "icmp" => Ok(Self::Icmp),
+            "tls" => Ok(Self::Tls),
+            "grpc" => Ok(Self::Grpc),
+            "ssh" => Ok(Self::Ssh),
+            "smtp" => Ok(Self::Smtp),
+            "imap" => Ok(Self::Imap),
+            "ntp" => Ok(Self::Ntp),
+            "arp" => Ok(Self::Arp),
+            _ => Err(format!(
+                "unknown scheme '{s}', expected 'icmp', 'tls', 'grpc', 'ssh', 'smtp', 'imap', 'ntp' or 'arp'"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Scheme {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Target of a probe, written as
+/// `[alias=][scheme://]host[:port][/path][?module=name]`.
+///
+/// The scheme defaults to ICMP, and IPv6 addresses must be bracketed when
+/// they are given a scheme or port. Targets are displayed in the same form,
+/// omitting the default scheme.
+///
+/// A target with a module is probed with the parameters of that module of
+/// the configuration, such as `1.1.1.1?module=icmp-fast`, instead of those
+/// of its scheme.
+///
+/// Targets which share an alias but differ in their [`probe`](Self::probe),
+/// such as `web=10.0.0.1` and `web=tls://10.0.0.1`, are probed alongside
+/// one another as the probes of a single target.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProbeTarget {
+    scheme: Scheme,
+    /// IP address, optionally scoped to an interface as in `fe80::1%eth0`,
+    /// or hostname.
+    host: String,
+    /// Port, unless it is the default of the scheme.
+    port: Option<u16>,
+    /// Path after the address, without its leading `/`.
+    path: Option<String>,
+    /// Name which the target is labelled by instead of its address.
+    alias: Option<String>,
+    /// Name of the module which the target is probed with.
+    module: Option<String>,
+}
+
+impl ProbeTarget {
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    /// Address or hostname of the target, without its scheme or port.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Port of the target, which is the default of its scheme unless
+    /// given.
+    pub fn port(&self) -> Option<u16> {
+        self.port.or(self.scheme.default_port())
+    }
+
+    /// Path after the address, such as the service of a gRPC target.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    /// Name of the module which the target is probed with, if any.
+    pub fn module(&self) -> Option<&str> {
+        self.module.as_deref()
+    }
+
+    /// Name of the probe of the target, which its results and metrics are
+    /// labelled by alongside its [`label`](Self::label): its module if it
+    /// has one, otherwise its scheme.
+    pub fn probe(&self) -> &str {
+        self.module.as_deref().unwrap_or(self.scheme.as_str())
+    }
+
+    /// IP address of the target, unless it is given by hostname.
+    pub fn ip(&self) -> Option<IpAddr> {
+        let (ip, _) = split_scope(&self.host);
+        ip.parse().ok()
+    }
+
+    /// Interface which an IPv6 address is scoped to, such as `eth0` of
+    /// `fe80::1%eth0`.
+    pub fn scope(&self) -> Option<&str> {
+        split_scope(&self.host).1
+    }
+
+    /// The same target of another IP address, such as of each address of a
+    /// [`TargetRange`](crate::range::TargetRange).
+    pub(crate) fn with_ip(&self, ip: IpAddr) -> Self {
+        Self {
+            host: ip.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the target is given by hostname rather than IP address.
+    pub fn is_hostname(&self) -> bool {
+        is_hostname(&self.host)
+    }
+
+    /// Label of the target within metrics and results, which is its alias
+    /// if it has one, otherwise its address.
+    pub fn label(&self) -> String {
+        match &self.alias {
+            Some(alias) => alias.clone(),
+            None => self.address(),
+        }
+    }
+
+    /// The target without its alias, such as `[::1]:443`.
+    fn address(&self) -> String {
+        let mut address = String::new();
+        if self.scheme != Scheme::default() {
+            address.push_str(self.scheme.as_str());
+            address.push_str("://");
+        }
+        match self.port {
+            Some(port) if self.host.contains(':') => {
+                address.push_str(&format!("[{}]:{port}", self.host))
+            }
+            Some(port) => address.push_str(&format!("{}:{port}", self.host)),
+            None => address.push_str(&self.host),
+        }
+        if let Some(path) = &self.path {
+            address.push('/');
+            address.push_str(path);
+        }
+        if let Some(module) = &self.module {
+            address.push_str("?module=");
+            address.push_str(module);
+        }
+        address
+    }
+}
+
+impl fmt::Display for ProbeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(alias) = &self.alias {
+            write!(f, "{alias}=")?;
+        }
+        f.write_str(&self.address())
+    }
+}
+
+impl FromStr for ProbeTarget {
+    type Err = UppiesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: &str| UppiesError::InvalidTarget(format!("invalid target '{s}': {reason}"));
+        let (rest, module) = match s.split_once('?') {
+            Some((rest, query)) => {
+                let module = query
+                    .strip_prefix("module=")
+                    .ok_or_else(|| invalid("expected '?module=name'"))?;
+                if module.is_empty() || module.chars().any(char::is_whitespace) {
+                    return Err(invalid("modules must be non-empty without whitespace"));
+                }
+                (rest, Some(module))
+            }
+            None => (s, None),
+        };
+        let (alias, rest) = match rest.split_once('=') {
+            Some((alias, rest)) => (Some(alias), rest),
+            None => (None, rest),
+        };
+        if let Some(alias) = alias {
+            if alias.is_empty() || alias.chars().any(char::is_whitespace) {
+                return Err(invalid("aliases must be non-empty without whitespace"));
+            }
+        }
+        let (scheme, rest) = match rest.split_once("://") {
+            Some((scheme, rest)) => (scheme.parse().map_err(|e: String| invalid(&e))?, rest),
+            None => (Scheme::default(), rest),
+        };
+        let (rest, path) = match rest.split_once('/') {
+            Some((rest, path)) => (rest, Some(path).filter(|path| !path.is_empty())),
+            None => (rest, None),
+        };
+        if path.is_some() && !scheme.has_path() {
+            let reason = format!("{} targets have no path", scheme.as_str());
+            return Err(invalid(&reason));
+        }
+
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or_else(|| invalid("missing closing bracket"))?;
+            let port = match rest {
+                "" => None,
+                _ => Some(
+                    rest.strip_prefix(':')
+                        .ok_or_else(|| invalid("expected a port"))?,
+                ),
+            };
+            (host, port)
+        } else {
+            match rest.rsplit_once(':') {
+                // Unbracketed IPv6 addresses have no port.
+                Some((host, port)) if !host.contains(':') => (host, Some(port)),
+                _ => (rest, None),
+            }
+        };
+        let port = port
+            .map(|port| port.parse::<u16>().map_err(|_| invalid("invalid port")))
+            .transpose()?;
+        let port = match (port, scheme.has_ports(), scheme.default_port()) {
+            (Some(_), false, _) => {
+                let reason = format!("{} targets have no port", scheme.as_str());
+                return Err(invalid(&reason));
+            }
+            (None, true, None) => {
+                let reason = format!("{} targets require a port", scheme.as_str());
+                return Err(invalid(&reason));
+            }
+            (port, _, default) if port == default => None,
+            (port, _, _) => port,
+        };
+
+        Ok(Self {
+            scheme,
+            host: normalize_host(host)
+                .ok_or_else(|| invalid("expected an IP address or hostname"))?,
+            port,
+            path: path.map(str::to_string),
+            alias: alias.map(str::to_string),
+            module: module.map(str::to_string),
+        })
+    }
+}
+
+impl TryFrom<String> for ProbeTarget {
+    type Error = UppiesError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ProbeTarget> for String {
+    fn from(target: ProbeTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// Split the interface scope from an IPv6 address such as `fe80::1%eth0`.
+fn split_scope(host: &str) -> (&str, Option<&str>) {
+    match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    }
+}
+
+/// Canonical form of the IP address or hostname `host`, if it is valid.
+fn normalize_host(host: &str) -> Option<String> {
+    let (ip, scope) = split_scope(host);
+    match (ip.parse::<IpAddr>(), scope) {
+        (Ok(ip @ IpAddr::V6(_)), Some(scope)) if !scope.is_empty() => {
+            return Some(format!("{ip}%{scope}"))
+        }
+        (Ok(ip), None) => return Some(ip.to_string()),
+        _ => {}
+    }
+    let hostname = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+    is_hostname(&hostname).then_some(hostname)
+}
+
+/// Whether `host` is a hostname rather than an IP address, such as
+/// `example.com` rather than `1.1.1`.
+fn is_hostname(host: &str) -> bool {
+    host.parse::<IpAddr>().is_err()
+        && host.chars().any(|c| c.is_ascii_alphabetic())
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && !host.split('.').any(str::is_empty)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProbeTarget, Scheme};
+    use crate::UppiesError;
+
+    fn parse(s: &str) -> ProbeTarget {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn normalization() {
+        for (target, normalized) in [
+            ("1.1.1.1", "1.1.1.1"),
+            ("icmp://1.1.1.1", "1.1.1.1"),
+            ("0:0::1", "::1"),
+            ("FE80::1%eth0", "fe80::1%eth0"),
+            ("icmp://[2001:DB8::1]", "2001:db8::1"),
+            ("Router.LAN.", "router.lan"),
+            ("gateway=192.168.1.1", "gateway=192.168.1.1"),
+            ("tls://Example.com:443", "tls://example.com"),
+            ("tls://[::1]:8443", "tls://[::1]:8443"),
+            ("grpc://Svc.local:50051/", "grpc://svc.local:50051"),
+            ("grpc://[::1]:50051/payments", "grpc://[::1]:50051/payments"),
+            ("ssh://bastion:22", "ssh://bastion"),
+            ("smtp://MX.example.com:587", "smtp://mx.example.com:587"),
+            ("imap://mail:143", "imap://mail"),
+            ("ntp://time.example.com:123", "ntp://time.example.com"),
+            ("arp://[fe80::1%eth0]", "arp://fe80::1%eth0"),
+            (
+                "dns=8.8.8.8?module=icmp-fast",
+                "dns=8.8.8.8?module=icmp-fast",
+            ),
+            (
+                "tls://[::1]:443?module=internal",
+                "tls://::1?module=internal",
+            ),
+        ] {
+            assert_eq!(parse(target).to_string(), normalized, "{target}");
+            assert_eq!(parse(normalized), parse(target), "{target}");
+        }
+        assert_eq!(parse("Example.com"), parse("example.com."));
+    }
+
+    #[test]
+    fn components() {
+        let target = parse("gateway=192.168.1.1");
+        assert_eq!(target.scheme(), Scheme::Icmp);
+        assert_eq!(target.host(), "192.168.1.1");
+        assert_eq!(target.port(), None);
+        assert_eq!(target.alias(), Some("gateway"));
+        assert_eq!(target.label(), "gateway");
+        assert_eq!(target.probe(), "icmp");
+        assert_eq!(target.ip(), "192.168.1.1".parse().ok());
+        assert!(!target.is_hostname());
+
+        let target = parse("fe80::1%eth0");
+        assert_eq!(target.label(), "fe80::1%eth0");
+        assert_eq!(target.ip(), "fe80::1".parse().ok());
+        assert_eq!(target.scope(), Some("eth0"));
+        assert_eq!(parse("fe80::1").scope(), None);
+        assert!(parse("router").is_hostname());
+
+        let target = parse("tls://example.com");
+        assert_eq!(target.scheme(), Scheme::Tls);
+        assert_eq!(target.port(), Some(443));
+        assert_eq!(target.probe(), "tls");
+        assert_eq!(parse("tls://example.com:8443").port(), Some(8443));
+        assert_eq!(parse("grpc://svc:50051/payments").path(), Some("payments"));
+
+        let target = parse("1.1.1.1?module=icmp-fast");
+        assert_eq!(target.module(), Some("icmp-fast"));
+        assert_eq!(target.host(), "1.1.1.1");
+        assert_eq!(target.label(), "1.1.1.1?module=icmp-fast");
+        assert_eq!(target.probe(), "icmp-fast");
+        assert_ne!(target, parse("1.1.1.1"));
+    }
+
+    #[test]
+    fn invalid() {
+        for target in [
+            "",
+            "1.1.1",
+            "example..com",
+            "exa mple.com",
+            "http://1.1.1.1",
+            "tls://example.com:https",
+            "tls://example.com:99999",
+            "tls://example.com/path",
+            "grpc://svc.local/payments",
+            "1.1.1.1:80",
+            "[::1",
+            "=1.1.1.1",
+            "1.1.1.1%eth0",
+            "1.1.1.1?module=",
+            "1.1.1.1?timeout=5",
+        ] {
+            assert!(target.parse::<ProbeTarget>().is_err(), "{target}");
+        }
+        let err = "http://1.1.1.1".parse::<ProbeTarget>().unwrap_err();
+        assert!(matches!(err, UppiesError::InvalidTarget(_)));
+        assert_eq!(
+            err.to_string(),
+            "invalid target 'http://1.1.1.1': unknown scheme 'http', expected 'icmp', 'tls', 'grpc', 'ssh', 'smtp', 'imap', 'ntp' or 'arp'"
+        );
+    }
+
+    #[test]
+    fn serde() {
+        let targets: Vec<ProbeTarget> =
+            serde_json::from_str(r#"["Router.LAN", "dns=8.8.8.8"]"#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&targets).unwrap(),
+            r#"["router.lan","dns=8.8.8.8"]"#
+        );
+        assert!(serde_json::from_str::<ProbeTarget>(r#""1.1.1""#).is_err());
+    }
+}
@@ -0,0 +1,611 @@
+//! Management of the targets of a running instance, so that they can be
+//! declared from version control rather than only on startup.
+//!
+//! The current targets are exported, and a desired list of targets is
+//! applied by reconciling: targets which aren't listed stop being pinged,
+//! and those which are new start being pinged. Sources of targets which
+//! change, such as a [`TargetsFile`] of one target per line, are reconciled
+//! in the same way through [`TargetSources`].
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{
+    range::{self, RangeConfig, TargetSpec},
+    target::ProbeTarget,
+    Result, Spawner, TaskStatus, UppiesError,
+};
+
+/// Format of a [`TargetList`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Toml => "application/toml",
+            Self::Yaml => "application/yaml",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            "yaml" => Ok(Self::Yaml),
+            _ => Err(format!(
+                "unknown format '{s}', expected 'json', 'toml' or 'yaml'"
+            )),
+        }
+    }
+}
+
+/// Targets in the same layout as the configuration file, served at
+/// [`TargetSet::PATH`] with a `format` and applied by a `PUT` to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetList {
+    pub targets: Vec<ProbeTarget>,
+}
+
+impl TargetList {
+    pub fn parse(contents: &str, format: Format) -> Result<Self> {
+        Ok(match format {
+            Format::Json => serde_json::from_str(contents)?,
+            Format::Toml => toml::from_str(contents)?,
+            Format::Yaml => serde_yaml_ng::from_str(contents)?,
+        })
+    }
+
+    pub fn render(&self, format: Format) -> Result<String> {
+        Ok(match format {
+            Format::Json => serde_json::to_string_pretty(self)? + "\n",
+            Format::Toml => toml::to_string(self)?,
+            Format::Yaml => serde_yaml_ng::to_string(self)?,
+        })
+    }
+}
+
+/// Changes made by [`TargetSet::reconcile`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reconciled {
+    pub added: Vec<ProbeTarget>,
+    pub removed: Vec<ProbeTarget>,
+}
+
+/// Handle to add and remove the targets of a started
+/// [`PingSender`](crate::PingSender).
+///
+/// Clones share the same underlying state.
+#[derive(Clone, Default)]
+pub struct TargetSet {
+    spawner: Arc<Mutex<Option<Spawner>>>,
+}
+
+impl TargetSet {
+    /// Path which the [`TargetList`] is served at when given a `format`
+    /// query, and applied at by a `PUT`. This is shared with
+    /// [`Pauses::PATH`](crate::pause::Pauses::PATH), which is served
+    /// without a `format`.
+    pub const PATH: &str = "/targets";
+
+    pub(crate) fn start(&self, spawner: Spawner) {
+        *self.spawner.lock().expect("targets lock poisoned") = Some(spawner);
+    }
+
+    fn with_spawner<T>(&self, f: impl FnOnce(&mut Spawner) -> Result<T>) -> Result<T> {
+        let mut spawner = self.spawner.lock().expect("targets lock poisoned");
+        match spawner.as_mut() {
+            Some(spawner) => f(spawner),
+            None => Err("pinging has not started".to_string().into()),
+        }
+    }
+
+    /// All targets which are pinged, including those pending launch,
+    /// ordered by label.
+    pub fn targets(&self) -> Vec<ProbeTarget> {
+        let mut targets = self
+            .with_spawner(|spawner| Ok(spawner.targets().cloned().collect::<Vec<_>>()))
+            .unwrap_or_default();
+        targets.sort_by_key(ProbeTarget::label);
+        targets
+    }
+
+    /// Whether no targets are pinged, including those pending launch.
+    pub fn is_empty(&self) -> bool {
+        self.with_spawner(|spawner| Ok(spawner.tasks.is_empty() && spawner.pending.is_empty()))
+            .unwrap_or(true)
+    }
+
+    /// Number of targets which are pending launch, see
+    /// [`launch`](crate::launch).
+    pub fn pending(&self) -> usize {
+        self.with_spawner(|spawner| Ok(spawner.pending.len()))
+            .unwrap_or_default()
+    }
+
+    /// Launch up to `count` of the pending targets, returning the number
+    /// which remain pending.
+    pub(crate) fn launch(&self, count: usize) -> usize {
+        self.with_spawner(|spawner| Ok(spawner.launch(count)))
+            .unwrap_or_default()
+    }
+
+    /// Start pinging `target`, returning `false` if a target with the same
+    /// label and probe is already pinged.
+    pub fn add(&self, target: &ProbeTarget) -> Result<bool> {
+        self.with_spawner(|spawner| spawner.add(target))
+    }
+
+    /// Stop pinging every probe of the target labelled `label`, returning
+    /// `false` if it isn't pinged.
+    pub fn remove(&self, label: &str) -> Result<bool> {
+        self.with_spawner(|spawner| Ok(spawner.remove(label)))
+    }
+
+    /// Stop pinging every target, including those pending launch, such as
+    /// on shutdown, returning the number of targets which were stopped.
+    pub fn stop(&self) -> usize {
+        self.with_spawner(|spawner| {
+            let labels: Vec<_> = spawner.targets().map(ProbeTarget::label).collect();
+            Ok(labels.iter().filter(|label| spawner.remove(label)).count())
+        })
+        .unwrap_or_default()
+    }
+
+    /// Stop pinging every target and drop the spawner, so that targets are
+    /// no longer accepted and the stream of results ends once the results
+    /// already sent are received, returning the number of targets which
+    /// were stopped.
+    pub(crate) fn close(&self) -> usize {
+        let Some(mut spawner) = self.spawner.lock().expect("targets lock poisoned").take() else {
+            return 0;
+        };
+        let labels: Vec<_> = spawner.targets().map(ProbeTarget::label).collect();
+        labels.iter().filter(|label| spawner.remove(label)).count()
+    }
+
+    /// Status of the dispatcher of each target, by label.
+    pub(crate) fn statuses(&self) -> BTreeMap<String, TaskStatus> {
+        self.with_spawner(|spawner| Ok(spawner.statuses()))
+            .unwrap_or_default()
+    }
+
+    /// Ping exactly the `desired` targets, adding and removing targets as
+    /// needed. Targets are matched by label and probe, so a target whose
+    /// address changes under the same alias is replaced.
+    ///
+    /// The probes of all added targets are built up front, so an invalid
+    /// target leaves the current targets unchanged.
+    pub fn reconcile(&self, desired: &[ProbeTarget]) -> Result<Reconciled> {
+        let key = |t: &ProbeTarget| (t.label(), t.probe().to_string());
+        self.with_spawner(|spawner| {
+            let desired: BTreeMap<_, _> = desired.iter().map(|t| (key(t), t)).collect();
+            let current: BTreeMap<_, _> = spawner
+                .probes()
+                .map(|(label, probe, t)| ((label.to_string(), probe.to_string()), t.clone()))
+                .collect();
+            let mut probes = Vec::new();
+            for (key, target) in &desired {
+                if current.get(key) != Some(*target) {
+                    probes.push(((*target).clone(), (spawner.probe_factory)(target)?));
+                }
+            }
+
+            let mut reconciled = Reconciled::default();
+            for ((label, probe), target) in current {
+                if desired.get(&(label.clone(), probe.clone())) != Some(&&target) {
+                    spawner.remove_probe(&label, &probe);
+                    reconciled.removed.push(target);
+                }
+            }
+            for (target, probe) in probes {
+                reconciled.added.push(target.clone());
+                spawner.add_probe(target, probe);
+            }
+            info!(
+                added = reconciled.added.len(),
+                removed = reconciled.removed.len(),
+                "reconciled targets"
+            );
+            Ok(reconciled)
+        })
+    }
+}
+
+/// Targets of the lines of a targets file, ignoring blank lines and
+/// comments after a `#`.
+pub fn parse_lines(contents: &str) -> Result<Vec<TargetSpec>> {
+    let mut specs = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        specs.push(
+            line.parse()
+                .map_err(|e| UppiesError::InvalidTarget(format!("line {}: {e}", i + 1)))?,
+        );
+    }
+    Ok(specs)
+}
+
+/// Targets of sources which change, such as a [`TargetsFile`] or
+/// [`discovery`](crate::discovery), which are reconciled together with the
+/// fixed targets of the command line and configuration, so that each
+/// source only adds and removes its own targets.
+///
+/// Targets applied at [`TargetSet::PATH`] are replaced by the next change
+/// of any source. Clones share the same underlying state.
+#[derive(Clone)]
+pub struct TargetSources {
+    target_set: TargetSet,
+    /// Targets which are pinged regardless of the sources.
+    fixed: Arc<Vec<ProbeTarget>>,
+    /// Latest targets of each source, by its name.
+    sources: Arc<Mutex<BTreeMap<String, Vec<ProbeTarget>>>>,
+}
+
+impl TargetSources {
+    pub fn new(target_set: TargetSet, fixed: Vec<ProbeTarget>) -> Self {
+        Self {
+            target_set,
+            fixed: Arc::new(fixed),
+            sources: Arc::default(),
+        }
+    }
+
+    /// Set the targets of `source` without reconciling, such as those
+    /// which are pinged from the start.
+    pub fn insert(&self, source: &str, targets: Vec<ProbeTarget>) {
+        self.sources
+            .lock()
+            .expect("sources lock poisoned")
+            .insert(source.to_string(), targets);
+    }
+
+    /// Replace the targets of `source`, and ping exactly the targets of
+    /// every source along with the fixed targets. The previous targets of
+    /// the source are kept if they can't be reconciled.
+    pub fn update(&self, source: &str, targets: Vec<ProbeTarget>) -> Result<Reconciled> {
+        let mut sources = self.sources.lock().expect("sources lock poisoned");
+        let previous = sources.insert(source.to_string(), targets);
+        let desired: Vec<_> = self
+            .fixed
+            .iter()
+            .chain(sources.values().flatten())
+            .cloned()
+            .collect();
+        let reconciled = self.target_set.reconcile(&desired);
+        if reconciled.is_err() {
+            match previous {
+                Some(previous) => sources.insert(source.to_string(), previous),
+                None => sources.remove(source),
+            };
+        }
+        reconciled
+    }
+}
+
+/// File of targets, one per line as they are given on the command line
+/// (such as `gateway=192.168.1.1` or `10.0.0.0/28`), which is watched so
+/// that editing it adds and removes the targets which are pinged.
+pub struct TargetsFile {
+    path: PathBuf,
+    ranges: RangeConfig,
+}
+
+impl TargetsFile {
+    /// Name of the file within [`TargetSources`].
+    pub const SOURCE: &str = "file";
+    /// Length of time to wait after a change before reloading, since a
+    /// single edit is often several events.
+    const SETTLE: Duration = Duration::from_millis(100);
+
+    pub fn new(path: &Path, ranges: RangeConfig) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            ranges,
+        }
+    }
+
+    /// Targets of the file.
+    pub fn load(&self) -> Result<Vec<ProbeTarget>> {
+        let path = self.path.display();
+        let contents = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("cannot read targets file {path}: {e}"))?;
+        let specs = parse_lines(&contents)
+            .map_err(|e| UppiesError::InvalidTarget(format!("invalid targets file {path}: {e}")))?;
+        range::expand(&specs, &self.ranges)
+    }
+
+    /// Update the targets of the file within `sources` whenever it changes.
+    /// A file which becomes invalid is logged and ignored until it is
+    /// fixed.
+    pub async fn watch(self, sources: TargetSources) -> Result<()> {
+        let (events, mut changes) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = events.send(event);
+        })?;
+        // The directory is watched, as files are often replaced by a rename
+        // rather than written in place, such as by ansible.
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        info!(path = %self.path.display(), "watching targets file");
+
+        while let Some(event) = changes.recv().await {
+            let event = event?;
+            if matches!(event.kind, EventKind::Access(_))
+                || !event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == self.path.file_name())
+            {
+                continue;
+            }
+            tokio::time::sleep(Self::SETTLE).await;
+            while changes.try_recv().is_ok() {}
+            match self
+                .load()
+                .and_then(|targets| sources.update(Self::SOURCE, targets))
+            {
+                Ok(reconciled) => info!(
+                    path = %self.path.display(),
+                    ?reconciled.added,
+                    ?reconciled.removed,
+                    "targets file changed"
+                ),
+                Err(e) => warn!(path = %self.path.display(), %e, "ignoring targets file"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeSet,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[cfg(feature = "metrics")]
+    use prometheus::Registry;
+    use tokio_stream::StreamExt;
+
+    use super::{
+        parse_lines, Format, Reconciled, TargetList, TargetSet, TargetSources, TargetsFile,
+    };
+    use crate::{
+        probe::MockProbe, range::RangeConfig, sink::Sink, target::ProbeTarget, PingOutcome,
+        PingSender, UppiesError,
+    };
+
+    fn targets(list: &[&str]) -> Vec<ProbeTarget> {
+        list.iter().map(|target| target.parse().unwrap()).collect()
+    }
+
+    fn labels(target_set: &TargetSet) -> Vec<String> {
+        target_set
+            .targets()
+            .iter()
+            .map(ProbeTarget::label)
+            .collect()
+    }
+
+    #[test]
+    fn formats() {
+        let list = TargetList {
+            targets: targets(&["1.1.1.1", "lan=fe80::1%eth0"]),
+        };
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            let rendered = list.render(format).unwrap();
+            assert_eq!(TargetList::parse(&rendered, format).unwrap(), list);
+        }
+        assert_eq!(
+            list.render(Format::Yaml).unwrap(),
+            "targets:\n- 1.1.1.1\n- lan=fe80::1%eth0\n"
+        );
+        assert!(TargetList::parse("targets = []\nsinks = []", Format::Toml).is_err());
+        assert!(TargetList::parse(r#"{"targets": ["1.1.1"]}"#, Format::Json).is_err());
+    }
+
+    /// Sink of the targets and probes which were removed.
+    #[derive(Default)]
+    struct Removed(Mutex<Vec<(String, String)>>);
+
+    impl Sink for Removed {
+        fn record(&self, _: &PingOutcome) {}
+
+        fn remove(&self, target: &str, probe: &str) {
+            let mut removed = self.0.lock().unwrap();
+            removed.push((target.to_string(), probe.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile() {
+        let removed = Arc::new(Removed::default());
+        let builder = PingSender::builder().with_ping_interval_ms(10);
+        #[cfg(feature = "metrics")]
+        let metrics = Registry::new();
+        #[cfg(feature = "metrics")]
+        let builder = builder.with_registry(&metrics);
+        let sender = builder
+            .build()
+            .unwrap()
+            .with_probe(
+                "a".parse().unwrap(),
+                MockProbe::new([Ok(Duration::from_millis(1))]),
+            )
+            .with_probe_factory(|target| match target.host() {
+                "invalid" => Err(UppiesError::InvalidTarget("invalid target".to_string())),
+                _ => Ok(MockProbe::new([Ok(Duration::from_millis(1))])),
+            })
+            .with_sink(removed.clone());
+        let target_set = sender.target_set();
+        assert!(
+            target_set.add(&"b".parse().unwrap()).is_err(),
+            "not started"
+        );
+        let mut results = sender.results();
+        assert_eq!(labels(&target_set), ["a"]);
+
+        let desired = targets(&["b", "c"]);
+        assert!(target_set.reconcile(&targets(&["invalid", "b"])).is_err());
+        assert_eq!(labels(&target_set), ["a"], "unchanged by an invalid target");
+        assert_eq!(
+            target_set.reconcile(&desired).unwrap(),
+            Reconciled {
+                added: desired.clone(),
+                removed: targets(&["a"]),
+            }
+        );
+        assert_eq!(target_set.targets(), desired);
+        assert_eq!(
+            target_set.reconcile(&targets(&["B.", "c"])).unwrap(),
+            Reconciled::default(),
+            "targets are matched once normalized"
+        );
+
+        let mut seen = BTreeSet::new();
+        while seen.len() < 2 {
+            let outcome = results.next().await.unwrap();
+            if &*outcome.target != "a" {
+                seen.insert(outcome.target.to_string());
+            }
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let gauge = metrics
+                .gather()
+                .into_iter()
+                .find(|m| m.name() == "uppies_targets")
+                .unwrap();
+            assert_eq!(gauge.get_metric()[0].get_gauge().value(), 2.0);
+        }
+
+        assert!(target_set.remove("b").unwrap());
+        assert!(!target_set.remove("b").unwrap());
+        assert_eq!(labels(&target_set), ["c"]);
+        assert_eq!(
+            *removed.0.lock().unwrap(),
+            [
+                ("a".to_string(), "icmp".to_string()),
+                ("b".to_string(), "icmp".to_string())
+            ],
+            "sinks forget removed targets"
+        );
+
+        // A target whose address changes under the same alias is replaced.
+        let aliased = targets(&["c", "gw=b"]);
+        target_set.reconcile(&aliased).unwrap();
+        assert_eq!(
+            target_set.reconcile(&targets(&["c", "gw=d"])).unwrap(),
+            Reconciled {
+                added: targets(&["gw=d"]),
+                removed: targets(&["gw=b"]),
+            }
+        );
+        assert!(!target_set.add(&"gw=e".parse().unwrap()).unwrap());
+
+        assert_eq!(target_set.stop(), 2);
+        assert!(target_set.targets().is_empty());
+    }
+
+    #[test]
+    fn lines() {
+        let specs =
+            parse_lines("# hosts\n1.1.1.1\n\n  gw=192.168.1.1  # gateway\n10.0.0.0/30\n").unwrap();
+        let specs: Vec<_> = specs.iter().map(ToString::to_string).collect();
+        assert_eq!(specs, ["1.1.1.1", "gw=192.168.1.1", "10.0.0.0/30"]);
+
+        let e = parse_lines("1.1.1.1\ntls://").unwrap_err();
+        assert!(matches!(e, UppiesError::InvalidTarget(_)));
+        let e = e.to_string();
+        assert!(e.starts_with("line 2: invalid target 'tls://'"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn watch_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts.txt");
+        std::fs::write(&path, "a\nb\n").unwrap();
+        let file = TargetsFile::new(&path, RangeConfig::default());
+
+        let loaded = file.load().unwrap();
+        let mut sender = PingSender::builder()
+            .with_ping_interval_ms(10)
+            .build()
+            .unwrap()
+            .with_probe_factory(|target| match target.host() {
+                "invalid" => Err(UppiesError::InvalidTarget("invalid target".to_string())),
+                _ => Ok(MockProbe::new([Ok(Duration::from_millis(1))])),
+            });
+        for target in targets(&["fixed"]).into_iter().chain(loaded.clone()) {
+            sender = sender.with_probe(target, MockProbe::new([Ok(Duration::from_millis(1))]));
+        }
+        let target_set = sender.target_set();
+        let _results = sender.results();
+        let sources = TargetSources::new(target_set.clone(), targets(&["fixed"]));
+        sources.insert(TargetsFile::SOURCE, loaded);
+        assert_eq!(labels(&target_set), ["a", "b", "fixed"]);
+
+        tokio::spawn(file.watch(sources.clone()));
+        // Give the watcher time to start, then replace the file as editors do.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let replaced = dir.path().join("hosts.txt.tmp");
+        std::fs::write(&replaced, "b\nc\n").unwrap();
+        std::fs::rename(&replaced, &path).unwrap();
+        for _ in 0..100 {
+            if labels(&target_set) == ["b", "c", "fixed"] {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(labels(&target_set), ["b", "c", "fixed"]);
+
+        std::fs::write(&path, "tls://\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            labels(&target_set),
+            ["b", "c", "fixed"],
+            "invalid files are ignored"
+        );
+
+        // Other sources only add and remove their own targets.
+        sources.update("other", targets(&["d"])).unwrap();
+        assert_eq!(labels(&target_set), ["b", "c", "d", "fixed"]);
+        assert!(sources.update("other", targets(&["invalid"])).is_err());
+        sources.update(TargetsFile::SOURCE, Vec::new()).unwrap();
+        assert_eq!(
+            labels(&target_set),
+            ["d", "fixed"],
+            "the previous targets of a source are kept when invalid"
+        );
+    }
+}
@@ -0,0 +1,453 @@
+//! Live view of the targets of a running instance, continuously sorted by
+//! their recent loss or round-trip time, like `top` for network targets.
+//!
+//! Results are read from the [`stream`](crate::stream) of the instance, and
+//! targets are paused through its [`pause`](crate::pause) API. The recent
+//! round-trip time of each target is also shown relative to its
+//! [`baseline`](crate::baseline), which is refreshed periodically.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Write as _,
+    time::Duration,
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    auth,
+    baseline::{self, BaselineReport},
+    pause, stream, Result,
+};
+
+/// Order in which targets are listed, worst first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    Loss,
+    Rtt,
+    Target,
+}
+
+impl SortBy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Loss => "loss",
+            Self::Rtt => "rtt",
+            Self::Target => "target",
+        }
+    }
+}
+
+/// Recent results of a single target.
+#[derive(Default)]
+struct TargetStats {
+    /// Whether recent pings succeeded and their round-trip times in
+    /// milliseconds, which targets expected to be down succeed without.
+    recent: VecDeque<(bool, Option<f64>)>,
+    paused: bool,
+    /// Median round-trip time over the window of the baseline.
+    baseline_ms: Option<f64>,
+}
+
+impl TargetStats {
+    /// Percentage of recent pings which failed.
+    fn loss(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 0.0;
+        }
+        let failed = self.recent.iter().filter(|(ok, _)| !ok).count();
+        failed as f64 / self.recent.len() as f64 * 100.0
+    }
+
+    /// Mean round-trip time of recent successful pings.
+    fn mean_rtt(&self) -> Option<f64> {
+        let rtts: Vec<_> = self.recent.iter().filter_map(|(_, rtt)| *rtt).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        Some(rtts.iter().sum::<f64>() / rtts.len() as f64)
+    }
+
+    fn last_rtt(&self) -> Option<f64> {
+        self.recent.back().and_then(|(_, rtt)| *rtt)
+    }
+
+    /// Percentage which the mean round-trip time is above the baseline, or
+    /// negative when below.
+    fn vs_baseline(&self) -> Option<f64> {
+        let baseline = self.baseline_ms.filter(|ms| *ms > 0.0)?;
+        Some((self.mean_rtt()? / baseline - 1.0) * 100.0)
+    }
+}
+
+/// State of the view, independent of the terminal.
+pub struct Top {
+    targets: BTreeMap<String, TargetStats>,
+    sort: SortBy,
+    /// Index of the selected row.
+    selected: usize,
+    /// Length of the window which baselines are learned over, once fetched.
+    baseline_window_secs: Option<u64>,
+}
+
+impl Default for Top {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Top {
+    /// Number of recent pings of each target which are summarised.
+    const WINDOW: usize = 20;
+
+    pub fn new() -> Self {
+        Self {
+            targets: BTreeMap::new(),
+            sort: SortBy::Loss,
+            selected: 0,
+            baseline_window_secs: None,
+        }
+    }
+
+    fn record(&mut self, event: &stream::StreamEvent) {
+        let stats = self.targets.entry(event.target.clone()).or_default();
+        if stats.recent.len() == Self::WINDOW {
+            stats.recent.pop_front();
+        }
+        stats
+            .recent
+            .push_back((event.error.is_none(), event.rtt_ms));
+    }
+
+    fn apply_baselines(&mut self, report: BaselineReport) {
+        self.baseline_window_secs = Some(report.window_secs);
+        for stats in self.targets.values_mut() {
+            stats.baseline_ms = None;
+        }
+        // Rows combine every probe of a target, so are shown against the
+        // baseline of the probe with the most samples.
+        let mut samples = BTreeMap::new();
+        for baseline in report.targets {
+            let most = samples.entry(baseline.target.clone()).or_insert(0);
+            if baseline.samples < *most {
+                continue;
+            }
+            *most = baseline.samples;
+            let stats = self.targets.entry(baseline.target).or_default();
+            stats.baseline_ms = Some(baseline.median_ms);
+        }
+    }
+
+    /// Targets in the current sort order.
+    fn rows(&self) -> Vec<(&str, &TargetStats)> {
+        let mut rows: Vec<_> = self
+            .targets
+            .iter()
+            .map(|(target, stats)| (target.as_str(), stats))
+            .collect();
+        match self.sort {
+            SortBy::Loss => rows.sort_by(|(_, a), (_, b)| b.loss().total_cmp(&a.loss())),
+            // Targets without any successful pings are the slowest.
+            SortBy::Rtt => rows.sort_by(|(_, a), (_, b)| {
+                let rtt = |s: &TargetStats| s.mean_rtt().unwrap_or(f64::INFINITY);
+                rtt(b).total_cmp(&rtt(a))
+            }),
+            SortBy::Target => {}
+        }
+        rows
+    }
+
+    fn selected_target(&self) -> Option<&str> {
+        self.rows().get(self.selected).map(|(target, _)| *target)
+    }
+
+    /// Lines of the view, with the selected row marked.
+    fn render(&self) -> Vec<String> {
+        let baseline = self
+            .baseline_window_secs
+            .map_or("BASELINE".to_string(), |secs| {
+                format!("VS {} MEDIAN", window(secs))
+            });
+        let mut lines = vec![
+            format!(
+                "uppies top - {} targets, sorted by {} ([l]oss [r]tt [t]arget, [p]ause, [q]uit)",
+                self.targets.len(),
+                self.sort.as_str()
+            ),
+            format!(
+                "  {:<40} {:>7} {:>10} {:>10} {:>16}  {}",
+                "TARGET", "LOSS%", "RTT", "AVG RTT", baseline, "STATE"
+            ),
+        ];
+        let ms = |rtt: Option<f64>| rtt.map_or("-".to_string(), |ms| format!("{ms:.1}ms"));
+        for (i, (target, stats)) in self.rows().into_iter().enumerate() {
+            lines.push(format!(
+                "{} {:<40} {:>7.1} {:>10} {:>10} {:>16}  {}",
+                if i == self.selected { ">" } else { " " },
+                target,
+                stats.loss(),
+                ms(stats.last_rtt()),
+                ms(stats.mean_rtt()),
+                stats
+                    .vs_baseline()
+                    .map_or("-".to_string(), |pct| format!("{pct:+.0}%")),
+                if stats.paused { "paused" } else { "" },
+            ));
+        }
+        lines
+    }
+
+    /// Apply a key press, returning the target to pause or resume if any.
+    fn key(&mut self, code: KeyCode) -> Option<(String, bool)> {
+        match code {
+            KeyCode::Char('l') => self.sort = SortBy::Loss,
+            KeyCode::Char('r') => self.sort = SortBy::Rtt,
+            KeyCode::Char('t') => self.sort = SortBy::Target,
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(self.targets.len().saturating_sub(1));
+            }
+            KeyCode::Char('p') => {
+                let target = self.selected_target()?.to_string();
+                let stats = self.targets.get_mut(&target)?;
+                stats.paused = !stats.paused;
+                return Some((target, stats.paused));
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Length of a window in its largest whole unit, such as `7d`.
+fn window(secs: u64) -> String {
+    match secs {
+        s if s.is_multiple_of(86400) => format!("{}d", s / 86400),
+        s if s.is_multiple_of(3600) => format!("{}h", s / 3600),
+        s if s.is_multiple_of(60) => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Restores the terminal when the view exits, even on error.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(
+            std::io::stdout(),
+            terminal::EnterAlternateScreen,
+            cursor::Hide
+        )?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = execute!(
+            std::io::stdout(),
+            cursor::Show,
+            terminal::LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Key presses read from the terminal by a thread of their own, which stops
+/// once the receiver is dropped.
+pub(crate) fn keys() -> mpsc::Receiver<KeyEvent> {
+    let (key_tx, keys) = mpsc::channel(16);
+    std::thread::spawn(move || loop {
+        match event::poll(Duration::from_millis(100)) {
+            Ok(true) => {
+                if let Ok(Event::Key(key)) = event::read() {
+                    if key_tx.blocking_send(key).is_err() {
+                        return;
+                    }
+                }
+            }
+            Ok(false) if key_tx.is_closed() => return,
+            Ok(false) => {}
+            Err(_) => return,
+        }
+    });
+    keys
+}
+
+/// Whether `key` quits the view, such as `q` or Ctrl+C.
+pub(crate) fn is_quit(key: &KeyEvent) -> bool {
+    let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+    ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+}
+
+/// Interval between fetching the baselines of the instance.
+const BASELINE_REFRESH: Duration = Duration::from_secs(60);
+
+/// Run the view against the instance at `base_url` until quit, sending
+/// `token` if the API requires one.
+pub async fn run(base_url: &str, refresh: Duration, token: Option<&str>) -> Result<()> {
+    let client = auth::client_builder(token)?.build()?;
+    let mut top = Top::new();
+    for status in pause::fetch(&client, base_url).await? {
+        top.targets.entry(status.target).or_default().paused = status.paused;
+    }
+    let mut events = stream::subscribe(&client, base_url).await?;
+
+    let _terminal = RawTerminal::enter()?;
+    let mut keys = keys();
+
+    let mut interval = tokio::time::interval(refresh);
+    let mut baseline_refresh = tokio::time::interval(BASELINE_REFRESH);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Ok(event)) => top.record(&event),
+                Some(Err(e)) => return Err(e),
+                None => return Err("stream closed".to_string().into()),
+            },
+            Some(key) = keys.recv() => {
+                if is_quit(&key) {
+                    return Ok(());
+                }
+                if let Some((target, paused)) = top.key(key.code) {
+                    pause::request(&client, base_url, &target, paused).await?;
+                }
+                draw(&top)?;
+            }
+            _ = interval.tick() => draw(&top)?,
+            _ = baseline_refresh.tick() => {
+                // Instances which don't serve baselines are shown without
+                // them.
+                if let Ok(report) = baseline::fetch(&client, base_url).await {
+                    top.apply_baselines(report);
+                }
+            }
+        }
+    }
+}
+
+fn draw(top: &Top) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    let (_, rows) = terminal::size()?;
+    queue!(
+        stdout,
+        cursor::MoveTo(0, 0),
+        terminal::Clear(ClearType::All)
+    )?;
+    for (i, line) in top.render().into_iter().take(rows as usize).enumerate() {
+        if i == 1 {
+            queue!(stdout, SetAttribute(Attribute::Bold))?;
+        }
+        queue!(stdout, Print(line), Print("\r\n"))?;
+        if i == 1 {
+            queue!(stdout, SetAttribute(Attribute::Reset))?;
+        }
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crossterm::event::KeyCode;
+
+    use super::Top;
+    use crate::{
+        baseline::{Baseline, BaselineReport},
+        stream::StreamEvent,
+    };
+
+    fn event(target: &str, rtt_ms: Option<f64>) -> StreamEvent {
+        StreamEvent {
+            target: target.to_string(),
+            probe: "icmp".to_string(),
+            sequence: 0,
+            rtt_ms,
+            error: rtt_ms.is_none().then(|| "timeout".to_string()),
+            timestamp_ms: 0,
+            reply_ttl: None,
+            reply_source: None,
+        }
+    }
+
+    fn order(top: &Top) -> Vec<&str> {
+        top.rows().into_iter().map(|(target, _)| target).collect()
+    }
+
+    #[test]
+    fn sort_by_loss_and_rtt() {
+        let mut top = Top::new();
+        for rtt in [Some(50.0), Some(60.0)] {
+            top.record(&event("slow", rtt));
+        }
+        for rtt in [Some(1.0), None] {
+            top.record(&event("lossy", rtt));
+        }
+        top.record(&event("fast", Some(2.0)));
+
+        assert_eq!(order(&top), ["lossy", "fast", "slow"]);
+        assert_eq!(top.targets["lossy"].loss(), 50.0);
+        top.key(KeyCode::Char('r'));
+        assert_eq!(order(&top), ["slow", "fast", "lossy"]);
+        assert_eq!(top.targets["slow"].mean_rtt(), Some(55.0));
+        top.key(KeyCode::Char('t'));
+        assert_eq!(order(&top), ["fast", "lossy", "slow"]);
+    }
+
+    #[test]
+    fn pause_selected() {
+        let mut top = Top::new();
+        top.record(&event("a", Some(1.0)));
+        top.record(&event("b", Some(1.0)));
+        top.key(KeyCode::Char('t'));
+
+        top.key(KeyCode::Down);
+        top.key(KeyCode::Down);
+        assert_eq!(
+            top.key(KeyCode::Char('p')),
+            Some(("b".to_string(), true)),
+            "selection stops at the last row"
+        );
+        assert_eq!(top.key(KeyCode::Char('p')), Some(("b".to_string(), false)));
+        assert!(top.render()[3].contains('b'));
+    }
+
+    #[test]
+    fn relative_to_baseline() {
+        let mut top = Top::new();
+        for rtt in [Some(12.0), Some(15.0)] {
+            top.record(&event("wan", rtt));
+        }
+        assert!(top.render()[1].contains("BASELINE"));
+
+        top.apply_baselines(BaselineReport {
+            window_secs: 7 * 86400,
+            targets: vec![
+                Baseline {
+                    target: "wan".to_string(),
+                    probe: "icmp".to_string(),
+                    median_ms: 10.0,
+                    samples: 100,
+                },
+                Baseline {
+                    target: "wan".to_string(),
+                    probe: "tls".to_string(),
+                    median_ms: 50.0,
+                    samples: 10,
+                },
+            ],
+        });
+        let lines = top.render();
+        assert!(lines[1].contains("VS 7d MEDIAN"));
+        assert!(lines[2].contains("+35%"), "{}", lines[2]);
+    }
+}
@@ -0,0 +1,295 @@
+//! Terminal UI of the targets of a running instance, with a sparkline of
+//! the round-trip times of each target, its loss and its last error.
+//!
+//! Like [`top`](crate::top), results are read from the
+//! [`stream`](crate::stream) of the instance, but the view is drawn with
+//! ratatui and includes a larger sparkline of the selected target, for
+//! troubleshooting rather than ranking targets.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use crossterm::event::KeyCode;
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Cell, Paragraph, Row, Sparkline, Table, TableState},
+    Frame,
+};
+
+use crate::{auth, stream, top, Result};
+
+/// Recent results of a single target.
+#[derive(Default)]
+struct TargetView {
+    /// Whether recent pings succeeded and their round-trip times in
+    /// milliseconds, which targets expected to be down succeed without.
+    recent: VecDeque<(bool, Option<f64>)>,
+    /// Most recent failure, which is kept after the target recovers.
+    last_error: Option<String>,
+}
+
+impl TargetView {
+    /// Percentage of recent pings which failed.
+    fn loss(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 0.0;
+        }
+        let failed = self.recent.iter().filter(|(ok, _)| !ok).count();
+        failed as f64 / self.recent.len() as f64 * 100.0
+    }
+}
+
+/// State of the view, independent of the terminal.
+pub struct Tui {
+    targets: BTreeMap<String, TargetView>,
+    table: TableState,
+}
+
+impl Default for Tui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tui {
+    /// Number of recent pings of each target which are kept.
+    const WINDOW: usize = 120;
+
+    /// Number of recent pings within the sparkline column of the table.
+    const SPARKLINE_WIDTH: usize = 30;
+
+    pub fn new() -> Self {
+        Self {
+            targets: BTreeMap::new(),
+            table: TableState::default().with_selected(0),
+        }
+    }
+
+    fn record(&mut self, event: &stream::StreamEvent) {
+        let view = self.targets.entry(event.target.clone()).or_default();
+        if view.recent.len() == Self::WINDOW {
+            view.recent.pop_front();
+        }
+        view.recent.push_back((event.error.is_none(), event.rtt_ms));
+        if event.error.is_some() {
+            view.last_error.clone_from(&event.error);
+        }
+    }
+
+    fn key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => {
+                let last = self.targets.len().saturating_sub(1);
+                let next = self.table.selected().map_or(0, |i| (i + 1).min(last));
+                self.table.select(Some(next));
+            }
+            _ => {}
+        }
+    }
+
+    fn selected(&self) -> Option<(&str, &TargetView)> {
+        let i = self.table.selected()?;
+        self.targets
+            .iter()
+            .nth(i)
+            .map(|(target, view)| (target.as_str(), view))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [title, table, detail] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(6),
+        ])
+        .areas(frame.area());
+
+        frame.render_widget(
+            Paragraph::new(format!(
+                "uppies tui - {} targets ([j/k] select, [q]uit)",
+                self.targets.len()
+            )),
+            title,
+        );
+
+        let ms = |rtt: Option<f64>| rtt.map_or("-".to_string(), |ms| format!("{ms:.1}ms"));
+        let rows = self.targets.iter().map(|(target, view)| {
+            let skip = view.recent.len().saturating_sub(Self::SPARKLINE_WIDTH);
+            Row::new([
+                Cell::from(target.as_str()),
+                Cell::from(sparkline(view.recent.iter().skip(skip).copied())),
+                Cell::from(format!("{:.1}", view.loss())),
+                Cell::from(ms(view.recent.back().and_then(|(_, rtt)| *rtt))),
+                Cell::from(view.last_error.as_deref().unwrap_or("-")),
+            ])
+        });
+        let header = Row::new(["TARGET", "RTT", "LOSS%", "LAST", "LAST ERROR"])
+            .style(Style::new().add_modifier(Modifier::BOLD));
+        let widths = [
+            Constraint::Max(40),
+            Constraint::Length(Self::SPARKLINE_WIDTH as u16),
+            Constraint::Length(6),
+            Constraint::Length(10),
+            Constraint::Fill(1),
+        ];
+        let table_widget = Table::new(rows, widths)
+            .header(header)
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(Block::new().borders(Borders::TOP | Borders::BOTTOM));
+        frame.render_stateful_widget(table_widget, table, &mut self.table);
+
+        if let Some((target, view)) = self.selected() {
+            // Pings without a round-trip time are drawn as gaps.
+            let data: Vec<_> = view
+                .recent
+                .iter()
+                .map(|(_, rtt)| rtt.map(|ms| (ms * 1000.0) as u64))
+                .collect();
+            let skip = data
+                .len()
+                .saturating_sub(detail.width.saturating_sub(2) as usize);
+            let sparkline = Sparkline::default()
+                .block(Block::bordered().title(Line::from(format!(
+                    "{target}: last {} pings",
+                    data.len() - skip
+                ))))
+                .data(&data[skip..]);
+            frame.render_widget(sparkline, detail);
+        }
+    }
+}
+
+/// Round-trip times as a line of block characters scaled between the
+/// fastest and slowest, with failed pings drawn as `x` and those which
+/// succeeded without a round-trip time as `·`.
+fn sparkline(pings: impl Iterator<Item = (bool, Option<f64>)> + Clone) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let (min, max) = pings
+        .clone()
+        .filter_map(|(_, rtt)| rtt)
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), rtt| {
+            (min.min(rtt), max.max(rtt))
+        });
+    pings
+        .map(|ping| match ping {
+            (_, Some(_)) if max <= min => BARS[0],
+            (_, Some(rtt)) => {
+                BARS[((rtt - min) / (max - min) * (BARS.len() - 1) as f64).round() as usize]
+            }
+            (true, None) => '·',
+            (false, None) => 'x',
+        })
+        .collect()
+}
+
+/// Run the view against the instance at `base_url` until quit, sending
+/// `token` if the API requires one.
+pub async fn run(base_url: &str, refresh: Duration, token: Option<&str>) -> Result<()> {
+    let client = auth::client_builder(token)?.build()?;
+    let mut events = stream::subscribe(&client, base_url).await?;
+    let mut tui = Tui::new();
+
+    let mut terminal = ratatui::try_init()?;
+    let mut keys = top::keys();
+    let mut interval = tokio::time::interval(refresh);
+    let result = loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Ok(event)) => tui.record(&event),
+                Some(Err(e)) => break Err(e),
+                None => break Err("stream closed".to_string().into()),
+            },
+            Some(key) = keys.recv() => {
+                if top::is_quit(&key) {
+                    break Ok(());
+                }
+                tui.key(key.code);
+                if let Err(e) = terminal.draw(|frame| tui.draw(frame)) {
+                    break Err(e.into());
+                }
+            }
+            _ = interval.tick() => {
+                if let Err(e) = terminal.draw(|frame| tui.draw(frame)) {
+                    break Err(e.into());
+                }
+            }
+        }
+    };
+    ratatui::try_restore()?;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crossterm::event::KeyCode;
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use super::{sparkline, Tui};
+    use crate::stream::StreamEvent;
+
+    fn event(target: &str, rtt_ms: Option<f64>) -> StreamEvent {
+        StreamEvent {
+            target: target.to_string(),
+            probe: "icmp".to_string(),
+            sequence: 0,
+            rtt_ms,
+            error: rtt_ms.is_none().then(|| "timeout".to_string()),
+            timestamp_ms: 0,
+            reply_ttl: None,
+            reply_source: None,
+        }
+    }
+
+    #[test]
+    fn sparklines() {
+        let pings = [
+            (true, Some(1.0)),
+            (true, Some(8.0)),
+            (false, None),
+            (true, None),
+            (true, Some(4.5)),
+        ];
+        assert_eq!(sparkline(pings.into_iter()), "▁█x·▅");
+        let pings = [(true, Some(3.0)), (true, Some(3.0))];
+        assert_eq!(sparkline(pings.into_iter()), "▁▁");
+    }
+
+    #[test]
+    fn render_table() {
+        let mut tui = Tui::new();
+        for rtt in [Some(1.0), None, Some(2.0)] {
+            tui.record(&event("192.168.1.1", rtt));
+        }
+        tui.record(&event("1.1.1.1", Some(10.0)));
+        tui.key(KeyCode::Down);
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+        terminal.draw(|frame| tui.draw(frame)).unwrap();
+        let lines: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content
+            .chunks(100)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+
+        assert!(lines[0].contains("2 targets"), "{}", lines[0]);
+        let row = lines
+            .iter()
+            .find(|line| line.contains("192.168.1.1"))
+            .unwrap();
+        assert!(row.contains("▁x█"), "{row}");
+        assert!(row.contains("33.3"), "{row}");
+        assert!(
+            row.contains("timeout"),
+            "the last error is kept after recovery"
+        );
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("192.168.1.1: last 3 pings")));
+    }
+}